@@ -0,0 +1,543 @@
+//!
+//! Parses the command line arguments. There are only a handful of flags, so we do it by hand
+//! instead of pulling in an argument parsing crate.
+//!
+
+use std::env;
+
+use crate::parser::combinators::integer_literal;
+use crate::simulator::{Location, OnIllegal, SharedMemoryConfig, TraceFilter};
+
+pub const USAGE: &str = "\
+Usage: ./fpgrars [OPTIONS] riscv_file.s [more_files.s...]
+
+Options:
+  --no-video            run without opening the video window
+  -q, --quiet           don't print the summary line when the program exits
+  --bench               when the program exits, print how many instructions per second it ran,
+                        and how much of the time went to ecalls instead of running instructions
+  --instruction-mix     when the program exits, print how many times it ran each kind of
+                        instruction and each mnemonic, and how many branches were taken
+  --profile             when the program exits, print the functions and lines where most of its
+                        instructions ran
+  --profile-folded PATH write the instructions run in each stack of calls to PATH, as folded
+                        stacks for a flame graph
+  --trace PATH          write each instruction that runs to PATH, with the registers it changed
+                        and the memory it read or wrote
+  --trace-only FILTER   only trace the code at the addresses START:END, or under a label, which
+                        can be given more than once
+  --break LOCATION      pause right before the instruction at an address or label and show the
+                        registers, which can be given more than once
+  --debug               start paused and read debugger commands, like `step`, `next` and
+                        `x/4 sp`, at each pause
+  --tui                 debug in a terminal interface showing the code, registers, memory and
+                        output of the program
+  --gdb PORT            wait for GDB to connect on PORT of localhost and let it debug the program
+  --dap PORT            wait for an editor like VS Code to connect on PORT of localhost and let
+                        it debug the program with the Debug Adapter Protocol
+  --history N           how many of the last instructions the debugger can step back over,
+                        100000 by default, or 0 to not keep them
+  --snapshot PATH       where the debugger's `save` command and --save-at save snapshots
+  --save-at LOCATION    save a snapshot of the program the first time it gets to an address or
+                        label, to resume it later with --restore
+  --restore PATH        start the program from a snapshot of it instead of from the beginning
+  --record PATH         write the keys the program gets and what its ecalls read to PATH, to play
+                        them back with --replay
+  --replay PATH         give the program the keys and input recorded to PATH instead of the ones
+                        from the keyboard and stdin
+  --list                print the address, machine code and source line of every assembled
+                        instruction instead of running the program
+  --list-file PATH      write that listing to PATH and run the program as usual
+  --symbols             print every label with its address and segment instead of running the
+                        program
+  -D NAME[=VALUE]       define NAME as if by `.eqv NAME VALUE`, with VALUE 1 by default, so the
+                        code can test it with `.ifdef`
+  --werror              treat warnings as errors, so the program only runs if there are none
+  -W, --warn-unused     also warn about labels nothing refers to, besides `main`, `_start` and
+                        the `.globl` ones
+  --strict              only accept what RARS accepts, like exact directive names and operands
+                        without expressions, for code that has to run in RARS too
+  --rv64                simulate RV64 instead of RV32, with 64-bit registers and the `w`
+                        instructions, ld and sd
+  --rv32e               only accept the registers x0 to x15, like RV32E, with the ecall number
+                        in t0 instead of a7
+  --rvc                 accept the compressed instructions of RV32C, like `c.addi`, which take
+                        2 bytes, so the addresses of the code match a real processor
+  --text-base ADDR      start the code at ADDR instead of 0, like 0x00400000 in RARS, so `auipc`,
+                        `la` and `jal` compute the addresses a real processor would
+  --check-jumps         stop at a `jalr` or `ret` to an address that isn't a multiple of 4, like
+                        RARS does, or trap to the program's handler
+  --strict-alignment    stop at a load or store whose address isn't a multiple of its size, like
+                        a `lw` from 0x1002, which RARS doesn't accept
+  --rars-layout         put the code at 0x00400000, the data at 0x10010000, `sp` at 0x7fffeffc and
+                        the keyboard and display MMIO at 0xffff0000, like RARS
+  --stack-top ADDR      start `sp` at ADDR, with the stack right below it, instead of at the end
+                        of the memory after the data
+  --stack-size BYTES    give the stack BYTES bytes, with the heap between it and the data, and
+                        stop with a stack overflow when a push goes past them
+  --readable-code       put the machine code in the memory from address 0, read-only, so the
+                        program can read its own code, with the data at 0x00400000
+  --self-modifying      put the machine code in the memory from address 0, so the program can
+                        change its own code and run code it wrote, with the data at 0x00400000
+  --vlen BITS           give each vector register BITS bits, a power of 2 from 32 to 65536
+                        (128 by default)
+  --entry LABEL         start the program at LABEL, instead of at `main` when it's declared
+                        global, or else at the first instruction
+  --permissive MODE     accept unknown instructions, and when running into one either `trap` to
+                        the program's handler at utvec or `skip` it with a warning
+  --console-limit BYTES stop printing the program's output after BYTES bytes, so a print in an
+                        infinite loop doesn't flood the terminal
+  --max-instructions N  stop the program with exit code 124 and show where it was if it's about
+                        to run more than N instructions
+  --timeout SECONDS     stop the program the same way once it has run for SECONDS, which can have
+                        a fraction like 2.5
+  --shared-memory NAME:ADDR:LEN
+                        share LEN bytes of memory starting at ADDR with every other instance
+                        started with the same NAME
+  --riscv-tests PATH    run the riscv-tests ISA suite sources in PATH (a directory or a .S file)
+  --parse-corpus DIR    parse every .s and .asm in DIR and report which instructions and
+                        directives we couldn't parse
+  --difftest N          run N random programs in the simulator and in a reference interpreter,
+                        and report where they disagree
+  --seed SEED           seed for --difftest (random by default)
+  -h, --help            print this message";
+
+/// Everything we understood from the command line
+#[derive(Debug, Default)]
+pub struct Args {
+    /// The RISC-V files to run. With more than one, they're linked together and the first one starts
+    pub files: Vec<String>,
+
+    /// Don't open the window, just run the simulator
+    pub no_video: bool,
+
+    /// Don't print the summary when the program exits
+    pub quiet: bool,
+
+    /// Print the instructions per second and the time spent in ecalls when the program exits
+    pub bench: bool,
+
+    /// Print how many times each kind of instruction ran when the program exits
+    pub instruction_mix: bool,
+
+    /// Print the functions and lines where most instructions ran when the program exits
+    pub profile: bool,
+
+    /// Write the instructions of each stack of calls to this file when the program exits
+    pub profile_folded: Option<String>,
+
+    /// Write each instruction that runs to this file
+    pub trace: Option<String>,
+
+    /// Only trace the instructions these cover, or all of them if there are none
+    pub trace_only: Vec<TraceFilter>,
+
+    /// Pause at these addresses and labels
+    pub breakpoints: Vec<Location>,
+
+    /// Start paused, reading the commands of the debugger
+    pub debug: bool,
+
+    /// Debug in the terminal interface
+    pub tui: bool,
+
+    /// Let GDB debug the program, connecting to this port
+    pub gdb: Option<u16>,
+
+    /// Let an editor debug the program, connecting to this port
+    pub dap: Option<u16>,
+
+    /// How many instructions the debugger can go back over
+    pub history: Option<usize>,
+
+    /// Where to save the snapshots
+    pub snapshot: Option<String>,
+
+    /// Save a snapshot when the program gets here
+    pub save_at: Option<Location>,
+
+    /// Start from this snapshot
+    pub restore: Option<String>,
+
+    /// Where to record the input of the program
+    pub record: Option<String>,
+
+    /// Play back the input recorded here
+    pub replay: Option<String>,
+
+    /// Print the listing of the program instead of running it
+    pub list: bool,
+
+    /// Write the listing of the program to this file before running it
+    pub list_file: Option<String>,
+
+    /// Print the labels of the program instead of running it
+    pub symbols: bool,
+
+    /// Names defined with `-D`, and their values
+    pub defines: Vec<(String, String)>,
+
+    /// Don't run the program if it has warnings
+    pub werror: bool,
+
+    /// Warn about unused labels
+    pub warn_unused: bool,
+
+    /// Reject the code RARS wouldn't accept
+    pub strict: bool,
+
+    /// Simulate RV64 instead of RV32
+    pub rv64: bool,
+
+    /// Only accept the registers of RV32E
+    pub rv32e: bool,
+
+    /// Accept the compressed instructions of RV32C
+    pub rvc: bool,
+
+    /// The address of the first instruction
+    pub text_base: Option<usize>,
+
+    /// Check that the targets of `jalr` are aligned
+    pub check_jumps: bool,
+
+    /// Check that loads and stores are aligned
+    pub strict_alignment: bool,
+
+    /// Use the memory layout of RARS
+    pub rars_layout: bool,
+
+    /// The first `sp`
+    pub stack_top: Option<usize>,
+
+    /// How many bytes the stack has
+    pub stack_size: Option<usize>,
+
+    /// Put the code in the memory, so it can be read
+    pub readable_code: bool,
+
+    /// Put the code in the memory, so it can be changed
+    pub self_modifying: bool,
+
+    /// How many bits each vector register has
+    pub vlen: Option<usize>,
+
+    /// The label to start the program at
+    pub entry: Option<String>,
+
+    /// Accept unknown instructions and deal with them at runtime like this
+    pub permissive: Option<OnIllegal>,
+
+    /// Maximum number of bytes of output to print
+    pub console_limit: Option<usize>,
+
+    /// Stop the program before it runs more instructions than this
+    pub max_instructions: Option<u64>,
+
+    /// Stop the program after it runs for this long
+    pub timeout: Option<std::time::Duration>,
+
+    /// Memory window shared with other instances
+    pub shared_memory: Option<SharedMemoryConfig>,
+
+    /// Run the riscv-tests sources found in this path instead of a regular program
+    pub riscv_tests: Option<String>,
+
+    /// Parse all files in this directory and report what we couldn't parse
+    pub parse_corpus: Option<String>,
+
+    /// Number of random programs to check against the reference interpreter
+    pub difftest: Option<usize>,
+
+    /// Seed for the random programs of `difftest`
+    pub seed: Option<u64>,
+}
+
+impl Args {
+    /// Parses the arguments of the current process
+    pub fn from_env() -> Result<Self, String> {
+        Self::parse(env::args().skip(1))
+    }
+
+    /// Parses a list of arguments (without the executable name)
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut res = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| {
+                args.next()
+                    .ok_or_else(|| format!("Missing a value for `{}`", flag))
+            };
+
+            match arg.as_str() {
+                "-h" | "--help" => return Err(USAGE.to_owned()),
+                "--no-video" => res.no_video = true,
+                "-q" | "--quiet" => res.quiet = true,
+                "--bench" => res.bench = true,
+                "--instruction-mix" => res.instruction_mix = true,
+                "--profile" => res.profile = true,
+                "--profile-folded" => res.profile_folded = Some(value(&arg)?),
+                "--trace" => res.trace = Some(value(&arg)?),
+                "--break" => {
+                    let location = value(&arg)?;
+                    res.breakpoints.push(location.parse().map_err(|e| format!("`{}`: {}", arg, e))?);
+                }
+                "--debug" => res.debug = true,
+                "--tui" => res.tui = true,
+                "--gdb" => res.gdb = Some(number(&arg, value(&arg)?)?),
+                "--dap" => res.dap = Some(number(&arg, value(&arg)?)?),
+                "--history" => res.history = Some(number(&arg, value(&arg)?)?),
+                "--snapshot" => res.snapshot = Some(value(&arg)?),
+                "--save-at" => {
+                    let location = value(&arg)?;
+                    res.save_at = Some(location.parse().map_err(|e| format!("`{}`: {}", arg, e))?);
+                }
+                "--restore" => res.restore = Some(value(&arg)?),
+                "--record" => res.record = Some(value(&arg)?),
+                "--replay" => res.replay = Some(value(&arg)?),
+                "--trace-only" => {
+                    let filter = value(&arg)?;
+                    res.trace_only.push(filter.parse().map_err(|e| format!("`{}`: {}", arg, e))?);
+                }
+                "--list" => res.list = true,
+                "--list-file" => res.list_file = Some(value(&arg)?),
+                "--symbols" => res.symbols = true,
+                "-D" => res.defines.push(define(value(&arg)?)),
+                flag if flag.starts_with("-D") => res.defines.push(define(flag[2..].to_owned())),
+                "--werror" => res.werror = true,
+                "-W" | "--warn-unused" => res.warn_unused = true,
+                "--strict" => res.strict = true,
+                "--rv64" => res.rv64 = true,
+                "--rv32e" => res.rv32e = true,
+                "--rvc" => res.rvc = true,
+                "--text-base" => res.text_base = Some(address(&arg, value(&arg)?)?),
+                "--check-jumps" => res.check_jumps = true,
+                "--strict-alignment" => res.strict_alignment = true,
+                "--rars-layout" => res.rars_layout = true,
+                "--stack-top" => res.stack_top = Some(address(&arg, value(&arg)?)?),
+                "--stack-size" => res.stack_size = Some(number(&arg, value(&arg)?)?),
+                "--readable-code" => res.readable_code = true,
+                "--self-modifying" => res.self_modifying = true,
+                "--vlen" => res.vlen = Some(number(&arg, value(&arg)?)?),
+                "--entry" => res.entry = Some(value(&arg)?),
+                "--permissive" => {
+                    let mode = value(&arg)?;
+                    res.permissive = Some(mode.parse().map_err(|e| format!("`{}`: {}", arg, e))?);
+                }
+                "--console-limit" => res.console_limit = Some(number(&arg, value(&arg)?)?),
+                "--max-instructions" => res.max_instructions = Some(number(&arg, value(&arg)?)?),
+                "--timeout" => {
+                    let seconds: f64 = number(&arg, value(&arg)?)?;
+                    if !seconds.is_finite() || seconds <= 0.0 {
+                        return Err(format!("`--timeout` has to be a positive number of seconds, got {}", seconds));
+                    }
+                    res.timeout = Some(std::time::Duration::from_secs_f64(seconds));
+                }
+                "--shared-memory" => {
+                    let window = value(&arg)?;
+                    res.shared_memory = Some(window.parse().map_err(|e| format!("`{}`: {}", arg, e))?);
+                }
+                "--riscv-tests" => res.riscv_tests = Some(value(&arg)?),
+                "--parse-corpus" => res.parse_corpus = Some(value(&arg)?),
+                "--difftest" => res.difftest = Some(number(&arg, value(&arg)?)?),
+                "--seed" => res.seed = Some(number(&arg, value(&arg)?)?),
+                flag if flag.starts_with('-') && flag.len() > 1 => {
+                    return Err(format!("Unknown option `{}`\n\n{}", flag, USAGE));
+                }
+                _ => res.files.push(arg),
+            }
+        }
+
+        if res.rvc && res.rv64 {
+            return Err("`--rvc` only has the compressed instructions of RV32, not the ones of RV64".to_owned());
+        }
+
+        if res.rvc && res.self_modifying {
+            return Err("`--self-modifying` only decodes 4-byte instructions, so it can't be used with `--rvc`".to_owned());
+        }
+
+        if let Some(text_base) = res.text_base {
+            if !text_base.is_multiple_of(4) || text_base >= 0x8000_0000 {
+                return Err(format!("`--text-base` has to be a multiple of 4 below 0x80000000, got {:#x}", text_base));
+            }
+        }
+
+        if let Some(stack_top) = res.stack_top {
+            if !stack_top.is_multiple_of(4) || stack_top > 0xffff_fff8 {
+                return Err(format!("`--stack-top` has to be a multiple of 4 below 0xfffffffc, got {:#x}", stack_top));
+            }
+        }
+
+        if let Some(stack_size) = res.stack_size {
+            if stack_size == 0 || !stack_size.is_multiple_of(4) {
+                return Err(format!("`--stack-size` has to be a positive multiple of 4, got {}", stack_size));
+            }
+        }
+
+        if res.save_at.is_some() && res.snapshot.is_none() {
+            return Err("`--save-at` needs a `--snapshot PATH` to save to".to_owned());
+        }
+
+        if res.record.is_some() && res.replay.is_some() {
+            return Err("`--record` and `--replay` can't be used together".to_owned());
+        }
+
+        if let Some(vlen) = res.vlen {
+            if !vlen.is_power_of_two() || !(32..=65536).contains(&vlen) {
+                return Err(format!("`--vlen` has to be a power of 2 from 32 to 65536, got {}", vlen));
+            }
+        }
+
+        let has_mode = res.riscv_tests.is_some() || res.parse_corpus.is_some() || res.difftest.is_some();
+        if res.files.is_empty() && !has_mode {
+            return Err(USAGE.to_owned());
+        }
+
+        Ok(res)
+    }
+}
+
+/// `NAME=VALUE`, or just `NAME`, which is defined as 1
+fn define(arg: String) -> (String, String) {
+    match arg.split_once('=') {
+        Some((name, value)) => (name.to_owned(), value.to_owned()),
+        None => (arg, "1".to_owned()),
+    }
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Expected a number for `{}`, got `{}`", flag, value))
+}
+
+/// A number like in the code, so addresses can be in hex
+fn address(flag: &str, value: String) -> Result<usize, String> {
+    integer_literal(&value)
+        .map(|x| x as usize)
+        .map_err(|_| format!("Expected an address for `{}`, got `{}`", flag, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|&s| s.to_owned()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse(&["--no-video", "file.s"]).unwrap();
+        assert_eq!(args.files, ["file.s"]);
+        assert!(args.no_video);
+        assert!(!args.quiet);
+        assert_eq!(args.permissive, None);
+
+        let args = parse(&["-q", "file.s"]).unwrap();
+        assert!(args.quiet);
+        assert!(!args.bench);
+        assert!(parse(&["--bench", "file.s"]).unwrap().bench);
+        assert!(parse(&["--instruction-mix", "file.s"]).unwrap().instruction_mix);
+        assert!(parse(&["--profile", "file.s"]).unwrap().profile);
+        let args = parse(&["--profile-folded", "out.folded", "file.s"]).unwrap();
+        assert_eq!(args.profile_folded.as_deref(), Some("out.folded"));
+        assert!(!args.profile);
+
+        let args = parse(&["--trace", "out.trace", "--trace-only", "draw", "--trace-only", "0x10:0x20", "file.s"]).unwrap();
+        assert_eq!(args.trace.as_deref(), Some("out.trace"));
+        assert_eq!(args.trace_only, [TraceFilter::Label("draw".to_owned()), TraceFilter::Range(0x10..0x20)]);
+
+        let args = parse(&["--break", "main", "--break", "0x400010", "file.s"]).unwrap();
+        assert_eq!(args.breakpoints, [Location::Label("main".to_owned()), Location::Address(0x400010)]);
+        assert!(!args.debug);
+        assert!(parse(&["--debug", "file.s"]).unwrap().debug);
+        assert!(parse(&["--tui", "file.s"]).unwrap().tui);
+        assert_eq!(parse(&["--gdb", "1234", "file.s"]).unwrap().gdb, Some(1234));
+        assert!(parse(&["--gdb", "70000", "file.s"]).is_err());
+        assert_eq!(parse(&["--dap", "4711", "file.s"]).unwrap().dap, Some(4711));
+        assert_eq!(parse(&["--history", "0", "file.s"]).unwrap().history, Some(0));
+        let args = parse(&["--snapshot", "board.snap", "--save-at", "game_loop", "file.s"]).unwrap();
+        assert_eq!((args.snapshot.as_deref(), args.save_at), (Some("board.snap"), Some(Location::Label("game_loop".to_owned()))));
+        assert!(parse(&["--save-at", "0x40", "file.s"]).is_err());
+        assert_eq!(parse(&["--restore", "board.snap", "file.s"]).unwrap().restore.as_deref(), Some("board.snap"));
+        assert_eq!(parse(&["--record", "game.rec", "file.s"]).unwrap().record.as_deref(), Some("game.rec"));
+        assert_eq!(parse(&["--replay", "game.rec", "file.s"]).unwrap().replay.as_deref(), Some("game.rec"));
+        assert!(parse(&["--record", "a.rec", "--replay", "b.rec", "file.s"]).is_err());
+
+        let args = parse(&["--list", "file.s"]).unwrap();
+        assert!(args.list);
+        let args = parse(&["--list-file", "out.txt", "file.s"]).unwrap();
+        assert_eq!(args.list_file.as_deref(), Some("out.txt"));
+        assert_eq!(args.files, ["file.s"]);
+        assert!(parse(&["--symbols", "file.s"]).unwrap().symbols);
+        assert!(parse(&["--werror", "file.s"]).unwrap().werror);
+        assert!(parse(&["-W", "file.s"]).unwrap().warn_unused);
+        assert!(!parse(&["file.s"]).unwrap().warn_unused);
+        assert!(parse(&["--strict", "file.s"]).unwrap().strict);
+        assert!(parse(&["--rv64", "file.s"]).unwrap().rv64);
+        assert!(parse(&["--rv32e", "file.s"]).unwrap().rv32e);
+        assert!(parse(&["--rvc", "file.s"]).unwrap().rvc);
+        assert!(parse(&["--rvc", "--rv64", "file.s"]).is_err());
+        assert_eq!(parse(&["--text-base", "0x00400000", "file.s"]).unwrap().text_base, Some(0x0040_0000));
+        assert!(parse(&["--text-base", "0x401", "file.s"]).is_err());
+        assert!(parse(&["--check-jumps", "file.s"]).unwrap().check_jumps);
+        assert!(parse(&["--strict-alignment", "file.s"]).unwrap().strict_alignment);
+        assert!(parse(&["--rars-layout", "file.s"]).unwrap().rars_layout);
+        assert_eq!(parse(&["--stack-top", "0x7fffeffc", "file.s"]).unwrap().stack_top, Some(0x7fff_effc));
+        assert_eq!(parse(&["--stack-size", "4096", "file.s"]).unwrap().stack_size, Some(4096));
+        assert!(parse(&["--stack-size", "6", "file.s"]).is_err());
+        assert!(parse(&["--readable-code", "--rvc", "file.s"]).unwrap().readable_code);
+        assert!(parse(&["--self-modifying", "file.s"]).unwrap().self_modifying);
+        assert!(parse(&["--self-modifying", "--rvc", "file.s"]).is_err());
+        assert_eq!(parse(&["--vlen", "256", "file.s"]).unwrap().vlen, Some(256));
+        assert!(parse(&["--vlen", "96", "file.s"]).is_err());
+        assert!(parse(&["--vlen", "16", "file.s"]).is_err());
+
+        let args = parse(&["-D", "DEBUG", "-DLEVEL=2", "file.s"]).unwrap();
+        assert_eq!(args.defines, [("DEBUG".into(), "1".into()), ("LEVEL".into(), "2".into())]);
+
+        let args = parse(&["--permissive", "skip", "file.s"]).unwrap();
+        assert_eq!(args.permissive, Some(OnIllegal::Skip));
+
+        let args = parse(&["--shared-memory", "game:0x2000:64", "file.s"]).unwrap();
+        let window = args.shared_memory.unwrap();
+        assert_eq!((window.name.as_str(), window.start, window.len), ("game", 0x2000, 64));
+
+        let args = parse(&["--entry", "start", "file.s"]).unwrap();
+        assert_eq!(args.entry.as_deref(), Some("start"));
+
+        let args = parse(&["--console-limit", "4096", "file.s"]).unwrap();
+        assert_eq!(args.console_limit, Some(4096));
+
+        let args = parse(&["--max-instructions", "1000000", "--timeout", "2.5", "file.s"]).unwrap();
+        assert_eq!(args.max_instructions, Some(1_000_000));
+        assert_eq!(args.timeout, Some(std::time::Duration::from_millis(2500)));
+        assert!(parse(&["--timeout", "0", "file.s"]).is_err());
+        assert!(parse(&["--timeout", "soon", "file.s"]).is_err());
+
+        let args = parse(&["--riscv-tests", "isa/rv32ui"]).unwrap();
+        assert_eq!(args.riscv_tests.as_deref(), Some("isa/rv32ui"));
+        assert!(args.files.is_empty());
+
+        let args = parse(&["--parse-corpus", "course"]).unwrap();
+        assert_eq!(args.parse_corpus.as_deref(), Some("course"));
+
+        let args = parse(&["--difftest", "500", "--seed", "42"]).unwrap();
+        assert_eq!(args.difftest, Some(500));
+        assert_eq!(args.seed, Some(42));
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--difftest", "many"]).is_err());
+        assert!(parse(&["--permissive", "ignore", "file.s"]).is_err());
+        assert!(parse(&["--riscv-tests"]).is_err());
+        assert!(parse(&["--shared-memory", "game", "file.s"]).is_err());
+        assert!(parse(&["--trace-only", "0x20:0x10", "file.s"]).is_err());
+        assert!(parse(&["--break", "a-b", "file.s"]).is_err());
+        assert!(parse(&["--wat", "file.s"]).is_err());
+
+        let args = parse(&["a.s", "--no-video", "b.s"]).unwrap();
+        assert_eq!(args.files, ["a.s", "b.s"]);
+    }
+}
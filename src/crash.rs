@@ -0,0 +1,81 @@
+//!
+//! Replaces the default panic message with a report of what the simulator was doing when it
+//! crashed: the instruction it was running and the line of code it came from. Without this, a bug
+//! in FPGRARS shows up as a Rust message in the middle of the program's output, which tells the
+//! user nothing about what triggered it.
+//!
+
+use std::io::Write;
+use std::panic::{self, PanicHookInfo};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use crate::simulator::Simulator;
+
+const ISSUES: &str = "https://github.com/LeoRiether/FPGRARS/issues";
+
+/// Address of the instruction the simulator is running. The simulator updates it on every
+/// instruction, so we know where it was if it crashes
+pub static PC: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Each instruction of the program and where it came from, in a format ready to be printed
+static PROGRAM: OnceLock<Vec<String>> = OnceLock::new();
+
+/// How many bytes each instruction of `PROGRAM` takes, which is 2 with `--rvc`
+static SLOT_SIZE: AtomicUsize = AtomicUsize::new(4);
+
+/// Address of the first instruction of `PROGRAM`, see `--text-base`
+static TEXT_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Replaces the panic message of every thread with our report. The process still aborts afterwards,
+/// which also closes the window.
+pub fn install_hook() {
+    panic::set_hook(Box::new(report));
+}
+
+/// Remembers the program, so the report can show the instruction that crashed
+pub fn set_program(sim: &Simulator) {
+    let describe = |i: usize| match sim.code_pos.get(i) {
+        Some(Some(pos)) => format!("{:?}, from {}:{}: {}", sim.code[i], pos.file, pos.line, pos.source.trim()),
+        _ => format!("{:?}", sim.code[i]),
+    };
+
+    let _ = PROGRAM.set((0..sim.code.len()).map(describe).collect());
+    SLOT_SIZE.store(sim.slot_size(), Ordering::Relaxed);
+    TEXT_BASE.store(sim.code_address(0), Ordering::Relaxed);
+}
+
+fn report(info: &PanicHookInfo) {
+    // Whatever the program printed comes before the report
+    let _ = std::io::stdout().flush();
+
+    let message = match info.payload().downcast_ref::<&str>() {
+        Some(s) => s.to_string(),
+        None => match info.payload().downcast_ref::<String>() {
+            Some(s) => s.clone(),
+            None => "unknown error".to_owned(),
+        },
+    };
+
+    let thread = std::thread::current();
+    eprintln!("\nFPGRARS crashed: {}", message);
+    eprintln!("  in the thread `{}`", thread.name().unwrap_or("unnamed"));
+    if let Some(location) = info.location() {
+        eprintln!("  at {}", location);
+    }
+
+    let pc = PC.load(Ordering::Relaxed);
+    if pc != usize::MAX {
+        let slot = SLOT_SIZE.load(Ordering::Relaxed);
+        let i = pc.wrapping_sub(TEXT_BASE.load(Ordering::Relaxed)) / slot;
+        match PROGRAM.get().and_then(|program| program.get(i)) {
+            Some(instruction) => eprintln!("  while running {:#x}: {}", pc, instruction),
+            None => eprintln!("  while running {:#x}", pc),
+        }
+    }
+
+    eprintln!(
+        "\nIf this looks like a bug in FPGRARS, please report it at {} along with your code",
+        ISSUES
+    );
+}
@@ -5,7 +5,7 @@
 //!
 //! Right now I don't aim to implement the instructions too close to what a real RISC-V processor
 //! would execute. For example, there are some pseudoinstructions implemented as real instructions,
-//! it's impossible to make self-modifying code and there's no difference between `jal` and `call`.
+//! self-modifying code needs `--self-modifying` and there's no difference between `jal` and `call`.
 //! Even then, I think these won't make too much of a difference for most users.
 //!
 //! Also note that the simulator cares less about correctness than RARS, so some programs that run
@@ -13,39 +13,267 @@
 //! FPGRARS doesn't care, but RARS complains.
 //!
 
+mod args;
+mod crash;
 mod renderer;
+mod riscv_tests;
 mod simulator;
 mod parser;
 
-use std::env;
 use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::thread;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let sim = simulator::Simulator::new();
+    crash::install_hook();
+
+    let args = match args::Args::from_env() {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(path) = &args.riscv_tests {
+        let all_passed = riscv_tests::run(path)?;
+        std::process::exit(if all_passed { 0 } else { 1 });
+    }
+
+    if let Some(dir) = &args.parse_corpus {
+        let all_parsed = parser::corpus::run(dir)?;
+        std::process::exit(if all_parsed { 0 } else { 1 });
+    }
+
+    if let Some(cases) = args.difftest {
+        let seed = args.seed.unwrap_or_else(rand::random);
+        let all_passed = simulator::difftest::run(cases, seed);
+        std::process::exit(if all_passed { 0 } else { 1 });
+    }
+
+    let mut sim = simulator::Simulator::new()
+        .defines(args.defines)
+        .werror(args.werror)
+        .warn_unused(args.warn_unused)
+        .strict(args.strict)
+        .rv64(args.rv64)
+        .rv32e(args.rv32e)
+        .rvc(args.rvc)
+        .rars_layout(args.rars_layout)
+        .check_jumps(args.check_jumps)
+        .strict_alignment(args.strict_alignment)
+        .readable_code(args.readable_code)
+        .self_modifying(args.self_modifying)
+        .entry(args.entry)
+        .bench(args.bench)
+        .instruction_mix(args.instruction_mix)
+        .profile(args.profile || args.profile_folded.is_some())
+        .breakpoints(args.breakpoints)
+        .debug(args.debug)
+        .tui(args.tui);
+    if let Some(text_base) = args.text_base {
+        sim = sim.text_base(text_base);
+    }
+    if let Some(stack_top) = args.stack_top {
+        sim = sim.stack_top(stack_top);
+    }
+    if let Some(bytes) = args.stack_size {
+        sim = sim.stack_size(bytes);
+    }
+    if let Some(vlen) = args.vlen {
+        sim = sim.vlen(vlen);
+    }
+    if let Some(on_illegal) = args.permissive {
+        sim = sim.permissive(on_illegal);
+    }
+    if let Some(length) = args.history {
+        sim = sim.history(length);
+    }
+    if let Some(path) = args.snapshot {
+        sim = sim.snapshot(path.into());
+    }
+    if let Some(location) = args.save_at {
+        sim = sim.save_at(location);
+    }
+    if let Some(bytes) = args.console_limit {
+        sim = sim.console_limit(bytes);
+    }
+    if let Some(instructions) = args.max_instructions {
+        sim = sim.max_instructions(instructions);
+    }
+    if let Some(time) = args.timeout {
+        sim = sim.timeout(time);
+    }
+    if let Some(path) = &args.trace {
+        let file = match std::fs::File::create(path) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Couldn't create the trace `{}`: {}", path, e);
+                std::process::exit(1);
+            }
+        };
+        sim = sim.trace(Box::new(file), args.trace_only);
+    }
+    if let Some(path) = &args.record {
+        let file = match std::fs::File::create(path) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Couldn't create the recording `{}`: {}", path, e);
+                std::process::exit(1);
+            }
+        };
+        sim = sim.record(Box::new(std::io::BufWriter::new(file)));
+    }
+    if let Some(path) = &args.replay {
+        let recording = match std::fs::read_to_string(path) {
+            Ok(recording) => recording,
+            Err(e) => {
+                eprintln!("Couldn't read the recording `{}`: {}", path, e);
+                std::process::exit(1);
+            }
+        };
+        sim = match sim.replay(&recording) {
+            Ok(sim) => sim,
+            Err(e) => {
+                eprintln!("The recording `{}` is wrong, {}", path, e);
+                std::process::exit(1);
+            }
+        };
+    }
+    if let Some(port) = args.gdb {
+        sim = match sim.gdb(port) {
+            Ok(sim) => sim,
+            Err(e) => {
+                eprintln!("Couldn't listen for GDB on port {}: {}", port, e);
+                std::process::exit(1);
+            }
+        };
+    }
+    if let Some(port) = args.dap {
+        sim = match sim.dap(port) {
+            Ok(sim) => sim,
+            Err(e) => {
+                eprintln!("Couldn't listen for the editor on port {}: {}", port, e);
+                std::process::exit(1);
+            }
+        };
+    }
+    if let Some(window) = &args.shared_memory {
+        sim = match sim.shared_memory(window) {
+            Ok(sim) => sim,
+            Err(e) => {
+                eprintln!("Couldn't map the shared memory `{}`: {}", window.name, e);
+                std::process::exit(1);
+            }
+        };
+    }
+
     let mmio = sim.memory.mmio.clone();
+    let keys = sim.key_queue();
+    let frames = Arc::new(AtomicU64::new(0));
+    let files = args.files;
+    let (list, list_file, symbols) = (args.list, args.list_file, args.symbols);
+    let (no_video, quiet) = (args.no_video || list || symbols, args.quiet);
+    let (bench, instruction_mix) = (args.bench, args.instruction_mix);
+    let (profile, profile_folded) = (args.profile, args.profile_folded);
+    let restore = args.restore;
+    let frames_rendered = frames.clone();
 
-    let mut args: Vec<String> = env::args().skip(1).collect();
-    let file = args.pop().expect("Usage: ./fpgrars [OPTIONS] riscv_file.s");
-
-    thread::Builder::new()
-        .name("FPGRARS Simulator".into())
-        .spawn(move || {
-            let mut sim = match sim.load_from_file(file) {
-                Ok(x) => x,
-                Err(e) => {
-                    eprintln!("An error occurred while parsing your code:\n{:?}", e);
-                    std::process::exit(0);
-                }
-            };
+    let run = move || {
+        let mut sim = match sim.load_from_files(files) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("An error occurred while parsing your code:\n{}", e);
+                std::process::exit(1);
+            }
+        };
+        crash::set_program(&sim);
+
+        for warning in &sim.warnings {
+            eprintln!("{}\n", warning);
+        }
 
-            let start_time = std::time::Instant::now();
-            sim.run();
-            println!("Finished in {}ms", start_time.elapsed().as_millis());
+        if let Some(path) = &list_file {
+            let written = std::fs::File::create(path).and_then(|mut file| sim.write_listing(&mut file));
+            if let Err(e) = written {
+                eprintln!("Couldn't write the listing to `{}`: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        if list || symbols {
+            let mut out = std::io::stdout().lock();
+            if list {
+                let _ = sim.write_listing(&mut out);
+            }
+            if symbols {
+                let _ = sim.write_symbols(&mut out);
+            }
             std::process::exit(0);
-        })?;
+        }
+
+        if let Some(path) = &restore {
+            if let Err(e) = sim.restore(std::path::Path::new(path)) {
+                eprintln!("Couldn't restore the snapshot `{}`: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+
+        let start_time = std::time::Instant::now();
+        let exit_code = sim.run();
+        let elapsed = start_time.elapsed();
+        if !quiet {
+            let frames = if no_video {
+                String::new()
+            } else {
+                format!(", {} frames", frames_rendered.load(Ordering::Relaxed))
+            };
+            eprintln!(
+                "Exited with code {} after {} instructions in {}ms, {} KiB of memory at its peak{}",
+                exit_code,
+                sim.stats.instructions,
+                elapsed.as_millis(),
+                sim.memory.peak_size() / 1024,
+                frames
+            );
+        }
+        if sim.stats.illegal_skipped > 0 {
+            eprintln!("Skipped {} unknown instructions", sim.stats.illegal_skipped);
+        }
+        if sim.output_dropped() > 0 {
+            eprintln!("Dropped {} bytes of output over the --console-limit", sim.output_dropped());
+        }
+        // With the profile, they're a part of it
+        if !sim.stats.regions.is_empty() && !profile {
+            eprint!("{}", sim.stats.regions);
+        }
+        if bench {
+            eprint!("{}", sim.stats.bench.report(sim.stats.instructions, elapsed));
+        }
+        if instruction_mix {
+            eprint!("{}", sim.stats.mix.report(&sim.code));
+        }
+        if profile {
+            let _ = sim.write_profile(&mut std::io::stderr().lock());
+        }
+        if let Some(path) = &profile_folded {
+            let written = std::fs::File::create(path).and_then(|mut file| sim.write_folded_stacks(&mut file));
+            if let Err(e) = written {
+                eprintln!("Couldn't write the folded stacks to `{}`: {}", path, e);
+            }
+        }
+        std::process::exit(exit_code);
+    };
+
+    if no_video {
+        run();
+    } else {
+        thread::Builder::new()
+            .name("FPGRARS Simulator".into())
+            .spawn(run)?;
 
-    renderer::init(mmio);
+        renderer::init(mmio, frames, keys);
+    }
 
     Ok(())
 }
@@ -0,0 +1,282 @@
+//!
+//! Constant expressions, like `3*WIDTH+4` or `(1<<12)-1`. They're evaluated with 64 bit integers,
+//! so intermediate results don't overflow, and the operators have the same precedence as in C.
+//!
+//! Symbols in an expression are labels. `.eqv`s have already been replaced when we get here.
+//!
+
+use nom::{
+    self,
+    branch::alt,
+    bytes::complete::{tag, take_while, take_while1},
+    character::complete::char as the_char,
+    combinator::{map, map_res, recognize, verify},
+    error::ErrorKind,
+    sequence::{delimited, pair, preceded},
+    IResult,
+};
+
+use super::{integer_literal64, shared::*};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    Xor,
+    And,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Num(i64),
+    Symbol(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Evaluates the expression, asking `lookup` for the value of each symbol
+    pub fn eval<F: Fn(&str) -> Option<i64>>(&self, lookup: &F) -> Result<i64, Error> {
+        use BinaryOp::*;
+
+        Ok(match self {
+            Expr::Num(x) => *x,
+            Expr::Symbol(s) => lookup(s).ok_or_else(|| Error::LabelNotFound(s.clone(), None))?,
+            Expr::Unary(UnaryOp::Neg, e) => e.eval(lookup)?.wrapping_neg(),
+            Expr::Unary(UnaryOp::Not, e) => !e.eval(lookup)?,
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(lookup)?, b.eval(lookup)?);
+                match op {
+                    Or => a | b,
+                    Xor => a ^ b,
+                    And => a & b,
+                    Shl => a.wrapping_shl(b as u32),
+                    Shr => a.wrapping_shr(b as u32),
+                    Add => a.wrapping_add(b),
+                    Sub => a.wrapping_sub(b),
+                    Mul => a.wrapping_mul(b),
+                    Div | Rem if b == 0 => return Err(Error::DivisionByZero(self.to_string())),
+                    Div => a.wrapping_div(b),
+                    Rem => a.wrapping_rem(b),
+                }
+            }
+        })
+    }
+
+    /// Evaluates an expression that can't have any symbols
+    pub fn eval_constant(&self) -> Result<i64, Error> {
+        self.eval(&|_| None)
+    }
+
+    pub fn has_symbols(&self) -> bool {
+        match self {
+            Expr::Num(_) => false,
+            Expr::Symbol(_) => true,
+            Expr::Unary(_, e) => e.has_symbols(),
+            Expr::Binary(_, a, b) => a.has_symbols() || b.has_symbols(),
+        }
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use BinaryOp::*;
+        match self {
+            Expr::Num(x) => write!(f, "{}", x),
+            Expr::Symbol(s) => write!(f, "{}", s),
+            Expr::Unary(UnaryOp::Neg, e) => write!(f, "-({})", e),
+            Expr::Unary(UnaryOp::Not, e) => write!(f, "~({})", e),
+            Expr::Binary(op, a, b) => {
+                let op = match op {
+                    Or => "|",
+                    Xor => "^",
+                    And => "&",
+                    Shl => "<<",
+                    Shr => ">>",
+                    Add => "+",
+                    Sub => "-",
+                    Mul => "*",
+                    Div => "/",
+                    Rem => "%",
+                };
+                write!(f, "({} {} {})", a, op, b)
+            }
+        }
+    }
+}
+
+/// Converts the result of an expression into an immediate. Like integer literals, it may be
+/// anything whose magnitude fits in 32 bits, and negative numbers are stored in two's complement.
+pub fn expr_to_u32(x: i64, text: &str) -> Result<u32, Error> {
+    if x.unsigned_abs() > u32::MAX as u64 {
+        return Err(Error::ImmediateOutOfRange(text.to_owned()));
+    }
+    Ok(x as u32)
+}
+
+pub fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'
+}
+
+/// Whitespace between the tokens of an expression. Commas aren't allowed, they separate arguments
+fn ws(s: &str) -> IResult<&str, &str> {
+    take_while(|c: char| c == ' ' || c == '\t')(s)
+}
+
+fn number(s: &str) -> IResult<&str, Expr> {
+    let digits = recognize(pair(
+        take_while1(|c: char| c.is_ascii_digit()),
+        take_while(|c: char| c.is_ascii_alphanumeric()),
+    ));
+    map(map_res(digits, integer_literal64), |x| Expr::Num(x as i64))(s)
+}
+
+fn symbol(s: &str) -> IResult<&str, Expr> {
+    let name = verify(take_while1(is_symbol_char), |name: &str| {
+        !name.starts_with(|c: char| c.is_ascii_digit())
+    });
+    map(name, |name: &str| Expr::Symbol(name.to_owned()))(s)
+}
+
+fn atom(s: &str) -> IResult<&str, Expr> {
+    alt((
+        number,
+        map(quoted_char, |c| Expr::Num(c as i64)),
+        symbol,
+        delimited(pair(the_char('('), ws), expr, pair(ws, the_char(')'))),
+    ))(s)
+}
+
+/// Unary operators can't be chained without parentheses, so `--1` is an error instead of 1
+fn unary(s: &str) -> IResult<&str, Expr> {
+    let op = alt((
+        map(the_char('-'), |_| Some(UnaryOp::Neg)),
+        map(the_char('~'), |_| Some(UnaryOp::Not)),
+        map(the_char('+'), |_| None),
+    ));
+
+    alt((
+        map(pair(op, preceded(ws, atom)), |(op, e)| match op {
+            Some(op) => Expr::Unary(op, Box::new(e)),
+            None => e,
+        }),
+        atom,
+    ))(s)
+}
+
+/// Parses a left associative chain of operators with the same precedence, like `a + b - c`
+fn chain<'a>(
+    s: &'a str,
+    operand: fn(&'a str) -> IResult<&'a str, Expr>,
+    ops: &[(&'static str, BinaryOp)],
+) -> IResult<&'a str, Expr> {
+    let (mut s, mut lhs) = operand(s)?;
+
+    'outer: loop {
+        for &(token, op) in ops {
+            let parsed = preceded(ws, preceded(tag(token), preceded(ws, operand)))(s);
+            if let Ok((rest, rhs)) = parsed {
+                s = rest;
+                lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                continue 'outer;
+            }
+        }
+        return Ok((s, lhs));
+    }
+}
+
+fn product(s: &str) -> IResult<&str, Expr> {
+    use BinaryOp::*;
+    chain(s, unary, &[("*", Mul), ("/", Div), ("%", Rem)])
+}
+
+fn sum(s: &str) -> IResult<&str, Expr> {
+    chain(s, product, &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)])
+}
+
+fn shift(s: &str) -> IResult<&str, Expr> {
+    chain(s, sum, &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)])
+}
+
+fn and(s: &str) -> IResult<&str, Expr> {
+    chain(s, shift, &[("&", BinaryOp::And)])
+}
+
+fn xor(s: &str) -> IResult<&str, Expr> {
+    chain(s, and, &[("^", BinaryOp::Xor)])
+}
+
+/// Parses an expression. Doesn't consume the whitespace after it.
+pub fn expr(s: &str) -> IResult<&str, Expr> {
+    chain(s, xor, &[("|", BinaryOp::Or)])
+}
+
+/// Parses a constant expression and evaluates it
+pub fn constant_expr(s: &str) -> IResult<&str, u32> {
+    let (rest, e) = expr(s)?;
+    let text = &s[..s.len() - rest.len()];
+    match e.eval_constant().and_then(|x| expr_to_u32(x, text)) {
+        Ok(x) => Ok((rest, x)),
+        Err(_) => Err(nom::Err::Error((s, ErrorKind::MapRes))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str) -> Result<i64, ()> {
+        let lookup = |sym: &str| match sym {
+            "WIDTH" => Some(320),
+            "array" => Some(0x1000),
+            _ => None,
+        };
+        match expr(s) {
+            Ok(("", e)) => e.eval(&lookup).map_err(|_| ()),
+            _ => Err(()),
+        }
+    }
+
+    #[test]
+    fn test_expr() {
+        assert_eq!(eval("3*WIDTH+4"), Ok(964));
+        assert_eq!(eval("(1<<12)-1"), Ok(4095));
+        assert_eq!(eval("( 1 << 12 ) - 1"), Ok(4095));
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("0xff & 0x0f | 0x100"), Ok(0x10f));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("-8 >> 1"), Ok(-4));
+        assert_eq!(eval("-(2+3)"), Ok(-5));
+        assert_eq!(eval("~0"), Ok(-1));
+        assert_eq!(eval("array+8"), Ok(0x1008));
+        assert_eq!(eval("'a' + 1"), Ok(98));
+        assert_eq!(eval("7 / 2 % 2"), Ok(1));
+        assert_eq!(eval("1 / 0"), Err(()));
+        assert_eq!(eval("nope + 1"), Err(()));
+        assert_eq!(eval("--1"), Err(()));
+        assert_eq!(eval("(1"), Err(()));
+        assert_eq!(eval("1 +"), Err(()));
+    }
+
+    #[test]
+    fn test_constant_expr() {
+        assert_eq!(constant_expr("0xA(sp)"), Ok(("(sp)", 10)));
+        assert_eq!(constant_expr("2*3 , t0"), Ok((" , t0", 6)));
+        assert_eq!(constant_expr("-(1<<31)"), Ok(("", 0x8000_0000)));
+        assert!(constant_expr("1<<32").is_err());
+        assert!(constant_expr("label+4").is_err());
+    }
+}
@@ -1,51 +1,120 @@
-use nom::{
-    self,
-    bytes::complete::{tag, take_till},
-    character::complete::space0,
-    sequence::{delimited, preceded},
-    IResult,
-};
-
-use super::shared::*;
-
-fn include_tag(s: &str) -> IResult<&str, &str> {
-    delimited(separator0, tag(".include"), separator0)(s)
-}
-
-/// Parses `.include "file.s"` and outputs `file.s`
-pub fn include_directive(s: &str) -> IResult<&str, String> {
-    preceded(include_tag, quoted_string)(s)
-}
-
-/// Strips indentation and removes comments
-pub fn strip_unneeded(s: &str) -> Result<&str, NomErr> {
-    preceded(space0, take_till(|c| c == '#'))(s)
-        .map(|(_i, o)| o)
-        .map(|s| s.trim_end())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_include_directive() {
-        assert_eq!(
-            include_directive(".include \"some file.s\""),
-            Ok(("", "some file.s".to_owned()))
-        );
-        assert_eq!(
-            include_directive(r#"  .include"file\n.s"  "#),
-            Ok(("  ", "file\n.s".to_owned()))
-        );
-    }
-
-    #[test]
-    fn test_strip_unneeded() {
-        assert_eq!(
-            strip_unneeded("     mv x0 x0 # does nothing"),
-            Ok("mv x0 x0"),
-        );
-        assert_eq!(strip_unneeded("j main # Does nothing"), Ok("j main"),)
-    }
-}
+use nom::{
+    self,
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{digit1, space0, space1},
+    combinator::{all_consuming, map, map_res, opt},
+    multi::many0,
+    sequence::{delimited, pair, preceded, terminated, tuple},
+    IResult,
+};
+
+use super::shared::*;
+
+fn include_tag(s: &str) -> IResult<&str, &str> {
+    delimited(separator0, tag(".include"), separator0)(s)
+}
+
+/// Parses `.include "file.s"` and outputs `file.s`
+pub fn include_directive(s: &str) -> IResult<&str, String> {
+    preceded(include_tag, quoted_string)(s)
+}
+
+/// Parses the markers `cpp` leaves in its output, `# 12 "file.s"` or `#line 12 "file.s"`, and
+/// outputs the line number and file the next line came from. The file is optional in `#line`, but
+/// not in `# 12`, so a comment like `# 3 times` isn't taken as a marker. The flags after the file,
+/// like the `2` of `# 1 "file.s" 2`, are ignored.
+pub fn line_marker(s: &str) -> IResult<&str, (usize, Option<String>)> {
+    let number = |s| map_res(digit1, str::parse::<usize>)(s);
+    let file = |s| preceded(space1, quoted_string)(s);
+    let flags = pair(many0(preceded(space1, digit1)), space0);
+
+    let cpp = map(tuple((tag("#"), space1, number, file)), |(_, _, line, file)| (line, Some(file)));
+    let directive = map(
+        tuple((tag("#line"), space1, number, opt(file))),
+        |(_, _, line, file)| (line, file),
+    );
+
+    all_consuming(terminated(alt((directive, cpp)), flags))(s)
+}
+
+/// Strips indentation and removes comments, which are `# ...`, `// ...` or `/* ... */`. The last
+/// one may go on for several lines, so `in_comment` says whether one is still open, and is updated
+/// at the end of the line. A `#` in a string or character, like in `li a0 '#'`, isn't a comment.
+pub fn strip_unneeded(s: &str, in_comment: &mut bool) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(c) = rest.chars().next() {
+        if *in_comment {
+            match rest.find("*/") {
+                Some(end) => {
+                    *in_comment = false;
+                    rest = &rest[end + 2..];
+                    res.push(' '); // so `a/* */b` doesn't become `ab`
+                }
+                None => break,
+            }
+        } else if rest.starts_with('#') || rest.starts_with("//") {
+            break;
+        } else if rest.starts_with("/*") {
+            *in_comment = true;
+            rest = &rest[2..];
+        } else {
+            let len = match c {
+                '"' | '\'' => quoted_len(rest, c),
+                c => c.len_utf8(),
+            };
+            res.push_str(&rest[..len]);
+            rest = &rest[len..];
+        }
+    }
+
+    res.trim().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_include_directive() {
+        assert_eq!(
+            include_directive(".include \"some file.s\""),
+            Ok(("", "some file.s".to_owned()))
+        );
+        assert_eq!(
+            include_directive(r#"  .include"file\n.s"  "#),
+            Ok(("  ", "file\n.s".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_line_marker() {
+        assert_eq!(line_marker("# 12 \"main.s\""), Ok(("", (12, Some("main.s".to_owned())))));
+        assert_eq!(line_marker("# 1 \"lib/io.s\" 1 3"), Ok(("", (1, Some("lib/io.s".to_owned())))));
+        assert_eq!(line_marker("#line 40"), Ok(("", (40, None))));
+        assert_eq!(line_marker("#line 7 \"a.s\""), Ok(("", (7, Some("a.s".to_owned())))));
+
+        assert!(line_marker("# 3 times").is_err());
+        assert!(line_marker("#12 \"main.s\"").is_err());
+        assert!(line_marker("# line 12").is_err());
+    }
+
+    #[test]
+    fn test_strip_unneeded() {
+        let strip = |s| strip_unneeded(s, &mut false);
+        assert_eq!(strip("     mv x0 x0 # does nothing"), "mv x0 x0");
+        assert_eq!(strip("j main # Does nothing"), "j main");
+        assert_eq!(strip("j main // Does nothing"), "j main");
+        assert_eq!(strip("add/* t0 */t1, t2 /* t3 */"), "add t1, t2");
+        assert_eq!(strip("li a0 '#' # a hash"), "li a0 '#'");
+        assert_eq!(strip(".string \"/* #1 */\" // not"), ".string \"/* #1 */\"");
+
+        let mut in_comment = false;
+        let lines = ["nop /* this", "is all", "a comment */ ret", "/**/ecall"];
+        let stripped: Vec<_> = lines.iter().map(|s| strip_unneeded(s, &mut in_comment)).collect();
+        assert_eq!(stripped, ["nop", "", "ret", "ecall"]);
+        assert!(!in_comment);
+    }
+}
@@ -1,138 +1,226 @@
-use nom::{
-    self,
-    branch::alt,
-    bytes::complete::{tag, take_till1, take_while1},
-    character::complete::char as the_char,
-    combinator::{all_consuming, map},
-    multi::separated_list,
-    sequence::{delimited, preceded, terminated, tuple},
-    IResult,
-};
-
-use super::riscv::{one_arg, owned_one_arg};
-use super::shared::*;
-
-fn macro_tag(s: &str) -> IResult<&str, &str> {
-    terminated(tag(".macro"), separator1)(s)
-}
-
-fn arg_list(s: &str) -> IResult<&str, Vec<String>> {
-    separated_list(
-        separator1,
-        preceded(
-            the_char('%'),
-            map(take_till1(|c| is_separator(c) || c == ')'), str::to_owned),
-        ),
-    )(s)
-}
-
-fn parenthesized_arg_list(s: &str) -> IResult<&str, Vec<String>> {
-    alt((
-        delimited(the_char('('), arg_list, the_char(')')), // parenthesis enclosed args
-        map(all_consuming(separator0), |_| vec![]),        // no args
-    ))(s)
-}
-
-/// Parses `.macro NAME(%arg1, %arg2)` into `("NAME", ["arg1", "arg2"])`
-pub fn declare_macro(s: &str) -> IResult<&str, (String, Vec<String>)> {
-    preceded(
-        macro_tag,
-        tuple((
-            owned_one_arg, // name
-            parenthesized_arg_list,
-        )),
-    )(s)
-}
-
-/// Recognizes`.end_macro`
-pub fn end_macro(s: &str) -> bool {
-    all_consuming(terminated(tag(".end_macro"), separator0))(s).is_ok()
-}
-
-fn use_arg_list(s: &str) -> IResult<&str, Vec<String>> {
-    separated_list(
-        separator1,
-        map(take_till1(|c| is_separator(c) || c == ')'), str::to_owned),
-    )(s)
-}
-
-/// Parses either `MACRO(...args)` or `MACRO`. Note that this identifies a line like `nop` as
-/// a macro with no arguments, but if the macro "nop" hasn't been declared, the macro parser
-/// will not identify this as a macro usage and pass the line onwards to the riscv parser
-pub fn macro_use(s: &str) -> IResult<&str, (String, Vec<String>)> {
-    let res = all_consuming(delimited(
-        separator0,
-        alt((
-            // MACRO(...args)
-            tuple((
-                one_arg,
-                delimited(the_char('('), use_arg_list, the_char(')')),
-            )),
-            // MACRO
-            map(one_arg, |a| (a, vec![])),
-        )),
-        separator0,
-    ))(s);
-
-    let (i, (name, args)) = res?;
-    Ok((i, (name.to_owned(), args)))
-}
-
-pub fn declare_eqv(s: &str) -> IResult<&str, (String, String)> {
-    preceded(
-        delimited(separator0, tag(".eqv"), separator1),
-        tuple((
-            owned_one_arg,
-            map(take_while1(|_| true), |tok: &str| tok.trim_end().to_owned()),
-        )),
-    )(s)
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_declare_macro() {
-        assert_eq!(
-            declare_macro(".macro NAME(%arg1, %arg2)").map_err(|_| ()),
-            Ok(("", ("NAME".into(), vec!["arg1".into(), "arg2".into()])))
-        );
-        assert_eq!(
-            declare_macro(".macro NAME").map_err(|_| ()),
-            Ok(("", ("NAME".into(), vec![])))
-        );
-        assert_eq!(
-            declare_macro(".macro MV(%rd %rs1)").map_err(|_| ()),
-            Ok(("", ("MV".into(), vec!["rd".into(), "rs1".into()])))
-        );
-    }
-
-    #[test]
-    fn test_macro_use() {
-        assert_eq!(
-            macro_use("    DO(mv x1 x0)"),
-            Ok((
-                "",
-                ("DO".into(), vec!["mv".into(), "x1".into(), "x0".into()])
-            )),
-        );
-        assert_eq!(macro_use(" MACRO "), Ok(("", ("MACRO".into(), vec![]))));
-        assert_eq!(macro_use(" MACRO()"), Ok(("", ("MACRO".into(), vec![]))));
-
-        // Parsing the label is the job of MacroParser::parse_macro_use
-        // because we need to keep it and pass it to the riscv parser
-        assert_eq!(
-            macro_use("label: DE1(s8,Label.L0)").is_err(),
-            true
-        );
-    }
-
-    #[test]
-    fn test_declare_eqv() {
-        assert_eq!(
-            declare_eqv(".eqv SCREEN_START 0xFF000000"),
-            Ok(("", ("SCREEN_START".into(), "0xFF000000".into())))
-        );
-    }
-}
+use nom::{
+    self,
+    branch::alt,
+    bytes::complete::{tag, take_till1, take_while1},
+    character::complete::char as the_char,
+    combinator::{all_consuming, map, opt},
+    multi::separated_list,
+    sequence::{delimited, preceded, terminated, tuple},
+    IResult,
+};
+
+use super::riscv::{one_arg, owned_one_arg};
+use super::shared::*;
+
+fn macro_tag(s: &str) -> IResult<&str, &str> {
+    terminated(tag(".macro"), separator1)(s)
+}
+
+/// The name of an argument in a macro declaration, and its default value if it has one
+pub type MacroArg = (String, Option<String>);
+
+/// An argument in a macro declaration, like `%reg`, or `%size=4` if it has a default value
+fn declared_arg(s: &str) -> IResult<&str, MacroArg> {
+    let name = take_till1(|c| is_separator(c) || c == ')' || c == '=');
+    let default = preceded(the_char('='), take_till1(|c| is_separator(c) || c == ')'));
+    preceded(
+        the_char('%'),
+        tuple((map(name, str::to_owned), opt(map(default, str::to_owned)))),
+    )(s)
+}
+
+fn arg_list(s: &str) -> IResult<&str, Vec<MacroArg>> {
+    separated_list(separator1, declared_arg)(s)
+}
+
+fn parenthesized_arg_list(s: &str) -> IResult<&str, Vec<MacroArg>> {
+    alt((
+        delimited(the_char('('), arg_list, the_char(')')), // parenthesis enclosed args
+        map(all_consuming(separator0), |_| vec![]),        // no args
+    ))(s)
+}
+
+/// Parses `.macro NAME(%arg1, %arg2=4)` into `("NAME", [("arg1", None), ("arg2", Some("4"))])`
+pub fn declare_macro(s: &str) -> IResult<&str, (String, Vec<MacroArg>)> {
+    preceded(
+        macro_tag,
+        tuple((
+            owned_one_arg, // name
+            parenthesized_arg_list,
+        )),
+    )(s)
+}
+
+/// Recognizes`.end_macro`
+pub fn end_macro(s: &str) -> bool {
+    all_consuming(terminated(tag(".end_macro"), separator0))(s).is_ok()
+}
+
+fn use_arg_list(s: &str) -> IResult<&str, Vec<String>> {
+    separated_list(
+        separator1,
+        map(take_till1(|c| is_separator(c) || c == ')'), str::to_owned),
+    )(s)
+}
+
+/// Parses either `MACRO(...args)` or `MACRO`. Note that this identifies a line like `nop` as
+/// a macro with no arguments, but if the macro "nop" hasn't been declared, the macro parser
+/// will not identify this as a macro usage and pass the line onwards to the riscv parser
+pub fn macro_use(s: &str) -> IResult<&str, (String, Vec<String>)> {
+    let res = all_consuming(delimited(
+        separator0,
+        alt((
+            // MACRO(...args)
+            tuple((
+                one_arg,
+                delimited(the_char('('), use_arg_list, the_char(')')),
+            )),
+            // MACRO
+            map(one_arg, |a| (a, vec![])),
+        )),
+        separator0,
+    ))(s);
+
+    let (i, (name, args)) = res?;
+    Ok((i, (name.to_owned(), args)))
+}
+
+pub fn declare_eqv(s: &str) -> IResult<&str, (String, String)> {
+    preceded(
+        delimited(separator0, tag(".eqv"), separator1),
+        tuple((
+            owned_one_arg,
+            map(take_while1(|_| true), |tok: &str| tok.trim_end().to_owned()),
+        )),
+    )(s)
+}
+
+/// Parses `.set NAME, EXPR` or `.equ NAME, EXPR` into `("NAME", "EXPR")`
+pub fn declare_set(s: &str) -> IResult<&str, (String, String)> {
+    preceded(
+        delimited(separator0, alt((tag(".set"), tag(".equ"))), separator1),
+        tuple((
+            owned_one_arg,
+            map(take_while1(|_| true), |tok: &str| tok.trim_end().to_owned()),
+        )),
+    )(s)
+}
+
+/// A directive of conditional assembly
+#[derive(Debug, PartialEq, Eq)]
+pub enum Conditional {
+    /// `.ifdef NAME`, or `.ifndef NAME` if it's `false`
+    IfDef(String, bool),
+    Else,
+    EndIf,
+}
+
+pub fn conditional(s: &str) -> IResult<&str, Conditional> {
+    let if_def = alt((
+        map(terminated(tag(".ifdef"), separator1), |_| true),
+        map(terminated(tag(".ifndef"), separator1), |_| false),
+    ));
+    let name = terminated(owned_one_arg, separator0);
+
+    all_consuming(preceded(
+        separator0,
+        alt((
+            map(tuple((if_def, name)), |(defined, name)| Conditional::IfDef(name, defined)),
+            map(terminated(tag(".else"), separator0), |_| Conditional::Else),
+            map(terminated(tag(".endif"), separator0), |_| Conditional::EndIf),
+        )),
+    ))(s)
+}
+
+/// A directive that repeats the lines between them
+#[derive(Debug, PartialEq, Eq)]
+pub enum Repeat {
+    /// `.rept COUNT`, with the expression of the count
+    Start(String),
+    /// `.endr`
+    End,
+}
+
+pub fn repeat(s: &str) -> IResult<&str, Repeat> {
+    let count = map(take_while1(|_| true), |count: &str| count.trim_end().to_owned());
+
+    all_consuming(preceded(
+        separator0,
+        alt((
+            map(preceded(terminated(tag(".rept"), separator1), count), Repeat::Start),
+            map(terminated(tag(".endr"), separator0), |_| Repeat::End),
+        )),
+    ))(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_declare_macro() {
+        assert_eq!(
+            declare_macro(".macro NAME(%arg1, %arg2)").map_err(|_| ()),
+            Ok(("", ("NAME".into(), vec![("arg1".into(), None), ("arg2".into(), None)])))
+        );
+        assert_eq!(
+            declare_macro(".macro NAME").map_err(|_| ()),
+            Ok(("", ("NAME".into(), vec![])))
+        );
+        assert_eq!(
+            declare_macro(".macro MV(%rd %rs1)").map_err(|_| ()),
+            Ok(("", ("MV".into(), vec![("rd".into(), None), ("rs1".into(), None)])))
+        );
+        assert_eq!(
+            declare_macro(".macro PUSH(%reg, %size=4)").map_err(|_| ()),
+            Ok(("", ("PUSH".into(), vec![("reg".into(), None), ("size".into(), Some("4".into()))])))
+        );
+    }
+
+    #[test]
+    fn test_macro_use() {
+        assert_eq!(
+            macro_use("    DO(mv x1 x0)"),
+            Ok((
+                "",
+                ("DO".into(), vec!["mv".into(), "x1".into(), "x0".into()])
+            )),
+        );
+        assert_eq!(macro_use(" MACRO "), Ok(("", ("MACRO".into(), vec![]))));
+        assert_eq!(macro_use(" MACRO()"), Ok(("", ("MACRO".into(), vec![]))));
+
+        // Parsing the label is the job of MacroParser::parse_macro_use
+        // because we need to keep it and pass it to the riscv parser
+        assert!(macro_use("label: DE1(s8,Label.L0)").is_err());
+    }
+
+    #[test]
+    fn test_declare_eqv() {
+        assert_eq!(
+            declare_eqv(".eqv SCREEN_START 0xFF000000"),
+            Ok(("", ("SCREEN_START".into(), "0xFF000000".into())))
+        );
+    }
+
+    #[test]
+    fn test_declare_set() {
+        assert_eq!(
+            declare_set(".set OFFSET, OFFSET + 4 "),
+            Ok(("", ("OFFSET".into(), "OFFSET + 4".into())))
+        );
+        assert_eq!(declare_set(".equ SIZE 8"), Ok(("", ("SIZE".into(), "8".into()))));
+        assert!(declare_set(".equiv SIZE 8").is_err());
+        assert!(declare_set(".set SIZE").is_err());
+    }
+
+    #[test]
+    fn test_conditional() {
+        assert_eq!(conditional(".ifdef DEBUG"), Ok(("", Conditional::IfDef("DEBUG".into(), true))));
+        assert_eq!(conditional("  .ifndef DEBUG "), Ok(("", Conditional::IfDef("DEBUG".into(), false))));
+        assert_eq!(conditional(".else"), Ok(("", Conditional::Else)));
+        assert_eq!(conditional(".endif"), Ok(("", Conditional::EndIf)));
+        assert!(conditional(".ifdef").is_err());
+        assert!(conditional(".ifdef A B").is_err());
+        assert!(conditional(".elseif").is_err());
+    }
+}
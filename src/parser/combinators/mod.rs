@@ -1,9 +1,11 @@
-mod shared;
-mod includes;
-mod macros;
-mod riscv;
-
-pub use shared::*;
-pub use includes::*;
-pub use macros::*;
-pub use riscv::*;
\ No newline at end of file
+mod shared;
+mod includes;
+mod macros;
+mod riscv;
+mod expr;
+
+pub use shared::*;
+pub use includes::*;
+pub use macros::*;
+pub use riscv::*;
+pub use expr::*;
\ No newline at end of file
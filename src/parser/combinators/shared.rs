@@ -1,69 +1,96 @@
-use nom::{
-    self,
-    branch::alt,
-    bytes::complete::{escaped_transform, is_not, take_till, take_till1},
-    character::complete::{char as the_char},
-    combinator::{map, value},
-    sequence::{delimited},
-    IResult,
-};
-
-pub type NomErr<'a> = nom::Err<(&'a str, nom::error::ErrorKind)>;
-pub use super::super::util::Error;
-
-pub fn is_separator(c: char) -> bool {
-    c == ',' || c.is_whitespace()
-}
-
-pub fn separator0(s: &str) -> IResult<&str, ()> {
-    map(take_till(|c| !is_separator(c)), |_| ())(s)
-}
-pub fn separator1(s: &str) -> IResult<&str, ()> {
-    map(take_till1(|c| !is_separator(c)), |_| ())(s)
-}
-
-fn transform_escaped_char(c: &str) -> IResult<&str, &str> {
-    alt((
-        value("\\", the_char('\\')),
-        value("\"", the_char('"')),
-        value("\n", the_char('n')),
-        value("\t", the_char('t')),
-    ))(c)
-}
-
-pub fn quoted_string(s: &str) -> IResult<&str, String> {
-    delimited(
-        the_char('"'),
-        escaped_transform(is_not("\"\\"), '\\', transform_escaped_char),
-        the_char('"'),
-    )(s)
-}
-
-// TODO: remove duplicated logic, this is almost the same as fn quoted_string
-pub fn quoted_char(s: &str) -> IResult<&str, char> {
-    let parser = delimited(
-        the_char('\''),
-        escaped_transform(is_not("\'\\"), '\\', transform_escaped_char),
-        the_char('\''),
-    );
-
-    map(parser, |c| c.chars().next().unwrap())(s)
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_quoted_string() {
-        assert_eq!(
-            quoted_string("\"some quoted string\""),
-            Ok(("", "some quoted string".to_owned()))
-        );
-        assert_eq!(
-            quoted_string(r#""escape \"sequences\"\n parsed \t correctly""#),
-            Ok(("", "escape \"sequences\"\n parsed \t correctly".to_owned()))
-        );
-    }
-
+use nom::{
+    self,
+    branch::alt,
+    bytes::complete::{escaped_transform, is_not, take_till, take_till1},
+    character::complete::{char as the_char},
+    combinator::{map, value},
+    sequence::{delimited},
+    IResult,
+};
+
+pub use super::super::util::Error;
+
+pub fn is_separator(c: char) -> bool {
+    c == ',' || c.is_whitespace()
+}
+
+pub fn separator0(s: &str) -> IResult<&str, ()> {
+    map(take_till(|c| !is_separator(c)), |_| ())(s)
+}
+pub fn separator1(s: &str) -> IResult<&str, ()> {
+    map(take_till1(|c| !is_separator(c)), |_| ())(s)
+}
+
+fn transform_escaped_char(c: &str) -> IResult<&str, &str> {
+    alt((
+        value("\\", the_char('\\')),
+        value("\"", the_char('"')),
+        value("'", the_char('\'')),
+        value("\n", the_char('n')),
+        value("\t", the_char('t')),
+        value("\r", the_char('r')),
+        value("\0", the_char('0')),
+    ))(c)
+}
+
+pub fn quoted_string(s: &str) -> IResult<&str, String> {
+    delimited(
+        the_char('"'),
+        escaped_transform(is_not("\"\\"), '\\', transform_escaped_char),
+        the_char('"'),
+    )(s)
+}
+
+// TODO: remove duplicated logic, this is almost the same as fn quoted_string
+pub fn quoted_char(s: &str) -> IResult<&str, char> {
+    let parser = delimited(
+        the_char('\''),
+        escaped_transform(is_not("\'\\"), '\\', transform_escaped_char),
+        the_char('\''),
+    );
+
+    map(parser, |c| c.chars().next().unwrap())(s)
+}
+
+/// Length of the string or character at the start of `s`, including the quotes. If it's not closed,
+/// that's the rest of `s`, and someone else will complain about it.
+pub fn quoted_len(s: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == quote => return i + 1,
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quoted_string() {
+        assert_eq!(
+            quoted_string("\"some quoted string\""),
+            Ok(("", "some quoted string".to_owned()))
+        );
+        assert_eq!(
+            quoted_string(r#""escape \"sequences\"\n parsed \t correctly""#),
+            Ok(("", "escape \"sequences\"\n parsed \t correctly".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_quoted_char() {
+        assert_eq!(quoted_char("'A'"), Ok(("", 'A')));
+        assert_eq!(quoted_char("'\\n', 1"), Ok((", 1", '\n')));
+        assert_eq!(quoted_char("'\\0'"), Ok(("", '\0')));
+        assert_eq!(quoted_char("'\\''"), Ok(("", '\'')));
+        assert_eq!(quoted_char("'\\\\'"), Ok(("", '\\')));
+        assert_eq!(quoted_char("'\"'"), Ok(("", '"')));
+    }
+
 }
\ No newline at end of file
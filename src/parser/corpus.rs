@@ -0,0 +1,192 @@
+//!
+//! Parses every file in a directory, like the material of a course, and counts which instructions
+//! and directives we understood and which we didn't. Nothing runs, this only tells us what the
+//! parser is missing and which of those things block the most files.
+//!
+
+use fnv::{FnvHashMap, FnvHashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::*;
+
+/// The simulator's data segment is about this large, so `.data ADDR` fails like it would there
+const DATA_SIZE: usize = 0x0040_0000;
+
+/// How many lines used an instruction or directive
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Usage {
+    lines: usize,
+    /// Lines we couldn't parse
+    failed: usize,
+    /// Files with at least one line of it we couldn't parse
+    files_failed: usize,
+}
+
+#[derive(Default)]
+struct Report {
+    usage: FnvHashMap<String, Usage>,
+    files: usize,
+    /// Files we couldn't parse, and the first error of each
+    failed: Vec<(PathBuf, Error)>,
+}
+
+/// Parses every `.s` and `.asm` in `dir` and its subdirectories and prints a report.
+/// Returns whether all of them were parsed.
+pub fn run(dir: &str) -> io::Result<bool> {
+    let mut paths = Vec::new();
+    find_files(Path::new(dir), &mut paths)?;
+    paths.sort();
+
+    let mut report = Report::default();
+    for path in &paths {
+        report.check_file(path);
+    }
+
+    report.print();
+    Ok(report.failed.is_empty())
+}
+
+fn find_files(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_files(&path, paths)?;
+        } else if matches!(path.extension().and_then(|e| e.to_str()), Some("s") | Some("asm")) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The instruction or directive of a line. Lines in the `.data` that just continue the previous
+/// directive, like `1, 2, 3`, are counted as `(data)`.
+fn mnemonic(text: &str, in_data: bool) -> Option<String> {
+    let text = match combinators::parse_label(text) {
+        Ok((rest, _)) => rest,
+        Err(_) => text,
+    };
+
+    let token = text.split(combinators::is_separator).find(|t| !t.is_empty())?;
+    if in_data && !token.starts_with('.') {
+        return Some("(data)".to_owned());
+    }
+    Some(token.to_owned())
+}
+
+impl Report {
+    fn check_file(&mut self, path: &Path) {
+        self.files += 1;
+
+        let lines = match file_lines(path) {
+            Ok(lines) => lines,
+            Err(e) => {
+                self.failed.push((path.to_owned(), e));
+                return;
+            }
+        };
+
+        let mut assembler = Assembler::new(&Options::default(), DATA_SIZE, &FnvHashSet::default());
+        let mut first_error = None;
+        let mut failed_here = FnvHashSet::default();
+
+        for line in lines.parse_includes(path.to_owned()).parse_macros() {
+            let mnemonic = mnemonic(&line.text, matches!(assembler.directive, Directive::Data | Directive::ReadOnlyData));
+            let result = assembler.line(&line);
+
+            if let Some(mnemonic) = mnemonic {
+                let usage = self.usage.entry(mnemonic.clone()).or_default();
+                usage.lines += 1;
+                if result.is_err() {
+                    usage.failed += 1;
+                    if failed_here.insert(mnemonic) {
+                        usage.files_failed += 1;
+                    }
+                }
+            }
+
+            if let Err(e) = result {
+                first_error.get_or_insert(e);
+            }
+        }
+
+        // Every line is fine, but the labels could still be wrong
+        if first_error.is_none() {
+            let mut errors = Vec::new();
+            let object = assembler.finish(&mut errors);
+            let ends = (object.code.len() * 4, object.data.len());
+            link_objects(vec![object], 0, ends, &Options::default(), &mut errors, &mut Vec::new());
+            first_error = errors.into_iter().next();
+        }
+
+        if let Some(e) = first_error {
+            self.failed.push((path.to_owned(), e));
+        }
+    }
+
+    fn print(&self) {
+        println!("Parsed {} of {} files", self.files - self.failed.len(), self.files);
+
+        if !self.failed.is_empty() {
+            println!("\nCouldn't parse:");
+            for (path, e) in &self.failed {
+                match e {
+                    Error::OnLine(..) => println!("\n{}", e),
+                    _ => println!("\n{}: {}", path.display(), e),
+                }
+            }
+        }
+
+        // The ones that fail the most come first
+        let mut usage: Vec<_> = self.usage.iter().collect();
+        usage.sort_by(|(a_name, a), (b_name, b)| {
+            (b.files_failed, b.failed, b.lines, a_name).cmp(&(a.files_failed, a.failed, a.lines, b_name))
+        });
+
+        let width = usage.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max(8);
+        println!("\n{:<width$} {:>8} {:>8} {:>14}", "Mnemonic", "Lines", "Failed", "Files failed", width = width);
+        for (name, u) in usage {
+            println!("{:<width$} {:>8} {:>8} {:>14}", name, u.lines, u.failed, u.files_failed, width = width);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corpus() {
+        let dir = std::env::temp_dir().join(format!("fpgrars-corpus-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("week2")).unwrap();
+        std::fs::write(dir.join("good.s"), ".data\nx: .word 1,\n2, 3\n.text\nli t0 1\naddi t0 t0 1").unwrap();
+        std::fs::write(dir.join("week2/bad.asm"), "li t0 1\naddd t0 t0 t0\naddd t1 t1 t1\nj nowhere").unwrap();
+        std::fs::write(dir.join("week2/label.s"), "j nowhere").unwrap();
+        std::fs::write(dir.join("notes.txt"), "addd").unwrap();
+
+        let mut paths = Vec::new();
+        find_files(&dir, &mut paths).unwrap();
+        paths.sort();
+
+        let mut report = Report::default();
+        for path in &paths {
+            report.check_file(path);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(report.files, 3);
+        let failed: Vec<_> = report
+            .failed
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(failed, ["bad.asm", "label.s"]);
+
+        let usage = |name: &str| &report.usage[name];
+        assert_eq!(usage("addd"), &Usage { lines: 2, failed: 2, files_failed: 1 });
+        assert_eq!(usage("li"), &Usage { lines: 2, failed: 0, files_failed: 0 });
+        assert_eq!(usage("(data)").lines, 1);
+        assert_eq!(usage(".word").lines, 1);
+        assert_eq!(usage("j").failed, 0);
+    }
+}
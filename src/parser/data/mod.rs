@@ -1,182 +1,388 @@
-use super::{combinators::*, util::Error};
-
-use nom::{
-    bytes::complete::take_till1, character::complete::char as the_char, multi::separated_list,
-    sequence::preceded,
-};
-
-use byteorder::{ByteOrder, LittleEndian};
-use std::borrow::{Borrow, Cow};
-use std::str::FromStr;
-
-#[derive(Debug, Clone, Copy)]
-pub(super) enum Type {
-    Word,
-    Byte,
-    Half,
-    Align,
-    Asciz,
-    Float,
-}
-
-impl Default for Type {
-    fn default() -> Type {
-        Type::Word
-    }
-}
-
-impl FromStr for Type {
-    type Err = Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        use Type::*;
-        match s {
-            "word" => Ok(Word),
-            "byte" => Ok(Byte),
-            "half" => Ok(Half),
-            "align" | "space" => Ok(Align),
-            "asciz" | "ascii" | "string" => Ok(Asciz),
-            "float" => Ok(Float),
-            _ => Err(Error::UnrecognizedDataType(s.to_owned())),
-        }
-    }
-}
-
-/// Stores the information of a label we found in the `.data` directive, so we can
-/// later populate the memory with the actual label values
-pub(super) struct Label {
-    /// Position in memory
-    pub(super) pos: usize,
-    pub(super) dtype: Type,
-    pub(super) label: String,
-}
-
-fn store_integer(x: u32, data: &mut Vec<u8>, dtype: Type) {
-    use Type::*;
-    match dtype {
-        Byte => {
-            data.push(x as u8);
-        }
-        Half => {
-            let pos = data.len();
-            data.resize(pos + 2, 0);
-            LittleEndian::write_u16(&mut data[pos..], x as u16);
-        }
-        Word => {
-            let pos = data.len();
-            data.resize(pos + 4, 0);
-            LittleEndian::write_u32(&mut data[pos..], x as u32);
-        }
-        Align => {
-            data.resize(data.len() + x as usize, 0);
-        }
-        _ => unreachable!("store_integer should only be called with an integer dtype"),
-    }
-}
-
-/// Pushes a [Label](struct.Label.html) onto a vector and resizes the data accordingly
-fn push_label(labels: &mut Vec<Label>, data: &mut Vec<u8>, dtype: Type, label: &str) {
-    use Type::*;
-
-    let pos = data.len();
-
-    match dtype {
-        Byte => data.resize(pos + 1, 0),
-        Half => data.resize(pos + 2, 0),
-        Word => data.resize(pos + 4, 0),
-        _ => unreachable!("push_label should only be called with byte, half, or word directive"),
-    }
-
-    labels.push(Label {
-        pos,
-        dtype,
-        label: label.to_owned(),
-    });
-}
-
-fn store_token(
-    s: &str,
-    data: &mut Vec<u8>,
-    found_labels: &mut Vec<Label>,
-    dtype: Type,
-) -> Result<(), Error> {
-    use Type::*;
-    match dtype {
-        Byte | Half | Word => match immediate(s) {
-            // .word <immediate>
-            Ok((_, x)) => store_integer(x, data, dtype),
-
-            // might be a .word <label>, might be .word <junk>
-            Err(_) => push_label(found_labels, data, dtype, s),
-        },
-        Align => {
-            let (_, x) = immediate(s)?;
-            store_integer(x, data, dtype);
-        }
-        Float => {
-            let x = match s.parse::<f32>() {
-                Ok(x) => x,
-                Err(e) => return Err(Error::FloatError(e)),
-            };
-
-            let pos = data.len();
-            data.resize(pos + 4, 0);
-            LittleEndian::write_f32(&mut data[pos..], x);
-        }
-        Asciz => {
-            data.extend(s.bytes().chain(Some(b'\0')));
-        }
-    }
-
-    Ok(())
-}
-
-fn directive_to_type(s: &str) -> Result<(&str, Type), Error> {
-    let (i, dir_str) = preceded(the_char('.'), one_arg)(s)?;
-
-    dir_str.parse::<Type>().map(move |dtype| (i, dtype))
-}
-
-fn one_token(dtype: Type) -> impl Fn(&str) -> nom::IResult<&str, Cow<str>> {
-    move |s: &str| {
-        use Type::*;
-        match dtype {
-            Word | Byte | Half | Align | Float => {
-                let (i, parsed) = take_till1(|c| is_separator(c))(s)?;
-                Ok((i, Cow::from(parsed)))
-            }
-            Asciz => {
-                let (i, parsed) = quoted_string(s)?;
-                Ok((i, Cow::from(parsed)))
-            }
-        }
-    }
-}
-
-/// Parses a line in the `.data` directive, puts the desired vales in `data` and
-/// updates the `type` parameter.
-/// If we find something that could be a label, we should store a [Label](struct.Label.html)
-/// so we can calculate the value to put in that position after parsing has been completed.
-pub(super) fn parse_line(
-    s: &str,
-    data: &mut Vec<u8>,
-    found_labels: &mut Vec<Label>,
-    dtype: &mut Type,
-) -> Result<(), Error> {
-    let (s, opt_new_dtype) = match directive_to_type(s) {
-        Ok((rest, new_dtype)) => (rest, Some(new_dtype)),
-        Err(_) => (s, None),
-    };
-
-    if let Some(new_dtype) = opt_new_dtype {
-        *dtype = new_dtype;
-    }
-
-    let (_i, tokens) = separated_list(separator1, one_token(*dtype))(s)?;
-
-    for tok in tokens {
-        store_token(tok.borrow(), data, found_labels, *dtype)?;
-    }
-
-    Ok(())
-}
+use super::{combinators::*, util::Error};
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_till1},
+    character::complete::{char as the_char, one_of, space1},
+    combinator::{all_consuming, not, recognize},
+    multi::{many0, separated_list},
+    sequence::{delimited, pair, preceded, terminated},
+    IResult,
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::borrow::{Borrow, Cow};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) enum Type {
+    #[default]
+    Word,
+    Byte,
+    Half,
+    /// `.dword`, for RV64. Labels in it are still 32-bit addresses, zero extended.
+    Dword,
+    /// `.space N`, which skips N bytes
+    Space,
+    /// `.align N`, which skips bytes until the position is a multiple of 2^N. The position is
+    /// counted from the start of the file's data, which is only aligned to a word.
+    P2Align,
+    /// `.balign N`, which skips bytes until the position is a multiple of N
+    BAlign,
+    Asciz,
+    Float,
+    Double,
+}
+
+impl FromStr for Type {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use Type::*;
+        match s {
+            // The GNU assembler has a few other names for the same things
+            "word" | "long" | "int" | "4byte" => Ok(Word),
+            "byte" => Ok(Byte),
+            "half" | "short" | "hword" | "2byte" => Ok(Half),
+            "dword" | "quad" | "8byte" => Ok(Dword),
+            "space" | "zero" | "skip" => Ok(Space),
+            "align" | "p2align" => Ok(P2Align),
+            "balign" => Ok(BAlign),
+            "asciz" | "ascii" | "string" => Ok(Asciz),
+            "float" | "single" => Ok(Float),
+            "double" => Ok(Double),
+            _ => Err(Error::UnrecognizedDataType(s.to_owned())),
+        }
+    }
+}
+
+/// Stores the information of a label we found in the `.data` directive, so we can
+/// later populate the memory with the actual label values
+pub(super) struct Label {
+    /// Position in memory
+    pub(super) pos: usize,
+    pub(super) dtype: Type,
+    pub(super) label: String,
+}
+
+/// Checks that `x` fits in a `bits`-wide field, either as a signed or unsigned number.
+/// Immediates are parsed as u32, so `-1` arrives here as `0xffffffff`
+pub(super) fn fits_in(x: u32, bits: u32) -> bool {
+    if bits >= 32 {
+        return true;
+    }
+    let signed = x as i32;
+    let min = -(1i64 << (bits - 1));
+    let max = (1i64 << bits) - 1;
+    (min..=max).contains(&(signed as i64)) || (x as i64) <= max
+}
+
+fn store_integer(x: u32, data: &mut Vec<u8>, dtype: Type, token: &str) -> Result<(), Error> {
+    use Type::*;
+    match dtype {
+        Byte => {
+            if !fits_in(x, 8) {
+                return Err(Error::ImmediateOutOfRange(token.to_owned()));
+            }
+            data.push(x as u8);
+        }
+        Half => {
+            if !fits_in(x, 16) {
+                return Err(Error::ImmediateOutOfRange(token.to_owned()));
+            }
+            let pos = data.len();
+            data.resize(pos + 2, 0);
+            LittleEndian::write_u16(&mut data[pos..], x as u16);
+        }
+        Word => {
+            let pos = data.len();
+            data.resize(pos + 4, 0);
+            LittleEndian::write_u32(&mut data[pos..], x);
+        }
+        Space => {
+            data.resize(data.len() + x as usize, 0);
+        }
+        P2Align | BAlign => {
+            let align = alignment(x, dtype, token)?;
+            data.resize(data.len().div_ceil(align) * align, 0);
+        }
+        _ => unreachable!("store_integer should only be called with an integer dtype"),
+    }
+
+    Ok(())
+}
+
+/// How many bytes `.align x` or `.balign x` align to
+fn alignment(x: u32, dtype: Type, token: &str) -> Result<usize, Error> {
+    let align = match dtype {
+        Type::P2Align if x < 16 => 1 << x,
+        Type::BAlign if x.is_power_of_two() && x <= 1 << 15 => x as usize,
+        _ => return Err(Error::ImmediateOutOfRange(token.to_owned())),
+    };
+    Ok(align)
+}
+
+/// If `s` is an `.align`, `.p2align` or `.balign`, how many bytes it aligns to. In the `.text`,
+/// these are the only data directives that make sense.
+pub(super) fn code_alignment(s: &str) -> Option<Result<usize, Error>> {
+    let (rest, dtype) = directive_to_type(s).ok()?;
+    if !matches!(dtype, Type::P2Align | Type::BAlign) {
+        return None;
+    }
+
+    // The fill value and the maximum to skip, like in `.p2align 2, 0, 4`, aren't supported
+    let parsed = preceded(separator0, terminated(immediate, separator0))(rest);
+    Some(match parsed {
+        Ok(("", x)) => alignment(x, dtype, rest.trim()),
+        Ok((junk, _)) => Err(Error::Nom(junk.to_owned())),
+        Err(e) => Err(e.into()),
+    })
+}
+
+/// Pushes a [Label](struct.Label.html) onto a vector and resizes the data accordingly
+fn push_label(labels: &mut Vec<Label>, data: &mut Vec<u8>, dtype: Type, label: &str) {
+    use Type::*;
+
+    let pos = data.len();
+
+    match dtype {
+        Byte => data.resize(pos + 1, 0),
+        Half => data.resize(pos + 2, 0),
+        Word => data.resize(pos + 4, 0),
+        Dword => data.resize(pos + 8, 0),
+        _ => unreachable!("push_label should only be called with byte, half, word or dword directive"),
+    }
+
+    labels.push(Label {
+        pos,
+        dtype,
+        label: label.to_owned(),
+    });
+}
+
+fn store_token(
+    s: &str,
+    data: &mut Vec<u8>,
+    found_labels: &mut Vec<Label>,
+    dtype: Type,
+) -> Result<(), Error> {
+    use Type::*;
+    match dtype {
+        // A literal can have 64 bits here, which the expressions can't
+        Dword if integer_literal64(s).is_ok() => {
+            let pos = data.len();
+            data.resize(pos + 8, 0);
+            LittleEndian::write_u64(&mut data[pos..], integer_literal64(s)?);
+        }
+        Byte | Half | Word | Dword => match all_consuming(expr)(s) {
+            // .word <label>, or an expression with labels, like `.word array+8`
+            Ok((_, e)) if e.has_symbols() => push_label(found_labels, data, dtype, s),
+
+            // .dword <immediate> can take all 64 bits
+            Ok((_, e)) if matches!(dtype, Dword) => {
+                let pos = data.len();
+                data.resize(pos + 8, 0);
+                LittleEndian::write_i64(&mut data[pos..], e.eval_constant()?);
+            }
+
+            // .word <immediate>, or a constant expression
+            Ok((_, e)) => store_integer(expr_to_u32(e.eval_constant()?, s)?, data, dtype, s)?,
+
+            // a malformed number, report it instead of looking for a label named `0x1ffffffff`
+            Err(_) if s.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+') => {
+                let e = integer_literal(s).err();
+                return Err(e.unwrap_or_else(|| Error::InvalidImmediate(s.to_owned())));
+            }
+
+            // might be a .word <label>, might be .word <junk>
+            Err(_) => push_label(found_labels, data, dtype, s),
+        },
+        Space | P2Align | BAlign => {
+            let (_, x) = all_consuming(immediate)(s)?;
+            store_integer(x, data, dtype, s)?;
+        }
+        Float => {
+            let x = match s.parse::<f32>() {
+                Ok(x) => x,
+                Err(e) => return Err(Error::ParseFloat(e)),
+            };
+
+            let pos = data.len();
+            data.resize(pos + 4, 0);
+            LittleEndian::write_f32(&mut data[pos..], x);
+        }
+        Double => {
+            let x = match s.parse::<f64>() {
+                Ok(x) => x,
+                Err(e) => return Err(Error::ParseFloat(e)),
+            };
+
+            let pos = data.len();
+            data.resize(pos + 8, 0);
+            LittleEndian::write_f64(&mut data[pos..], x);
+        }
+        Asciz => {
+            data.extend(s.bytes().chain(Some(b'\0')));
+        }
+    }
+
+    Ok(())
+}
+
+fn directive_to_type(s: &str) -> Result<(&str, Type), Error> {
+    let (i, dir_str) = preceded(the_char('.'), one_arg)(s)?;
+
+    dir_str.parse::<Type>().map(move |dtype| (i, dtype))
+}
+
+/// An operand of `.word`, `.half` or `.byte`. Spaces separate operands, like in `.word 1 2 3`,
+/// but not the ones around an operator, so `.word table + 4` is a single operand and
+/// `.word 1 -2` are still two.
+fn integer_operand(s: &str) -> IResult<&str, &str> {
+    // quoted chars might be separators themselves, like in `.byte ' ', ','`
+    let quoted = terminated(recognize(quoted_char), not(take_till1(is_separator)));
+    let term = alt((quoted, take_till1(is_separator)));
+    let operator = alt((tag("<<"), tag(">>"), recognize(one_of("+-*/%&|^"))));
+    let operation = pair(delimited(space1, operator, space1), &term);
+    let (i, parsed) = recognize(pair(&term, many0(operation)))(s)?;
+    Ok((i, parsed))
+}
+
+fn one_token(dtype: Type) -> impl Fn(&str) -> IResult<&str, Cow<str>> {
+    move |s: &str| {
+        use Type::*;
+        match dtype {
+            Word | Byte | Half | Dword => {
+                let (i, parsed) = integer_operand(s)?;
+                Ok((i, Cow::from(parsed)))
+            }
+            Space | P2Align | BAlign | Float | Double => {
+                // quoted chars might be separators themselves, like in `.byte ' ', ','`
+                let quoted = terminated(recognize(quoted_char), not(take_till1(is_separator)));
+                let (i, parsed) = alt((quoted, take_till1(is_separator)))(s)?;
+                Ok((i, Cow::from(parsed)))
+            }
+            Asciz => {
+                let (i, parsed) = quoted_string(s)?;
+                Ok((i, Cow::from(parsed)))
+            }
+        }
+    }
+}
+
+/// Parses a line in the `.data` directive, puts the desired vales in `data` and
+/// updates the `type` parameter.
+/// If we find something that could be a label, we should store a [Label](struct.Label.html)
+/// so we can calculate the value to put in that position after parsing has been completed.
+pub(super) fn parse_line(
+    s: &str,
+    data: &mut Vec<u8>,
+    found_labels: &mut Vec<Label>,
+    dtype: &mut Type,
+) -> Result<(), Error> {
+    let (s, opt_new_dtype) = match directive_to_type(s) {
+        Ok((rest, new_dtype)) => (rest, Some(new_dtype)),
+        Err(_) => (s, None),
+    };
+
+    if let Some(new_dtype) = opt_new_dtype {
+        *dtype = new_dtype;
+    }
+
+    let (_i, tokens) = separated_list(separator1, one_token(*dtype))(s)?;
+
+    for tok in tokens {
+        store_token(tok.borrow(), data, found_labels, *dtype)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        let mut labels = Vec::new();
+        parse_line(s, &mut data, &mut labels, &mut Type::default())?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_integer_literals() {
+        assert_eq!(
+            parse(".byte 0x1F, 0b1010, 0o17, 'A', -1").ok(),
+            Some(vec![31, 10, 15, 65, 255])
+        );
+        assert_eq!(parse(".byte ' ', ','").ok(), Some(vec![32, 44]));
+        assert_eq!(parse(".half -32768, 0xffff").ok(), Some(vec![0, 0x80, 0xff, 0xff]));
+        assert_eq!(parse(".word -0b1").ok(), Some(vec![0xff; 4]));
+    }
+
+    #[test]
+    fn test_expressions() {
+        assert_eq!(parse(".word 3*320+4").ok(), Some(vec![0xc4, 0x03, 0, 0]));
+        assert_eq!(parse(".byte (1<<4)-1, 'a'+1").ok(), Some(vec![15, 98]));
+        assert_eq!(parse(".half 0x1234&0xff00|1").ok(), Some(vec![0x01, 0x12]));
+        assert!(matches!(parse(".word 1<<32"), Err(Error::ImmediateOutOfRange(_))));
+        assert!(matches!(parse(".word 1/0"), Err(Error::DivisionByZero(_))));
+
+        let mut labels = Vec::new();
+        parse_line(".word array+8", &mut Vec::new(), &mut labels, &mut Type::Word).unwrap();
+        assert_eq!(labels[0].label, "array+8");
+    }
+
+    #[test]
+    fn test_spaced_expressions() {
+        assert_eq!(parse(".word 3 * 320 + 4").ok(), Some(vec![0xc4, 0x03, 0, 0]));
+        assert_eq!(parse(".byte 1 -2, 'a' + 1").ok(), Some(vec![1, 0xfe, 98]));
+        assert_eq!(parse(".half 1 2\t<<\t8").ok(), Some(vec![1, 0, 0, 2]));
+
+        // a jump table, with the labels resolved when linking
+        let mut data = Vec::new();
+        let mut labels = Vec::new();
+        let line = ".word case0, table + 4, end - start";
+        parse_line(line, &mut data, &mut labels, &mut Type::Word).unwrap();
+        assert_eq!(data.len(), 12);
+        let labels: Vec<_> = labels.iter().map(|l| (l.pos, l.label.as_str())).collect();
+        assert_eq!(labels, [(0, "case0"), (4, "table + 4"), (8, "end - start")]);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        assert!(matches!(parse(".byte 256"), Err(Error::ImmediateOutOfRange(_))));
+        assert!(matches!(parse(".byte -129"), Err(Error::ImmediateOutOfRange(_))));
+        assert!(matches!(parse(".half 0x10000"), Err(Error::ImmediateOutOfRange(_))));
+        assert!(matches!(parse(".word 0x100000000"), Err(Error::ImmediateOutOfRange(_))));
+        assert!(matches!(parse(".word 0b12"), Err(Error::InvalidImmediate(_))));
+    }
+
+    #[test]
+    fn test_gnu_directives() {
+        assert_eq!(parse(".short 1\n").ok(), Some(vec![1, 0]));
+        assert_eq!(parse(".2byte 1, 2").ok(), Some(vec![1, 0, 2, 0]));
+        assert_eq!(parse(".long 1").ok(), Some(vec![1, 0, 0, 0]));
+        assert_eq!(parse(".double 1.5").ok(), Some(1.5f64.to_le_bytes().to_vec()));
+        assert_eq!(parse(".dword -2").ok(), Some((-2i64).to_le_bytes().to_vec()));
+        assert_eq!(parse(".quad 0x123456789abcdef0").ok(), Some(0x1234_5678_9abc_def0u64.to_le_bytes().to_vec()));
+        assert_eq!(parse(".8byte 1 << 4").ok(), Some(16u64.to_le_bytes().to_vec()));
+        assert_eq!(parse(".zero 3").ok(), Some(vec![0; 3]));
+        assert_eq!(parse(".string \"ab\"").ok(), Some(vec![b'a', b'b', 0]));
+        assert_eq!(parse(".string \"é─\"").ok(), Some(vec![0xc3, 0xa9, 0xe2, 0x94, 0x80, 0]));
+
+        let mut data = vec![1];
+        parse_line(".p2align 2", &mut data, &mut Vec::new(), &mut Type::default()).unwrap();
+        assert_eq!(data.len(), 4);
+        parse_line(".align 3", &mut data, &mut Vec::new(), &mut Type::default()).unwrap();
+        assert_eq!(data.len(), 8);
+        parse_line(".balign 8", &mut data, &mut Vec::new(), &mut Type::default()).unwrap();
+        assert_eq!(data.len(), 8);
+        assert!(parse(".balign 3").is_err());
+
+        assert_eq!(code_alignment(".p2align 4").map(Result::ok), Some(Some(16)));
+        assert_eq!(code_alignment(".balign 8 ").map(Result::ok), Some(Some(8)));
+        assert!(matches!(code_alignment(".align 2, 0"), Some(Err(_))));
+        assert!(code_alignment(".word 4").is_none());
+    }
+}
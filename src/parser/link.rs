@@ -0,0 +1,727 @@
+//!
+//! Puts several files together into a single program. Each file is parsed on its own, with its own
+//! labels, and only the labels declared with `.globl` (or `.global`) can be used by the other files:
+//!
+//! ```
+//! # main.s                    # lib.s
+//! .text                       .globl square
+//!     li a0, 5                square:
+//!     call square                 mul a0, a0, a0
+//!                                 ret
+//! ```
+//!
+//! The code and the data of the files are placed one after the other, in the order they were given.
+//! After them comes the space reserved by `.extern label size`, whose labels are also global. Many
+//! files can declare the same `.extern`, and they'll all refer to the same space.
+//!
+//! A `call` or `tail` is a `jal`, which only reaches 1 MiB away. The ones whose label turns out to
+//! be further are only found here, once every label has its address, and then all files are
+//! assembled again with an `auipc` and a `jalr` in their place. That moves the code after them,
+//! so it goes on until every call reaches its label.
+//!
+
+use fnv::{FnvHashMap, FnvHashSet};
+use radix_trie::Trie;
+use std::cell::RefCell;
+use std::ops::Range;
+use std::sync::Arc;
+
+use super::*;
+
+/// A file that was parsed, but whose labels weren't resolved yet
+pub(super) struct Object {
+    pub code: Vec<PreLabelInstruction>,
+    /// Where each instruction came from
+    pub code_pos: Vec<Arc<Pos>>,
+    pub data: Vec<u8>,
+    pub data_labels: Vec<data::Label>,
+    /// Where each `.data` label came from
+    pub data_label_pos: Vec<Arc<Pos>>,
+    pub read_only: Vec<Range<usize>>,
+    pub labels: Trie<String, usize>,
+    /// Where each label was defined
+    pub label_pos: FnvHashMap<String, Arc<Pos>>,
+    /// The labels in the order they were defined, to show to the user
+    pub symbols: Vec<Symbol>,
+    /// Names declared with `.globl`. They may be defined in another file, too.
+    pub globals: Vec<String>,
+    /// Labels declared with `.extern`, and their size in bytes
+    pub externs: Vec<(String, usize)>,
+    /// The warnings found while assembling the file
+    pub warnings: Vec<Error>,
+}
+
+/// The labels a file can see: its own, and the global ones of every file
+pub(super) struct Symbols<'a> {
+    local: &'a Trie<String, usize>,
+    global: &'a Trie<String, usize>,
+    /// The labels that were looked up, to find the ones nobody uses
+    used: RefCell<FnvHashSet<String>>,
+}
+
+impl Symbols<'_> {
+    pub fn get(&self, label: &str) -> Option<usize> {
+        let label = label.to_owned();
+        let pos = self.local.get(&label).or_else(|| self.global.get(&label)).copied();
+        if pos.is_some() {
+            self.used.borrow_mut().insert(label);
+        }
+        pos
+    }
+
+    /// Adds the label the user probably meant to a [LabelNotFound](enum.Error.html#variant.LabelNotFound)
+    fn suggest(&self, e: Error) -> Error {
+        match e {
+            Error::LabelNotFound(label, None) => {
+                let labels = self.local.keys().chain(self.global.keys()).map(String::as_str);
+                let suggestion = closest(&label, labels).map(str::to_owned);
+                Error::LabelNotFound(label, suggestion)
+            }
+            e => e,
+        }
+    }
+}
+
+/// Parses several files (already preprocessed, like in [parse_riscv](trait.RISCVParser.html#tymethod.parse_riscv))
+/// and links them into a single program. The `data_segment_size` is the size of the data of all
+/// files together.
+///
+/// Doesn't stop at the first error, so we can report everything that's wrong at once.
+pub fn link<F, I>(files: F, data_segment_size: usize, options: &Options) -> ParseResult
+where
+    F: IntoIterator<Item = I>,
+    I: Iterator<Item = Line>,
+{
+    let files: Vec<Vec<Line>> = files.into_iter().map(Iterator::collect).collect();
+    let mut far_calls = FnvHashSet::default();
+    loop {
+        let mut errors = Vec::new();
+        let mut found = Vec::new();
+        let mut parsed = assemble(&files, data_segment_size, options, &far_calls, &mut errors, &mut found);
+        if !found.is_empty() {
+            far_calls.extend(found.into_iter().map(|pos| (*pos).clone()));
+            continue;
+        }
+
+        if options.werror {
+            errors.append(&mut parsed.warnings);
+        }
+        return match Error::many(errors) {
+            Some(e) => Err(e),
+            None => Ok(parsed),
+        };
+    }
+}
+
+/// Assembles and links `files` once, with the calls in `far_calls` as an `auipc` and a `jalr`. The
+/// ones that are still too far for a `jal` are pushed into `found`.
+fn assemble(
+    files: &[Vec<Line>],
+    data_segment_size: usize,
+    options: &Options,
+    far_calls: &FnvHashSet<Pos>,
+    errors: &mut Vec<Error>,
+    found: &mut Vec<Arc<Pos>>,
+) -> Parsed {
+    let mut objects = Vec::new();
+    let mut options = options.clone();
+    let data_end = options.data_base + data_segment_size;
+
+    for file in files {
+        let mut assembler = Assembler::new(&options, data_end, far_calls);
+        for line in file {
+            if let Err(e) = assembler.line(line) {
+                errors.push(e);
+            }
+        }
+
+        let object = assembler.finish(errors);
+        options.text_base += object.code.len() * options.slot_size();
+        options.data_base += object.data.len();
+        objects.push(object);
+    }
+
+    let ends = (options.text_base, options.data_base);
+    link_objects(objects, data_segment_size, ends, &options, errors, found)
+}
+
+/// Puts together files that were already assembled. `ends` is where the code and the data of the
+/// last one end. The program starts at [Options::entry](struct.Options.html#structfield.entry), if
+/// there's one, with `rvc` the code is made of 2-byte slots, and with `warn_unused`, the labels
+/// nothing refers to are warnings. The errors are pushed into `errors`, and the instructions with
+/// labels we couldn't resolve become `Illegal`. The calls too far for a `jal` are pushed into
+/// `far_calls`, and still become one.
+pub(super) fn link_objects(
+    objects: Vec<Object>,
+    data_segment_size: usize,
+    (text_end, data_end): (usize, usize),
+    options: &Options,
+    errors: &mut Vec<Error>,
+    far_calls: &mut Vec<Arc<Pos>>,
+) -> Parsed {
+    let (entry, rvc, warn_unused) = (options.entry.as_deref(), options.rvc, options.warn_unused);
+    // The same `.extern` may be declared in many files, and gets the largest size it was declared with
+    let mut externs: Vec<(String, usize)> = Vec::new();
+    for (name, size) in objects.iter().flat_map(|object| &object.externs) {
+        match externs.iter_mut().find(|(other, _)| other == name) {
+            Some((_, other_size)) => *other_size = (*other_size).max(*size),
+            None => externs.push((name.clone(), *size)),
+        }
+    }
+
+    let mut symbols: Vec<_> = objects.iter().flat_map(|object| object.symbols.iter().cloned()).collect();
+    let start = find_entry(&symbols, entry, errors);
+    let mut globals = Trie::new();
+    let mut extern_pos = data_end;
+    for (name, size) in externs {
+        symbols.push(Symbol {
+            name: name.clone(),
+            address: extern_pos,
+            segment: Segment::Extern,
+            global: true,
+        });
+        globals.insert(name, extern_pos);
+        extern_pos += size.div_ceil(4) * 4;
+    }
+
+    for object in &objects {
+        for name in &object.globals {
+            let pos = match object.labels.get(name) {
+                Some(&pos) => pos,
+                None => continue, // defined in some other file
+            };
+
+            if globals.insert(name.clone(), pos).is_some() {
+                errors.push(Error::DuplicateGlobal(name.clone()));
+            }
+        }
+    }
+
+    let mut code = Vec::new();
+    let mut code_pos = Vec::new();
+    let mut data = Vec::with_capacity(data_segment_size);
+    let mut read_only = Vec::new();
+    let mut warnings = Vec::new();
+    let slot_size = if rvc { 2 } else { 4 };
+    let mut pc = text_end - objects.iter().map(|object| object.code.len()).sum::<usize>() * slot_size;
+
+    for object in objects {
+        let symbols = Symbols {
+            local: &object.labels,
+            global: &globals,
+            used: RefCell::default(),
+        };
+        warnings.extend(object.warnings);
+
+        let mut object_data = object.data;
+        for (data_label, pos) in object.data_labels.into_iter().zip(&object.data_label_pos) {
+            match unlabel_data(data_label, &mut object_data, &symbols) {
+                Ok(Some(warning)) => warnings.push(Error::Warning(warning).at(pos, &pos.source)),
+                Ok(None) => {}
+                Err(e) => errors.push(symbols.suggest(e).at(pos, &pos.source)),
+            }
+        }
+        data.extend(object_data);
+        read_only.extend(object.read_only);
+
+        for (instruction, pos) in object.code.into_iter().zip(object.code_pos) {
+            if let PreLabelInstruction::Call(_, label) = &instruction {
+                if resolve_label(label, &symbols).is_ok_and(|target| !text::jal_reaches(pc, target as usize)) {
+                    far_calls.push(pos.clone());
+                }
+            }
+            match unlabel_instruction(instruction, pc, &symbols) {
+                Ok(instruction) => code.push(instruction),
+                Err(e) => {
+                    errors.push(symbols.suggest(e).at(&pos, &pos.source));
+                    code.push(Instruction::Illegal(pos.source.clone()));
+                }
+            }
+            code_pos.push(Some(pos));
+            pc += slot_size;
+        }
+
+        let used = symbols.used.into_inner();
+        for symbol in &object.symbols {
+            let is_entry = symbol.name == "main" || symbol.name == "_start" || Some(symbol.name.as_str()) == entry;
+            if !warn_unused || symbol.global || is_entry || used.contains(&symbol.name) {
+                continue;
+            }
+            if let Some(pos) = object.label_pos.get(&symbol.name) {
+                let warning = Warning::UnusedLabel(symbol.name.clone());
+                warnings.push(Error::Warning(warning).at(pos, &pos.source));
+            }
+        }
+    }
+
+    if rvc {
+        check_compressed(&code, &code_pos, text_end - code.len() * 2, errors);
+    }
+
+    // If the program ever drops off bottom, we make an "exit" ecall and terminate execution
+    let exit = [
+        Instruction::Li(17, 10), // li a7 10
+        Instruction::Ecall,
+    ];
+    for instruction in exit {
+        code.push(instruction);
+        code_pos.push(None);
+        if rvc {
+            code.push(Instruction::padding());
+            code_pos.push(None);
+        }
+    }
+
+    data.resize(data_segment_size, 0);
+    Parsed { code, code_pos, data, read_only, symbols, warnings, entry: start, exit: text_end, data_end: extern_pos }
+}
+
+/// Checks that the compressed instructions of RVC code, which start at `text_base`, fit in 2 bytes.
+/// Those are the ones that aren't followed by a [padding](enum.Instruction.html#method.padding).
+/// The `Illegal` ones are either a `.half` or were already reported.
+fn check_compressed(code: &[Instruction], code_pos: &[Option<Arc<Pos>>], text_base: usize, errors: &mut Vec<Error>) {
+    for (i, (instruction, pos)) in code.iter().zip(code_pos).enumerate() {
+        let compressed = !instruction.is_padding() && !code.get(i + 1).is_some_and(Instruction::is_padding);
+        if !compressed || matches!(instruction, Instruction::Illegal(_)) {
+            continue;
+        }
+        if text::compress(instruction, text_base + i * 2).is_none() {
+            let e = Error::NotCompressible(instruction.to_string());
+            match pos {
+                Some(pos) => errors.push(e.at(pos, &pos.source)),
+                None => errors.push(e),
+            }
+        }
+    }
+}
+
+/// Where the program starts: at the `entry` label, or at a global `main` if there's no `entry`.
+/// A global label comes before the ones of each file with the same name.
+fn find_entry(symbols: &[Symbol], entry: Option<&str>, errors: &mut Vec<Error>) -> Option<usize> {
+    let in_text = symbols.iter().filter(|symbol| symbol.segment == Segment::Text);
+    let label = match entry {
+        Some(label) => label,
+        None => {
+            let mut globals = in_text.filter(|symbol| symbol.global);
+            return globals.find(|symbol| symbol.name == "main").map(|symbol| symbol.address);
+        }
+    };
+
+    let found = in_text.clone().filter(|symbol| symbol.name == label).min_by_key(|symbol| !symbol.global);
+    if found.is_none() {
+        let suggestion = closest(label, in_text.map(|symbol| symbol.name.as_str())).map(str::to_owned);
+        errors.push(Error::LabelNotFound(label.to_owned(), suggestion));
+    }
+    found.map(|symbol| symbol.address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn lines(s: &str) -> impl Iterator<Item = String> + '_ {
+        s.lines().map(str::to_owned)
+    }
+
+    fn link_files(files: &[&str]) -> ParseResult {
+        let files = files.iter().enumerate().map(|(i, f)| {
+            lines(f)
+                .parse_includes(PathBuf::from(format!("file{}.s", i)))
+                .parse_macros()
+        });
+        link(files, 64, &Options::default())
+    }
+
+    #[test]
+    fn test_link() {
+        use Instruction::*;
+
+        let main = ".data\nx: .word square\n.text\nstart: call square\nj start";
+        let lib = ".globl square\n.data\ny: .word 7\n.text\nsquare: la t0 y\nstart: ret";
+
+        // `la` is an `auipc` and an `addi`, so the `start` after it is 8 bytes later
+        let Parsed { code, data, symbols, .. } = link_files(&[main, lib]).unwrap();
+        assert_eq!(code[..5], [Jal(1, 8), Jal(0, 0), Auipc(5, 0), Addi(5, 5, (-4i32) as u32), Ret]);
+        assert_eq!(data[..8], [8, 0, 0, 0, 7, 0, 0, 0]);
+        let starts: Vec<_> = symbols.iter().filter(|s| s.name == "start").map(|s| s.address).collect();
+        assert_eq!(starts, [0, 16]);
+
+        // Labels that aren't global can't be seen from other files
+        let lib = ".text\nsquare: ret";
+        let is_not_found = |e: &Error| {
+            matches!(e, Error::OnLine(_, e) if matches!(**e, Error::LabelNotFound(..)))
+        };
+        assert!(matches!(
+            link_files(&[main, lib]),
+            Err(Error::Many(errors)) if errors.len() == 2 && errors.iter().all(is_not_found)
+        ));
+
+        let lib = ".globl square, other\nsquare: ret";
+        assert!(link_files(&[main, lib]).is_ok());
+        assert!(matches!(
+            link_files(&[main, lib, lib]),
+            Err(Error::DuplicateGlobal(_))
+        ));
+    }
+
+    #[test]
+    fn test_far_calls() {
+        use Instruction::*;
+
+        // The `tail` uses `t1`, and the `call` after it still fits in a `jal`, 4 bytes later
+        let main = "call far\ntail far\nback: call back\n.text 0x200000\nfar: ret";
+        let Parsed { code, symbols, .. } = link_files(&[main]).unwrap();
+        assert_eq!(
+            code[..5],
+            [Auipc(1, 0x20_0000), Jalr(1, 1, 0), Auipc(6, 0x20_0000), Jalr(0, 6, (-8i32) as u32), Jal(1, 16)]
+        );
+        assert_eq!(symbols.iter().find(|s| s.name == "back").map(|s| s.address), Some(16));
+        assert_eq!(code[0x8_0000], Ret);
+
+        // A global label of another file, whose code moves once the call takes two instructions.
+        // The `j` is 4 bytes short of 1 MiB away, so it stays a `jal`.
+        let main = "call square\nj square";
+        let lib = ".globl square\nnop\n.text 0x100004\nsquare: ret";
+        let Parsed { code, .. } = link_files(&[main, lib]).unwrap();
+        assert_eq!(code[..4], [Auipc(1, 0x10_0000), Jalr(1, 1, 4), Jal(0, 0x10_0004), Mv(0, 0)]);
+    }
+
+    #[test]
+    fn test_pseudo_addresses() {
+        use Instruction::*;
+
+        // Each of these is two instructions, whatever the value of the label is
+        let main = ".data\nx: .word 1\n.text\nstart: la t0, x\nli t1, end - start\nlw t2, x\nsw t2, x, t3\nend: j end";
+        let Parsed { code, symbols, .. } = link_files(&[main]).unwrap();
+        let address = |name: &str| symbols.iter().find(|s| s.name == name).unwrap().address;
+        assert_eq!(address("end"), 32);
+        assert_eq!(code[2..4], [Li(6, 0), Addi(6, 6, 32)]);
+        assert_eq!(code[4..8], [Auipc(7, 0), Lw(7, (-16i32) as u32, 7), Auipc(28, 0), Sw(7, (-24i32) as u32, 28)]);
+        assert_eq!(code[8], Jal(0, 32));
+
+        // The lower bits are sign extended, so the upper ones are rounded up when they're negative
+        let main = ".data\n.space 0x808\nx: .word 1\n.text\nli t0, x\nla t1, x";
+        let Parsed { code, .. } = link_files(&[main]).unwrap();
+        assert_eq!(code[..4], [Li(5, 0x1000), Addi(5, 5, (-0x7f8i32) as u32), Auipc(6, 0x1000), Addi(6, 6, (-0x800i32) as u32)]);
+    }
+
+    #[test]
+    fn test_extern() {
+        use Instruction::*;
+
+        let main = ".extern buffer 6\n.extern count 4\n.data\nx: .word 1\n.text\nla t0 buffer\nla t1 count";
+        let lib = ".data\ny: .byte 2\n.text\n.extern count, 2\nlw t2 count(zero)";
+
+        let Parsed { code, code_pos, .. } = link_files(&[main, lib]).unwrap();
+        assert_eq!(code[..5], [Auipc(5, 0), Addi(5, 5, 8), Auipc(6, 0), Addi(6, 6, 8), Lw(7, 16, 0)]);
+
+        let lines: Vec<_> = code_pos
+            .iter()
+            .map(|pos| pos.as_ref().map(|pos| (pos.file.as_str(), pos.line)))
+            .collect();
+        let expected = [6, 6, 7, 7].map(|line| Some(("file0.s", line)));
+        assert_eq!(lines, [&expected[..], &[Some(("file1.s", 5)), None, None]].concat());
+
+        let lib = ".globl count\ncount: nop";
+        assert!(matches!(
+            link_files(&[main, lib]),
+            Err(Error::DuplicateGlobal(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let main = ".data\nx: .word 1\n.section .rodata, \"a\"\ny: .word 2\n.byte 3\n.section .text\nla t0 y";
+        let lib = ".section .rodata\nz: .word 4\n.section .bss\nw: .space 4";
+
+        let Parsed { code, data, read_only, .. } = link_files(&[main, lib]).unwrap();
+        assert_eq!(code[..2], [Instruction::Auipc(5, 0), Instruction::Addi(5, 5, 4)]);
+        assert_eq!(data[..16], [1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0]);
+        assert_eq!(read_only, [4..9, 12..16]);
+    }
+
+    #[test]
+    fn test_text_words() {
+        use Instruction::*;
+
+        let Parsed { code, .. } = link_files(&["nop\n.word 0x00000013, 0xfe029ee3\n.word -1"]).unwrap();
+        assert_eq!(
+            code[..4],
+            [Mv(0, 0), Addi(0, 0, 0), Bne(5, 0, 4), Illegal(".word 0xffffffff".to_owned())]
+        );
+
+        assert!(link_files(&[".text\n.word label\nlabel: nop"]).is_err());
+    }
+
+    #[test]
+    fn test_segment_addresses() {
+        use Instruction::*;
+
+        let main = ".data\nx: .byte 1\n.data 0x10\ny: .byte 2\n.text 8\nstart: la t0 y\n.data 0x20\n.text 0x10\nret";
+        let Parsed { code, code_pos, data, .. } = link_files(&[main]).unwrap();
+        let zero = || Illegal(".word 0x00000000".to_owned());
+        assert_eq!(code[..5], [zero(), zero(), Auipc(5, 0), Addi(5, 5, 8), Ret]);
+        assert_eq!(code_pos[0].as_ref().unwrap().line, 5);
+        assert_eq!((data[0], data[0x10]), (1, 2));
+
+        let error = |file: &str| link_files(&[file]).err().unwrap().to_string();
+        assert_eq!(
+            error(".data\n.word 1, 2\n.data 4"),
+            "file0.s:3:7: can't go to the address `4`, the .data is already at 0x8\n3 | .data 4\n  |       ^"
+        );
+        assert_eq!(
+            error(".data 0x10010000"),
+            "file0.s:1:7: can't go to the address `0x10010000`, the data segment goes from 0x0 to 0x40\n\
+             1 | .data 0x10010000\n  |       ^"
+        );
+        assert!(link_files(&[".text 6"]).is_err());
+        assert!(link_files(&[".text 0x00400000"]).is_err());
+        assert!(link_files(&[".text foo"]).is_err());
+        assert!(link_files(&[".database\n.textual\nnop"]).is_ok());
+    }
+
+    #[test]
+    fn test_gcc_output() {
+        use Instruction::*;
+
+        let main = "\t.file\t\"main.c\"\n\t.option nopic\n\t.attribute arch, \"rv32i2p1_m2p0\"\n\
+                    \t.text\n\t.align\t2\n\t.globl\tmain\n\t.type\tmain, @function\nmain:\n\
+                    \t.cfi_startproc\n\tla\ta5,.LC1\n\tnop\n\t.p2align 4\n\
+                    loop:\n\tj loop\n\t.cfi_endproc\n\t.size\tmain, .-main\n\
+                    \t.section\t.rodata\n\t.align\t2\n.LC0:\n\t.byte 1\n\t.p2align 2\n.LC1:\n\t.string\t\"hi\"\n\
+                    \t.zero\t2\n\t.bss\n\t.ident\t\"GCC: (GNU) 13.2.0\"\n\
+                    \t.section\t.note.GNU-stack,\"\",@progbits";
+
+        let Parsed { code, data, read_only, .. } = link_files(&[main]).unwrap();
+        assert_eq!(code[..5], [Auipc(15, 0), Addi(15, 15, 4), Mv(0, 0), Mv(0, 0), Jal(0, 16)]);
+        assert_eq!(data[..10], [1, 0, 0, 0, b'h', b'i', 0, 0, 0, 0]);
+        assert_eq!(read_only, vec![Range { start: 0, end: 9 }]);
+    }
+
+    #[test]
+    fn test_local_labels() {
+        use Instruction::*;
+
+        // Every use of a macro gets its own `1:`
+        let main = ".macro dec\nbeqz a0, 1f\naddi a0, a0, -1\n1:\n.end_macro\n1:\ndec\ndec\nj 1b\n.data\n.word 1b";
+        let Parsed { code, data, .. } = link_files(&[main]).unwrap();
+        let dec = || Addi(10, 10, (-1i32) as u32);
+        assert_eq!(code[..5], [Beq(10, 0, 8), dec(), Beq(10, 0, 16), dec(), Jal(0, 16)]);
+        assert_eq!(data[..4], [16, 0, 0, 0]);
+
+        let error = |file: &str| link_files(&[file]).err().unwrap().to_string();
+        assert_eq!(error("j 1f\n1b: nop"), "file0.s:1:3: label not found: `1f`\n1 | j 1f\n  |   ^");
+        assert_eq!(error("j 1b\n1: nop"), "file0.s:1:3: label not found: `1b`\n1 | j 1b\n  |   ^");
+    }
+
+    #[test]
+    fn test_entry() {
+        let link_from = |files: &[&str], label: &str| {
+            let files = files.iter().map(|f| lines(f).parse_includes(PathBuf::from("file.s")).parse_macros());
+            let options = Options { entry: Some(label.to_owned()), ..Options::default() };
+            link(files, 64, &options)
+        };
+        let entry = |parsed: Parsed| (parsed.entry, parsed.exit);
+
+        // Only a global `main` counts, and it may be in any file
+        assert_eq!(entry(link_files(&["nop\nmain: nop"]).unwrap()), (None, 8));
+        assert_eq!(entry(link_files(&["nop", ".globl main\nnop\nmain: nop"]).unwrap()), (Some(8), 12));
+        assert_eq!(entry(link_from(&[".globl main\nmain: nop\nstart: nop"], "start").unwrap()), (Some(4), 8));
+
+        // The label has to be in the code, and it isn't unused even if nothing jumps to it
+        assert!(link_from(&["nop\n.data\nstart: .word 1"], "start").is_err());
+        assert!(link_from(&["nop"], "start").is_err());
+        assert!(link_from(&["start: nop\nnop"], "start").unwrap().warnings.is_empty());
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let error = |files: &[&str]| link_files(files).err().unwrap().to_string();
+
+        assert_eq!(
+            error(&[".text\n  add x32, t0, t1 # oops"]),
+            "file0.s:2:7: unknown register `x32`\n2 |   add x32, t0, t1 # oops\n  |       ^^^"
+        );
+
+        // The carets go under everything the error is about
+        assert_eq!(
+            error(&["addi t0, t0, 3000 + 2000"]),
+            "file0.s:1:14: immediate `5000` is out of range, it has to be from -2048 to 2047\n\
+             1 | addi t0, t0, 3000 + 2000\n  |              ^^^^^^^^^^^"
+        );
+        assert_eq!(
+            error(&["lw t0, 4(sp) extra"]),
+            "file0.s:1:14: unexpected `extra`\n1 | lw t0, 4(sp) extra\n  |              ^^^^^"
+        );
+
+        // Found while linking
+        assert_eq!(
+            error(&["nop", "nop\n\tj nowhere"]),
+            "file1.s:2:4: label not found: `nowhere`\n2 | \tj nowhere\n  | \t  ^"
+        );
+
+        // Lines of a macro point to where it was used, and to where they were written in the macro
+        assert_eq!(
+            error(&[".macro go\n  j nowhere\n.end_macro\nnop\ngo"]),
+            "file0.s:5: label not found: `nowhere`\n5 | go\n  = in macro `go`, file0.s:2: j nowhere"
+        );
+        assert_eq!(
+            error(&[".macro inner\nadd t0 t0 x99\n.end_macro\n.macro outer\nnop\ninner\n.end_macro\nouter"]),
+            "file0.s:8: unknown register `x99`\n8 | outer\n  = in macro `inner`, file0.s:2: add t0 t0 x99"
+        );
+        assert_eq!(
+            error(&[".macro go(%a)\nj %a\n.end_macro\ngo"]),
+            "file0.s:4: macro `go` takes 1 argument, but got 0\n4 | go"
+        );
+        assert_eq!(error(&["nop\n.error \"not done yet\""]), "file0.s:2: not done yet\n2 | .error \"not done yet\"");
+
+        assert_eq!(
+            error(&["main: nop\nloop: j main\nloop: nop"]),
+            "file0.s:3:1: label `loop` is already defined, at file0.s:2: loop: j main\n3 | loop: nop\n  | ^"
+        );
+        assert_eq!(
+            error(&["loop: nop\nj lop", ".globl square\nsquare: ret"]),
+            "file0.s:2:3: label not found: `lop`, did you mean `loop`?\n2 | j lop\n  |   ^"
+        );
+        assert_eq!(
+            error(&[".data\n.word sqare", ".globl square\nsquare: ret"]),
+            "file0.s:2:7: label not found: `sqare`, did you mean `square`?\n2 | .word sqare\n  |       ^"
+        );
+    }
+
+    #[test]
+    fn test_all_errors() {
+        let main = ".text\nfoo t0\nj nowhere\nadd t0 t1\n.data\n.word nothing";
+        let lib = "ret\nsub t0, t0, x99";
+
+        let errors = match link_files(&[main, lib]) {
+            Err(Error::Many(errors)) => errors,
+            _ => panic!("expected many errors"),
+        };
+
+        // Errors of every line come before the ones we find when linking
+        let lines: Vec<_> = errors
+            .iter()
+            .map(|e| match e {
+                Error::OnLine(pos, _) => (pos.file.as_str(), pos.line),
+                _ => panic!("{} doesn't say where it is", e),
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [("file0.s", 2), ("file0.s", 4), ("file1.s", 2), ("file0.s", 6), ("file0.s", 3)]
+        );
+        assert!(Error::Many(errors).to_string().ends_with("Found 5 errors"));
+    }
+
+    #[test]
+    fn test_label_mistakes() {
+        // The second `loop` is an error, but the first one is still there for the `j` before it, and
+        // the lines after it are still checked
+        let code = "loop: nop\nj loop\nloop: li t0, 1\nj lopo\nj nowhere";
+        let errors = match link_files(&[code]) {
+            Err(Error::Many(errors)) => errors,
+            _ => panic!("expected many errors"),
+        };
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|e| match e {
+                Error::OnLine(pos, e) => (pos.line, &**e),
+                _ => panic!("{} doesn't say where it is", e),
+            })
+            .collect();
+
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(errors[0], (3, Error::DuplicateLabel(label, first)) if label == "loop" && first.line == 1));
+        assert!(matches!(errors[1], (4, Error::LabelNotFound(_, Some(label))) if label == "loop"));
+        assert!(matches!(errors[2], (5, Error::LabelNotFound(label, None)) if label == "nowhere"));
+    }
+
+    #[test]
+    fn test_warnings() {
+        let code = ".data\nc: .byte 1\nw: .word 2\nb: .byte w+300\n.text\nmain: j w\nloop: ret\nunused: nop";
+        let Parsed { warnings, .. } = link_files(&[code]).unwrap();
+
+        let found: Vec<_> = warnings
+            .iter()
+            .map(|e| match e {
+                Error::OnLine(pos, e) => match &**e {
+                    Error::Warning(w) => (pos.line, w.clone()),
+                    e => panic!("{} isn't a warning", e),
+                },
+                e => panic!("{} doesn't say where it is", e),
+            })
+            .collect();
+
+        let expected = [
+            (3, Warning::UnalignedData(".word".to_owned(), 1)),
+            (6, Warning::BranchToData("w".to_owned())),
+            (4, Warning::Truncated("w+300".to_owned(), 8)),
+        ];
+        assert_eq!(found, expected);
+
+        let options = Options { werror: true, ..Default::default() };
+        let files = std::iter::once(lines(code).parse_includes(PathBuf::from("file0.s")).parse_macros());
+        assert!(matches!(link(files, 64, &options), Err(Error::Many(errors)) if errors.len() == 3));
+
+        // The unused labels are only looked for when asked to
+        let options = Options { werror: true, warn_unused: true, ..Default::default() };
+        let files = std::iter::once(lines(code).parse_includes(PathBuf::from("file0.s")).parse_macros());
+        let Err(Error::Many(errors)) = link(files, 64, &options) else { panic!("no warnings") };
+        let unused: Vec<_> = errors
+            .iter()
+            .filter_map(|e| match e {
+                Error::OnLine(pos, e) => match &**e {
+                    Error::Warning(Warning::UnusedLabel(label)) => Some((pos.line, label.as_str())),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        assert_eq!(unused, [(2, "c"), (4, "b"), (7, "loop"), (8, "unused")]);
+    }
+
+    #[test]
+    fn test_rvc() {
+        use Instruction::*;
+
+        let rvc = |code: &str| {
+            let options = Options { rvc: true, ..Default::default() };
+            let files = std::iter::once(lines(code).parse_includes(PathBuf::from("file0.s")).parse_macros());
+            link(files, 64, &options)
+        };
+        let pad = Instruction::padding;
+
+        // The compressed instructions take a slot, the others take two
+        let code = "c.li s0, 1
+loop: addi s0, s0, 1
+c.bnez s0, loop
+.half 0x0505, 0
+.p2align 3
+end: c.j end";
+        let Parsed { code, symbols, .. } = rvc(code).unwrap();
+        assert_eq!(
+            code[..10],
+            [Li(8, 1), Addi(8, 8, 1), pad(), Bne(8, 0, 2), Addi(10, 10, 1), Illegal(".half 0x0000".to_owned()),
+             Mv(0, 0), Mv(0, 0), Jal(0, 16), Li(17, 10)]
+        );
+        assert_eq!(code[10..], [pad(), Ecall, pad()]);
+        assert_eq!((symbols[0].address, symbols[1].address), (2, 16));
+
+        let not_compressible = |code: &str| {
+            matches!(rvc(code), Err(Error::OnLine(_, e)) if matches!(*e, Error::NotCompressible(_)))
+        };
+        assert!(not_compressible("c.addi a0, 32"));
+        assert!(not_compressible("c.lw a0, 0(t0)"));
+        assert!(not_compressible("c.beqz s0, end
+.text 0x200
+end: nop"));
+        assert!(!not_compressible("c.beqz s0, end
+.text 0xF0
+end: nop"));
+
+        let without_rvc = link_files(&["c.nop"]);
+        assert!(matches!(without_rvc, Err(Error::OnLine(_, e)) if matches!(*e, Error::NeedsRvc(_))));
+    }
+}
@@ -0,0 +1,200 @@
+//!
+//! Writes the program as it was assembled, one instruction per line, like the Text Segment window
+//! of RARS:
+//!
+//! ```text
+//! Address    | Code       | Basic                      | Source
+//! 0x00000000 | 0x00a00513 | addi a0, zero, 10          | main.s:3: li a0, 10
+//! 0x00000004 |            | li a1, 268500996           | main.s:4: lb a1, label
+//! 0x00000008 | 0x00058583 | lb a1, 0(a1)
+//! ```
+//!
+//! A pseudoinstruction that became many instructions only shows its line once. The ones without a
+//! code are simulated as a single instruction, but would take more than one in a real processor.
+//! With [rvc](../struct.Options.html#structfield.rvc), the compressed instructions have a 2-byte
+//! code, like `0x0505`, and their basic instruction is the one they stand for.
+//!
+//! An instruction that came from a macro shows the line that used the macro, and then the line of
+//! the `.macro` as an inlined frame, like ``main.s:9: inc(t0) (inlined from macro `inc` at main.s:2:
+//! addi %r, %r, 1)``.
+//!
+//! The labels can be listed too, with [write_symbols](fn.write_symbols.html).
+//!
+
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use super::{register_names, text, Instruction, Pos, Symbol};
+
+/// Writes the listing of `code`, whose first instruction is at `text_base`, into `out`. With `rvc`,
+/// the code is made of 2-byte slots.
+pub fn write_listing<W: Write>(
+    out: &mut W,
+    code: &[Instruction],
+    code_pos: &[Option<Arc<Pos>>],
+    text_base: usize,
+    rvc: bool,
+) -> io::Result<()> {
+    writeln!(out, "{:<10} | {:<10} | {:<26} | Source", "Address", "Code", "Basic")?;
+
+    let status = register_names::status();
+
+    let mut last_pos: Option<&Arc<Pos>> = None;
+    let slot = if rvc { 2 } else { 4 };
+    for (i, instruction) in code.iter().enumerate() {
+        if rvc && instruction.is_padding() {
+            continue;
+        }
+        let compressed = rvc && !code.get(i + 1).is_some_and(Instruction::is_padding);
+
+        // What the processor would really run, like the `addi` of a small `li`
+        let address = text_base + i * slot;
+        let (encoding, basic) = if compressed {
+            let half = text::compress(instruction, address);
+            let encoding = half.map(|half| format!("{:#06x}", half));
+            (encoding, half.and_then(|half| text::decompress(half, address)))
+        } else {
+            let word = text::encode(instruction, address);
+            let encoding = word.map(|word| format!("{:#010x}", word));
+            (encoding, word.and_then(|word| text::decode(word, address, &status)))
+        };
+        let encoding = encoding.unwrap_or_default();
+        let basic = match basic {
+            Some(basic) => basic.to_string(),
+            None => instruction.to_string(),
+        };
+
+        // The exit we add at the end of the program has no position
+        let pos = code_pos.get(i).and_then(Option::as_ref);
+        let source = match pos {
+            Some(pos) if !last_pos.is_some_and(|last| Arc::ptr_eq(last, pos)) => {
+                let source = format!("{}:{}: {}", pos.file, pos.line, pos.source.trim());
+                match &pos.macro_origin {
+                    Some(origin) => format!("{} ({})", source, origin),
+                    None => source,
+                }
+            }
+            _ => String::new(),
+        };
+        last_pos = pos;
+
+        let line = format!("{:#010x} | {:<10} | {:<26} | {}", address, encoding, basic, source);
+        writeln!(out, "{}", line.trim_end_matches([' ', '|']))?;
+    }
+
+    Ok(())
+}
+
+/// Writes every label in `symbols` with its address, sorted by segment and then by address. The
+/// numeric local labels, like `1:`, aren't there, since there may be many of each.
+pub fn write_symbols<W: Write>(out: &mut W, symbols: &[Symbol]) -> io::Result<()> {
+    writeln!(out, "{:<10} | {:<7} | Label", "Address", "Segment")?;
+
+    let mut sorted: Vec<_> = symbols.iter().collect();
+    sorted.sort_by_key(|symbol| (symbol.segment, symbol.address));
+    for symbol in sorted {
+        let global = if symbol.global { " (global)" } else { "" };
+        writeln!(out, "{:#010x} | {:<7} | {}{}", symbol.address, symbol.segment, symbol.name, global)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::*;
+    use std::path::PathBuf;
+
+    fn parse(code: &str) -> Parsed {
+        code.lines()
+            .map(str::to_owned)
+            .parse_includes(PathBuf::from("main.s"))
+            .parse_macros()
+            .parse_riscv(64, &Options::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_listing() {
+        let code = ".data\nx: .word 1\n.text\nli a0, 10\nlb a1, x\nbnez a0, end\nend: nop";
+        let Parsed { code, code_pos, .. } = parse(code);
+
+        let mut out = Vec::new();
+        write_listing(&mut out, &code, &code_pos, 0, false).unwrap();
+        let listing = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = listing.lines().collect();
+
+        assert!(lines[0].starts_with("Address"));
+        assert_eq!(
+            &lines[1..5],
+            [
+                "0x00000000 | 0x00a00513 | addi a0, zero, 10          | main.s:4: li a0, 10",
+                "0x00000004 | 0x00000597 | auipc a1, 0x0              | main.s:5: lb a1, x",
+                "0x00000008 | 0xffc58583 | lb a1, -4(a1)",
+                "0x0000000c | 0x00051263 | bne a0, zero, 0x10         | main.s:6: bnez a0, end",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_macro_listing() {
+        let code = ".macro inc(%r)\naddi %r, %r, 1\nslli %r, %r, 1\n.end_macro\n.text\nli t0, 1\ninc(t0)";
+        let Parsed { code, code_pos, .. } = parse(code);
+
+        let mut out = Vec::new();
+        write_listing(&mut out, &code, &code_pos, 0, false).unwrap();
+        let listing = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = listing.lines().collect();
+
+        // Each line of the macro is its own inlined frame
+        let inlined = |line: usize, source: &str| {
+            format!("main.s:7: inc(t0) (inlined from macro `inc` at main.s:{}: {})", line, source)
+        };
+        assert_eq!(lines[2], format!("0x00000004 | 0x00128293 | {:<26} | {}", "addi t0, t0, 1", inlined(2, "addi %r, %r, 1")));
+        assert_eq!(lines[3], format!("0x00000008 | 0x00129293 | {:<26} | {}", "slli t0, t0, 1", inlined(3, "slli %r, %r, 1")));
+    }
+
+    #[test]
+    fn test_rvc_listing() {
+        let code = ".text\nc.li a0, 10\nloop: addi a0, a0, -1\nc.bnez a0, loop";
+        let options = Options { rvc: true, ..Default::default() };
+        let lines = code.lines().map(str::to_owned).parse_includes(PathBuf::from("main.s"));
+        let Parsed { code, code_pos, .. } = lines.parse_macros().parse_riscv(64, &options).unwrap();
+
+        let mut out = Vec::new();
+        write_listing(&mut out, &code, &code_pos, 0, true).unwrap();
+        let listing = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = listing.lines().collect();
+
+        assert_eq!(
+            &lines[1..4],
+            [
+                "0x00000000 | 0x4529     | li a0, 10                  | main.s:2: c.li a0, 10",
+                "0x00000002 | 0xfff50513 | addi a0, a0, -1            | main.s:3: loop: addi a0, a0, -1",
+                "0x00000006 | 0xfd75     | bne a0, zero, 0x2          | main.s:4: c.bnez a0, loop",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let code = ".globl main\n.data\nx: .word 1\ny: .byte 2\n.text\nmain: nop\n1: j 1b\nend: ret";
+        let Parsed { symbols, .. } = parse(code);
+
+        let mut out = Vec::new();
+        write_symbols(&mut out, &symbols).unwrap();
+        let listing = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = listing.lines().collect();
+
+        assert_eq!(
+            &lines[1..],
+            [
+                "0x00000000 | text    | main (global)",
+                "0x00000008 | text    | end",
+                "0x00000000 | data    | x",
+                "0x00000004 | data    | y",
+            ]
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//!
+//! Numeric local labels, like in GNU as. A label made only of digits, like `1:`, can be defined
+//! many times, and `1b` refers to the closest one before it, while `1f` refers to the closest one
+//! after it:
+//!
+//! ```
+//! 1:  addi t0, t0, -1
+//!     bnez t0, 1b     # jumps back to the addi
+//!     beqz t1, 1f     # jumps to the ret
+//!     li a0, 0
+//! 1:  ret
+//! ```
+//!
+//! Each definition gets a name of its own, like `.L1$0` and `.L1$1`, and the references are replaced
+//! by these names as the lines are parsed, so everything after that sees them as normal labels.
+//!
+
+use fnv::FnvHashMap;
+use radix_trie::Trie;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use super::combinators::{is_symbol_char, quoted_len};
+use super::{Error, Pos};
+
+#[derive(Debug, Default)]
+pub(super) struct LocalLabels {
+    /// How many times each number was defined so far
+    defined: FnvHashMap<String, usize>,
+    /// The `1f`s we saw, the name they were given and where they were written. They're only known
+    /// to be wrong at the end of the file.
+    forward: Vec<(String, String, Arc<Pos>)>,
+}
+
+fn name(number: &str, index: usize) -> String {
+    format!(".L{}${}", number, index)
+}
+
+fn is_numeric(label: &str) -> bool {
+    !label.is_empty() && label.bytes().all(|c| c.is_ascii_digit())
+}
+
+impl LocalLabels {
+    /// Defines `label` if it's numeric, and returns the name it should have instead
+    pub fn define(&mut self, label: &str) -> Option<String> {
+        if !is_numeric(label) {
+            return None;
+        }
+
+        let count = self.defined.entry(label.to_owned()).or_insert(0);
+        *count += 1;
+        Some(name(label, *count - 1))
+    }
+
+    /// Replaces the `1b`s and `1f`s in `line` by the names of the labels they refer to. Strings and
+    /// characters, like in `.string "1b"`, are left alone.
+    pub fn replace<'a>(&mut self, line: &'a str, pos: &Arc<Pos>) -> Result<Cow<'a, str>, Error> {
+        if !line.bytes().any(|c| c == b'b' || c == b'f') {
+            return Ok(Cow::Borrowed(line));
+        }
+
+        let mut out = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            let len = match c {
+                '"' | '\'' => quoted_len(rest, c),
+                c if is_symbol_char(c) => rest.find(|c| !is_symbol_char(c)).unwrap_or(rest.len()),
+                c => c.len_utf8(),
+            };
+
+            let (token, after) = rest.split_at(len);
+            match self.reference(token, pos)? {
+                Some(name) => out.push_str(&name),
+                None => out.push_str(token),
+            }
+            rest = after;
+        }
+
+        Ok(Cow::Owned(out))
+    }
+
+    /// The name of the label `token` refers to, if it's a reference like `1b` or `1f`
+    fn reference(&mut self, token: &str, pos: &Arc<Pos>) -> Result<Option<String>, Error> {
+        let (number, direction) = token.split_at(token.len().saturating_sub(1));
+        if !is_numeric(number) {
+            return Ok(None);
+        }
+
+        let defined = self.defined.get(number).copied().unwrap_or(0);
+        match direction {
+            "b" if defined == 0 => Err(Error::LabelNotFound(token.to_owned(), None)),
+            "b" => Ok(Some(name(number, defined - 1))),
+            "f" => {
+                let name = name(number, defined);
+                self.forward.push((token.to_owned(), name.clone(), pos.clone()));
+                Ok(Some(name))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Errors for the `1f`s that were never defined, with the label that was written instead of the
+    /// name it was given. Their names are added to `labels` anyway, so the linker doesn't complain
+    /// again about a name nobody wrote.
+    pub fn check(&self, labels: &mut Trie<String, usize>) -> Vec<Error> {
+        let mut errors = Vec::new();
+        for (token, name, pos) in &self.forward {
+            if labels.get(name).is_none() {
+                errors.push(Error::LabelNotFound(token.clone(), None).at(pos, &pos.source));
+                labels.insert(name.clone(), 0);
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_labels() {
+        let pos = Arc::new(Pos {
+            file: "test.s".into(),
+            line: 1,
+            column: None,
+            width: 0,
+            source: "j 2f".into(),
+            macro_origin: None,
+        });
+        let mut locals = LocalLabels::default();
+
+        assert_eq!(locals.define("loop"), None);
+        assert!(locals.replace("j 1b", &pos).is_err());
+
+        assert_eq!(locals.define("1").as_deref(), Some(".L1$0"));
+        assert_eq!(locals.replace("bnez t0, 1b", &pos).unwrap(), "bnez t0, .L1$0");
+        assert_eq!(locals.replace("beq t0 t1 1f+4", &pos).unwrap(), "beq t0 t1 .L1$1+4");
+        assert_eq!(locals.replace("li t0 0x1f", &pos).unwrap(), "li t0 0x1f");
+        assert_eq!(
+            locals.replace(".string \"1b \\\" 1f\" '1'", &pos).unwrap(),
+            ".string \"1b \\\" 1f\" '1'"
+        );
+
+        assert_eq!(locals.define("1").as_deref(), Some(".L1$1"));
+        assert_eq!(locals.define("23").as_deref(), Some(".L23$0"));
+        assert_eq!(locals.replace(".word 1b, 23b", &pos).unwrap(), ".word .L1$1, .L23$0");
+
+        let mut labels = Trie::new();
+        labels.insert(".L1$1".to_owned(), 8);
+        assert!(locals.check(&mut labels).is_empty());
+
+        locals.replace("j 2f", &pos).unwrap();
+        let is_2f = |e: &Error| matches!(e, Error::OnLine(_, e) if matches!(&**e, Error::LabelNotFound(l, _) if l == "2f"));
+        assert!(matches!(&locals.check(&mut labels)[..], [e] if is_2f(e)));
+        assert!(locals.check(&mut labels).is_empty());
+    }
+}
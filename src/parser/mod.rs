@@ -1,339 +1,1235 @@
-//!
-//! Parses RISC-V code into code and data parts, so it can be used in the simulator module.
-//! We use a lot of mnemonics here, I'll try to link to a cheatsheet here later.
-//!
-
-use radix_trie::Trie;
-use byteorder::{ByteOrder, LittleEndian};
-
-pub mod register_names;
-use register_names::{self as reg_names, FullRegMap};
-
-pub mod combinators;
-
-mod preprocessor;
-pub use preprocessor::*;
-
-mod util;
-pub use util::*;
-
-mod data;
-mod text;
-
-/// Floating point instructions.
-/// In a separate enum because maybe someday I'll have a cargo feature to disable
-/// floating point instructions.
-/// Everything here is single precision, no doubles allowed.
-#[derive(Debug, PartialEq, Eq)]
-pub enum FloatInstruction {
-    /// rd, rs1, rs2
-    Add(u8, u8, u8),
-    Sub(u8, u8, u8),
-    Mul(u8, u8, u8),
-    Div(u8, u8, u8),
-    Equ(u8, u8, u8), // Eq was taken
-    Le(u8, u8, u8),
-    Lt(u8, u8, u8),
-    Max(u8, u8, u8),
-    Min(u8, u8, u8),
-    SgnjS(u8, u8, u8),
-    SgnjNS(u8, u8, u8),
-    SgnjXS(u8, u8, u8),
-
-    /// rd, rs1
-    Class(u8, u8),
-    CvtSW(u8, u8),  // fcvt.s.w
-    CvtSWu(u8, u8), // fcvt.s.wu
-    CvtWS(u8, u8),  // fcvt.w.s
-    CvtWuS(u8, u8), // fcvw.wu.s
-    MvSX(u8, u8),   // fmv.s.x
-    MvXS(u8, u8),   // fmv.x.s
-    Sqrt(u8, u8),
-
-    Lw(u8, u32, u8),
-    Sw(u8, u32, u8),
-}
-
-/// Giant enum that represents a single RISC-V instruction and its arguments
-#[derive(Debug, PartialEq, Eq)]
-pub enum Instruction {
-    // Type R
-    /// rd, rs1, rs2
-    Add(u8, u8, u8),
-    Sub(u8, u8, u8),
-    Sll(u8, u8, u8),
-    Slt(u8, u8, u8),
-    Sltu(u8, u8, u8),
-    Xor(u8, u8, u8),
-    Srl(u8, u8, u8),
-    Sra(u8, u8, u8),
-    Or(u8, u8, u8),
-    And(u8, u8, u8),
-    Mul(u8, u8, u8), // TODO: mulh, mulhsu, mulhu
-    Div(u8, u8, u8),
-    Divu(u8, u8, u8),
-    Rem(u8, u8, u8),
-    Remu(u8, u8, u8),
-
-    // Type I
-    Ecall,
-    /// rd, imm, rs1
-    Lb(u8, u32, u8),
-    Lh(u8, u32, u8),
-    Lw(u8, u32, u8),
-    Lbu(u8, u32, u8),
-    Lhu(u8, u32, u8),
-    /// rd, rs1, imm
-    Addi(u8, u8, u32),
-    Slti(u8, u8, u32),
-    Sltiu(u8, u8, u32),
-    Slli(u8, u8, u32),
-    Srli(u8, u8, u32),
-    Srai(u8, u8, u32),
-    Ori(u8, u8, u32),
-    Andi(u8, u8, u32),
-    Xori(u8, u8, u32),
-
-    // Type S
-    /// rs2, imm, rs1
-    Sb(u8, u32, u8),
-    Sh(u8, u32, u8),
-    Sw(u8, u32, u8),
-
-    // Type SB + jumps
-    /// rs1, rs2, label
-    Beq(u8, u8, usize),
-    Bne(u8, u8, usize),
-    Blt(u8, u8, usize),
-    Bge(u8, u8, usize),
-    Bltu(u8, u8, usize),
-    Bgeu(u8, u8, usize),
-    /// rd, rs1, imm
-    Jalr(u8, u8, u32),
-    /// rd, label
-    Jal(u8, usize),
-
-    // CSR
-    /// rd, fcsr, rs1
-    CsrRw(u8, u8, u8),
-    CsrRs(u8, u8, u8),
-    CsrRc(u8, u8, u8),
-    /// rd, fcsr, imm
-    CsrRwi(u8, u8, u32),
-    CsrRsi(u8, u8, u32),
-    CsrRci(u8, u8, u32),
-
-    // Floating point
-    Float(FloatInstruction),
-
-    // Some pseudoinstructions
-    /// rd, imm
-    Li(u8, u32),
-    /// rd, rs1
-    Mv(u8, u8),
-
-    Ret,
-    URet,
-}
-
-/// Also giant enum that represents a single RISC-V instruction, but we save
-/// labels as strings because it might not have parsed it yet (for example,
-/// consider a jump instruction that jumps to a label in the next line).
-///
-/// We process the labels stored after the entire file has been parsed.
-#[derive(Debug, PartialEq, Eq)]
-enum PreLabelInstruction {
-    Beq(u8, u8, String),
-    Bne(u8, u8, String),
-    Blt(u8, u8, String),
-    Bge(u8, u8, String),
-    Bltu(u8, u8, String),
-    Bgeu(u8, u8, String),
-    Jal(u8, String),
-
-    /// Gets mapped to an Instruction::Li(rd, position) after unlabeling
-    La(u8, String),
-
-    Other(Instruction),
-}
-
-impl From<Instruction> for PreLabelInstruction {
-    fn from(i: Instruction) -> PreLabelInstruction {
-        PreLabelInstruction::Other(i)
-    }
-}
-
-impl From<FloatInstruction> for PreLabelInstruction {
-    fn from(i: FloatInstruction) -> PreLabelInstruction {
-        PreLabelInstruction::Other(Instruction::Float(i))
-    }
-}
-
-/// Represents a successful parser result. This is the same format the simulator
-/// will use to execute the instructions
-pub struct Parsed {
-    pub code: Vec<Instruction>,
-    pub data: Vec<u8>,
-}
-
-pub type ParseResult = Result<Parsed, Error>;
-
-/// The "current" parser directive
-enum Directive {
-    Text,
-    Data,
-}
-
-pub trait RISCVParser {
-    /// Parses an iterator of preprocessed lines and returns the instructions and
-    /// the data it parsed. Remember to preprocess the iterator before calling this,
-    /// as `parse_riscv` does not understand macros and includes.
-    /// ```
-    /// parse::file_lines("riscv.s".to_owned())?
-    ///     .parse_includes()
-    ///     .parse_macros()
-    ///     .parse_riscv(DATA_SIZE)?;
-    /// ```
-    ///
-    /// The `data_segment_size` parameter is the final size of the data segment, in bytes.
-    fn parse_riscv(self, data_segment_size: usize) -> ParseResult;
-}
-
-impl<I: Iterator<Item = String>> RISCVParser for I {
-    fn parse_riscv(self, data_segment_size: usize) -> ParseResult {
-        use combinators::*;
-
-        let regmaps: FullRegMap = (reg_names::regs(), reg_names::floats(), reg_names::status());
-        let mut labels = Trie::<String, usize>::new();
-
-        let mut directive = Directive::Text;
-        let mut code = Vec::new();
-
-        let mut data = Vec::with_capacity(data_segment_size);
-        let mut current_data_type = data::Type::default();
-        let mut data_labels: Vec<data::Label> = Vec::new();
-
-        for line in self {
-            let full_line = &line;
-
-            let line = match parse_label(&line) {
-                Ok((rest, label)) => {
-                    let label_pos = match directive {
-                        Directive::Text => code.len() * 4,
-                        Directive::Data => data.len(),
-                    };
-                    labels.insert(label.to_owned(), label_pos);
-                    rest
-                }
-                Err(_) => &line,
-            };
-
-            let (line, _) = separator0(line)?;
-            if line.is_empty() {
-                continue;
-            }
-
-            // Identify directives
-            // This accepts stuff like ".textSOMETHING" or ".database", but RARS accepts it too
-            // Gotta be consistent! ¯\_(ツ)_/¯
-            if line.starts_with(".data") {
-                directive = Directive::Data;
-                continue;
-            } else if line.starts_with(".text") {
-                directive = Directive::Text;
-                continue;
-            }
-
-            let res = match directive {
-                Directive::Text => text::parse_line(line, &regmaps, &mut code),
-                Directive::Data => {
-                    data::parse_line(line, &mut data, &mut data_labels, &mut current_data_type)
-                }
-            };
-
-            res.wrap_meta(full_line)?;
-        }
-
-        unlabel_data(data_labels, &mut data, &labels)?;
-
-        let code: Result<Vec<Instruction>, Error> = code
-            .into_iter()
-            .map(|i| unlabel_instruction(i, &labels))
-            .collect();
-        let mut code = code?;
-
-        // If the program ever drops off bottom, we make an "exit" ecall and terminate execution
-        code.extend(vec![
-            Instruction::Li(17, 10), // li a7 10
-            Instruction::Ecall,
-        ]);
-
-        data.resize(data_segment_size, 0);
-        Ok(Parsed { code, data })
-    }
-}
-
-/// Transforms a PreLabelInstruction into a normal Instruction by "commiting" the labels
-/// into positions in the code. For example, Jal(0, "Label") maps to Jal(0, labels_trie.get("Label"))
-fn unlabel_instruction(
-    instruction: PreLabelInstruction,
-    labels: &Trie<String, usize>,
-) -> Result<Instruction, Error> {
-    use Instruction::*;
-    use PreLabelInstruction as p;
-
-    macro_rules! unlabel {
-        ($inst:ident, $rd:ident, $label:ident) => {
-            labels
-                .get(&$label)
-                .map(|&pos| $inst($rd, pos))
-                .ok_or(Error::LabelNotFound($label))
-        };
-        ($inst:ident, $rs1:ident, $rs2:ident, $label:ident) => {
-            labels
-                .get(&$label)
-                .map(|&pos| $inst($rs1, $rs2, pos))
-                .ok_or(Error::LabelNotFound($label))
-        };
-    }
-
-    match instruction {
-        p::Jal(rd, label) => unlabel!(Jal, rd, label),
-        p::Beq(rs1, rs2, label) => unlabel!(Beq, rs1, rs2, label),
-        p::Bne(rs1, rs2, label) => unlabel!(Bne, rs1, rs2, label),
-        p::Bge(rs1, rs2, label) => unlabel!(Bge, rs1, rs2, label),
-        p::Blt(rs1, rs2, label) => unlabel!(Blt, rs1, rs2, label),
-        p::Bltu(rs1, rs2, label) => unlabel!(Bltu, rs1, rs2, label),
-        p::Bgeu(rs1, rs2, label) => unlabel!(Bgeu, rs1, rs2, label),
-
-        p::La(rd, label) => labels
-            .get(&label)
-            .map(|&pos| Li(rd, pos as u32))
-            .ok_or(Error::LabelNotFound(label)),
-
-        p::Other(instruction) => Ok(instruction),
-    }
-}
-
-/// Replaces all positions in the `.data` that had labels with their
-/// actual values
-fn unlabel_data(data_labels: Vec<data::Label>, data: &mut Vec<u8>, labels: &Trie<String, usize>) -> Result<(), Error> {
-    for dl in data_labels {
-        let data::Label{ pos, dtype, label } = dl;
-
-        let value = match labels.get(&label) {
-            Some(x) => *x,
-            None => return Err(Error::LabelNotFound(label)),
-        };
-
-        use data::Type::*;
-        match dtype {
-            Byte => { data[pos] = value as u8; }
-            Half => LittleEndian::write_u16(&mut data[pos..], value as u16),
-            Word => LittleEndian::write_u32(&mut data[pos..], value as u32),
-            _ => unreachable!("label can only be parsed in .byte, .half or .word"),
-        }
-    }
-
-    Ok(())
+//!
+//! Parses RISC-V code into code and data parts, so it can be used in the simulator module.
+//! We use a lot of mnemonics here, I'll try to link to a cheatsheet here later.
+//!
+
+use radix_trie::Trie;
+use fnv::{FnvHashMap, FnvHashSet};
+use byteorder::{ByteOrder, LittleEndian};
+use std::ops::Range;
+use std::sync::Arc;
+
+pub mod register_names;
+use register_names::{self as reg_names, FullRegMap};
+
+pub mod combinators;
+
+mod preprocessor;
+pub use preprocessor::*;
+
+mod util;
+pub use util::*;
+
+mod data;
+mod local_labels;
+mod strict;
+mod text;
+
+mod link;
+pub use link::link;
+use link::{link_objects, Object, Symbols};
+
+mod listing;
+pub use listing::{write_listing, write_symbols};
+
+pub mod corpus;
+
+/// The `rm` of a float instruction that rounds like `frm` says
+pub const DYNAMIC_ROUNDING: u8 = 7;
+
+/// Floating point instructions.
+/// In a separate enum because maybe someday I'll have a cargo feature to disable
+/// floating point instructions.
+/// The variants that end in `D`, and `Ld` and `Sd`, are double precision. The rest is single.
+/// `rm` is the rounding mode of the instruction, or [DYNAMIC_ROUNDING](constant.DYNAMIC_ROUNDING.html)
+/// to use the one in `frm`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FloatInstruction {
+    /// rd, rs1, rs2, rm
+    Add(u8, u8, u8, u8),
+    Sub(u8, u8, u8, u8),
+    Mul(u8, u8, u8, u8),
+    Div(u8, u8, u8, u8),
+
+    /// rd, rs1, rs2
+    Equ(u8, u8, u8), // Eq was taken
+    Le(u8, u8, u8),
+    Lt(u8, u8, u8),
+    Max(u8, u8, u8),
+    Min(u8, u8, u8),
+    SgnjS(u8, u8, u8),
+    SgnjNS(u8, u8, u8),
+    SgnjXS(u8, u8, u8),
+
+    /// rd, rs1
+    Class(u8, u8),
+    MvSX(u8, u8), // fmv.s.x
+    MvXS(u8, u8), // fmv.x.s
+
+    /// rd, rs1, rm
+    CvtSW(u8, u8, u8),  // fcvt.s.w
+    CvtSWu(u8, u8, u8), // fcvt.s.wu
+    CvtWS(u8, u8, u8),  // fcvt.w.s
+    CvtWuS(u8, u8, u8), // fcvw.wu.s
+    Sqrt(u8, u8, u8),
+
+    /// rd, rs1, rs2, rs3, rm
+    Madd(u8, u8, u8, u8, u8),  // fmadd.s, rs1 * rs2 + rs3
+    Msub(u8, u8, u8, u8, u8),  // fmsub.s, rs1 * rs2 - rs3
+    NMsub(u8, u8, u8, u8, u8), // fnmsub.s, -(rs1 * rs2) + rs3
+    NMadd(u8, u8, u8, u8, u8), // fnmadd.s, -(rs1 * rs2) - rs3
+
+    Lw(u8, u32, u8),
+    Sw(u8, u32, u8),
+
+    /// rd, rs1, rs2, rm
+    AddD(u8, u8, u8, u8),
+    SubD(u8, u8, u8, u8),
+    MulD(u8, u8, u8, u8),
+    DivD(u8, u8, u8, u8),
+
+    /// rd, rs1, rs2
+    EquD(u8, u8, u8),
+    LeD(u8, u8, u8),
+    LtD(u8, u8, u8),
+    MaxD(u8, u8, u8),
+    MinD(u8, u8, u8),
+    SgnjD(u8, u8, u8),
+    SgnjND(u8, u8, u8),
+    SgnjXD(u8, u8, u8),
+
+    /// rd, rs1
+    ClassD(u8, u8),
+    CvtDW(u8, u8),  // fcvt.d.w, always exact
+    CvtDWu(u8, u8), // fcvt.d.wu
+    CvtDS(u8, u8),  // fcvt.d.s
+
+    /// rd, rs1, rm
+    CvtWD(u8, u8, u8),  // fcvt.w.d
+    CvtWuD(u8, u8, u8), // fcvt.wu.d
+    CvtSD(u8, u8, u8),  // fcvt.s.d
+    SqrtD(u8, u8, u8),
+
+    /// rd, rs1, rs2, rs3, rm
+    MaddD(u8, u8, u8, u8, u8),
+    MsubD(u8, u8, u8, u8, u8),
+    NMsubD(u8, u8, u8, u8, u8),
+    NMaddD(u8, u8, u8, u8, u8),
+
+    Ld(u8, u32, u8), // fld
+    Sd(u8, u32, u8), // fsd
+}
+
+/// Instructions that only exist in RV64, only parsed with [Options::rv64](struct.Options.html#structfield.rv64).
+/// The registers of the simulator are always 64 bits wide, and in RV32 the upper half is just the
+/// sign extension of the lower one, so the RV32 [Instructions](enum.Instruction.html) behave like
+/// the `*w` ones of RV64 would. Still, these have their own `*w` variants, so they're encoded and
+/// shown as what they are. The others are the base instructions that work on the whole 64 bits.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rv64Instruction {
+    /// rd, rs1, rs2
+    Add(u8, u8, u8),
+    Sub(u8, u8, u8),
+    Sll(u8, u8, u8),
+    Srl(u8, u8, u8),
+    Sra(u8, u8, u8),
+    Mul(u8, u8, u8),
+    Mulh(u8, u8, u8),
+    Mulhsu(u8, u8, u8),
+    Mulhu(u8, u8, u8),
+    Div(u8, u8, u8),
+    Divu(u8, u8, u8),
+    Rem(u8, u8, u8),
+    Remu(u8, u8, u8),
+    Sh1add(u8, u8, u8),
+    Sh2add(u8, u8, u8),
+    Sh3add(u8, u8, u8),
+    Bset(u8, u8, u8),
+    Bclr(u8, u8, u8),
+    Binv(u8, u8, u8),
+    Bext(u8, u8, u8),
+    /// rd, rs1, imm
+    Addi(u8, u8, u32),
+    Slli(u8, u8, u32),
+    Srli(u8, u8, u32),
+    Srai(u8, u8, u32),
+    Bseti(u8, u8, u32),
+    Bclri(u8, u8, u32),
+    Binvi(u8, u8, u32),
+    Bexti(u8, u8, u32),
+
+    /// rd, rs1, rs2
+    Addw(u8, u8, u8),
+    Subw(u8, u8, u8),
+    Sllw(u8, u8, u8),
+    Srlw(u8, u8, u8),
+    Sraw(u8, u8, u8),
+    Mulw(u8, u8, u8),
+    Divw(u8, u8, u8),
+    Divuw(u8, u8, u8),
+    Remw(u8, u8, u8),
+    Remuw(u8, u8, u8),
+    /// rd, rs1, imm
+    Addiw(u8, u8, u32),
+    Slliw(u8, u8, u32),
+    Srliw(u8, u8, u32),
+    Sraiw(u8, u8, u32),
+
+    /// rd, imm, rs1
+    Ld(u8, u32, u8),
+    Lwu(u8, u32, u8),
+    /// rs2, imm, rs1
+    Sd(u8, u32, u8),
+}
+
+/// A small part of the vector extension, RVV: setting the vector length, loading and storing words,
+/// and adding, multiplying and reducing vectors of them. The registers are `v0` to `v31`, each with
+/// `VLEN` bits, see [Simulator::vlen](../simulator/struct.Simulator.html#method.vlen). There's no
+/// masking, so every instruction works on all of the first `vl` elements.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VectorInstruction {
+    /// rd, rs1, vtypei, like `vsetvli t0, a0, e32, m1, ta, ma`
+    Setvli(u8, u8, u32),
+    /// vd, rs1, for `vle32.v vd, (rs1)`
+    Le32(u8, u8),
+    /// vs3, rs1, for `vse32.v vs3, (rs1)`
+    Se32(u8, u8),
+    /// vd, vs2, vs1
+    Add(u8, u8, u8),
+    Mul(u8, u8, u8),
+    /// vd, vs2, vs1. The element 0 of `vd` gets the element 0 of `vs1` with every element of `vs2`
+    RedSum(u8, u8, u8),
+    RedMax(u8, u8, u8),
+    RedMaxu(u8, u8, u8),
+    RedMin(u8, u8, u8),
+    RedMinu(u8, u8, u8),
+    /// rd, vs2, the element 0 of `vs2` into an integer register
+    MvXS(u8, u8),
+    /// vd, rs1, an integer register into the element 0 of `vd`
+    MvSX(u8, u8),
+}
+
+/// Giant enum that represents a single RISC-V instruction and its arguments
+#[derive(Debug, PartialEq, Eq)]
+pub enum Instruction {
+    // Type R
+    /// rd, rs1, rs2
+    Add(u8, u8, u8),
+    Sub(u8, u8, u8),
+    Sll(u8, u8, u8),
+    Slt(u8, u8, u8),
+    Sltu(u8, u8, u8),
+    Xor(u8, u8, u8),
+    Srl(u8, u8, u8),
+    Sra(u8, u8, u8),
+    Or(u8, u8, u8),
+    And(u8, u8, u8),
+    Mul(u8, u8, u8),
+    /// The upper 32 bits of the 64-bit product, with both operands signed
+    Mulh(u8, u8, u8),
+    /// Like `Mulh`, but `rs2` is unsigned
+    Mulhsu(u8, u8, u8),
+    Mulhu(u8, u8, u8),
+    Div(u8, u8, u8),
+    Divu(u8, u8, u8),
+    Rem(u8, u8, u8),
+    Remu(u8, u8, u8),
+    /// From Zba, `rs1` shifted left by 1, 2 or 3, plus `rs2`, for indexing arrays of halves, words
+    /// or doubles
+    Sh1add(u8, u8, u8),
+    Sh2add(u8, u8, u8),
+    Sh3add(u8, u8, u8),
+    /// From Zbs, `rs1` with its bit number `rs2` set, cleared or inverted, or that bit by itself in
+    /// `bext`
+    Bset(u8, u8, u8),
+    Bclr(u8, u8, u8),
+    Binv(u8, u8, u8),
+    Bext(u8, u8, u8),
+    /// From Zicond, zero when `rs2` is zero (`eqz`) or isn't (`nez`), or else `rs1`
+    CzeroEqz(u8, u8, u8),
+    CzeroNez(u8, u8, u8),
+
+    // Type I
+    Ecall,
+    /// Stops the program, or goes to the trap handler if there's one
+    Ebreak,
+    /// rd, imm, rs1
+    Lb(u8, u32, u8),
+    Lh(u8, u32, u8),
+    Lw(u8, u32, u8),
+    Lbu(u8, u32, u8),
+    Lhu(u8, u32, u8),
+    /// rd, rs1, imm
+    Addi(u8, u8, u32),
+    Slti(u8, u8, u32),
+    Sltiu(u8, u8, u32),
+    Slli(u8, u8, u32),
+    Srli(u8, u8, u32),
+    Srai(u8, u8, u32),
+    /// The Zbs instructions, with the bit number as the immediate
+    Bseti(u8, u8, u32),
+    Bclri(u8, u8, u32),
+    Binvi(u8, u8, u32),
+    Bexti(u8, u8, u32),
+    Ori(u8, u8, u32),
+    Andi(u8, u8, u32),
+    Xori(u8, u8, u32),
+
+    // Type S
+    /// rs2, imm, rs1
+    Sb(u8, u32, u8),
+    Sh(u8, u32, u8),
+    Sw(u8, u32, u8),
+
+    // Type SB + jumps
+    /// rs1, rs2, label
+    Beq(u8, u8, usize),
+    Bne(u8, u8, usize),
+    Blt(u8, u8, usize),
+    Bge(u8, u8, usize),
+    Bltu(u8, u8, usize),
+    Bgeu(u8, u8, usize),
+    /// rd, rs1, imm
+    Jalr(u8, u8, u32),
+    /// rd, label
+    Jal(u8, usize),
+
+    // Type U
+    /// rd, imm already shifted to the upper 20 bits. `lui` is just a `Li`
+    Auipc(u8, u32),
+
+    /// The sets of accesses before and after it, with a bit each for `iorw`. There's only one hart
+    /// and no cache, so the fences don't do anything.
+    Fence(u8, u8),
+    /// Decodes the code again when it can be modified, see
+    /// [Simulator::self_modifying](../simulator/struct.Simulator.html#method.self_modifying)
+    FenceI,
+
+    // Atomics. Their `.aq` and `.rl` bits aren't kept, for the same reason.
+    /// rd, rs1
+    LrW(u8, u8),
+    /// rd, rs2, rs1, like in `sc.w rd, rs2, (rs1)`
+    ScW(u8, u8, u8),
+    AmoSwapW(u8, u8, u8),
+    AmoAddW(u8, u8, u8),
+    AmoXorW(u8, u8, u8),
+    AmoAndW(u8, u8, u8),
+    AmoOrW(u8, u8, u8),
+    AmoMinW(u8, u8, u8),
+    AmoMaxW(u8, u8, u8),
+    AmoMinuW(u8, u8, u8),
+    AmoMaxuW(u8, u8, u8),
+
+    // CSR
+    /// rd, fcsr, rs1
+    CsrRw(u8, u8, u8),
+    CsrRs(u8, u8, u8),
+    CsrRc(u8, u8, u8),
+    /// rd, fcsr, imm
+    CsrRwi(u8, u8, u32),
+    CsrRsi(u8, u8, u32),
+    CsrRci(u8, u8, u32),
+
+    // Floating point
+    Float(FloatInstruction),
+
+    Rv64(Rv64Instruction),
+
+    Vector(VectorInstruction),
+
+    // Some pseudoinstructions
+    /// rd, imm
+    Li(u8, u32),
+    /// rd, rs1
+    Mv(u8, u8),
+
+    Ret,
+    URet,
+    /// Returns from a trap handler in machine mode, to the privilege level in `mstatus`
+    MRet,
+    /// Waits for an interrupt. There are none, so it doesn't wait at all, which the spec allows.
+    Wfi,
+
+    /// An instruction we don't know, only emitted when parsing with
+    /// [Options::permissive](struct.Options.html#structfield.permissive).
+    /// Keeps the original line so we can tell the user what it was.
+    /// An empty line is the [padding](#method.padding) of RVC code.
+    Illegal(String),
+}
+
+impl Instruction {
+    /// The second half of a 4-byte instruction in [rvc](struct.Options.html#structfield.rvc) code,
+    /// where each element of the code is 2 bytes. Running it means the program jumped into the
+    /// middle of an instruction.
+    pub fn padding() -> Self {
+        Instruction::Illegal(String::new())
+    }
+
+    pub fn is_padding(&self) -> bool {
+        matches!(self, Instruction::Illegal(line) if line.is_empty())
+    }
+}
+
+/// Also giant enum that represents a single RISC-V instruction, but we save
+/// labels as strings because it might not have parsed it yet (for example,
+/// consider a jump instruction that jumps to a label in the next line).
+///
+/// We process the labels stored after the entire file has been parsed.
+#[derive(Debug, PartialEq, Eq)]
+enum PreLabelInstruction {
+    Beq(u8, u8, String),
+    Bne(u8, u8, String),
+    Blt(u8, u8, String),
+    Bge(u8, u8, String),
+    Bltu(u8, u8, String),
+    Bgeu(u8, u8, String),
+    Jal(u8, String),
+
+    /// A `call`, with `ra`, or a `tail`, with `zero`. It's a `jal`, unless linking finds the label
+    /// too far for one, and then the file is assembled again with an `auipc` and a `jalr` in its place
+    Call(u8, String),
+
+    /// The `auipc` of `la rd, label`, or of a load or store from a label, which gets the upper bits
+    /// of the distance from it to the label, rounded so the instruction after it can add the lower ones
+    PcRelHi(u8, String),
+
+    /// The instruction after a `PcRelHi`, like the `addi` of `la` or the `lw` of `lw t0, label`,
+    /// whose immediate gets the lower 12 bits of the distance from the `auipc` to the label
+    PcRelLo(Instruction, String),
+
+    /// The `lui` of `li rd, label`, which gets the upper bits of the value of the expression,
+    /// rounded like in a `PcRelHi`
+    Hi(u8, String),
+
+    /// The `addi` after a `Hi`, which gets the lower 12 bits of the value of the expression
+    Lo(Instruction, String),
+
+    /// An instruction whose immediate depends on labels, like `lw t1 matrix+16(t2)` or
+    /// `li t0 end - start`. The immediate gets replaced by the value of the expression after unlabeling
+    LabelImm(Instruction, String),
+
+    Other(Instruction),
+}
+
+impl From<Instruction> for PreLabelInstruction {
+    fn from(i: Instruction) -> PreLabelInstruction {
+        PreLabelInstruction::Other(i)
+    }
+}
+
+impl From<FloatInstruction> for Instruction {
+    fn from(i: FloatInstruction) -> Instruction {
+        Instruction::Float(i)
+    }
+}
+
+impl From<FloatInstruction> for PreLabelInstruction {
+    fn from(i: FloatInstruction) -> PreLabelInstruction {
+        PreLabelInstruction::Other(Instruction::Float(i))
+    }
+}
+
+impl From<Rv64Instruction> for Instruction {
+    fn from(i: Rv64Instruction) -> Instruction {
+        Instruction::Rv64(i)
+    }
+}
+
+impl From<Rv64Instruction> for PreLabelInstruction {
+    fn from(i: Rv64Instruction) -> PreLabelInstruction {
+        PreLabelInstruction::Other(Instruction::Rv64(i))
+    }
+}
+
+impl From<VectorInstruction> for Instruction {
+    fn from(i: VectorInstruction) -> Instruction {
+        Instruction::Vector(i)
+    }
+}
+
+impl From<VectorInstruction> for PreLabelInstruction {
+    fn from(i: VectorInstruction) -> PreLabelInstruction {
+        PreLabelInstruction::Other(Instruction::Vector(i))
+    }
+}
+
+/// Represents a successful parser result. This is the same format the simulator
+/// will use to execute the instructions
+pub struct Parsed {
+    pub code: Vec<Instruction>,
+    /// Where each instruction came from. The ones we add at the end, to exit, don't have a position
+    pub code_pos: Vec<Option<Arc<Pos>>>,
+    pub data: Vec<u8>,
+    /// Addresses of the data that came from `.section .rodata`, which the program can't change
+    pub read_only: Vec<Range<usize>>,
+    /// Every label of the program, in the order they were defined
+    pub symbols: Vec<Symbol>,
+    /// [Warnings](enum.Warning.html) about the code, each one wrapped in an `Error::OnLine`
+    pub warnings: Vec<Error>,
+    /// Where the program starts, if not at its first instruction, see [Options::entry](struct.Options.html#structfield.entry)
+    pub entry: Option<usize>,
+    /// Address of the exit we add after the code. A program that starts at an `entry` returns here.
+    pub exit: usize,
+    /// Address right after the data and the space of the `.extern`s, where the heap can start
+    pub data_end: usize,
+}
+
+/// Where a label points to. The code and the data have addresses of their own, so an address alone
+/// doesn't say what's there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Segment {
+    Text,
+    Data,
+    ReadOnlyData,
+    /// Space reserved by `.extern`, after the data of every file
+    Extern,
+}
+
+impl std::fmt::Display for Segment {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Segment::Text => "text",
+            Segment::Data => "data",
+            Segment::ReadOnlyData => "rodata",
+            Segment::Extern => "extern",
+        };
+        f.pad(name)
+    }
+}
+
+/// A label and where it ended up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: usize,
+    pub segment: Segment,
+    /// Declared with `.globl` or `.extern`, so every file can see it
+    pub global: bool,
+}
+
+pub type ParseResult = Result<Parsed, Error>;
+
+/// Changes how [parse_riscv](trait.RISCVParser.html#tymethod.parse_riscv) treats the code
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Instead of failing on instructions we don't know, emit an `Instruction::Illegal`
+    /// and let the simulator decide what to do with it
+    pub permissive: bool,
+
+    /// Address of the first instruction, so labels in `.text` point to the right place when the
+    /// code doesn't start at 0
+    pub text_base: usize,
+
+    /// Address of the first byte of the `.data`
+    pub data_base: usize,
+
+    /// Treat the [warnings](enum.Warning.html) as errors
+    pub werror: bool,
+
+    /// Warn about the labels nothing refers to, which are often just there to name a loop
+    pub warn_unused: bool,
+
+    /// Only accept what RARS accepts: the directives it knows, with their exact names, data
+    /// directives only in the `.data`, no numeric labels and no expressions in the operands
+    pub strict: bool,
+
+    /// The label the program starts at. Without one, it starts at `main` if it's global, like RARS
+    /// does when set to, or else at its first instruction.
+    pub entry: Option<String>,
+
+    /// Parse RV64 code, where the registers are 64 bits wide and there are the
+    /// [Rv64Instructions](enum.Rv64Instruction.html)
+    pub rv64: bool,
+
+    /// Parse RV32E code, which only has the registers x0 to x15
+    pub rv32e: bool,
+
+    /// Accept the compressed instructions of RV32C, like `c.addi`, which take 2 bytes. Each element
+    /// of the code is then 2 bytes, and the 4-byte instructions are followed by a
+    /// [padding](enum.Instruction.html#method.padding).
+    pub rvc: bool,
+}
+
+impl Options {
+    /// How many bytes each element of the code takes
+    pub fn slot_size(&self) -> usize {
+        if self.rvc {
+            2
+        } else {
+            4
+        }
+    }
+}
+
+/// The code isn't in the memory, so the `.text` could be as large as we wanted, but `.text ADDR`
+/// fills everything before `ADDR` with instructions, so it can't go too far
+const TEXT_SIZE: usize = 0x0040_0000;
+
+/// The address in `.data ADDR` or `.text ADDR`, if there's one. `args` is what comes after the
+/// `.data` or `.text`.
+fn segment_address(args: &str) -> Result<Option<usize>, Error> {
+    use combinators::*;
+    use nom::{combinator::all_consuming, sequence::terminated};
+
+    // Like RARS, we accept `.database` as a `.data`, unless we're being strict
+    if !args.starts_with(is_separator) {
+        return Ok(None);
+    }
+
+    let (args, _) = separator0(args)?;
+    if args.is_empty() {
+        return Ok(None);
+    }
+
+    let (_, address) = all_consuming(terminated(constant_expr, separator0))(args)?;
+    Ok(Some(address as usize))
+}
+
+/// Directives in the output of `gcc -S` that only matter to a linker or a debugger
+const IGNORED_DIRECTIVES: &[&str] = &[".file", ".ident", ".type", ".size", ".option", ".attribute", ".local"];
+
+fn is_ignored_directive(line: &str) -> bool {
+    let name = line.split(combinators::is_separator).next().unwrap_or("");
+    IGNORED_DIRECTIVES.contains(&name) || name.starts_with(".cfi_")
+}
+
+/// The "current" parser directive
+enum Directive {
+    Text,
+    Data,
+    /// Like `.data`, but the program can't write to it
+    ReadOnlyData,
+}
+
+impl Directive {
+    /// Where the labels defined under this directive go
+    fn segment(&self) -> Segment {
+        match self {
+            Directive::Text => Segment::Text,
+            Directive::Data => Segment::Data,
+            Directive::ReadOnlyData => Segment::ReadOnlyData,
+        }
+    }
+
+    /// The directive of a `.section`. We only know the usual ones, and everything else goes to the data
+    fn of_section(name: &str) -> Directive {
+        if name.starts_with(".text") {
+            Directive::Text
+        } else if name.starts_with(".rodata") || name.starts_with(".srodata") {
+            Directive::ReadOnlyData
+        } else {
+            Directive::Data
+        }
+    }
+}
+
+pub trait RISCVParser {
+    /// Parses an iterator of preprocessed lines and returns the instructions and
+    /// the data it parsed. Remember to preprocess the iterator before calling this,
+    /// as `parse_riscv` does not understand macros and includes.
+    /// ```
+    /// parse::file_lines("riscv.s".to_owned())?
+    ///     .parse_includes()
+    ///     .parse_macros()
+    ///     .parse_riscv(DATA_SIZE, &Options::default())?;
+    /// ```
+    ///
+    /// The `data_segment_size` parameter is the final size of the data segment, in bytes.
+    /// To put several files together in the same program, use [link](fn.link.html) instead.
+    fn parse_riscv(self, data_segment_size: usize, options: &Options) -> ParseResult;
+}
+
+impl<I: Iterator<Item = Line>> RISCVParser for I {
+    fn parse_riscv(self, data_segment_size: usize, options: &Options) -> ParseResult {
+        link(std::iter::once(self), data_segment_size, options)
+    }
+}
+
+/// The machine code of `instruction` at the address `pc`, if it has one. Some pseudoinstructions
+/// we run as a single instruction don't, like a `li` with a constant that needs more than 12 bits.
+pub fn encode(instruction: &Instruction, pc: usize) -> Option<u32> {
+    text::encode(instruction, pc)
+}
+
+/// The 2-byte machine code of `instruction` at the address `pc`, if it has a compressed form
+pub fn compress(instruction: &Instruction, pc: usize) -> Option<u16> {
+    text::compress(instruction, pc)
+}
+
+/// Decodes the instruction `word` at the address `pc`, like a `.word` in the `.text`. The ones we
+/// can't decode become `Illegal`, like they'd be in a real processor. `status` has the names of
+/// the CSRs, from [register_names::status](register_names/fn.status.html).
+pub fn decode(word: u32, pc: usize, rv64: bool, status: &register_names::RegMap) -> Instruction {
+    text::decode(word, pc, status)
+        .and_then(|i| text::for_width(i, rv64))
+        .unwrap_or_else(|| Instruction::Illegal(format!(".word {:#010x}", word)))
+}
+
+/// Parses the lines of a single file, one at a time, without resolving its labels. The code and
+/// data start at `options.text_base` and `options.data_base`, so the positions of the labels are
+/// already final. A line with an error doesn't change anything, so we can go on to the next one.
+struct Assembler {
+    options: Options,
+    /// Where the data segment ends, so `.data ADDR` doesn't go past it
+    data_end: usize,
+    /// The lines with a `call` or `tail` that linking found too far for a `jal`
+    far_calls: FnvHashSet<Pos>,
+    regmaps: FullRegMap,
+    directive: Directive,
+
+    labels: Trie<String, usize>,
+    /// Where each label was defined, to point at the first one if it's defined again
+    label_pos: FnvHashMap<String, Arc<Pos>>,
+    locals: local_labels::LocalLabels,
+    /// The labels that were defined, except for the numeric ones
+    symbols: Vec<Symbol>,
+    globals: Vec<String>,
+    externs: Vec<(String, usize)>,
+
+    code: Vec<PreLabelInstruction>,
+    data: Vec<u8>,
+    current_data_type: data::Type,
+    data_labels: Vec<data::Label>,
+    read_only: Vec<Range<usize>>,
+
+    // Where each instruction and `.data` label came from, for the errors we find when linking
+    code_pos: Vec<Arc<Pos>>,
+    data_label_pos: Vec<Arc<Pos>>,
+
+    warnings: Vec<Error>,
+}
+
+impl Assembler {
+    /// The data of the file has to end before `data_end`, and the calls in `far_calls` take an
+    /// `auipc` and a `jalr`
+    fn new(options: &Options, data_end: usize, far_calls: &FnvHashSet<Pos>) -> Self {
+        Self {
+            options: options.clone(),
+            data_end,
+            far_calls: far_calls.clone(),
+            regmaps: (
+                if options.rv32e { reg_names::rv32e_regs() } else { reg_names::regs() },
+                reg_names::floats(),
+                reg_names::status(),
+            ),
+            directive: Directive::Text,
+            labels: Trie::new(),
+            label_pos: FnvHashMap::default(),
+            locals: Default::default(),
+            symbols: Vec::new(),
+            globals: Vec::new(),
+            externs: Vec::new(),
+            code: Vec::new(),
+            data: Vec::new(),
+            current_data_type: data::Type::default(),
+            data_labels: Vec::new(),
+            read_only: Vec::new(),
+            code_pos: Vec::new(),
+            data_label_pos: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    fn line(&mut self, full_line: &Line) -> Result<(), Error> {
+        use combinators::*;
+
+        // A label defined twice keeps its first definition. The rest of the line is still parsed,
+        // so the labels after it don't move.
+        let mut duplicate = None;
+        let line = match parse_label(&full_line.text) {
+            Ok((rest, label)) => {
+                if self.options.strict {
+                    strict::check_label(label).wrap_meta(full_line)?;
+                }
+                let label_pos = match self.directive {
+                    Directive::Text => self.options.text_base + self.code.len() * self.options.slot_size(),
+                    Directive::Data | Directive::ReadOnlyData => self.options.data_base + self.data.len(),
+                };
+                let local = self.locals.define(label);
+                let is_local = local.is_some();
+                let name = local.unwrap_or_else(|| label.to_owned());
+                match self.label_pos.get(&name) {
+                    Some(first) => duplicate = Some(Error::DuplicateLabel(name, first.clone())),
+                    None if is_local => {
+                        self.label_pos.insert(name.clone(), full_line.pos.clone());
+                        self.labels.insert(name, label_pos);
+                    }
+                    None => {
+                        self.label_pos.insert(name.clone(), full_line.pos.clone());
+                        self.symbols.push(Symbol {
+                            name: name.clone(),
+                            address: label_pos,
+                            segment: self.directive.segment(),
+                            global: false,
+                        });
+                        self.labels.insert(name, label_pos);
+                    }
+                }
+                rest
+            }
+            Err(_) => &full_line.text,
+        };
+        let line = self.locals.replace(line, &full_line.pos).wrap_meta(full_line)?;
+        match (self.statement(&line, full_line), duplicate) {
+            (Ok(()), Some(e)) => Err(e).wrap_meta(full_line),
+            (res, _) => res,
+        }
+    }
+
+    /// Parses what comes after the label of a line, if there's anything
+    fn statement(&mut self, line: &str, full_line: &Line) -> Result<(), Error> {
+        use combinators::*;
+
+        let (line, _) = separator0(line)?;
+        if line.is_empty() {
+            return Ok(());
+        }
+        if self.options.strict {
+            strict::check(line, matches!(self.directive, Directive::Text)).wrap_meta(full_line)?;
+        }
+
+        // Identify directives
+        // This accepts stuff like ".textSOMETHING" or ".database", but RARS accepts it too
+        // Gotta be consistent! ¯\_(ツ)_/¯ The strict checks above already rejected them if needed
+        if let Some(args) = line.strip_prefix(".data") {
+            self.directive = Directive::Data;
+            if let Some(address) = segment_address(args).wrap_meta(full_line)? {
+                self.move_data(address, args.trim()).wrap_meta(full_line)?;
+            }
+            return Ok(());
+        } else if let Some(args) = line.strip_prefix(".text") {
+            self.directive = Directive::Text;
+            if let Some(address) = segment_address(args).wrap_meta(full_line)? {
+                self.move_text(address, args.trim()).wrap_meta(full_line)?;
+                self.code_pos.resize(self.code.len(), full_line.pos.clone());
+            }
+            return Ok(());
+        } else if let Some(args) = line.strip_prefix(".section") {
+            // Flags like in `.section .rodata, "a"` don't matter to us
+            let name = args.split(is_separator).find(|name| !name.is_empty()).unwrap_or("");
+            self.directive = Directive::of_section(name);
+            return Ok(());
+        } else if line.starts_with(".bss") {
+            self.directive = Directive::Data;
+            return Ok(());
+        } else if is_ignored_directive(line) {
+            return Ok(());
+        } else if let Some(align) = data::code_alignment(line).filter(|_| matches!(self.directive, Directive::Text)) {
+            // Instructions are always aligned to their size, more than that takes some nops, which
+            // are `c.nop`s with `rvc`
+            let align = align.wrap_meta(full_line)?;
+            let slot = self.options.slot_size();
+            let here = self.options.text_base + self.code.len() * slot;
+            let nops = (here.div_ceil(align) * align - here) / slot;
+            self.code.resize_with(self.code.len() + nops, || Instruction::Mv(0, 0).into());
+            self.code_pos.resize(self.code.len(), full_line.pos.clone());
+            return Ok(());
+        } else if let Some(names) = line.strip_prefix(".globl").or_else(|| line.strip_prefix(".global")) {
+            let names = names.split(is_separator).filter(|name| !name.is_empty());
+            self.globals.extend(names.map(str::to_owned));
+            return Ok(());
+        } else if let Some(args) = line.strip_prefix(".extern") {
+            let (name, size) = args_extern(args.trim_start()).wrap_meta(full_line)?;
+            self.externs.push((name, size as usize));
+            return Ok(());
+        } else if let Some(message) = line.strip_prefix(".error") {
+            let message = message.trim().trim_matches('"');
+            return Err(Error::Custom(message.to_owned())).wrap_meta(full_line);
+        } else if let Ok((_, file)) = include_directive(line) {
+            // The preprocessor leaves the includes it couldn't open
+            return Err(Error::IncludeNotFound(file)).wrap_meta(full_line);
+        }
+
+        let (code_len, data_len, data_labels_len) = (self.code.len(), self.data.len(), self.data_labels.len());
+        let res = match self.directive {
+            Directive::Text if line.starts_with(".word") => self.text_words(line),
+            Directive::Text if self.options.rvc && line.starts_with(".half") => self.text_halves(line),
+            Directive::Text => self.instruction(line, &full_line.pos),
+            Directive::Data | Directive::ReadOnlyData => data::parse_line(
+                line,
+                &mut self.data,
+                &mut self.data_labels,
+                &mut self.current_data_type,
+            ),
+        };
+
+        if let Err(e) = res {
+            // Forget whatever the line managed to push before the error
+            self.code.truncate(code_len);
+            self.data.truncate(data_len);
+            self.data_labels.truncate(data_labels_len);
+            return Err(e.span_in(line, &full_line.text)).wrap_meta(full_line);
+        }
+
+        if self.data.len() > data_len {
+            self.check_alignment(self.options.data_base + data_len, line, full_line);
+        }
+
+        if let Directive::ReadOnlyData = self.directive {
+            let (start, end) = (self.options.data_base + data_len, self.options.data_base + self.data.len());
+            match self.read_only.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ if start < end => self.read_only.push(start..end),
+                _ => {}
+            }
+        }
+
+        self.code_pos.resize(self.code.len(), full_line.pos.clone());
+        self.data_label_pos.resize(self.data_labels.len(), full_line.pos.clone());
+        Ok(())
+    }
+
+    /// Warns if the data that was just written at `address` isn't aligned to its size, like a
+    /// `.word` right after a `.byte`
+    fn check_alignment(&mut self, address: usize, line: &str, full_line: &Line) {
+        let size = match self.current_data_type {
+            data::Type::Double | data::Type::Dword => 8,
+            data::Type::Word | data::Type::Float => 4,
+            data::Type::Half => 2,
+            _ => return,
+        };
+
+        if !address.is_multiple_of(size) {
+            let directive = line.split(combinators::is_separator).next().unwrap_or(line);
+            let warning = Warning::UnalignedData(directive.to_owned(), address);
+            self.warnings.push(Error::Warning(warning).at(&full_line.pos, &full_line.text));
+        }
+    }
+
+    /// Moves the end of the `.data` forward to `address`, like in `.data 0x1000`. The bytes we skip
+    /// are zeros.
+    fn move_data(&mut self, address: usize, text: &str) -> Result<(), Error> {
+        let here = self.options.data_base + self.data.len();
+        let why = if address < here {
+            format!("the .data is already at {:#x}", here)
+        } else if address >= self.data_end {
+            let (start, end) = (self.options.data_base, self.data_end);
+            format!("the data segment goes from {:#x} to {:#x}", start, end)
+        } else {
+            self.data.resize(address - self.options.data_base, 0);
+            return Ok(());
+        };
+        Err(Error::InvalidAddress(text.to_owned(), why))
+    }
+
+    /// Moves the end of the `.text` forward to `address`, like in `.text 0x1000`. The words we skip
+    /// are zeros, which aren't valid instructions.
+    fn move_text(&mut self, address: usize, text: &str) -> Result<(), Error> {
+        let slot = self.options.slot_size();
+        let here = self.options.text_base + self.code.len() * slot;
+        let end = self.options.text_base + TEXT_SIZE;
+        let why = if !address.is_multiple_of(slot) {
+            format!("instructions are aligned to {} bytes", slot)
+        } else if address < here {
+            format!("the .text is already at {:#x}", here)
+        } else if address >= end {
+            format!("the text segment goes from {:#x} to {:#x}", self.options.text_base, end)
+        } else {
+            let zero = if self.options.rvc { ".half 0x0000" } else { ".word 0x00000000" };
+            let zero = || Instruction::Illegal(zero.to_owned()).into();
+            self.code.resize_with(self.code.len() + (address - here) / slot, zero);
+            return Ok(());
+        };
+        Err(Error::InvalidAddress(text.to_owned(), why))
+    }
+
+    /// Parses an instruction, or a pseudoinstruction that becomes many. With
+    /// [rvc](struct.Options.html#structfield.rvc), the ones that aren't compressed take two slots.
+    fn instruction(&mut self, line: &str, pos: &Pos) -> Result<(), Error> {
+        if let Some(mnemonic) = text::compressed_mnemonic(line) {
+            if !self.options.rvc {
+                return Err(Error::NeedsRvc(mnemonic.to_owned()));
+            }
+            // Always a single instruction, unlike a `li` that becomes two. Whether it fits in 2
+            // bytes is only checked when linking, once we know where its labels are.
+            let instruction = text::parse_instruction(line, &self.regmaps, self.options.rv64)?;
+            self.code.push(instruction);
+            return Ok(());
+        }
+
+        let start = self.code.len();
+        match text::parse_line(line, &self.regmaps, self.options.rv64, &mut self.code) {
+            Err(Error::UnknownInstruction(..)) if self.options.permissive => {
+                self.code.push(Instruction::Illegal(line.to_owned()).into());
+            }
+            res => res?,
+        }
+
+        // Like in RARS, with `ra` holding the upper bits of a `call`, and `t1` of a `tail`
+        if let [PreLabelInstruction::Call(rd, label)] = &self.code[start..] {
+            if self.far_calls.contains(pos) {
+                let (rd, label) = (*rd, label.clone());
+                let temp = if rd == 0 { 6 } else { rd };
+                self.code.truncate(start);
+                self.code.extend([
+                    PreLabelInstruction::PcRelHi(temp, label.clone()),
+                    PreLabelInstruction::PcRelLo(Instruction::Jalr(rd, temp, 0), label),
+                ]);
+            }
+        }
+
+        if self.options.rvc {
+            for instruction in self.code.split_off(start) {
+                self.code.push(instruction);
+                self.code.push(Instruction::padding().into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The bytes of a `.word` or `.half` in the `.text`, which can't have labels
+    fn text_bytes(line: &str) -> Result<Vec<u8>, Error> {
+        let (mut bytes, mut labels) = (Vec::new(), Vec::new());
+        data::parse_line(line, &mut bytes, &mut labels, &mut data::Type::default())?;
+        match labels.first() {
+            Some(label) => Err(Error::InvalidImmediate(label.label.clone())),
+            None => Ok(bytes),
+        }
+    }
+
+    /// `.word`s in the `.text` are instructions that were already encoded, like `.word 0x00000013`.
+    /// The ones we can't decode become `Illegal`, like they'd be in a real processor.
+    fn text_words(&mut self, line: &str) -> Result<(), Error> {
+        let bytes = Self::text_bytes(line)?;
+        for word in bytes.chunks(4).map(LittleEndian::read_u32) {
+            let pc = self.options.text_base + self.code.len() * self.options.slot_size();
+            self.code.push(decode(word, pc, self.options.rv64, &self.regmaps.2).into());
+            if self.options.rvc {
+                self.code.push(Instruction::padding().into());
+            }
+        }
+        Ok(())
+    }
+
+    /// With [rvc](struct.Options.html#structfield.rvc), `.half`s in the `.text` are compressed
+    /// instructions that were already encoded, like `.half 0x0505`. Like with
+    /// [text_words](#method.text_words), the ones we can't decode become `Illegal`.
+    fn text_halves(&mut self, line: &str) -> Result<(), Error> {
+        let bytes = Self::text_bytes(line)?;
+        for half in bytes.chunks(2).map(LittleEndian::read_u16) {
+            let pc = self.options.text_base + self.code.len() * 2;
+            let instruction = text::decompress(half, pc)
+                .unwrap_or_else(|| Instruction::Illegal(format!(".half {:#06x}", half)));
+            self.code.push(instruction.into());
+        }
+        Ok(())
+    }
+
+    /// Finishes the file. Errors that can only be found at its end are pushed into `errors`.
+    fn finish(mut self, errors: &mut Vec<Error>) -> Object {
+        errors.extend(self.locals.check(&mut self.labels));
+
+        // Keeps the data of the next file aligned to a word
+        let aligned_len = self.data.len().div_ceil(4) * 4;
+        self.data.resize(aligned_len, 0);
+
+        for symbol in &mut self.symbols {
+            symbol.global = self.globals.contains(&symbol.name);
+        }
+
+        // Only the labels of this file are known here, a jump to the data of another file isn't caught
+        for (instruction, pos) in self.code.iter().zip(&self.code_pos) {
+            let label = match jump_label(instruction) {
+                Some(label) => label,
+                None => continue,
+            };
+            let is_data = |symbol: &Symbol| symbol.name == label && symbol.segment != Segment::Text;
+            if self.symbols.iter().any(is_data) {
+                let warning = Warning::BranchToData(label.to_owned());
+                self.warnings.push(Error::Warning(warning).at(pos, &pos.source));
+            }
+        }
+
+        Object {
+            code: self.code,
+            code_pos: self.code_pos,
+            data: self.data,
+            data_labels: self.data_labels,
+            data_label_pos: self.data_label_pos,
+            read_only: self.read_only,
+            labels: self.labels,
+            label_pos: self.label_pos,
+            symbols: self.symbols,
+            globals: self.globals,
+            externs: self.externs,
+            warnings: self.warnings,
+        }
+    }
+}
+
+/// The label a branch or a jump goes to, if it's one
+fn jump_label(instruction: &PreLabelInstruction) -> Option<&str> {
+    use PreLabelInstruction as p;
+    match instruction {
+        p::Beq(_, _, label) | p::Bne(_, _, label) | p::Blt(_, _, label) | p::Bge(_, _, label)
+        | p::Bltu(_, _, label) | p::Bgeu(_, _, label) | p::Jal(_, label) | p::Call(_, label) => Some(label),
+        _ => None,
+    }
+}
+
+/// The upper 20 bits of `x`, rounded up when the lower 12 are negative, so that adding
+/// [lower_bits](fn.lower_bits.html) of it to them gets back to `x`
+fn upper_bits(x: u32) -> u32 {
+    x.wrapping_sub(lower_bits(x))
+}
+
+/// The lower 12 bits of `x`, sign extended, like the immediate of an `addi` takes them
+fn lower_bits(x: u32) -> u32 {
+    (((x << 20) as i32) >> 20) as u32
+}
+
+/// Transforms a PreLabelInstruction into a normal Instruction by "commiting" the labels
+/// into positions in the code. For example, Jal(0, "Label") maps to Jal(0, labels_trie.get("Label")).
+/// `pc` is the address of the instruction, which the `auipc`s need.
+fn unlabel_instruction(
+    instruction: PreLabelInstruction,
+    pc: usize,
+    labels: &Symbols,
+) -> Result<Instruction, Error> {
+    use Instruction::*;
+    use PreLabelInstruction as p;
+
+    macro_rules! unlabel {
+        ($inst:ident, $rd:ident, $label:ident) => {
+            resolve_label(&$label, labels).map(|pos| $inst($rd, pos as usize))
+        };
+        ($inst:ident, $rs1:ident, $rs2:ident, $label:ident) => {
+            resolve_label(&$label, labels).map(|pos| $inst($rs1, $rs2, pos as usize))
+        };
+    }
+
+    match instruction {
+        p::Jal(rd, label) | p::Call(rd, label) => unlabel!(Jal, rd, label),
+        p::Beq(rs1, rs2, label) => unlabel!(Beq, rs1, rs2, label),
+        p::Bne(rs1, rs2, label) => unlabel!(Bne, rs1, rs2, label),
+        p::Bge(rs1, rs2, label) => unlabel!(Bge, rs1, rs2, label),
+        p::Blt(rs1, rs2, label) => unlabel!(Blt, rs1, rs2, label),
+        p::Bltu(rs1, rs2, label) => unlabel!(Bltu, rs1, rs2, label),
+        p::Bgeu(rs1, rs2, label) => unlabel!(Bgeu, rs1, rs2, label),
+
+        p::PcRelHi(rd, label) => {
+            resolve_label(&label, labels).map(|pos| Auipc(rd, upper_bits(pos.wrapping_sub(pc as u32))))
+        }
+        p::Hi(rd, label) => resolve_label(&label, labels).map(|value| Li(rd, upper_bits(value))),
+        // The `auipc` or `lui` right before already says if the label isn't there
+        p::PcRelLo(instruction, label) => {
+            let auipc = pc.wrapping_sub(4) as u32;
+            let pos = resolve_label(&label, labels).unwrap_or(auipc);
+            Ok(with_imm(instruction, lower_bits(pos.wrapping_sub(auipc))))
+        }
+        p::Lo(instruction, label) => {
+            let value = resolve_label(&label, labels).unwrap_or(0);
+            Ok(with_imm(instruction, lower_bits(value)))
+        }
+        p::LabelImm(instruction, label) => {
+            resolve_label(&label, labels).map(|imm| with_imm(instruction, imm))
+        }
+
+        p::Other(instruction) => Ok(instruction),
+    }
+}
+
+/// Evaluates a label expression, which is either a plain label or an expression with labels
+/// in it, like `array+8` or `end - start`
+fn resolve_label(expr: &str, labels: &Symbols) -> Result<u32, Error> {
+    if let Some(pos) = labels.get(expr) {
+        return Ok(pos as u32);
+    }
+
+    let parsed = match nom::combinator::all_consuming(combinators::expr)(expr) {
+        Ok((_, parsed)) => parsed,
+        Err(_) => return Err(Error::LabelNotFound(expr.to_owned(), None)),
+    };
+
+    let value = parsed.eval(&|label| labels.get(label).map(|pos| pos as i64))?;
+    Ok(value as u32)
+}
+
+/// Replaces the immediate of an instruction, see [PreLabelInstruction::LabelImm](enum.PreLabelInstruction.html)
+fn with_imm(instruction: Instruction, imm: u32) -> Instruction {
+    use Instruction::*;
+    use FloatInstruction as F;
+    use Rv64Instruction as R;
+
+    match instruction {
+        Lb(rd, _, rs1) => Lb(rd, imm, rs1),
+        Lh(rd, _, rs1) => Lh(rd, imm, rs1),
+        Lw(rd, _, rs1) => Lw(rd, imm, rs1),
+        Lbu(rd, _, rs1) => Lbu(rd, imm, rs1),
+        Lhu(rd, _, rs1) => Lhu(rd, imm, rs1),
+        Sb(rs2, _, rs1) => Sb(rs2, imm, rs1),
+        Sh(rs2, _, rs1) => Sh(rs2, imm, rs1),
+        Sw(rs2, _, rs1) => Sw(rs2, imm, rs1),
+        Float(F::Lw(rd, _, rs1)) => Float(F::Lw(rd, imm, rs1)),
+        Float(F::Sw(rs2, _, rs1)) => Float(F::Sw(rs2, imm, rs1)),
+        Float(F::Ld(rd, _, rs1)) => Float(F::Ld(rd, imm, rs1)),
+        Float(F::Sd(rs2, _, rs1)) => Float(F::Sd(rs2, imm, rs1)),
+        Rv64(R::Ld(rd, _, rs1)) => Rv64(R::Ld(rd, imm, rs1)),
+        Rv64(R::Lwu(rd, _, rs1)) => Rv64(R::Lwu(rd, imm, rs1)),
+        Rv64(R::Sd(rs2, _, rs1)) => Rv64(R::Sd(rs2, imm, rs1)),
+        Rv64(R::Addi(rd, rs1, _)) => Rv64(R::Addi(rd, rs1, imm)),
+        Rv64(R::Addiw(rd, rs1, _)) => Rv64(R::Addiw(rd, rs1, imm)),
+        Rv64(R::Slli(rd, rs1, _)) => Rv64(R::Slli(rd, rs1, imm)),
+        Rv64(R::Srli(rd, rs1, _)) => Rv64(R::Srli(rd, rs1, imm)),
+        Rv64(R::Srai(rd, rs1, _)) => Rv64(R::Srai(rd, rs1, imm)),
+        Rv64(R::Slliw(rd, rs1, _)) => Rv64(R::Slliw(rd, rs1, imm)),
+        Rv64(R::Srliw(rd, rs1, _)) => Rv64(R::Srliw(rd, rs1, imm)),
+        Rv64(R::Sraiw(rd, rs1, _)) => Rv64(R::Sraiw(rd, rs1, imm)),
+        Rv64(R::Bseti(rd, rs1, _)) => Rv64(R::Bseti(rd, rs1, imm)),
+        Rv64(R::Bclri(rd, rs1, _)) => Rv64(R::Bclri(rd, rs1, imm)),
+        Rv64(R::Binvi(rd, rs1, _)) => Rv64(R::Binvi(rd, rs1, imm)),
+        Rv64(R::Bexti(rd, rs1, _)) => Rv64(R::Bexti(rd, rs1, imm)),
+        Addi(rd, rs1, _) => Addi(rd, rs1, imm),
+        Slti(rd, rs1, _) => Slti(rd, rs1, imm),
+        Sltiu(rd, rs1, _) => Sltiu(rd, rs1, imm),
+        Slli(rd, rs1, _) => Slli(rd, rs1, imm),
+        Srli(rd, rs1, _) => Srli(rd, rs1, imm),
+        Srai(rd, rs1, _) => Srai(rd, rs1, imm),
+        Bseti(rd, rs1, _) => Bseti(rd, rs1, imm),
+        Bclri(rd, rs1, _) => Bclri(rd, rs1, imm),
+        Binvi(rd, rs1, _) => Binvi(rd, rs1, imm),
+        Bexti(rd, rs1, _) => Bexti(rd, rs1, imm),
+        Ori(rd, rs1, _) => Ori(rd, rs1, imm),
+        Andi(rd, rs1, _) => Andi(rd, rs1, imm),
+        Xori(rd, rs1, _) => Xori(rd, rs1, imm),
+        Jalr(rd, rs1, _) => Jalr(rd, rs1, imm),
+        Li(rd, _) => Li(rd, imm),
+        Auipc(rd, _) => Auipc(rd, imm),
+        _ => unreachable!("{:?} doesn't have an immediate to replace", instruction),
+    }
+}
+
+/// Replaces the position in the `.data` that had a label with its
+/// actual value. Returns a warning if the value didn't fit.
+fn unlabel_data(data_label: data::Label, data: &mut [u8], labels: &Symbols) -> Result<Option<Warning>, Error> {
+    let data::Label{ pos, dtype, label } = data_label;
+
+    let value = resolve_label(&label, labels)?;
+
+    use data::Type::*;
+    let bits = match dtype {
+        Byte => {
+            data[pos] = value as u8;
+            8
+        }
+        Half => {
+            LittleEndian::write_u16(&mut data[pos..], value as u16);
+            16
+        }
+        Word => {
+            LittleEndian::write_u32(&mut data[pos..], value);
+            32
+        }
+        Dword => {
+            LittleEndian::write_u64(&mut data[pos..], value as u64);
+            64
+        }
+        _ => unreachable!("label can only be parsed in .byte, .half, .word or .dword"),
+    };
+
+    if data::fits_in(value, bits) {
+        Ok(None)
+    } else {
+        Ok(Some(Warning::Truncated(label, bits)))
+    }
 }
\ No newline at end of file
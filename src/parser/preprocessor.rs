@@ -1,377 +1,842 @@
-use fnv::FnvHashMap;
-use std::mem;
-use std::path::PathBuf;
-
-use super::combinators::*;
-use super::util::*;
-
-/// Generally created by calling [parse_includes](trait.Includable.html#method.parse_includes)
-/// on an iterator of Strings
-// TODO: check for ciclic includes, preferably in a better way than RARS
-// (we should allow a file to be included more than once, maybe?)
-pub struct Includer<'a> {
-    /// Stack of line iterators. Every time we encounter an .include,
-    /// we push its iterator onto the stack.
-    stack: Vec<Box<dyn Iterator<Item = String> + 'a>>,
-
-    /// Stores the directory of each file include (the path but without the actual filename at the end)
-    paths: Vec<PathBuf>,
-}
-
-impl<'a> Includer<'a> {
-    fn pop(&mut self) {
-        self.stack.pop();
-        self.paths.pop();
-    }
-}
-
-impl<'a> Iterator for Includer<'a> {
-    type Item = String;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        // Check the last iterator of the stack until we find one that still has items
-        let line = loop {
-            let maybe_line = match self.stack.last_mut() {
-                Some(iterator) => iterator.next(),
-                None => return None,
-            };
-
-            match maybe_line {
-                Some(line) => {
-                    break line;
-                }
-                None => {
-                    self.pop();
-                }
-            }
-        };
-
-        let line = strip_unneeded(&line).unwrap();
-
-        if let Ok((_, file)) = include_directive(&line) {
-            // Get the current path and push the filename
-            let mut path = self.paths.last().unwrap().clone();
-            path.push(file);
-
-            // Push the new file line iterator onto the stack
-            let error = format!("Can't open file: <{:?}>", path.to_str());
-            self.stack
-                .push(Box::new(file_lines(path.clone()).expect(&error)));
-
-            // Push the new current path onto the stack
-            path.pop();
-            self.paths.push(path);
-
-            self.next()
-        } else {
-            Some(line.into())
-        }
-    }
-}
-
-pub trait Includable<'a, I: Iterator<Item = String> + 'a> {
-    /// Returns an iterator over RISC-V lines that can process `.include "file"` directives
-    /// and flatten all of the files into one stream. Refer to
-    /// [RISCVParser](../trait.RISCVParser.html#fn.parse_riscv) for example usage.
-    ///
-    /// Also removes comments for some reason.
-    fn parse_includes(self, filepath: PathBuf) -> Includer<'a>;
-}
-
-impl<'a, I: Iterator<Item = String> + 'a> Includable<'a, I> for I {
-    fn parse_includes(self, mut filepath: PathBuf) -> Includer<'a> {
-        filepath.pop(); // discard the filename
-        Includer {
-            stack: vec![Box::new(self)],
-            paths: vec![filepath],
-        }
-    }
-}
-
-/// We store each line of a parsed macro in a similar manner to JavaScript's template strings.
-/// When the arguments are applied (in [build()](struct.MacroLine.html#method.build)),
-/// we output the concatenation `{ raw[0], arg[0], raw[1], arg[1], ..., raw[n-1], arg[n-1], raw[n] }`
-#[derive(Debug, Default, PartialEq, Eq)]
-struct MacroLine {
-    raw: Vec<String>,
-    args: Vec<usize>,
-}
-
-impl MacroLine {
-    fn from_string(s: &str, arg_names: &FnvHashMap<String, usize>) -> Result<Self, Error> {
-        use nom::bytes::complete::take_till;
-        let take_raw = |s| take_till::<_, _, ()>(|c| c == '%')(s).unwrap();
-        let take_arg = |s| take_till::<_, _, ()>(|c| is_separator(c) || c == '(' || c == ')')(s).unwrap();
-
-        let mut res = Self::default();
-
-        fn ignore_char(t: &str) -> &str {
-            if t.len() >= 1 {
-                &t[1..]
-            } else {
-                t
-            }
-        }
-
-        let (mut s, prefix) = take_raw(s);
-        s = ignore_char(s); // ignore the %
-        res.raw.push(prefix.into());
-
-        while s.len() > 0 {
-            let (rest, arg) = take_arg(s);
-            let (rest, raw) = take_raw(rest);
-            s = ignore_char(rest);
-
-            let arg_index = match arg_names.get(arg) {
-                Some(x) => *x,
-                None => return Err(Error::ArgNotFoundMacro(arg.to_owned())),
-            };
-
-            res.args.push(arg_index);
-            res.raw.push(raw.into());
-        }
-
-        Ok(res)
-    }
-
-    /// Builds a single line, replacing where arguments were by the actual values
-    fn build(&self, args: &[String]) -> String {
-        let mut ans = String::new();
-
-        for (r, &p) in self.raw.iter().zip(self.args.iter()) {
-            ans.extend(r.chars());
-            ans.extend(args[p].chars());
-        }
-
-        ans.extend(self.raw.last().unwrap().chars());
-        ans
-    }
-}
-
-/// Exists while we're inside a `.macro`, `.end_macro` definition, just to keep
-/// track of the argument names. After that we can discard arg_names and keep only thea
-/// lines: this is a [Macro](struct.Macro.html)
-struct MacroBuilder {
-    /// Maps a argument string to its index in the macro declaration
-    arg_names: FnvHashMap<String, usize>,
-
-    /// Stack of macro lines
-    lines: Vec<MacroLine>,
-
-    name: String,
-}
-
-impl MacroBuilder {
-    fn new(name: String, arg_names: Vec<String>) -> Self {
-        Self {
-            arg_names: arg_names
-                .into_iter()
-                .enumerate()
-                .map(|(i, s)| (s, i))
-                .collect(),
-            lines: Vec::new(),
-            name,
-        }
-    }
-
-    fn push_line(&mut self, s: &str) -> Result<(), Error> {
-        self.lines.push(MacroLine::from_string(s, &self.arg_names)?);
-        Ok(())
-    }
-
-    fn to_macro(self) -> Macro {
-        // We reverse the lines so we can get them in stack order later
-        Macro {
-            lines: self.lines.into_iter().rev().collect(),
-        }
-    }
-}
-
-/// Represents a parsed macro.
-struct Macro {
-    /// Stack of macro lines
-    lines: Vec<MacroLine>,
-}
-
-impl Macro {
-    /// Builds a stack of macro lines by building every line with [MacroLine.build](struct.MacroLine.html#method.build)
-    fn build(&self, args: &[String]) -> Vec<String> {
-        self.lines.iter().map(|m| m.build(args)).collect()
-    }
-}
-
-/// Generally created calling [parse_macros](trait.MacroParseable.html#method.parse_macros)
-/// on an iterator of Strings
-pub struct MacroParser<I>
-where
-    I: Iterator<Item = String>,
-{
-    items: I,
-
-    /// Stack of lines we should process before consuming items
-    buf: Vec<String>,
-
-    macros: FnvHashMap<(String, usize), Macro>,
-    eqvs: FnvHashMap<String, String>,
-}
-
-impl<I: Iterator<Item = String>> MacroParser<I> {
-    /// Parses a `.macro NAME(%args)` declaration and, if it encounters it, returns a MacroBuilder
-    fn parse_macro_declaration(&self, s: &str) -> Option<MacroBuilder> {
-        declare_macro(s)
-            .ok()
-            .map(|(_, (name, args))| MacroBuilder::new(name, args))
-    }
-
-    /// Consumes the lines until we find an `.end_macro`
-    fn parse_until_end(
-        &mut self,
-        mut builder: MacroBuilder,
-    ) -> Result<((String, usize), Macro), Error> {
-        loop {
-            match self.items.next() {
-                Some(line) if end_macro(&line) => {
-                    let arg_count = builder.arg_names.len();
-                    let name = mem::replace(&mut builder.name, String::new());
-                    return Ok(((name, arg_count), builder.to_macro()));
-                }
-                None => return Err(Error::UnendedMacro(builder.name)),
-
-                Some(line) => builder.push_line(&line)?,
-            };
-        }
-    }
-
-    /// Parses a macro usage and optionally returns the lines to be inlined
-    fn parse_macro_use(&self, s: &str) -> Option<Vec<String>> {
-        let (s, label) = nom::combinator::opt(parse_label)(s).unwrap();
-        let label = label.map(|l| format!("{}:", l));
-
-        let (_, (name, args)) = macro_use(s).ok()?;
-        let key = (name, args.len());
-        self.macros.get(&key).map(|m| m.build(&args)).map(|mut v| {
-            v.extend(label);
-            v
-        })
-    }
-
-    // TODO: this function copies every line, even when it doesn't find
-    // any eqvs, which is most of the time. We should optimize it a bit
-    // it also replaces matches inside a string, which is not desirable
-    /// Bad functon in dire need of a rewrite. Replaces eqvs by their correspondents
-    /// in an inneficient manner and replaces stuff it shouldn't. Will do for now.
-    fn replace_eqvs(&self, s: String) -> String {
-        // There can't be any eqvs
-        if self.eqvs.len() == 0 {
-            return s;
-        }
-
-        let is_token = |c| !is_separator(c) && c != '(' && c != ')';
-        let mut buf = String::new();
-        let mut ans = String::new();
-        let mut found_eqv = false;
-
-        let mut push_buf = |buf: &mut String, ans: &mut String| {
-            if buf.len() > 0 {
-                let eqv_to = self.eqvs.get(buf);
-                found_eqv = found_eqv || eqv_to.is_some();
-                ans.push_str(eqv_to.unwrap_or(&buf));
-                buf.clear();
-            }
-        };
-
-        for c in s.chars() {
-            match is_token(c) {
-                true => buf.push(c),
-                false => {
-                    push_buf(&mut buf, &mut ans);
-                    ans.push(c);
-                }
-            }
-        }
-        push_buf(&mut buf, &mut ans);
-
-        // we won't support eqv aliasing for now, I might implement them whenever I feel like
-        // detecting some eqv cycles
-        ans
-    }
-}
-
-impl<I: Iterator<Item = String>> Iterator for MacroParser<I> {
-    type Item = String;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let line = match self.buf.pop() {
-            Some(line) => line,
-            None => self.items.next()?,
-        };
-
-        // Is the line a macro declaration?
-        if let Some(builder) = self.parse_macro_declaration(&line) {
-            let (key, parsed_macro) = self.parse_until_end(builder).unwrap();
-            self.macros.insert(key, parsed_macro);
-            return self.next();
-        }
-
-        // Is the line a macro usage?
-        if let Some(inlined) = self.parse_macro_use(&line) {
-            self.buf.extend(inlined);
-            return self.next();
-        }
-
-        // Is the line an eqv declaration?
-        if let Ok((_, (key, value))) = declare_eqv(&line) {
-            self.eqvs.insert(key, value);
-            return self.next();
-        }
-
-        Some(self.replace_eqvs(line))
-    }
-}
-
-pub trait MacroParseable<I: Iterator<Item = String>> {
-    /// Returns an iterator that inlines macros defined in the strings.
-    /// Refer to [RISCVParser](../trait.RISCVParser.html#fn.parse_riscv)
-    /// for example usage.
-    fn parse_macros(self) -> MacroParser<I>;
-}
-
-impl<I: Sized + Iterator<Item = String>> MacroParseable<I> for I {
-    fn parse_macros(self) -> MacroParser<I> {
-        MacroParser {
-            items: self,
-            buf: Vec::new(),
-            macros: FnvHashMap::default(),
-            eqvs: FnvHashMap::default(),
-        }
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_macros() {
-        let mut builder = MacroBuilder::new("Bob".into(), vec!["arg1".into(), "arg2".into()]);
-        builder.push_line("li %arg1 10").unwrap();
-        builder.push_line("%arg2").unwrap();
-
-        let m = builder.to_macro();
-
-        // notice the lines are in stack order
-        assert_eq!(
-            m.lines,
-            vec![
-                MacroLine {
-                    raw: vec!["".into(), "".into()],
-                    args: vec![1]
-                },
-                MacroLine {
-                    raw: vec!["li ".into(), " 10".into()],
-                    args: vec![0]
-                },
-            ]
-        );
-    }
-}
+use fnv::FnvHashMap;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::combinators::*;
+use super::util::*;
+
+/// Generally created by calling [parse_includes](trait.Includable.html#method.parse_includes)
+/// on an iterator of Strings
+// TODO: check for ciclic includes, preferably in a better way than RARS
+// (we should allow a file to be included more than once, maybe?)
+pub struct Includer<'a> {
+    /// Stack of files. Every time we encounter an .include,
+    /// we push its lines onto the stack.
+    stack: Vec<IncludedFile<'a>>,
+}
+
+struct IncludedFile<'a> {
+    lines: Box<dyn Iterator<Item = String> + 'a>,
+    /// Path of the file. Includes inside it are relative to its directory
+    path: PathBuf,
+    /// Number of the last line we read
+    line: usize,
+    /// Whether we're inside a `/* */` comment
+    in_comment: bool,
+    /// The last `# 12 "file.s"` marker left by `cpp`, if any. Lines after it are said to be from
+    /// that file, counting from that number.
+    marker: Option<LineMarker>,
+}
+
+struct LineMarker {
+    file: String,
+    /// Number the line after the marker has
+    line: usize,
+    /// Number of the marker in the file we're really reading
+    at: usize,
+}
+
+impl IncludedFile<'_> {
+    /// The file and line number errors in the `line`th line should point to
+    fn origin(&self, line: usize) -> (String, usize) {
+        match &self.marker {
+            Some(marker) => (marker.file.clone(), marker.line + line - marker.at - 1),
+            None => (self.path.display().to_string(), line),
+        }
+    }
+}
+
+impl<'a> Iterator for Includer<'a> {
+    type Item = Line;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Check the last file of the stack until we find one that still has lines
+        let source = loop {
+            let file = self.stack.last_mut()?;
+            match file.lines.next() {
+                Some(line) => {
+                    file.line += 1;
+                    break line;
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        };
+
+        let file = self.stack.last_mut().unwrap();
+        if !file.in_comment {
+            if let Ok((_, (line, name))) = line_marker(&source) {
+                let (current, _) = file.origin(file.line);
+                file.marker = Some(LineMarker {
+                    file: name.unwrap_or(current),
+                    line,
+                    at: file.line,
+                });
+                return self.next();
+            }
+        }
+
+        let (origin, line_number) = file.origin(file.line);
+        let mut line = strip_unneeded(&source, &mut file.in_comment);
+        let mut source = source;
+
+        // A `\` at the end of a line continues it on the next one
+        while let Some(len) = line.strip_suffix('\\').map(|rest| rest.trim_end().len()) {
+            line.truncate(len);
+            let next = match file.lines.next() {
+                Some(next) => next,
+                None => break,
+            };
+            file.line += 1;
+
+            line.push(' ');
+            line.push_str(&strip_unneeded(&next, &mut file.in_comment));
+            source.push(' ');
+            source.push_str(next.trim());
+        }
+
+        // The included file is relative to the current one
+        let included = include_directive(&line).ok().map(|(_, included)| {
+            let mut path = file.path.clone();
+            path.pop();
+            path.push(included);
+            path
+        });
+
+        // If we can't open it, the line goes on to the parser, which reports the error
+        let included_lines = included.as_ref().and_then(|path| file_lines(path.clone()).ok());
+
+        if let (Some(path), Some(lines)) = (included, included_lines) {
+            self.stack.push(IncludedFile {
+                lines: Box::new(lines),
+                path,
+                line: 0,
+                in_comment: false,
+                marker: None,
+            });
+
+            self.next()
+        } else {
+            let pos = Pos {
+                file: origin,
+                line: line_number,
+                column: None,
+                width: 0,
+                source,
+                macro_origin: None,
+            };
+
+            Some(Line {
+                text: line,
+                pos: Arc::new(pos),
+            })
+        }
+    }
+}
+
+pub trait Includable<'a, I: Iterator<Item = String> + 'a> {
+    /// Returns an iterator over RISC-V lines that can process `.include "file"` directives
+    /// and flatten all of the files into one stream. Refer to
+    /// [RISCVParser](../trait.RISCVParser.html#fn.parse_riscv) for example usage.
+    ///
+    /// Also removes comments for some reason, and keeps track of where each line came from.
+    fn parse_includes(self, filepath: PathBuf) -> Includer<'a>;
+}
+
+impl<'a, I: Iterator<Item = String> + 'a> Includable<'a, I> for I {
+    fn parse_includes(self, filepath: PathBuf) -> Includer<'a> {
+        Includer {
+            stack: vec![IncludedFile {
+                lines: Box::new(self),
+                path: filepath,
+                line: 0,
+                in_comment: false,
+                marker: None,
+            }],
+        }
+    }
+}
+
+/// We store each line of a parsed macro in a similar manner to JavaScript's template strings.
+/// When the arguments are applied (in [build()](struct.MacroLine.html#method.build)),
+/// we output the concatenation `{ raw[0], arg[0], raw[1], arg[1], ..., raw[n-1], arg[n-1], raw[n] }`
+#[derive(Debug, Default, PartialEq, Eq)]
+struct MacroLine {
+    raw: Vec<String>,
+    args: Vec<usize>,
+}
+
+impl MacroLine {
+    fn from_string(s: &str, arg_names: &FnvHashMap<String, usize>) -> Result<Self, Error> {
+        use nom::bytes::complete::take_till;
+        let take_raw = |s| take_till::<_, _, ()>(|c| c == '%')(s).unwrap();
+        let take_arg = |s| take_till::<_, _, ()>(|c| is_separator(c) || c == '(' || c == ')')(s).unwrap();
+
+        let mut res = Self::default();
+
+        fn ignore_char(t: &str) -> &str {
+            if !t.is_empty() {
+                &t[1..]
+            } else {
+                t
+            }
+        }
+
+        let (mut s, prefix) = take_raw(s);
+        s = ignore_char(s); // ignore the %
+        res.raw.push(prefix.into());
+
+        while !s.is_empty() {
+            let (rest, arg) = take_arg(s);
+            let (rest, raw) = take_raw(rest);
+            s = ignore_char(rest);
+
+            let arg_index = match arg_names.get(arg) {
+                Some(x) => *x,
+                None => return Err(Error::ArgNotFoundMacro(arg.to_owned())),
+            };
+
+            res.args.push(arg_index);
+            res.raw.push(raw.into());
+        }
+
+        Ok(res)
+    }
+
+    /// Builds a single line, replacing where arguments were by the actual values
+    fn build(&self, args: &[String]) -> String {
+        let mut ans = String::new();
+
+        for (r, &p) in self.raw.iter().zip(self.args.iter()) {
+            ans.push_str(r);
+            ans.push_str(&args[p]);
+        }
+
+        ans.push_str(self.raw.last().unwrap());
+        ans
+    }
+}
+
+/// Exists while we're inside a `.macro`, `.end_macro` definition, just to keep
+/// track of the argument names. After that we can discard arg_names and keep only thea
+/// lines: this is a [Macro](struct.Macro.html)
+struct MacroBuilder {
+    /// Maps a argument string to its index in the macro declaration
+    arg_names: FnvHashMap<String, usize>,
+    /// Default value of each argument, if it has one
+    defaults: Vec<Option<String>>,
+
+    /// Stack of macro lines
+    lines: Vec<MacroLine>,
+    /// Where each line was written, in the same order
+    positions: Vec<Arc<Pos>>,
+
+    name: String,
+}
+
+impl MacroBuilder {
+    fn new(name: String, args: Vec<MacroArg>) -> Self {
+        let (arg_names, defaults): (Vec<_>, _) = args.into_iter().unzip();
+        Self {
+            arg_names: arg_names
+                .into_iter()
+                .enumerate()
+                .map(|(i, s)| (s, i))
+                .collect(),
+            defaults,
+            lines: Vec::new(),
+            positions: Vec::new(),
+            name,
+        }
+    }
+
+    fn push_line(&mut self, s: &str) -> Result<(), Error> {
+        self.lines.push(MacroLine::from_string(s, &self.arg_names)?);
+        Ok(())
+    }
+
+    fn into_macro(self) -> Macro {
+        // We reverse the lines so we can get them in stack order later
+        Macro {
+            lines: self.lines.into_iter().rev().collect(),
+            positions: self.positions.into_iter().rev().collect(),
+            defaults: self.defaults,
+            name: self.name,
+        }
+    }
+}
+
+/// Represents a parsed macro.
+struct Macro {
+    /// Stack of macro lines
+    lines: Vec<MacroLine>,
+    /// Where each line was written, in the same order
+    positions: Vec<Arc<Pos>>,
+    /// Default value of each argument, if it has one
+    defaults: Vec<Option<String>>,
+    name: String,
+}
+
+impl Macro {
+    /// Whether the macro can be used with `n` arguments. The ones missing at the end must have
+    /// default values.
+    fn accepts(&self, n: usize) -> bool {
+        n <= self.defaults.len() && self.defaults[n..].iter().all(Option::is_some)
+    }
+
+    /// Builds a stack of macro lines by building every line with [MacroLine.build](struct.MacroLine.html#method.build).
+    /// They all get the position of the line that used the macro, and remember where they were written.
+    fn build(&self, args: &[String], pos: &Arc<Pos>) -> Vec<Line> {
+        let defaults = self.defaults[args.len()..].iter().flatten().cloned();
+        let args: Vec<String> = args.iter().cloned().chain(defaults).collect();
+
+        self.lines
+            .iter()
+            .zip(&self.positions)
+            .map(|(m, def)| {
+                let origin = MacroOrigin {
+                    name: self.name.clone(),
+                    pos: def.clone(),
+                };
+
+                Line {
+                    text: m.build(&args),
+                    pos: Arc::new(Pos {
+                        macro_origin: Some(origin),
+                        ..(**pos).clone()
+                    }),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Generally created calling [parse_macros](trait.MacroParseable.html#method.parse_macros)
+/// on an iterator of Lines
+pub struct MacroParser<I>
+where
+    I: Iterator<Item = Line>,
+{
+    items: I,
+
+    /// Stack of lines we should process before consuming items
+    buf: Vec<Line>,
+
+    /// Macros with the same name but a different number of arguments are different macros
+    macros: FnvHashMap<String, Vec<Macro>>,
+    eqvs: FnvHashMap<String, String>,
+
+    /// The `.ifdef`s we're inside of, from the outermost to the innermost
+    conditions: Vec<Condition>,
+
+    /// Leave `.set`, `.equ` and the conditionals alone, so the parser rejects them like RARS would
+    strict: bool,
+}
+
+/// An `.ifdef` or `.ifndef` that wasn't closed yet
+struct Condition {
+    /// Whether the lines after it should be kept. Lines are kept only if every condition they're
+    /// in is active.
+    active: bool,
+    has_else: bool,
+    /// Where the `.ifdef` is
+    pos: Arc<Pos>,
+}
+
+impl<I: Iterator<Item = Line>> MacroParser<I> {
+    /// Defines `name` like an `.eqv` written before the code, as in `-D NAME=value`
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        self.eqvs.insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Only handles what RARS' preprocessor handles, see
+    /// [Options::strict](../struct.Options.html#structfield.strict)
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Parses a `.macro NAME(%args)` declaration and, if it encounters it, returns a MacroBuilder
+    fn parse_macro_declaration(&self, s: &str) -> Option<MacroBuilder> {
+        declare_macro(s)
+            .ok()
+            .map(|(_, (name, args))| MacroBuilder::new(name, args))
+    }
+
+    /// Consumes the lines until we find an `.end_macro`. `pos` is where the macro was declared.
+    /// If something is wrong, we still skip the whole macro, and return the first error and where
+    /// it happened.
+    fn parse_until_end(
+        &mut self,
+        mut builder: MacroBuilder,
+        pos: &Arc<Pos>,
+    ) -> Result<Macro, Box<(Error, Arc<Pos>)>> {
+        let mut error = None;
+        loop {
+            let line = match self.items.next() {
+                Some(line) => line,
+                None if error.is_some() => break,
+                None => return Err(Box::new((Error::UnendedMacro(builder.name), pos.clone()))),
+            };
+
+            if end_macro(&line.text) {
+                break;
+            }
+            if error.is_none() {
+                match builder.push_line(&line.text) {
+                    Ok(()) => builder.positions.push(line.pos),
+                    Err(e) => error = Some((e, line.pos)),
+                }
+            }
+        }
+
+        match error {
+            Some(error) => Err(Box::new(error)),
+            None => Ok(builder.into_macro()),
+        }
+    }
+
+    /// Opens or closes a region of conditional assembly. `pos` is where the directive is.
+    fn apply_conditional(&mut self, conditional: Conditional, pos: &Arc<Pos>) -> Result<(), Error> {
+        match conditional {
+            Conditional::IfDef(name, defined) => self.conditions.push(Condition {
+                active: self.eqvs.contains_key(&name) == defined,
+                has_else: false,
+                pos: pos.clone(),
+            }),
+            Conditional::Else => match self.conditions.last_mut() {
+                Some(condition) if !condition.has_else => {
+                    condition.active = !condition.active;
+                    condition.has_else = true;
+                }
+                _ => return Err(Error::UnmatchedConditional(".else".to_owned())),
+            },
+            Conditional::EndIf => {
+                if self.conditions.pop().is_none() {
+                    return Err(Error::UnmatchedConditional(".endif".to_owned()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the lines of a `.rept` until its `.endr`, and puts them back in the stack once for
+    /// each repetition, so they go through the preprocessor again. `.set`s in them can make each
+    /// repetition different.
+    fn apply_repeat(&mut self, rept: Repeat) -> Result<(), Error> {
+        let count = match rept {
+            Repeat::Start(count) => count,
+            Repeat::End => return Err(Error::UnmatchedEndr),
+        };
+
+        let mut lines = Vec::new();
+        let mut depth = 0;
+        loop {
+            let line = self.buf.pop().or_else(|| self.items.next());
+            let line = line.ok_or(Error::UnendedRept)?;
+            match repeat(&line.text) {
+                Ok((_, Repeat::Start(_))) => depth += 1,
+                Ok((_, Repeat::End)) if depth == 0 => break,
+                Ok((_, Repeat::End)) => depth -= 1,
+                Err(_) => {}
+            }
+            lines.push(line);
+        }
+
+        let times = self.eval_set(count.clone())?;
+        let times = u32::try_from(times).map_err(|_| Error::ImmediateOutOfRange(count))?;
+        for _ in 0..times {
+            self.buf.extend(lines.iter().rev().cloned());
+        }
+        Ok(())
+    }
+
+    /// Parses a macro usage and optionally returns the lines to be inlined, or an error if the
+    /// macro exists but can't be used with that many arguments
+    fn parse_macro_use(&self, line: &Line) -> Option<Result<Vec<Line>, Error>> {
+        let (s, label) = nom::combinator::opt(parse_label)(&line.text).unwrap();
+        let label = label.map(|l| Line {
+            text: format!("{}:", l),
+            pos: line.pos.clone(),
+        });
+
+        let (_, (name, args)) = macro_use(s).ok()?;
+        let overloads = self.macros.get(&name)?;
+        let found = overloads
+            .iter()
+            .find(|m| m.defaults.len() == args.len())
+            .or_else(|| overloads.iter().find(|m| m.accepts(args.len())));
+
+        let m = match found {
+            Some(m) => m,
+            None => {
+                let mut counts: Vec<usize> = overloads
+                    .iter()
+                    .flat_map(|m| (0..=m.defaults.len()).filter(move |&n| m.accepts(n)))
+                    .collect();
+                counts.sort_unstable();
+                counts.dedup();
+                return Some(Err(Error::MacroArgCount(name, counts, args.len())));
+            }
+        };
+
+        let mut lines = m.build(&args, &line.pos);
+        lines.extend(label);
+        Some(Ok(lines))
+    }
+
+    /// Evaluates the expression of a `.set`, which can only have numbers and names that were
+    /// already defined
+    fn eval_set(&self, value: String) -> Result<i64, Error> {
+        let value = self.replace_eqvs(value);
+        let (_, parsed) = nom::combinator::all_consuming(expr)(&value)?;
+        parsed.eval_constant()
+    }
+
+    // TODO: this function copies every line, even when it doesn't find
+    // any eqvs, which is most of the time. We should optimize it a bit
+    // it also replaces matches inside a string, which is not desirable
+    /// Bad functon in dire need of a rewrite. Replaces eqvs by their correspondents
+    /// in an inneficient manner and replaces stuff it shouldn't. Will do for now.
+    fn replace_eqvs(&self, s: String) -> String {
+        // There can't be any eqvs
+        if self.eqvs.is_empty() {
+            return s;
+        }
+
+        // Operators split tokens too, so `3*WIDTH+4` finds `WIDTH`
+        let is_token = |c| !is_separator(c) && !"()+-*/%<>&|^~".contains(c);
+        let mut buf = String::new();
+        let mut ans = String::new();
+        let mut found_eqv = false;
+
+        let mut push_buf = |buf: &mut String, ans: &mut String| {
+            if !buf.is_empty() {
+                let eqv_to = self.eqvs.get(buf);
+                found_eqv = found_eqv || eqv_to.is_some();
+                ans.push_str(eqv_to.unwrap_or(buf));
+                buf.clear();
+            }
+        };
+
+        for c in s.chars() {
+            match is_token(c) {
+                true => buf.push(c),
+                false => {
+                    push_buf(&mut buf, &mut ans);
+                    ans.push(c);
+                }
+            }
+        }
+        push_buf(&mut buf, &mut ans);
+
+        // we won't support eqv aliasing for now, I might implement them whenever I feel like
+        // detecting some eqv cycles
+        ans
+    }
+}
+
+impl<I: Iterator<Item = Line>> Iterator for MacroParser<I> {
+    type Item = Line;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.buf.pop().or_else(|| self.items.next()) {
+                Some(line) => line,
+                None => {
+                    // The file ended inside an `.ifdef`
+                    let condition = self.conditions.pop()?;
+                    return Some(error_line(Error::UnendedIf, condition.pos));
+                }
+            };
+
+            // Is the line an `.ifdef`, `.else` or `.endif`?
+            if let Some((_, conditional)) = conditional(&line.text).ok().filter(|_| !self.strict) {
+                match self.apply_conditional(conditional, &line.pos) {
+                    Ok(()) => continue,
+                    Err(e) => return Some(error_line(e, line.pos)),
+                }
+            }
+
+            // Are we in a region the conditions turned off?
+            if self.conditions.iter().any(|c| !c.active) {
+                continue;
+            }
+
+            // Is the line a `.rept`?
+            if let Some((_, rept)) = repeat(&line.text).ok().filter(|_| !self.strict) {
+                match self.apply_repeat(rept) {
+                    Ok(()) => continue,
+                    Err(e) => return Some(error_line(e, line.pos)),
+                }
+            }
+
+            // Is the line a macro declaration?
+            if let Some(builder) = self.parse_macro_declaration(&line.text) {
+                let parsed_macro = match self.parse_until_end(builder, &line.pos) {
+                    Ok(m) => m,
+                    Err(error) => {
+                        let (e, pos) = *error;
+                        return Some(error_line(e, pos));
+                    }
+                };
+
+                // Declaring it again with the same number of arguments replaces it
+                let overloads = self.macros.entry(parsed_macro.name.clone()).or_default();
+                overloads.retain(|m| m.defaults.len() != parsed_macro.defaults.len());
+                overloads.push(parsed_macro);
+                continue;
+            }
+
+            // Is the line a macro usage?
+            match self.parse_macro_use(&line) {
+                Some(Ok(inlined)) => {
+                    self.buf.extend(inlined);
+                    continue;
+                }
+                Some(Err(e)) => return Some(error_line(e, line.pos)),
+                None => {}
+            }
+
+            // Is the line an eqv declaration?
+            if let Ok((_, (key, value))) = declare_eqv(&line.text) {
+                self.eqvs.insert(key, value);
+                continue;
+            }
+
+            // Is the line a `.set`? Unlike an eqv, its value is computed right here, so it can be
+            // defined again in terms of itself, like `.set OFFSET, OFFSET + 4`
+            if let Some((_, (key, value))) = declare_set(&line.text).ok().filter(|_| !self.strict) {
+                match self.eval_set(value) {
+                    Ok(value) => self.eqvs.insert(key, value.to_string()),
+                    Err(e) => return Some(error_line(e, line.pos)),
+                };
+                continue;
+            }
+
+            return Some(Line {
+                text: self.replace_eqvs(line.text),
+                pos: line.pos,
+            });
+        }
+    }
+}
+
+/// The preprocessor can't return errors, so it leaves an `.error` in their place, and the parser
+/// reports it
+fn error_line(e: Error, pos: Arc<Pos>) -> Line {
+    Line {
+        text: format!(".error \"{}\"", e),
+        pos,
+    }
+}
+
+pub trait MacroParseable<I: Iterator<Item = Line>> {
+    /// Returns an iterator that inlines macros defined in the strings.
+    /// Refer to [RISCVParser](../trait.RISCVParser.html#fn.parse_riscv)
+    /// for example usage.
+    fn parse_macros(self) -> MacroParser<I>;
+}
+
+impl<I: Sized + Iterator<Item = Line>> MacroParseable<I> for I {
+    fn parse_macros(self) -> MacroParser<I> {
+        MacroParser {
+            items: self,
+            buf: Vec::new(),
+            macros: FnvHashMap::default(),
+            eqvs: FnvHashMap::default(),
+            conditions: Vec::new(),
+            strict: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_macros() {
+        let mut builder = MacroBuilder::new("Bob".into(), vec![("arg1".into(), None), ("arg2".into(), None)]);
+        builder.push_line("li %arg1 10").unwrap();
+        builder.push_line("%arg2").unwrap();
+
+        let m = builder.into_macro();
+
+        // notice the lines are in stack order
+        assert_eq!(
+            m.lines,
+            vec![
+                MacroLine {
+                    raw: vec!["".into(), "".into()],
+                    args: vec![1]
+                },
+                MacroLine {
+                    raw: vec!["li ".into(), " 10".into()],
+                    args: vec![0]
+                },
+            ]
+        );
+    }
+    fn expand(s: &str) -> Vec<String> {
+        s.lines()
+            .map(str::to_owned)
+            .parse_includes(PathBuf::from("test.s"))
+            .parse_macros()
+            .map(|line| line.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_macro_args() {
+        // Default values, and macros that use other macros
+        let code = ".macro PUSH(%reg, %size=4)\naddi sp sp -%size\nsw %reg 0(sp)\n.end_macro\n\
+                    .macro SAVE(%a, %b)\nPUSH(%a)\nPUSH(%b, 8)\n.end_macro\n\
+                    SAVE(t0, t1)";
+        assert_eq!(
+            expand(code),
+            ["addi sp sp -4", "sw t0 0(sp)", "addi sp sp -8", "sw t1 0(sp)"]
+        );
+
+        // The same name with a different number of arguments is another macro
+        let code = ".macro EXIT\nli a0 0\n.end_macro\n.macro EXIT(%code)\nli a0 %code\n.end_macro\nEXIT\nEXIT(3)";
+        assert_eq!(expand(code), ["li a0 0", "li a0 3"]);
+
+        assert_eq!(
+            expand(".macro PUSH(%reg, %size=4)\n.end_macro\nPUSH\nPUSH(t0, 4, 4)\nPUSH(t0)"),
+            [
+                ".error \"macro `PUSH` takes 1 or 2 arguments, but got 0\"",
+                ".error \"macro `PUSH` takes 1 or 2 arguments, but got 3\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        assert_eq!(
+            expand(".macro BAD(%a)\nli %b 1\nnop\n.end_macro\nret"),
+            [".error \"macro argument `%b` was never declared\"", "ret"]
+        );
+        assert_eq!(
+            expand("nop\n.macro FOREVER\nnop"),
+            ["nop", ".error \"macro `FOREVER` is missing its .end_macro\""]
+        );
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let code = ".ifdef DEBUG\nli a7 1\n.ifndef QUIET\nli a7 4\n.endif\n.else\nnop\n.endif\nret";
+        assert_eq!(expand(code), ["nop", "ret"]);
+
+        let defined: Vec<String> = code
+            .lines()
+            .map(str::to_owned)
+            .parse_includes(PathBuf::from("test.s"))
+            .parse_macros()
+            .define("DEBUG", "1")
+            .map(|line| line.text)
+            .collect();
+        assert_eq!(defined, ["li a7 1", "li a7 4", "ret"]);
+
+        // Inactive regions can have anything, even macros and unbalanced `.else`s
+        let code = ".eqv ON 1\n.ifndef ON\n.macro M\n.end_macro\n.ifdef X\n.else\n.endif\n.endif\nM";
+        assert_eq!(expand(code), ["M"]);
+
+        assert_eq!(
+            expand(".else\n.ifdef X\n.else\n.else\n.endif\n.endif"),
+            [
+                ".error \"`.else` without a matching `.ifdef`\"",
+                ".error \"`.else` without a matching `.ifdef`\"",
+                ".error \"`.endif` without a matching `.ifdef`\"",
+            ]
+        );
+        assert_eq!(expand(".ifdef X\nnop"), [".error \"`.ifdef` is missing its `.endif`\""]);
+    }
+
+    #[test]
+    fn test_rept() {
+        assert_eq!(expand(".rept 3\nnop\n.endr\nret"), ["nop", "nop", "nop", "ret"]);
+        assert_eq!(expand(".eqv N 2\n.rept 0\nnop\n.endr\n.rept N * 2\nret\n.endr").len(), 4);
+
+        // Nested, with a `.set` that changes in every repetition
+        let code = ".set I, 0\n.rept 2\n.rept 2\n.word I\n.set I, I + 1\n.endr\n.endr";
+        assert_eq!(expand(code), [".word 0", ".word 1", ".word 2", ".word 3"]);
+
+        let code = ".macro ZEROS(%n)\n.rept %n\n.word 0\n.endr\n.end_macro\nZEROS(2)\nret";
+        assert_eq!(expand(code), [".word 0", ".word 0", "ret"]);
+
+        assert_eq!(expand(".rept 2\nnop"), [".error \"`.rept` is missing its `.endr`\""]);
+        assert_eq!(expand(".endr"), [".error \"`.endr` without a matching `.rept`\""]);
+        assert_eq!(
+            expand(".rept -1\nnop\n.endr\nret"),
+            [".error \"immediate `-1` is out of range\"", "ret"]
+        );
+    }
+
+    #[test]
+    fn test_line_continuation() {
+        let code = ".word 1, 2, \\\n  3, 4 \\ # the comment goes away first\n  5\nnop";
+        assert_eq!(expand(code), [".word 1, 2, 3, 4 5", "nop"]);
+
+        let code = ".macro PRINT(%reg)\nmv a0, \\\n\t%reg\nli a7 1\n.end_macro\nPRINT(t0)";
+        assert_eq!(expand(code), ["mv a0, t0", "li a7 1"]);
+
+        // The line number is the one of the first line
+        let lines: Vec<_> = "nop\nli t0, \\\n  10\nret\\"
+            .lines()
+            .map(str::to_owned)
+            .parse_includes(PathBuf::from("test.s"))
+            .collect();
+        let lines: Vec<_> = lines.iter().map(|l| (l.text.as_str(), l.pos.line, l.pos.source.as_str())).collect();
+        assert_eq!(lines, [("nop", 1, "nop"), ("li t0, 10", 2, "li t0, \\ 10"), ("ret", 4, "ret\\")]);
+    }
+
+    #[test]
+    fn test_line_markers() {
+        let code = "# 1 \"main.S\"\nnop\n# 1 \"lib.s\" 1\nli a0, 1\n\n# 3 \"main.S\" 2\nret\n#line 10\necall";
+        let lines: Vec<_> = code
+            .lines()
+            .map(str::to_owned)
+            .parse_includes(PathBuf::from("main.i"))
+            .filter(|l| !l.text.is_empty())
+            .map(|l| (l.text, l.pos.file.clone(), l.pos.line))
+            .collect();
+
+        let expected = [("nop", "main.S", 1), ("li a0, 1", "lib.s", 1), ("ret", "main.S", 3), ("ecall", "main.S", 10)];
+        let expected: Vec<_> = expected.iter().map(|&(t, f, l)| (t.to_owned(), f.to_owned(), l)).collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn test_strict() {
+        // RARS doesn't have these, so they're left for the parser to reject
+        let code = ".set A, 1\n.ifdef A\n.eqv B 2\nli t0 B\n.endif";
+        let lines: Vec<String> = code
+            .lines()
+            .map(str::to_owned)
+            .parse_includes(PathBuf::from("test.s"))
+            .parse_macros()
+            .strict(true)
+            .map(|line| line.text)
+            .collect();
+        assert_eq!(lines, [".set A, 1", ".ifdef A", "li t0 2", ".endif"]);
+    }
+
+    #[test]
+    fn test_set() {
+        // A structure with a 4 byte `x`, 4 byte `y` and 1 byte `alive`
+        let code = ".set OFFSET, 0\n.equ X, OFFSET\n.set OFFSET, OFFSET + 4\n.equ Y, OFFSET\n\
+                    .set OFFSET, OFFSET + 4\n.equ ALIVE, OFFSET\n.set OFFSET, OFFSET + 1\n\
+                    lw t0 X(a0)\nlw t1 Y(a0)\nlb t2 ALIVE(a0)\naddi sp sp -OFFSET";
+        assert_eq!(expand(code), ["lw t0 0(a0)", "lw t1 4(a0)", "lb t2 8(a0)", "addi sp sp -9"]);
+
+        assert_eq!(expand(".set A, 2*(3+1)\n.set A, A-10\nli t0 A"), ["li t0 -2"]);
+        assert_eq!(
+            expand(".set A, B + 1\n.set C, 1/0"),
+            [".error \"label not found: `B`\"", ".error \"division by zero in `(1 / 0)`\""]
+        );
+    }
+}
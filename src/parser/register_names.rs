@@ -1,95 +1,142 @@
-use fnv::FnvHashMap;
-
-pub const TIME_INDEX: u8 = 0;
-pub const MISA_INDEX: u8 = 1;
-pub const UEPC_INDEX: u8 = 2;
-pub const USTATUS_INDEX: u8 = 3;
-pub const UTVEC_INDEX: u8 = 4;
-pub const UCAUSE_INDEX: u8 = 5;
-
-use super::util::Error;
-
-pub type RegMap = FnvHashMap<String, u8>;
-pub type FullRegMap = (RegMap, RegMap, RegMap);
-
-fn insert_names(map: &mut RegMap, names: &[&'static str]) {
-    for (i, name) in names.into_iter().enumerate() {
-        map.insert(name.to_string(), i as u8);
-    }
-}
-
-pub fn regs() -> RegMap {
-    let mut map = RegMap::with_capacity_and_hasher(64, Default::default());
-
-    // Insert x-prefixed registers
-    for i in 0..32 {
-        map.insert(format!("x{}", i), i);
-    }
-
-    // Insert named registers
-    let names = vec![
-        "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
-        "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
-        "t5", "t6",
-    ];
-    insert_names(&mut map, &names);
-
-    map
-}
-
-pub fn floats() -> RegMap {
-    let mut map = RegMap::with_capacity_and_hasher(64, Default::default());
-
-    // Insert f-prefixed registers
-    for i in 0..32 {
-        map.insert(format!("f{}", i), i);
-    }
-
-    // Insert named registers
-    let names = vec![
-        "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
-        "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8",
-        "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
-    ];
-    insert_names(&mut map, &names);
-
-    map
-}
-
-pub fn status() -> RegMap {
-    let mut map = RegMap::default();
-
-    map.insert("time".to_owned(), TIME_INDEX);
-    map.insert("misa".to_owned(), MISA_INDEX);
-    map.insert("uepc".to_owned(), UEPC_INDEX);
-    map.insert("ustatus".to_owned(), USTATUS_INDEX);
-    map.insert("utvec".to_owned(), UTVEC_INDEX);
-    map.insert("ucause".to_owned(), UCAUSE_INDEX);
-
-    let names = vec!["uscratch", "utval", "instret", "instreth", "cycle", "timeh"];
-    for name in names {
-        map.insert(name.to_string(), map.len() as u8);
-    }
-
-    map.insert("0".to_owned(), USTATUS_INDEX);
-    map.insert("3073".to_owned(), TIME_INDEX);
-    map.insert("769".to_owned(), MISA_INDEX);
-    map.insert("65".to_owned(), UEPC_INDEX);
-    map.insert("0".to_owned(), USTATUS_INDEX);
-    map.insert("5".to_owned(), UTVEC_INDEX);
-    map.insert("66".to_owned(), UCAUSE_INDEX);
-
-    map
-}
-
-pub trait TryGetRegister {
-    fn try_get(&self, name: &str) -> Result<u8, Error>;
-}
-
-impl TryGetRegister for RegMap {
-    fn try_get(&self, name: &str) -> Result<u8, Error> {
-        self.get(name)
-            .copied()
-            .ok_or_else(|| Error::RegisterNotFound(name.to_owned()))
-    }
-}
+use fnv::FnvHashMap;
+
+pub const TIME_INDEX: u8 = 0;
+pub const MISA_INDEX: u8 = 1;
+pub const UEPC_INDEX: u8 = 2;
+pub const USTATUS_INDEX: u8 = 3;
+pub const UTVEC_INDEX: u8 = 4;
+pub const UCAUSE_INDEX: u8 = 5;
+pub const INSTRET_INDEX: u8 = 8;
+pub const INSTRETH_INDEX: u8 = 9;
+pub const CYCLE_INDEX: u8 = 10;
+pub const TIMEH_INDEX: u8 = 11;
+pub const FFLAGS_INDEX: u8 = 12;
+pub const FRM_INDEX: u8 = 13;
+pub const FCSR_INDEX: u8 = 14;
+pub const CYCLEH_INDEX: u8 = 15;
+pub const MSTATUS_INDEX: u8 = 16;
+pub const MTVEC_INDEX: u8 = 17;
+pub const MEPC_INDEX: u8 = 19;
+pub const MCAUSE_INDEX: u8 = 20;
+pub const VL_INDEX: u8 = 22;
+pub const VTYPE_INDEX: u8 = 23;
+pub const VLENB_INDEX: u8 = 24;
+
+use super::util::Error;
+
+/// Names of the integer registers, in order
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Names of the float registers, in order
+pub const FLOAT_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8",
+    "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// Names of the status registers we have, by their index in the simulator
+pub const STATUS_NAMES: [&str; 25] = [
+    "time", "misa", "uepc", "ustatus", "utvec", "ucause", "uscratch", "utval", "instret",
+    "instreth", "cycle", "timeh", "fflags", "frm", "fcsr", "cycleh", "mstatus", "mtvec",
+    "mscratch", "mepc", "mcause", "mtval", "vl", "vtype", "vlenb",
+];
+
+/// The CSR numbers of [STATUS_NAMES](constant.STATUS_NAMES.html), which is what goes in the
+/// encoded instructions
+pub const STATUS_CSRS: [u32; 25] = [
+    0xC01, 0x301, 0x041, 0x000, 0x005, 0x042, 0x040, 0x043, 0xC02, 0xC82, 0xC00, 0xC81, 0x001,
+    0x002, 0x003, 0xC80, 0x300, 0x305, 0x340, 0x341, 0x342, 0x343, 0xC20, 0xC21, 0xC22,
+];
+
+/// Whether the status register at `index` can only be read. Like in the spec, those are the CSRs
+/// whose numbers start with `0b11`, like `cycle` and `time`.
+pub fn is_read_only(index: u8) -> bool {
+    STATUS_CSRS[index as usize] >> 10 == 0b11
+}
+
+/// Whether the status register at `index` can only be used in machine mode. Like in the spec,
+/// those are the CSRs whose numbers have `0b11` in bits 9 and 8, like `mstatus` and `misa`.
+pub fn is_machine_only(index: u8) -> bool {
+    STATUS_CSRS[index as usize] >> 8 & 0b11 == 0b11
+}
+
+pub type RegMap = FnvHashMap<String, u8>;
+pub type FullRegMap = (RegMap, RegMap, RegMap);
+
+fn insert_names(map: &mut RegMap, names: &[&'static str]) {
+    for (i, name) in names.iter().enumerate() {
+        map.insert(name.to_string(), i as u8);
+    }
+}
+
+pub fn regs() -> RegMap {
+    let mut map = RegMap::with_capacity_and_hasher(64, Default::default());
+
+    // Insert x-prefixed registers
+    for i in 0..32 {
+        map.insert(format!("x{}", i), i);
+    }
+
+    // Insert named registers
+    insert_names(&mut map, &REGISTER_NAMES);
+
+    map
+}
+
+/// Marks the registers RV32E doesn't have in [rv32e_regs](fn.rv32e_regs.html)
+const NOT_IN_RV32E: u8 = 0x80;
+
+/// The integer registers of RV32E, which only has x0 to x15. The others are still there, marked,
+/// so using one is an error that says why instead of an unknown register.
+pub fn rv32e_regs() -> RegMap {
+    let mut map = regs();
+    for i in map.values_mut().filter(|i| **i >= 16) {
+        *i |= NOT_IN_RV32E;
+    }
+    map
+}
+
+pub fn floats() -> RegMap {
+    let mut map = RegMap::with_capacity_and_hasher(64, Default::default());
+
+    // Insert f-prefixed registers
+    for i in 0..32 {
+        map.insert(format!("f{}", i), i);
+    }
+
+    // Insert named registers
+    insert_names(&mut map, &FLOAT_NAMES);
+
+    map
+}
+
+pub fn status() -> RegMap {
+    let mut map = RegMap::default();
+
+    insert_names(&mut map, &STATUS_NAMES);
+
+    // They can also be written as their CSR numbers, like `csrr t0, 3`
+    for (i, csr) in STATUS_CSRS.iter().enumerate() {
+        map.insert(csr.to_string(), i as u8);
+    }
+
+    map
+}
+
+pub trait TryGetRegister {
+    fn try_get(&self, name: &str) -> Result<u8, Error>;
+}
+
+impl TryGetRegister for RegMap {
+    fn try_get(&self, name: &str) -> Result<u8, Error> {
+        match self.get(name) {
+            Some(&i) if i & NOT_IN_RV32E != 0 => Err(Error::NotInRv32E(name.to_owned())),
+            Some(&i) => Ok(i),
+            None => Err(Error::RegisterNotFound(name.to_owned())),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//!
+//! The checks of [Options::strict](../struct.Options.html#structfield.strict), for code that has to
+//! assemble in RARS too. We accept a lot that RARS doesn't, like the directives of the GNU assembler,
+//! expressions in the operands and numeric labels, and it's easy to depend on them by accident.
+//!
+
+use super::combinators::{integer_literal, is_separator, quoted_len};
+use super::Error;
+
+/// Every directive RARS knows
+const RARS_DIRECTIVES: &[&str] = &[
+    ".align", ".ascii", ".asciz", ".byte", ".data", ".double", ".dword", ".end_macro", ".eqv", ".extern",
+    ".float", ".globl", ".global", ".half", ".include", ".kdata", ".ktext", ".macro", ".section",
+    ".space", ".string", ".text", ".word",
+];
+
+/// Directives that RARS only accepts in the `.data`
+const DATA_DIRECTIVES: &[&str] = &[
+    ".align", ".ascii", ".asciz", ".byte", ".double", ".dword", ".float", ".half", ".space", ".string",
+    ".word",
+];
+
+/// Checks a line without its label. `in_text` says whether it's in the `.text`.
+pub(super) fn check(line: &str, in_text: bool) -> Result<(), Error> {
+    let name = line.split(is_separator).next().unwrap_or("");
+
+    // The preprocessor writes its errors as `.error`s, which aren't the user's fault
+    if name == ".error" {
+        return Ok(());
+    }
+
+    if name.starts_with('.') && !RARS_DIRECTIVES.contains(&name) {
+        return Err(Error::NotInRars(name.to_owned(), "it's not one of its directives"));
+    }
+    if in_text && DATA_DIRECTIVES.contains(&name) {
+        return Err(Error::NotInRars(name.to_owned(), "it can only be used in the .data"));
+    }
+    if let Some(operand) = expression(line) {
+        let why = "operands can only be numbers, characters and `label+offset`";
+        return Err(Error::NotInRars(operand.to_owned(), why));
+    }
+
+    Ok(())
+}
+
+/// RARS labels can't start with a digit, so there are no `1:` labels
+pub(super) fn check_label(label: &str) -> Result<(), Error> {
+    match label.starts_with(|c: char| c.is_ascii_digit()) {
+        true => Err(Error::NotInRars(label.to_owned(), "labels can't start with a digit")),
+        false => Ok(()),
+    }
+}
+
+/// The first operand of `line` that's an expression RARS can't compute, like `3*4`, `1<<2` or
+/// `1+2`. Strings and characters, like `'*'`, are left alone.
+fn expression(line: &str) -> Option<&str> {
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '"' | '\'' => quoted_len(rest, c),
+            c if is_separator(c) => c.len_utf8(),
+            _ => rest
+                .find(|c| is_separator(c) || c == '"' || c == '\'')
+                .unwrap_or(rest.len()),
+        };
+
+        let (token, after) = rest.split_at(len);
+        if !token.starts_with(['"', '\'']) && (token.contains(|c| "*/%<>&|^~".contains(c)) || bad_sum(token)) {
+            return Some(token);
+        }
+        rest = after;
+    }
+    None
+}
+
+/// Whether `token` adds or subtracts anything but a number to a label, like `1+2` or the `4+4` of
+/// `4+4(sp)`. The sign of a number, like in `-4(sp)`, and of an exponent, like in `1.5e-3`, are fine.
+fn bad_sum(token: &str) -> bool {
+    let operand = token.split('(').next().unwrap_or("");
+    let body = operand.trim_start_matches(['+', '-']);
+    let is_exponent = |before: &str| {
+        before.ends_with(['e', 'E']) && before.starts_with(|c: char| c.is_ascii_digit()) && !before.contains(['x', 'X'])
+    };
+    let sign = body.char_indices().find(|&(i, c)| (c == '+' || c == '-') && !is_exponent(&body[..i]));
+
+    match sign {
+        Some((i, _)) => {
+            let (label, offset) = (&body[..i], &body[i + 1..]);
+            let is_label = label.starts_with(|c: char| c.is_ascii_alphabetic() || "_.$".contains(c));
+            !(is_label && body.len() == operand.len() && integer_literal(offset).is_ok())
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strict() {
+        assert!(check(".data", false).is_ok());
+        assert!(check(".text 0x400", true).is_ok());
+        assert!(check(".string \"a*b\"", false).is_ok());
+        assert!(check("lw t0, -4(sp)", true).is_ok());
+        assert!(check("la t0, array+8", true).is_ok());
+        assert!(check("lw t0, array-4", true).is_ok());
+        assert!(check("li t0, -2048", true).is_ok());
+        assert!(check(".float -1.5e-3, 2E+4", false).is_ok());
+        assert!(check("li t0, '*'", true).is_ok());
+        assert!(check(".error \"anything\"", true).is_ok());
+
+        let rejected = |line, in_text| match check(line, in_text) {
+            Err(Error::NotInRars(s, _)) => s,
+            res => panic!("`{}` gave {:?}", line, res),
+        };
+        assert_eq!(rejected(".database", false), ".database");
+        assert_eq!(rejected(".textures", false), ".textures");
+        assert_eq!(rejected(".p2align 2", false), ".p2align");
+        assert_eq!(rejected(".set X, 1", false), ".set");
+        assert_eq!(rejected(".word 1", true), ".word");
+        assert_eq!(rejected("li t0, 3*320+4", true), "3*320+4");
+        assert_eq!(rejected(".word 1<<4", false), "1<<4");
+        assert_eq!(rejected("li t0, 1+2", true), "1+2");
+        assert_eq!(rejected("lw t0, 4+4(sp)", true), "4+4(sp)");
+        assert_eq!(rejected("addi t0, t0, -1-1", true), "-1-1");
+        assert_eq!(rejected("la t0, end-start", true), "end-start");
+
+        assert!(check_label("loop").is_ok());
+        assert!(check_label("1").is_err());
+    }
+}
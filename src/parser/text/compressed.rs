@@ -0,0 +1,309 @@
+//!
+//! The compressed instructions of RV32C, which take 2 bytes instead of 4. They aren't
+//! [Instructions](../../enum.Instruction.html) of their own: each one is a base instruction with a
+//! shorter encoding, like `c.addi a0, 1` is `addi a0, a0, 1`, which only exists when its registers
+//! and immediate are small enough. [compress](fn.compress.html) finds that encoding, and
+//! [decompress](fn.decompress.html) goes back.
+//!
+
+use super::super::{FloatInstruction, Instruction};
+use super::decode::sext;
+
+/// Where the bits of an immediate go: each pair is the highest position in the instruction and the
+/// bits of the immediate that go there, from that position down
+type Layout = &'static [(u32, &'static [u32])];
+
+/// `c.addi`, `c.li`, `c.andi` and the shifts
+const CI: Layout = &[(12, &[5]), (6, &[4, 3, 2, 1, 0])];
+const ADDI16SP: Layout = &[(12, &[9]), (6, &[4, 6, 8, 7, 5])];
+const ADDI4SPN: Layout = &[(12, &[5, 4, 9, 8, 7, 6, 2, 3])];
+const LUI: Layout = &[(12, &[17]), (6, &[16, 15, 14, 13, 12])];
+/// `c.lw`, `c.sw` and their float versions
+const LW: Layout = &[(12, &[5, 4, 3]), (6, &[2, 6])];
+/// `c.fld` and `c.fsd`
+const LD: Layout = &[(12, &[5, 4, 3]), (6, &[7, 6])];
+const LWSP: Layout = &[(12, &[5]), (6, &[4, 3, 2, 7, 6])];
+const LDSP: Layout = &[(12, &[5]), (6, &[4, 3, 8, 7, 6])];
+const SWSP: Layout = &[(12, &[5, 4, 3, 2, 7, 6])];
+const SDSP: Layout = &[(12, &[5, 4, 3, 8, 7, 6])];
+const BRANCH: Layout = &[(12, &[8, 4, 3]), (6, &[7, 6, 2, 1, 5])];
+const JUMP: Layout = &[(12, &[11, 4, 9, 8, 10, 6, 7, 3, 2, 1, 5])];
+
+/// Reads the immediate laid out like `layout` in `half`. A `signed` one is sign extended from its
+/// highest bit.
+fn read(half: u16, layout: Layout, signed: bool) -> u32 {
+    let mut imm = 0;
+    for &(top, bits) in layout {
+        for (i, &bit) in bits.iter().enumerate() {
+            imm |= (half as u32 >> (top - i as u32) & 1) << bit;
+        }
+    }
+
+    let highest = layout.iter().flat_map(|(_, bits)| bits.iter()).max().unwrap();
+    if signed {
+        sext(imm, highest + 1)
+    } else {
+        imm
+    }
+}
+
+/// Lays `imm` out like `layout`, or `None` if it has bits the layout has no room for
+fn field(imm: u32, layout: Layout, signed: bool) -> Option<u16> {
+    let mut half = 0;
+    for &(top, bits) in layout {
+        for (i, &bit) in bits.iter().enumerate() {
+            half |= ((imm >> bit & 1) as u16) << (top - i as u32);
+        }
+    }
+    Some(half).filter(|&half| read(half, layout, signed) == imm)
+}
+
+/// The 3-bit number of a register from x8 to x15, which are the only ones most compressed
+/// instructions have room for
+fn prime(r: u8) -> Option<u16> {
+    (8..16).contains(&r).then(|| r as u16 - 8)
+}
+
+fn ci(op: u16, funct3: u16, rd: u8, imm: u32, layout: Layout, signed: bool) -> Option<u16> {
+    Some(funct3 << 13 | field(imm, layout, signed)? | (rd as u16) << 7 | op)
+}
+
+fn css(funct3: u16, rs2: u8, imm: u32, layout: Layout) -> Option<u16> {
+    Some(funct3 << 13 | field(imm, layout, false)? | (rs2 as u16) << 2 | 0b10)
+}
+
+/// The loads and stores with a base register, where `r` is the loaded or stored one
+fn cl(funct3: u16, r: u8, imm: u32, rs1: u8, layout: Layout) -> Option<u16> {
+    Some(funct3 << 13 | field(imm, layout, false)? | prime(rs1)? << 7 | prime(r)? << 2)
+}
+
+fn ca(funct2: u16, rd: u8, rs2: u8) -> Option<u16> {
+    Some(0b100011 << 10 | prime(rd)? << 7 | funct2 << 5 | prime(rs2)? << 2 | 0b01)
+}
+
+/// `c.srli`, `c.srai` and `c.andi`, which share their funct3
+fn cb_imm(funct2: u16, rd: u8, imm: u32, signed: bool) -> Option<u16> {
+    Some(0b100 << 13 | funct2 << 10 | field(imm, CI, signed)? | prime(rd)? << 7 | 0b01)
+}
+
+fn cb(funct3: u16, rs1: u8, offset: u32) -> Option<u16> {
+    Some(funct3 << 13 | field(offset, BRANCH, true)? | prime(rs1)? << 7 | 0b01)
+}
+
+fn cj(funct3: u16, offset: u32) -> Option<u16> {
+    Some(funct3 << 13 | field(offset, JUMP, true)? | 0b01)
+}
+
+fn cr(funct4: u16, rd: u8, rs2: u8) -> u16 {
+    funct4 << 12 | (rd as u16) << 7 | (rs2 as u16) << 2 | 0b10
+}
+
+/// Encodes `instruction`, which is at the address `pc`, in 2 bytes. Returns `None` if it has no
+/// compressed version, or if its registers or immediate don't fit in it.
+pub(in super::super) fn compress(instruction: &Instruction, pc: usize) -> Option<u16> {
+    use FloatInstruction as F;
+    use Instruction::*;
+
+    let offset = |target: usize| (target as u32).wrapping_sub(pc as u32);
+
+    let half = match *instruction {
+        Mv(0, 0) => 0x0001, // c.nop
+        Addi(rd, rs1, imm) if rd == rs1 && rd != 0 && imm != 0 => {
+            let addi16sp = || ci(0b01, 3, 2, imm, ADDI16SP, true).filter(|_| rd == 2);
+            ci(0b01, 0, rd, imm, CI, true).or_else(addi16sp)?
+        }
+        Addi(rd, 2, imm) if imm != 0 => Some(field(imm, ADDI4SPN, false)? | prime(rd)? << 2)?,
+        Li(rd, imm) if rd != 0 => {
+            let lui = || ci(0b01, 3, rd, imm, LUI, true).filter(|_| rd != 2 && imm != 0);
+            ci(0b01, 2, rd, imm, CI, true).or_else(lui)?
+        }
+        Slli(rd, rs1, shamt) if rd == rs1 && rd != 0 && (1..32).contains(&shamt) => {
+            ci(0b10, 0, rd, shamt, CI, false)?
+        }
+        Srli(rd, rs1, shamt) if rd == rs1 && (1..32).contains(&shamt) => cb_imm(0, rd, shamt, false)?,
+        Srai(rd, rs1, shamt) if rd == rs1 && (1..32).contains(&shamt) => cb_imm(1, rd, shamt, false)?,
+        Andi(rd, rs1, imm) if rd == rs1 => cb_imm(2, rd, imm, true)?,
+        Sub(rd, rs1, rs2) if rd == rs1 => ca(0, rd, rs2)?,
+        Xor(rd, rs1, rs2) if rd == rs1 => ca(1, rd, rs2)?,
+        Or(rd, rs1, rs2) if rd == rs1 => ca(2, rd, rs2)?,
+        And(rd, rs1, rs2) if rd == rs1 => ca(3, rd, rs2)?,
+        Mv(rd, rs2) if rd != 0 && rs2 != 0 => cr(0b1000, rd, rs2),
+        Add(rd, rs1, rs2) if rd == rs1 && rd != 0 && rs2 != 0 => cr(0b1001, rd, rs2),
+
+        Jalr(0, rs1, 0) if rs1 != 0 => cr(0b1000, rs1, 0), // c.jr
+        Ret => cr(0b1000, 1, 0),
+        Jalr(1, rs1, 0) if rs1 != 0 => cr(0b1001, rs1, 0), // c.jalr
+        Jal(0, target) => cj(5, offset(target))?,
+        Jal(1, target) => cj(1, offset(target))?,
+        Beq(rs1, 0, target) => cb(6, rs1, offset(target))?,
+        Bne(rs1, 0, target) => cb(7, rs1, offset(target))?,
+        Ebreak => 0x9002,
+
+        // The ones relative to `sp` have a larger offset and any register
+        Lw(rd, imm, 2) if rd != 0 => ci(0b10, 2, rd, imm, LWSP, false)?,
+        Lw(rd, imm, rs1) => cl(2, rd, imm, rs1, LW)?,
+        Sw(rs2, imm, 2) => css(6, rs2, imm, SWSP)?,
+        Sw(rs2, imm, rs1) => cl(6, rs2, imm, rs1, LW)?,
+        Float(F::Lw(rd, imm, 2)) => ci(0b10, 3, rd, imm, LWSP, false)?,
+        Float(F::Lw(rd, imm, rs1)) => cl(3, rd, imm, rs1, LW)?,
+        Float(F::Sw(rs2, imm, 2)) => css(7, rs2, imm, SWSP)?,
+        Float(F::Sw(rs2, imm, rs1)) => cl(7, rs2, imm, rs1, LW)?,
+        Float(F::Ld(rd, imm, 2)) => ci(0b10, 1, rd, imm, LDSP, false)?,
+        Float(F::Ld(rd, imm, rs1)) => cl(1, rd, imm, rs1, LD)?,
+        Float(F::Sd(rs2, imm, 2)) => css(5, rs2, imm, SDSP)?,
+        Float(F::Sd(rs2, imm, rs1)) => cl(5, rs2, imm, rs1, LD)?,
+
+        // A `.half` in the `.text` we couldn't decode
+        Illegal(ref line) => {
+            let hex = line.strip_prefix(".half 0x")?;
+            u16::from_str_radix(hex, 16).ok()?
+        }
+        _ => return None,
+    };
+
+    Some(half)
+}
+
+/// Decodes the compressed instruction `half`, which is at the address `pc`, into the base
+/// instruction it stands for. The reserved encodings and the hints aren't decoded.
+pub(in super::super) fn decompress(half: u16, pc: usize) -> Option<Instruction> {
+    use FloatInstruction as F;
+    use Instruction::*;
+
+    let bits = |lo: u32, len: u32| (half as u32 >> lo) & ((1 << len) - 1);
+    let (op, funct3) = (bits(0, 2), bits(13, 3));
+    let (rd, rs2) = (bits(7, 5) as u8, bits(2, 5) as u8);
+    // The registers from x8 to x15 of the instructions that only have room for those
+    let (rs1_prime, rd_prime) = (bits(7, 3) as u8 + 8, bits(2, 3) as u8 + 8);
+
+    let imm = |layout: Layout, signed: bool| read(half, layout, signed);
+    let target = |layout: Layout| (pc as u32).wrapping_add(read(half, layout, true)) as usize;
+
+    let instruction = match (op, funct3) {
+        (0b00, 0) if imm(ADDI4SPN, false) != 0 => Addi(rd_prime, 2, imm(ADDI4SPN, false)),
+        (0b00, 1) => F::Ld(rd_prime, imm(LD, false), rs1_prime).into(),
+        (0b00, 2) => Lw(rd_prime, imm(LW, false), rs1_prime),
+        (0b00, 3) => F::Lw(rd_prime, imm(LW, false), rs1_prime).into(),
+        (0b00, 5) => F::Sd(rd_prime, imm(LD, false), rs1_prime).into(),
+        (0b00, 6) => Sw(rd_prime, imm(LW, false), rs1_prime),
+        (0b00, 7) => F::Sw(rd_prime, imm(LW, false), rs1_prime).into(),
+
+        (0b01, 0) if rd == 0 && imm(CI, false) == 0 => Mv(0, 0),
+        (0b01, 0) if rd != 0 && imm(CI, false) != 0 => Addi(rd, rd, imm(CI, true)),
+        (0b01, 1) => Jal(1, target(JUMP)),
+        (0b01, 2) if rd != 0 => Li(rd, imm(CI, true)),
+        (0b01, 3) if rd == 2 && imm(ADDI16SP, false) != 0 => Addi(2, 2, imm(ADDI16SP, true)),
+        (0b01, 3) if rd != 0 && imm(LUI, false) != 0 => Li(rd, imm(LUI, true)),
+        (0b01, 4) => {
+            let shamt = imm(CI, false);
+            match (bits(10, 2), bits(12, 1), bits(5, 2)) {
+                (0, _, _) if (1..32).contains(&shamt) => Srli(rs1_prime, rs1_prime, shamt),
+                (1, _, _) if (1..32).contains(&shamt) => Srai(rs1_prime, rs1_prime, shamt),
+                (2, _, _) => Andi(rs1_prime, rs1_prime, imm(CI, true)),
+                (3, 0, 0) => Sub(rs1_prime, rs1_prime, rd_prime),
+                (3, 0, 1) => Xor(rs1_prime, rs1_prime, rd_prime),
+                (3, 0, 2) => Or(rs1_prime, rs1_prime, rd_prime),
+                (3, 0, 3) => And(rs1_prime, rs1_prime, rd_prime),
+                _ => return None,
+            }
+        }
+        (0b01, 5) => Jal(0, target(JUMP)),
+        (0b01, 6) => Beq(rs1_prime, 0, target(BRANCH)),
+        (0b01, 7) => Bne(rs1_prime, 0, target(BRANCH)),
+
+        (0b10, 0) if rd != 0 && (1..32).contains(&imm(CI, false)) => Slli(rd, rd, imm(CI, false)),
+        (0b10, 1) => F::Ld(rd, imm(LDSP, false), 2).into(),
+        (0b10, 2) if rd != 0 => Lw(rd, imm(LWSP, false), 2),
+        (0b10, 3) => F::Lw(rd, imm(LWSP, false), 2).into(),
+        (0b10, 4) => match (bits(12, 1), rd, rs2) {
+            (0, 0, _) | (1, 0, 1..) => return None,
+            (0, rs1, 0) => Jalr(0, rs1, 0),
+            (0, rd, rs2) => Mv(rd, rs2),
+            (1, 0, 0) => Ebreak,
+            (1, rs1, 0) => Jalr(1, rs1, 0),
+            (_, rd, rs2) => Add(rd, rd, rs2),
+        },
+        (0b10, 5) => F::Sd(rs2, imm(SDSP, false), 2).into(),
+        (0b10, 6) => Sw(rs2, imm(SWSP, false), 2),
+        (0b10, 7) => F::Sw(rs2, imm(SWSP, false), 2).into(),
+
+        // The lowest bits of the 4-byte instructions are 0b11
+        _ => return None,
+    };
+
+    Some(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Instruction::*;
+
+    #[test]
+    fn test_compress() {
+        use FloatInstruction as F;
+
+        // From the GNU assembler
+        let cases: Vec<(Instruction, u16)> = vec![
+            (Mv(0, 0), 0x0001),
+            (Addi(10, 10, 1), 0x0505),
+            (Addi(10, 10, (-32i32) as u32), 0x1501),
+            (Addi(2, 2, (-64i32) as u32), 0x7139),
+            (Addi(8, 2, 16), 0x0800),
+            (Li(10, 5), 0x4515),
+            (Li(10, 0x1000), 0x6505),
+            (Li(15, 0xFFFF_F000), 0x77fd),
+            (Slli(10, 10, 3), 0x050e),
+            (Srli(8, 8, 1), 0x8005),
+            (Srai(9, 9, 31), 0x84fd),
+            (Andi(10, 10, 15), 0x893d),
+            (Sub(8, 8, 9), 0x8c05),
+            (Xor(10, 10, 11), 0x8d2d),
+            (Or(14, 14, 15), 0x8f5d),
+            (And(8, 8, 15), 0x8c7d),
+            (Mv(10, 11), 0x852e),
+            (Add(10, 10, 11), 0x952e),
+            (Jalr(0, 5, 0), 0x8282),
+            (Jalr(0, 1, 0), 0x8082),
+            (Jalr(1, 5, 0), 0x9282),
+            (Ebreak, 0x9002),
+            (Lw(10, 4, 11), 0x41c8),
+            (Sw(10, 124, 15), 0xdfe8),
+            (Lw(1, 12, 2), 0x40b2),
+            (Sw(1, 252, 2), 0xdf86),
+            (F::Lw(8, 0, 9).into(), 0x6080),
+            (F::Sd(8, 8, 2).into(), 0xa422),
+        ];
+        for (instruction, half) in cases {
+            assert_eq!(compress(&instruction, 0), Some(half), "{:?}", instruction);
+            assert_eq!(decompress(half, 0), Some(instruction));
+        }
+
+        // Relative to where they are
+        let jumps = [
+            (Jal(0, 0x100), 0xa001),
+            (Jal(1, 0xFE), 0x3ffd),
+            (Jal(0, 0x8FE), 0xaffd),
+            (Beq(8, 0, 0x2), 0xd009),
+            (Beq(8, 0, 0x1FE), 0xcc7d),
+            (Bne(15, 0, 0xF0), 0xfbe5),
+        ];
+        for (instruction, half) in jumps {
+            assert_eq!(compress(&instruction, 0x100), Some(half), "{:?}", instruction);
+            assert_eq!(decompress(half, 0x100), Some(instruction));
+        }
+
+        assert_eq!(compress(&Ret, 0), Some(0x8082));
+
+        // Registers or immediates that don't fit
+        assert_eq!(compress(&Addi(10, 11, 1), 0), None);
+        assert_eq!(compress(&Addi(10, 10, 32), 0), None);
+        assert_eq!(compress(&Sub(5, 5, 9), 0), None);
+        assert_eq!(compress(&Lw(10, 2, 11), 0), None);
+        assert_eq!(compress(&Lw(10, 128, 11), 0), None);
+        assert_eq!(compress(&Beq(8, 0, 0x1000), 0), None);
+        assert_eq!(compress(&Ecall, 0), None);
+        assert_eq!(decompress(0x0000, 0), None);
+        assert_eq!(decompress(0x0013, 0), None);
+    }
+}
@@ -0,0 +1,286 @@
+//!
+//! Turns instructions that were already encoded, like the `0x00000013` in `.word 0x00000013`, back
+//! into [Instructions](../../enum.Instruction.html). Only the ones we can simulate are decoded.
+//!
+
+use super::super::{
+    register_names::RegMap, FloatInstruction, Instruction, Rv64Instruction, VectorInstruction,
+};
+
+/// Sign extends the lowest `bits` bits of `x`
+pub(super) fn sext(x: u32, bits: u32) -> u32 {
+    let shift = 32 - bits;
+    (((x << shift) as i32) >> shift) as u32
+}
+
+/// Decodes the instruction `word`, which is at the address `pc`. Branches and jumps are relative
+/// to it. `status` is used to find the status registers of the CSR instructions.
+pub(in super::super) fn decode(word: u32, pc: usize, status: &RegMap) -> Option<Instruction> {
+    use FloatInstruction as F;
+    use Instruction::*;
+    use Rv64Instruction as R;
+    use VectorInstruction as V;
+
+    let bits = |lo: u32, len: u32| (word >> lo) & ((1 << len) - 1);
+    let (opcode, funct3, funct7) = (bits(0, 7), bits(12, 3), bits(25, 7));
+    let (rd, rs1, rs2) = (bits(7, 5) as u8, bits(15, 5) as u8, bits(20, 5) as u8);
+
+    let imm_i = sext(bits(20, 12), 12);
+    let imm_s = sext(bits(25, 7) << 5 | bits(7, 5), 12);
+    let imm_b = sext(bits(31, 1) << 12 | bits(7, 1) << 11 | bits(25, 6) << 5 | bits(8, 4) << 1, 13);
+    let imm_j = sext(bits(31, 1) << 20 | bits(12, 8) << 12 | bits(20, 1) << 11 | bits(21, 10) << 1, 21);
+    let target = |offset: u32| (pc as u32).wrapping_add(offset) as usize;
+
+    let instruction = match (opcode, funct3, funct7) {
+        (0x33, 0, 0x00) => Add(rd, rs1, rs2),
+        (0x33, 0, 0x20) => Sub(rd, rs1, rs2),
+        (0x33, 1, 0x00) => Sll(rd, rs1, rs2),
+        (0x33, 2, 0x00) => Slt(rd, rs1, rs2),
+        (0x33, 3, 0x00) => Sltu(rd, rs1, rs2),
+        (0x33, 4, 0x00) => Xor(rd, rs1, rs2),
+        (0x33, 5, 0x00) => Srl(rd, rs1, rs2),
+        (0x33, 5, 0x20) => Sra(rd, rs1, rs2),
+        (0x33, 6, 0x00) => Or(rd, rs1, rs2),
+        (0x33, 7, 0x00) => And(rd, rs1, rs2),
+        (0x33, 0, 0x01) => Mul(rd, rs1, rs2),
+        (0x33, 1, 0x01) => Mulh(rd, rs1, rs2),
+        (0x33, 2, 0x01) => Mulhsu(rd, rs1, rs2),
+        (0x33, 3, 0x01) => Mulhu(rd, rs1, rs2),
+        (0x33, 4, 0x01) => Div(rd, rs1, rs2),
+        (0x33, 5, 0x01) => Divu(rd, rs1, rs2),
+        (0x33, 6, 0x01) => Rem(rd, rs1, rs2),
+        (0x33, 7, 0x01) => Remu(rd, rs1, rs2),
+        (0x33, 2, 0x10) => Sh1add(rd, rs1, rs2),
+        (0x33, 4, 0x10) => Sh2add(rd, rs1, rs2),
+        (0x33, 6, 0x10) => Sh3add(rd, rs1, rs2),
+        (0x33, 1, 0x14) => Bset(rd, rs1, rs2),
+        (0x33, 1, 0x24) => Bclr(rd, rs1, rs2),
+        (0x33, 1, 0x34) => Binv(rd, rs1, rs2),
+        (0x33, 5, 0x24) => Bext(rd, rs1, rs2),
+        (0x33, 5, 0x07) => CzeroEqz(rd, rs1, rs2),
+        (0x33, 7, 0x07) => CzeroNez(rd, rs1, rs2),
+
+        (0x13, 0, _) => Addi(rd, rs1, imm_i),
+        (0x13, 2, _) => Slti(rd, rs1, imm_i),
+        (0x13, 3, _) => Sltiu(rd, rs1, imm_i),
+        (0x13, 4, _) => Xori(rd, rs1, imm_i),
+        (0x13, 6, _) => Ori(rd, rs1, imm_i),
+        (0x13, 7, _) => Andi(rd, rs1, imm_i),
+        (0x13, 1, 0x00) => Slli(rd, rs1, rs2 as u32),
+        (0x13, 5, 0x00) => Srli(rd, rs1, rs2 as u32),
+        (0x13, 5, 0x20) => Srai(rd, rs1, rs2 as u32),
+        // In RV64 the shift amount has a sixth bit, which lands on funct7
+        (0x13, 1, 0x01) => R::Slli(rd, rs1, rs2 as u32 + 32).into(),
+        (0x13, 5, 0x01) => R::Srli(rd, rs1, rs2 as u32 + 32).into(),
+        (0x13, 5, 0x21) => R::Srai(rd, rs1, rs2 as u32 + 32).into(),
+        (0x13, 1, 0x14) => Bseti(rd, rs1, rs2 as u32),
+        (0x13, 1, 0x24) => Bclri(rd, rs1, rs2 as u32),
+        (0x13, 1, 0x34) => Binvi(rd, rs1, rs2 as u32),
+        (0x13, 5, 0x24) => Bexti(rd, rs1, rs2 as u32),
+        (0x13, 1, 0x15) => R::Bseti(rd, rs1, rs2 as u32 + 32).into(),
+        (0x13, 1, 0x25) => R::Bclri(rd, rs1, rs2 as u32 + 32).into(),
+        (0x13, 1, 0x35) => R::Binvi(rd, rs1, rs2 as u32 + 32).into(),
+        (0x13, 5, 0x25) => R::Bexti(rd, rs1, rs2 as u32 + 32).into(),
+
+        (0x3b, 0, 0x00) => R::Addw(rd, rs1, rs2).into(),
+        (0x3b, 0, 0x20) => R::Subw(rd, rs1, rs2).into(),
+        (0x3b, 1, 0x00) => R::Sllw(rd, rs1, rs2).into(),
+        (0x3b, 5, 0x00) => R::Srlw(rd, rs1, rs2).into(),
+        (0x3b, 5, 0x20) => R::Sraw(rd, rs1, rs2).into(),
+        (0x3b, 0, 0x01) => R::Mulw(rd, rs1, rs2).into(),
+        (0x3b, 4, 0x01) => R::Divw(rd, rs1, rs2).into(),
+        (0x3b, 5, 0x01) => R::Divuw(rd, rs1, rs2).into(),
+        (0x3b, 6, 0x01) => R::Remw(rd, rs1, rs2).into(),
+        (0x3b, 7, 0x01) => R::Remuw(rd, rs1, rs2).into(),
+        (0x1b, 0, _) => R::Addiw(rd, rs1, imm_i).into(),
+        (0x1b, 1, 0x00) => R::Slliw(rd, rs1, rs2 as u32).into(),
+        (0x1b, 5, 0x00) => R::Srliw(rd, rs1, rs2 as u32).into(),
+        (0x1b, 5, 0x20) => R::Sraiw(rd, rs1, rs2 as u32).into(),
+
+        (0x03, 0, _) => Lb(rd, imm_i, rs1),
+        (0x03, 1, _) => Lh(rd, imm_i, rs1),
+        (0x03, 2, _) => Lw(rd, imm_i, rs1),
+        (0x03, 4, _) => Lbu(rd, imm_i, rs1),
+        (0x03, 5, _) => Lhu(rd, imm_i, rs1),
+        (0x23, 0, _) => Sb(rs2, imm_s, rs1),
+        (0x23, 1, _) => Sh(rs2, imm_s, rs1),
+        (0x23, 2, _) => Sw(rs2, imm_s, rs1),
+        (0x03, 3, _) => R::Ld(rd, imm_i, rs1).into(),
+        (0x03, 6, _) => R::Lwu(rd, imm_i, rs1).into(),
+        (0x23, 3, _) => R::Sd(rs2, imm_s, rs1).into(),
+
+        (0x63, 0, _) => Beq(rs1, rs2, target(imm_b)),
+        (0x63, 1, _) => Bne(rs1, rs2, target(imm_b)),
+        (0x63, 4, _) => Blt(rs1, rs2, target(imm_b)),
+        (0x63, 5, _) => Bge(rs1, rs2, target(imm_b)),
+        (0x63, 6, _) => Bltu(rs1, rs2, target(imm_b)),
+        (0x63, 7, _) => Bgeu(rs1, rs2, target(imm_b)),
+        (0x6f, _, _) => Jal(rd, target(imm_j)),
+        (0x67, 0, _) => Jalr(rd, rs1, imm_i),
+
+        (0x37, _, _) => Li(rd, word & 0xFFFF_F000),
+        (0x17, _, _) => Auipc(rd, word & 0xFFFF_F000),
+
+        (0x0F, 0, _) => Fence(bits(24, 4) as u8, bits(20, 4) as u8),
+        (0x0F, 1, _) => FenceI,
+
+        // The `aq` and `rl` bits don't matter
+        (0x2f, 2, _) => match (funct7 >> 2, rs2) {
+            (0x02, 0) => LrW(rd, rs1),
+            (0x03, _) => ScW(rd, rs2, rs1),
+            (0x01, _) => AmoSwapW(rd, rs2, rs1),
+            (0x00, _) => AmoAddW(rd, rs2, rs1),
+            (0x04, _) => AmoXorW(rd, rs2, rs1),
+            (0x0c, _) => AmoAndW(rd, rs2, rs1),
+            (0x08, _) => AmoOrW(rd, rs2, rs1),
+            (0x10, _) => AmoMinW(rd, rs2, rs1),
+            (0x14, _) => AmoMaxW(rd, rs2, rs1),
+            (0x18, _) => AmoMinuW(rd, rs2, rs1),
+            (0x1c, _) => AmoMaxuW(rd, rs2, rs1),
+            _ => return None,
+        },
+
+        (0x73, 0, _) => match word {
+            0x0000_0073 => Ecall,
+            0x0010_0073 => Ebreak,
+            0x0020_0073 => URet,
+            0x3020_0073 => MRet,
+            0x1050_0073 => Wfi,
+            _ => return None,
+        },
+        (0x73, _, _) => {
+            let csr = *status.get(&bits(20, 12).to_string())?;
+            match funct3 {
+                1 => CsrRw(rd, csr, rs1),
+                2 => CsrRs(rd, csr, rs1),
+                3 => CsrRc(rd, csr, rs1),
+                5 => CsrRwi(rd, csr, rs1 as u32),
+                6 => CsrRsi(rd, csr, rs1 as u32),
+                7 => CsrRci(rd, csr, rs1 as u32),
+                _ => return None,
+            }
+        }
+
+        (0x07, 2, _) => F::Lw(rd, imm_i, rs1).into(),
+        (0x27, 2, _) => F::Sw(rs2, imm_s, rs1).into(),
+        (0x07, 3, _) => F::Ld(rd, imm_i, rs1).into(),
+        (0x27, 3, _) => F::Sd(rs2, imm_s, rs1).into(),
+
+        // Vectors, only unmasked, so funct7 always ends in 1
+        (0x07, 6, 0x01) if rs2 == 0 => V::Le32(rd, rs1).into(),
+        (0x27, 6, 0x01) if rs2 == 0 => V::Se32(rd, rs1).into(),
+        (0x57, 7, _) if bits(31, 1) == 0 => V::Setvli(rd, rs1, bits(20, 11)).into(),
+        (0x57, _, _) if funct7 & 1 == 1 => match (funct3, funct7 >> 1) {
+            (0, 0x00) => V::Add(rd, rs2, rs1).into(),
+            (2, 0x25) => V::Mul(rd, rs2, rs1).into(),
+            (2, 0x00) => V::RedSum(rd, rs2, rs1).into(),
+            (2, 0x04) => V::RedMinu(rd, rs2, rs1).into(),
+            (2, 0x05) => V::RedMin(rd, rs2, rs1).into(),
+            (2, 0x06) => V::RedMaxu(rd, rs2, rs1).into(),
+            (2, 0x07) => V::RedMax(rd, rs2, rs1).into(),
+            (2, 0x10) if rs1 == 0 => V::MvXS(rd, rs2).into(),
+            (6, 0x10) if rs2 == 0 => V::MvSX(rd, rs1).into(),
+            _ => return None,
+        },
+        // The rounding modes 5 and 6 are reserved
+        (0x43 | 0x47 | 0x4b | 0x4f | 0x53, 5..=6, _) => return None,
+        (0x43 | 0x47 | 0x4b | 0x4f, rm, _) => {
+            let (rs3, rm) = (bits(27, 5) as u8, rm as u8);
+            match (opcode, funct7 & 3) {
+                (0x43, 0) => F::Madd(rd, rs1, rs2, rs3, rm),
+                (0x47, 0) => F::Msub(rd, rs1, rs2, rs3, rm),
+                (0x4b, 0) => F::NMsub(rd, rs1, rs2, rs3, rm),
+                (0x4f, 0) => F::NMadd(rd, rs1, rs2, rs3, rm),
+                (0x43, 1) => F::MaddD(rd, rs1, rs2, rs3, rm),
+                (0x47, 1) => F::MsubD(rd, rs1, rs2, rs3, rm),
+                (0x4b, 1) => F::NMsubD(rd, rs1, rs2, rs3, rm),
+                (0x4f, 1) => F::NMaddD(rd, rs1, rs2, rs3, rm),
+                _ => return None,
+            }
+            .into()
+        }
+        (0x53, rm, _) => match (funct7, funct3, rs2) {
+            (0x00, _, _) => F::Add(rd, rs1, rs2, rm as u8),
+            (0x04, _, _) => F::Sub(rd, rs1, rs2, rm as u8),
+            (0x08, _, _) => F::Mul(rd, rs1, rs2, rm as u8),
+            (0x0c, _, _) => F::Div(rd, rs1, rs2, rm as u8),
+            (0x2c, _, 0) => F::Sqrt(rd, rs1, rm as u8),
+            (0x10, 0, _) => F::SgnjS(rd, rs1, rs2),
+            (0x10, 1, _) => F::SgnjNS(rd, rs1, rs2),
+            (0x10, 2, _) => F::SgnjXS(rd, rs1, rs2),
+            (0x14, 0, _) => F::Min(rd, rs1, rs2),
+            (0x14, 1, _) => F::Max(rd, rs1, rs2),
+            (0x50, 0, _) => F::Le(rd, rs1, rs2),
+            (0x50, 1, _) => F::Lt(rd, rs1, rs2),
+            (0x50, 2, _) => F::Equ(rd, rs1, rs2),
+            (0x60, _, 0) => F::CvtWS(rd, rs1, rm as u8),
+            (0x60, _, 1) => F::CvtWuS(rd, rs1, rm as u8),
+            (0x68, _, 0) => F::CvtSW(rd, rs1, rm as u8),
+            (0x68, _, 1) => F::CvtSWu(rd, rs1, rm as u8),
+            (0x70, 0, 0) => F::MvXS(rd, rs1),
+            (0x70, 1, 0) => F::Class(rd, rs1),
+            (0x78, 0, 0) => F::MvSX(rd, rs1),
+
+            (0x01, _, _) => F::AddD(rd, rs1, rs2, rm as u8),
+            (0x05, _, _) => F::SubD(rd, rs1, rs2, rm as u8),
+            (0x09, _, _) => F::MulD(rd, rs1, rs2, rm as u8),
+            (0x0d, _, _) => F::DivD(rd, rs1, rs2, rm as u8),
+            (0x2d, _, 0) => F::SqrtD(rd, rs1, rm as u8),
+            (0x11, 0, _) => F::SgnjD(rd, rs1, rs2),
+            (0x11, 1, _) => F::SgnjND(rd, rs1, rs2),
+            (0x11, 2, _) => F::SgnjXD(rd, rs1, rs2),
+            (0x15, 0, _) => F::MinD(rd, rs1, rs2),
+            (0x15, 1, _) => F::MaxD(rd, rs1, rs2),
+            (0x51, 0, _) => F::LeD(rd, rs1, rs2),
+            (0x51, 1, _) => F::LtD(rd, rs1, rs2),
+            (0x51, 2, _) => F::EquD(rd, rs1, rs2),
+            (0x20, _, 1) => F::CvtSD(rd, rs1, rm as u8),
+            (0x21, _, 0) => F::CvtDS(rd, rs1),
+            (0x61, _, 0) => F::CvtWD(rd, rs1, rm as u8),
+            (0x61, _, 1) => F::CvtWuD(rd, rs1, rm as u8),
+            (0x69, _, 0) => F::CvtDW(rd, rs1),
+            (0x69, _, 1) => F::CvtDWu(rd, rs1),
+            (0x71, 1, 0) => F::ClassD(rd, rs1),
+            _ => return None,
+        }
+        .into(),
+
+        _ => return None,
+    };
+
+    Some(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::register_names::status;
+    use Instruction::*;
+
+    #[test]
+    fn test_decode() {
+        let status = status();
+        let decode = |word, pc| decode(word, pc, &status);
+
+        assert_eq!(decode(0x0000_0013, 0), Some(Addi(0, 0, 0))); // nop
+        assert_eq!(decode(0xfff2_8293, 0), Some(Addi(5, 5, 0xFFFF_FFFF))); // addi t0 t0 -1
+        assert_eq!(decode(0x4073_52b3, 0), Some(Sra(5, 6, 7))); // sra t0 t1 t2
+        assert_eq!(decode(0x0273_02b3, 0), Some(Mul(5, 6, 7))); // mul t0 t1 t2
+        assert_eq!(decode(0x4053_5293, 0), Some(Srai(5, 6, 5))); // srai t0 t1 5
+        assert_eq!(decode(0xffc1_2283, 0), Some(Lw(5, (-4i32) as u32, 2))); // lw t0 -4(sp)
+        assert_eq!(decode(0xfe51_2e23, 0), Some(Sw(5, (-4i32) as u32, 2))); // sw t0 -4(sp)
+        assert_eq!(decode(0xfe02_9ee3, 0x20), Some(Bne(5, 0, 0x1c))); // bnez t0 -4
+        assert_eq!(decode(0x0080_00ef, 0x10), Some(Jal(1, 0x18))); // jal ra 8
+        assert_eq!(decode(0x0000_8067, 0), Some(Jalr(0, 1, 0))); // ret
+        assert_eq!(decode(0x1234_52b7, 0), Some(Li(5, 0x1234_5000))); // lui t0 0x12345
+        assert_eq!(decode(0x0000_1297, 0), Some(Auipc(5, 0x1000))); // auipc t0 1
+        assert_eq!(decode(0x0000_0073, 0), Some(Ecall));
+        assert_eq!(decode(0x1605_22af, 0), Some(LrW(5, 10))); // lr.w.aqrl t0 (a0)
+        assert_eq!(decode(0x1065_22af, 0), None); // lr.w with an rs2
+        assert_eq!(decode(0x0051_1073, 0), Some(CsrRw(0, 4, 2))); // csrw utvec sp
+        assert_eq!(decode(0x0020_72d3, 0), Some(FloatInstruction::Add(5, 0, 2, 7).into())); // fadd.s ft5 ft0 ft2
+
+        assert_eq!(decode(0xffff_ffff, 0), None);
+        assert_eq!(decode(0x0000_0000, 0), None);
+    }
+}
@@ -0,0 +1,413 @@
+//!
+//! Writes [Instructions](../../enum.Instruction.html) back as assembly, like a disassembler would.
+//! Branches and jumps show the address they go to, since that's what we keep of them.
+//!
+
+use std::fmt;
+
+use super::super::{
+    register_names::{FLOAT_NAMES, REGISTER_NAMES, STATUS_NAMES},
+    FloatInstruction, Instruction, Rv64Instruction, VectorInstruction,
+};
+use super::ROUNDING_MODES;
+
+fn x(r: u8) -> &'static str {
+    REGISTER_NAMES[r as usize]
+}
+
+fn f(r: u8) -> &'static str {
+    FLOAT_NAMES[r as usize]
+}
+
+fn csr(r: u8) -> &'static str {
+    STATUS_NAMES.get(r as usize).copied().unwrap_or("?")
+}
+
+/// A set of a `fence`, like `rw`
+fn fence_set(set: u8) -> String {
+    let letters = "iorw".chars().enumerate().filter(|&(bit, _)| set & (8 >> bit) != 0);
+    let letters: String = letters.map(|(_, letter)| letter).collect();
+    if letters.is_empty() { "0".to_owned() } else { letters }
+}
+
+/// The rounding mode at the end of a float instruction, which is left out when it's the dynamic one
+fn rounding(rm: u8) -> String {
+    match ROUNDING_MODES.get(rm as usize) {
+        Some(mode) => format!(", {}", mode),
+        None => String::new(),
+    }
+}
+
+/// The immediates are kept as `u32`, but most of them are really signed
+fn signed(x: u32) -> i32 {
+    x as i32
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+
+        macro_rules! r {
+            ($name:expr, $rd:expr, $rs1:expr, $rs2:expr) => {
+                write!(fmt, "{} {}, {}, {}", $name, x($rd), x($rs1), x($rs2))
+            };
+        }
+        macro_rules! amo {
+            ($name:expr, $rd:expr, $rs2:expr, $rs1:expr) => {
+                write!(fmt, "{} {}, {}, ({})", $name, x($rd), x($rs2), x($rs1))
+            };
+        }
+        macro_rules! i {
+            ($name:expr, $rd:expr, $rs1:expr, $imm:expr) => {
+                write!(fmt, "{} {}, {}, {}", $name, x($rd), x($rs1), signed($imm))
+            };
+        }
+        macro_rules! mem {
+            ($name:expr, $rd:expr, $imm:expr, $rs1:expr) => {
+                write!(fmt, "{} {}, {}({})", $name, x($rd), signed($imm), x($rs1))
+            };
+        }
+        macro_rules! b {
+            ($name:expr, $rs1:expr, $rs2:expr, $target:expr) => {
+                write!(fmt, "{} {}, {}, {:#x}", $name, x($rs1), x($rs2), $target)
+            };
+        }
+        macro_rules! csr {
+            ($name:expr, $rd:expr, $csr:expr, $rs1:expr) => {
+                write!(fmt, "{} {}, {}, {}", $name, x($rd), csr($csr), $rs1)
+            };
+        }
+
+        match *self {
+            Add(rd, rs1, rs2) => r!("add", rd, rs1, rs2),
+            Sub(rd, rs1, rs2) => r!("sub", rd, rs1, rs2),
+            Sll(rd, rs1, rs2) => r!("sll", rd, rs1, rs2),
+            Slt(rd, rs1, rs2) => r!("slt", rd, rs1, rs2),
+            Sltu(rd, rs1, rs2) => r!("sltu", rd, rs1, rs2),
+            Xor(rd, rs1, rs2) => r!("xor", rd, rs1, rs2),
+            Srl(rd, rs1, rs2) => r!("srl", rd, rs1, rs2),
+            Sra(rd, rs1, rs2) => r!("sra", rd, rs1, rs2),
+            Or(rd, rs1, rs2) => r!("or", rd, rs1, rs2),
+            And(rd, rs1, rs2) => r!("and", rd, rs1, rs2),
+            Mul(rd, rs1, rs2) => r!("mul", rd, rs1, rs2),
+            Mulh(rd, rs1, rs2) => r!("mulh", rd, rs1, rs2),
+            Mulhsu(rd, rs1, rs2) => r!("mulhsu", rd, rs1, rs2),
+            Mulhu(rd, rs1, rs2) => r!("mulhu", rd, rs1, rs2),
+            Div(rd, rs1, rs2) => r!("div", rd, rs1, rs2),
+            Divu(rd, rs1, rs2) => r!("divu", rd, rs1, rs2),
+            Rem(rd, rs1, rs2) => r!("rem", rd, rs1, rs2),
+            Remu(rd, rs1, rs2) => r!("remu", rd, rs1, rs2),
+            Sh1add(rd, rs1, rs2) => r!("sh1add", rd, rs1, rs2),
+            Sh2add(rd, rs1, rs2) => r!("sh2add", rd, rs1, rs2),
+            Sh3add(rd, rs1, rs2) => r!("sh3add", rd, rs1, rs2),
+            Bset(rd, rs1, rs2) => r!("bset", rd, rs1, rs2),
+            Bclr(rd, rs1, rs2) => r!("bclr", rd, rs1, rs2),
+            Binv(rd, rs1, rs2) => r!("binv", rd, rs1, rs2),
+            Bext(rd, rs1, rs2) => r!("bext", rd, rs1, rs2),
+            CzeroEqz(rd, rs1, rs2) => r!("czero.eqz", rd, rs1, rs2),
+            CzeroNez(rd, rs1, rs2) => r!("czero.nez", rd, rs1, rs2),
+
+            Ecall => write!(fmt, "ecall"),
+            Ebreak => write!(fmt, "ebreak"),
+            Fence(pred, succ) => write!(fmt, "fence {}, {}", fence_set(pred), fence_set(succ)),
+            FenceI => write!(fmt, "fence.i"),
+            LrW(rd, rs1) => write!(fmt, "lr.w {}, ({})", x(rd), x(rs1)),
+            ScW(rd, rs2, rs1) => amo!("sc.w", rd, rs2, rs1),
+            AmoSwapW(rd, rs2, rs1) => amo!("amoswap.w", rd, rs2, rs1),
+            AmoAddW(rd, rs2, rs1) => amo!("amoadd.w", rd, rs2, rs1),
+            AmoXorW(rd, rs2, rs1) => amo!("amoxor.w", rd, rs2, rs1),
+            AmoAndW(rd, rs2, rs1) => amo!("amoand.w", rd, rs2, rs1),
+            AmoOrW(rd, rs2, rs1) => amo!("amoor.w", rd, rs2, rs1),
+            AmoMinW(rd, rs2, rs1) => amo!("amomin.w", rd, rs2, rs1),
+            AmoMaxW(rd, rs2, rs1) => amo!("amomax.w", rd, rs2, rs1),
+            AmoMinuW(rd, rs2, rs1) => amo!("amominu.w", rd, rs2, rs1),
+            AmoMaxuW(rd, rs2, rs1) => amo!("amomaxu.w", rd, rs2, rs1),
+            Lb(rd, imm, rs1) => mem!("lb", rd, imm, rs1),
+            Lh(rd, imm, rs1) => mem!("lh", rd, imm, rs1),
+            Lw(rd, imm, rs1) => mem!("lw", rd, imm, rs1),
+            Lbu(rd, imm, rs1) => mem!("lbu", rd, imm, rs1),
+            Lhu(rd, imm, rs1) => mem!("lhu", rd, imm, rs1),
+            Addi(rd, rs1, imm) => i!("addi", rd, rs1, imm),
+            Slti(rd, rs1, imm) => i!("slti", rd, rs1, imm),
+            Sltiu(rd, rs1, imm) => i!("sltiu", rd, rs1, imm),
+            Slli(rd, rs1, imm) => i!("slli", rd, rs1, imm),
+            Srli(rd, rs1, imm) => i!("srli", rd, rs1, imm),
+            Srai(rd, rs1, imm) => i!("srai", rd, rs1, imm),
+            Bseti(rd, rs1, imm) => i!("bseti", rd, rs1, imm),
+            Bclri(rd, rs1, imm) => i!("bclri", rd, rs1, imm),
+            Binvi(rd, rs1, imm) => i!("binvi", rd, rs1, imm),
+            Bexti(rd, rs1, imm) => i!("bexti", rd, rs1, imm),
+            Ori(rd, rs1, imm) => i!("ori", rd, rs1, imm),
+            Andi(rd, rs1, imm) => i!("andi", rd, rs1, imm),
+            Xori(rd, rs1, imm) => i!("xori", rd, rs1, imm),
+
+            Sb(rs2, imm, rs1) => mem!("sb", rs2, imm, rs1),
+            Sh(rs2, imm, rs1) => mem!("sh", rs2, imm, rs1),
+            Sw(rs2, imm, rs1) => mem!("sw", rs2, imm, rs1),
+
+            Beq(rs1, rs2, target) => b!("beq", rs1, rs2, target),
+            Bne(rs1, rs2, target) => b!("bne", rs1, rs2, target),
+            Blt(rs1, rs2, target) => b!("blt", rs1, rs2, target),
+            Bge(rs1, rs2, target) => b!("bge", rs1, rs2, target),
+            Bltu(rs1, rs2, target) => b!("bltu", rs1, rs2, target),
+            Bgeu(rs1, rs2, target) => b!("bgeu", rs1, rs2, target),
+            Jalr(rd, rs1, imm) => mem!("jalr", rd, imm, rs1),
+            Jal(rd, target) => write!(fmt, "jal {}, {:#x}", x(rd), target),
+
+            Auipc(rd, imm) => write!(fmt, "auipc {}, {:#x}", x(rd), imm >> 12),
+
+            CsrRw(rd, fcsr, rs1) => csr!("csrrw", rd, fcsr, x(rs1)),
+            CsrRs(rd, fcsr, rs1) => csr!("csrrs", rd, fcsr, x(rs1)),
+            CsrRc(rd, fcsr, rs1) => csr!("csrrc", rd, fcsr, x(rs1)),
+            CsrRwi(rd, fcsr, imm) => csr!("csrrwi", rd, fcsr, imm),
+            CsrRsi(rd, fcsr, imm) => csr!("csrrsi", rd, fcsr, imm),
+            CsrRci(rd, fcsr, imm) => csr!("csrrci", rd, fcsr, imm),
+
+            Float(ref instruction) => write!(fmt, "{}", instruction),
+            Rv64(ref instruction) => write!(fmt, "{}", instruction),
+            Vector(ref instruction) => write!(fmt, "{}", instruction),
+
+            Li(rd, imm) => write!(fmt, "li {}, {}", x(rd), signed(imm)),
+            Mv(0, 0) => write!(fmt, "nop"),
+            Mv(rd, rs1) => write!(fmt, "mv {}, {}", x(rd), x(rs1)),
+            Ret => write!(fmt, "ret"),
+            URet => write!(fmt, "uret"),
+            MRet => write!(fmt, "mret"),
+            Wfi => write!(fmt, "wfi"),
+
+            Illegal(ref line) => write!(fmt, "{}", line),
+        }
+    }
+}
+
+impl fmt::Display for Rv64Instruction {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use Rv64Instruction::*;
+
+        macro_rules! r {
+            ($name:expr, $rd:expr, $rs1:expr, $rs2:expr) => {
+                write!(fmt, "{} {}, {}, {}", $name, x($rd), x($rs1), x($rs2))
+            };
+        }
+        macro_rules! i {
+            ($name:expr, $rd:expr, $rs1:expr, $imm:expr) => {
+                write!(fmt, "{} {}, {}, {}", $name, x($rd), x($rs1), signed($imm))
+            };
+        }
+        macro_rules! mem {
+            ($name:expr, $rd:expr, $imm:expr, $rs1:expr) => {
+                write!(fmt, "{} {}, {}({})", $name, x($rd), signed($imm), x($rs1))
+            };
+        }
+
+        match *self {
+            Add(rd, rs1, rs2) => r!("add", rd, rs1, rs2),
+            Sub(rd, rs1, rs2) => r!("sub", rd, rs1, rs2),
+            Sll(rd, rs1, rs2) => r!("sll", rd, rs1, rs2),
+            Srl(rd, rs1, rs2) => r!("srl", rd, rs1, rs2),
+            Sra(rd, rs1, rs2) => r!("sra", rd, rs1, rs2),
+            Mul(rd, rs1, rs2) => r!("mul", rd, rs1, rs2),
+            Mulh(rd, rs1, rs2) => r!("mulh", rd, rs1, rs2),
+            Mulhsu(rd, rs1, rs2) => r!("mulhsu", rd, rs1, rs2),
+            Mulhu(rd, rs1, rs2) => r!("mulhu", rd, rs1, rs2),
+            Div(rd, rs1, rs2) => r!("div", rd, rs1, rs2),
+            Divu(rd, rs1, rs2) => r!("divu", rd, rs1, rs2),
+            Rem(rd, rs1, rs2) => r!("rem", rd, rs1, rs2),
+            Remu(rd, rs1, rs2) => r!("remu", rd, rs1, rs2),
+            Sh1add(rd, rs1, rs2) => r!("sh1add", rd, rs1, rs2),
+            Sh2add(rd, rs1, rs2) => r!("sh2add", rd, rs1, rs2),
+            Sh3add(rd, rs1, rs2) => r!("sh3add", rd, rs1, rs2),
+            Bset(rd, rs1, rs2) => r!("bset", rd, rs1, rs2),
+            Bclr(rd, rs1, rs2) => r!("bclr", rd, rs1, rs2),
+            Binv(rd, rs1, rs2) => r!("binv", rd, rs1, rs2),
+            Bext(rd, rs1, rs2) => r!("bext", rd, rs1, rs2),
+            Addi(rd, rs1, imm) => i!("addi", rd, rs1, imm),
+            Slli(rd, rs1, imm) => i!("slli", rd, rs1, imm),
+            Srli(rd, rs1, imm) => i!("srli", rd, rs1, imm),
+            Srai(rd, rs1, imm) => i!("srai", rd, rs1, imm),
+            Bseti(rd, rs1, imm) => i!("bseti", rd, rs1, imm),
+            Bclri(rd, rs1, imm) => i!("bclri", rd, rs1, imm),
+            Binvi(rd, rs1, imm) => i!("binvi", rd, rs1, imm),
+            Bexti(rd, rs1, imm) => i!("bexti", rd, rs1, imm),
+
+            Addw(rd, rs1, rs2) => r!("addw", rd, rs1, rs2),
+            Subw(rd, rs1, rs2) => r!("subw", rd, rs1, rs2),
+            Sllw(rd, rs1, rs2) => r!("sllw", rd, rs1, rs2),
+            Srlw(rd, rs1, rs2) => r!("srlw", rd, rs1, rs2),
+            Sraw(rd, rs1, rs2) => r!("sraw", rd, rs1, rs2),
+            Mulw(rd, rs1, rs2) => r!("mulw", rd, rs1, rs2),
+            Divw(rd, rs1, rs2) => r!("divw", rd, rs1, rs2),
+            Divuw(rd, rs1, rs2) => r!("divuw", rd, rs1, rs2),
+            Remw(rd, rs1, rs2) => r!("remw", rd, rs1, rs2),
+            Remuw(rd, rs1, rs2) => r!("remuw", rd, rs1, rs2),
+            Addiw(rd, rs1, imm) => i!("addiw", rd, rs1, imm),
+            Slliw(rd, rs1, imm) => i!("slliw", rd, rs1, imm),
+            Srliw(rd, rs1, imm) => i!("srliw", rd, rs1, imm),
+            Sraiw(rd, rs1, imm) => i!("sraiw", rd, rs1, imm),
+
+            Ld(rd, imm, rs1) => mem!("ld", rd, imm, rs1),
+            Lwu(rd, imm, rs1) => mem!("lwu", rd, imm, rs1),
+            Sd(rs2, imm, rs1) => mem!("sd", rs2, imm, rs1),
+        }
+    }
+}
+
+/// The operands of a `vsetvli`, like `e32, m1, ta, ma`
+fn vtype(vtype: u32) -> String {
+    let lmul = ["m1", "m2", "m4", "m8", "?", "mf8", "mf4", "mf2"][(vtype & 7) as usize];
+    let sew = 8 << (vtype >> 3 & 7).min(3);
+    let ta = if vtype & 0x40 != 0 { "ta" } else { "tu" };
+    let ma = if vtype & 0x80 != 0 { "ma" } else { "mu" };
+    format!("e{}, {}, {}, {}", sew, lmul, ta, ma)
+}
+
+impl fmt::Display for VectorInstruction {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use VectorInstruction::*;
+
+        macro_rules! vvv {
+            ($name:expr, $vd:expr, $vs2:expr, $vs1:expr) => {
+                write!(fmt, "{} v{}, v{}, v{}", $name, $vd, $vs2, $vs1)
+            };
+        }
+
+        match *self {
+            Setvli(rd, rs1, vt) => write!(fmt, "vsetvli {}, {}, {}", x(rd), x(rs1), vtype(vt)),
+            Le32(vd, rs1) => write!(fmt, "vle32.v v{}, ({})", vd, x(rs1)),
+            Se32(vs3, rs1) => write!(fmt, "vse32.v v{}, ({})", vs3, x(rs1)),
+            Add(vd, vs2, vs1) => vvv!("vadd.vv", vd, vs2, vs1),
+            Mul(vd, vs2, vs1) => vvv!("vmul.vv", vd, vs2, vs1),
+            RedSum(vd, vs2, vs1) => vvv!("vredsum.vs", vd, vs2, vs1),
+            RedMax(vd, vs2, vs1) => vvv!("vredmax.vs", vd, vs2, vs1),
+            RedMaxu(vd, vs2, vs1) => vvv!("vredmaxu.vs", vd, vs2, vs1),
+            RedMin(vd, vs2, vs1) => vvv!("vredmin.vs", vd, vs2, vs1),
+            RedMinu(vd, vs2, vs1) => vvv!("vredminu.vs", vd, vs2, vs1),
+            MvXS(rd, vs2) => write!(fmt, "vmv.x.s {}, v{}", x(rd), vs2),
+            MvSX(vd, rs1) => write!(fmt, "vmv.s.x v{}, {}", vd, x(rs1)),
+        }
+    }
+}
+
+impl fmt::Display for FloatInstruction {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use FloatInstruction::*;
+
+        // `fff` has three float registers, `xff` writes an integer register, and so on
+        macro_rules! fff {
+            ($name:expr, $rd:expr, $rs1:expr, $rs2:expr) => {
+                write!(fmt, "{} {}, {}, {}", $name, f($rd), f($rs1), f($rs2))
+            };
+            ($name:expr, $rd:expr, $rs1:expr, $rs2:expr, $rm:expr) => {
+                write!(fmt, "{} {}, {}, {}{}", $name, f($rd), f($rs1), f($rs2), rounding($rm))
+            };
+        }
+        macro_rules! ffff {
+            ($name:expr, $rd:expr, $rs1:expr, $rs2:expr, $rs3:expr, $rm:expr) => {
+                write!(fmt, "{} {}, {}, {}, {}{}", $name, f($rd), f($rs1), f($rs2), f($rs3), rounding($rm))
+            };
+        }
+        macro_rules! xff {
+            ($name:expr, $rd:expr, $rs1:expr, $rs2:expr) => {
+                write!(fmt, "{} {}, {}, {}", $name, x($rd), f($rs1), f($rs2))
+            };
+        }
+
+        match *self {
+            Add(rd, rs1, rs2, rm) => fff!("fadd.s", rd, rs1, rs2, rm),
+            Sub(rd, rs1, rs2, rm) => fff!("fsub.s", rd, rs1, rs2, rm),
+            Mul(rd, rs1, rs2, rm) => fff!("fmul.s", rd, rs1, rs2, rm),
+            Div(rd, rs1, rs2, rm) => fff!("fdiv.s", rd, rs1, rs2, rm),
+            Max(rd, rs1, rs2) => fff!("fmax.s", rd, rs1, rs2),
+            Min(rd, rs1, rs2) => fff!("fmin.s", rd, rs1, rs2),
+            SgnjS(rd, rs1, rs2) => fff!("fsgnj.s", rd, rs1, rs2),
+            SgnjNS(rd, rs1, rs2) => fff!("fsgnjn.s", rd, rs1, rs2),
+            SgnjXS(rd, rs1, rs2) => fff!("fsgnjx.s", rd, rs1, rs2),
+            Equ(rd, rs1, rs2) => xff!("feq.s", rd, rs1, rs2),
+            Le(rd, rs1, rs2) => xff!("fle.s", rd, rs1, rs2),
+            Lt(rd, rs1, rs2) => xff!("flt.s", rd, rs1, rs2),
+
+            Class(rd, rs1) => write!(fmt, "fclass.s {}, {}", x(rd), f(rs1)),
+            CvtSW(rd, rs1, rm) => write!(fmt, "fcvt.s.w {}, {}{}", f(rd), x(rs1), rounding(rm)),
+            CvtSWu(rd, rs1, rm) => write!(fmt, "fcvt.s.wu {}, {}{}", f(rd), x(rs1), rounding(rm)),
+            CvtWS(rd, rs1, rm) => write!(fmt, "fcvt.w.s {}, {}{}", x(rd), f(rs1), rounding(rm)),
+            CvtWuS(rd, rs1, rm) => write!(fmt, "fcvt.wu.s {}, {}{}", x(rd), f(rs1), rounding(rm)),
+            MvSX(rd, rs1) => write!(fmt, "fmv.s.x {}, {}", f(rd), x(rs1)),
+            MvXS(rd, rs1) => write!(fmt, "fmv.x.s {}, {}", x(rd), f(rs1)),
+            Sqrt(rd, rs1, rm) => write!(fmt, "fsqrt.s {}, {}{}", f(rd), f(rs1), rounding(rm)),
+
+            Madd(rd, rs1, rs2, rs3, rm) => ffff!("fmadd.s", rd, rs1, rs2, rs3, rm),
+            Msub(rd, rs1, rs2, rs3, rm) => ffff!("fmsub.s", rd, rs1, rs2, rs3, rm),
+            NMsub(rd, rs1, rs2, rs3, rm) => ffff!("fnmsub.s", rd, rs1, rs2, rs3, rm),
+            NMadd(rd, rs1, rs2, rs3, rm) => ffff!("fnmadd.s", rd, rs1, rs2, rs3, rm),
+
+            Lw(rd, imm, rs1) => write!(fmt, "flw {}, {}({})", f(rd), signed(imm), x(rs1)),
+            Sw(rs2, imm, rs1) => write!(fmt, "fsw {}, {}({})", f(rs2), signed(imm), x(rs1)),
+
+            AddD(rd, rs1, rs2, rm) => fff!("fadd.d", rd, rs1, rs2, rm),
+            SubD(rd, rs1, rs2, rm) => fff!("fsub.d", rd, rs1, rs2, rm),
+            MulD(rd, rs1, rs2, rm) => fff!("fmul.d", rd, rs1, rs2, rm),
+            DivD(rd, rs1, rs2, rm) => fff!("fdiv.d", rd, rs1, rs2, rm),
+            MaxD(rd, rs1, rs2) => fff!("fmax.d", rd, rs1, rs2),
+            MinD(rd, rs1, rs2) => fff!("fmin.d", rd, rs1, rs2),
+            SgnjD(rd, rs1, rs2) => fff!("fsgnj.d", rd, rs1, rs2),
+            SgnjND(rd, rs1, rs2) => fff!("fsgnjn.d", rd, rs1, rs2),
+            SgnjXD(rd, rs1, rs2) => fff!("fsgnjx.d", rd, rs1, rs2),
+            EquD(rd, rs1, rs2) => xff!("feq.d", rd, rs1, rs2),
+            LeD(rd, rs1, rs2) => xff!("fle.d", rd, rs1, rs2),
+            LtD(rd, rs1, rs2) => xff!("flt.d", rd, rs1, rs2),
+
+            ClassD(rd, rs1) => write!(fmt, "fclass.d {}, {}", x(rd), f(rs1)),
+            CvtDW(rd, rs1) => write!(fmt, "fcvt.d.w {}, {}", f(rd), x(rs1)),
+            CvtDWu(rd, rs1) => write!(fmt, "fcvt.d.wu {}, {}", f(rd), x(rs1)),
+            CvtWD(rd, rs1, rm) => write!(fmt, "fcvt.w.d {}, {}{}", x(rd), f(rs1), rounding(rm)),
+            CvtWuD(rd, rs1, rm) => write!(fmt, "fcvt.wu.d {}, {}{}", x(rd), f(rs1), rounding(rm)),
+            CvtSD(rd, rs1, rm) => write!(fmt, "fcvt.s.d {}, {}{}", f(rd), f(rs1), rounding(rm)),
+            CvtDS(rd, rs1) => write!(fmt, "fcvt.d.s {}, {}", f(rd), f(rs1)),
+            SqrtD(rd, rs1, rm) => write!(fmt, "fsqrt.d {}, {}{}", f(rd), f(rs1), rounding(rm)),
+
+            MaddD(rd, rs1, rs2, rs3, rm) => ffff!("fmadd.d", rd, rs1, rs2, rs3, rm),
+            MsubD(rd, rs1, rs2, rs3, rm) => ffff!("fmsub.d", rd, rs1, rs2, rs3, rm),
+            NMsubD(rd, rs1, rs2, rs3, rm) => ffff!("fnmsub.d", rd, rs1, rs2, rs3, rm),
+            NMaddD(rd, rs1, rs2, rs3, rm) => ffff!("fnmadd.d", rd, rs1, rs2, rs3, rm),
+
+            Ld(rd, imm, rs1) => write!(fmt, "fld {}, {}({})", f(rd), signed(imm), x(rs1)),
+            Sd(rs2, imm, rs1) => write!(fmt, "fsd {}, {}({})", f(rs2), signed(imm), x(rs1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Instruction::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Addi(5, 5, (-4i32) as u32).to_string(), "addi t0, t0, -4");
+        assert_eq!(Lw(10, 8, 2).to_string(), "lw a0, 8(sp)");
+        assert_eq!(Bne(5, 0, 0x40).to_string(), "bne t0, zero, 0x40");
+        assert_eq!(Li(11, 0x1001_0000).to_string(), "li a1, 268500992");
+        assert_eq!(Mv(0, 0).to_string(), "nop");
+        assert_eq!(Fence(0xF, 0b0001).to_string(), "fence iorw, w");
+        assert_eq!(CsrRs(5, 4, 0).to_string(), "csrrs t0, utvec, zero");
+        assert_eq!(LrW(5, 10).to_string(), "lr.w t0, (a0)");
+        assert_eq!(AmoOrW(0, 6, 10).to_string(), "amoor.w zero, t1, (a0)");
+        assert_eq!(Bexti(10, 10, 3).to_string(), "bexti a0, a0, 3");
+        assert_eq!(Instruction::from(FloatInstruction::Le(10, 1, 2)).to_string(), "fle.s a0, ft1, ft2");
+        assert_eq!(Instruction::from(FloatInstruction::Sw(8, 4, 2)).to_string(), "fsw fs0, 4(sp)");
+        assert_eq!(Instruction::from(FloatInstruction::CvtSD(0, 1, 7)).to_string(), "fcvt.s.d ft0, ft1");
+        assert_eq!(Instruction::from(FloatInstruction::CvtWS(5, 1, 1)).to_string(), "fcvt.w.s t0, ft1, rtz");
+        assert_eq!(
+            Instruction::from(FloatInstruction::NMaddD(10, 11, 12, 13, 7)).to_string(),
+            "fnmadd.d fa0, fa1, fa2, fa3"
+        );
+        assert_eq!(Instruction::from(Rv64Instruction::Addiw(5, 5, 1)).to_string(), "addiw t0, t0, 1");
+        assert_eq!(Instruction::from(Rv64Instruction::Sd(1, (-8i32) as u32, 2)).to_string(), "sd ra, -8(sp)");
+        assert_eq!(
+            Instruction::from(VectorInstruction::Setvli(5, 10, 0xd0)).to_string(),
+            "vsetvli t0, a0, e32, m1, ta, ma"
+        );
+        assert_eq!(Instruction::from(VectorInstruction::Le32(1, 10)).to_string(), "vle32.v v1, (a0)");
+        assert_eq!(Instruction::from(VectorInstruction::RedSum(1, 2, 3)).to_string(), "vredsum.vs v1, v2, v3");
+    }
+}
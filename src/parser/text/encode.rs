@@ -0,0 +1,471 @@
+//!
+//! Turns [Instructions](../../enum.Instruction.html) back into the machine code a real processor
+//! would run, the opposite of [decode](../decode/index.html). Some of ours have no encoding of their
+//! own, like a `li` with a constant that doesn't fit in 12 bits, which would take two instructions.
+//!
+
+use super::super::{
+    register_names::STATUS_CSRS, FloatInstruction, Instruction, Rv64Instruction, VectorInstruction,
+};
+
+/// Whether `x` fits in a signed immediate of `bits` bits
+fn fits(x: u32, bits: u32) -> bool {
+    let shift = 32 - bits;
+    (((x << shift) as i32) >> shift) as u32 == x
+}
+
+/// Whether a `jal` at `pc` reaches `target`, which takes it to be less than 1 MiB away. A `call` or
+/// `tail` further than that becomes an `auipc` and a `jalr`.
+pub(in super::super) fn jal_reaches(pc: usize, target: usize) -> bool {
+    fits((target as u32).wrapping_sub(pc as u32), 21)
+}
+
+fn r_type(opcode: u32, funct3: u32, funct7: u32, rd: u8, rs1: u8, rs2: u8) -> u32 {
+    funct7 << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | opcode
+}
+
+fn i_type(opcode: u32, funct3: u32, rd: u8, rs1: u8, imm: u32) -> Option<u32> {
+    if !fits(imm, 12) {
+        return None;
+    }
+    Some((imm & 0xFFF) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | opcode)
+}
+
+fn shift(funct3: u32, funct7: u32, rd: u8, rs1: u8, shamt: u32) -> Option<u32> {
+    if shamt >= 32 {
+        return None;
+    }
+    Some(r_type(0x13, funct3, funct7, rd, rs1, shamt as u8))
+}
+
+fn s_type(opcode: u32, funct3: u32, rs2: u8, imm: u32, rs1: u8) -> Option<u32> {
+    if !fits(imm, 12) {
+        return None;
+    }
+    let (hi, lo) = (imm >> 5 & 0x7F, imm & 0x1F);
+    Some(hi << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 | lo << 7 | opcode)
+}
+
+fn b_type(funct3: u32, rs1: u8, rs2: u8, offset: u32) -> Option<u32> {
+    if !fits(offset, 13) || offset & 1 != 0 {
+        return None;
+    }
+    let bit = |i: u32, len: u32| offset >> i & ((1 << len) - 1);
+    let imm_hi = bit(12, 1) << 6 | bit(5, 6);
+    let imm_lo = bit(1, 4) << 1 | bit(11, 1);
+    Some(imm_hi << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 | imm_lo << 7 | 0x63)
+}
+
+fn j_type(rd: u8, offset: u32) -> Option<u32> {
+    if !fits(offset, 21) || offset & 1 != 0 {
+        return None;
+    }
+    let bit = |i: u32, len: u32| offset >> i & ((1 << len) - 1);
+    let imm = bit(20, 1) << 19 | bit(1, 10) << 9 | bit(11, 1) << 8 | bit(12, 8);
+    Some(imm << 12 | (rd as u32) << 7 | 0x6f)
+}
+
+fn csr(funct3: u32, rd: u8, csr: u8, rs1: u8) -> Option<u32> {
+    let number = *STATUS_CSRS.get(csr as usize)?;
+    Some(number << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | 0x73)
+}
+
+/// Encodes `instruction`, which is at the address `pc`. Branches and jumps are relative to it.
+/// Returns `None` if it doesn't fit in a single RV32 instruction.
+pub(in super::super) fn encode(instruction: &Instruction, pc: usize) -> Option<u32> {
+    use Instruction::*;
+
+    let offset = |target: usize| (target as u32).wrapping_sub(pc as u32);
+
+    let word = match *instruction {
+        Add(rd, rs1, rs2) => r_type(0x33, 0, 0x00, rd, rs1, rs2),
+        Sub(rd, rs1, rs2) => r_type(0x33, 0, 0x20, rd, rs1, rs2),
+        Sll(rd, rs1, rs2) => r_type(0x33, 1, 0x00, rd, rs1, rs2),
+        Slt(rd, rs1, rs2) => r_type(0x33, 2, 0x00, rd, rs1, rs2),
+        Sltu(rd, rs1, rs2) => r_type(0x33, 3, 0x00, rd, rs1, rs2),
+        Xor(rd, rs1, rs2) => r_type(0x33, 4, 0x00, rd, rs1, rs2),
+        Srl(rd, rs1, rs2) => r_type(0x33, 5, 0x00, rd, rs1, rs2),
+        Sra(rd, rs1, rs2) => r_type(0x33, 5, 0x20, rd, rs1, rs2),
+        Or(rd, rs1, rs2) => r_type(0x33, 6, 0x00, rd, rs1, rs2),
+        And(rd, rs1, rs2) => r_type(0x33, 7, 0x00, rd, rs1, rs2),
+        Mul(rd, rs1, rs2) => r_type(0x33, 0, 0x01, rd, rs1, rs2),
+        Mulh(rd, rs1, rs2) => r_type(0x33, 1, 0x01, rd, rs1, rs2),
+        Mulhsu(rd, rs1, rs2) => r_type(0x33, 2, 0x01, rd, rs1, rs2),
+        Mulhu(rd, rs1, rs2) => r_type(0x33, 3, 0x01, rd, rs1, rs2),
+        Div(rd, rs1, rs2) => r_type(0x33, 4, 0x01, rd, rs1, rs2),
+        Divu(rd, rs1, rs2) => r_type(0x33, 5, 0x01, rd, rs1, rs2),
+        Rem(rd, rs1, rs2) => r_type(0x33, 6, 0x01, rd, rs1, rs2),
+        Remu(rd, rs1, rs2) => r_type(0x33, 7, 0x01, rd, rs1, rs2),
+        Sh1add(rd, rs1, rs2) => r_type(0x33, 2, 0x10, rd, rs1, rs2),
+        Sh2add(rd, rs1, rs2) => r_type(0x33, 4, 0x10, rd, rs1, rs2),
+        Sh3add(rd, rs1, rs2) => r_type(0x33, 6, 0x10, rd, rs1, rs2),
+        Bset(rd, rs1, rs2) => r_type(0x33, 1, 0x14, rd, rs1, rs2),
+        Bclr(rd, rs1, rs2) => r_type(0x33, 1, 0x24, rd, rs1, rs2),
+        Binv(rd, rs1, rs2) => r_type(0x33, 1, 0x34, rd, rs1, rs2),
+        Bext(rd, rs1, rs2) => r_type(0x33, 5, 0x24, rd, rs1, rs2),
+        CzeroEqz(rd, rs1, rs2) => r_type(0x33, 5, 0x07, rd, rs1, rs2),
+        CzeroNez(rd, rs1, rs2) => r_type(0x33, 7, 0x07, rd, rs1, rs2),
+        Bseti(rd, rs1, imm) => shift(1, 0x14, rd, rs1, imm)?,
+        Bclri(rd, rs1, imm) => shift(1, 0x24, rd, rs1, imm)?,
+        Binvi(rd, rs1, imm) => shift(1, 0x34, rd, rs1, imm)?,
+        Bexti(rd, rs1, imm) => shift(5, 0x24, rd, rs1, imm)?,
+
+        Ecall => 0x0000_0073,
+        Ebreak => 0x0010_0073,
+        Fence(pred, succ) => (pred as u32) << 24 | (succ as u32) << 20 | 0x0F,
+        FenceI => 0x0000_100F,
+        // The atomics are encoded without the `aq` and `rl` bits, which are the lowest of funct7
+        LrW(rd, rs1) => r_type(0x2f, 2, 0x02 << 2, rd, rs1, 0),
+        ScW(rd, rs2, rs1) => r_type(0x2f, 2, 0x03 << 2, rd, rs1, rs2),
+        AmoSwapW(rd, rs2, rs1) => r_type(0x2f, 2, 0x01 << 2, rd, rs1, rs2),
+        AmoAddW(rd, rs2, rs1) => r_type(0x2f, 2, 0x00, rd, rs1, rs2),
+        AmoXorW(rd, rs2, rs1) => r_type(0x2f, 2, 0x04 << 2, rd, rs1, rs2),
+        AmoAndW(rd, rs2, rs1) => r_type(0x2f, 2, 0x0c << 2, rd, rs1, rs2),
+        AmoOrW(rd, rs2, rs1) => r_type(0x2f, 2, 0x08 << 2, rd, rs1, rs2),
+        AmoMinW(rd, rs2, rs1) => r_type(0x2f, 2, 0x10 << 2, rd, rs1, rs2),
+        AmoMaxW(rd, rs2, rs1) => r_type(0x2f, 2, 0x14 << 2, rd, rs1, rs2),
+        AmoMinuW(rd, rs2, rs1) => r_type(0x2f, 2, 0x18 << 2, rd, rs1, rs2),
+        AmoMaxuW(rd, rs2, rs1) => r_type(0x2f, 2, 0x1c << 2, rd, rs1, rs2),
+        Lb(rd, imm, rs1) => i_type(0x03, 0, rd, rs1, imm)?,
+        Lh(rd, imm, rs1) => i_type(0x03, 1, rd, rs1, imm)?,
+        Lw(rd, imm, rs1) => i_type(0x03, 2, rd, rs1, imm)?,
+        Lbu(rd, imm, rs1) => i_type(0x03, 4, rd, rs1, imm)?,
+        Lhu(rd, imm, rs1) => i_type(0x03, 5, rd, rs1, imm)?,
+        Addi(rd, rs1, imm) => i_type(0x13, 0, rd, rs1, imm)?,
+        Slti(rd, rs1, imm) => i_type(0x13, 2, rd, rs1, imm)?,
+        Sltiu(rd, rs1, imm) => i_type(0x13, 3, rd, rs1, imm)?,
+        Xori(rd, rs1, imm) => i_type(0x13, 4, rd, rs1, imm)?,
+        Ori(rd, rs1, imm) => i_type(0x13, 6, rd, rs1, imm)?,
+        Andi(rd, rs1, imm) => i_type(0x13, 7, rd, rs1, imm)?,
+        Slli(rd, rs1, imm) => shift(1, 0x00, rd, rs1, imm)?,
+        Srli(rd, rs1, imm) => shift(5, 0x00, rd, rs1, imm)?,
+        Srai(rd, rs1, imm) => shift(5, 0x20, rd, rs1, imm)?,
+
+        Sb(rs2, imm, rs1) => s_type(0x23, 0, rs2, imm, rs1)?,
+        Sh(rs2, imm, rs1) => s_type(0x23, 1, rs2, imm, rs1)?,
+        Sw(rs2, imm, rs1) => s_type(0x23, 2, rs2, imm, rs1)?,
+
+        Beq(rs1, rs2, target) => b_type(0, rs1, rs2, offset(target))?,
+        Bne(rs1, rs2, target) => b_type(1, rs1, rs2, offset(target))?,
+        Blt(rs1, rs2, target) => b_type(4, rs1, rs2, offset(target))?,
+        Bge(rs1, rs2, target) => b_type(5, rs1, rs2, offset(target))?,
+        Bltu(rs1, rs2, target) => b_type(6, rs1, rs2, offset(target))?,
+        Bgeu(rs1, rs2, target) => b_type(7, rs1, rs2, offset(target))?,
+        Jalr(rd, rs1, imm) => i_type(0x67, 0, rd, rs1, imm)?,
+        Jal(rd, target) => j_type(rd, offset(target))?,
+
+        Auipc(rd, imm) => (imm & 0xFFFF_F000) | (rd as u32) << 7 | 0x17,
+
+        CsrRw(rd, fcsr, rs1) => csr(1, rd, fcsr, rs1)?,
+        CsrRs(rd, fcsr, rs1) => csr(2, rd, fcsr, rs1)?,
+        CsrRc(rd, fcsr, rs1) => csr(3, rd, fcsr, rs1)?,
+        CsrRwi(rd, fcsr, imm) if imm < 32 => csr(5, rd, fcsr, imm as u8)?,
+        CsrRsi(rd, fcsr, imm) if imm < 32 => csr(6, rd, fcsr, imm as u8)?,
+        CsrRci(rd, fcsr, imm) if imm < 32 => csr(7, rd, fcsr, imm as u8)?,
+        CsrRwi(..) | CsrRsi(..) | CsrRci(..) => return None,
+
+        Float(ref f) => encode_float(f)?,
+        Rv64(ref r) => encode_rv64(r)?,
+        Vector(ref v) => encode_vector(v)?,
+
+        // A small `li` is an `addi`, and `lui` is a `Li` with the lower bits clear
+        Li(rd, imm) if fits(imm, 12) => i_type(0x13, 0, rd, 0, imm)?,
+        Li(rd, imm) if imm & 0xFFF == 0 => imm | (rd as u32) << 7 | 0x37,
+        Li(..) => return None,
+        Mv(rd, rs1) => i_type(0x13, 0, rd, rs1, 0)?,
+        Ret => 0x0000_8067,
+        URet => 0x0020_0073,
+        MRet => 0x3020_0073,
+        Wfi => 0x1050_0073,
+
+        // Either a `.word` we couldn't decode, which is its own encoding, or a line we didn't know
+        Illegal(ref line) => {
+            let hex = line.strip_prefix(".word 0x")?;
+            u32::from_str_radix(hex, 16).ok()?
+        }
+    };
+
+    Some(word)
+}
+
+fn encode_rv64(instruction: &Rv64Instruction) -> Option<u32> {
+    use Rv64Instruction::*;
+
+    // The shift amount has 6 bits, and the highest one goes where funct7 would start
+    let shift = |opcode, funct3, funct7, rd, rs1, shamt: u32| match shamt {
+        0..=63 => Some(r_type(opcode, funct3, funct7 | shamt >> 5, rd, rs1, (shamt & 0x1f) as u8)),
+        _ => None,
+    };
+    let word_shift = |funct3, funct7, rd, rs1, shamt| match shamt {
+        0..=31 => shift(0x1b, funct3, funct7, rd, rs1, shamt),
+        _ => None,
+    };
+
+    let word = match *instruction {
+        Add(rd, rs1, rs2) => r_type(0x33, 0, 0x00, rd, rs1, rs2),
+        Sub(rd, rs1, rs2) => r_type(0x33, 0, 0x20, rd, rs1, rs2),
+        Sll(rd, rs1, rs2) => r_type(0x33, 1, 0x00, rd, rs1, rs2),
+        Srl(rd, rs1, rs2) => r_type(0x33, 5, 0x00, rd, rs1, rs2),
+        Sra(rd, rs1, rs2) => r_type(0x33, 5, 0x20, rd, rs1, rs2),
+        Mul(rd, rs1, rs2) => r_type(0x33, 0, 0x01, rd, rs1, rs2),
+        Mulh(rd, rs1, rs2) => r_type(0x33, 1, 0x01, rd, rs1, rs2),
+        Mulhsu(rd, rs1, rs2) => r_type(0x33, 2, 0x01, rd, rs1, rs2),
+        Mulhu(rd, rs1, rs2) => r_type(0x33, 3, 0x01, rd, rs1, rs2),
+        Div(rd, rs1, rs2) => r_type(0x33, 4, 0x01, rd, rs1, rs2),
+        Divu(rd, rs1, rs2) => r_type(0x33, 5, 0x01, rd, rs1, rs2),
+        Rem(rd, rs1, rs2) => r_type(0x33, 6, 0x01, rd, rs1, rs2),
+        Remu(rd, rs1, rs2) => r_type(0x33, 7, 0x01, rd, rs1, rs2),
+        Sh1add(rd, rs1, rs2) => r_type(0x33, 2, 0x10, rd, rs1, rs2),
+        Sh2add(rd, rs1, rs2) => r_type(0x33, 4, 0x10, rd, rs1, rs2),
+        Sh3add(rd, rs1, rs2) => r_type(0x33, 6, 0x10, rd, rs1, rs2),
+        Bset(rd, rs1, rs2) => r_type(0x33, 1, 0x14, rd, rs1, rs2),
+        Bclr(rd, rs1, rs2) => r_type(0x33, 1, 0x24, rd, rs1, rs2),
+        Binv(rd, rs1, rs2) => r_type(0x33, 1, 0x34, rd, rs1, rs2),
+        Bext(rd, rs1, rs2) => r_type(0x33, 5, 0x24, rd, rs1, rs2),
+        Addi(rd, rs1, imm) => i_type(0x13, 0, rd, rs1, imm)?,
+        Slli(rd, rs1, imm) => shift(0x13, 1, 0x00, rd, rs1, imm)?,
+        Srli(rd, rs1, imm) => shift(0x13, 5, 0x00, rd, rs1, imm)?,
+        Srai(rd, rs1, imm) => shift(0x13, 5, 0x20, rd, rs1, imm)?,
+        Bseti(rd, rs1, imm) => shift(0x13, 1, 0x14, rd, rs1, imm)?,
+        Bclri(rd, rs1, imm) => shift(0x13, 1, 0x24, rd, rs1, imm)?,
+        Binvi(rd, rs1, imm) => shift(0x13, 1, 0x34, rd, rs1, imm)?,
+        Bexti(rd, rs1, imm) => shift(0x13, 5, 0x24, rd, rs1, imm)?,
+
+        Addw(rd, rs1, rs2) => r_type(0x3b, 0, 0x00, rd, rs1, rs2),
+        Subw(rd, rs1, rs2) => r_type(0x3b, 0, 0x20, rd, rs1, rs2),
+        Sllw(rd, rs1, rs2) => r_type(0x3b, 1, 0x00, rd, rs1, rs2),
+        Srlw(rd, rs1, rs2) => r_type(0x3b, 5, 0x00, rd, rs1, rs2),
+        Sraw(rd, rs1, rs2) => r_type(0x3b, 5, 0x20, rd, rs1, rs2),
+        Mulw(rd, rs1, rs2) => r_type(0x3b, 0, 0x01, rd, rs1, rs2),
+        Divw(rd, rs1, rs2) => r_type(0x3b, 4, 0x01, rd, rs1, rs2),
+        Divuw(rd, rs1, rs2) => r_type(0x3b, 5, 0x01, rd, rs1, rs2),
+        Remw(rd, rs1, rs2) => r_type(0x3b, 6, 0x01, rd, rs1, rs2),
+        Remuw(rd, rs1, rs2) => r_type(0x3b, 7, 0x01, rd, rs1, rs2),
+        Addiw(rd, rs1, imm) => i_type(0x1b, 0, rd, rs1, imm)?,
+        Slliw(rd, rs1, imm) => word_shift(1, 0x00, rd, rs1, imm)?,
+        Srliw(rd, rs1, imm) => word_shift(5, 0x00, rd, rs1, imm)?,
+        Sraiw(rd, rs1, imm) => word_shift(5, 0x20, rd, rs1, imm)?,
+
+        Ld(rd, imm, rs1) => i_type(0x03, 3, rd, rs1, imm)?,
+        Lwu(rd, imm, rs1) => i_type(0x03, 6, rd, rs1, imm)?,
+        Sd(rs2, imm, rs1) => s_type(0x23, 3, rs2, imm, rs1)?,
+    };
+
+    Some(word)
+}
+
+fn encode_vector(instruction: &VectorInstruction) -> Option<u32> {
+    use VectorInstruction::*;
+
+    // funct7 is funct6 and then `vm`, which is 1 since nothing is masked
+    let op = |funct6: u32, funct3, vd, vs1, vs2| r_type(0x57, funct3, funct6 << 1 | 1, vd, vs1, vs2);
+    const OPIVV: u32 = 0;
+    const OPMVV: u32 = 2;
+    const OPMVX: u32 = 6;
+
+    let word = match *instruction {
+        Setvli(rd, rs1, vtype) if vtype < 0x800 => {
+            vtype << 20 | (rs1 as u32) << 15 | 7 << 12 | (rd as u32) << 7 | 0x57
+        }
+        Setvli(..) => return None,
+        // The width of 32 bits is 0b110, in the opcodes of the float loads and stores
+        Le32(vd, rs1) => r_type(0x07, 6, 1, vd, rs1, 0),
+        Se32(vs3, rs1) => r_type(0x27, 6, 1, vs3, rs1, 0),
+        Add(vd, vs2, vs1) => op(0x00, OPIVV, vd, vs1, vs2),
+        Mul(vd, vs2, vs1) => op(0x25, OPMVV, vd, vs1, vs2),
+        RedSum(vd, vs2, vs1) => op(0x00, OPMVV, vd, vs1, vs2),
+        RedMinu(vd, vs2, vs1) => op(0x04, OPMVV, vd, vs1, vs2),
+        RedMin(vd, vs2, vs1) => op(0x05, OPMVV, vd, vs1, vs2),
+        RedMaxu(vd, vs2, vs1) => op(0x06, OPMVV, vd, vs1, vs2),
+        RedMax(vd, vs2, vs1) => op(0x07, OPMVV, vd, vs1, vs2),
+        MvXS(rd, vs2) => op(0x10, OPMVV, rd, 0, vs2),
+        MvSX(vd, rs1) => op(0x10, OPMVX, vd, rs1, 0),
+    };
+
+    Some(word)
+}
+
+fn encode_float(instruction: &FloatInstruction) -> Option<u32> {
+    use FloatInstruction::*;
+
+    // The conversions that are always exact still have a rounding mode, which is 0b111, the
+    // dynamic one, like GNU as does
+    const DYN: u32 = 7;
+    let op = |funct7, funct3, rd, rs1, rs2| r_type(0x53, funct3, funct7, rd, rs1, rs2);
+    // The fused ones have their own opcodes, and rs3 and the format where funct7 would be
+    let r4 = |opcode, fmt, rd, rs1, rs2, rs3: u8, rm: u8| {
+        r_type(opcode, rm as u32, (rs3 as u32) << 2 | fmt, rd, rs1, rs2)
+    };
+
+    let word = match *instruction {
+        Add(rd, rs1, rs2, rm) => op(0x00, rm as u32, rd, rs1, rs2),
+        Sub(rd, rs1, rs2, rm) => op(0x04, rm as u32, rd, rs1, rs2),
+        Mul(rd, rs1, rs2, rm) => op(0x08, rm as u32, rd, rs1, rs2),
+        Div(rd, rs1, rs2, rm) => op(0x0c, rm as u32, rd, rs1, rs2),
+        Sqrt(rd, rs1, rm) => op(0x2c, rm as u32, rd, rs1, 0),
+        SgnjS(rd, rs1, rs2) => op(0x10, 0, rd, rs1, rs2),
+        SgnjNS(rd, rs1, rs2) => op(0x10, 1, rd, rs1, rs2),
+        SgnjXS(rd, rs1, rs2) => op(0x10, 2, rd, rs1, rs2),
+        Min(rd, rs1, rs2) => op(0x14, 0, rd, rs1, rs2),
+        Max(rd, rs1, rs2) => op(0x14, 1, rd, rs1, rs2),
+        Le(rd, rs1, rs2) => op(0x50, 0, rd, rs1, rs2),
+        Lt(rd, rs1, rs2) => op(0x50, 1, rd, rs1, rs2),
+        Equ(rd, rs1, rs2) => op(0x50, 2, rd, rs1, rs2),
+        CvtWS(rd, rs1, rm) => op(0x60, rm as u32, rd, rs1, 0),
+        CvtWuS(rd, rs1, rm) => op(0x60, rm as u32, rd, rs1, 1),
+        CvtSW(rd, rs1, rm) => op(0x68, rm as u32, rd, rs1, 0),
+        CvtSWu(rd, rs1, rm) => op(0x68, rm as u32, rd, rs1, 1),
+        MvXS(rd, rs1) => op(0x70, 0, rd, rs1, 0),
+        Class(rd, rs1) => op(0x70, 1, rd, rs1, 0),
+        MvSX(rd, rs1) => op(0x78, 0, rd, rs1, 0),
+        Madd(rd, rs1, rs2, rs3, rm) => r4(0x43, 0, rd, rs1, rs2, rs3, rm),
+        Msub(rd, rs1, rs2, rs3, rm) => r4(0x47, 0, rd, rs1, rs2, rs3, rm),
+        NMsub(rd, rs1, rs2, rs3, rm) => r4(0x4b, 0, rd, rs1, rs2, rs3, rm),
+        NMadd(rd, rs1, rs2, rs3, rm) => r4(0x4f, 0, rd, rs1, rs2, rs3, rm),
+        Lw(rd, imm, rs1) => i_type(0x07, 2, rd, rs1, imm)?,
+        Sw(rs2, imm, rs1) => s_type(0x27, 2, rs2, imm, rs1)?,
+
+        // The double precision versions have a 1 in the lowest bit of funct7
+        AddD(rd, rs1, rs2, rm) => op(0x01, rm as u32, rd, rs1, rs2),
+        SubD(rd, rs1, rs2, rm) => op(0x05, rm as u32, rd, rs1, rs2),
+        MulD(rd, rs1, rs2, rm) => op(0x09, rm as u32, rd, rs1, rs2),
+        DivD(rd, rs1, rs2, rm) => op(0x0d, rm as u32, rd, rs1, rs2),
+        SqrtD(rd, rs1, rm) => op(0x2d, rm as u32, rd, rs1, 0),
+        SgnjD(rd, rs1, rs2) => op(0x11, 0, rd, rs1, rs2),
+        SgnjND(rd, rs1, rs2) => op(0x11, 1, rd, rs1, rs2),
+        SgnjXD(rd, rs1, rs2) => op(0x11, 2, rd, rs1, rs2),
+        MinD(rd, rs1, rs2) => op(0x15, 0, rd, rs1, rs2),
+        MaxD(rd, rs1, rs2) => op(0x15, 1, rd, rs1, rs2),
+        LeD(rd, rs1, rs2) => op(0x51, 0, rd, rs1, rs2),
+        LtD(rd, rs1, rs2) => op(0x51, 1, rd, rs1, rs2),
+        EquD(rd, rs1, rs2) => op(0x51, 2, rd, rs1, rs2),
+        CvtSD(rd, rs1, rm) => op(0x20, rm as u32, rd, rs1, 1),
+        CvtDS(rd, rs1) => op(0x21, DYN, rd, rs1, 0),
+        CvtWD(rd, rs1, rm) => op(0x61, rm as u32, rd, rs1, 0),
+        CvtWuD(rd, rs1, rm) => op(0x61, rm as u32, rd, rs1, 1),
+        CvtDW(rd, rs1) => op(0x69, DYN, rd, rs1, 0),
+        CvtDWu(rd, rs1) => op(0x69, DYN, rd, rs1, 1),
+        ClassD(rd, rs1) => op(0x71, 1, rd, rs1, 0),
+        MaddD(rd, rs1, rs2, rs3, rm) => r4(0x43, 1, rd, rs1, rs2, rs3, rm),
+        MsubD(rd, rs1, rs2, rs3, rm) => r4(0x47, 1, rd, rs1, rs2, rs3, rm),
+        NMsubD(rd, rs1, rs2, rs3, rm) => r4(0x4b, 1, rd, rs1, rs2, rs3, rm),
+        NMaddD(rd, rs1, rs2, rs3, rm) => r4(0x4f, 1, rd, rs1, rs2, rs3, rm),
+        Ld(rd, imm, rs1) => i_type(0x07, 3, rd, rs1, imm)?,
+        Sd(rs2, imm, rs1) => s_type(0x27, 3, rs2, imm, rs1)?,
+    };
+
+    Some(word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::decode;
+    use super::*;
+    use crate::parser::register_names::status;
+    use Instruction::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(&Addi(0, 0, 0), 0), Some(0x0000_0013));
+        assert_eq!(encode(&Li(5, 0x1234_5000), 0), Some(0x1234_52b7));
+        assert_eq!(encode(&Li(5, 0x1234_5678), 0), None);
+        assert_eq!(encode(&Li(10, (-1i32) as u32), 0), Some(0xfff0_0513));
+        assert_eq!(encode(&Jal(1, 0x10), 0x20), Some(0xff1f_f0ef));
+        assert_eq!(encode(&Jal(0, 0x20_0000), 0), None);
+        assert!(jal_reaches(0x10_0000, 0x1f_fffe) && jal_reaches(0x10_0000, 0));
+        assert!(!jal_reaches(0x10_0000, 0x20_0000) && !jal_reaches(0x10_0004, 0));
+        assert_eq!(encode(&Lw(5, 4096, 2), 0), None);
+        assert_eq!(encode(&Illegal(".word 0xdeadbeef".to_owned()), 0), Some(0xdead_beef));
+        assert_eq!(encode(&Illegal("vadd.vv v1, v2, v3".to_owned()), 0), None);
+        assert_eq!(encode(&FloatInstruction::Madd(0, 1, 2, 3, 7).into(), 0), Some(0x1820_f043));
+        assert_eq!(encode(&AmoAddW(5, 6, 10), 0), Some(0x0065_22af));
+        assert_eq!(encode(&LrW(5, 10), 0), Some(0x1005_22af));
+        assert_eq!(encode(&MRet, 0), Some(0x3020_0073));
+        assert_eq!(encode(&VectorInstruction::Setvli(5, 10, 0xd0).into(), 0), Some(0x0d05_72d7));
+        assert_eq!(encode(&VectorInstruction::Le32(1, 10).into(), 0), Some(0x0205_6087));
+        assert_eq!(encode(&VectorInstruction::Mul(4, 5, 6).into(), 0), Some(0x9653_2257));
+        assert_eq!(encode(&VectorInstruction::RedMax(1, 2, 3).into(), 0), Some(0x1e21_a0d7));
+        assert_eq!(encode(&Rv64Instruction::Addiw(10, 10, 1).into(), 0), Some(0x0015_051b));
+        assert_eq!(encode(&Rv64Instruction::Slli(5, 5, 63).into(), 0), Some(0x03f2_9293));
+        assert_eq!(encode(&Rv64Instruction::Slli(5, 5, 64).into(), 0), None);
+        assert_eq!(encode(&Rv64Instruction::Sraiw(5, 5, 32).into(), 0), None);
+
+        // Whatever we encode has to decode back to the same instruction
+        let status = status();
+        let roundtrip = [
+            Sub(5, 6, 7),
+            Remu(31, 30, 29),
+            Mulh(1, 2, 3),
+            Mulhsu(4, 5, 6),
+            Mulhu(7, 8, 9),
+            Sh3add(10, 11, 12),
+            Bclr(1, 2, 3),
+            Bext(4, 5, 6),
+            CzeroEqz(7, 8, 9),
+            CzeroNez(10, 11, 12),
+            Binvi(7, 8, 31),
+            Bexti(9, 10, 0),
+            Addi(10, 10, (-2048i32) as u32),
+            Srai(5, 5, 31),
+            Lhu(1, 2047, 2),
+            Sw(8, (-4i32) as u32, 2),
+            Beq(5, 0, 0x1000 - 4096),
+            Bgeu(1, 2, 0x1000 + 4094),
+            Jal(0, 0x0010_0000 - 2),
+            Jalr(1, 5, 12),
+            Auipc(3, 0xFFFF_F000),
+            CsrRs(5, 2, 0),
+            CsrRwi(0, 4, 31),
+            Ecall,
+            Ebreak,
+            URet,
+            MRet,
+            Wfi,
+            Fence(0b0011, 0b0001),
+            FenceI,
+            LrW(1, 2),
+            ScW(3, 4, 5),
+            AmoSwapW(6, 7, 8),
+            AmoXorW(9, 10, 11),
+            AmoMinW(0, 12, 13),
+            AmoMaxuW(14, 15, 16),
+            FloatInstruction::Div(1, 2, 3, 7).into(),
+            FloatInstruction::CvtSWu(1, 2, 1).into(),
+            FloatInstruction::Class(10, 2).into(),
+            FloatInstruction::Sw(1, 8, 2).into(),
+            FloatInstruction::NMsub(1, 2, 3, 31, 7).into(),
+            FloatInstruction::MulD(4, 5, 6, 3).into(),
+            FloatInstruction::CvtSD(1, 2, 7).into(),
+            FloatInstruction::CvtDS(2, 1).into(),
+            FloatInstruction::CvtWuD(10, 3, 1).into(),
+            FloatInstruction::ClassD(10, 2).into(),
+            FloatInstruction::MaddD(4, 5, 6, 7, 0).into(),
+            FloatInstruction::Ld(8, (-8i32) as u32, 2).into(),
+            FloatInstruction::Sd(8, 16, 2).into(),
+            Rv64Instruction::Addw(1, 2, 3).into(),
+            Rv64Instruction::Sraw(4, 5, 6).into(),
+            Rv64Instruction::Remuw(7, 8, 9).into(),
+            Rv64Instruction::Addiw(10, 11, (-2048i32) as u32).into(),
+            Rv64Instruction::Sraiw(12, 13, 31).into(),
+            Rv64Instruction::Slli(5, 5, 40).into(),
+            Rv64Instruction::Srai(5, 5, 63).into(),
+            Rv64Instruction::Bseti(5, 5, 40).into(),
+            Rv64Instruction::Bexti(6, 7, 63).into(),
+            Rv64Instruction::Ld(1, (-8i32) as u32, 2).into(),
+            Rv64Instruction::Lwu(3, 4, 2).into(),
+            Rv64Instruction::Sd(1, 2040, 2).into(),
+            VectorInstruction::Setvli(0, 0, 0x57).into(),
+            VectorInstruction::Se32(2, 11).into(),
+            VectorInstruction::Add(1, 2, 3).into(),
+            VectorInstruction::RedSum(1, 2, 3).into(),
+            VectorInstruction::RedMinu(1, 2, 3).into(),
+            VectorInstruction::MvXS(10, 1).into(),
+            VectorInstruction::MvSX(1, 10).into(),
+        ];
+        for instruction in &roundtrip {
+            let pc = 0x1000;
+            let word = encode(instruction, pc).unwrap();
+            assert_eq!(decode(word, pc, &status).as_ref(), Some(instruction), "{:#010x}", word);
+        }
+    }
+}
@@ -1,327 +1,1322 @@
-use super::{
-    combinators::*,
-    register_names::{FullRegMap, RegMap},
-    util::Error,
-    FloatInstruction, Instruction, PreLabelInstruction,
-};
-
-/// Parses a line that produces many instructions at a time, like `lw a0 label`.
-pub(super) fn parse_multi_instruction(s: &str, regmaps: &FullRegMap) -> Option<Vec<PreLabelInstruction>> {
-    let (regs, _floats, _status) = regmaps;
-
-    use PreLabelInstruction as pre;
-    use Instruction::*;
-
-    let (s, instruction) = match one_arg(s) {
-        Ok((s, i)) => (s, i),
-        Err(_) => { return None; }
-    };
-
-    macro_rules! load {
-        ($inst:ident) => {
-            args_jal(s, &regs)
-                .map(|(rd, label)| vec![
-                    pre::La(rd, label),
-                    $inst(rd, 0, rd).into(),
-                ])
-                .ok()
-        }
-    }
-
-    match instruction {
-        "lb" => load!(Lb),
-        "lh" => load!(Lh),
-        "lw" => load!(Lw),
-        "lbu" => load!(Lbu),
-        "lhu" => load!(Lhu),
-        _ => None
-    }
-}
-
-/// Parses a line that produces a single instruction
-pub(super) fn parse_instruction(s: &str, regmaps: &FullRegMap) -> Result<PreLabelInstruction, Error> {
-    let (regs, floats, status) = regmaps;
-
-    use FloatInstruction as F;
-    use Instruction::*;
-    use PreLabelInstruction as pre;
-
-    let (s, instruction) = one_arg(s)?;
-
-    macro_rules! type_i {
-        ($inst:expr) => {
-            args_type_i(s, &regs).map(|(rd, rs1, imm)| $inst(rd, rs1, imm).into())?
-        };
-    }
-
-    macro_rules! type_r {
-        ($inst:expr) => {
-            args_type_r(s, &regs).map(|(rd, rs1, rs2)| $inst(rd, rs1, rs2).into())?
-        };
-        (float $inst:expr) => {
-            args_float_r(s, &floats).map(|(rd, rs1, rs2)| $inst(rd, rs1, rs2).into())?
-        };
-        (mixed $inst:expr) => {
-            args_float_r_mixed(s, &regs, &floats)
-                .map(|(rd, rs1, rs2)| $inst(rd, rs1, rs2).into())?
-        };
-    }
-
-    macro_rules! type_sb {
-        ($inst:expr) => {
-            args_type_sb(s, &regs).map(|(rs1, rs2, label)| $inst(rs1, rs2, label))?
-        };
-    }
-
-    // bgez, bnez, ...
-    macro_rules! type_sb_z {
-        ($inst:expr) => {
-            args_jal(s, &regs).map(|(rs1, label)| $inst(rs1, 0, label))?
-        };
-    }
-
-    // Reverses the order of rs1 and rs2 to convert, for example,
-    // `ble t0 t1 label` into `bge t1 t0 label`
-    macro_rules! type_sb_reversed {
-        ($inst:expr) => {
-            args_type_sb(s, &regs).map(|(rs1, rs2, label)| $inst(rs2, rs1, label))?
-        };
-    }
-
-    // blez, ...
-    macro_rules! type_sb_reversed_z {
-        ($inst:expr) => {
-            args_jal(s, &regs).map(|(rs1, label)| $inst(0, rs1, label))?
-        };
-    }
-
-    macro_rules! type_s {
-        ($inst:expr) => {
-            args_type_s(s, &regs).map(|(r1, imm, r2)| $inst(r1, imm, r2).into())?
-        };
-        (float $inst:expr) => {
-            args_type_s_mixed(s, &floats, &regs).map(|(r1, imm, r2)| $inst(r1, imm, r2).into())?
-        };
-    }
-
-    macro_rules! csr {
-        ($inst:expr) => {
-            args_csr(s, &regs, &status).map(|(rd, fcsr, rs1)| $inst(rd, fcsr, rs1).into())?
-        };
-    }
-
-    macro_rules! csr_imm {
-        ($inst:expr) => {
-            args_csr_imm(s, &regs, &status).map(|(rd, fcsr, imm)| $inst(rd, fcsr, imm).into())?
-        };
-    }
-
-    macro_rules! csr_small {
-        ($inst:expr) => {
-            args_csr_small(s, &regs, &status).map(|(rs1, fcsr)| $inst(0, fcsr, rs1).into())?
-        };
-    }
-
-    macro_rules! csr_small_imm {
-        ($inst:expr) => {
-            args_csr_small_imm(s, &status).map(|(fcsr, imm)| $inst(0, fcsr, imm).into())?
-        };
-    }
-
-    macro_rules! float_two_regs {
-        ($inst:expr, $rd_regmap:expr, $rs1_regmap:expr) => {
-            float_two_regs(s, $rd_regmap, $rs1_regmap).map(|(rd, rs1)| $inst(rd, rs1).into())?
-        };
-    }
-
-    let parsed = match instruction.to_lowercase().as_str() {
-        // Type R
-        "add" => type_r!(Add),
-        "sub" => type_r!(Sub),
-        "sll" => type_r!(Sll),
-        "slt" => type_r!(Slt),
-        "sltu" => type_r!(Sltu),
-        "xor" => type_r!(Xor),
-        "srl" => type_r!(Srl),
-        "sra" => type_r!(Sra),
-        "or" => type_r!(Or),
-        "and" => type_r!(And),
-        "mul" => type_r!(Mul),
-        "div" => type_r!(Div),
-        "divu" => type_r!(Divu),
-        "rem" => type_r!(Rem),
-        "remu" => type_r!(Remu),
-        "neg" => args_mv(s, &regs).map(|(rd, rs1)| Sub(rd, 0, rs1).into())?,
-        "not" => args_mv(s, &regs).map(|(rd, rs1)| Xori(rd, rs1, (-1i32) as u32).into())?,
-        "mv" => args_mv(s, &regs).map(|(rd, rs1)| Mv(rd, rs1).into())?,
-        "snez" => args_mv(s, &regs).map(|(rd, rs1)| Sltu(rd, 0, rs1).into())?,
-        "sltz" => args_mv(s, &regs).map(|(rd, rs1)| Slt(rd, rs1, 0).into())?,
-        "sgtz" => args_mv(s, &regs).map(|(rd, rs1)| Slt(rd, 0, rs1).into())?,
-
-        // Type I
-        "addi" => type_i!(Addi),
-        "slli" => type_i!(Slli),
-        "slti" => type_i!(Slti),
-        "sltiu" => type_i!(Sltiu),
-        "xori" => type_i!(Xori),
-        "srli" => type_i!(Srli),
-        "srai" => type_i!(Srai),
-        "ori" => type_i!(Ori),
-        "andi" => type_i!(Andi),
-        "jalr" => type_i!(Jalr),
-        "jr" => one_reg(&regs)(s).map(|(_i, rs1)| Jalr(0, rs1, 0).into())?,
-        "seqz" => args_mv(s, &regs).map(|(rd, rs1)| Sltiu(rd, rs1, 1).into())?,
-
-        // Type I, loads from memory
-        "lb" => type_s!(Lb),
-        "lh" => type_s!(Lh),
-        "lw" => type_s!(Lw),
-        "lbu" => type_s!(Lbu),
-        "lhu" => type_s!(Lhu),
-
-        // Type S
-        "sb" => type_s!(Sb),
-        "sh" => type_s!(Sh),
-        "sw" => type_s!(Sw),
-
-        // Type SB and pseudoinstructions that map to SBs
-        "beq" => type_sb!(pre::Beq),
-        "bne" => type_sb!(pre::Bne),
-        "blt" => type_sb!(pre::Blt),
-        "bge" => type_sb!(pre::Bge),
-        "bltu" => type_sb!(pre::Bltu),
-        "bgeu" => type_sb!(pre::Bgeu),
-        "bgt" => type_sb_reversed!(pre::Blt),
-        "ble" => type_sb_reversed!(pre::Bge),
-        "bgtu" => type_sb_reversed!(pre::Bltu),
-        "bleu" => type_sb_reversed!(pre::Bgeu),
-        "beqz" => type_sb_z!(pre::Beq),
-        "bnez" => type_sb_z!(pre::Bne),
-        "bltz" => type_sb_z!(pre::Blt),
-        "bgez" => type_sb_z!(pre::Bge),
-        "bltuz" => type_sb_z!(pre::Bltu),
-        "bgeuz" => type_sb_z!(pre::Bgeu),
-        "bgtz" => type_sb_reversed_z!(pre::Blt),
-        "blez" => type_sb_reversed_z!(pre::Bge),
-
-        // CSR
-        "csrw" => csr_small!(CsrRw),
-        "csrc" => csr_small!(CsrRc),
-        "csrs" => csr_small!(CsrRs),
-        "csrwi" => csr_small_imm!(CsrRwi),
-        "csrci" => csr_small_imm!(CsrRci),
-        "csrsi" => csr_small_imm!(CsrRsi),
-        "csrrs" => csr!(CsrRs),
-        "csrrw" => csr!(CsrRw),
-        "csrrc" => csr!(CsrRc),
-        "csrrsi" => csr_imm!(CsrRsi),
-        "csrrwi" => csr_imm!(CsrRwi),
-        "csrrci" => csr_imm!(CsrRci),
-        "csrr" => args_csr_small(s, &regs, &status).map(|(rd, fcsr)| CsrRs(rd, fcsr, 0).into())?,
-
-        // Jumps
-        "jal" => parse_jal(s, &regs)?,
-        "call" => one_arg(s).map(|(_i, label)| pre::Jal(1, label.to_owned()))?,
-        "j" | "tail" | "b" => one_arg(s).map(|(_i, label)| pre::Jal(0, label.to_owned()))?,
-        "ret" => Ret.into(),
-
-        "ecall" => Ecall.into(),
-
-        // not quite a `jal`, but the same arguments
-        "la" => args_jal(s, &regs).map(|(rd, label)| pre::La(rd, label.to_owned()))?,
-
-        "li" => args_li(s, &regs).map(|(rd, imm)| Li(rd, imm).into())?,
-        "lui" => args_li(s, &regs).map(|(rd, imm)| Li(rd, imm << 12).into())?,
-
-        "nop" => Mv(0, 0).into(),
-
-        "fadd.s" => type_r!(float F::Add),
-        "fsub.s" => type_r!(float F::Sub),
-        "fmul.s" => type_r!(float F::Mul),
-        "fdiv.s" => type_r!(float F::Div),
-        "feq.s" => type_r!(mixed F::Equ),
-        "fle.s" => type_r!(mixed F::Le),
-        "flt.s" => type_r!(mixed F::Lt),
-        "fmax.s" => type_r!(float F::Max),
-        "fmin.s" => type_r!(float F::Min),
-        "fsgnj.s" => type_r!(float F::SgnjS),
-        "fsgnjn.s" => type_r!(float F::SgnjNS),
-        "fsgnjx.s" => type_r!(float F::SgnjXS),
-        "fclass.s" => float_two_regs!(F::Class, &regs, &floats),
-        "fcvt.s.w" => float_two_regs!(F::CvtSW, &floats, &regs),
-        "fcvt.s.wu" => float_two_regs!(F::CvtSWu, &floats, &regs),
-        "fcvt.w.s" => float_two_regs!(F::CvtWS, &regs, &floats),
-        "fcvt.wu.s" => float_two_regs!(F::CvtWuS, &regs, &floats),
-        "fmv.s.x" => float_two_regs!(F::MvSX, &floats, &regs),
-        "fmv.x.s" => float_two_regs!(F::MvXS, &regs, &floats),
-        "fsqrt.s" => float_two_regs!(F::Sqrt, &floats, &floats),
-        "fabs.s" => {
-            float_two_regs(s, &floats, &floats).map(|(rd, rs1)| F::SgnjXS(rd, rs1, rs1).into())?
-        }
-        "fmv.s" => {
-            float_two_regs(s, &floats, &floats).map(|(rd, rs1)| F::SgnjS(rd, rs1, rs1).into())?
-        }
-        "fneg.s" => {
-            float_two_regs(s, &floats, &floats).map(|(rd, rs1)| F::SgnjNS(rd, rs1, rs1).into())?
-        }
-        "flw" => type_s!(float F::Lw),
-        "fsw" => type_s!(float F::Sw),
-
-        "uret" => URet.into(),
-
-        dont_know => return Err(Error::InstructionNotFound(dont_know.to_owned())),
-    };
-
-    Ok(parsed)
-}
-
-/// Parses either `jal rd label` or `jal label`. In the last case, we set `rd = ra`
-fn parse_jal<'a>(s: &'a str, regs: &RegMap) -> Result<PreLabelInstruction, Error> {
-    use PreLabelInstruction as pre;
-    args_jal(s, regs)
-        .map(|(rd, label)| pre::Jal(rd, label.to_owned()))
-        .or_else(|_| one_arg(s).map(|(_i, label)| pre::Jal(1, label.to_owned())))
-        .map_err(|e| e.into())
-}
-
-/// Parses a single line of RISC-V code and pushes one or more instructions to the `code` vector
-pub(super) fn parse_line(s: &str, regmaps: &FullRegMap, code: &mut Vec<PreLabelInstruction>) -> Result<(), Error> {
-    if let Some(instructions) = parse_multi_instruction(s, regmaps) {
-        code.extend(instructions);
-        return Ok(());
-    }
-
-    let i = parse_instruction(s, regmaps)?;
-    code.push(i);
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::Instruction::*;
-    use super::PreLabelInstruction as pre;
-    use super::*;
-    use crate::parser::register_names as reg_names;
-
-    use lazy_static::*;
-    lazy_static! {
-        static ref FULLREG: FullRegMap =
-            { (reg_names::regs(), reg_names::floats(), reg_names::status()) };
-    }
-
-    #[test]
-    fn test_parse_text() {
-        assert_eq!(
-            parse_instruction("add s0, s0, s1,,,, ", &FULLREG).map_err(|_| ()),
-            Ok(Add(8, 8, 9).into())
-        );
-        assert_eq!(
-            parse_instruction("j label", &FULLREG).map_err(|_| ()),
-            Ok(pre::Jal(0, "label".to_owned()).into())
-        );
-        assert_eq!(
-            parse_instruction("bgtz x1 somewhere", &FULLREG).map_err(|_| ()),
-            Ok(pre::Blt(0, 1, "somewhere".to_owned()).into())
-        );
-    }
-}
+mod decode;
+pub(super) use decode::decode;
+
+mod compressed;
+pub(super) use compressed::{compress, decompress};
+
+mod display;
+
+mod encode;
+pub(super) use encode::{encode, jal_reaches};
+
+mod tokens;
+use tokens::{Operands, Value};
+
+use super::{
+    register_names::{
+        FullRegMap, RegMap, CYCLEH_INDEX, CYCLE_INDEX, FCSR_INDEX, FFLAGS_INDEX, FRM_INDEX,
+        INSTRETH_INDEX, INSTRET_INDEX, TIMEH_INDEX, TIME_INDEX,
+    },
+    util::{closest, Error},
+    FloatInstruction, Instruction, PreLabelInstruction, Rv64Instruction, VectorInstruction,
+};
+
+/// Every instruction we know, so we can suggest one when there's a typo. Keep it in sync with
+/// [parse_instruction](fn.parse_instruction.html)
+const INSTRUCTIONS: &[&str] = &[
+    "add", "sub", "sll", "slt", "sltu", "xor", "srl", "sra", "or", "and", "mul", "div", "divu",
+    "rem", "remu", "mulh", "mulhsu", "mulhu", "neg", "not", "mv", "snez", "sltz", "sgtz",
+    "sh1add", "sh2add", "sh3add", "bset", "bclr", "binv", "bext", "bseti", "bclri", "binvi",
+    "bexti", "czero.eqz", "czero.nez",
+    "addi", "slli", "slti", "sltiu", "xori", "srli", "srai", "ori", "andi", "jalr", "jr", "seqz",
+    "lb", "lh", "lw", "lbu", "lhu", "sb", "sh", "sw",
+    "beq", "bne", "blt", "bge", "bltu", "bgeu", "bgt", "ble", "bgtu", "bleu",
+    "beqz", "bnez", "bltz", "bgez", "bltuz", "bgeuz", "bgtz", "blez",
+    "csrw", "csrc", "csrs", "csrwi", "csrci", "csrsi", "csrrs", "csrrw", "csrrc",
+    "csrrsi", "csrrwi", "csrrci", "csrr", "rdcycle", "rdcycleh", "rdtime", "rdtimeh", "rdinstret",
+    "rdinstreth",
+    "jal", "call", "j", "tail", "b", "ret", "ecall", "ebreak", "la", "li", "lui", "auipc", "nop",
+    "fadd.s", "fsub.s", "fmul.s", "fdiv.s", "feq.s", "fle.s", "flt.s", "fmax.s", "fmin.s",
+    "fsgnj.s", "fsgnjn.s", "fsgnjx.s", "fclass.s", "fcvt.s.w", "fcvt.s.wu", "fcvt.w.s",
+    "fcvt.wu.s", "fmv.s.x", "fmv.x.s", "fmv.w.x", "fmv.x.w", "fsqrt.s", "fabs.s", "fmv.s",
+    "fneg.s", "flw", "fsw", "fmadd.s", "fmsub.s", "fnmsub.s", "fnmadd.s",
+    "fadd.d", "fsub.d", "fmul.d", "fdiv.d", "feq.d", "fle.d", "flt.d", "fmax.d", "fmin.d",
+    "fsgnj.d", "fsgnjn.d", "fsgnjx.d", "fclass.d", "fcvt.d.w", "fcvt.d.wu", "fcvt.w.d",
+    "fcvt.wu.d", "fcvt.s.d", "fcvt.d.s", "fsqrt.d", "fabs.d", "fmv.d", "fneg.d", "fld", "fsd",
+    "fmadd.d", "fmsub.d", "fnmsub.d", "fnmadd.d",
+    "frcsr", "fscsr", "frrm", "fsrm", "fsrmi", "frflags", "fsflags", "fsflagsi",
+    "uret", "mret", "wfi", "fence", "fence.i",
+    "lr.w", "sc.w", "amoswap.w", "amoadd.w", "amoxor.w", "amoand.w", "amoor.w", "amomin.w",
+    "amomax.w", "amominu.w", "amomaxu.w",
+    "vsetvli", "vle32.v", "vse32.v", "vadd.vv", "vmul.vv", "vredsum.vs", "vredmax.vs",
+    "vredmaxu.vs", "vredmin.vs", "vredminu.vs", "vmv.x.s", "vmv.s.x",
+    "c.nop", "c.addi", "c.addi16sp", "c.addi4spn", "c.li", "c.lui", "c.mv", "c.add", "c.sub",
+    "c.xor", "c.or", "c.and", "c.andi", "c.slli", "c.srli", "c.srai", "c.lw", "c.sw", "c.lwsp",
+    "c.swsp", "c.flw", "c.fsw", "c.flwsp", "c.fswsp", "c.fld", "c.fsd", "c.fldsp", "c.fsdsp",
+    "c.j", "c.jal", "c.jr", "c.jalr", "c.beqz", "c.bnez", "c.ebreak",
+];
+
+/// The instructions that only exist in RV64, see [Rv64Instruction](../enum.Rv64Instruction.html)
+const RV64_INSTRUCTIONS: &[&str] = &[
+    "addw", "subw", "sllw", "srlw", "sraw", "mulw", "divw", "divuw", "remw", "remuw", "addiw",
+    "slliw", "srliw", "sraiw", "negw", "sext.w", "ld", "lwu", "sd",
+];
+
+/// The rounding modes a float instruction can end with, in the order of their encoding. There's
+/// also `dyn`, which is [DYNAMIC_ROUNDING](../constant.DYNAMIC_ROUNDING.html).
+const ROUNDING_MODES: &[&str] = &["rne", "rtz", "rdn", "rup", "rmm"];
+
+/// Parses a line that produces many instructions at a time, like `lw a0 label`, which is an
+/// `auipc` to get close to the label and the load from there, like in RARS.
+pub(super) fn parse_multi_instruction(s: &str, regmaps: &FullRegMap, rv64: bool) -> Option<Vec<PreLabelInstruction>> {
+    let (regs, floats, _status) = regmaps;
+
+    use FloatInstruction as F;
+    use PreLabelInstruction as pre;
+    use Instruction::*;
+    use Rv64Instruction as R;
+
+    let mut ops = Operands::new(s);
+    let instruction = ops.mnemonic().ok()?;
+
+    macro_rules! load {
+        ($inst:expr) => {
+            ops.attempt(|ops| Ok((ops.reg(regs)?, ops.label()?)))
+                .map(|(rd, label)| vec![
+                    pre::PcRelHi(rd, label.clone()),
+                    pre::PcRelLo($inst(rd, 0, rd).into(), label),
+                ])
+                .ok()
+        }
+    }
+
+    // Like RARS, `sw t0, label, t1` needs a register for the address, since `t0` can't be
+    // overwritten. The same goes for the float loads, which can't keep an address in `ft0`.
+    macro_rules! with_temp {
+        ($regs:expr, $inst:expr) => {
+            ops.attempt(|ops| Ok((ops.reg($regs)?, ops.label()?, ops.reg(regs)?)))
+                .map(|(r, label, temp)| vec![
+                    pre::PcRelHi(temp, label.clone()),
+                    pre::PcRelLo($inst(r, 0, temp).into(), label),
+                ])
+                .ok()
+        }
+    }
+
+    match instruction {
+        "lb" => load!(Lb),
+        "lh" => load!(Lh),
+        "lw" => load!(Lw),
+        "lbu" => load!(Lbu),
+        "lhu" => load!(Lhu),
+        "sb" => with_temp!(regs, Sb),
+        "sh" => with_temp!(regs, Sh),
+        "sw" => with_temp!(regs, Sw),
+        "flw" => with_temp!(floats, F::Lw),
+        "fsw" => with_temp!(floats, F::Sw),
+        "fld" => with_temp!(floats, F::Ld),
+        "fsd" => with_temp!(floats, F::Sd),
+        "ld" if rv64 => load!(R::Ld),
+        "lwu" if rv64 => load!(R::Lwu),
+        "sd" if rv64 => with_temp!(regs, R::Sd),
+        "li" if rv64 => ops
+            .attempt(|ops| match (ops.reg(regs)?, ops.wide_value()?) {
+                (rd, Value::Imm(imm)) => Ok(load_immediate(rd, imm, true)),
+                (_, Value::Label(label)) => Err(Error::InvalidImmediate(label)),
+            })
+            .map(|code| code.into_iter().map(pre::from).collect())
+            .ok(),
+        _ => None
+    }
+}
+
+/// Parses a line that produces a single instruction. With `rv64`, the instructions that depend on
+/// the width of the registers are [widened](fn.widen.html).
+pub(super) fn parse_instruction(s: &str, regmaps: &FullRegMap, rv64: bool) -> Result<PreLabelInstruction, Error> {
+    let (regs, floats, status) = regmaps;
+
+    use FloatInstruction as F;
+    use Instruction::*;
+    use PreLabelInstruction as pre;
+    use Rv64Instruction as R;
+    use VectorInstruction as V;
+
+    let mut ops = Operands::new(s);
+    let instruction = ops.mnemonic()?;
+
+    // An immediate that may also be an expression with labels, like `addi t0 t0 end - start`
+    macro_rules! with_imm {
+        ($value:expr, $inst:expr) => {
+            match $value {
+                Value::Imm(imm) => $inst(imm).into(),
+                Value::Label(label) => pre::LabelImm($inst(0).into(), label),
+            }
+        };
+    }
+
+    macro_rules! type_i {
+        ($inst:expr) => {{
+            let (rd, rs1) = (ops.reg(regs)?, ops.reg(regs)?);
+            with_imm!(ops.value()?, |imm| $inst(rd, rs1, imm))
+        }};
+    }
+
+    // `sc.w` and the `amo`s, like `amoadd.w rd, rs2, (rs1)`
+    macro_rules! type_amo {
+        ($inst:expr) => {
+            $inst(ops.reg(regs)?, ops.reg(regs)?, ops.atomic_address(regs)?).into()
+        };
+    }
+
+    macro_rules! type_r {
+        ($inst:expr) => {
+            $inst(ops.reg(regs)?, ops.reg(regs)?, ops.reg(regs)?).into()
+        };
+        (float $inst:expr) => {
+            type_r!(rounding $inst, floats)
+        };
+        (mixed $inst:expr) => {
+            type_r!(rounding $inst, regs)
+        };
+        (rounded $inst:expr) => {
+            $inst(ops.reg(floats)?, ops.reg(floats)?, ops.reg(floats)?, ops.rounding_mode()?).into()
+        };
+        // Also accepts a rounding mode at the end, and ignores it
+        (rounding $inst:expr, $rd_regs:expr) => {{
+            let parsed = $inst(ops.reg($rd_regs)?, ops.reg(floats)?, ops.reg(floats)?).into();
+            ops.rounding_mode()?;
+            parsed
+        }};
+    }
+
+    // Vector instructions on whole registers, like `vadd.vv vd, vs2, vs1`
+    macro_rules! type_v {
+        ($inst:expr) => {
+            $inst(ops.vector_reg()?, ops.vector_reg()?, ops.vector_reg()?).into()
+        };
+    }
+
+    // The fused multiply-adds, with three sources
+    macro_rules! type_r4 {
+        ($inst:expr) => {{
+            let (rd, rs1, rs2, rs3) = (ops.reg(floats)?, ops.reg(floats)?, ops.reg(floats)?, ops.reg(floats)?);
+            $inst(rd, rs1, rs2, rs3, ops.rounding_mode()?).into()
+        }};
+    }
+
+    macro_rules! type_sb {
+        ($inst:expr) => {
+            $inst(ops.reg(regs)?, ops.reg(regs)?, ops.label()?)
+        };
+    }
+
+    // bgez, bnez, ...
+    macro_rules! type_sb_z {
+        ($inst:expr) => {
+            $inst(ops.reg(regs)?, 0, ops.label()?)
+        };
+    }
+
+    // Reverses the order of rs1 and rs2 to convert, for example,
+    // `ble t0 t1 label` into `bge t1 t0 label`
+    macro_rules! type_sb_reversed {
+        ($inst:expr) => {{
+            let (rs1, rs2) = (ops.reg(regs)?, ops.reg(regs)?);
+            $inst(rs2, rs1, ops.label()?)
+        }};
+    }
+
+    // blez, ...
+    macro_rules! type_sb_reversed_z {
+        ($inst:expr) => {{
+            let rs1 = ops.reg(regs)?;
+            $inst(0, rs1, ops.label()?)
+        }};
+    }
+
+    // Also accepts a label as the offset, like `lw t1 matrix+16(t2)`
+    macro_rules! type_s {
+        ($inst:expr) => {
+            type_s!($inst, regs)
+        };
+        (float $inst:expr) => {
+            type_s!($inst, floats)
+        };
+        ($inst:expr, $rs2_regs:expr) => {{
+            let r1 = ops.reg($rs2_regs)?;
+            let (offset, r2) = ops.mem(regs)?;
+            with_imm!(offset, |imm| $inst(r1, imm, r2))
+        }};
+    }
+
+    // The compressed instructions whose destination is also their first source, like `c.add rd, rs2`
+    macro_rules! two_address {
+        ($inst:expr) => {{
+            let rd = ops.reg(regs)?;
+            $inst(rd, rd, ops.reg(regs)?).into()
+        }};
+        (imm $inst:expr) => {{
+            let rd = ops.reg(regs)?;
+            $inst(rd, rd, ops.imm()?).into()
+        }};
+    }
+
+    macro_rules! csr {
+        ($inst:expr) => {
+            $inst(ops.reg(regs)?, ops.csr(status)?, ops.reg(regs)?).into()
+        };
+    }
+
+    macro_rules! csr_imm {
+        ($inst:expr) => {
+            $inst(ops.reg(regs)?, ops.csr(status)?, ops.imm()?).into()
+        };
+    }
+
+    macro_rules! csr_small {
+        ($inst:expr) => {{
+            let rs1 = ops.reg(regs)?;
+            $inst(0, ops.csr(status)?, rs1).into()
+        }};
+    }
+
+    macro_rules! csr_small_imm {
+        ($inst:expr) => {
+            $inst(0, ops.csr(status)?, ops.imm()?).into()
+        };
+    }
+
+    // Swaps a part of `fcsr`, like `fsrm rd, rs1`, or just `fsrm rs1`, which doesn't keep the old one
+    macro_rules! float_csr {
+        ($csr:expr) => {{
+            let first = ops.reg(regs)?;
+            match ops.is_empty() {
+                true => CsrRw(0, $csr, first),
+                false => CsrRw(first, $csr, ops.reg(regs)?),
+            }
+            .into()
+        }};
+        (imm $csr:expr) => {
+            match ops.reg(regs) {
+                Ok(rd) => CsrRwi(rd, $csr, ops.imm()?),
+                Err(_) => CsrRwi(0, $csr, ops.imm()?),
+            }
+            .into()
+        };
+    }
+
+    macro_rules! float_two_regs {
+        (rounded $inst:expr, $rd_regmap:expr, $rs1_regmap:expr) => {
+            $inst(ops.reg($rd_regmap)?, ops.reg($rs1_regmap)?, ops.rounding_mode()?).into()
+        };
+        // Also accepts a rounding mode at the end, and ignores it
+        ($inst:expr, $rd_regmap:expr, $rs1_regmap:expr) => {{
+            let parsed = $inst(ops.reg($rd_regmap)?, ops.reg($rs1_regmap)?).into();
+            ops.rounding_mode()?;
+            parsed
+        }};
+    }
+
+    // The upper 20 bits of `lui` and `auipc`, written either unsigned or signed
+    let upper_imm = |ops: &mut Operands| {
+        let (rd, imm) = (ops.reg(regs)?, ops.imm()?);
+        if imm <= 0xF_FFFF || (-0x8_0000..0).contains(&(imm as i32)) {
+            Ok((rd, imm << 12))
+        } else {
+            Err(ops.at_imm(Error::FieldOutOfRange((imm as i32).to_string(), -0x8_0000, 0xF_FFFF)))
+        }
+    };
+
+    // Atomics are the same with any ordering, since there's a single hart
+    let mut name = instruction.to_lowercase();
+    if ["lr.", "sc.", "amo"].iter().any(|p| name.starts_with(p)) {
+        for suffix in &[".aqrl", ".aq", ".rl"] {
+            if name.ends_with(suffix) {
+                name.truncate(name.len() - suffix.len());
+                break;
+            }
+        }
+    }
+
+    if !rv64 && RV64_INSTRUCTIONS.contains(&name.as_str()) {
+        return Err(Error::NotInRv32(instruction.to_owned()));
+    }
+
+    let parsed = match name.as_str() {
+        // Type R
+        "add" => type_r!(Add),
+        "sub" => type_r!(Sub),
+        "sll" => type_r!(Sll),
+        "slt" => type_r!(Slt),
+        "sltu" => type_r!(Sltu),
+        "xor" => type_r!(Xor),
+        "srl" => type_r!(Srl),
+        "sra" => type_r!(Sra),
+        "or" => type_r!(Or),
+        "and" => type_r!(And),
+        "mul" => type_r!(Mul),
+        "mulh" => type_r!(Mulh),
+        "mulhsu" => type_r!(Mulhsu),
+        "mulhu" => type_r!(Mulhu),
+        "div" => type_r!(Div),
+        "divu" => type_r!(Divu),
+        "rem" => type_r!(Rem),
+        "remu" => type_r!(Remu),
+        "sh1add" => type_r!(Sh1add),
+        "sh2add" => type_r!(Sh2add),
+        "sh3add" => type_r!(Sh3add),
+        "bset" => type_r!(Bset),
+        "bclr" => type_r!(Bclr),
+        "binv" => type_r!(Binv),
+        "bext" => type_r!(Bext),
+        "czero.eqz" => type_r!(CzeroEqz),
+        "czero.nez" => type_r!(CzeroNez),
+        "neg" => Sub(ops.reg(regs)?, 0, ops.reg(regs)?).into(),
+        "not" => Xori(ops.reg(regs)?, ops.reg(regs)?, (-1i32) as u32).into(),
+        "mv" => Mv(ops.reg(regs)?, ops.reg(regs)?).into(),
+        "snez" => Sltu(ops.reg(regs)?, 0, ops.reg(regs)?).into(),
+        "sltz" => Slt(ops.reg(regs)?, ops.reg(regs)?, 0).into(),
+        "sgtz" => Slt(ops.reg(regs)?, 0, ops.reg(regs)?).into(),
+
+        // Type I
+        "addi" => type_i!(Addi),
+        "slli" => type_i!(Slli),
+        "slti" => type_i!(Slti),
+        "sltiu" => type_i!(Sltiu),
+        "xori" => type_i!(Xori),
+        "srli" => type_i!(Srli),
+        "srai" => type_i!(Srai),
+        "bseti" => type_i!(Bseti),
+        "bclri" => type_i!(Bclri),
+        "binvi" => type_i!(Binvi),
+        "bexti" => type_i!(Bexti),
+        "ori" => type_i!(Ori),
+        "andi" => type_i!(Andi),
+        "jalr" => parse_jalr(&mut ops, regs)?,
+        "jr" => Jalr(0, ops.reg(regs)?, 0).into(),
+        "seqz" => Sltiu(ops.reg(regs)?, ops.reg(regs)?, 1).into(),
+
+        // Type I, loads from memory
+        "lb" => type_s!(Lb),
+        "lh" => type_s!(Lh),
+        "lw" => type_s!(Lw),
+        "lbu" => type_s!(Lbu),
+        "lhu" => type_s!(Lhu),
+
+        // Type S
+        "sb" => type_s!(Sb),
+        "sh" => type_s!(Sh),
+        "sw" => type_s!(Sw),
+
+        // Type SB and pseudoinstructions that map to SBs
+        "beq" => type_sb!(pre::Beq),
+        "bne" => type_sb!(pre::Bne),
+        "blt" => type_sb!(pre::Blt),
+        "bge" => type_sb!(pre::Bge),
+        "bltu" => type_sb!(pre::Bltu),
+        "bgeu" => type_sb!(pre::Bgeu),
+        "bgt" => type_sb_reversed!(pre::Blt),
+        "ble" => type_sb_reversed!(pre::Bge),
+        "bgtu" => type_sb_reversed!(pre::Bltu),
+        "bleu" => type_sb_reversed!(pre::Bgeu),
+        "beqz" => type_sb_z!(pre::Beq),
+        "bnez" => type_sb_z!(pre::Bne),
+        "bltz" => type_sb_z!(pre::Blt),
+        "bgez" => type_sb_z!(pre::Bge),
+        "bltuz" => type_sb_z!(pre::Bltu),
+        "bgeuz" => type_sb_z!(pre::Bgeu),
+        "bgtz" => type_sb_reversed_z!(pre::Blt),
+        "blez" => type_sb_reversed_z!(pre::Bge),
+
+        // CSR
+        "csrw" => csr_small!(CsrRw),
+        "csrc" => csr_small!(CsrRc),
+        "csrs" => csr_small!(CsrRs),
+        "csrwi" => csr_small_imm!(CsrRwi),
+        "csrci" => csr_small_imm!(CsrRci),
+        "csrsi" => csr_small_imm!(CsrRsi),
+        "csrrs" => csr!(CsrRs),
+        "csrrw" => csr!(CsrRw),
+        "csrrc" => csr!(CsrRc),
+        "csrrsi" => csr_imm!(CsrRsi),
+        "csrrwi" => csr_imm!(CsrRwi),
+        "csrrci" => csr_imm!(CsrRci),
+        "csrr" => CsrRs(ops.reg(regs)?, ops.csr(status)?, 0).into(),
+        "rdcycle" => CsrRs(ops.reg(regs)?, CYCLE_INDEX, 0).into(),
+        "rdcycleh" => CsrRs(ops.reg(regs)?, CYCLEH_INDEX, 0).into(),
+        "rdtime" => CsrRs(ops.reg(regs)?, TIME_INDEX, 0).into(),
+        "rdtimeh" => CsrRs(ops.reg(regs)?, TIMEH_INDEX, 0).into(),
+        "rdinstret" => CsrRs(ops.reg(regs)?, INSTRET_INDEX, 0).into(),
+        "rdinstreth" => CsrRs(ops.reg(regs)?, INSTRETH_INDEX, 0).into(),
+
+        // Jumps
+        "jal" => parse_jal(&mut ops, regs)?,
+        "call" => pre::Call(1, ops.label()?),
+        "tail" => pre::Call(0, ops.label()?),
+        "j" | "b" => pre::Jal(0, ops.label()?),
+        "ret" => Ret.into(),
+
+        "ecall" => Ecall.into(),
+        "ebreak" => Ebreak.into(),
+
+        // Only the `auipc`, the `addi` after it is added by `parse_line`
+        "la" => pre::PcRelHi(ops.reg(regs)?, ops.label()?),
+
+        // In RV64, an immediate that doesn't fit in 32 bits takes more than a `lui` and an `addiw`,
+        // so it's left to `parse_multi_instruction`
+        "li" if rv64 => {
+            let rd = ops.reg(regs)?;
+            match ops.wide_value()? {
+                Value::Imm(imm) if imm == imm as i32 as i64 => Li(rd, imm as u32).into(),
+                Value::Imm(imm) => return Err(Error::ImmediateOutOfRange(imm.to_string())),
+                Value::Label(label) => pre::LabelImm(Li(rd, 0), label),
+            }
+        }
+        "li" => {
+            let rd = ops.reg(regs)?;
+            with_imm!(ops.value()?, |imm| Li(rd, imm))
+        }
+        "lui" => upper_imm(&mut ops).map(|(rd, imm)| Li(rd, imm).into())?,
+        "auipc" => upper_imm(&mut ops).map(|(rd, imm)| Auipc(rd, imm).into())?,
+
+        "nop" => Mv(0, 0).into(),
+
+        "fadd.s" => type_r!(rounded F::Add),
+        "fsub.s" => type_r!(rounded F::Sub),
+        "fmul.s" => type_r!(rounded F::Mul),
+        "fdiv.s" => type_r!(rounded F::Div),
+        "feq.s" => type_r!(mixed F::Equ),
+        "fle.s" => type_r!(mixed F::Le),
+        "flt.s" => type_r!(mixed F::Lt),
+        "fmax.s" => type_r!(float F::Max),
+        "fmin.s" => type_r!(float F::Min),
+        "fsgnj.s" => type_r!(float F::SgnjS),
+        "fsgnjn.s" => type_r!(float F::SgnjNS),
+        "fsgnjx.s" => type_r!(float F::SgnjXS),
+        "fclass.s" => float_two_regs!(F::Class, regs, floats),
+        "fcvt.s.w" => float_two_regs!(rounded F::CvtSW, floats, regs),
+        "fcvt.s.wu" => float_two_regs!(rounded F::CvtSWu, floats, regs),
+        "fcvt.w.s" => float_two_regs!(rounded F::CvtWS, regs, floats),
+        "fcvt.wu.s" => float_two_regs!(rounded F::CvtWuS, regs, floats),
+        "fmv.s.x" => float_two_regs!(F::MvSX, floats, regs),
+        "fmv.x.s" => float_two_regs!(F::MvXS, regs, floats),
+        // The names the spec uses now, since they move a word and not a float
+        "fmv.w.x" => float_two_regs!(F::MvSX, floats, regs),
+        "fmv.x.w" => float_two_regs!(F::MvXS, regs, floats),
+        "fsqrt.s" => float_two_regs!(rounded F::Sqrt, floats, floats),
+        "fabs.s" => float_two_regs!(|rd, rs1| F::SgnjXS(rd, rs1, rs1), floats, floats),
+        "fmv.s" => float_two_regs!(|rd, rs1| F::SgnjS(rd, rs1, rs1), floats, floats),
+        "fneg.s" => float_two_regs!(|rd, rs1| F::SgnjNS(rd, rs1, rs1), floats, floats),
+        "flw" => type_s!(float F::Lw),
+        "fsw" => type_s!(float F::Sw),
+        "fmadd.s" => type_r4!(F::Madd),
+        "fmsub.s" => type_r4!(F::Msub),
+        "fnmsub.s" => type_r4!(F::NMsub),
+        "fnmadd.s" => type_r4!(F::NMadd),
+
+        "fadd.d" => type_r!(rounded F::AddD),
+        "fsub.d" => type_r!(rounded F::SubD),
+        "fmul.d" => type_r!(rounded F::MulD),
+        "fdiv.d" => type_r!(rounded F::DivD),
+        "feq.d" => type_r!(mixed F::EquD),
+        "fle.d" => type_r!(mixed F::LeD),
+        "flt.d" => type_r!(mixed F::LtD),
+        "fmax.d" => type_r!(float F::MaxD),
+        "fmin.d" => type_r!(float F::MinD),
+        "fsgnj.d" => type_r!(float F::SgnjD),
+        "fsgnjn.d" => type_r!(float F::SgnjND),
+        "fsgnjx.d" => type_r!(float F::SgnjXD),
+        "fclass.d" => float_two_regs!(F::ClassD, regs, floats),
+        "fcvt.d.w" => float_two_regs!(F::CvtDW, floats, regs),
+        "fcvt.d.wu" => float_two_regs!(F::CvtDWu, floats, regs),
+        "fcvt.w.d" => float_two_regs!(rounded F::CvtWD, regs, floats),
+        "fcvt.wu.d" => float_two_regs!(rounded F::CvtWuD, regs, floats),
+        "fcvt.s.d" => float_two_regs!(rounded F::CvtSD, floats, floats),
+        "fcvt.d.s" => float_two_regs!(F::CvtDS, floats, floats),
+        "fsqrt.d" => float_two_regs!(rounded F::SqrtD, floats, floats),
+        "fabs.d" => float_two_regs!(|rd, rs1| F::SgnjXD(rd, rs1, rs1), floats, floats),
+        "fmv.d" => float_two_regs!(|rd, rs1| F::SgnjD(rd, rs1, rs1), floats, floats),
+        "fneg.d" => float_two_regs!(|rd, rs1| F::SgnjND(rd, rs1, rs1), floats, floats),
+        "fld" => type_s!(float F::Ld),
+        "fsd" => type_s!(float F::Sd),
+        "fmadd.d" => type_r4!(F::MaddD),
+        "fmsub.d" => type_r4!(F::MsubD),
+        "fnmsub.d" => type_r4!(F::NMsubD),
+        "fnmadd.d" => type_r4!(F::NMaddD),
+
+        "frcsr" => CsrRs(ops.reg(regs)?, FCSR_INDEX, 0).into(),
+        "fscsr" => float_csr!(FCSR_INDEX),
+        "frrm" => CsrRs(ops.reg(regs)?, FRM_INDEX, 0).into(),
+        "fsrm" => float_csr!(FRM_INDEX),
+        "fsrmi" => float_csr!(imm FRM_INDEX),
+        "frflags" => CsrRs(ops.reg(regs)?, FFLAGS_INDEX, 0).into(),
+        "fsflags" => float_csr!(FFLAGS_INDEX),
+        "fsflagsi" => float_csr!(imm FFLAGS_INDEX),
+
+        "uret" => URet.into(),
+        "mret" => MRet.into(),
+        "wfi" => Wfi.into(),
+        // The sets are `iorw, iorw` when left out
+        "fence" if ops.is_empty() => Fence(0xF, 0xF).into(),
+        "fence" => Fence(ops.fence_set()?, ops.fence_set()?).into(),
+        "fence.i" => FenceI.into(),
+
+        // Atomics
+        "lr.w" => LrW(ops.reg(regs)?, ops.atomic_address(regs)?).into(),
+        "sc.w" => type_amo!(ScW),
+        "amoswap.w" => type_amo!(AmoSwapW),
+        "amoadd.w" => type_amo!(AmoAddW),
+        "amoxor.w" => type_amo!(AmoXorW),
+        "amoand.w" => type_amo!(AmoAndW),
+        "amoor.w" => type_amo!(AmoOrW),
+        "amomin.w" => type_amo!(AmoMinW),
+        "amomax.w" => type_amo!(AmoMaxW),
+        "amominu.w" => type_amo!(AmoMinuW),
+        "amomaxu.w" => type_amo!(AmoMaxuW),
+
+        // Vectors
+        "vsetvli" => V::Setvli(ops.reg(regs)?, ops.reg(regs)?, ops.vtype()?).into(),
+        "vle32.v" => V::Le32(ops.vector_reg()?, ops.atomic_address(regs)?).into(),
+        "vse32.v" => V::Se32(ops.vector_reg()?, ops.atomic_address(regs)?).into(),
+        "vadd.vv" => type_v!(V::Add),
+        "vmul.vv" => type_v!(V::Mul),
+        "vredsum.vs" => type_v!(V::RedSum),
+        "vredmax.vs" => type_v!(V::RedMax),
+        "vredmaxu.vs" => type_v!(V::RedMaxu),
+        "vredmin.vs" => type_v!(V::RedMin),
+        "vredminu.vs" => type_v!(V::RedMinu),
+        "vmv.x.s" => V::MvXS(ops.reg(regs)?, ops.vector_reg()?).into(),
+        "vmv.s.x" => V::MvSX(ops.vector_reg()?, ops.reg(regs)?).into(),
+
+        // RV64
+        "addw" => type_r!(R::Addw),
+        "subw" => type_r!(R::Subw),
+        "sllw" => type_r!(R::Sllw),
+        "srlw" => type_r!(R::Srlw),
+        "sraw" => type_r!(R::Sraw),
+        "mulw" => type_r!(R::Mulw),
+        "divw" => type_r!(R::Divw),
+        "divuw" => type_r!(R::Divuw),
+        "remw" => type_r!(R::Remw),
+        "remuw" => type_r!(R::Remuw),
+        "addiw" => type_i!(R::Addiw),
+        "slliw" => type_i!(R::Slliw),
+        "srliw" => type_i!(R::Srliw),
+        "sraiw" => type_i!(R::Sraiw),
+        "negw" => R::Subw(ops.reg(regs)?, 0, ops.reg(regs)?).into(),
+        "sext.w" => R::Addiw(ops.reg(regs)?, ops.reg(regs)?, 0).into(),
+        "ld" => type_s!(R::Ld),
+        "lwu" => type_s!(R::Lwu),
+        "sd" => type_s!(R::Sd),
+
+        // RV32C. Each compressed instruction is the base one it stands for, which takes 2 bytes
+        // instead of 4 with `--rvc`
+        "c.nop" => Mv(0, 0).into(),
+        "c.addi" | "c.addi16sp" => two_address!(imm Addi),
+        "c.addi4spn" => type_i!(Addi),
+        "c.li" => Li(ops.reg(regs)?, ops.imm()?).into(),
+        "c.lui" => upper_imm(&mut ops).map(|(rd, imm)| Li(rd, imm).into())?,
+        "c.mv" => Mv(ops.reg(regs)?, ops.reg(regs)?).into(),
+        "c.add" => two_address!(Add),
+        "c.sub" => two_address!(Sub),
+        "c.xor" => two_address!(Xor),
+        "c.or" => two_address!(Or),
+        "c.and" => two_address!(And),
+        "c.andi" => two_address!(imm Andi),
+        "c.slli" => two_address!(imm Slli),
+        "c.srli" => two_address!(imm Srli),
+        "c.srai" => two_address!(imm Srai),
+        "c.lw" | "c.lwsp" => type_s!(Lw),
+        "c.sw" | "c.swsp" => type_s!(Sw),
+        "c.flw" | "c.flwsp" => type_s!(float F::Lw),
+        "c.fsw" | "c.fswsp" => type_s!(float F::Sw),
+        "c.fld" | "c.fldsp" => type_s!(float F::Ld),
+        "c.fsd" | "c.fsdsp" => type_s!(float F::Sd),
+        "c.j" => pre::Jal(0, ops.label()?),
+        "c.jal" => pre::Jal(1, ops.label()?),
+        "c.jr" => Jalr(0, ops.reg(regs)?, 0).into(),
+        "c.jalr" => Jalr(1, ops.reg(regs)?, 0).into(),
+        "c.beqz" => type_sb_z!(pre::Beq),
+        "c.bnez" => type_sb_z!(pre::Bne),
+        "c.ebreak" => Ebreak.into(),
+
+        dont_know => {
+            let suggestion = closest(dont_know, INSTRUCTIONS.iter().copied()).map(str::to_owned);
+            return Err(Error::UnknownInstruction(instruction.to_owned(), suggestion));
+        }
+    };
+    ops.end()?;
+
+    let parsed = match parsed {
+        pre::Other(instruction) if rv64 => pre::Other(widen(instruction)),
+        pre::LabelImm(instruction, label) if rv64 => pre::LabelImm(widen(instruction), label),
+        parsed => parsed,
+    };
+
+    if let pre::Other(instruction) = &parsed {
+        check_immediate(instruction).map_err(|e| ops.at_imm(e))?;
+    }
+    Ok(parsed)
+}
+
+/// Checks that the immediate of `instruction` fits in its field. We'd run it just fine with any
+/// 32-bit immediate, but a real processor wouldn't, and neither would RARS.
+fn check_immediate(instruction: &Instruction) -> Result<(), Error> {
+    use FloatInstruction as F;
+    use Instruction::*;
+    use Rv64Instruction as R;
+
+    const TWELVE_BITS: (i64, i64) = (-2048, 2047);
+    const SIX_BITS: (i64, i64) = (0, 63);
+    const FIVE_BITS: (i64, i64) = (0, 31);
+
+    let (imm, (min, max)) = match *instruction {
+        Addi(_, _, imm) | Slti(_, _, imm) | Sltiu(_, _, imm) | Xori(_, _, imm) | Ori(_, _, imm)
+        | Andi(_, _, imm) | Jalr(_, _, imm) => (imm, TWELVE_BITS),
+        Lb(_, imm, _) | Lh(_, imm, _) | Lw(_, imm, _) | Lbu(_, imm, _) | Lhu(_, imm, _)
+        | Sb(_, imm, _) | Sh(_, imm, _) | Sw(_, imm, _) => (imm, TWELVE_BITS),
+        Float(F::Lw(_, imm, _)) | Float(F::Sw(_, imm, _)) => (imm, TWELVE_BITS),
+        Float(F::Ld(_, imm, _)) | Float(F::Sd(_, imm, _)) => (imm, TWELVE_BITS),
+        Slli(_, _, imm) | Srli(_, _, imm) | Srai(_, _, imm) => (imm, FIVE_BITS),
+        Bseti(_, _, imm) | Bclri(_, _, imm) | Binvi(_, _, imm) | Bexti(_, _, imm) => (imm, FIVE_BITS),
+        CsrRwi(_, _, imm) | CsrRsi(_, _, imm) | CsrRci(_, _, imm) => (imm, FIVE_BITS),
+        Rv64(R::Addi(_, _, imm)) | Rv64(R::Addiw(_, _, imm)) => (imm, TWELVE_BITS),
+        Rv64(R::Ld(_, imm, _)) | Rv64(R::Lwu(_, imm, _)) | Rv64(R::Sd(_, imm, _)) => (imm, TWELVE_BITS),
+        Rv64(R::Slli(_, _, imm)) | Rv64(R::Srli(_, _, imm)) | Rv64(R::Srai(_, _, imm)) => (imm, SIX_BITS),
+        Rv64(R::Bseti(_, _, imm) | R::Bclri(_, _, imm) | R::Binvi(_, _, imm) | R::Bexti(_, _, imm)) => {
+            (imm, SIX_BITS)
+        }
+        Rv64(R::Slliw(_, _, imm)) | Rv64(R::Srliw(_, _, imm)) | Rv64(R::Sraiw(_, _, imm)) => (imm, FIVE_BITS),
+        _ => return Ok(()),
+    };
+
+    let imm = imm as i32 as i64;
+    if (min..=max).contains(&imm) {
+        Ok(())
+    } else {
+        Err(Error::FieldOutOfRange(imm.to_string(), min, max))
+    }
+}
+
+/// Parses either `jal rd label` or `jal label`. In the last case, we set `rd = ra`
+fn parse_jal(ops: &mut Operands, regs: &RegMap) -> Result<PreLabelInstruction, Error> {
+    use PreLabelInstruction as pre;
+    ops.attempt(|ops| Ok(pre::Jal(ops.reg(regs)?, ops.label()?)))
+        .or_else(|_| ops.attempt(|ops| Ok(pre::Jal(1, ops.label()?))))
+}
+
+/// Parses the forms of `jalr` RARS accepts: `jalr rd, rs1, imm`, `jalr rd, imm(rs1)`, `jalr rd, rs1`
+/// and `jalr rs1`, which links to `ra`. The immediate can also be an expression with labels.
+fn parse_jalr(ops: &mut Operands, regs: &RegMap) -> Result<PreLabelInstruction, Error> {
+    use Instruction::Jalr;
+    use PreLabelInstruction as pre;
+    ops.attempt(|ops| {
+        let (rd, rs1) = (ops.reg(regs)?, ops.reg(regs)?);
+        Ok(match ops.value()? {
+            Value::Imm(imm) => Jalr(rd, rs1, imm).into(),
+            Value::Label(label) => pre::LabelImm(Jalr(rd, rs1, 0), label),
+        })
+    })
+    .or_else(|e| {
+        ops.attempt(|ops| {
+            let rd = ops.reg(regs)?;
+            match ops.mem(regs)? {
+                (Value::Imm(imm), rs1) => Ok(Jalr(rd, rs1, imm).into()),
+                (Value::Label(label), rs1) => Ok(pre::LabelImm(Jalr(rd, rs1, 0), label)),
+            }
+        })
+        .or_else(|_| ops.attempt(|ops| Ok(Jalr(ops.reg(regs)?, ops.reg(regs)?, 0).into())))
+        .or_else(|_| ops.attempt(|ops| Ok(Jalr(1, ops.reg(regs)?, 0).into())))
+        .map_err(|_| e)
+    })
+}
+
+/// What `li rd, imm` becomes. Like RARS, it's a single `addi` or `lui` when one of them can load
+/// `imm`, and a `lui` with the upper bits followed by an `addi` with the lower ones otherwise. In
+/// RV64, that's an `addiw`, so the result is still the 32-bit `imm` sign extended.
+///
+/// An RV64 `imm` that doesn't fit in 32 bits is built like GCC and LLVM do: its upper bits are
+/// loaded first, then shifted into place with an `slli` and the lower 12 bits added with an `addi`.
+fn load_immediate(rd: u8, imm: i64, rv64: bool) -> Vec<Instruction> {
+    use Instruction::*;
+    use Rv64Instruction as R;
+
+    if rv64 && imm != imm as i32 as i64 {
+        let lower = (imm << 52) >> 52;
+        let upper = (imm - lower) >> 12;
+        let shift = 12 + upper.trailing_zeros();
+
+        let mut code = load_immediate(rd, upper >> (shift - 12), true);
+        code.push(R::Slli(rd, rd, shift).into());
+        if lower != 0 {
+            code.push(R::Addi(rd, rd, lower as u32).into());
+        }
+        return code;
+    }
+
+    let imm = imm as u32;
+    let lower = (((imm << 20) as i32) >> 20) as u32;
+    if lower == imm || lower == 0 {
+        vec![Li(rd, imm)]
+    } else if rv64 {
+        vec![Li(rd, imm.wrapping_sub(lower)), R::Addiw(rd, rd, lower).into()]
+    } else {
+        vec![Li(rd, imm.wrapping_sub(lower)), Addi(rd, rd, lower)]
+    }
+}
+
+/// Turns the base instructions whose result depends on the width of the registers, like `add`
+/// or `slli`, into their RV64 versions. The others, like `and` or `beq`, give the same result on
+/// 64-bit registers as long as the values are sign extended, so they're kept.
+pub(super) fn widen(instruction: Instruction) -> Instruction {
+    use Instruction::*;
+    use Rv64Instruction as R;
+
+    match instruction {
+        Add(rd, rs1, rs2) => R::Add(rd, rs1, rs2).into(),
+        Sub(rd, rs1, rs2) => R::Sub(rd, rs1, rs2).into(),
+        Sll(rd, rs1, rs2) => R::Sll(rd, rs1, rs2).into(),
+        Srl(rd, rs1, rs2) => R::Srl(rd, rs1, rs2).into(),
+        Sra(rd, rs1, rs2) => R::Sra(rd, rs1, rs2).into(),
+        Mul(rd, rs1, rs2) => R::Mul(rd, rs1, rs2).into(),
+        Mulh(rd, rs1, rs2) => R::Mulh(rd, rs1, rs2).into(),
+        Mulhsu(rd, rs1, rs2) => R::Mulhsu(rd, rs1, rs2).into(),
+        Mulhu(rd, rs1, rs2) => R::Mulhu(rd, rs1, rs2).into(),
+        Div(rd, rs1, rs2) => R::Div(rd, rs1, rs2).into(),
+        Divu(rd, rs1, rs2) => R::Divu(rd, rs1, rs2).into(),
+        Rem(rd, rs1, rs2) => R::Rem(rd, rs1, rs2).into(),
+        Remu(rd, rs1, rs2) => R::Remu(rd, rs1, rs2).into(),
+        Sh1add(rd, rs1, rs2) => R::Sh1add(rd, rs1, rs2).into(),
+        Sh2add(rd, rs1, rs2) => R::Sh2add(rd, rs1, rs2).into(),
+        Sh3add(rd, rs1, rs2) => R::Sh3add(rd, rs1, rs2).into(),
+        Bset(rd, rs1, rs2) => R::Bset(rd, rs1, rs2).into(),
+        Bclr(rd, rs1, rs2) => R::Bclr(rd, rs1, rs2).into(),
+        Binv(rd, rs1, rs2) => R::Binv(rd, rs1, rs2).into(),
+        Bext(rd, rs1, rs2) => R::Bext(rd, rs1, rs2).into(),
+        Bseti(rd, rs1, imm) => R::Bseti(rd, rs1, imm).into(),
+        Bclri(rd, rs1, imm) => R::Bclri(rd, rs1, imm).into(),
+        Binvi(rd, rs1, imm) => R::Binvi(rd, rs1, imm).into(),
+        Bexti(rd, rs1, imm) => R::Bexti(rd, rs1, imm).into(),
+        Addi(rd, rs1, imm) => R::Addi(rd, rs1, imm).into(),
+        Slli(rd, rs1, imm) => R::Slli(rd, rs1, imm).into(),
+        Srli(rd, rs1, imm) => R::Srli(rd, rs1, imm).into(),
+        Srai(rd, rs1, imm) => R::Srai(rd, rs1, imm).into(),
+        other => other,
+    }
+}
+
+/// Adapts a decoded instruction to the register width: RV64 instructions are illegal in RV32, and
+/// the base ones are [widened](fn.widen.html) in RV64
+pub(super) fn for_width(instruction: Instruction, rv64: bool) -> Option<Instruction> {
+    match instruction {
+        Instruction::Rv64(_) if !rv64 => None,
+        i if rv64 => Some(widen(i)),
+        i => Some(i),
+    }
+}
+
+/// The mnemonic of `s` if it's a compressed instruction, like `c.addi`
+pub(super) fn compressed_mnemonic(s: &str) -> Option<&str> {
+    let mnemonic = Operands::new(s).mnemonic().ok()?;
+    let prefix = mnemonic.get(..2)?;
+    prefix.eq_ignore_ascii_case("c.").then_some(mnemonic)
+}
+
+/// Parses a single line of RISC-V code and pushes one or more instructions to the `code` vector
+pub(super) fn parse_line(
+    s: &str,
+    regmaps: &FullRegMap,
+    rv64: bool,
+    code: &mut Vec<PreLabelInstruction>,
+) -> Result<(), Error> {
+    // The single instruction comes first, otherwise `lw t0 (sp)` would load from a label called `(sp)`
+    use PreLabelInstruction as pre;
+
+    let error = match parse_instruction(s, regmaps, rv64) {
+        Ok(pre::Other(Instruction::Li(rd, imm))) => {
+            code.extend(load_immediate(rd, imm as i32 as i64, rv64).into_iter().map(PreLabelInstruction::from));
+            return Ok(());
+        }
+        // The value of a label isn't known yet, so it always takes the two instructions it may
+        // need, an `auipc` and an `addi` for `la`, and a `lui` and an `addi` for `li`
+        Ok(pre::PcRelHi(rd, label)) => {
+            let addi = if rv64 { widen(Instruction::Addi(rd, rd, 0)) } else { Instruction::Addi(rd, rd, 0) };
+            code.extend([pre::PcRelHi(rd, label.clone()), pre::PcRelLo(addi, label)]);
+            return Ok(());
+        }
+        Ok(pre::LabelImm(Instruction::Li(rd, _), label)) => {
+            let addi = if rv64 { Rv64Instruction::Addiw(rd, rd, 0).into() } else { Instruction::Addi(rd, rd, 0) };
+            code.extend([pre::Hi(rd, label.clone()), pre::Lo(addi, label)]);
+            return Ok(());
+        }
+        Ok(i) => {
+            code.push(i);
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+
+    match parse_multi_instruction(s, regmaps, rv64) {
+        Some(instructions) => {
+            code.extend(instructions);
+            Ok(())
+        }
+        None => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Instruction::*;
+    use super::PreLabelInstruction as pre;
+    use super::*;
+    use crate::parser::register_names as reg_names;
+
+    use lazy_static::*;
+    lazy_static! {
+        static ref FULLREG: FullRegMap =
+            (reg_names::regs(), reg_names::floats(), reg_names::status());
+    }
+
+    /// Parses `s`, without the span of the error
+    fn unspanned(s: &str) -> Result<PreLabelInstruction, Error> {
+        parse_instruction(s, &FULLREG, false).map_err(|e| match e {
+            Error::Span(e, _) => *e,
+            e => e,
+        })
+    }
+
+    #[test]
+    fn test_parse_text() {
+        assert_eq!(
+            parse_instruction("add s0, s0, s1,,,, ", &FULLREG, false).map_err(|_| ()),
+            Ok(Add(8, 8, 9).into())
+        );
+        assert_eq!(
+            parse_instruction("mulhsu a0, a1, a2", &FULLREG, false).map_err(|_| ()),
+            Ok(Mulhsu(10, 11, 12).into())
+        );
+        assert_eq!(
+            parse_instruction("j label", &FULLREG, false).map_err(|_| ()),
+            Ok(pre::Jal(0, "label".to_owned()))
+        );
+        assert_eq!(
+            parse_instruction("bgtz x1 somewhere", &FULLREG, false).map_err(|_| ()),
+            Ok(pre::Blt(0, 1, "somewhere".to_owned()))
+        );
+        assert_eq!(
+            parse_instruction("lw t1, matrix+16(t2)", &FULLREG, false).map_err(|_| ()),
+            Ok(pre::LabelImm(Lw(6, 0, 7), "matrix+16".to_owned()))
+        );
+        assert_eq!(
+            parse_instruction("fsw ft1, -4(sp)", &FULLREG, false).map_err(|_| ()),
+            Ok(FloatInstruction::Sw(1, (-4i32) as u32, 2).into())
+        );
+        assert_eq!(
+            parse_instruction("li t0, (1<<12)-1", &FULLREG, false).map_err(|_| ()),
+            Ok(Li(5, 4095).into())
+        );
+        assert_eq!(
+            parse_instruction("li t0, end - start", &FULLREG, false).map_err(|_| ()),
+            Ok(pre::LabelImm(Li(5, 0), "end - start".to_owned()))
+        );
+        assert_eq!(
+            parse_instruction("addi sp, sp, -4 * 4", &FULLREG, false).map_err(|_| ()),
+            Ok(Addi(2, 2, (-16i32) as u32).into())
+        );
+        assert_eq!(
+            parse_instruction("j loop + 8", &FULLREG, false).map_err(|_| ()),
+            Ok(pre::Jal(0, "loop + 8".to_owned()))
+        );
+        assert_eq!(parse_instruction("ebreak", &FULLREG, false).map_err(|_| ()), Ok(Ebreak.into()));
+        assert_eq!(
+            parse_instruction("fadd.s ft0 ft1 ft2 dyn", &FULLREG, false).map_err(|_| ()),
+            Ok(FloatInstruction::Add(0, 1, 2, 7).into())
+        );
+        assert_eq!(
+            parse_instruction("fcvt.w.s a0, ft0, rtz", &FULLREG, false).map_err(|_| ()),
+            Ok(FloatInstruction::CvtWS(10, 0, 1).into())
+        );
+        assert!(parse_instruction("fadd.s ft0, ft1, ft2, rtx", &FULLREG, false).is_err());
+        assert_eq!(
+            parse_instruction("fnmsub.d fa0, fa1, fa2, fa3, rdn", &FULLREG, false).map_err(|_| ()),
+            Ok(FloatInstruction::NMsubD(10, 11, 12, 13, 2).into())
+        );
+        assert_eq!(
+            parse_instruction("fmv.x.w a0, fa0", &FULLREG, false).map_err(|_| ()),
+            Ok(FloatInstruction::MvXS(10, 10).into())
+        );
+        assert_eq!(
+            parse_instruction("fsrm t0, t1", &FULLREG, false).map_err(|_| ()),
+            Ok(CsrRw(5, FRM_INDEX, 6).into())
+        );
+        assert_eq!(
+            parse_instruction("fsflagsi 0", &FULLREG, false).map_err(|_| ()),
+            Ok(CsrRwi(0, FFLAGS_INDEX, 0).into())
+        );
+        assert_eq!(
+            parse_instruction("csrrw ra instret, sp", &FULLREG, false).map_err(|_| ()),
+            Ok(CsrRw(1, FULLREG.2["instret"], 2).into())
+        );
+        assert_eq!(
+            parse_instruction("csrr x15 time", &FULLREG, false).map_err(|_| ()),
+            Ok(CsrRs(15, FULLREG.2["time"], 0).into())
+        );
+        assert_eq!(
+            parse_instruction("csrr t0, 0xC00", &FULLREG, false).map_err(|_| ()),
+            Ok(CsrRs(5, FULLREG.2["cycle"], 0).into())
+        );
+        assert_eq!(
+            parse_instruction("csrrsi t0, 0b11, 1", &FULLREG, false).map_err(|_| ()),
+            Ok(CsrRsi(5, FCSR_INDEX, 1).into())
+        );
+        assert!(parse_instruction("csrr t0, 0x7ff", &FULLREG, false).is_err());
+        assert_eq!(
+            parse_instruction("sh2add a0, a1, a0", &FULLREG, false).map_err(|_| ()),
+            Ok(Sh2add(10, 11, 10).into())
+        );
+        assert_eq!(
+            parse_instruction("czero.nez a0, a1, a2", &FULLREG, false).map_err(|_| ()),
+            Ok(CzeroNez(10, 11, 12).into())
+        );
+        assert_eq!(
+            parse_instruction("rdinstreth a1", &FULLREG, false).map_err(|_| ()),
+            Ok(CsrRs(11, INSTRETH_INDEX, 0).into())
+        );
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let parse = |s| {
+            let mut code = Vec::new();
+            parse_line(s, &FULLREG, false, &mut code).map(|_| code).map_err(|_| ())
+        };
+
+        assert_eq!(parse("lw t0, (sp)"), Ok(vec![Lw(5, 0, 2).into()]));
+        assert_eq!(parse("lhu t0, -0x4(sp)"), Ok(vec![Lhu(5, (-4i32) as u32, 2).into()]));
+        assert_eq!(
+            parse("lb a0, label"),
+            Ok(vec![pre::PcRelHi(10, "label".to_owned()), pre::PcRelLo(Lb(10, 0, 10), "label".to_owned())])
+        );
+        assert_eq!(parse("sw t0, label"), Err(()));
+        assert_eq!(parse("li t0, -2048"), Ok(vec![Li(5, (-2048i32) as u32).into()]));
+        assert_eq!(parse("li t0, 0x12345000"), Ok(vec![Li(5, 0x1234_5000).into()]));
+        assert_eq!(
+            parse("li t0, 0x12345fff"),
+            Ok(vec![Li(5, 0x1234_6000).into(), Addi(5, 5, (-1i32) as u32).into()])
+        );
+        assert_eq!(
+            parse("li t0, end - start"),
+            Ok(vec![pre::Hi(5, "end - start".to_owned()), pre::Lo(Addi(5, 5, 0), "end - start".to_owned())])
+        );
+        assert_eq!(
+            parse("sh a0, label+2, t1"),
+            Ok(vec![pre::PcRelHi(6, "label+2".to_owned()), pre::PcRelLo(Sh(10, 0, 6), "label+2".to_owned())])
+        );
+        assert_eq!(
+            parse("flw ft0, label, t1"),
+            Ok(vec![pre::PcRelHi(6, "label".to_owned()), pre::PcRelLo(FloatInstruction::Lw(0, 0, 6).into(), "label".to_owned())])
+        );
+        assert_eq!(parse("fsw ft0, label"), Err(()));
+        assert_eq!(parse("fld fa0, 8(sp)"), Ok(vec![FloatInstruction::Ld(10, 8, 2).into()]));
+        assert_eq!(
+            parse("fsd fa0, label, t0"),
+            Ok(vec![pre::PcRelHi(5, "label".to_owned()), pre::PcRelLo(FloatInstruction::Sd(10, 0, 5).into(), "label".to_owned())])
+        );
+    }
+
+    #[test]
+    fn test_label_access() {
+        use crate::simulator::Simulator;
+
+        // Stores through `t1`, so `t0` keeps its value
+        let code = ".data\nx: .word 0\nf: .float 0\n.text\nli t0, 42\nsw t0, x, t1\nlw a0, x\nadd a0, a0, t0
+            li t2, 3\nfcvt.s.w ft0, t2\nfsw ft0, f, t1\nflw ft1, f, t1\nfcvt.w.s a1, ft1
+            add a0, a0, a1\nli a7, 93\necall";
+        let mut sim = Simulator::new().load_str(code).unwrap();
+        assert_eq!(sim.run(), 42 + 42 + 3);
+    }
+
+    #[test]
+    fn test_calls() {
+        // Only linking tells whether they're too far for a `jal`
+        let parse = |s| parse_instruction(s, &FULLREG, false).map_err(|_| ());
+        assert_eq!(parse("call far_away"), Ok(pre::Call(1, "far_away".to_owned())));
+        assert_eq!(parse("tail far_away"), Ok(pre::Call(0, "far_away".to_owned())));
+        assert_eq!(parse("j far_away"), Ok(pre::Jal(0, "far_away".to_owned())));
+        assert_eq!(parse("jr t0"), Ok(Jalr(0, 5, 0).into()));
+    }
+
+    #[test]
+    fn test_unary_pseudoinstructions() {
+        let parse = |s| parse_instruction(s, &FULLREG, false).map_err(|_| ());
+        assert_eq!(parse("not t0, t1"), Ok(Xori(5, 6, 0xFFFF_FFFF).into()));
+        assert_eq!(parse("neg t0, t1"), Ok(Sub(5, 0, 6).into()));
+        assert_eq!(parse("seqz t0, t1"), Ok(Sltiu(5, 6, 1).into()));
+        assert_eq!(parse("snez t0, t1"), Ok(Sltu(5, 0, 6).into()));
+        assert_eq!(parse("sltz t0, t1"), Ok(Slt(5, 6, 0).into()));
+        assert_eq!(parse("sgtz t0, t1"), Ok(Slt(5, 0, 6).into()));
+    }
+
+    #[test]
+    fn test_branch_zero() {
+        let parse = |s| parse_instruction(s, &FULLREG, false).map_err(|_| ());
+        let label = || "label".to_owned();
+        assert_eq!(parse("beqz t0, label"), Ok(pre::Beq(5, 0, label())));
+        assert_eq!(parse("bnez t0, label"), Ok(pre::Bne(5, 0, label())));
+        assert_eq!(parse("bltz t0, label"), Ok(pre::Blt(5, 0, label())));
+        assert_eq!(parse("bgez t0, label"), Ok(pre::Bge(5, 0, label())));
+        assert_eq!(parse("bgtz t0, label"), Ok(pre::Blt(0, 5, label())));
+        assert_eq!(parse("blez t0, label"), Ok(pre::Bge(0, 5, label())));
+    }
+
+    #[test]
+    fn test_pseudoinstructions() {
+        let parse = |s| parse_instruction(s, &FULLREG, false).map_err(|_| ());
+
+        // `li` takes any 32-bit constant, there's no need to split it into `lui` + `addi`
+        assert_eq!(parse("li t0, 0xFFFFFFFF"), Ok(Li(5, 0xFFFF_FFFF).into()));
+        assert_eq!(parse("li t0, -2147483648"), Ok(Li(5, 0x8000_0000).into()));
+        assert_eq!(parse("li t0, 0x12345678"), Ok(Li(5, 0x1234_5678).into()));
+        assert_eq!(parse("li t0, 4294967296"), Err(()));
+
+        assert_eq!(parse("li t0, 'A'"), Ok(Li(5, 65).into()));
+        assert_eq!(parse("li t0, '\\n'"), Ok(Li(5, 10).into()));
+        assert_eq!(parse("addi t0, t0, '0'"), Ok(Addi(5, 5, 48).into()));
+        assert_eq!(parse("addi t0, t0, 'a' - 'A'"), Ok(Addi(5, 5, 32).into()));
+        assert_eq!(parse("li t0, ','"), Ok(Li(5, 44).into()));
+
+        assert_eq!(parse("mv a0, t2"), Ok(Mv(10, 7).into()));
+        assert_eq!(parse("la a1, here"), Ok(pre::PcRelHi(11, "here".to_owned())));
+    }
+
+    #[test]
+    fn test_fence() {
+        let parse = |s| parse_instruction(s, &FULLREG, false).map_err(|_| ());
+
+        assert_eq!(parse("fence"), Ok(Fence(0xF, 0xF).into()));
+        assert_eq!(parse("fence rw, w"), Ok(Fence(0b0011, 0b0001).into()));
+        assert_eq!(parse("fence.i"), Ok(FenceI.into()));
+        assert_eq!(parse("fence.i t0"), Err(()));
+        assert_eq!(parse("fence rw"), Err(()));
+        assert_eq!(parse("fence io r"), Ok(Fence(0b1100, 0b0010).into()));
+        assert_eq!(parse("fence 1, 0b1000"), Ok(Fence(1, 8).into()));
+        assert_eq!(parse("fence wr, r"), Err(()));
+        assert_eq!(parse("fence 16, r"), Err(()));
+    }
+
+    #[test]
+    fn test_atomics() {
+        let parse = |s| parse_instruction(s, &FULLREG, false).map_err(|_| ());
+
+        assert_eq!(parse("lr.w t0, (a0)"), Ok(LrW(5, 10).into()));
+        assert_eq!(parse("sc.w t1, t2, (a0)"), Ok(ScW(6, 7, 10).into()));
+        assert_eq!(parse("amoadd.w t0, t1, 0(a0)"), Ok(AmoAddW(5, 6, 10).into()));
+        assert_eq!(parse("amoswap.w.aqrl zero, t1, (a0)"), Ok(AmoSwapW(0, 6, 10).into()));
+        assert_eq!(parse("LR.W.AQ t0, (sp)"), Ok(LrW(5, 2).into()));
+        assert_eq!(parse("amomaxu.w.rl a0, a1, (a2)"), Ok(AmoMaxuW(10, 11, 12).into()));
+        assert_eq!(parse("amoadd.w t0, t1, 4(a0)"), Err(()));
+        assert_eq!(parse("lr.w t0, a0"), Err(()));
+        assert_eq!(parse("add.aq t0, t1, t2"), Err(()));
+    }
+
+    #[test]
+    fn test_vectors() {
+        use VectorInstruction as V;
+        let parse = |s| parse_instruction(s, &FULLREG, false).map_err(|_| ());
+
+        assert_eq!(parse("vsetvli t0, a0, e32, m1, ta, ma"), Ok(V::Setvli(5, 10, 0xd0).into()));
+        assert_eq!(parse("vsetvli zero, zero, e32, m4"), Ok(V::Setvli(0, 0, 0x12).into()));
+        assert_eq!(parse("vsetvli t0, a0, e8, mf2, tu, ma"), Ok(V::Setvli(5, 10, 0x87).into()));
+        assert_eq!(parse("vle32.v v1, (a0)"), Ok(V::Le32(1, 10).into()));
+        assert_eq!(parse("vse32.v v31, 0(sp)"), Ok(V::Se32(31, 2).into()));
+        assert_eq!(parse("vadd.vv v1, v2, v3"), Ok(V::Add(1, 2, 3).into()));
+        assert_eq!(parse("vredsum.vs v0, v8, v0"), Ok(V::RedSum(0, 8, 0).into()));
+        assert_eq!(parse("vmv.x.s a0, v0"), Ok(V::MvXS(10, 0).into()));
+        assert_eq!(parse("vmv.s.x v0, zero"), Ok(V::MvSX(0, 0).into()));
+        assert_eq!(parse("vsetvli t0, a0, e32"), Err(()));
+        assert_eq!(parse("vsetvli t0, a0, e32, m3"), Err(()));
+        assert_eq!(parse("vsetvli t0, a0, e32, m1, ma, ta"), Err(()));
+        assert_eq!(parse("vadd.vv v1, v2, v32"), Err(()));
+        assert_eq!(parse("vadd.vv v1, v2, v3, v0.t"), Err(()));
+        assert_eq!(parse("vle32.v v1, 4(a0)"), Err(()));
+    }
+
+    #[test]
+    fn test_rv64() {
+        let parse = |s| parse_instruction(s, &FULLREG, true).map_err(|_| ());
+        assert_eq!(parse("addw t0, t1, t2"), Ok(Rv64Instruction::Addw(5, 6, 7).into()));
+        assert_eq!(parse("add t0, t1, t2"), Ok(Rv64Instruction::Add(5, 6, 7).into()));
+        assert_eq!(parse("slli t0, t0, 40"), Ok(Rv64Instruction::Slli(5, 5, 40).into()));
+        assert_eq!(parse("slliw t0, t0, 32"), Err(()));
+        assert_eq!(parse("ld a0, -8(sp)"), Ok(Rv64Instruction::Ld(10, (-8i32) as u32, 2).into()));
+        assert_eq!(parse("sd a0, 0(sp)"), Ok(Rv64Instruction::Sd(10, 0, 2).into()));
+        assert_eq!(parse("negw t0, t1"), Ok(Rv64Instruction::Subw(5, 0, 6).into()));
+        assert_eq!(parse("sext.w t0, t1"), Ok(Rv64Instruction::Addiw(5, 6, 0).into()));
+        assert_eq!(parse("lw t0, 0(sp)"), Ok(Lw(5, 0, 2).into()));
+
+        assert!(matches!(unspanned("addw t0, t1, t2"), Err(Error::NotInRv32(name)) if name == "addw"));
+
+        let mut code = Vec::new();
+        parse_line("li t0, 0x12345fff", &FULLREG, true, &mut code).unwrap();
+        assert_eq!(
+            code,
+            vec![Li(5, 0x1234_6000).into(), Rv64Instruction::Addiw(5, 5, (-1i32) as u32).into()]
+        );
+
+        // Values that don't fit in 32 bits are shifted into place
+        let li = |s| {
+            let mut code = Vec::new();
+            parse_line(s, &FULLREG, true, &mut code).map(|_| code).map_err(|_| ())
+        };
+        assert_eq!(li("li t0, -1"), Ok(vec![Li(5, u32::MAX).into()]));
+        assert_eq!(
+            li("li t0, 0xffffffff"),
+            Ok(vec![
+                Li(5, 1).into(),
+                Rv64Instruction::Slli(5, 5, 32).into(),
+                Rv64Instruction::Addi(5, 5, (-1i32) as u32).into(),
+            ])
+        );
+        assert_eq!(
+            li("li t0, 1 << 40"),
+            Ok(vec![Li(5, 1).into(), Rv64Instruction::Slli(5, 5, 40).into()])
+        );
+        assert_eq!(
+            li("li t0, 0x123456789abcdef0"),
+            Ok(vec![
+                Li(5, 0x247000).into(),
+                Rv64Instruction::Addiw(5, 5, (-0x753i32) as u32).into(),
+                Rv64Instruction::Slli(5, 5, 14).into(),
+                Rv64Instruction::Addi(5, 5, (-0x3b3i32) as u32).into(),
+                Rv64Instruction::Slli(5, 5, 12).into(),
+                Rv64Instruction::Addi(5, 5, 0x5e7).into(),
+                Rv64Instruction::Slli(5, 5, 13).into(),
+                Rv64Instruction::Addi(5, 5, (-0x110i32) as u32).into(),
+            ])
+        );
+        assert!(li("li t0, 0x10000000000000000").is_err());
+        // Only RV64 has room for them
+        let mut code = Vec::new();
+        assert!(parse_line("li t0, 1 << 40", &FULLREG, false, &mut code).is_err());
+    }
+
+    #[test]
+    fn test_rv32e() {
+        let regmaps = (reg_names::rv32e_regs(), reg_names::floats(), reg_names::status());
+        let parse = |s| parse_instruction(s, &regmaps, false);
+
+        assert_eq!(parse("add a5, x15, s1").map_err(|_| ()), Ok(Add(15, 15, 9).into()));
+        assert_eq!(parse("fadd.s ft0, fs11, ft0, dyn").map_err(|_| ()), Ok(FloatInstruction::Add(0, 27, 0, 7).into()));
+        for s in &["add a6, a0, a1", "lw t0, 0(x16)", "mv a0, t6"] {
+            match parse(s) {
+                Err(Error::Span(e, _)) => assert!(matches!(*e, Error::NotInRv32E(_)), "{}: {:?}", s, e),
+                res => panic!("`{}` should use a register RV32E doesn't have, got {:?}", s, res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_upper_immediates() {
+        let parse = |s| parse_instruction(s, &FULLREG, false).map_err(|_| ());
+        assert_eq!(parse("lui t0, 0x12345"), Ok(Li(5, 0x1234_5000).into()));
+        assert_eq!(parse("lui t0, 0xFFFFF"), Ok(Li(5, 0xFFFF_F000).into()));
+        assert_eq!(parse("lui t0, -1"), Ok(Li(5, 0xFFFF_F000).into()));
+        assert_eq!(parse("auipc t0, 1"), Ok(Auipc(5, 0x1000).into()));
+        assert_eq!(parse("auipc t0, -0x80000"), Ok(Auipc(5, 0x8000_0000).into()));
+        assert!(matches!(
+            unspanned("lui t0, 0x100000"),
+            Err(Error::FieldOutOfRange(..))
+        ));
+        assert!(matches!(
+            unspanned("auipc t0, -0x80001"),
+            Err(Error::FieldOutOfRange(..))
+        ));
+    }
+
+    #[test]
+    fn test_jalr() {
+        let parse = |s| parse_instruction(s, &FULLREG, false).map_err(|_| ());
+        assert_eq!(parse("jalr t0, t1, 8"), Ok(Jalr(5, 6, 8).into()));
+        assert_eq!(parse("jalr t0, -4(t1)"), Ok(Jalr(5, 6, (-4i32) as u32).into()));
+        assert_eq!(parse("jalr t0, (t1)"), Ok(Jalr(5, 6, 0).into()));
+        assert_eq!(parse("jalr zero, t1"), Ok(Jalr(0, 6, 0).into()));
+        assert_eq!(parse("jalr t1"), Ok(Jalr(1, 6, 0).into()));
+        assert_eq!(
+            parse("jalr t0, t1, end - start"),
+            Ok(pre::LabelImm(Jalr(5, 6, 0), "end - start".to_owned()))
+        );
+        assert_eq!(parse("ret"), Ok(Ret.into()));
+    }
+
+    #[test]
+    fn test_shift_amount() {
+        assert_eq!(parse_instruction("slli t0 t0 31", &FULLREG, false).map_err(|_| ()), Ok(Slli(5, 5, 31).into()));
+        assert_eq!(parse_instruction("srai t0 t0 0", &FULLREG, false).map_err(|_| ()), Ok(Srai(5, 5, 0).into()));
+        assert!(matches!(
+            unspanned("srli t0 t0 32"),
+            Err(Error::FieldOutOfRange(imm, 0, 31)) if imm == "32"
+        ));
+        assert!(matches!(
+            unspanned("slli t0 t0 -1"),
+            Err(Error::FieldOutOfRange(imm, 0, 31)) if imm == "-1"
+        ));
+        assert_eq!(parse_instruction("bseti t0 t0 31", &FULLREG, false).map_err(|_| ()), Ok(Bseti(5, 5, 31).into()));
+        assert!(matches!(unspanned("bexti t0 t0 32"), Err(Error::FieldOutOfRange(..))));
+    }
+
+    #[test]
+    fn test_immediate_range() {
+        let parse = unspanned;
+        let out_of_range = |s| match parse(s) {
+            Err(Error::FieldOutOfRange(imm, min, max)) => (imm, min, max),
+            res => panic!("`{}` should be out of range, got {:?}", s, res),
+        };
+
+        assert!(parse("addi t0, t0, 2047").is_ok());
+        assert!(parse("addi t0, t0, -2048").is_ok());
+        assert!(parse("andi t0, t0, -1").is_ok());
+        assert!(parse("not t0, t1").is_ok());
+        assert_eq!(out_of_range("addi t0, t0, 0x12345"), ("74565".to_owned(), -2048, 2047));
+        assert_eq!(out_of_range("slti t0, t0, -2049"), ("-2049".to_owned(), -2048, 2047));
+        assert_eq!(out_of_range("andi t0, t0, 0xfff").0, "4095");
+        assert_eq!(out_of_range("lw t0, 4096(sp)").0, "4096");
+        assert_eq!(out_of_range("sb t0, -3000(sp)").0, "-3000");
+        assert_eq!(out_of_range("flw ft0, 2048(sp)").0, "2048");
+        assert_eq!(out_of_range("jalr ra, t0, 5000").0, "5000");
+        assert_eq!(out_of_range("csrrwi t0, utvec, 32"), ("32".to_owned(), 0, 31));
+
+        // An expression with labels is only known when linking, and `li` takes anything
+        assert!(parse("addi t0, t0, end - start").is_ok());
+        assert!(parse("li t0, 0x12345678").is_ok());
+    }
+
+    #[test]
+    fn test_unknown_instruction() {
+        let suggestion = |s| match parse_instruction(s, &FULLREG, false) {
+            Err(Error::UnknownInstruction(_, suggestion)) => suggestion,
+            _ => panic!("`{}` shouldn't be an instruction", s),
+        };
+
+        assert_eq!(suggestion("addd t0 t0 t0"), Some("add".to_owned()));
+        assert_eq!(suggestion("ADDII t0 t0 1"), Some("addi".to_owned()));
+        assert_eq!(suggestion("bnqe t0 t1 label"), Some("bne".to_owned()));
+        assert_eq!(suggestion("fadd.q ft0 ft1 ft2"), Some("fadd.s".to_owned()));
+        assert_eq!(suggestion("whatever"), None);
+
+        for instruction in INSTRUCTIONS {
+            assert!(
+                !matches!(parse_instruction(instruction, &FULLREG, false), Err(Error::UnknownInstruction(..))),
+                "`{}` is in INSTRUCTIONS but we don't know it",
+                instruction
+            );
+        }
+        for instruction in RV64_INSTRUCTIONS {
+            assert!(
+                !matches!(parse_instruction(instruction, &FULLREG, true), Err(Error::UnknownInstruction(..))),
+                "`{}` is in RV64_INSTRUCTIONS but we don't know it",
+                instruction
+            );
+        }
+    }
+}
@@ -0,0 +1,554 @@
+//!
+//! Splits an instruction into tokens that remember where they are in the line, and parses its
+//! operands from them. The errors point at the tokens they're about, so the caret goes right under
+//! the `t9` of `add t0, t1, t9`, or under the whole `3000 + 2000` of an immediate that doesn't fit.
+//!
+
+use nom::combinator::all_consuming;
+use std::ops::Range;
+
+use super::super::{
+    combinators::{expr, expr_to_u32, integer_literal, is_separator, is_symbol_char, quoted_len},
+    register_names::{RegMap, TryGetRegister},
+    util::Error,
+    DYNAMIC_ROUNDING,
+};
+use super::ROUNDING_MODES;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Kind {
+    /// A mnemonic, register, label or number, like `fcvt.s.w`, `t0`, `.L1$0` or `0x1F`
+    Word,
+    /// A character or a string, with its quotes, like `'a'`. An unclosed one goes to the end of the line.
+    Quoted,
+    Comma,
+    Open,
+    Close,
+    /// An operator of an expression, like `+` or `<<`
+    Operator,
+    /// Anything else, which is always an error
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Token<'a> {
+    pub kind: Kind,
+    pub text: &'a str,
+    /// Where it is in the line, in bytes
+    pub span: Range<usize>,
+}
+
+/// Splits `line` into tokens. Whitespace only separates them, so it isn't kept.
+pub(super) fn tokenize(line: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+
+    while let Some(c) = line[start..].chars().next() {
+        let rest = &line[start..];
+        let (kind, len) = match c {
+            c if c.is_whitespace() => {
+                start += c.len_utf8();
+                continue;
+            }
+            '\'' | '"' => (Kind::Quoted, quoted_len(rest, c)),
+            ',' => (Kind::Comma, 1),
+            '(' => (Kind::Open, 1),
+            ')' => (Kind::Close, 1),
+            _ if rest.starts_with("<<") || rest.starts_with(">>") => (Kind::Operator, 2),
+            c if "+-*/%&|^~".contains(c) => (Kind::Operator, 1),
+            c if is_symbol_char(c) => (Kind::Word, rest.find(|c| !is_symbol_char(c)).unwrap_or(rest.len())),
+            c => (Kind::Other, c.len_utf8()),
+        };
+
+        tokens.push(Token {
+            kind,
+            text: &rest[..len],
+            span: start..start + len,
+        });
+        start += len;
+    }
+
+    tokens
+}
+
+/// An immediate, or an expression with labels that's only known after the whole file is parsed.
+/// Immediates have 32 bits, except for the ones read by [wide_value](struct.Operands.html#method.wide_value).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Value<T = u32> {
+    Imm(T),
+    Label(String),
+}
+
+/// The operands of an instruction, read from left to right. Like in RARS, commas between them are
+/// optional, and extra ones are ignored.
+pub(super) struct Operands<'a> {
+    line: &'a str,
+    tokens: Vec<Token<'a>>,
+    /// The next token to be read
+    next: usize,
+    /// Where the last immediate was, to point at it if it doesn't fit in its instruction
+    imm_span: Option<Range<usize>>,
+}
+
+impl<'a> Operands<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Self {
+            line,
+            tokens: tokenize(line),
+            next: 0,
+            imm_span: None,
+        }
+    }
+
+    /// The next token that isn't a comma, without reading it
+    fn peek(&mut self) -> Option<&Token<'a>> {
+        while self.tokens.get(self.next).is_some_and(|t| t.kind == Kind::Comma) {
+            self.next += 1;
+        }
+        self.tokens.get(self.next)
+    }
+
+    /// An error for the token at `index`, or for the end of the line if there's none
+    fn unexpected(&self, index: usize) -> Error {
+        let start = self.tokens.get(index).map_or(self.line.len(), |t| t.span.start);
+        let end = self.tokens.get(index).map_or(start, |t| t.span.end);
+        Error::Nom(self.line[start..].to_owned()).span(start..end)
+    }
+
+    /// Reads the name of the instruction, which is everything up to the first separator or
+    /// parenthesis. It's only checked later, to say what instruction it looks like.
+    pub fn mnemonic(&mut self) -> Result<&'a str, Error> {
+        let end = self.line.find(|c| is_separator(c) || c == '(').unwrap_or(self.line.len());
+        if end == 0 {
+            return Err(self.unexpected(0));
+        }
+        while self.tokens.get(self.next).is_some_and(|t| t.span.start < end) {
+            self.next += 1;
+        }
+        Ok(&self.line[..end])
+    }
+
+    /// Reads a single word, like a rounding mode
+    fn word(&mut self) -> Result<&'a str, Error> {
+        match self.peek() {
+            Some(&Token { kind: Kind::Word, text, .. }) => {
+                self.next += 1;
+                Ok(text)
+            }
+            _ => Err(self.unexpected(self.next)),
+        }
+    }
+
+    /// Reads a register, with one of the names in `regs`
+    pub fn reg(&mut self, regs: &RegMap) -> Result<u8, Error> {
+        let (text, span) = match self.peek() {
+            Some(Token { kind: Kind::Word, text, span }) => (*text, span.clone()),
+            _ => return Err(self.unexpected(self.next)),
+        };
+        let reg = regs.try_get(text).map_err(|e| e.span(span))?;
+        self.next += 1;
+        Ok(reg)
+    }
+
+    /// Reads a CSR, either by its name in `status` or by its number in any base, like `0xC00`
+    pub fn csr(&mut self, status: &RegMap) -> Result<u8, Error> {
+        let (text, span) = match self.peek() {
+            Some(Token { kind: Kind::Word, text, span }) => (*text, span.clone()),
+            _ => return Err(self.unexpected(self.next)),
+        };
+        // The numbers are in `status` in decimal
+        let key = integer_literal(text).map_or_else(|_| text.to_owned(), |x| x.to_string());
+        let csr = status
+            .try_get(&key)
+            .map_err(|_| Error::RegisterNotFound(text.to_owned()).span(span))?;
+        self.next += 1;
+        Ok(csr)
+    }
+
+    /// Where the operand that starts at the token `start` ends: at a comma that isn't inside
+    /// parentheses, or at the end of the line
+    fn operand_end(&self, start: usize) -> usize {
+        let mut depth = 0usize;
+        for (i, token) in self.tokens.iter().enumerate().skip(start) {
+            match token.kind {
+                Kind::Open => depth += 1,
+                Kind::Close => depth = depth.saturating_sub(1),
+                Kind::Comma if depth == 0 => return i,
+                _ => {}
+            }
+        }
+        self.tokens.len()
+    }
+
+    /// Parses the tokens in `range` as an expression whose magnitude fits in 32 bits
+    fn value_in(&mut self, range: Range<usize>) -> Result<Value, Error> {
+        match self.wide_value_in(range)? {
+            Value::Imm(x) => {
+                let span = self.imm_span.clone().unwrap_or_default();
+                expr_to_u32(x, &self.line[span.clone()]).map(Value::Imm).map_err(|e| e.span(span))
+            }
+            Value::Label(label) => Ok(Value::Label(label)),
+        }
+    }
+
+    /// Parses the tokens in `range` as an expression
+    fn wide_value_in(&mut self, range: Range<usize>) -> Result<Value<i64>, Error> {
+        if range.is_empty() || range.end > self.tokens.len() {
+            return Err(self.unexpected(range.start));
+        }
+
+        let span = self.tokens[range.start].span.start..self.tokens[range.end - 1].span.end;
+        let text = &self.line[span.clone()];
+        self.imm_span = Some(span.clone());
+
+        let parsed = match all_consuming(expr)(text) {
+            Ok((_, parsed)) => parsed,
+            Err(e) => {
+                // A malformed number says what's wrong with it
+                let malformed = self.tokens[range.clone()]
+                    .iter()
+                    .filter(|t| t.kind == Kind::Word && t.text.starts_with(|c: char| c.is_ascii_digit()))
+                    .find_map(|t| integer_literal(t.text).err().map(|e| e.span(t.span.clone())));
+                if let Some(error) = malformed {
+                    return Err(error);
+                }
+
+                let rest = match e {
+                    nom::Err::Error((rest, _)) | nom::Err::Failure((rest, _)) => rest,
+                    nom::Err::Incomplete(_) => text,
+                };
+                let start = span.start + text.len() - rest.len();
+                let index = self.tokens.iter().position(|t| t.span.start >= start).unwrap_or(self.tokens.len());
+                return Err(self.unexpected(index));
+            }
+        };
+
+        if parsed.has_symbols() {
+            return Ok(Value::Label(text.to_owned()));
+        }
+        let x = parsed.eval_constant().map_err(|e| e.span(span))?;
+        Ok(Value::Imm(x))
+    }
+
+    /// Reads an immediate, or an expression with labels, which goes up to the next comma
+    pub fn value(&mut self) -> Result<Value, Error> {
+        self.peek();
+        let end = self.operand_end(self.next);
+        let value = self.value_in(self.next..end)?;
+        self.next = end;
+        Ok(value)
+    }
+
+    /// Like [value](#method.value), but the immediate can have 64 bits, for `li` in RV64
+    pub fn wide_value(&mut self) -> Result<Value<i64>, Error> {
+        self.peek();
+        let end = self.operand_end(self.next);
+        let value = self.wide_value_in(self.next..end)?;
+        self.next = end;
+        Ok(value)
+    }
+
+    /// Reads an immediate, which can't have labels
+    pub fn imm(&mut self) -> Result<u32, Error> {
+        match self.value()? {
+            Value::Imm(x) => Ok(x),
+            Value::Label(label) => {
+                let span = self.imm_span.clone().unwrap_or_default();
+                Err(Error::InvalidImmediate(label).span(span))
+            }
+        }
+    }
+
+    /// Reads a label, which can also be an expression with labels, like `array + 8`, or an address.
+    /// It's kept as written and evaluated once we know where every label is.
+    pub fn label(&mut self) -> Result<String, Error> {
+        self.value()?;
+        let span = self.imm_span.clone().unwrap_or_default();
+        Ok(self.line[span].to_owned())
+    }
+
+    /// Reads the rounding mode at the end of a float instruction, like `rtz`. Without one, the
+    /// instruction rounds like `frm` says, which is the same as writing `dyn`.
+    pub fn rounding_mode(&mut self) -> Result<u8, Error> {
+        if self.is_empty() {
+            return Ok(DYNAMIC_ROUNDING);
+        }
+
+        let index = self.next;
+        let word = self.word()?.to_lowercase();
+        match ROUNDING_MODES.iter().position(|&mode| mode == word) {
+            Some(rm) => Ok(rm as u8),
+            None if word == "dyn" => Ok(DYNAMIC_ROUNDING),
+            None => Err(self.unexpected(index)),
+        }
+    }
+
+    /// Reads one of the sets of a `fence`, either some of `iorw` in this order, like `rw`, or a
+    /// number, like `0b0011`. Each letter is a bit, from `i` in the highest to `w` in the lowest.
+    pub fn fence_set(&mut self) -> Result<u8, Error> {
+        let word = self.word()?;
+        let index = self.next - 1;
+
+        if let Ok(x) = integer_literal(word) {
+            return if x < 16 { Ok(x as u8) } else { Err(self.unexpected(index)) };
+        }
+
+        let mut rest = word;
+        let mut set = 0;
+        for (bit, letter) in "iorw".chars().enumerate() {
+            if let Some(after) = rest.strip_prefix(letter) {
+                set |= 8 >> bit;
+                rest = after;
+            }
+        }
+        if rest.is_empty() { Ok(set) } else { Err(self.unexpected(index)) }
+    }
+
+    /// Reads a vector register, `v0` to `v31`
+    pub fn vector_reg(&mut self) -> Result<u8, Error> {
+        let (text, span) = match self.peek() {
+            Some(Token { kind: Kind::Word, text, span }) => (*text, span.clone()),
+            _ => return Err(self.unexpected(self.next)),
+        };
+        let reg = text
+            .strip_prefix('v')
+            .and_then(|n| n.parse::<u8>().ok())
+            .filter(|&n| n < 32)
+            .ok_or_else(|| Error::RegisterNotFound(text.to_owned()).span(span))?;
+        self.next += 1;
+        Ok(reg)
+    }
+
+    /// Reads the type of a `vsetvli`, like `e32, m1, ta, ma`: the width of the elements, how many
+    /// registers are grouped together, and then the tail and mask policies, which can be left out
+    /// and are undisturbed by default. Returns it encoded as the `vtype` CSR.
+    pub fn vtype(&mut self) -> Result<u32, Error> {
+        let mut field = |options: &[&str], optional: bool| -> Result<Option<u32>, Error> {
+            if optional && self.is_empty() {
+                return Ok(None);
+            }
+            self.peek();
+            let index = self.next;
+            let word = self.word()?.to_lowercase();
+            match options.iter().position(|&option| option == word) {
+                Some(i) => Ok(Some(i as u32)),
+                None => Err(self.unexpected(index)),
+            }
+        };
+
+        let sew = field(&["e8", "e16", "e32", "e64"], false)?.unwrap_or(0);
+        // The fractional ones are 5 to 7, 4 is reserved
+        let lmul = field(&["m1", "m2", "m4", "m8", "", "mf8", "mf4", "mf2"], false)?.unwrap_or(0);
+        let ta = field(&["tu", "ta"], true)?.unwrap_or(0);
+        let ma = field(&["mu", "ma"], true)?.unwrap_or(0);
+        Ok(ma << 7 | ta << 6 | sew << 3 | lmul)
+    }
+
+    /// Reads a memory operand, like `-4(sp)`, `(sp)` or `matrix+16(t2)`. `regs` has the names of
+    /// the base register.
+    pub fn mem(&mut self, regs: &RegMap) -> Result<(Value, u8), Error> {
+        self.peek();
+        let mut depth = 0usize;
+        let mut end = self.tokens.len();
+        let mut close = None;
+        for (i, token) in self.tokens.iter().enumerate().skip(self.next) {
+            let after = self.tokens[i + 1..].iter().find(|t| t.kind != Kind::Comma).map(|t| t.kind);
+            match token.kind {
+                Kind::Open => depth += 1,
+                // The base register is in the last parentheses, the others are a part of the offset,
+                // like in `(1<<4)(t0)`
+                Kind::Close if depth == 1 && !matches!(after, Some(Kind::Open | Kind::Operator)) => {
+                    close = Some(i);
+                    break;
+                }
+                Kind::Close => depth = depth.saturating_sub(1),
+                // The offset may be apart from the base register, like in `' ', (x7)`
+                Kind::Comma if depth == 0 && after != Some(Kind::Open) => {
+                    end = i;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let close = match close {
+            Some(close) => close,
+            // Unless it's missing a parenthesis, it's something else entirely, like a label
+            None if end > self.next && !self.tokens[self.next..end].iter().any(|t| t.kind == Kind::Open) => {
+                return Err(self.unexpected(self.next))
+            }
+            None => return Err(self.unexpected(end)),
+        };
+        let open = match self.tokens[self.next..close].iter().rposition(|t| t.kind == Kind::Open) {
+            Some(open) => self.next + open,
+            None => return Err(self.unexpected(close)),
+        };
+
+        // Commas are fine in there too, like in `4( ,sp, )`
+        let mut inside = (open + 1..close).filter(|&i| self.tokens[i].kind != Kind::Comma);
+        let base = match (inside.next(), inside.next()) {
+            (Some(reg), None) if self.tokens[reg].kind == Kind::Word => {
+                let Token { text, span, .. } = &self.tokens[reg];
+                regs.try_get(text).map_err(|e| e.span(span.clone()))?
+            }
+            (Some(reg), Some(extra)) if self.tokens[reg].kind == Kind::Word => return Err(self.unexpected(extra)),
+            (Some(token), _) => return Err(self.unexpected(token)),
+            (None, _) => return Err(self.unexpected(close)),
+        };
+
+        let offset_end = self.tokens[..open].iter().rposition(|t| t.kind != Kind::Comma).map_or(0, |i| i + 1);
+        let offset = match offset_end > self.next {
+            true => self.value_in(self.next..offset_end)?,
+            false => Value::Imm(0),
+        };
+        self.next = close + 1;
+        Ok((offset, base))
+    }
+
+    /// Reads the address of an atomic instruction, which is a register in parentheses, like
+    /// `(t0)`. An offset of `0` is also fine, as GNU as accepts it.
+    pub fn atomic_address(&mut self, regs: &RegMap) -> Result<u8, Error> {
+        self.peek();
+        let start = self.next;
+        match self.mem(regs)? {
+            (Value::Imm(0), base) => Ok(base),
+            _ => Err(self.unexpected(start)),
+        }
+    }
+
+    /// Whether there's nothing left but commas
+    pub fn is_empty(&mut self) -> bool {
+        self.peek().is_none()
+    }
+
+    /// Fails if there's something left
+    pub fn end(&mut self) -> Result<(), Error> {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(self.unexpected(self.next)),
+        }
+    }
+
+    /// Tries to read the operands with `parse`, and goes back to where it was if it fails, so
+    /// another form of the instruction can be tried
+    pub fn attempt<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        let next = self.next;
+        let parsed = parse(self).and_then(|x| self.end().map(|_| x));
+        if parsed.is_err() {
+            self.next = next;
+        }
+        parsed
+    }
+
+    /// Points `e` at the last immediate that was read
+    pub fn at_imm(&self, e: Error) -> Error {
+        match &self.imm_span {
+            Some(span) => e.span(span.clone()),
+            None => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::register_names as reg_names;
+
+    #[test]
+    fn test_tokenize() {
+        let tokens: Vec<_> = tokenize("lw t1, -4*2(sp) # '('")
+            .into_iter()
+            .map(|t| (t.kind, t.text, t.span))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (Kind::Word, "lw", 0..2),
+                (Kind::Word, "t1", 3..5),
+                (Kind::Comma, ",", 5..6),
+                (Kind::Operator, "-", 7..8),
+                (Kind::Word, "4", 8..9),
+                (Kind::Operator, "*", 9..10),
+                (Kind::Word, "2", 10..11),
+                (Kind::Open, "(", 11..12),
+                (Kind::Word, "sp", 12..14),
+                (Kind::Close, ")", 14..15),
+                (Kind::Other, "#", 16..17),
+                (Kind::Quoted, "'('", 18..21),
+            ]
+        );
+        assert_eq!(tokenize("1<<4")[1].text, "<<");
+    }
+
+    /// Where the error points to, and what it is
+    fn span_of(e: Error) -> (Range<usize>, String) {
+        match e {
+            Error::Span(e, span) => (span, e.to_string()),
+            e => panic!("{:?} doesn't have a span", e),
+        }
+    }
+
+    #[test]
+    fn test_operands() {
+        let regs = reg_names::regs();
+
+        let mut ops = Operands::new("a0,,,a1 , a2 ,");
+        assert_eq!((ops.reg(&regs).unwrap(), ops.reg(&regs).unwrap(), ops.reg(&regs).unwrap()), (10, 11, 12));
+        assert!(ops.end().is_ok());
+
+        let mut ops = Operands::new("sp, sp, -4 * 4,,");
+        assert_eq!((ops.reg(&regs).unwrap(), ops.reg(&regs).unwrap()), (2, 2));
+        assert_eq!(ops.value().unwrap(), Value::Imm((-16i32) as u32));
+        assert!(ops.end().is_ok());
+
+        let mut ops = Operands::new("t0 end - start");
+        ops.reg(&regs).unwrap();
+        assert_eq!(ops.value().unwrap(), Value::Label("end - start".to_owned()));
+
+        let mem = |s| Operands::new(s).mem(&regs).ok();
+        assert_eq!(mem("0xA(x25)"), Some((Value::Imm(10), 25)));
+        assert_eq!(mem("4 ( ,sp, )"), Some((Value::Imm(4), 2)));
+        assert_eq!(mem("' ',(,x7,) ,"), Some((Value::Imm(32), 7)));
+        assert_eq!(mem("(t0)"), Some((Value::Imm(0), 5)));
+        assert_eq!(mem("(1<<4)(t0)"), Some((Value::Imm(16), 5)));
+        assert_eq!(mem("matrix+16(t2)"), Some((Value::Label("matrix+16".to_owned()), 7)));
+        assert_eq!(mem("label"), None);
+
+        // Errors point at what's wrong
+        let error = |s: &str, parse: fn(&mut Operands) -> Result<(), Error>| span_of(parse(&mut Operands::new(s)).unwrap_err());
+        let three_regs = |ops: &mut Operands| {
+            let regs = reg_names::regs();
+            (0..3).try_for_each(|_| ops.reg(&regs).map(|_| ()))?;
+            ops.end()
+        };
+        assert_eq!(error("t0, t1, t9", three_regs), (8..10, "unknown register `t9`".to_owned()));
+        assert_eq!(error("t0, t1", three_regs), (6..6, "unexpected end of line".to_owned()));
+        assert_eq!(error("t0 t1 t2 t3", three_regs), (9..11, "unexpected `t3`".to_owned()));
+
+        let value = |ops: &mut Operands| ops.value().map(|_| ());
+        assert_eq!(error("0b102", value), (0..5, "invalid immediate `0b102`".to_owned()));
+        assert_eq!(error("4 + 12a", value), (4..7, "invalid immediate `12a`".to_owned()));
+        assert_eq!(error("1 + (2 / 0)", value).0, 0..11);
+        assert_eq!(error("1+", value), (1..2, "unexpected `+`".to_owned()));
+        assert_eq!(error("@", value), (0..1, "unexpected `@`".to_owned()));
+
+        let mem = |ops: &mut Operands| ops.mem(&reg_names::regs()).map(|_| ());
+        assert_eq!(error("4(xx)", mem), (2..4, "unknown register `xx`".to_owned()));
+        assert_eq!(error("4(sp", mem), (4..4, "unexpected end of line".to_owned()));
+        assert_eq!(error("label", mem), (0..5, "unexpected `label`".to_owned()));
+        assert_eq!(error("4(sp t0)", mem), (5..7, "unexpected `t0`".to_owned()));
+        assert_eq!(error("4(sp) t0", |ops| ops.mem(&reg_names::regs()).and_then(|_| ops.end())).0, 6..8);
+    }
+
+    #[test]
+    fn test_attempt() {
+        let regs = reg_names::regs();
+        let mut ops = Operands::new("t0, label");
+
+        assert!(ops.attempt(|ops| ops.reg(&regs)).is_err());
+        let label = ops.attempt(|ops| {
+            ops.reg(&regs)?;
+            ops.value()
+        });
+        assert_eq!(label.unwrap(), Value::Label("label".to_owned()));
+    }
+}
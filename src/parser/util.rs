@@ -1,104 +1,466 @@
-use std::fmt;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
-use std::path::Path;
-
-/// Represents any kind of error the parser may find
-#[derive(Debug)]
-pub enum Error {
-    /// Not the parser's fault, some std::io went wrong
-    IO(io::Error),
-
-    LabelNotFound(String),
-    Nom(String, nom::error::ErrorKind), // I'm feeling lazy
-    RegisterNotFound(String),
-    InstructionNotFound(String),
-
-    UnendedMacro(String),
-    ArgNotFoundMacro(String),
-
-    /// Didn't recognize a type/directive in the `.data` directive
-    /// (like `.double` or `.nothing`)
-    UnrecognizedDataType(String),
-    FloatError(std::num::ParseFloatError),
-
-    OnLine(String, Box<Error>),
-}
-
-impl From<io::Error> for Error {
-    fn from(e: io::Error) -> Self {
-        Error::IO(e)
-    }
-}
-
-impl<'a> From<nom::Err<(&'a str, nom::error::ErrorKind)>> for Error {
-    fn from(err: nom::Err<(&'a str, nom::error::ErrorKind)>) -> Self {
-        use nom::Err as e;
-        match err {
-            e::Incomplete(_) => {
-                unreachable!("nom::Err::Incomplete should only exist in streaming parsers")
-            }
-            e::Error((i, e)) => Error::Nom(i.into(), e),
-            e::Failure((i, e)) => Error::Nom(i.into(), e),
-        }
-    }
-}
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self) // muahahahaha
-    }
-}
-
-impl std::error::Error for Error {}
-
-pub trait WrapMeta<T> {
-    fn wrap_meta(self, s: &str) -> Result<T, Error>;
-}
-
-impl<T, E: Into<Error>> WrapMeta<T> for Result<T, E> {
-    /// Wraps an Err in an OnLine(line_string, Err)
-    fn wrap_meta(self, s: &str) -> Result<T, Error> {
-        self.map_err(|e| Error::OnLine(s.to_owned(), Box::new(e.into())))
-    }
-}
-
-struct LossyLines {
-    reader: BufReader<File>,
-    buf: Vec<u8>,
-}
-
-impl LossyLines {
-    fn new(reader: BufReader<File>) -> Self {
-        LossyLines {
-            reader,
-            buf: vec![],
-        }
-    }
-}
-
-// TODO: replace Strings in the parser iterators by a Cow
-impl Iterator for LossyLines {
-    type Item = String;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.buf.clear();
-        let bytes_read = self.reader
-            .read_until(b'\n', &mut self.buf)
-            .expect("LossyLines reader shouldn't fail to read a line");
-
-        if bytes_read == 0 {
-            return None;
-        }
-
-        let line = String::from_utf8_lossy(&self.buf);
-        let line = line.trim_end_matches("\r\n").trim_end_matches("\n");
-        Some(line.to_owned())
-    }
-}
-
-/// Returns an iterator over the lines of a file
-pub fn file_lines<P: AsRef<Path>>(filepath: P) -> Result<impl Iterator<Item = String>, Error> {
-    let reader = File::open(filepath).map(BufReader::new)?;
-    Ok(LossyLines::new(reader))
-}
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Where a line of code came from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pos {
+    pub file: String,
+    /// Starts at 1
+    pub line: usize,
+    /// Starts at 1. Only known when pointing at an error
+    pub column: Option<usize>,
+    /// How many characters the error takes from the column on, if we know it
+    pub width: usize,
+    /// The line as it was written, with comments and everything
+    pub source: String,
+    /// The macro this line came from, if it did. The rest of the position is where it was used
+    pub macro_origin: Option<MacroOrigin>,
+}
+
+/// Where a line that came from a macro was written
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MacroOrigin {
+    pub name: String,
+    /// The line inside the `.macro` definition
+    pub pos: Arc<Pos>,
+}
+
+impl fmt::Display for MacroOrigin {
+    /// The line inside the macro, as a frame inlined where it was used, like
+    /// ``inlined from macro `inc` at main.s:2: addi t0, t0, 1``
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let def = &self.pos;
+        write!(f, "inlined from macro `{}` at {}:{}: {}", self.name, def.file, def.line, def.source.trim())
+    }
+}
+
+/// A line of code, as the preprocessor outputs it. Lines that came from a macro point to where
+/// the macro was used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub text: String,
+    pub pos: Arc<Pos>,
+}
+
+/// Something in the code that's probably a mistake, but still assembles. These are only errors
+/// with `--werror`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// A label nothing refers to, only looked for with `--warn-unused`. Global labels and `main`
+    /// don't count, since they're meant to be used from the outside.
+    UnusedLabel(String),
+    /// A label expression in a `.byte` or `.half` whose value doesn't fit, and how many bits were kept
+    Truncated(String, u32),
+    /// A `.word`, `.half` or `.float` that isn't aligned to its size, and its address
+    UnalignedData(String, usize),
+    /// A branch or jump to a label in the `.data`
+    BranchToData(String),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Warning::*;
+        match self {
+            UnusedLabel(label) => write!(f, "label `{}` is never used", label),
+            Truncated(label, bits) => {
+                write!(f, "the value of `{}` doesn't fit in {} bits and was truncated", label, bits)
+            }
+            UnalignedData(directive, address) => {
+                write!(f, "`{}` at the unaligned address {:#x}", directive, address)
+            }
+            BranchToData(label) => write!(f, "jumps to `{}`, which is a label in the data", label),
+        }
+    }
+}
+
+/// Represents any kind of error the parser may find
+#[derive(Debug)]
+pub enum Error {
+    /// Not the parser's fault, some std::io went wrong
+    IO(io::Error),
+
+    /// A label that wasn't defined, and the one the user probably meant
+    LabelNotFound(String, Option<String>),
+    /// The address of a `.data ADDR` or `.text ADDR`, and why we can't go there
+    InvalidAddress(String, String),
+    /// A label defined twice in the same file, and where it was defined first
+    DuplicateLabel(String, Arc<Pos>),
+    /// nom couldn't parse the line, starting from here
+    Nom(String),
+    RegisterNotFound(String),
+    /// An instruction we don't know, and the one the user probably meant
+    UnknownInstruction(String, Option<String>),
+
+    /// Something that looked like a number, but wasn't, like `0b102` or `12a`
+    InvalidImmediate(String),
+    /// The immediate doesn't fit in the place it's being used, like `.byte 256`
+    ImmediateOutOfRange(String),
+    /// The immediate doesn't fit in the field of its instruction, like `addi t0, t0, 5000`. Has the
+    /// smallest and the largest values the field takes.
+    FieldOutOfRange(String, i64, i64),
+    /// A constant expression divides by zero, like `.word 4/(2-2)`
+    DivisionByZero(String),
+
+    /// Couldn't open the file of an `.include`
+    IncludeNotFound(String),
+
+    UnendedMacro(String),
+    ArgNotFoundMacro(String),
+    /// A macro was used with a wrong number of arguments. Has its name, the numbers of arguments
+    /// it can be used with, and how many it got.
+    MacroArgCount(String, Vec<usize>, usize),
+    /// An `.else` or `.endif` outside of an `.ifdef`, or a second `.else` in the same one
+    UnmatchedConditional(String),
+    UnendedIf,
+    /// An `.endr` outside of a `.rept`
+    UnmatchedEndr,
+    UnendedRept,
+    /// Written with `.error "message"`. The preprocessor leaves these for the errors it finds, too.
+    Custom(String),
+
+    /// The same label was declared `.globl` and defined in more than one file
+    DuplicateGlobal(String),
+
+    /// Something RARS wouldn't accept, with [strict](struct.Options.html#structfield.strict) on,
+    /// and why
+    NotInRars(String, &'static str),
+    /// An RV64 instruction, without [rv64](struct.Options.html#structfield.rv64) on
+    NotInRv32(String),
+    /// A register from x16 to x31, with [rv32e](struct.Options.html#structfield.rv32e) on
+    NotInRv32E(String),
+    /// A compressed instruction, like `c.addi`, without [rvc](struct.Options.html#structfield.rvc)
+    NeedsRvc(String),
+    /// A compressed instruction whose registers or immediate don't fit in its 2 bytes
+    NotCompressible(String),
+
+    /// Didn't recognize a type/directive in the `.data` directive
+    /// (like `.double` or `.nothing`)
+    UnrecognizedDataType(String),
+    ParseFloat(std::num::ParseFloatError),
+
+    /// A [Warning](enum.Warning.html). They're kept apart from the errors, unless the user asked
+    /// otherwise.
+    Warning(Warning),
+
+    /// An error in the instruction being parsed, and where it is in it, in bytes. It becomes a
+    /// column once we know the line.
+    Span(Box<Error>, Range<usize>),
+    OnLine(Pos, Box<Error>),
+    /// Every error we found in the code, in the order they appear
+    Many(Vec<Error>),
+}
+
+impl Error {
+    /// Puts many errors together, or returns `None` if there are none
+    pub fn many(mut errors: Vec<Error>) -> Option<Error> {
+        match errors.len() {
+            0 => None,
+            1 => errors.pop(),
+            _ => Some(Error::Many(errors)),
+        }
+    }
+
+    /// Says that the error is at `span` of the text being parsed. An error that already knows where
+    /// it is stays there.
+    pub fn span(self, span: Range<usize>) -> Error {
+        match self {
+            Error::Span(..) | Error::OnLine(..) | Error::Many(_) => self,
+            e => Error::Span(Box::new(e), span),
+        }
+    }
+
+    /// Moves the span of an error found in `part` to the same place in `whole`, where `part` came
+    /// from. If `part` was changed on the way, it goes to where the text of the span is, if it's there.
+    pub fn span_in(self, part: &str, whole: &str) -> Error {
+        let (e, span) = match self {
+            Error::Span(e, span) => (e, span),
+            e => return e,
+        };
+
+        let offset = match whole.strip_suffix(part) {
+            Some(before) => Some(before.len() + span.start),
+            None => part.get(span.clone()).filter(|s| !s.is_empty()).and_then(|s| whole.find(s)),
+        };
+        match offset {
+            Some(offset) => Error::Span(e, offset..offset + span.len()),
+            None => *e,
+        }
+    }
+
+    /// Says where the error happened, unless we already know it. `text` is what was being parsed,
+    /// which helps finding the column of [Nom](enum.Error.html#variant.Nom) errors.
+    pub fn at(self, pos: &Pos, text: &str) -> Error {
+        if let Error::OnLine(..) | Error::Many(_) = self {
+            return self;
+        }
+
+        let (offset, e, width) = match self {
+            Error::Span(e, span) => {
+                // The text is usually in the line as it was written, but an `.eqv` or a macro may
+                // have changed it, and then we look for what's in the span
+                let spanned = text.get(span.clone()).unwrap_or("");
+                let offset = pos.source.find(text).map(|start| start + span.start);
+                let offset = offset.or_else(|| pos.source.find(spanned).filter(|_| !spanned.is_empty()));
+                match offset {
+                    Some(offset) => (Some(offset), *e, spanned.chars().count()),
+                    None => (e.offset_in(&pos.source, text), *e, 0),
+                }
+            }
+            e => (e.offset_in(&pos.source, text), e, 0),
+        };
+
+        let pos = Pos {
+            column: offset.map(|offset| pos.source[..offset].chars().count() + 1),
+            width,
+            ..pos.clone()
+        };
+        Error::OnLine(pos, Box::new(e))
+    }
+
+    /// Tries to find where the error is in the line as it was written, in bytes. This is a guess if
+    /// an `.eqv` changed the line or the same thing is written twice in it, but it's right most of
+    /// the time.
+    fn offset_in(&self, source: &str, text: &str) -> Option<usize> {
+        use Error::*;
+        let offset = match self {
+            Nom(rest) if text.ends_with(rest.as_str()) => {
+                source.find(text)? + text.len() - rest.len()
+            }
+            LabelNotFound(s, _) | DuplicateLabel(s, _) | RegisterNotFound(s) | UnknownInstruction(s, _) | InvalidImmediate(s)
+            | ImmediateOutOfRange(s) | FieldOutOfRange(s, ..) | InvalidAddress(s, _) | NotInRars(s, _) | NotInRv32(s)
+            | NotInRv32E(s) | NeedsRvc(s) | UnrecognizedDataType(s) | IncludeNotFound(s) => {
+                source.find(s.as_str())?
+            }
+            Warning(self::Warning::UnusedLabel(s) | self::Warning::Truncated(s, _) | self::Warning::BranchToData(s)) => {
+                source.find(s.as_str())?
+            }
+            _ => return None,
+        };
+
+        Some(offset)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::IO(e)
+    }
+}
+
+impl<'a> From<nom::Err<(&'a str, nom::error::ErrorKind)>> for Error {
+    fn from(err: nom::Err<(&'a str, nom::error::ErrorKind)>) -> Self {
+        use nom::Err as e;
+        match err {
+            e::Incomplete(_) => {
+                unreachable!("nom::Err::Incomplete should only exist in streaming parsers")
+            }
+            e::Error((i, _)) => Error::Nom(i.into()),
+            e::Failure((i, _)) => Error::Nom(i.into()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            IO(e) => write!(f, "{}", e),
+            LabelNotFound(label, None) => write!(f, "label not found: `{}`", label),
+            LabelNotFound(label, Some(suggestion)) => {
+                write!(f, "label not found: `{}`, did you mean `{}`?", label, suggestion)
+            }
+            InvalidAddress(address, why) => write!(f, "can't go to the address `{}`, {}", address, why),
+            NotInRars(s, why) => write!(f, "RARS doesn't accept `{}`, {}", s, why),
+            NotInRv32(s) => write!(f, "`{}` is an RV64 instruction, run with `--rv64` to use it", s),
+            NotInRv32E(s) => write!(f, "RV32E doesn't have `{}`, only x0 to x15", s),
+            NeedsRvc(s) => write!(f, "`{}` is a compressed instruction, run with `--rvc` to use it", s),
+            NotCompressible(s) => write!(f, "`{}` doesn't fit in a compressed instruction", s),
+            DuplicateLabel(label, first) => write!(
+                f,
+                "label `{}` is already defined, at {}:{}: {}",
+                label,
+                first.file,
+                first.line,
+                first.source.trim()
+            ),
+            Nom(rest) => {
+                let is_end = |c: char| c == ',' || c == '(' || c == ')' || c.is_whitespace();
+                match rest.split(is_end).next() {
+                    Some("") if rest.is_empty() => write!(f, "unexpected end of line"),
+                    Some("") | None => write!(f, "couldn't parse `{}`", rest),
+                    Some(token) => write!(f, "unexpected `{}`", token),
+                }
+            }
+            RegisterNotFound(reg) => write!(f, "unknown register `{}`", reg),
+            UnknownInstruction(inst, None) => write!(f, "unknown instruction `{}`", inst),
+            UnknownInstruction(inst, Some(suggestion)) => {
+                write!(f, "unknown instruction `{}`, did you mean `{}`?", inst, suggestion)
+            }
+            InvalidImmediate(imm) => write!(f, "invalid immediate `{}`", imm),
+            ImmediateOutOfRange(imm) => write!(f, "immediate `{}` is out of range", imm),
+            FieldOutOfRange(imm, min, max) => {
+                write!(f, "immediate `{}` is out of range, it has to be from {} to {}", imm, min, max)
+            }
+            DivisionByZero(expr) => write!(f, "division by zero in `{}`", expr),
+            IncludeNotFound(file) => write!(f, "couldn't open the included file `{}`", file),
+            UnendedMacro(name) => write!(f, "macro `{}` is missing its .end_macro", name),
+            ArgNotFoundMacro(arg) => write!(f, "macro argument `%{}` was never declared", arg),
+            MacroArgCount(name, counts, given) => {
+                let counts: Vec<String> = counts.iter().map(usize::to_string).collect();
+                let expected = match counts.split_last() {
+                    Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+                    _ => counts.join(""),
+                };
+                let plural = if counts.last().is_some_and(|n| n == "1") { "" } else { "s" };
+                write!(f, "macro `{}` takes {} argument{}, but got {}", name, expected, plural, given)
+            }
+            UnmatchedConditional(directive) => write!(f, "`{}` without a matching `.ifdef`", directive),
+            UnendedIf => write!(f, "`.ifdef` is missing its `.endif`"),
+            UnmatchedEndr => write!(f, "`.endr` without a matching `.rept`"),
+            UnendedRept => write!(f, "`.rept` is missing its `.endr`"),
+            Custom(message) => write!(f, "{}", message),
+            DuplicateGlobal(label) => write!(f, "global label `{}` is defined in more than one file", label),
+            UnrecognizedDataType(dtype) => write!(f, "unrecognized directive `.{}`", dtype),
+            ParseFloat(e) => write!(f, "invalid float: {}", e),
+            Warning(w) => write!(f, "warning: {}", w),
+            Span(e, _) => write!(f, "{}", e),
+            OnLine(pos, e) => {
+                match pos.column {
+                    Some(column) => writeln!(f, "{}:{}:{}: {}", pos.file, pos.line, column, e)?,
+                    None => writeln!(f, "{}:{}: {}", pos.file, pos.line, e)?,
+                }
+
+                // Echoes the line, with a caret under the column
+                let number = pos.line.to_string();
+                write!(f, "{} | {}", number, pos.source)?;
+                if let Some(column) = pos.column {
+                    let padding: String = pos.source.chars()
+                        .take(column - 1)
+                        .map(|c| if c == '\t' { '\t' } else { ' ' })
+                        .collect();
+                    let carets = "^".repeat(pos.width.max(1));
+                    write!(f, "\n{} | {}{}", " ".repeat(number.len()), padding, carets)?;
+                }
+
+                // And the line inside the macro, if it came from one
+                if let Some(origin) = &pos.macro_origin {
+                    let def = &origin.pos;
+                    write!(
+                        f,
+                        "\n{} = in macro `{}`, {}:{}: {}",
+                        " ".repeat(number.len()),
+                        origin.name,
+                        def.file,
+                        def.line,
+                        def.source.trim()
+                    )?;
+                }
+                Ok(())
+            }
+            Many(errors) => {
+                for e in errors {
+                    write!(f, "{}\n\n", e)?;
+                }
+                write!(f, "Found {} errors", errors.len())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub trait WrapMeta<T> {
+    fn wrap_meta(self, line: &Line) -> Result<T, Error>;
+}
+
+impl<T, E: Into<Error>> WrapMeta<T> for Result<T, E> {
+    /// Wraps an Err in an OnLine, pointing to where the error is in the line
+    fn wrap_meta(self, line: &Line) -> Result<T, Error> {
+        self.map_err(|e| e.into().at(&line.pos, &line.text))
+    }
+}
+
+struct LossyLines {
+    reader: BufReader<File>,
+    buf: Vec<u8>,
+}
+
+impl LossyLines {
+    fn new(reader: BufReader<File>) -> Self {
+        LossyLines {
+            reader,
+            buf: vec![],
+        }
+    }
+}
+
+// TODO: replace Strings in the parser iterators by a Cow
+impl Iterator for LossyLines {
+    type Item = String;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buf.clear();
+        let bytes_read = self.reader
+            .read_until(b'\n', &mut self.buf)
+            .expect("LossyLines reader shouldn't fail to read a line");
+
+        if bytes_read == 0 {
+            return None;
+        }
+
+        let line = String::from_utf8_lossy(&self.buf);
+        let line = line.trim_end_matches("\r\n").trim_end_matches("\n");
+        Some(line.to_owned())
+    }
+}
+
+/// Finds the candidate that is the fewest edits away from `word`, if any is close enough to be a typo
+pub fn closest<'a>(word: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let word = word.to_lowercase();
+    let max_distance = match word.chars().count() {
+        0..=2 => 1,
+        _ => 2,
+    };
+
+    candidates
+        .map(|candidate| (edit_distance(&word, &candidate.to_lowercase()), candidate))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// How many characters we have to insert, remove, change or swap with the next one to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+
+    // d[i][j] is the distance between the first i chars of a and the first j chars of b
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in d[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1).min(d[i][j - 1] + 1).min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    d[a.len()][b.len()]
+}
+
+/// Returns an iterator over the lines of a file
+pub fn file_lines<P: AsRef<Path>>(filepath: P) -> Result<impl Iterator<Item = String>, Error> {
+    let reader = File::open(filepath).map(BufReader::new)?;
+    Ok(LossyLines::new(reader))
+}
@@ -1,188 +1,477 @@
-mod scancode;
-
-use glium::glutin;
-use pixel_canvas::{
-    canvas::CanvasInfo,
-    input::{Event, WindowEvent},
-    Canvas, Color,
-};
-use std::sync::{Arc, Mutex};
-
-pub const WIDTH: usize = 320;
-pub const HEIGHT: usize = 240;
-pub const FRAME_SELECT: usize = 0x20_0604;
-pub const FRAME_0: usize = 0;
-pub const FRAME_1: usize = 0x10_0000;
-const KEYBOARD: usize = 0x20_0000;
-const KEYBUFFER: usize = 0x20_0100;
-const KEYBUFFER_SIZE: usize = 8;
-const KEYMAP: usize = 0x20_0520;
-
-fn push_key_to_buffer(mmio: &mut [u8], key: u8) {
-    // Shift buffer
-    for i in (KEYBUFFER + 1..KEYBUFFER + KEYBUFFER_SIZE).rev() {
-        mmio[i] = mmio[i - 1];
-    }
-
-    // Push key to mmio[KEYBUFFER]
-    mmio[KEYBUFFER] = key;
-}
-
-fn push_key_to_map(mmio: &mut [u8], key: u8) {
-    let (byte, bit) = (key / 8, key % 8);
-    mmio[KEYMAP + byte as usize] |= 1 << bit;
-}
-
-fn remove_key_from_map(mmio: &mut [u8], key: u8) {
-    let (byte, bit) = (key / 8, key % 8);
-    mmio[KEYMAP + byte as usize] &= !(1 << bit);
-}
-
-struct MyState {
-    mmio: Arc<Mutex<Vec<u8>>>,
-}
-
-impl MyState {
-    fn new(mmio: Arc<Mutex<Vec<u8>>>) -> Self {
-        Self { mmio }
-    }
-
-    fn handle_input(_info: &CanvasInfo, state: &mut MyState, event: &Event<()>) -> bool {
-        match event {
-            // Match a keypress with scancode "key"
-            Event::WindowEvent {
-                event:
-                    WindowEvent::KeyboardInput {
-                        input:
-                            glutin::event::KeyboardInput {
-                                state: glutin::event::ElementState::Pressed,
-                                scancode: key,
-                                ..
-                            },
-                        is_synthetic: false,
-                        ..
-                    },
-                ..
-            } => {
-                let mut mmio = state.mmio.lock().unwrap();
-                mmio[KEYBOARD] = 1;
-                mmio[KEYBOARD + 4] = scancode::to_ascii(*key);
-
-                push_key_to_buffer(&mut mmio, *key as u8);
-                push_key_to_map(&mut mmio, *key as u8);
-
-                true
-            }
-
-            // Match a keyup with scancode "key"
-            Event::WindowEvent {
-                event:
-                    WindowEvent::KeyboardInput {
-                        input:
-                            glutin::event::KeyboardInput {
-                                state: glutin::event::ElementState::Released,
-                                scancode: key,
-                                ..
-                            },
-                        is_synthetic: false,
-                        ..
-                    },
-                ..
-            } => {
-                let mut mmio = state.mmio.lock().unwrap();
-
-                push_key_to_buffer(&mut mmio, 0xF0);
-                push_key_to_buffer(&mut mmio, *key as u8);
-
-                remove_key_from_map(&mut mmio, *key as u8);
-
-                true
-            }
-
-            _ => false,
-        }
-    }
-}
-
-// TODO: change the color format in pixel-canvas to ClientFormat::U8
-fn mmio_color_to_rgb(x: u8) -> Color {
-    let r = x & 0b111;
-    let g = (x >> 3) & 0b111;
-    let b = x >> 6;
-    Color {
-        r: r * 36,
-        g: g * 36,
-        b: b * 85,
-    }
-}
-
-pub fn init(mmio: Arc<Mutex<Vec<u8>>>) {
-    let canvas = Canvas::new(2 * WIDTH, 2 * HEIGHT)
-        .title("FPGRARS")
-        .state(MyState::new(mmio.clone()))
-        .input(MyState::handle_input);
-
-    #[cfg(feature = "show_ms")]
-    let canvas = canvas.show_ms(true);
-
-    canvas.render(move |_state, image| {
-        let mmio = mmio.lock().unwrap();
-
-        let frame = mmio[FRAME_SELECT];
-        let start = if frame == 0 { FRAME_0 } else { FRAME_1 };
-
-        // Draw each MMIO pixel as a 2x2 square
-        for (y, row) in image.chunks_mut(2 * WIDTH).enumerate() {
-            for (x, pixel) in row.iter_mut().enumerate() {
-                let (x, y) = (x / 2, HEIGHT - 1 - y / 2);
-                let index = start + y * WIDTH + x;
-
-                let col = if cfg!(debug_assertions) {
-                    *mmio
-                        .get(index)
-                        .expect("Out of bound access to the video memory!")
-                } else {
-                    unsafe { *mmio.get_unchecked(index) }
-                };
-
-                // if col != 0xc7 {
-                *pixel = mmio_color_to_rgb(col);
-                // }
-            }
-        }
-
-        // Alternative, possibly slower, implementation:
-
-        // let mut set = move |i, col| {
-        //     if cfg!(debug_assertions) {
-        //         *image
-        //             .get_mut(i)
-        //             .expect("Out of bounds access to the video memory!") = mmio_color_to_rgb(col);
-        //     } else {
-        //         unsafe {
-        //             *image.get_unchecked_mut(i) = mmio_color_to_rgb(col);
-        //         }
-        //     }
-        // };
-
-        // for i in 0..FRAME_SIZE {
-        //     let col = mmio[i + start];
-
-        //     // 0xC7 is "transparent"
-        //     if col != 0xC7 {
-        //         // Don't ask
-        //         // TODO: if this is too slow, we can try filling in line by line,
-        //         // as every other line is just a copy of the one above it
-        //         {
-        //             set((i % WIDTH) * 2 + (i / WIDTH) * WIDTH * 4, col);
-        //             set(1 + (i % WIDTH) * 2 + (i / WIDTH) * WIDTH * 4, col);
-        //             set((i % WIDTH) * 2 + (i / WIDTH) * WIDTH * 4 + 2 * WIDTH, col);
-        //             set(
-        //                 1 + (i % WIDTH) * 2 + (i / WIDTH) * WIDTH * 4 + 2 * WIDTH,
-        //                 col,
-        //             );
-        //         }
-        //     }
-        // }
-    });
-}
+mod scancode;
+
+use glium::glutin;
+use glium::glutin::event::VirtualKeyCode;
+use pixel_canvas::{
+    canvas::CanvasInfo,
+    input::{Event, WindowEvent},
+    Canvas, Color, Image,
+};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const WIDTH: usize = 320;
+pub const HEIGHT: usize = 240;
+pub const FRAME_SELECT: usize = 0x20_0604;
+pub const FRAME_0: usize = 0;
+pub const FRAME_1: usize = 0x10_0000;
+const KEYBOARD: usize = 0x20_0000;
+const KEYBUFFER: usize = 0x20_0100;
+const KEYBUFFER_SIZE: usize = 8;
+const KEYMAP: usize = 0x20_0520;
+/// Where the settings of the window are kept, in the directory FPGRARS runs from
+const SETTINGS_FILE: &str = "fpgrars.toml";
+
+fn push_key_to_buffer(mmio: &mut [u8], key: u8) {
+    // Shift buffer
+    for i in (KEYBUFFER + 1..KEYBUFFER + KEYBUFFER_SIZE).rev() {
+        mmio[i] = mmio[i - 1];
+    }
+
+    // Push key to mmio[KEYBUFFER]
+    mmio[KEYBUFFER] = key;
+}
+
+fn push_key_to_map(mmio: &mut [u8], key: u8) {
+    let (byte, bit) = (key / 8, key % 8);
+    mmio[KEYMAP + byte as usize] |= 1 << bit;
+}
+
+fn remove_key_from_map(mmio: &mut [u8], key: u8) {
+    let (byte, bit) = (key / 8, key % 8);
+    mmio[KEYMAP + byte as usize] &= !(1 << bit);
+}
+
+/// A key pressed or released in the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub scancode: u32,
+    pub pressed: bool,
+}
+
+/// Where the keys of the window go instead of the MMIO, for the simulator to give them to the
+/// program when it's [recording or replaying](../simulator/replay/index.html) them
+pub type KeyQueue = Arc<Mutex<Vec<Key>>>;
+
+/// Puts `key` in the MMIO: the keyboard control and data of RARS, the buffer of scancodes and the
+/// map of the keys that are down
+pub fn apply_key(mmio: &mut [u8], key: Key) {
+    if key.pressed {
+        mmio[KEYBOARD] = 1;
+        mmio[KEYBOARD + 4] = scancode::to_ascii(key.scancode);
+
+        push_key_to_buffer(mmio, key.scancode as u8);
+        push_key_to_map(mmio, key.scancode as u8);
+    } else {
+        push_key_to_buffer(mmio, 0xF0);
+        push_key_to_buffer(mmio, key.scancode as u8);
+
+        remove_key_from_map(mmio, key.scancode as u8);
+    }
+}
+
+/// The colors the bytes of the video memory are drawn with. Each byte is a color in the BBGGGRRR
+/// format of the FPGA
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Palette {
+    /// Like the FPGA shows them
+    Fpga,
+    /// Stretched so the brightest red, green and blue are 255
+    Full,
+    /// The brightness of each color, to see whether things can be told apart without colors
+    Gray,
+}
+
+impl Palette {
+    /// How they're called in the settings file
+    const NAMES: [(Palette, &'static str); 3] =
+        [(Palette::Fpga, "fpga"), (Palette::Full, "full"), (Palette::Gray, "gray")];
+
+    fn name(self) -> &'static str {
+        Self::NAMES.iter().find(|(palette, _)| *palette == self).map_or("fpga", |(_, name)| name)
+    }
+
+    fn next(self) -> Self {
+        match self {
+            Palette::Fpga => Palette::Full,
+            Palette::Full => Palette::Gray,
+            Palette::Gray => Palette::Fpga,
+        }
+    }
+
+    fn color(self, x: u8) -> Color {
+        let (r, g, b) = (x & 0b111, (x >> 3) & 0b111, x >> 6);
+        match self {
+            Palette::Fpga => mmio_color_to_rgb(x),
+            Palette::Full => Color {
+                r: (r as u32 * 255 / 7) as u8,
+                g: (g as u32 * 255 / 7) as u8,
+                b: b * 85,
+            },
+            Palette::Gray => {
+                let Color { r, g, b } = mmio_color_to_rgb(x);
+                let y = ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8;
+                Color { r: y, g: y, b: y }
+            }
+        }
+    }
+}
+
+/// How the window shows the video. These change while the program runs with F2 (the scale), F3
+/// (the frame rate) and F4 (the palette), without losing where the program is. F5 reads them again
+/// from [SETTINGS_FILE](constant.SETTINGS_FILE.html), which the window also starts with, and F6
+/// saves them there. There's no menu in the window, the new settings are printed instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Settings {
+    /// How many pixels of the window each pixel of the video takes, across and down
+    scale: usize,
+    /// How many frames we draw each second, at most
+    fps: u32,
+    palette: Palette,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self { scale: 2, fps: 60, palette: Palette::Fpga }
+    }
+}
+
+impl Settings {
+    /// Whether `key` is one of the keys of the settings, which don't get to the program
+    fn is_hotkey(key: VirtualKeyCode) -> bool {
+        use VirtualKeyCode::*;
+        matches!(key, F2 | F3 | F4 | F5 | F6)
+    }
+
+    /// Changes the setting of the key `key`, and returns whether it's one of those keys
+    fn change(&mut self, key: VirtualKeyCode) -> bool {
+        match key {
+            VirtualKeyCode::F2 => self.scale = self.scale % 4 + 1,
+            VirtualKeyCode::F3 => self.fps = if self.fps > 15 { self.fps / 2 } else { 60 },
+            VirtualKeyCode::F4 => self.palette = self.palette.next(),
+            _ => return false,
+        }
+        true
+    }
+
+    /// The settings in `path`, or the default ones if there's no such file
+    fn load(path: &Path) -> Self {
+        if !path.exists() {
+            return Self::default();
+        }
+        Self::read(path).unwrap_or_else(|e| {
+            eprintln!("Couldn't read the settings in `{}`: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    fn read(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::parse(&text)
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.to_toml())
+    }
+
+    /// Parses the `key = value` lines of a settings file, like the ones [to_toml](#method.to_toml)
+    /// writes. The keys that aren't there keep their default.
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut settings = Self::default();
+        for line in text.lines().map(|line| line.split('#').next().unwrap_or("").trim()) {
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| format!("expected `key = value`, got `{}`", line))?;
+            let (key, value) = (key.trim(), value.trim().trim_matches('"'));
+            let invalid = || format!("`{}` can't be `{}`", key, value);
+            match key {
+                "scale" => {
+                    settings.scale = value.parse().ok().filter(|scale| (1..=4).contains(scale)).ok_or_else(invalid)?
+                }
+                "fps" => settings.fps = value.parse().ok().filter(|fps| (1..=60).contains(fps)).ok_or_else(invalid)?,
+                "palette" => {
+                    let palette = Palette::NAMES.iter().find(|(_, name)| *name == value);
+                    settings.palette = palette.map(|(palette, _)| *palette).ok_or_else(invalid)?;
+                }
+                _ => return Err(format!("there's no setting called `{}`", key)),
+            }
+        }
+        Ok(settings)
+    }
+
+    fn to_toml(self) -> String {
+        format!("scale = {}\nfps = {}\npalette = \"{}\"\n", self.scale, self.fps, self.palette.name())
+    }
+
+    /// Whether it's too soon after the frame drawn at `drawn_at` to draw another one
+    fn too_soon(&self, drawn_at: Instant) -> bool {
+        // The window asks for a frame every 1/60 of a second, give or take
+        let slack = Duration::from_millis(4);
+        drawn_at.elapsed() + slack < Duration::from_secs(1) / self.fps
+    }
+}
+
+struct MyState {
+    mmio: Arc<Mutex<Vec<u8>>>,
+    keys: Option<KeyQueue>,
+    settings: Settings,
+    /// When we last drew a frame, to keep to the frame rate of the `settings`
+    drawn_at: Option<Instant>,
+}
+
+impl MyState {
+    fn new(mmio: Arc<Mutex<Vec<u8>>>, keys: Option<KeyQueue>, settings: Settings) -> Self {
+        Self { mmio, keys, settings, drawn_at: None }
+    }
+
+    /// Does what the key of a setting does, see [Settings](struct.Settings.html)
+    fn hotkey(&mut self, key: VirtualKeyCode) {
+        let path = Path::new(SETTINGS_FILE);
+        match key {
+            VirtualKeyCode::F5 => match Settings::read(path) {
+                Ok(settings) => self.settings = settings,
+                Err(e) => return eprintln!("Couldn't read the settings in `{}`: {}", SETTINGS_FILE, e),
+            },
+            VirtualKeyCode::F6 => match self.settings.write(path) {
+                Ok(()) => eprintln!("Window: saved the settings to `{}`", SETTINGS_FILE),
+                Err(e) => return eprintln!("Couldn't save the settings to `{}`: {}", SETTINGS_FILE, e),
+            },
+            _ => {
+                self.settings.change(key);
+            }
+        }
+        let Settings { scale, fps, palette } = self.settings;
+        eprintln!("Window: {}x scale, {} fps, {:?} palette", scale, fps, palette);
+    }
+
+    fn key(&self, key: Key) {
+        match &self.keys {
+            Some(keys) => keys.lock().unwrap().push(key),
+            None => apply_key(&mut self.mmio.lock().unwrap(), key),
+        }
+    }
+
+    fn handle_input(_info: &CanvasInfo, state: &mut MyState, event: &Event<()>) -> bool {
+        match event {
+            // Match a keypress with scancode "key"
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            glutin::event::KeyboardInput {
+                                state: glutin::event::ElementState::Pressed,
+                                scancode: key,
+                                virtual_keycode,
+                                ..
+                            },
+                        is_synthetic: false,
+                        ..
+                    },
+                ..
+            } => {
+                // The keys of the settings don't get to the program
+                if let Some(code) = virtual_keycode.filter(|&code| Settings::is_hotkey(code)) {
+                    state.hotkey(code);
+                    return true;
+                }
+                state.key(Key { scancode: *key, pressed: true });
+                true
+            }
+
+            // Match a keyup with scancode "key"
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            glutin::event::KeyboardInput {
+                                state: glutin::event::ElementState::Released,
+                                scancode: key,
+                                virtual_keycode,
+                                ..
+                            },
+                        is_synthetic: false,
+                        ..
+                    },
+                ..
+            } => {
+                if virtual_keycode.is_some_and(Settings::is_hotkey) {
+                    return true;
+                }
+                state.key(Key { scancode: *key, pressed: false });
+                true
+            }
+
+            _ => false,
+        }
+    }
+}
+
+// TODO: change the color format in pixel-canvas to ClientFormat::U8
+fn mmio_color_to_rgb(x: u8) -> Color {
+    let r = x & 0b111;
+    let g = (x >> 3) & 0b111;
+    let b = x >> 6;
+    Color {
+        r: r * 36,
+        g: g * 36,
+        b: b * 85,
+    }
+}
+
+/// Draws the current frame of `mmio` in `image`, which is `settings.scale` times the size of the
+/// video
+fn draw(mmio: &[u8], settings: &Settings, image: &mut [Color]) {
+    let frame = mmio[FRAME_SELECT];
+    let start = if frame == 0 { FRAME_0 } else { FRAME_1 };
+    let scale = settings.scale;
+
+    // Draw each MMIO pixel as a square of `scale` by `scale`
+    for (y, row) in image.chunks_mut(scale * WIDTH).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let (x, y) = (x / scale, HEIGHT - 1 - y / scale);
+            let index = start + y * WIDTH + x;
+
+            let col = if cfg!(debug_assertions) {
+                *mmio
+                    .get(index)
+                    .expect("Out of bound access to the video memory!")
+            } else {
+                unsafe { *mmio.get_unchecked(index) }
+            };
+
+            // if col != 0xc7 {
+            *pixel = settings.palette.color(col);
+            // }
+        }
+    }
+}
+
+/// Opens the window and draws the current frame of the MMIO in it, counting the frames in `frames`.
+/// The keys go to `keys` if there's a queue for them, or straight to the MMIO.
+pub fn init(mmio: Arc<Mutex<Vec<u8>>>, frames: Arc<AtomicU64>, keys: Option<KeyQueue>) {
+    let settings = Settings::load(Path::new(SETTINGS_FILE));
+    let canvas = Canvas::new(settings.scale * WIDTH, settings.scale * HEIGHT)
+        .title("FPGRARS")
+        .state(MyState::new(mmio.clone(), keys, settings))
+        .input(MyState::handle_input);
+
+    #[cfg(feature = "show_ms")]
+    let canvas = canvas.show_ms(true);
+
+    canvas.render(move |state, image| {
+        let settings = state.settings;
+        if state.drawn_at.is_some_and(|at| settings.too_soon(at)) {
+            return;
+        }
+        state.drawn_at = Some(Instant::now());
+        frames.fetch_add(1, Ordering::Relaxed);
+
+        // The window takes the size of the image
+        if image.width() != settings.scale * WIDTH {
+            *image = Image::new(settings.scale * WIDTH, settings.scale * HEIGHT);
+        }
+        draw(&mmio.lock().unwrap(), &settings, image);
+
+        // Alternative, possibly slower, implementation:
+
+        // let mut set = move |i, col| {
+        //     if cfg!(debug_assertions) {
+        //         *image
+        //             .get_mut(i)
+        //             .expect("Out of bounds access to the video memory!") = mmio_color_to_rgb(col);
+        //     } else {
+        //         unsafe {
+        //             *image.get_unchecked_mut(i) = mmio_color_to_rgb(col);
+        //         }
+        //     }
+        // };
+
+        // for i in 0..FRAME_SIZE {
+        //     let col = mmio[i + start];
+
+        //     // 0xC7 is "transparent"
+        //     if col != 0xC7 {
+        //         // Don't ask
+        //         // TODO: if this is too slow, we can try filling in line by line,
+        //         // as every other line is just a copy of the one above it
+        //         {
+        //             set((i % WIDTH) * 2 + (i / WIDTH) * WIDTH * 4, col);
+        //             set(1 + (i % WIDTH) * 2 + (i / WIDTH) * WIDTH * 4, col);
+        //             set((i % WIDTH) * 2 + (i / WIDTH) * WIDTH * 4 + 2 * WIDTH, col);
+        //             set(
+        //                 1 + (i % WIDTH) * 2 + (i / WIDTH) * WIDTH * 4 + 2 * WIDTH,
+        //                 col,
+        //             );
+        //         }
+        //     }
+        // }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(color: Color) -> (u8, u8, u8) {
+        (color.r, color.g, color.b)
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut settings = Settings::default();
+        assert!(settings.change(VirtualKeyCode::F2));
+        assert!(settings.change(VirtualKeyCode::F3));
+        assert!(settings.change(VirtualKeyCode::F4));
+        assert_eq!(settings, Settings { scale: 3, fps: 30, palette: Palette::Full });
+        assert!(!settings.change(VirtualKeyCode::A));
+        assert!(Settings::is_hotkey(VirtualKeyCode::F6) && !Settings::is_hotkey(VirtualKeyCode::A));
+        // Both go around, through 4, 1 and 2 times and 15, 60 and 30 fps
+        for _ in 0..3 {
+            settings.change(VirtualKeyCode::F2);
+            settings.change(VirtualKeyCode::F3);
+        }
+        assert_eq!((settings.scale, settings.fps), (2, 30));
+
+        assert_eq!(rgb(Palette::Fpga.color(0xff)), (252, 252, 255));
+        assert_eq!(rgb(Palette::Full.color(0xff)), (255, 255, 255));
+        assert_eq!(rgb(Palette::Gray.color(0b111)), (75, 75, 75));
+
+        // The last pixel of the video is at the bottom right, 3 by 3 in the window, whose rows
+        // go from the bottom up
+        let mut mmio = vec![0; FRAME_SELECT + 1];
+        mmio[WIDTH * HEIGHT - 1] = 0b111;
+        let settings = Settings { scale: 3, ..settings };
+        let mut image = vec![Color::default(); 9 * WIDTH * HEIGHT];
+        draw(&mmio, &settings, &mut image);
+        let lit: Vec<usize> = (0..image.len()).filter(|&i| image[i].r != 0).collect();
+        let row = 3 * WIDTH;
+        assert_eq!(lit.len(), 9);
+        assert_eq!((lit[0], lit[8]), (row - 3, 3 * row - 1));
+    }
+
+    #[test]
+    fn test_settings_file() {
+        let settings = Settings { scale: 3, fps: 30, palette: Palette::Gray };
+        assert_eq!(settings.to_toml(), "scale = 3\nfps = 30\npalette = \"gray\"\n");
+        assert_eq!(Settings::parse(&settings.to_toml()), Ok(settings));
+        // What's missing keeps its default, and comments are skipped
+        let slow = Settings { fps: 15, ..Settings::default() };
+        assert_eq!(Settings::parse("# the window\n\nfps = 15 # slowly"), Ok(slow));
+        assert!(Settings::parse("scale = 5").is_err());
+        assert!(Settings::parse("palette = \"pink\"").is_err());
+        assert!(Settings::parse("volume = 11").is_err());
+        assert!(Settings::parse("scale 2").is_err());
+
+        // Saved, and read again like F5 does
+        let path = std::env::temp_dir().join(format!("fpgrars-settings-{}.toml", std::process::id()));
+        assert_eq!(Settings::load(&path), Settings::default());
+        settings.write(&path).unwrap();
+        assert_eq!(Settings::read(&path), Ok(settings));
+        assert_eq!(Settings::load(&path), settings);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+//!
+//! Runs the [riscv-tests](https://github.com/riscv-software-src/riscv-tests) ISA suite, so we can
+//! check the simulator against the official tests (mainly `isa/rv32ui` and `isa/rv32um`).
+//!
+//! The tests are assembly sources written for the C preprocessor. We run them through `cpp` with our
+//! own `riscv_test.h`, which replaces the usual test environment by a couple of ecalls, while
+//! `test_macros.h` is taken from the suite itself (`isa/macros/scalar`). A test passes if it exits
+//! with code 0, otherwise the exit code is the number of the test case that failed.
+//!
+//! Every test runs in its own FPGRARS process, so a test that hangs or crashes the simulator
+//! doesn't take the whole suite down with it.
+//!
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::simulator::Simulator;
+
+/// How long a single test may run before we consider it stuck
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Our replacement for the `riscv_test.h` of the test environments
+const COMPAT_HEADER: &str = r#"
+#define RVTEST_RV32U .text
+#define RVTEST_RV64U .text
+#define RVTEST_RV32M .text
+#define RVTEST_RV64M .text
+#define TESTNUM gp
+#define RVTEST_CODE_BEGIN .text; main:
+#define RVTEST_CODE_END
+#define RVTEST_PASS li a0, 0; li a7, 93; ecall
+#define RVTEST_FAIL mv a0, TESTNUM; li a7, 93; ecall
+#define RVTEST_DATA_BEGIN .data
+#define RVTEST_DATA_END
+#define EXTRA_DATA
+"#;
+
+/// The outcome of a single test file
+enum Outcome {
+    Pass,
+    /// Failed at the given test case
+    Fail(i32),
+    /// Couldn't be preprocessed or parsed
+    Error(String),
+    Timeout,
+}
+
+/// Runs every `.S` test found in `path` (or `path` itself if it's a file) and prints a report.
+/// Returns whether all the tests passed.
+pub fn run(path: &str) -> io::Result<bool> {
+    let path = Path::new(path);
+    let mut tests: Vec<PathBuf> = if path.is_dir() {
+        fs::read_dir(path)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|ext| ext == "S"))
+            .collect()
+    } else {
+        vec![path.to_owned()]
+    };
+    tests.sort();
+
+    let workdir = std::env::temp_dir().join(format!("fpgrars-riscv-tests-{}", std::process::id()));
+    fs::create_dir_all(&workdir)?;
+    fs::write(workdir.join("riscv_test.h"), COMPAT_HEADER)?;
+
+    let mut passed = 0;
+    for test in &tests {
+        let name = test.file_stem().unwrap_or_default().to_string_lossy();
+        match run_test(test, &workdir)? {
+            Outcome::Pass => {
+                passed += 1;
+                println!("PASS    {}", name);
+            }
+            Outcome::Fail(case) => println!("FAIL    {} (test case #{})", name, case),
+            Outcome::Error(e) => println!("ERROR   {}: {}", name, e.replace('\n', " ")),
+            Outcome::Timeout => println!("TIMEOUT {}", name),
+        }
+    }
+
+    fs::remove_dir_all(&workdir)?;
+
+    println!("\n{}/{} tests passed", passed, tests.len());
+    Ok(passed == tests.len())
+}
+
+fn run_test(test: &Path, workdir: &Path) -> io::Result<Outcome> {
+    let source = match preprocess(test, workdir)? {
+        Ok(source) => split_statements(&source),
+        Err(e) => return Ok(Outcome::Error(e)),
+    };
+
+    // Parse it here first, so we can report errors properly
+    let lines = source.lines().map(str::to_owned);
+    if let Err(e) = Simulator::new().load_from_lines(lines, test.to_owned()) {
+        return Ok(Outcome::Error(e.to_string()));
+    }
+
+    let file = workdir.join(test.file_name().unwrap_or_default()).with_extension("s");
+    fs::write(&file, source)?;
+
+    let mut child = Command::new(std::env::current_exe()?)
+        .arg("--no-video")
+        .arg(&file)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() > TIMEOUT {
+            child.kill()?;
+            child.wait()?;
+            return Ok(Outcome::Timeout);
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+
+    Ok(match status.code() {
+        Some(0) => Outcome::Pass,
+        Some(case) => Outcome::Fail(case),
+        None => Outcome::Error("the simulator crashed".to_owned()),
+    })
+}
+
+/// Runs a test through the C preprocessor and returns either the output or cpp's complaints
+fn preprocess(test: &Path, workdir: &Path) -> io::Result<Result<String, String>> {
+    let test_dir = test.parent().unwrap_or_else(|| Path::new("."));
+
+    let output = Command::new("cpp")
+        .args(["-x", "assembler-with-cpp", "-P", "-D__riscv_xlen=32"])
+        .arg("-I")
+        .arg(workdir)
+        .arg("-I")
+        .arg(test_dir.join("../macros/scalar"))
+        .arg(test)
+        .output()?;
+
+    if !output.status.success() {
+        return Ok(Err(String::from_utf8_lossy(&output.stderr).into_owned()));
+    }
+
+    Ok(Ok(String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+/// The preprocessor expands each macro into a single line, with the statements separated by `;`,
+/// but we expect one statement per line
+fn split_statements(source: &str) -> String {
+    let mut res = String::with_capacity(source.len());
+    let mut quoted = None;
+    let mut escaped = false;
+
+    for c in source.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted.is_some() => escaped = true,
+            '"' | '\'' if quoted == Some(c) => quoted = None,
+            '"' | '\'' if quoted.is_none() => quoted = Some(c),
+            ';' if quoted.is_none() => {
+                res.push('\n');
+                continue;
+            }
+            _ => {}
+        }
+        res.push(c);
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_statements() {
+        assert_eq!(
+            split_statements("test_2: li gp, 2; add x14, x1, x2; bne x14, x7, fail;"),
+            "test_2: li gp, 2\n add x14, x1, x2\n bne x14, x7, fail\n"
+        );
+        assert_eq!(
+            split_statements(".asciz \"a;b\"; li t0, ';'"),
+            ".asciz \"a;b\"\n li t0, ';'"
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//!
+//! What `--bench` shows when the program exits: how many instructions we ran per second, and how
+//! much of the time went to ecalls, like printing, reading files or sleeping, instead of running
+//! instructions. The ecalls take as long as they take whatever the simulator does, so it's the
+//! instructions per second of the execution alone that show if the simulator got slower.
+//!
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::{EcallSignal, Simulator};
+
+/// The ecalls of a run, counted when the [bench](../struct.Simulator.html#method.bench) is on
+#[derive(Debug, Default)]
+pub struct Bench {
+    ecalls: u64,
+    in_ecalls: Duration,
+}
+
+impl Bench {
+    /// The report of a run that took `elapsed` to run `instructions` instructions
+    pub fn report(&self, instructions: u64, elapsed: Duration) -> Report<'_> {
+        Report {
+            bench: self,
+            instructions,
+            elapsed,
+        }
+    }
+}
+
+pub struct Report<'a> {
+    bench: &'a Bench,
+    instructions: u64,
+    elapsed: Duration,
+}
+
+/// Millions of instructions per second, or 0 if no time passed
+fn mips(instructions: u64, elapsed: Duration) -> f64 {
+    match elapsed.as_secs_f64() {
+        secs if secs > 0.0 => instructions as f64 / secs / 1e6,
+        _ => 0.0,
+    }
+}
+
+fn ms(elapsed: Duration) -> f64 {
+    elapsed.as_secs_f64() * 1000.0
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Report { bench, instructions, elapsed } = *self;
+        let execution = elapsed.saturating_sub(bench.in_ecalls);
+        let share = match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => bench.in_ecalls.as_secs_f64() / secs * 100.0,
+            _ => 0.0,
+        };
+
+        writeln!(
+            f,
+            "{} instructions in {:.2}ms, {:.2} MIPS",
+            instructions,
+            ms(elapsed),
+            mips(instructions, elapsed)
+        )?;
+        writeln!(f, "  ecalls:    {} taking {:.2}ms ({:.1}%)", bench.ecalls, ms(bench.in_ecalls), share)?;
+        writeln!(f, "  execution: {:.2}ms, {:.2} MIPS", ms(execution), mips(instructions, execution))
+    }
+}
+
+impl Simulator {
+    /// Runs an ecall and adds how long it took to the [Bench](struct.Bench.html)
+    pub(super) fn timed_ecall(&mut self) -> EcallSignal {
+        let began = Instant::now();
+        let signal = self.ecall();
+        self.stats.bench.ecalls += 1;
+        self.stats.bench.in_ecalls += began.elapsed();
+        signal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bench() {
+        // Sleeps for 20ms in the middle of a few instructions
+        let code = "li a0, 20\nli a7, 32\necall\nli t0, 100\nloop: addi t0, t0, -1\nbnez t0, loop\nli a7, 10\necall";
+        let mut sim = Simulator::new()
+            .bench(true)
+            .load_str(code)
+            .unwrap();
+        sim.run();
+        assert_eq!(sim.stats.bench.ecalls, 2);
+        assert!(sim.stats.bench.in_ecalls >= Duration::from_millis(20));
+
+        let bench = Bench {
+            ecalls: 3,
+            in_ecalls: Duration::from_millis(250),
+        };
+        let report = bench.report(2_000_000, Duration::from_secs(1)).to_string();
+        assert_eq!(
+            report,
+            "2000000 instructions in 1000.00ms, 2.00 MIPS\n  ecalls:    3 taking 250.00ms (25.0%)\n  execution: 750.00ms, 2.67 MIPS\n"
+        );
+        assert!(bench.report(0, Duration::ZERO).to_string().contains("0.00 MIPS"));
+    }
+}
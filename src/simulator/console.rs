@@ -0,0 +1,177 @@
+//!
+//! Everything the program prints with the print ecalls goes through here. A program that prints in
+//! an infinite loop can flood the terminal until it's unusable, so the output can be limited with
+//! `--console-limit`: after that many bytes, we say the output was truncated and drop the rest.
+//! With the [tui](../tui/index.html), the output is kept here instead, to be shown in its pane.
+//! Only its last [CAPTURED_LINES](constant.CAPTURED_LINES.html) lines are kept, so a program that
+//! prints forever doesn't take all the memory.
+//!
+
+use std::collections::VecDeque;
+use std::fmt;
+
+/// How many of the last lines of the output we keep when it's captured
+const CAPTURED_LINES: usize = 1000;
+
+/// The last lines of the output, the last one still being written
+#[derive(Debug)]
+struct Captured {
+    lines: VecDeque<String>,
+    /// How many bytes were captured, counting the ones of the lines that were dropped
+    total: usize,
+}
+
+impl Captured {
+    fn push_str(&mut self, s: &str) {
+        let mut pieces = s.split('\n');
+        if let (Some(first), Some(last)) = (pieces.next(), self.lines.back_mut()) {
+            last.push_str(first);
+        }
+        self.lines.extend(pieces.map(str::to_owned));
+        while self.lines.len() > CAPTURED_LINES {
+            self.lines.pop_front();
+        }
+        self.total += s.len();
+    }
+
+    fn text(&self) -> String {
+        Vec::from(self.lines.clone()).join("\n")
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Console {
+    /// Maximum number of bytes we print, if there is one
+    limit: Option<usize>,
+    /// Bytes printed so far
+    written: usize,
+    /// Bytes we didn't print because of the limit
+    dropped: usize,
+    /// The output kept instead of printed, when it's captured
+    captured: Option<Captured>,
+}
+
+impl Console {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Keeps the output from now on instead of printing it
+    pub fn capture(&mut self) {
+        self.captured.get_or_insert_with(|| Captured { lines: VecDeque::from([String::new()]), total: 0 });
+    }
+
+    /// The output kept since [capture](#method.capture), as far as its last lines go
+    pub fn captured(&self) -> String {
+        self.captured.as_ref().map(Captured::text).unwrap_or_default()
+    }
+
+    /// The lines of the output that are kept, see [captured](#method.captured)
+    pub fn captured_lines(&self) -> impl Iterator<Item = &str> {
+        self.captured.iter().flat_map(|captured| captured.lines.iter().map(String::as_str))
+    }
+
+    /// The output captured after its first `sent` bytes, as far as it's still kept, and how many
+    /// bytes were captured in all
+    pub fn captured_after(&self, sent: usize) -> (String, usize) {
+        let Some(captured) = &self.captured else { return (String::new(), 0) };
+        let text = captured.text();
+        let kept_from = captured.total - text.len();
+        (text[sent.saturating_sub(kept_from).min(text.len())..].to_owned(), captured.total)
+    }
+
+    pub fn print(&mut self, x: impl fmt::Display) {
+        let s = x.to_string();
+        let was_truncated = self.dropped > 0;
+        let s = self.take(&s);
+        match &mut self.captured {
+            Some(captured) => captured.push_str(s),
+            None => print!("{}", s),
+        }
+
+        if self.dropped > 0 && !was_truncated {
+            let message = format!("[output truncated after {} bytes]", self.written);
+            match &mut self.captured {
+                Some(captured) => captured.push_str(&format!("\n{}\n", message)),
+                None => {
+                    println!();
+                    eprintln!("{}", message);
+                }
+            }
+        }
+    }
+
+    /// The part of `s` that still fits in the limit. Once something is dropped, everything else is too
+    fn take<'a>(&mut self, s: &'a str) -> &'a str {
+        let room = match self.limit {
+            _ if self.dropped > 0 => 0,
+            Some(limit) => limit.saturating_sub(self.written),
+            None => s.len(),
+        };
+
+        let mut end = room.min(s.len());
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.written += end;
+        self.dropped += s.len() - end;
+        &s[..end]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_console_limit() {
+        let mut console = Console::new(Some(8));
+        assert_eq!(console.take("hello "), "hello ");
+        assert_eq!(console.take("aáb"), "a"); // doesn't cut `á` in half
+        assert_eq!(console.take("c"), "");
+        assert_eq!((console.written, console.dropped()), (7, 4));
+
+        let mut console = Console::new(None);
+        assert_eq!(console.take(&"a".repeat(1000)).len(), 1000);
+        assert_eq!(console.dropped(), 0);
+
+        let mut console = Console::new(Some(4));
+        console.capture();
+        console.print("abc");
+        console.print(12);
+        assert_eq!(console.captured(), "abc1\n[output truncated after 4 bytes]\n");
+    }
+
+    #[test]
+    fn test_captured_lines() {
+        let mut console = Console::new(None);
+        console.capture();
+        for i in 0..CAPTURED_LINES + 10 {
+            console.print(format!("line {}\n", i));
+        }
+        console.print("last, without a newline");
+
+        // Only the last lines are kept, the last one still being written
+        let lines: Vec<&str> = console.captured_lines().collect();
+        assert_eq!(lines.len(), CAPTURED_LINES);
+        assert_eq!(lines[0], "line 11");
+        assert_eq!(lines[CAPTURED_LINES - 2], format!("line {}", CAPTURED_LINES + 9));
+        assert_eq!(lines[CAPTURED_LINES - 1], "last, without a newline");
+        assert!(console.captured().starts_with("line 11\nline 12\n"));
+
+        // What comes after some bytes, even if the lines they were in were dropped
+        let (after, total) = console.captured_after(0);
+        assert_eq!(after, console.captured());
+        let (after, _) = console.captured_after(total - 7);
+        assert_eq!(after, "newline");
+        assert_eq!(console.captured_after(total), (String::new(), total));
+    }
+}
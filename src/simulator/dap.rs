@@ -0,0 +1,539 @@
+//!
+//! A server of the Debug Adapter Protocol, for `--dap PORT`, so an editor like VS Code can debug
+//! the program with breakpoints in the `.s` files, with `"debugServer": PORT` in its `launch.json`.
+//! Once the program is loaded, we wait for the editor to connect, and run the program once it's
+//! done setting the breakpoints, from its first instruction if `stopOnEntry` is set.
+//!
+//! Stepping works like the `step`, `next` and `finish` of the [debugger](../debugger/index.html),
+//! the stack frames are the calls we find on the stack, like when it overflows, and the variables
+//! are the registers. Stepping back goes back over the [history](../history/index.html). The
+//! output of the program goes to the debug console of the editor.
+//!
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+
+use fnv::FnvHashMap;
+use serde_json::{json, Value};
+
+use super::debugger::{Mode, Resume};
+use super::Simulator;
+use crate::parser::register_names::{FLOAT_NAMES, REGISTER_NAMES};
+
+/// How many instructions run between two checks for a request while the program runs
+const POLL_EVERY: u64 = 1 << 16;
+
+/// The `variablesReference` of each scope, which are the same in every frame
+const INTEGER_REGISTERS: u64 = 1;
+const FLOAT_REGISTERS: u64 = 2;
+
+pub struct Dap {
+    listener: TcpListener,
+    /// The connection, once the editor connects
+    stream: Option<BufReader<TcpStream>>,
+    /// The number of the last message we sent
+    seq: u64,
+    stop_on_entry: bool,
+    /// Whether the program stopped before
+    stopped: bool,
+    /// The addresses of the breakpoints of each file, which the editor sets all at once
+    breakpoints: FnvHashMap<String, Vec<usize>>,
+    /// How much of the output of the program we already sent
+    output_sent: usize,
+    /// Instructions until the next check for a request while running
+    countdown: u64,
+}
+
+/// What a request asks us to do with the program
+enum Action {
+    Nothing,
+    Run(Mode),
+    Quit,
+}
+
+impl Dap {
+    /// Listens on `port` of this machine only, since whoever connects can change its memory
+    pub(super) fn listen(port: u16) -> io::Result<Self> {
+        Ok(Self::new(TcpListener::bind(("127.0.0.1", port))?))
+    }
+
+    fn new(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            stream: None,
+            seq: 0,
+            stop_on_entry: false,
+            stopped: false,
+            breakpoints: FnvHashMap::default(),
+            output_sent: 0,
+            countdown: POLL_EVERY,
+        }
+    }
+
+    /// Reads the next message, or returns `None` if the editor is gone
+    fn read_message(&mut self) -> Option<Value> {
+        let stream = self.stream.as_mut()?;
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length:") {
+                length = value.trim().parse().ok();
+            }
+        }
+        let mut body = vec![0; length?];
+        stream.read_exact(&mut body).ok()?;
+        serde_json::from_slice(&body).ok()
+    }
+
+    fn send(&mut self, mut message: Value) {
+        let Some(stream) = self.stream.as_mut() else { return };
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        // If the editor is gone, the next read says so
+        let _ = write!(stream.get_mut(), "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn fail(&mut self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    /// Whether the editor sent something, without waiting for it
+    fn has_message(&mut self) -> bool {
+        let Some(stream) = self.stream.as_mut() else { return false };
+        if !stream.buffer().is_empty() {
+            return true;
+        }
+        if stream.get_ref().set_nonblocking(true).is_err() {
+            return false;
+        }
+        let filled = stream.fill_buf().map(|buffer| !buffer.is_empty());
+        let _ = stream.get_ref().set_nonblocking(false);
+        matches!(filled, Ok(true))
+    }
+}
+
+/// Whether `a` and `b` are paths of the same file
+fn same_file(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+    match (Path::new(a).canonicalize(), Path::new(b).canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+impl Simulator {
+    /// Waits for the editor to connect once the program is loaded, and answers it until it's done
+    /// setting the breakpoints
+    pub(super) fn init_dap(&mut self) {
+        let Some(mut dap) = self.dap.take() else { return };
+        if dap.stream.is_none() {
+            if let Ok(address) = dap.listener.local_addr() {
+                eprintln!("Waiting for the editor to connect on port {}", address.port());
+            }
+            match dap.listener.accept() {
+                Ok((stream, _)) => {
+                    let _ = stream.set_nodelay(true);
+                    dap.stream = Some(BufReader::new(stream));
+                }
+                Err(e) => eprintln!("Warning: the editor couldn't connect: {}", e),
+            }
+            self.console.capture();
+        }
+
+        while let Some(request) = dap.read_message() {
+            if request["command"] == "configurationDone" {
+                dap.respond(&request, json!({}));
+                break;
+            }
+            if let Action::Quit = self.dap_request(&mut dap, &request) {
+                break;
+            }
+        }
+        // Starts paused only if the editor asked to
+        if !dap.stop_on_entry {
+            self.debugger.set_mode(Mode::Run);
+        }
+        self.dap = Some(dap);
+    }
+
+    /// Sends the rest of the output, and tells the editor the program exited
+    pub(super) fn finish_dap(&mut self, exit_code: i32) {
+        let Some(mut dap) = self.dap.take() else { return };
+        self.send_output(&mut dap);
+        dap.event("exited", json!({ "exitCode": exit_code }));
+        dap.event("terminated", json!({}));
+        self.dap = Some(dap);
+    }
+
+    /// Sends what the program printed since the last time
+    fn send_output(&self, dap: &mut Dap) {
+        let (new, total) = self.console.captured_after(dap.output_sent);
+        if total > dap.output_sent {
+            dap.output_sent = total;
+            dap.event("output", json!({ "category": "stdout", "output": new }));
+        }
+    }
+
+    /// Tells the editor the program stopped because of `what`, and answers it until it runs the
+    /// program again
+    pub(super) fn dap_pause(&mut self, what: &str) -> Resume {
+        let Some(mut dap) = self.dap.take() else { return Resume::Continue };
+        let reason = match what {
+            "the breakpoint" => "breakpoint",
+            "the ebreak" => "exception",
+            _ if !dap.stopped => "entry",
+            "" => "step",
+            _ => "pause",
+        };
+        dap.stopped = true;
+        self.send_output(&mut dap);
+        dap.event("stopped", json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true }));
+
+        let resume = loop {
+            let Some(request) = dap.read_message() else {
+                // Without the editor, the program runs to the end
+                self.debugger.set_mode(Mode::Run);
+                break Resume::Continue;
+            };
+            match self.dap_request(&mut dap, &request) {
+                Action::Nothing => {}
+                Action::Run(mode) => {
+                    self.debugger.set_mode(mode);
+                    break Resume::Continue;
+                }
+                Action::Quit => break Resume::Quit,
+            }
+        };
+        self.dap = Some(dap);
+        resume
+    }
+
+    /// Answers the requests that came while the program runs, every so many instructions
+    pub(super) fn dap_tick(&mut self) -> Resume {
+        let Some(dap) = &mut self.dap else { return Resume::Continue };
+        dap.countdown -= 1;
+        if dap.countdown > 0 {
+            return Resume::Continue;
+        }
+        dap.countdown = POLL_EVERY;
+
+        let mut dap = self.dap.take().unwrap();
+        self.send_output(&mut dap);
+        let mut action = Action::Nothing;
+        while dap.has_message() {
+            let Some(request) = dap.read_message() else { break };
+            action = self.dap_request(&mut dap, &request);
+            if !matches!(action, Action::Nothing) {
+                break;
+            }
+        }
+        self.dap = Some(dap);
+        match action {
+            Action::Run(Mode::Step) => self.dap_pause("pause"),
+            Action::Quit => Resume::Quit,
+            _ => Resume::Continue,
+        }
+    }
+
+    /// Answers a request, and returns what it asks to do with the program
+    fn dap_request(&mut self, dap: &mut Dap, request: &Value) -> Action {
+        let args = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or("");
+        let body = match command {
+            "initialize" => {
+                dap.respond(request, json!({ "supportsConfigurationDoneRequest": true, "supportsTerminateRequest": true, "supportsStepBack": true }));
+                dap.event("initialized", json!({}));
+                return Action::Nothing;
+            }
+            "launch" | "attach" => {
+                dap.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                json!({})
+            }
+            "setBreakpoints" => {
+                let path = args["source"]["path"].as_str().unwrap_or("");
+                let lines: Vec<usize> = match args["breakpoints"].as_array() {
+                    Some(breakpoints) => breakpoints.iter().filter_map(|b| b["line"].as_u64()).map(|l| l as usize).collect(),
+                    None => Vec::new(),
+                };
+                for address in dap.breakpoints.remove(path).unwrap_or_default() {
+                    self.debugger.remove_breakpoint(address);
+                }
+                let mut addresses = Vec::new();
+                let mut breakpoints = Vec::new();
+                for line in lines {
+                    match self.code_at_line(path, line) {
+                        Some((address, line)) => {
+                            self.debugger.add_breakpoint(address);
+                            addresses.push(address);
+                            breakpoints.push(json!({ "verified": true, "line": line }));
+                        }
+                        None => breakpoints.push(json!({ "verified": false, "line": line, "message": "No code here" })),
+                    }
+                }
+                dap.breakpoints.insert(path.to_owned(), addresses);
+                json!({ "breakpoints": breakpoints })
+            }
+            "setExceptionBreakpoints" => json!({}),
+            "configurationDone" => json!({}),
+            "threads" => json!({ "threads": [{ "id": 1, "name": "main" }] }),
+            "stackTrace" => json!({ "stackFrames": self.stack_frames() }),
+            "scopes" => json!({ "scopes": [
+                { "name": "Registers", "variablesReference": INTEGER_REGISTERS, "expensive": false },
+                { "name": "Float registers", "variablesReference": FLOAT_REGISTERS, "expensive": false },
+            ] }),
+            "variables" => json!({ "variables": self.variables(args["variablesReference"].as_u64().unwrap_or(0)) }),
+            "continue" | "next" | "stepIn" | "stepOut" | "pause" => {
+                let mode = match command {
+                    "continue" => Mode::Run,
+                    "next" => Mode::Over(0),
+                    "stepOut" => Mode::Over(1),
+                    _ => Mode::Step,
+                };
+                dap.respond(request, json!({ "allThreadsContinued": true }));
+                return Action::Run(mode);
+            }
+            "stepBack" | "reverseContinue" => {
+                let back = if command == "stepBack" { self.step_back() } else { self.reverse_continue() };
+                let reason = if back && command == "reverseContinue" { "breakpoint" } else { "step" };
+                dap.respond(request, json!({}));
+                dap.event("stopped", json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true }));
+                return Action::Nothing;
+            }
+            "disconnect" | "terminate" => {
+                dap.respond(request, json!({}));
+                return Action::Quit;
+            }
+            _ => {
+                dap.fail(request, &format!("`{}` isn't supported", command));
+                return Action::Nothing;
+            }
+        };
+        dap.respond(request, body);
+        Action::Nothing
+    }
+
+    /// The address of the first instruction of `line` of the file at `path`, or of the next line
+    /// with code, and that line
+    fn code_at_line(&self, path: &str, line: usize) -> Option<(usize, usize)> {
+        let mut same: FnvHashMap<&str, bool> = FnvHashMap::default();
+        let mut best: Option<(usize, usize)> = None;
+        for (slot, pos) in self.code_pos.iter().enumerate() {
+            let Some(pos) = pos else { continue };
+            if pos.line < line || best.is_some_and(|(_, best)| pos.line >= best) {
+                continue;
+            }
+            if *same.entry(&pos.file).or_insert_with(|| same_file(&pos.file, path)) {
+                best = Some((self.code_address(slot), pos.line));
+            }
+        }
+        best
+    }
+
+    /// The pc and the calls that led to it, most recent first
+    fn stack_frames(&self) -> Vec<Value> {
+        let mut calls = vec![self.pc];
+        // A function that doesn't call others doesn't push `ra`
+        let ra = self.get_reg::<u32>(1) as usize;
+        let trace = self.call_trace(self.stack_of_current());
+        if let Some(call) = self.call_before(ra) {
+            if trace.first() != Some(&call) {
+                calls.push(call);
+            }
+        }
+        calls.extend(trace);
+
+        let mut frames = Vec::new();
+        for (id, &pc) in calls.iter().enumerate() {
+            let name = match self.function_at(pc) {
+                Some(name) => name.to_owned(),
+                None => format!("{:#x}", pc),
+            };
+            let mut frame = json!({ "id": id, "name": name, "line": 0, "column": 0 });
+            if let Some(Some(pos)) = self.code_pos.get(self.slot(pc)) {
+                frame["line"] = json!(pos.line);
+                frame["column"] = json!(1);
+                frame["source"] = json!({ "name": pos.file, "path": pos.file });
+            }
+            frames.push(frame);
+        }
+        frames
+    }
+
+    fn variables(&self, reference: u64) -> Vec<Value> {
+        let variable = |name: &str, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        match reference {
+            INTEGER_REGISTERS => {
+                let width = if self.rv64 { 18 } else { 10 };
+                let mut variables = vec![variable("pc", format!("{:#0width$x}", self.pc, width = width))];
+                for (i, name) in REGISTER_NAMES.iter().enumerate() {
+                    let value = if self.rv64 {
+                        format!("{:#018x} ({})", self.registers[i], self.registers[i] as i64)
+                    } else {
+                        format!("{:#010x} ({})", self.registers[i] as u32, self.registers[i] as i32)
+                    };
+                    variables.push(variable(name, value));
+                }
+                variables
+            }
+            FLOAT_REGISTERS => FLOAT_NAMES
+                .iter()
+                .zip(self.floats)
+                .map(|(name, x)| {
+                    // A single is NaN-boxed, anything else is a double
+                    let value = if x >> 32 == 0xffff_ffff { f32::from_bits(x as u32) as f64 } else { f64::from_bits(x) };
+                    variable(name, value.to_string())
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// The editor's side of the connection
+    struct Editor {
+        stream: BufReader<TcpStream>,
+        seq: u64,
+    }
+
+    impl Editor {
+        fn read(&mut self) -> Value {
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                self.stream.read_line(&mut line).unwrap();
+                match line.trim().strip_prefix("Content-Length:") {
+                    Some(value) => length = value.trim().parse().unwrap(),
+                    None if line.trim().is_empty() => break,
+                    None => {}
+                }
+            }
+            let mut body = vec![0; length];
+            self.stream.read_exact(&mut body).unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        /// Sends a request, and returns its response, skipping the events before it
+        fn ask(&mut self, command: &str, arguments: Value) -> Value {
+            self.seq += 1;
+            let body = json!({ "seq": self.seq, "type": "request", "command": command, "arguments": arguments }).to_string();
+            write!(self.stream.get_mut(), "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+            loop {
+                let message = self.read();
+                if message["type"] == "response" {
+                    assert_eq!(message["request_seq"], self.seq);
+                    return message;
+                }
+            }
+        }
+
+        /// Skips messages until the event `name`
+        fn event(&mut self, name: &str) -> Value {
+            loop {
+                let message = self.read();
+                if message["event"] == name {
+                    return message["body"].clone();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dap() {
+        let code = "main: li a0, 5\ncall double\nli a7, 1\necall\nli a7, 10\necall
+            double: addi sp, sp, -4\nsw ra, 0(sp)\ncall add\nlw ra, 0(sp)\naddi sp, sp, 4\nret
+            add: add a0, a0, a0\nret";
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut sim = Simulator::new()
+            .debug(true)
+            .load_str(code)
+            .unwrap();
+        sim.dap = Some(Dap::new(listener));
+
+        let editor = thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut editor = Editor { stream: BufReader::new(stream), seq: 0 };
+            let capabilities = editor.ask("initialize", json!({ "adapterID": "fpgrars" }));
+            assert_eq!(capabilities["body"]["supportsConfigurationDoneRequest"], true);
+            editor.event("initialized");
+            assert_eq!(editor.ask("launch", json!({ "stopOnEntry": false }))["success"], true);
+
+            // There's no code after the last line
+            let set = editor.ask("setBreakpoints", json!({ "source": { "path": "main.s" }, "breakpoints": [{ "line": 13 }, { "line": 30 }] }));
+            let breakpoints = &set["body"]["breakpoints"];
+            assert_eq!((&breakpoints[0]["verified"], &breakpoints[0]["line"]), (&json!(true), &json!(13)));
+            assert_eq!(breakpoints[1]["verified"], false);
+            editor.ask("configurationDone", json!({}));
+
+            assert_eq!(editor.event("stopped")["reason"], "breakpoint");
+            let frames = editor.ask("stackTrace", json!({ "threadId": 1 }));
+            let names: Vec<&str> = frames["body"]["stackFrames"].as_array().unwrap().iter().map(|f| f["name"].as_str().unwrap()).collect();
+            assert_eq!(names, ["add", "double", "main"]);
+            assert_eq!(frames["body"]["stackFrames"][0]["line"], 13);
+
+            let variables = editor.ask("variables", json!({ "variablesReference": INTEGER_REGISTERS }));
+            assert_eq!(variables["body"]["variables"][11], json!({ "name": "a0", "value": "0x00000005 (5)", "variablesReference": 0 }));
+
+            // Out of `add` and `double`, and to the end
+            editor.ask("stepOut", json!({ "threadId": 1 }));
+            assert_eq!(editor.event("stopped")["reason"], "step");
+            editor.ask("stepOut", json!({ "threadId": 1 }));
+            assert_eq!(editor.event("stopped")["reason"], "step");
+            let frames = editor.ask("stackTrace", json!({ "threadId": 1 }));
+            assert_eq!(frames["body"]["stackFrames"][0]["line"], 3);
+            assert_eq!(editor.ask("evaluate", json!({ "expression": "a0" }))["success"], false);
+
+            // Back out of `double`, and to the breakpoint
+            editor.ask("stepBack", json!({ "threadId": 1 }));
+            assert_eq!(editor.event("stopped")["reason"], "step");
+            let frames = editor.ask("stackTrace", json!({ "threadId": 1 }));
+            assert_eq!(frames["body"]["stackFrames"][0]["line"], 12);
+            editor.ask("reverseContinue", json!({ "threadId": 1 }));
+            assert_eq!(editor.event("stopped")["reason"], "breakpoint");
+            let frames = editor.ask("stackTrace", json!({ "threadId": 1 }));
+            assert_eq!(frames["body"]["stackFrames"][0]["line"], 13);
+            editor.ask("continue", json!({ "threadId": 1 }));
+            assert_eq!(editor.event("output")["output"], "10");
+            editor.event("exited")["exitCode"].clone()
+        });
+
+        assert_eq!(sim.run(), 0);
+        assert_eq!(editor.join().unwrap(), 0);
+    }
+}
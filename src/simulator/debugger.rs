@@ -0,0 +1,497 @@
+//!
+//! Breakpoints and the debugger. `--break` takes an address or a label, and can be given more than
+//! once. Right before the instruction there runs, the simulator pauses and shows the registers,
+//! and waits for Enter to go on, or `q` to stop the program. An `ebreak` without a trap handler
+//! pauses the same way, and then goes on after it.
+//!
+//! Pausing needs someone to press a key, so when the input isn't a terminal, like in a script, a
+//! breakpoint only shows the registers and goes on, and an `ebreak` stops the program like it used
+//! to.
+//!
+//! With `--debug`, the program starts paused at its first instruction, and each pause reads
+//! commands instead, even when the input isn't a terminal:
+//!
+//! - `step` (`s`) runs an instruction
+//! - `next` (`n`) runs an instruction, or a whole call
+//! - `finish` runs until the function returns
+//! - `continue` (`c`) runs until a breakpoint
+//! - `regs` (`r`) shows the registers
+//! - `x/LEN ADDR` shows LEN words of memory from ADDR, which can also be a register or a label
+//! - `break LOCATION` (`b`) adds a breakpoint, or lists them without a location
+//! - `back N` undoes the last N instructions, or the last one without N, and `rc` goes back to the
+//!   last breakpoint, see [history](../history/index.html)
+//! - `save PATH` saves a [snapshot](../snapshot/index.html) to PATH, or to the one of `--snapshot`
+//! - `quit` (`q`) stops the program
+//!
+//! An empty line runs the last command again. The simulator runs in its own thread, so the video
+//! keeps being drawn while it's paused. Calls and returns are told apart like in the
+//! [profile](../profile/index.html), so a function left some other way throws `next` and `finish`
+//! off.
+//!
+
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+
+use super::profile::Transfer;
+use super::Simulator;
+use crate::parser::combinators::integer_literal;
+use crate::parser::register_names::REGISTER_NAMES;
+use crate::parser::Segment;
+
+/// Where to put a breakpoint, as given in the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Address(usize),
+    Label(String),
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    /// Parses an address, like `0x400010`, or a label
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(address) = integer_literal(s) {
+            return Ok(Location::Address(address as usize));
+        }
+        let valid_label = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$';
+        if s.is_empty() || !s.chars().all(valid_label) {
+            return Err(format!("expected an address or a label, got `{}`", s));
+        }
+        Ok(Location::Label(s.to_owned()))
+    }
+}
+
+/// What to do after a pause
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Resume {
+    Continue,
+    Quit,
+    /// Stops the program with this exit code, like at a [limit](../limits/index.html)
+    Exit(i32),
+}
+
+/// When to pause, besides at the breakpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Mode {
+    Run,
+    Step,
+    /// Once the calls that began since are this many fewer than the ones that returned
+    Over(i64),
+}
+
+/// Where the keys come from
+enum Input {
+    /// There's no one to press them
+    None,
+    Stdin,
+    #[cfg(test)]
+    Keys(&'static [u8]),
+}
+
+pub struct Debugger {
+    locations: Vec<Location>,
+    /// The addresses of the `locations`, once the program is loaded
+    breakpoints: Vec<usize>,
+    input: Input,
+    /// Whether pausing reads commands, for `--debug`
+    commands: bool,
+    mode: Mode,
+    /// What the instruction that ran last does to the calls
+    last: Transfer,
+    last_command: String,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self {
+            locations: Vec::new(),
+            breakpoints: Vec::new(),
+            input: if io::stdin().is_terminal() { Input::Stdin } else { Input::None },
+            commands: false,
+            mode: Mode::Run,
+            last: Transfer::Nothing,
+            last_command: String::new(),
+        }
+    }
+}
+
+impl Debugger {
+    pub(super) fn set_locations(&mut self, locations: Vec<Location>) {
+        self.locations = locations;
+    }
+
+    /// Starts paused, and reads commands at each pause
+    pub(super) fn set_commands(&mut self) {
+        self.commands = true;
+        self.mode = Mode::Step;
+        self.input = Input::Stdin;
+    }
+
+    pub(super) fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    /// Says what the instruction about to run does to the calls, when it isn't the one after the
+    /// last, like after going back
+    pub(super) fn set_last(&mut self, last: Transfer) {
+        self.last = last;
+    }
+
+    #[cfg(test)]
+    pub(super) fn set_keys(&mut self, keys: &'static str) {
+        self.input = Input::Keys(keys.as_bytes());
+    }
+
+    pub(super) fn breakpoints(&self) -> &[usize] {
+        &self.breakpoints
+    }
+
+    pub(super) fn add_breakpoint(&mut self, address: usize) {
+        self.breakpoints.push(address);
+    }
+
+    pub(super) fn remove_breakpoint(&mut self, address: usize) {
+        if let Some(i) = self.breakpoints.iter().position(|&b| b == address) {
+            self.breakpoints.remove(i);
+        }
+    }
+
+    /// Whether the simulator has to check for a pause before each instruction
+    pub(super) fn is_on(&self) -> bool {
+        self.commands || !self.locations.is_empty()
+    }
+
+    pub(super) fn reads_commands(&self) -> bool {
+        self.commands
+    }
+
+    pub(super) fn is_interactive(&self) -> bool {
+        !matches!(self.input, Input::None)
+    }
+
+    /// Reads a line of keys, or returns `None` if there are no more
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        let read = match &mut self.input {
+            Input::None => return None,
+            Input::Stdin => io::stdin().read_line(&mut line),
+            #[cfg(test)]
+            Input::Keys(keys) => io::BufRead::read_line(keys, &mut line),
+        };
+        match read {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line),
+        }
+    }
+}
+
+impl Simulator {
+    /// Works out the addresses of the labels of the breakpoints, once the program is loaded
+    pub(super) fn init_breakpoints(&mut self) {
+        let mut breakpoints = Vec::new();
+        for location in &self.debugger.locations {
+            match (self.address_of(location), location) {
+                (Some(address), _) => breakpoints.push(address),
+                (None, Location::Label(name)) => eprintln!("Warning: there's no label `{}` in the code to break at", name),
+                (None, Location::Address(_)) => unreachable!(),
+            }
+        }
+        self.debugger.breakpoints = breakpoints;
+    }
+
+    /// The address of a location, if it's a label that's in the code
+    pub(super) fn address_of(&self, location: &Location) -> Option<usize> {
+        match location {
+            Location::Address(address) => Some(*address),
+            Location::Label(name) => self
+                .symbols
+                .iter()
+                .find(|s| s.name == *name && s.segment == Segment::Text)
+                .map(|s| s.address),
+        }
+    }
+
+    /// Pauses if there's a breakpoint at `pc`, or if a `step`, `next` or `finish` is done
+    pub(super) fn check_breakpoint(&mut self) -> Resume {
+        let slot = self.slot(self.pc);
+        let debugger = &mut self.debugger;
+        if let Mode::Over(depth) = &mut debugger.mode {
+            match debugger.last {
+                Transfer::Call => *depth += 1,
+                Transfer::Return => *depth -= 1,
+                Transfer::Nothing => {}
+            }
+        }
+        if debugger.commands {
+            debugger.last = Transfer::of(&self.code[slot]);
+        }
+
+        if self.debugger.breakpoints.contains(&self.pc) {
+            return self.pause("the breakpoint");
+        }
+        match self.debugger.mode {
+            Mode::Step => self.pause(""),
+            Mode::Over(depth) if depth <= 0 => self.pause(""),
+            _ if self.terminal.is_some() => self.tui_tick(),
+            _ if self.gdb.is_some() => self.gdb_tick(),
+            _ if self.dap.is_some() => self.dap_tick(),
+            _ => Resume::Continue,
+        }
+    }
+
+    /// Shows where we are and the registers, and waits for a key if there's someone to press it
+    pub(super) fn pause(&mut self, what: &str) -> Resume {
+        let slot = self.slot(self.pc);
+        if self.terminal.is_some() {
+            return self.tui_pause(what);
+        }
+        if self.gdb.is_some() {
+            return self.gdb_pause();
+        }
+        if self.dap.is_some() {
+            return self.dap_pause(what);
+        }
+        if self.debugger.commands {
+            self.show_pc();
+            return self.read_commands();
+        }
+
+        eprintln!("Paused at {} at {:#x}{}: {}", what, self.pc, self.source_at(self.pc), self.code[slot]);
+        if let Some(Some(pos)) = self.code_pos.get(slot) {
+            eprintln!("  {}", pos.source.trim());
+        }
+        eprint!("{}", self.registers_table());
+
+        if !self.debugger.is_interactive() {
+            return Resume::Continue;
+        }
+        eprint!("Press Enter to continue, or q and Enter to stop: ");
+        // Without more input, there's nothing to wait for
+        match self.debugger.read_line().as_deref().map(str::trim) {
+            Some("q" | "quit") => Resume::Quit,
+            _ => Resume::Continue,
+        }
+    }
+
+    /// Shows the instruction at the pc, where the program goes on from
+    fn show_pc(&self) {
+        let slot = self.slot(self.pc);
+        eprintln!("{:#x}{}: {}", self.pc, self.source_at(self.pc), self.code[slot]);
+    }
+
+    /// Runs the commands of the debugger until one of them goes on
+    fn read_commands(&mut self) -> Resume {
+        loop {
+            eprint!("(fpgrars) ");
+            let Some(line) = self.debugger.read_line() else {
+                // Without more input, the program runs to the end
+                self.debugger.mode = Mode::Run;
+                return Resume::Continue;
+            };
+            let mut command = line.trim().to_owned();
+            if command.is_empty() {
+                command = self.debugger.last_command.clone();
+            }
+            let (name, arg) = command.split_once(' ').unwrap_or((&command, ""));
+            let arg = arg.trim();
+
+            let mode = match name {
+                "" => continue,
+                "s" | "step" => Mode::Step,
+                "n" | "next" => Mode::Over(0),
+                "finish" => Mode::Over(1),
+                "c" | "continue" => Mode::Run,
+                "q" | "quit" => return Resume::Quit,
+                "r" | "regs" => {
+                    eprint!("{}", self.registers_table());
+                    continue;
+                }
+                "b" | "break" => {
+                    self.add_breakpoint(arg);
+                    continue;
+                }
+                "back" => {
+                    let n = if arg.is_empty() { Ok(1) } else { arg.parse::<usize>() };
+                    match n {
+                        Ok(n) => {
+                            eprintln!("{}", self.go_back(n));
+                            self.show_pc();
+                        }
+                        Err(_) => eprintln!("Expected a number of instructions, like in `back 4`, got `{}`", arg),
+                    }
+                    self.debugger.last_command = command;
+                    continue;
+                }
+                "rc" | "reverse-continue" => {
+                    if !self.reverse_continue() {
+                        eprintln!("Went back as far as the history goes");
+                    }
+                    self.show_pc();
+                    continue;
+                }
+                "save" => {
+                    eprintln!("{}", self.save_command(arg));
+                    continue;
+                }
+                "x" => {
+                    self.examine("1", arg);
+                    continue;
+                }
+                _ if name.starts_with("x/") => {
+                    self.examine(&name[2..], arg);
+                    continue;
+                }
+                "h" | "help" => {
+                    eprintln!("Commands: step, next, finish, continue, regs, x/LEN ADDR, break [LOCATION], back [N], rc, save [PATH], quit");
+                    continue;
+                }
+                _ => {
+                    eprintln!("Unknown command `{}`, see `help`", name);
+                    continue;
+                }
+            };
+            self.debugger.last_command = command;
+            self.debugger.mode = mode;
+            return Resume::Continue;
+        }
+    }
+
+    /// Adds a breakpoint at `location`, or lists them if it's empty
+    fn add_breakpoint(&mut self, location: &str) {
+        if location.is_empty() {
+            for &address in &self.debugger.breakpoints {
+                eprintln!("  {:#x}{}", address, self.source_at(address));
+            }
+            return;
+        }
+        match location.parse().ok().and_then(|location| self.address_of(&location)) {
+            Some(address) => {
+                self.debugger.add_breakpoint(address);
+                eprintln!("Breakpoint at {:#x}{}", address, self.source_at(address));
+            }
+            None => eprintln!("There's no label `{}` in the code", location),
+        }
+    }
+
+    /// Shows `len` words of memory from `addr`, 4 to a line
+    fn examine(&self, len: &str, addr: &str) {
+        let Ok(len) = len.parse::<usize>() else {
+            eprintln!("Expected a number of words, like in `x/4 sp`, got `{}`", len);
+            return;
+        };
+        let start = match self.resolve_address(addr) {
+            Some(start) => start,
+            None => {
+                eprintln!("Expected an address, a register or a label, got `{}`", addr);
+                return;
+            }
+        };
+
+        for line in (0..len).step_by(4) {
+            let mut text = format!("{:#010x}:", start + line * 4);
+            for i in line..len.min(line + 4) {
+                let address = start + i * 4;
+                if self.memory.is_mapped(address, 4) {
+                    text += &format!(" {:#010x}", self.memory.get_word(address));
+                } else {
+                    text += " ----------";
+                }
+            }
+            eprintln!("{}", text);
+        }
+    }
+
+    /// The address `addr` stands for, which can be a number, a register or a label
+    pub(super) fn resolve_address(&self, addr: &str) -> Option<usize> {
+        if let Some(r) = REGISTER_NAMES.iter().position(|&r| r == addr) {
+            let x = if self.rv64 { self.get_reg::<u64>(r as u8) } else { self.get_reg::<u32>(r as u8) as u64 };
+            return Some(x as usize);
+        }
+        match integer_literal(addr) {
+            Ok(address) => Some(address as usize),
+            Err(_) => self.symbols.iter().find(|s| s.name == addr).map(|s| s.address),
+        }
+    }
+
+    /// The pc and the integer registers, 4 to a line
+    fn registers_table(&self) -> String {
+        let width = if self.rv64 { 18 } else { 10 };
+        let mut table = format!("  {:>4} {:#0width$x}\n", "pc", self.pc, width = width);
+        for (i, name) in REGISTER_NAMES.iter().enumerate() {
+            let x = if self.rv64 { self.registers[i] } else { self.registers[i] as u32 as u64 };
+            table += &format!("  {:>4} {:#0width$x}", name, x, width = width);
+            if i % 4 == 3 {
+                table.push('\n');
+            }
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(code: &str, locations: Vec<Location>, keys: &'static str) -> Simulator {
+        let mut sim = Simulator::new()
+            .breakpoints(locations)
+            .load_str(code)
+            .unwrap();
+        sim.debugger.input = Input::Keys(keys.as_bytes());
+        sim
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let code = "li a0, 1\nloop: addi a0, a0, 1\nli t0, 4\nblt a0, t0, loop\nebreak\naddi a0, a0, 10\nli a7, 93\necall";
+
+        // 3 passes of `loop`, and the `ebreak` goes on after it
+        let mut sim = load(code, vec!["loop".parse().unwrap()], "\n\n\n\n");
+        assert_eq!(sim.run(), 14);
+        assert_eq!(sim.debugger.breakpoints, [4]);
+
+        // Stops at the second pass
+        let mut sim = load(code, vec!["loop".parse().unwrap()], "\nq\n");
+        assert_eq!(sim.run(), 1);
+        assert_eq!(sim.get_reg::<u32>(10), 2);
+
+        // A breakpoint at an address, without anyone to press a key
+        let mut sim = load(code, vec![Location::Address(0x8)], "");
+        sim.debugger.input = Input::None;
+        assert_eq!(sim.run(), 1); // the `ebreak` stops it
+        assert_eq!(sim.pc, 0x10);
+    }
+
+    /// Where the program is when `keys` make it quit
+    fn debug(keys: &'static str) -> usize {
+        // `main` calls `double` twice, which calls `add`
+        let code = "main: li a0, 3\ncall double\ncall double\nli a7, 93\necall
+            double: addi sp, sp, -4\nsw ra, 0(sp)\ncall add\nlw ra, 0(sp)\naddi sp, sp, 4\nret
+            add: add a0, a0, a0\nret";
+        let mut sim = Simulator::new()
+            .debug(true)
+            .load_str(code)
+            .unwrap();
+        sim.debugger.input = Input::Keys(keys.as_bytes());
+        assert_eq!(sim.run(), 1);
+        sim.pc
+    }
+
+    #[test]
+    fn test_debug() {
+        assert_eq!(debug("q\n"), 0x0);
+        assert_eq!(debug("s\n\nq\n"), 0x14); // into `double`
+        assert_eq!(debug("s\nn\nq\n"), 0x8); // over it
+        assert_eq!(debug("s\ns\nfinish\nq\n"), 0x8); // out of it
+        assert_eq!(debug("s\ns\nn\nn\nn\nq\n"), 0x20); // over `add`
+        assert_eq!(debug("b add\nc\nc\nq\n"), 0x2c); // the second call of `add`
+        assert_eq!(debug("break 0x10\nwat\nregs\nx/5 sp\nx main\nc\nq\n"), 0x10);
+    }
+
+    #[test]
+    fn test_location() {
+        assert_eq!("0x10".parse(), Ok(Location::Address(0x10)));
+        assert_eq!("main".parse(), Ok(Location::Label("main".to_owned())));
+        assert!("a b".parse::<Location>().is_err());
+    }
+}
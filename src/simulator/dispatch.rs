@@ -0,0 +1,265 @@
+//!
+//! The code as the interpreter runs it. An [Instruction](../../parser/enum.Instruction.html) is
+//! big, since some of them carry a `String`, and the RV64, float and vector ones are nested enums,
+//! so matching on it takes a couple of jumps and loads. Before running, each instruction is
+//! compiled into an [Op](struct.Op.html), 12 bytes with a flat `kind` and the operands packed, and
+//! [Simulator::run](../struct.Simulator.html#method.run) matches on that instead.
+//!
+//! Only the instructions that make up most of a program have their own `Kind`: the integer ones,
+//! loads and stores, branches and `jal`. Everything else is `Kind::Slow`, which runs the
+//! instruction from `code` like before. The fast arms do exactly what the arms of the plain
+//! instructions in the slow match do, which are still there for the RV64 ones that share them.
+//!
+//! Some pairs of instructions show up together all the time, like the `addi` and `bnez` that close
+//! a loop, so they're fused into a single `Op` that runs both, a superinstruction. It's in the slot
+//! of the first one and counts as two instructions, and the second one keeps its own `Op`, so
+//! jumping right to it still works. If the second one stops the program or traps, the pc is
+//! already at it, like it would be without fusing. The pairs are:
+//!
+//! - `li` and an `addi` to the same register, which is what a `lui` and an `addi` assemble to
+//! - `li` and a load from the register it wrote, like `lw t0, label`
+//! - an `addi` to a register and a `bne` or `blt` comparing it, like a loop counter
+//! - `slt` or `sltu` and a `beqz` or `bnez` on its result
+//!
+//! An `auipc` is the `li` of the address it computes, since it's always at the same address, so
+//! the `auipc` and `addi` of `la` and the `auipc` and load of `lw t0, label` are fused too.
+//!
+//! Compressed code isn't fused, since its instructions don't all take 4 bytes, and neither is the
+//! code of a run with [hooks](../struct.Simulator.html#method.before_instruction), like the
+//! [mix](../mix/index.html), which have to see each instruction as it starts.
+//!
+//! `ops` has to follow `code`: it's compiled when the program starts running, and whatever changes
+//! `code` after that, like [self_modifying](../self_modifying/index.html) code or loading another
+//! program, compiles what it changed, along with the instruction before, which could have been
+//! fused with it.
+//!
+
+use std::ops::Range;
+
+use super::Simulator;
+use crate::parser::Instruction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Add,
+    Sub,
+    Sll,
+    Slt,
+    Sltu,
+    Xor,
+    Srl,
+    Sra,
+    Or,
+    And,
+    Addi,
+    Slli,
+    Slti,
+    Sltiu,
+    Xori,
+    Srli,
+    Srai,
+    Ori,
+    Andi,
+    Lb,
+    Lh,
+    Lw,
+    Lbu,
+    Lhu,
+    Sb,
+    Sh,
+    Sw,
+    Beq,
+    Bne,
+    Blt,
+    Bge,
+    Bltu,
+    Bgeu,
+    Jal,
+    Li,
+    Mv,
+    Auipc,
+
+    /// `li rd, imm` and `addi rd, rd, x`, with `imm` already added up
+    LiAddi,
+    /// `li rs1, imm` and `lw rd, imm2(rs1)`
+    LiLw,
+    /// `addi rd, rd, imm2` and `bne rd, rs2, imm`
+    AddiBne,
+    /// `addi rd, rd, imm2` and `blt rd, rs2, imm`
+    AddiBlt,
+    /// `slt rd, rs1, rs2` and `beqz rd, imm`
+    SltBeqz,
+    /// `slt rd, rs1, rs2` and `bnez rd, imm`
+    SltBnez,
+    /// `sltu rd, rs1, rs2` and `beqz rd, imm`
+    SltuBeqz,
+    /// `sltu rd, rs1, rs2` and `bnez rd, imm`
+    SltuBnez,
+
+    /// Anything else, which runs from `code`
+    Slow,
+}
+
+/// An instruction compiled for the interpreter. Stores and branches have no `rd`, so their first
+/// register goes in `rd`, and the target of a branch or `jal` in `imm`. Only superinstructions
+/// use `imm2`, for the immediate of their first or second instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Op {
+    pub kind: Kind,
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub imm: u32,
+    pub imm2: u32,
+}
+
+impl Op {
+    const fn new(kind: Kind, rd: u8, rs1: u8, rs2: u8, imm: u32) -> Self {
+        Self { kind, rd, rs1, rs2, imm, imm2: 0 }
+    }
+
+    const fn fused(kind: Kind, rd: u8, rs1: u8, rs2: u8, imm: u32, imm2: u32) -> Self {
+        Self { kind, rd, rs1, rs2, imm, imm2 }
+    }
+}
+
+impl Simulator {
+    /// Compiles `code[slots]` into `ops`, and the instruction before them again, since it might
+    /// be fused with the first one now
+    pub(super) fn compile_code(&mut self, slots: Range<usize>) {
+        self.ops.resize(self.code.len(), Op::new(Kind::Slow, 0, 0, 0, 0));
+        for slot in slots.start.saturating_sub(1)..slots.end {
+            let next = self.code.get(slot + 1).filter(|_| !self.rvc && !self.hooks);
+            let li;
+            let first = match self.code[slot] {
+                Instruction::Auipc(rd, imm) => {
+                    li = Instruction::Li(rd, (self.code_address(slot) as u32).wrapping_add(imm));
+                    &li
+                }
+                ref first => first,
+            };
+            let fused = next.and_then(|next| fuse(first, next));
+            self.ops[slot] = fused.unwrap_or_else(|| compile(&self.code[slot]));
+        }
+    }
+}
+
+/// The superinstruction that runs `first` and then `second`, if there's one
+fn fuse(first: &Instruction, second: &Instruction) -> Option<Op> {
+    use Instruction::*;
+    use Kind as K;
+
+    let op = match (first, second) {
+        (&Li(rd, imm), &Addi(rd2, rs1, x)) if rd2 == rd && rs1 == rd => Op::new(K::LiAddi, rd, 0, 0, imm.wrapping_add(x)),
+        (&Li(rs1, imm), &Lw(rd, offset, base)) if base == rs1 => Op::fused(K::LiLw, rd, rs1, 0, imm, offset),
+        (&Addi(rd, rs1, x), &Bne(a, rs2, label)) if rs1 == rd && a == rd => {
+            Op::fused(K::AddiBne, rd, 0, rs2, label as u32, x)
+        }
+        (&Addi(rd, rs1, x), &Blt(a, rs2, label)) if rs1 == rd && a == rd => {
+            Op::fused(K::AddiBlt, rd, 0, rs2, label as u32, x)
+        }
+        (&Slt(rd, rs1, rs2), &Beq(a, 0, label)) if a == rd => Op::new(K::SltBeqz, rd, rs1, rs2, label as u32),
+        (&Slt(rd, rs1, rs2), &Bne(a, 0, label)) if a == rd => Op::new(K::SltBnez, rd, rs1, rs2, label as u32),
+        (&Sltu(rd, rs1, rs2), &Beq(a, 0, label)) if a == rd => Op::new(K::SltuBeqz, rd, rs1, rs2, label as u32),
+        (&Sltu(rd, rs1, rs2), &Bne(a, 0, label)) if a == rd => Op::new(K::SltuBnez, rd, rs1, rs2, label as u32),
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// Compiles an instruction into an [Op](struct.Op.html)
+fn compile(instruction: &Instruction) -> Op {
+    use Instruction::*;
+    use Kind as K;
+
+    let r = |kind, rd, rs1, rs2| Op::new(kind, rd, rs1, rs2, 0);
+    let i = |kind, rd, rs1, imm| Op::new(kind, rd, rs1, 0, imm);
+    match *instruction {
+        Add(rd, rs1, rs2) => r(K::Add, rd, rs1, rs2),
+        Sub(rd, rs1, rs2) => r(K::Sub, rd, rs1, rs2),
+        Sll(rd, rs1, rs2) => r(K::Sll, rd, rs1, rs2),
+        Slt(rd, rs1, rs2) => r(K::Slt, rd, rs1, rs2),
+        Sltu(rd, rs1, rs2) => r(K::Sltu, rd, rs1, rs2),
+        Xor(rd, rs1, rs2) => r(K::Xor, rd, rs1, rs2),
+        Srl(rd, rs1, rs2) => r(K::Srl, rd, rs1, rs2),
+        Sra(rd, rs1, rs2) => r(K::Sra, rd, rs1, rs2),
+        Or(rd, rs1, rs2) => r(K::Or, rd, rs1, rs2),
+        And(rd, rs1, rs2) => r(K::And, rd, rs1, rs2),
+        Addi(rd, rs1, imm) => i(K::Addi, rd, rs1, imm),
+        Slli(rd, rs1, imm) => i(K::Slli, rd, rs1, imm),
+        Slti(rd, rs1, imm) => i(K::Slti, rd, rs1, imm),
+        Sltiu(rd, rs1, imm) => i(K::Sltiu, rd, rs1, imm),
+        Xori(rd, rs1, imm) => i(K::Xori, rd, rs1, imm),
+        Srli(rd, rs1, imm) => i(K::Srli, rd, rs1, imm),
+        Srai(rd, rs1, imm) => i(K::Srai, rd, rs1, imm),
+        Ori(rd, rs1, imm) => i(K::Ori, rd, rs1, imm),
+        Andi(rd, rs1, imm) => i(K::Andi, rd, rs1, imm),
+        Lb(rd, imm, rs1) => i(K::Lb, rd, rs1, imm),
+        Lh(rd, imm, rs1) => i(K::Lh, rd, rs1, imm),
+        Lw(rd, imm, rs1) => i(K::Lw, rd, rs1, imm),
+        Lbu(rd, imm, rs1) => i(K::Lbu, rd, rs1, imm),
+        Lhu(rd, imm, rs1) => i(K::Lhu, rd, rs1, imm),
+        Sb(rs2, imm, rs1) => Op::new(K::Sb, 0, rs1, rs2, imm),
+        Sh(rs2, imm, rs1) => Op::new(K::Sh, 0, rs1, rs2, imm),
+        Sw(rs2, imm, rs1) => Op::new(K::Sw, 0, rs1, rs2, imm),
+        // Labels are addresses of the code, which fit in 32 bits
+        Beq(rs1, rs2, label) => Op::new(K::Beq, 0, rs1, rs2, label as u32),
+        Bne(rs1, rs2, label) => Op::new(K::Bne, 0, rs1, rs2, label as u32),
+        Blt(rs1, rs2, label) => Op::new(K::Blt, 0, rs1, rs2, label as u32),
+        Bge(rs1, rs2, label) => Op::new(K::Bge, 0, rs1, rs2, label as u32),
+        Bltu(rs1, rs2, label) => Op::new(K::Bltu, 0, rs1, rs2, label as u32),
+        Bgeu(rs1, rs2, label) => Op::new(K::Bgeu, 0, rs1, rs2, label as u32),
+        Jal(rd, label) => i(K::Jal, rd, 0, label as u32),
+        Li(rd, imm) => i(K::Li, rd, 0, imm),
+        Mv(rd, rs1) => r(K::Mv, rd, rs1, 0),
+        Auipc(rd, imm) => i(K::Auipc, rd, 0, imm),
+        _ => Op::new(K::Slow, 0, 0, 0, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile() {
+        assert_eq!(std::mem::size_of::<Op>(), 12);
+        assert_eq!(compile(&Instruction::Lw(5, 8, 2)), Op::new(Kind::Lw, 5, 2, 0, 8));
+        assert_eq!(compile(&Instruction::Sw(5, 8, 2)), Op::new(Kind::Sw, 0, 2, 5, 8));
+        assert_eq!(compile(&Instruction::Bne(5, 0, 0x40)), Op::new(Kind::Bne, 0, 5, 0, 0x40));
+        assert_eq!(compile(&Instruction::Ecall).kind, Kind::Slow);
+    }
+
+    #[test]
+    fn test_fuse() {
+        use Instruction::*;
+        assert_eq!(fuse(&Li(5, 0x1000), &Addi(5, 5, 4)), Some(Op::new(Kind::LiAddi, 5, 0, 0, 0x1004)));
+        assert_eq!(fuse(&Li(5, 0x1000), &Lw(6, 8, 5)), Some(Op::fused(Kind::LiLw, 6, 5, 0, 0x1000, 8)));
+        assert_eq!(fuse(&Addi(5, 5, -1i32 as u32), &Bne(5, 0, 0x40)), Some(Op::fused(Kind::AddiBne, 5, 0, 0, 0x40, -1i32 as u32)));
+        assert_eq!(fuse(&Sltu(5, 6, 7), &Beq(5, 0, 0x40)), Some(Op::new(Kind::SltuBeqz, 5, 6, 7, 0x40)));
+        // Not the same register
+        assert_eq!(fuse(&Addi(5, 6, 1), &Bne(5, 0, 0x40)), None);
+        assert_eq!(fuse(&Slt(5, 6, 7), &Bne(6, 0, 0x40)), None);
+    }
+
+    #[test]
+    fn test_fused_run() {
+        // A loop of fused pairs, which jumps into the middle of one at `mid`
+        let code = ".data\nx: .word 7\n.text\nli s0, 0\nli s1, 10
+            loop: lw t1, x\nadd s0, s0, t1\nslt t2, t1, s0\nbnez t2, mid
+            li t3, 1\nmid: addi s1, s1, -1\nbne s1, zero, loop
+            mv a0, s0\nli a7, 93\necall";
+        let mut sim = Simulator::new().load_str(code).unwrap();
+        assert_eq!(sim.run(), 70);
+        let kinds: Vec<_> = sim.ops[2..9].iter().map(|op| op.kind).collect();
+        assert_eq!(kinds, [Kind::LiLw, Kind::Lw, Kind::Add, Kind::SltBnez, Kind::Bne, Kind::Li, Kind::AddiBne]);
+        // Every pass but the first skips the `li t3, 1`, and fused pairs still count as two
+        assert_eq!(sim.stats.instructions, 2 + 8 + 9 * 7 + 3);
+
+        // The `auipc` and `addi` of `la`, at an address that isn't 0
+        let code = ".data\nw: .word 1\nx: .word 7\n.text\nnop\nla t0, x\nlw a0, 0(t0)\nli a7, 93\necall";
+        let mut sim = Simulator::new().load_str(code).unwrap();
+        assert_eq!(sim.run(), 7);
+        assert_eq!(sim.ops[1], Op::new(Kind::LiAddi, 5, 0, 0, 4));
+    }
+}
@@ -0,0 +1,599 @@
+//!
+//! Rounding modes and exception flags of the floating point instructions.
+//!
+//! Rust only rounds to the nearest, ties to even. The other modes start from that result and from
+//! the sign of its error, which is computed exactly with an `fma` or with a few more additions.
+//! If the exact result was above the rounded one and we're rounding up, the answer is the next
+//! float, and so on.
+//!
+
+use std::cmp::Ordering;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// Invalid operation (NV), like `0/0` or `sqrt(-1)`
+pub const INVALID: u32 = 0x10;
+/// Divide by zero (DZ)
+pub const DIVIDE_BY_ZERO: u32 = 0x08;
+/// Overflow (OF)
+pub const OVERFLOW: u32 = 0x04;
+/// Underflow (UF), when the result is tiny and inexact
+pub const UNDERFLOW: u32 = 0x02;
+/// Inexact (NX)
+pub const INEXACT: u32 = 0x01;
+
+/// The rounding modes, in the order of their encoding in `frm` and in the `rm` field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// `rne`
+    NearestEven,
+    /// `rtz`
+    TowardZero,
+    /// `rdn`
+    Down,
+    /// `rup`
+    Up,
+    /// `rmm`, to the nearest with ties away from zero
+    NearestMaxMagnitude,
+}
+
+impl Rounding {
+    /// The mode encoded as `bits`, if it's one. 5 and 6 are reserved, and 7 means "whatever is
+    /// in `frm`", which the caller has to look up.
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        use Rounding::*;
+        [NearestEven, TowardZero, Down, Up, NearestMaxMagnitude].get(bits as usize).copied()
+    }
+}
+
+/// A result and the exception flags it raised
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rounded<T> {
+    pub value: T,
+    pub flags: u32,
+}
+
+/// What the rounding needs from `f32` and `f64`
+pub trait Float:
+    Copy
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+{
+    const ZERO: Self;
+    const ONE: Self;
+    const MAX: Self;
+    const MIN_POSITIVE: Self;
+    /// A power of two large enough to bring the errors of tiny results back to the normal range
+    const SCALE: Self;
+
+    fn canonical_nan() -> Self;
+    fn is_signaling(self) -> bool;
+    /// The closest float above this one
+    fn next_up(self) -> Self;
+    fn mul_add(self, a: Self, b: Self) -> Self;
+    fn sqrt(self) -> Self;
+    fn abs(self) -> Self;
+    fn is_nan(self) -> bool;
+    fn is_finite(self) -> bool;
+    fn is_infinite(self) -> bool;
+    fn is_sign_negative(self) -> bool;
+
+    /// The closest float below this one
+    fn next_down(self) -> Self {
+        -(-self).next_up()
+    }
+}
+
+macro_rules! impl_float {
+    ($type:ident, $canonical:expr, $quiet:expr, $scale:expr) => {
+        impl Float for $type {
+            const ZERO: Self = 0.0;
+            const ONE: Self = 1.0;
+            const MAX: Self = $type::MAX;
+            const MIN_POSITIVE: Self = $type::MIN_POSITIVE;
+            const SCALE: Self = $scale;
+
+            fn canonical_nan() -> Self {
+                $type::from_bits($canonical)
+            }
+
+            fn is_signaling(self) -> bool {
+                self.is_nan() && self.to_bits() & $quiet == 0
+            }
+
+            fn next_up(self) -> Self {
+                if self.is_nan() || self == $type::INFINITY {
+                    self
+                } else if self == 0.0 {
+                    $type::from_bits(1)
+                } else if self > 0.0 {
+                    $type::from_bits(self.to_bits() + 1)
+                } else {
+                    $type::from_bits(self.to_bits() - 1)
+                }
+            }
+
+            fn mul_add(self, a: Self, b: Self) -> Self {
+                $type::mul_add(self, a, b)
+            }
+
+            fn sqrt(self) -> Self {
+                $type::sqrt(self)
+            }
+
+            fn abs(self) -> Self {
+                $type::abs(self)
+            }
+
+            fn is_nan(self) -> bool {
+                $type::is_nan(self)
+            }
+
+            fn is_finite(self) -> bool {
+                $type::is_finite(self)
+            }
+
+            fn is_infinite(self) -> bool {
+                $type::is_infinite(self)
+            }
+
+            fn is_sign_negative(self) -> bool {
+                $type::is_sign_negative(self)
+            }
+        }
+    };
+}
+
+impl_float!(f32, 0x7fc0_0000, 0x0040_0000, 281_474_976_710_656.0); // 2^48
+impl_float!(f64, 0x7ff8_0000_0000_0000, 0x0008_0000_0000_0000, 8.112_963_841_460_668e31); // 2^106
+
+/// Raises NV if any of `operands` is a signaling NaN
+fn signaling<T: Float>(operands: &[T]) -> u32 {
+    if operands.iter().any(|x| x.is_signaling()) {
+        INVALID
+    } else {
+        0
+    }
+}
+
+/// Whether the exact result is exactly halfway between `nearest` and the float next to it, in the
+/// direction of `error`. `error` has to be exact, and multiplied by `scale`.
+fn is_tie<T: Float>(nearest: T, error: T, scale: T) -> bool {
+    let next = if error > T::ZERO { nearest.next_up() } else { nearest.next_down() };
+    error != T::ZERO && (next - nearest) * scale == error + error
+}
+
+/// The errors of results this small might be too small to be floats themselves, so they're
+/// computed with everything multiplied by [Float::SCALE](trait.Float.html#associatedconstant.SCALE)
+fn scale_for<T: Float>(result: T) -> T {
+    if result.abs() < T::MIN_POSITIVE * T::SCALE { T::SCALE } else { T::ONE }
+}
+
+/// Rounds a result given `nearest`, which is it rounded to the nearest even, whether the exact
+/// result was above or below it, and whether it was exactly halfway to the next float.
+/// `overflow` is whether `nearest` overflowed to infinity.
+fn round<T: Float>(nearest: T, error: Ordering, tie: bool, overflow: bool, mode: Rounding) -> Rounded<T> {
+    use Rounding::*;
+
+    let negative = nearest.is_sign_negative();
+    if overflow {
+        let to_infinity = match mode {
+            NearestEven | NearestMaxMagnitude => true,
+            TowardZero => false,
+            Down => negative,
+            Up => !negative,
+        };
+        let value = match (to_infinity, negative) {
+            (true, _) => nearest,
+            (false, false) => T::MAX,
+            (false, true) => -T::MAX,
+        };
+        return Rounded { value, flags: OVERFLOW | INEXACT };
+    }
+
+    if error == Ordering::Equal {
+        return Rounded { value: nearest, flags: 0 };
+    }
+
+    let above = error == Ordering::Greater;
+    let value = match mode {
+        NearestEven => nearest,
+        // A tie was rounded to the even one, which might have been toward zero
+        NearestMaxMagnitude if tie && above != negative => {
+            if above { nearest.next_up() } else { nearest.next_down() }
+        }
+        NearestMaxMagnitude => nearest,
+        TowardZero if above && negative => nearest.next_up(),
+        TowardZero if !above && !negative && nearest != T::ZERO => nearest.next_down(),
+        TowardZero => nearest,
+        Down if !above => nearest.next_down(),
+        Up if above => nearest.next_up(),
+        Down | Up => nearest,
+    };
+
+    let mut flags = INEXACT;
+    if value.is_infinite() {
+        flags |= OVERFLOW;
+    }
+    if value.abs() < T::MIN_POSITIVE {
+        flags |= UNDERFLOW;
+    }
+    Rounded { value, flags }
+}
+
+/// The rest of an operation that ended in a NaN, which is always the canonical one. It's an
+/// invalid operation if none of the operands were NaNs, or if any of them was signaling.
+fn nan_result<T: Float>(operands: &[T]) -> Rounded<T> {
+    let from_nan = operands.iter().any(|x| x.is_nan());
+    let flags = if from_nan { signaling(operands) } else { INVALID };
+    Rounded { value: T::canonical_nan(), flags }
+}
+
+fn sign_of<T: Float>(x: T) -> Ordering {
+    x.partial_cmp(&T::ZERO).unwrap_or(Ordering::Equal)
+}
+
+/// Knuth's TwoSum: `a + b` and its error, exactly
+fn two_sum<T: Float>(a: T, b: T) -> (T, T) {
+    let sum = a + b;
+    let b_virtual = sum - a;
+    (sum, (a - (sum - b_virtual)) + (b - b_virtual))
+}
+
+/// The sign of the exact sum of `terms`. Each term is added with a [two_sum](fn.two_sum.html)
+/// to an expansion, a list of floats that don't overlap and add up to the sum so far, like
+/// Shewchuk does. The sign of the sum is the sign of the largest one.
+fn sign_of_sum<T: Float>(terms: &[T]) -> Ordering {
+    let mut expansion = [T::ZERO; 8];
+    for (len, &term) in terms.iter().enumerate() {
+        let mut carry = term;
+        for component in &mut expansion[..len] {
+            let (sum, error) = two_sum(carry, *component);
+            carry = sum;
+            *component = error;
+        }
+        expansion[len] = carry;
+    }
+
+    let largest = expansion[..terms.len()].iter().rev().find(|&&x| x != T::ZERO);
+    largest.map_or(Ordering::Equal, |&x| sign_of(x))
+}
+
+pub fn add<T: Float>(a: T, b: T, mode: Rounding) -> Rounded<T> {
+    let sum = a + b;
+    if sum.is_nan() {
+        return nan_result(&[a, b]);
+    }
+    let overflow = sum.is_infinite() && a.is_finite() && b.is_finite();
+    if overflow || sum.is_infinite() {
+        return round(sum, Ordering::Equal, false, overflow, mode);
+    }
+
+    let (_, error) = two_sum(a, b);
+
+    // An exact zero is -0 when rounding down, unless both were +0
+    let exact_zero = sum == T::ZERO && error == T::ZERO;
+    if exact_zero && mode == Rounding::Down && (a.is_sign_negative() || b.is_sign_negative()) {
+        return Rounded { value: -T::ZERO, flags: 0 };
+    }
+    round(sum, sign_of(error), is_tie(sum, error, T::ONE), false, mode)
+}
+
+pub fn sub<T: Float>(a: T, b: T, mode: Rounding) -> Rounded<T> {
+    add(a, -b, mode)
+}
+
+pub fn mul<T: Float>(a: T, b: T, mode: Rounding) -> Rounded<T> {
+    let product = a * b;
+    if product.is_nan() {
+        return nan_result(&[a, b]);
+    }
+    let overflow = product.is_infinite() && a.is_finite() && b.is_finite();
+    if product.is_infinite() || product == T::ZERO && (a == T::ZERO || b == T::ZERO) {
+        return round(product, Ordering::Equal, false, overflow, mode);
+    }
+
+    // The error of the product is exact with an fma. Only the smaller operand is scaled, so
+    // nothing overflows
+    let scale = scale_for(product);
+    let (x, y) = if a.abs() < b.abs() { (a * scale, b) } else { (a, b * scale) };
+    let error = x.mul_add(y, -(product * scale));
+    let sign = match sign_of(error) {
+        // Even scaled, the exact product was too small to be anything but zero
+        Ordering::Equal if product == T::ZERO && a.is_sign_negative() != b.is_sign_negative() => {
+            Ordering::Less
+        }
+        Ordering::Equal if product == T::ZERO => Ordering::Greater,
+        sign => sign,
+    };
+    round(product, sign, is_tie(product, error, scale), false, mode)
+}
+
+pub fn div<T: Float>(a: T, b: T, mode: Rounding) -> Rounded<T> {
+    let quotient = a / b;
+    if quotient.is_nan() {
+        return nan_result(&[a, b]);
+    }
+    if b == T::ZERO {
+        let flags = if a.is_finite() { DIVIDE_BY_ZERO } else { 0 };
+        return Rounded { value: quotient, flags };
+    }
+    let overflow = quotient.is_infinite() && a.is_finite();
+    if quotient.is_infinite() || b.is_infinite() || a == T::ZERO {
+        return round(quotient, Ordering::Equal, false, overflow, mode);
+    }
+
+    // The remainder `a - quotient * b` is exact, and a quotient is never exactly halfway.
+    // The remainder is much smaller than `a`, so that's what decides the scale.
+    let scale = scale_for(a);
+    let remainder = (-(quotient * scale)).mul_add(b, a * scale);
+    let error = match sign_of(b) {
+        Ordering::Less => sign_of(remainder).reverse(),
+        _ => sign_of(remainder),
+    };
+    round(quotient, error, false, false, mode)
+}
+
+pub fn sqrt<T: Float>(a: T, mode: Rounding) -> Rounded<T> {
+    let root = a.sqrt();
+    if root.is_nan() {
+        return nan_result(&[a]);
+    }
+    if root.is_infinite() || root == T::ZERO {
+        return Rounded { value: root, flags: 0 };
+    }
+
+    // Like in the division, the square root is never exactly halfway
+    let scale = scale_for(a);
+    let scaled_root = root * scale;
+    let remainder = (-scaled_root).mul_add(scaled_root, a * scale * scale);
+    round(root, sign_of(remainder), false, false, mode)
+}
+
+/// `fmadd`, which is `a * b + c` rounded only once. The other fused instructions are it with some
+/// of the operands negated.
+pub fn mul_add<T: Float>(a: T, b: T, c: T, mode: Rounding) -> Rounded<T> {
+    let result = a.mul_add(b, c);
+    if result.is_nan() {
+        // Infinity times zero is invalid even when `c` is a quiet NaN
+        let mut nan = nan_result(&[a, b, c]);
+        if (a.is_infinite() && b == T::ZERO) || (a == T::ZERO && b.is_infinite()) {
+            nan.flags |= INVALID;
+        }
+        return nan;
+    }
+    let overflow = result.is_infinite() && [a, b, c].iter().all(|x| x.is_finite());
+    if result.is_infinite() {
+        return round(result, Ordering::Equal, false, overflow, mode);
+    }
+
+    // The error is `a * b + c - result`, a sum of floats once `a * b` is split in two. When the
+    // product is tiny and `c` isn't, the result is `c` and the error is just the product.
+    let product = a * b;
+    let product_sign = match a.is_sign_negative() == b.is_sign_negative() {
+        true => Ordering::Greater,
+        false => Ordering::Less,
+    };
+    let scale = scale_for(product);
+    let (error, tie) = if scale != T::ONE && c.abs() >= T::ONE {
+        let zero = a == T::ZERO || b == T::ZERO;
+        (if zero { Ordering::Equal } else { product_sign }, false)
+    } else {
+        let (x, y) = if a.abs() < b.abs() { (a * scale, b) } else { (a, b * scale) };
+        let high = x * y;
+        let low = x.mul_add(y, -high);
+        let (sum, sum_error) = two_sum(high, c * scale);
+        let mut terms = [low, sum_error, sum, -(result * scale), T::ZERO];
+        let error = sign_of_sum(&terms[..4]);
+
+        // It's a tie if the error minus half the distance to the next float is zero
+        let next = if error == Ordering::Greater { result.next_up() } else { result.next_down() };
+        terms[4] = (result - next) * scale / (T::ONE + T::ONE);
+        (error, error != Ordering::Equal && sign_of_sum(&terms) == Ordering::Equal)
+    };
+
+    // An exact zero is -0 when rounding down, like in the addition
+    let negative_term = product_sign == Ordering::Less || c.is_sign_negative();
+    if result == T::ZERO && error == Ordering::Equal && mode == Rounding::Down && negative_term {
+        return Rounded { value: -T::ZERO, flags: 0 };
+    }
+    round(result, error, tie, false, mode)
+}
+
+/// `fmin`, which returns the number when only one of the operands is a NaN, and considers
+/// -0 to be less than +0
+pub fn min<T: Float>(a: T, b: T) -> Rounded<T> {
+    let flags = signaling(&[a, b]);
+    let value = match (a.is_nan(), b.is_nan()) {
+        (true, true) => T::canonical_nan(),
+        (true, false) => b,
+        (false, true) => a,
+        _ if a == b => if a.is_sign_negative() { a } else { b },
+        _ => if a < b { a } else { b },
+    };
+    Rounded { value, flags }
+}
+
+/// `fmax`, see [min](fn.min.html)
+pub fn max<T: Float>(a: T, b: T) -> Rounded<T> {
+    let flags = signaling(&[a, b]);
+    let value = match (a.is_nan(), b.is_nan()) {
+        (true, true) => T::canonical_nan(),
+        (true, false) => b,
+        (false, true) => a,
+        _ if a == b => if a.is_sign_negative() { b } else { a },
+        _ => if a > b { a } else { b },
+    };
+    Rounded { value, flags }
+}
+
+/// `feq`, which only complains about signaling NaNs
+pub fn equal<T: Float>(a: T, b: T) -> Rounded<bool> {
+    Rounded { value: a == b, flags: signaling(&[a, b]) }
+}
+
+/// `flt` and `fle`, which are invalid with any NaN
+pub fn less<T: Float>(a: T, b: T, or_equal: bool) -> Rounded<bool> {
+    let flags = if a.is_nan() || b.is_nan() { INVALID } else { 0 };
+    let value = if or_equal { a <= b } else { a < b };
+    Rounded { value, flags }
+}
+
+/// `fcvt.w` and `fcvt.wu`. Every float is exactly a double, so `x` comes as one. Out of range
+/// values and NaNs are invalid, and give the closest integer, or the largest one for NaNs.
+pub fn to_int(x: f64, signed: bool, mode: Rounding) -> Rounded<u32> {
+    use Rounding::*;
+
+    let rounded = match mode {
+        NearestEven => x.round_ties_even(),
+        TowardZero => x.trunc(),
+        Down => x.floor(),
+        Up => x.ceil(),
+        NearestMaxMagnitude => x.round(),
+    };
+
+    let (min, max) = if signed { (i32::MIN as f64, i32::MAX as f64) } else { (0.0, u32::MAX as f64) };
+    let value = if x.is_nan() || rounded > max {
+        max
+    } else if rounded < min {
+        min
+    } else {
+        let flags = if rounded == x { 0 } else { INEXACT };
+        let value = if signed { rounded as i32 as u32 } else { rounded as u32 };
+        return Rounded { value, flags };
+    };
+
+    let value = if signed { value as i32 as u32 } else { value as u32 };
+    Rounded { value, flags: INVALID }
+}
+
+/// `fcvt.s.w`, `fcvt.s.wu` and `fcvt.s.d`. `x` can be any integer or float, as a double, and it's
+/// rounded to a float.
+pub fn to_single(x: f64, mode: Rounding) -> Rounded<f32> {
+    if x.is_nan() {
+        return nan_result(&[x]).map(|_| f32::canonical_nan());
+    }
+
+    let nearest = x as f32;
+    let overflow = nearest.is_infinite() && x.is_finite();
+    if overflow || nearest.is_infinite() {
+        return round(nearest, Ordering::Equal, false, overflow, mode);
+    }
+
+    // Both are doubles, so the error and the distance to the next float are exact
+    let error = x - nearest as f64;
+    let next = if error > 0.0 { nearest.next_up() } else { nearest.next_down() };
+    let tie = error != 0.0 && next as f64 - nearest as f64 == error + error;
+    round(nearest, sign_of(error), tie, false, mode)
+}
+
+/// `fcvt.d.s`, which is exact, but still has to deal with NaNs
+pub fn to_double(x: f32) -> Rounded<f64> {
+    match x.is_nan() {
+        true => Rounded { value: f64::canonical_nan(), flags: signaling(&[x]) },
+        false => Rounded { value: x as f64, flags: 0 },
+    }
+}
+
+impl<T> Rounded<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Rounded<U> {
+        Rounded { value: f(self.value), flags: self.flags }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rounding::*;
+    use super::*;
+
+    #[test]
+    fn test_rounding() {
+        let third = |mode| div(1.0f32, 3.0, mode).value;
+        // The nearest float to 1/3 is above it
+        assert_eq!(third(NearestEven), 1.0 / 3.0);
+        assert_eq!(third(Up), 1.0 / 3.0);
+        assert_eq!(third(Down), (1.0f32 / 3.0).next_down());
+        assert_eq!(third(TowardZero), (1.0f32 / 3.0).next_down());
+        assert_eq!(div(-1.0f32, 3.0, TowardZero).value, (-1.0f32 / 3.0).next_up());
+        assert_eq!(div(-1.0f32, 3.0, Down).value, -1.0 / 3.0);
+
+        // Tiny results, whose errors are below the smallest subnormal
+        let tiny = f32::from_bits(1);
+        assert_eq!(mul(tiny, 0.75, Up).value, tiny);
+        assert_eq!(mul(tiny, 0.75, Down).value, 0.0);
+        assert_eq!(mul(tiny, -0.25, Down).value, -tiny);
+        assert_eq!(div(tiny, 3.0, Up).value, tiny);
+        let tiny = f64::from_bits(2);
+        assert_eq!(sqrt(tiny, Up).value, sqrt(tiny, Down).value.next_up());
+
+        // 1 + 2^-24 is exactly halfway between 1 and the float after it
+        let half_ulp = f32::EPSILON / 2.0;
+        assert_eq!(add(1.0f32, half_ulp, NearestEven).value, 1.0);
+        assert_eq!(add(1.0f32, half_ulp, NearestMaxMagnitude).value, 1.0f32.next_up());
+        assert_eq!(add(-1.0f32, -half_ulp, NearestMaxMagnitude).value, (-1.0f32).next_down());
+        assert_eq!(add(1.0f64, 1e-30, Up).value, 1.0f64.next_up());
+        assert!(add(1.0f32, -1.0, Down).value.is_sign_negative());
+        assert_eq!(sqrt(2.0f64, Up).value, 2.0f64.sqrt());
+        assert_eq!(sqrt(2.0f64, Down).value, 2.0f64.sqrt().next_down());
+
+        // Rounded once, so the error of 0.1 * 10 shows up, and it's exact
+        let fused = mul_add(0.1f32, 10.0, -1.0, NearestEven);
+        assert_eq!(fused, Rounded { value: 2.0 * 2f32.powi(-27), flags: 0 });
+        assert_eq!(mul_add(1.0f64, 1.0, 1e-30, Up).value, 1.0f64.next_up());
+        assert_eq!(mul_add(1.0f32, half_ulp, 1.0, NearestMaxMagnitude).value, 1.0f32.next_up());
+        assert!(mul_add(2.0f32, 3.0, -6.0, Down).value.is_sign_negative());
+        assert_eq!(mul_add(f32::INFINITY, 0.0, f32::NAN, NearestEven).flags, INVALID);
+
+        assert_eq!(mul(f32::MAX, 2.0, TowardZero).value, f32::MAX);
+        assert_eq!(mul(f32::MAX, -2.0, Up).value, -f32::MAX);
+        assert_eq!(mul(f32::MAX, 2.0, NearestEven).value, f32::INFINITY);
+    }
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(div(1.0f32, 3.0, NearestEven).flags, INEXACT);
+        assert_eq!(div(1.0f32, 0.0, NearestEven).flags, DIVIDE_BY_ZERO);
+        assert_eq!(div(0.0f32, 0.0, NearestEven).flags, INVALID);
+        assert_eq!(mul(f32::MAX, 2.0, NearestEven).flags, OVERFLOW | INEXACT);
+        assert_eq!(mul(f32::MIN_POSITIVE, 0.1, NearestEven).flags, UNDERFLOW | INEXACT);
+        assert_eq!(add(1.0f64, 2.0, NearestEven).flags, 0);
+        assert_eq!(sqrt(-1.0f32, NearestEven).flags, INVALID);
+        assert_eq!(sqrt(-1.0f32, NearestEven).value.to_bits(), 0x7fc0_0000);
+
+        // Quiet NaNs go through arithmetic silently, signaling ones don't
+        let signaling_nan = f32::from_bits(0x7f80_0001);
+        assert_eq!(add(f32::NAN, 1.0, NearestEven).flags, 0);
+        assert_eq!(add(signaling_nan, 1.0, NearestEven).flags, INVALID);
+        assert_eq!(equal(f32::NAN, 1.0).flags, 0);
+        assert_eq!(less(f32::NAN, 1.0, false).flags, INVALID);
+        assert_eq!(min(f32::NAN, 2.0), Rounded { value: 2.0, flags: 0 });
+        assert_eq!(max(1.0, f32::NAN).value, 1.0);
+        assert_eq!(min(f32::NAN, -f32::NAN).value.to_bits(), 0x7fc0_0000);
+        assert!(min(0.0f64, -0.0).value.is_sign_negative());
+        assert!(max(-0.0f32, 0.0).value.is_sign_positive());
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(to_int(2.5, true, NearestEven), Rounded { value: 2, flags: INEXACT });
+        assert_eq!(to_int(2.5, true, NearestMaxMagnitude).value, 3);
+        assert_eq!(to_int(-2.5, true, TowardZero).value, (-2i32) as u32);
+        assert_eq!(to_int(-2.5, true, Down).value, (-3i32) as u32);
+        assert_eq!(to_int(f64::NAN, true, NearestEven), Rounded { value: i32::MAX as u32, flags: INVALID });
+        assert_eq!(to_int(-1e10, true, NearestEven).value, i32::MIN as u32);
+        assert_eq!(to_int(-1.0, false, NearestEven), Rounded { value: 0, flags: INVALID });
+        assert_eq!(to_int(-0.25, false, NearestEven), Rounded { value: 0, flags: INEXACT });
+        assert_eq!(to_int(4e9, false, NearestEven).value, 4_000_000_000);
+
+        // 2^24 + 1 doesn't fit in a float, and is halfway between two of them
+        assert_eq!(to_single(16_777_217.0, NearestEven).value, 16_777_216.0);
+        assert_eq!(to_single(16_777_217.0, NearestMaxMagnitude).value, 16_777_218.0);
+        assert_eq!(to_single(0.1, Up).value, 0.1);
+        assert_eq!(to_single(0.1, Down).value, 0.1f32.next_down());
+        assert_eq!(to_single(1e300, TowardZero), Rounded { value: f32::MAX, flags: OVERFLOW | INEXACT });
+        assert_eq!(to_double(f32::from_bits(0x7f80_0001)).flags, INVALID);
+    }
+}
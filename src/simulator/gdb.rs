@@ -0,0 +1,480 @@
+//!
+//! A stub of the GDB remote serial protocol, for `--gdb PORT`, so GDB or an IDE that speaks it can
+//! debug the program: `target remote :PORT` in `riscv32-unknown-elf-gdb` (or `gdb-multiarch`).
+//! Once the program is loaded, we wait for GDB to connect, and the program starts paused at its
+//! first instruction, like with the [debugger](../debugger/index.html).
+//!
+//! We answer the core of the protocol: reading and writing the registers and the memory,
+//! continuing, stepping, breakpoints and Ctrl-C, which GDB sees while the program runs every so
+//! many instructions, and `reverse-stepi` and `reverse-continue`, which go back over the
+//! [history](../history/index.html). The registers are described in a `target.xml`, so GDB knows
+//! it's RISC-V without an ELF file. Writing to the code doesn't change the instructions that run, unless the
+//! code is `--self-modifying`.
+//!
+
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use super::debugger::{Mode, Resume};
+use super::Simulator;
+use crate::parser::register_names::REGISTER_NAMES;
+
+/// How many instructions run between two checks for a Ctrl-C while the program runs
+const POLL_EVERY: u64 = 1 << 16;
+
+/// The registers GDB reads with `g`: the integer ones and the pc
+const REGISTERS: usize = 33;
+
+pub struct Gdb {
+    listener: TcpListener,
+    /// The connection, once GDB connects
+    stream: Option<BufReader<TcpStream>>,
+    /// Whether the packets have to be acknowledged, until GDB asks us not to
+    acks: bool,
+    /// Whether GDB ran the program, and waits to hear it stopped
+    running: bool,
+    /// Instructions until the next check for a Ctrl-C while running
+    countdown: u64,
+}
+
+impl Gdb {
+    /// Listens on `port` of this machine only, since whoever connects can change its memory
+    pub(super) fn listen(port: u16) -> io::Result<Self> {
+        Ok(Self::new(TcpListener::bind(("127.0.0.1", port))?))
+    }
+
+    fn new(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            stream: None,
+            acks: true,
+            running: false,
+            countdown: POLL_EVERY,
+        }
+    }
+
+    /// Reads the next packet, or returns `None` if GDB is gone. A Ctrl-C on its own reads as `\x03`.
+    fn read_packet(&mut self) -> Option<String> {
+        let stream = self.stream.as_mut()?;
+        let mut byte = [0];
+        loop {
+            stream.read_exact(&mut byte).ok()?;
+            match byte[0] {
+                b'$' => break,
+                0x03 => return Some("\x03".to_owned()),
+                // Acknowledgements of our replies, which are never lost in TCP
+                _ => {}
+            }
+        }
+
+        let mut packet = Vec::new();
+        loop {
+            stream.read_exact(&mut byte).ok()?;
+            match byte[0] {
+                b'#' => break,
+                // Escaped, like in the binary data of `X`
+                b'}' => {
+                    stream.read_exact(&mut byte).ok()?;
+                    packet.push(byte[0] ^ 0x20);
+                }
+                x => packet.push(x),
+            }
+        }
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum).ok()?;
+        if self.acks {
+            stream.get_mut().write_all(b"+").ok()?;
+        }
+        Some(String::from_utf8_lossy(&packet).into_owned())
+    }
+
+    fn send(&mut self, reply: &str) {
+        let Some(stream) = self.stream.as_mut() else { return };
+        let checksum = reply.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        let packet = format!("${}#{:02x}", reply, checksum);
+        // If GDB is gone, the next read says so
+        let _ = stream.get_mut().write_all(packet.as_bytes());
+    }
+
+    /// Whether GDB sent a Ctrl-C, without waiting for it
+    fn interrupted(&mut self) -> bool {
+        let Some(stream) = self.stream.as_mut() else { return false };
+        if stream.get_ref().set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut byte = [0];
+        let read = stream.read(&mut byte);
+        let _ = stream.get_ref().set_nonblocking(false);
+        matches!(read, Ok(1) if byte[0] == 0x03)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The bytes of `s`, or `None` if it's not all pairs of hex digits
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Parses the `ADDR,LEN` of the memory packets
+fn address_and_length(s: &str) -> Option<(usize, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((usize::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+impl Simulator {
+    /// The size of the registers, in bytes
+    fn register_size(&self) -> usize {
+        if self.rv64 {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// Register `i` as GDB numbers them, in little endian hex
+    fn gdb_register(&self, i: usize) -> Option<String> {
+        let x = match i {
+            0..=31 => self.registers[i],
+            32 => self.pc as u64,
+            _ => return None,
+        };
+        Some(hex(&x.to_le_bytes()[..self.register_size()]))
+    }
+
+    fn set_gdb_register(&mut self, i: usize, value: &[u8]) -> Option<()> {
+        let mut bytes = [0; 8];
+        bytes.get_mut(..value.len())?.copy_from_slice(value);
+        let x = u64::from_le_bytes(bytes);
+        match i {
+            // Sign extended, like they're kept in rv32
+            0..=31 if self.rv64 => self.set_reg(i as u8, x),
+            0..=31 => self.set_reg(i as u8, x as u32),
+            32 => self.pc = x as usize,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn target_xml(&self) -> String {
+        let bits = self.register_size() * 8;
+        let mut xml = format!(
+            "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target><architecture>riscv:rv{}</architecture><feature name=\"org.gnu.gdb.riscv.cpu\">",
+            bits
+        );
+        for (i, name) in REGISTER_NAMES.iter().enumerate() {
+            let kind = match i {
+                1 => "code_ptr",
+                2 | 3 | 8 => "data_ptr",
+                _ => "int",
+            };
+            xml += &format!("<reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>", name, bits, kind);
+        }
+        xml += &format!("<reg name=\"pc\" bitsize=\"{}\" type=\"code_ptr\"/></feature></target>", bits);
+        xml
+    }
+
+    /// Waits for GDB to connect, once the program is loaded
+    pub(super) fn init_gdb(&mut self) {
+        let Some(gdb) = &mut self.gdb else { return };
+        if gdb.stream.is_some() {
+            return;
+        }
+        if let Ok(address) = gdb.listener.local_addr() {
+            eprintln!("Waiting for GDB to connect on port {}", address.port());
+        }
+        match gdb.listener.accept() {
+            Ok((stream, _)) => {
+                let _ = stream.set_nodelay(true);
+                gdb.stream = Some(BufReader::new(stream));
+            }
+            Err(e) => eprintln!("Warning: GDB couldn't connect: {}", e),
+        }
+    }
+
+    /// Tells GDB the program exited
+    pub(super) fn finish_gdb(&mut self, exit_code: i32) {
+        if let Some(gdb) = &mut self.gdb {
+            gdb.send(&format!("W{:02x}", exit_code as u8));
+        }
+    }
+
+    /// Tells GDB the program stopped, and answers its packets until it runs the program again
+    pub(super) fn gdb_pause(&mut self) -> Resume {
+        let Some(mut gdb) = self.gdb.take() else { return Resume::Continue };
+        if gdb.running {
+            gdb.send("S05");
+            gdb.running = false;
+        }
+        let resume = loop {
+            let Some(packet) = gdb.read_packet() else {
+                // Without GDB, the program runs to the end
+                self.debugger.set_mode(Mode::Run);
+                break Resume::Continue;
+            };
+            match self.gdb_packet(&mut gdb, &packet) {
+                Some(Mode::Run) if packet.starts_with('D') => {
+                    self.debugger.set_mode(Mode::Run);
+                    gdb.stream = None;
+                    break Resume::Continue;
+                }
+                Some(mode) => {
+                    self.debugger.set_mode(mode);
+                    gdb.running = true;
+                    break Resume::Continue;
+                }
+                None if packet == "k" => break Resume::Quit,
+                None => {}
+            }
+        };
+        self.gdb = Some(gdb);
+        resume
+    }
+
+    /// Answers a packet, or returns how to run the program if it asks to
+    fn gdb_packet(&mut self, gdb: &mut Gdb, packet: &str) -> Option<Mode> {
+        let (kind, args) = packet.split_at(packet.len().min(1));
+        let reply = match kind {
+            "c" | "s" | "D" => {
+                if let Ok(address) = usize::from_str_radix(args, 16) {
+                    self.pc = address;
+                }
+                if kind == "D" {
+                    gdb.send("OK");
+                }
+                return Some(if kind == "s" { Mode::Step } else { Mode::Run });
+            }
+            "k" => return None,
+            "?" | "\x03" => "S05".to_owned(),
+            "g" => (0..REGISTERS).filter_map(|i| self.gdb_register(i)).collect(),
+            "G" => {
+                let size = self.register_size() * 2;
+                let written = (0..REGISTERS)
+                    .map(|i| args.get(i * size..(i + 1) * size).and_then(unhex))
+                    .enumerate()
+                    .all(|(i, value)| value.and_then(|value| self.set_gdb_register(i, &value)).is_some());
+                if written { "OK" } else { "E01" }.to_owned()
+            }
+            "p" => usize::from_str_radix(args, 16)
+                .ok()
+                .and_then(|i| self.gdb_register(i))
+                .unwrap_or_else(|| "E01".to_owned()),
+            "P" => {
+                let written = args.split_once('=').and_then(|(i, value)| {
+                    self.set_gdb_register(usize::from_str_radix(i, 16).ok()?, &unhex(value)?)
+                });
+                if written.is_some() { "OK" } else { "E01" }.to_owned()
+            }
+            "m" => match address_and_length(args) {
+                // What can be read, up to the first byte that isn't in the memory
+                Some((addr, len)) if self.memory.is_mapped(addr, 1) => {
+                    let bytes: Vec<u8> = (addr..addr.saturating_add(len))
+                        .take_while(|&i| self.memory.is_mapped(i, 1))
+                        .map(|i| self.memory.get_byte(i))
+                        .collect();
+                    hex(&bytes)
+                }
+                _ => "E14".to_owned(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(place, data)| Some((address_and_length(place)?, unhex(data)?)));
+                match write {
+                    Some(((addr, len), data)) if data.len() == len && self.memory.is_mapped(addr, len.max(1)) => {
+                        for (i, byte) in data.into_iter().enumerate() {
+                            self.memory.set_byte(addr + i, byte);
+                        }
+                        "OK".to_owned()
+                    }
+                    _ => "E14".to_owned(),
+                }
+            }
+            "Z" | "z" => {
+                // Software and hardware breakpoints are the same here, and there are no watchpoints
+                let mut fields = args.split(',');
+                let (kind_of, address) = (fields.next(), fields.next().and_then(|a| usize::from_str_radix(a, 16).ok()));
+                match (kind_of, address) {
+                    (Some("0" | "1"), Some(address)) if kind == "Z" => {
+                        self.debugger.add_breakpoint(address);
+                        "OK".to_owned()
+                    }
+                    (Some("0" | "1"), Some(address)) => {
+                        self.debugger.remove_breakpoint(address);
+                        "OK".to_owned()
+                    }
+                    _ => String::new(),
+                }
+            }
+            "b" if args == "s" || args == "c" => {
+                let back = if args == "s" { self.step_back() } else { self.reverse_continue() };
+                // At the start of the history, GDB is told it can't go further back
+                if back { "S05" } else { "T05replaylog:begin;" }.to_owned()
+            }
+            "H" | "T" => "OK".to_owned(),
+            "q" | "Q" => self.gdb_query(packet),
+            _ => String::new(),
+        };
+        gdb.send(&reply);
+        // The `OK` is still acknowledged, only what comes after it isn't
+        if packet == "QStartNoAckMode" {
+            gdb.acks = false;
+        }
+        None
+    }
+
+    /// Answers the general queries, or says they aren't supported with an empty reply
+    fn gdb_query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;ReverseStep+;ReverseContinue+".to_owned();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = self.target_xml();
+            let Some((offset, len)) = address_and_length(range) else { return "E01".to_owned() };
+            let start = offset.min(xml.len());
+            let end = start.saturating_add(len).min(xml.len());
+            // `l` for the last part
+            let more = if end < xml.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &xml[start..end]);
+        }
+        match packet {
+            "QStartNoAckMode" => "OK",
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        }
+        .to_owned()
+    }
+
+    /// Checks for a Ctrl-C every so many instructions while the program runs
+    pub(super) fn gdb_tick(&mut self) -> Resume {
+        let Some(gdb) = &mut self.gdb else { return Resume::Continue };
+        gdb.countdown -= 1;
+        if gdb.countdown > 0 {
+            return Resume::Continue;
+        }
+        gdb.countdown = POLL_EVERY;
+        if gdb.interrupted() {
+            self.gdb_pause()
+        } else {
+            Resume::Continue
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Sends `packet` and returns the reply
+    fn ask(stream: &mut TcpStream, packet: &str) -> String {
+        let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(stream, "${}#{:02x}", packet, checksum).unwrap();
+        reply(stream)
+    }
+
+    /// Reads a reply, skipping the acknowledgements
+    fn reply(stream: &mut TcpStream) -> String {
+        let mut byte = [0];
+        let mut reply = Vec::new();
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        stream.read_exact(&mut [0; 2]).unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn test_gdb() {
+        let code = ".data\nx: .word 7\n.text\nmain: li a0, 5\ncall double\nlw t0, x\nadd a0, a0, t0\nli a7, 93\necall
+            double: add a0, a0, a0\nret";
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut sim = Simulator::new()
+            .debug(true)
+            .load_str(code)
+            .unwrap();
+        sim.gdb = Some(Gdb::new(listener));
+        let x = sim.symbols.iter().find(|s| s.name == "x").unwrap().address;
+
+        let gdb = thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            assert_eq!(ask(&mut stream, "?"), "S05");
+            assert!(ask(&mut stream, "qSupported:swbreak+").contains("qXfer:features:read+"));
+            let xml = ask(&mut stream, "qXfer:features:read:target.xml:0,1000");
+            assert!(xml.starts_with("l<?xml") && xml.contains("riscv:rv32"), "{}", xml);
+            assert!(ask(&mut stream, "qXfer:features:read:target.xml:0,10").starts_with("m<?xml"));
+
+            // Steps into `double`, and it's where the pc says
+            assert_eq!(ask(&mut stream, "s"), "S05");
+            assert_eq!(ask(&mut stream, "s"), "S05");
+            assert_eq!(ask(&mut stream, "p20"), "1c000000");
+
+            // Back to the call, to the start, and into `double` again
+            assert_eq!(ask(&mut stream, "bs"), "S05");
+            assert_eq!(ask(&mut stream, "p20"), "04000000");
+            assert_eq!(ask(&mut stream, "bc"), "T05replaylog:begin;");
+            assert_eq!(ask(&mut stream, "p20"), "00000000");
+            assert_eq!(ask(&mut stream, "s"), "S05");
+            assert_eq!(ask(&mut stream, "s"), "S05");
+            assert_eq!(ask(&mut stream, "p20"), "1c000000");
+            let registers = ask(&mut stream, "g");
+            assert_eq!(registers.len(), 33 * 8);
+            assert_eq!(&registers[10 * 8..11 * 8], "05000000"); // a0
+
+            // Breaks after the call, and changes a0 and x
+            assert_eq!(ask(&mut stream, "Z0,8,4"), "OK");
+            assert_eq!(ask(&mut stream, "c"), "S05");
+            assert_eq!(ask(&mut stream, "pa"), "0a000000");
+            assert_eq!(ask(&mut stream, "Pa=64000000"), "OK");
+            assert_eq!(ask(&mut stream, &format!("m{:x},4", x)), "07000000");
+            assert_eq!(ask(&mut stream, &format!("M{:x},4:01000000", x)), "OK");
+            assert_eq!(ask(&mut stream, "m0,0"), "");
+            assert_eq!(ask(&mut stream, "m7ffffff0,4"), "E14");
+            assert_eq!(ask(&mut stream, "z0,8,4"), "OK");
+            assert_eq!(ask(&mut stream, "vMustReplyEmpty"), "");
+            ask(&mut stream, "c")
+        });
+
+        assert_eq!(sim.run(), 101);
+        assert_eq!(gdb.join().unwrap(), "W65");
+    }
+
+    #[test]
+    fn test_no_ack_mode() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut sim = Simulator::new()
+            .debug(true)
+            .load_str("li a0, 3\nli a7, 93\necall")
+            .unwrap();
+        sim.gdb = Some(Gdb::new(listener));
+
+        let gdb = thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            write!(stream, "$QStartNoAckMode#b0").unwrap();
+            write!(stream, "$?#3f").unwrap();
+            // One acknowledged `OK`, and nothing else before the next reply, which isn't acknowledged
+            let mut bytes = [0; 14];
+            stream.read_exact(&mut bytes).unwrap();
+            assert_eq!(std::str::from_utf8(&bytes).unwrap(), "+$OK#9a$S05#b8");
+            ask(&mut stream, "c")
+        });
+
+        assert_eq!(sim.run(), 3);
+        assert_eq!(gdb.join().unwrap(), "W03");
+    }
+}
@@ -0,0 +1,104 @@
+//!
+//! The heap, which a program grows with the `sbrk` ecall, like in RARS:
+//!
+//! ```
+//! li a0, 16        # how many bytes
+//! li a7, 9         # sbrk
+//! ecall            # a0 = the address of the first of them
+//! ```
+//!
+//! It starts empty, right after the data, and grows towards the stack. Without a
+//! [stack_size](../struct.Simulator.html#method.stack_size), the stack gives way to it down to
+//! `sp`, otherwise the heap only gets the space between them. What's past the end of the heap isn't
+//! in the memory until it's allocated, so a load from there is an access fault.
+//!
+
+use super::{EcallSignal, Simulator};
+
+/// a0 = how many bytes to allocate, rounded up to a multiple of 4. Returns the address of the
+/// first one in a0
+const SBRK: u32 = 9;
+
+impl Simulator {
+    /// Tries to handle a heap ecall, returns `None` if it isn't one
+    pub(super) fn heap_ecall(&mut self, a7: u32) -> Option<EcallSignal> {
+        match a7 {
+            SBRK => Some(self.sbrk(self.get_reg::<i32>(10))),
+            _ => None,
+        }
+    }
+
+    /// Takes `bytes` more bytes for the heap of the current program. Like RARS, asking for a
+    /// negative amount or for more than there is stops the program.
+    fn sbrk(&mut self, bytes: i32) -> EcallSignal {
+        let id = self.processes.current();
+        let heap = self.memory.heap(id);
+        let start = heap.end.next_multiple_of(4);
+        let end = start + (bytes.max(0) as usize).next_multiple_of(4);
+
+        let limit = match id {
+            0 if self.stack_size.is_some() => self.stack.start,
+            _ => self.get_reg::<u32>(2) as usize,
+        };
+        if bytes < 0 || end > limit {
+            if bytes < 0 {
+                eprintln!("sbrk can't give back memory, it got {} bytes", bytes);
+            } else {
+                eprintln!("There's no room for {} more bytes of heap at {:#x}, the stack is at {:#x}", bytes, start, limit);
+            }
+            eprintln!("  at {:#x}{}", self.pc, self.source_at(self.pc));
+            return EcallSignal::Exit(1);
+        }
+
+        self.memory.set_heap_end(id, end);
+        self.stack = self.memory.stack(id);
+        self.set_reg(10, start as u32);
+        EcallSignal::Nothing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulator::{Segment, Simulator};
+
+    fn load(code: &str, stack_size: Option<usize>) -> Simulator {
+        let mut sim = Simulator::new();
+        if let Some(bytes) = stack_size {
+            sim = sim.stack_size(bytes);
+        }
+        sim.load_str(code).unwrap()
+    }
+
+    #[test]
+    fn test_sbrk() {
+        // A linked list of 3 nodes, each one {value, next}, summed from the last one
+        let code = ".data\nx: .byte 1\n.text\nli s0, 0\nli s1, 1
+            loop: li a0, 6\nli a7, 9\necall\nsw s1, 0(a0)\nsw s0, 4(a0)\nmv s0, a0\naddi s1, s1, 1\nli t0, 4\nblt s1, t0, loop
+            li a0, 0\nsum: lw t0, 0(s0)\nadd a0, a0, t0\nlw s0, 4(s0)\nbnez s0, sum
+            li a7, 93\necall";
+
+        let mut sim = load(code, None);
+        assert_eq!(sim.run(), 6);
+        // The data takes a word, and each node 8 bytes, with the 6 asked for rounded up
+        assert_eq!(sim.memory.heap(0), 4..28);
+        assert_eq!(sim.memory.get_word(20), 3);
+        assert_eq!(sim.memory.segment(28), Some(Segment::Stack));
+
+        // The heap can't take the stack's place when it has a size
+        let mut sim = load(code, Some(0x3f_ffe8));
+        assert_eq!(sim.run(), 1);
+        assert_eq!(sim.memory.heap(0), 4..20);
+        assert!(!sim.memory.is_mapped(20, 4));
+    }
+
+    #[test]
+    fn test_sbrk_rars_layout() {
+        // The heap starts where RARS's does, after all of its data
+        let code = ".data\nx: .word 1\n.text\nli a0, 8\nli a7, 9\necall\nmv s0, a0\nli a0, 4\necall
+            sub a0, a0, s0\nli a7, 93\necall";
+        let mut sim = Simulator::new().rars_layout(true).load_str(code).unwrap();
+        assert_eq!(sim.run(), 8);
+        assert_eq!(sim.get_reg::<u32>(8), 0x1004_0000);
+        assert_eq!(sim.memory.segment(0x1004_0000), Some(Segment::Heap));
+    }
+}
@@ -0,0 +1,208 @@
+//!
+//! Going back in time, for the [debugger](../debugger/index.html): while debugging, we keep what
+//! each of the last instructions changed, the registers it wrote and the bytes of memory it stored
+//! over, so the program can step back with `back`, or run back to the last breakpoint it went
+//! through with `rc`. The [tui](../tui/index.html) does the same with `S` and `C`, GDB with
+//! `reverse-stepi` and `reverse-continue`, and an editor with its step back.
+//!
+//! `--history N` keeps the last N instructions, and 0 turns it off. Only the registers, the pc and
+//! the stores are undone: what an `ecall` does besides setting registers, like reading into the
+//! memory or printing, and the CSRs and the vector registers, stay like they are.
+//!
+
+use std::collections::VecDeque;
+
+use super::profile::Transfer;
+use super::trace::Access;
+use super::Simulator;
+
+/// How many instructions we keep by default
+pub const DEFAULT_LENGTH: usize = 100_000;
+
+/// What an instruction changed, with the values from before it
+#[derive(Debug)]
+struct Step {
+    pc: usize,
+    registers: Vec<(u8, u64)>,
+    floats: Vec<(u8, u64)>,
+    /// The address it stored to, and the bytes that were there
+    memory: Option<(usize, Vec<u8>)>,
+}
+
+/// The instruction about to run, and what things are like before it
+struct Running {
+    pc: usize,
+    registers: [u64; 32],
+    floats: [u64; 32],
+    memory: Option<(usize, Vec<u8>)>,
+}
+
+pub struct History {
+    length: usize,
+    /// Whether we keep the steps, which is only while debugging
+    recording: bool,
+    steps: VecDeque<Step>,
+    running: Option<Running>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_LENGTH)
+    }
+}
+
+impl History {
+    /// Keeps the last `length` instructions
+    pub(super) fn new(length: usize) -> Self {
+        Self { length, recording: false, steps: VecDeque::new(), running: None }
+    }
+
+    /// Forgets what ran before, and keeps what runs from now on if `recording`
+    pub(super) fn start(&mut self, recording: bool) {
+        self.recording = recording && self.length > 0;
+        self.steps.clear();
+        self.running = None;
+    }
+
+    pub(super) fn is_on(&self) -> bool {
+        self.recording
+    }
+}
+
+/// The registers whose values in `now` aren't the ones `before`, with the ones before
+fn changed(now: &[u64; 32], before: &[u64; 32]) -> Vec<(u8, u64)> {
+    (0..32).filter(|&i| now[i] != before[i]).map(|i| (i as u8, before[i])).collect()
+}
+
+impl Simulator {
+    /// Keeps what the instruction that just ran changed, and what things are like before the one
+    /// at `pc`
+    pub(super) fn record_step(&mut self) {
+        if let Some(running) = self.history.running.take() {
+            let step = Step {
+                pc: running.pc,
+                registers: changed(&self.registers, &running.registers),
+                floats: changed(&self.floats, &running.floats),
+                memory: running.memory,
+            };
+            let history = &mut self.history;
+            if history.steps.len() == history.length {
+                history.steps.pop_front();
+            }
+            history.steps.push_back(step);
+        }
+        self.history.running = Some(self.running_step());
+    }
+
+    fn running_step(&self) -> Running {
+        let slot = self.slot(self.pc);
+        let memory = match self.memory_access(&self.code[slot]) {
+            Some((Access::Write(_) | Access::Both, address, size)) if self.memory.is_mapped(address, size) => {
+                Some((address, self.memory.get_with(address, size, <[u8]>::to_vec)))
+            }
+            _ => None,
+        };
+        Running { pc: self.pc, registers: self.registers, floats: self.floats, memory }
+    }
+
+    /// Undoes the instruction that ran last, or returns `false` if the history doesn't go further
+    /// back
+    pub(super) fn step_back(&mut self) -> bool {
+        let Some(step) = self.history.steps.pop_back() else { return false };
+        for (r, x) in step.registers {
+            self.registers[r as usize] = x;
+        }
+        for (r, x) in step.floats {
+            self.floats[r as usize] = x;
+        }
+        // Not with `set_byte`, which leaves out the transparent bytes of the video
+        if let Some((address, bytes)) = step.memory {
+            self.memory.set_with(address, bytes.len(), &bytes[..], |old, bytes| old.copy_from_slice(bytes));
+        }
+        self.pc = step.pc;
+        self.stats.instructions -= 1;
+
+        // The instruction at the pc runs again
+        self.history.running = Some(self.running_step());
+        let slot = self.slot(self.pc);
+        self.debugger.set_last(Transfer::of(&self.code[slot]));
+        true
+    }
+
+    /// Steps back until a breakpoint, and returns whether there was one before the history ran out
+    pub(super) fn reverse_continue(&mut self) -> bool {
+        while self.step_back() {
+            if self.debugger.breakpoints().contains(&self.pc) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Runs the instruction at the pc next, after the debugger moved it while paused in the
+    /// middle of another one, like an `ebreak`
+    pub(super) fn resume_at_pc(&mut self) {
+        // The run loop counts it again
+        self.stats.instructions -= 1;
+        self.history.running = None;
+    }
+
+    /// Goes back `n` instructions, and says where we got to
+    pub(super) fn go_back(&mut self, n: usize) -> String {
+        let went = (0..n).take_while(|_| self.step_back()).count();
+        let plural = if went == 1 { "" } else { "s" };
+        if went < n {
+            format!("Went back {} instruction{}, as far as the history goes", went, plural)
+        } else {
+            format!("Went back {} instruction{}", went, plural)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The simulator after `keys` for the debugger make it quit
+    fn debug(code: &str, length: usize, keys: &'static str) -> Simulator {
+        let mut sim = Simulator::new()
+            .debug(true)
+            .history(length)
+            .load_str(code)
+            .unwrap();
+        sim.debugger.set_keys(keys);
+        assert_eq!(sim.run(), 1);
+        sim
+    }
+
+    #[test]
+    fn test_history() {
+        let code = ".data\nx: .word 7\n.text\nla t0, x\nli t1, 42\nsw t1, 0(t0)\nfcvt.s.w ft0, t1\naddi t1, t1, 1";
+        let sim = debug(code, 100, "s\n\n\n\n\n\nback\nback 2\nq\n");
+        assert_eq!((sim.pc, sim.get_reg::<u32>(6), sim.stats.instructions), (0xc, 42, 4));
+        let x = sim.resolve_address("x").unwrap();
+        assert_eq!(sim.memory.get_word(x), 7);
+        assert_eq!(f32::from_bits(sim.floats[0] as u32), 0.0);
+
+        // And forwards again
+        let sim = debug(code, 100, "s\n\n\n\n\n\nback 3\ns\n\n\nq\n");
+        assert_eq!((sim.pc, sim.get_reg::<u32>(6)), (0x18, 43));
+        assert_eq!(sim.memory.get_word(x), 42);
+
+        // Only the last 2 are kept
+        let sim = debug(code, 2, "s\n\n\n\n\nback 10\nq\n");
+        assert_eq!(sim.pc, 0xc);
+
+        // A transparent pixel is put back too
+        let code = "li t0, 0xff000000\nli t1, 0xc7\nfmv.w.x ft0, t1\nfsw ft0, 0(t0)\nli t1, 7\nsb t1, 0(t0)";
+        let sim = debug(code, 100, "s\n\n\n\n\n\nback\nq\n");
+        assert_eq!(sim.memory.get_byte(0xff000000), 0xc7);
+
+        // Back to the last pass of the loop
+        let code = "li t1, 0\nloop: addi t1, t1, 1\nli t2, 3\nblt t1, t2, loop\nebreak\nli a7, 93\necall";
+        let sim = debug(code, 100, "b loop\nc\nc\nc\nc\nrc\nq\n");
+        assert_eq!((sim.pc, sim.get_reg::<u32>(6)), (0x4, 2));
+        let sim = debug(code, 100, "c\nrc\nq\n");
+        assert_eq!(sim.pc, 0x0);
+    }
+}
@@ -1,45 +1,83 @@
-///
-/// If a type implements IntoRegister, then we can store its bit regresentation
-/// in a 32-bit register as a u32
-///
-pub trait IntoRegister {
-    fn into(self) -> u32;
-}
-
-macro_rules! impl_into_reg {
-    ($type:ident, $conv:ident) => {
-        impl IntoRegister for $type {
-            fn into(self) -> u32 {
-                self as $conv as u32
-            }
-        }
-    };
-}
-
-impl_into_reg!(u32, u32);
-impl_into_reg!(i32, u32);
-impl_into_reg!(u16, u16);
-impl_into_reg!(i16, u16);
-impl_into_reg!(u8, u8);
-impl_into_reg!(i8, u8);
-
-pub trait FromRegister {
-    fn from(x: u32) -> Self;
-}
-
-macro_rules! impl_from_reg {
-    ($type:ident) => {
-        impl FromRegister for $type {
-            fn from(x: u32) -> Self {
-                x as $type
-            }
-        }
-    };
-}
-
-impl_from_reg!(u32);
-impl_from_reg!(i32);
-impl_from_reg!(u16);
-impl_from_reg!(i16);
-impl_from_reg!(u8);
-impl_from_reg!(i8);
\ No newline at end of file
+///
+/// If a type implements IntoRegister, then we can store its bit regresentation
+/// in a register as a u64. The registers are 64 bits wide so that RV64 fits, and a 32-bit value is
+/// sign extended, which is what RV64 does and what keeps RV32 the same on the low 32 bits.
+///
+pub trait IntoRegister {
+    fn into(self) -> u64;
+}
+
+macro_rules! impl_into_reg {
+    ($type:ident, $conv:ident) => {
+        impl IntoRegister for $type {
+            fn into(self) -> u64 {
+                self as $conv as u64
+            }
+        }
+    };
+}
+
+impl_into_reg!(u64, u64);
+impl_into_reg!(i64, u64);
+impl_into_reg!(u32, i32);
+impl_into_reg!(i32, i32);
+impl_into_reg!(u16, u16);
+impl_into_reg!(i16, u16);
+impl_into_reg!(u8, u8);
+impl_into_reg!(i8, u8);
+
+pub trait FromRegister {
+    fn from(x: u64) -> Self;
+}
+
+macro_rules! impl_from_reg {
+    ($type:ident) => {
+        impl FromRegister for $type {
+            fn from(x: u64) -> Self {
+                x as $type
+            }
+        }
+    };
+}
+
+impl_from_reg!(u64);
+impl_from_reg!(i64);
+impl_from_reg!(u32);
+impl_from_reg!(i32);
+impl_from_reg!(u16);
+impl_from_reg!(i16);
+impl_from_reg!(u8);
+impl_from_reg!(i8);
+///
+/// The float registers are 64 bits wide, to fit a double. A single precision float is kept
+/// NaN-boxed, with all of the upper 32 bits set, and reading one that isn't gives the
+/// canonical NaN, like the RISC-V spec says.
+///
+pub trait FloatRegister {
+    fn from_float_reg(x: u64) -> Self;
+    fn into_float_reg(self) -> u64;
+}
+
+impl FloatRegister for f32 {
+    fn from_float_reg(x: u64) -> Self {
+        if x >> 32 == 0xFFFF_FFFF {
+            f32::from_bits(x as u32)
+        } else {
+            f32::from_bits(0x7fc0_0000)
+        }
+    }
+
+    fn into_float_reg(self) -> u64 {
+        0xFFFF_FFFF_0000_0000 | self.to_bits() as u64
+    }
+}
+
+impl FloatRegister for f64 {
+    fn from_float_reg(x: u64) -> Self {
+        f64::from_bits(x)
+    }
+
+    fn into_float_reg(self) -> u64 {
+        self.to_bits()
+    }
+}
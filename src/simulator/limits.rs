@@ -0,0 +1,109 @@
+//!
+//! Limits on how long a program runs, for `--max-instructions N` and `--timeout SECONDS`, so a
+//! program stuck in an infinite loop stops by itself instead of hanging whoever is waiting for it,
+//! like a script grading the programs of a class. Past a limit, the program stops with the exit
+//! code 124, like with the `timeout` command, and we say where it was and the calls that got it
+//! there, which usually points right at the loop.
+//!
+//! The time starts when the program does, and is only looked at every so many instructions, so a
+//! program waiting for input or sleeping in an ecall can go a bit past it.
+//!
+
+use std::time::Duration;
+
+use super::debugger::Resume;
+use super::Simulator;
+
+/// The exit code of a program stopped at a limit
+pub const EXIT_CODE: i32 = 124;
+
+/// How many instructions run between two looks at the clock
+const POLL_EVERY: u64 = 1 << 12;
+
+#[derive(Debug, Default)]
+pub struct Limits {
+    instructions: Option<u64>,
+    time: Option<Duration>,
+    /// Instructions until the next look at the clock
+    countdown: u64,
+}
+
+impl Limits {
+    pub(super) fn set_instructions(&mut self, instructions: u64) {
+        self.instructions = Some(instructions);
+    }
+
+    pub(super) fn set_time(&mut self, time: Duration) {
+        self.time = Some(time);
+    }
+
+    pub(super) fn is_on(&self) -> bool {
+        self.instructions.is_some() || self.time.is_some()
+    }
+}
+
+impl Simulator {
+    /// Stops the program if it's about to run past a limit
+    pub(super) fn check_limits(&mut self) -> Resume {
+        if let Some(max) = self.limits.instructions {
+            if self.stats.instructions > max {
+                // The instruction at the pc was counted, but doesn't run
+                self.stats.instructions -= 1;
+                return self.stop_at_limit(&format!("it ran {} instructions, its limit", max));
+            }
+        }
+
+        let Some(time) = self.limits.time else { return Resume::Continue };
+        self.limits.countdown = self.limits.countdown.saturating_sub(1);
+        if self.limits.countdown > 0 {
+            return Resume::Continue;
+        }
+        self.limits.countdown = POLL_EVERY;
+        if self.started_at.elapsed() < time {
+            return Resume::Continue;
+        }
+        self.stats.instructions -= 1;
+        self.stop_at_limit(&format!("it ran for {:?}, its time limit", time))
+    }
+
+    /// Says why and where the program stopped
+    fn stop_at_limit(&self, why: &str) -> Resume {
+        eprintln!("Stopped the program because {}", why);
+        eprintln!("  at {:#x}{}: {}", self.pc, self.source_at(self.pc), self.code[self.slot(self.pc)]);
+        self.report_calls();
+        Resume::Exit(EXIT_CODE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(code: &str) -> Simulator {
+        Simulator::new().load_str(code).unwrap()
+    }
+
+    #[test]
+    fn test_limits() {
+        // Calls a function that never returns
+        let code = "li a0, 3\njal spin\nli a7, 93\necall\nspin: addi a0, a0, 1\nj spin";
+
+        let mut sim = load(code).max_instructions(1000);
+        assert_eq!(sim.run(), EXIT_CODE);
+        assert_eq!(sim.stats.instructions, 1000);
+        assert_eq!(sim.resolve_address("spin").map(|spin| sim.pc - spin), Some(0));
+        assert_eq!(sim.get_reg::<u32>(10), 3 + 499);
+
+        let mut sim = load(code).timeout(Duration::from_millis(50));
+        let started = std::time::Instant::now();
+        assert_eq!(sim.run(), EXIT_CODE);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // A program that ends before its limits runs like always
+        let code = "li a0, 7\nli a7, 93\necall";
+        let mut sim = load(code).max_instructions(3).timeout(Duration::from_secs(10));
+        assert_eq!(sim.run(), 7);
+        let mut sim = load(code).max_instructions(2);
+        assert_eq!(sim.run(), EXIT_CODE);
+    }
+}
@@ -0,0 +1,834 @@
+//!
+//! The memory the program sees, split in segments: the text, when the code is in the memory, the
+//! data, the heap and the stack, plus the devices mapped over them, like the MMIO of the video and
+//! the keyboard or a [shared memory](../struct.SharedMemoryConfig.html) window.
+//!
+//! The text, data, heap and stack are backed by pages of 4 KiB that cover the whole 32-bit address
+//! space, and the list of segments says which one owns each address. A page is only allocated when
+//! something is written to it, the others read as zeros, so the segments can be far apart, like the
+//! data at 0x10010000 and the stack at 0x7fffeffc of RARS. A [Device](trait.Device.html) takes a range of
+//! addresses and gets every access to them, hiding whatever is below, so a new one only has to be
+//! [mapped](struct.Memory.html#method.map) to show up for the program.
+//!
+//! Each segment says whether the program can read, write and run what's in it, see
+//! [Permissions](struct.Permissions.html). The code is only run from the text, which can't be read
+//! nor written to unless we run with `readable_code` or `self_modifying`, and the rest can't be
+//! run. The `.rodata` can't be written to either. Breaking these is an access fault, which goes to
+//! the trap handler, if there's one, or stops the program. The code isn't in the memory unless
+//! with those two, so it's only a segment when its addresses aren't the data's, like with
+//! `--rars-layout`.
+//!
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use super::{DATA_SIZE, KBMMIO_CONTROL, KBMMIO_DATA, MMIO_SIZE, MMIO_START, RARS_MMIO, VIDEO_END, VIDEO_START};
+
+const PAGE_BITS: usize = 12;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+const PAGE_MASK: usize = PAGE_SIZE - 1;
+/// How many pages there are in the 32-bit address space
+const PAGES: usize = 1 << (32 - PAGE_BITS);
+
+/// What every page that wasn't written to has
+static ZERO_PAGE: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+/// What owns an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    /// The machine code, with [readable_code](../struct.Simulator.html#method.readable_code) or
+    /// [self_modifying](../struct.Simulator.html#method.self_modifying)
+    Text,
+    /// The `.data` and `.rodata` of a program, and its `.extern`s
+    Data,
+    Heap,
+    Stack,
+    /// The video and keyboard, or some other [Device](trait.Device.html)
+    Mmio,
+    /// A window shared with other instances
+    Shared,
+}
+
+/// Every segment, in the order of their numbers in a [snapshot](../snapshot/index.html)
+const SEGMENTS: [Segment; 6] = [Segment::Text, Segment::Data, Segment::Heap, Segment::Stack, Segment::Mmio, Segment::Shared];
+
+impl Segment {
+    /// The position of the segment in `SEGMENTS`
+    fn index(self) -> usize {
+        SEGMENTS.iter().position(|&s| s == self).unwrap()
+    }
+}
+
+/// What the program can do with the bytes of a segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    /// The data, the heap, the stack and the devices
+    pub const DATA: Self = Self { read: true, write: true, execute: false };
+    /// The code, when it isn't in the memory
+    pub const CODE: Self = Self { read: false, write: false, execute: true };
+    /// The code with [readable_code](../struct.Simulator.html#method.readable_code)
+    pub const READABLE_CODE: Self = Self { read: true, write: false, execute: true };
+    /// The code with [self_modifying](../struct.Simulator.html#method.self_modifying)
+    pub const ALL: Self = Self { read: true, write: true, execute: true };
+
+    fn and(self, other: Self) -> Self {
+        Self { read: self.read && other.read, write: self.write && other.write, execute: self.execute && other.execute }
+    }
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Segment::Text => "code",
+            Segment::Data => "data",
+            Segment::Heap => "heap",
+            Segment::Stack => "stack",
+            Segment::Mmio => "MMIO",
+            Segment::Shared => "shared memory",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something mapped into the memory, which gets every access to its addresses. The bytes given to
+/// `read` and `write` start at `addr`, the address of the access.
+pub trait Device: Send {
+    fn read(&self, addr: usize, read: &mut dyn FnMut(&[u8]));
+    fn write(&mut self, addr: usize, write: &mut dyn FnMut(&mut [u8]));
+}
+
+/// The video and the keyboard, whose bytes the renderer reads and writes too
+struct Mmio(Arc<Mutex<Vec<u8>>>);
+
+impl Device for Mmio {
+    fn read(&self, addr: usize, read: &mut dyn FnMut(&[u8])) {
+        let mut mmio = self.0.lock().unwrap();
+        // Reading the key says we got it
+        if addr == KBMMIO_DATA {
+            mmio[KBMMIO_CONTROL - MMIO_START] = 0;
+        }
+        read(&mmio[addr - MMIO_START..])
+    }
+
+    fn write(&mut self, addr: usize, write: &mut dyn FnMut(&mut [u8])) {
+        let mut mmio = self.0.lock().unwrap();
+        write(&mut mmio[addr - MMIO_START..])
+    }
+}
+
+/// How many bytes the keyboard and display MMIO of RARS takes
+pub(super) const RARS_MMIO_SIZE: usize = 16;
+
+/// The keyboard and display MMIO of RARS: the receiver control and data are the ones of our
+/// keyboard, the transmitter is always ready, and a byte stored to its data is printed
+pub(super) struct RarsMmio(pub(super) Arc<Mutex<Vec<u8>>>);
+
+impl Device for RarsMmio {
+    fn read(&self, addr: usize, read: &mut dyn FnMut(&[u8])) {
+        let mut mmio = self.0.lock().unwrap();
+        let keyboard = KBMMIO_CONTROL - MMIO_START;
+        let mut words = [0; RARS_MMIO_SIZE];
+        words[..8].copy_from_slice(&mmio[keyboard..keyboard + 8]);
+        words[8] = 1;
+        if addr == RARS_MMIO + 4 {
+            mmio[keyboard] = 0;
+        }
+        read(&words[addr - RARS_MMIO..])
+    }
+
+    fn write(&mut self, addr: usize, write: &mut dyn FnMut(&mut [u8])) {
+        let mut words = [0; RARS_MMIO_SIZE];
+        write(&mut words[addr - RARS_MMIO..]);
+        if addr == RARS_MMIO + 12 {
+            print!("{}", words[12] as char);
+        }
+    }
+}
+
+/// The memory itself, allocated a page at a time
+struct Pages(Box<[Option<Box<[u8; PAGE_SIZE]>>; PAGES]>);
+
+impl Pages {
+    fn new() -> Self {
+        let pages = vec![None; PAGES].into_boxed_slice();
+        Self(pages.try_into().unwrap_or_else(|_| unreachable!()))
+    }
+
+    /// The page of the address `i`. Addresses past the 32-bit space wrap around.
+    /// How many pages were written to
+    fn allocated(&self) -> usize {
+        self.0.iter().filter(|page| page.is_some()).count()
+    }
+
+    #[inline]
+    fn page(&self, i: usize) -> &[u8; PAGE_SIZE] {
+        self.0[i >> PAGE_BITS & (PAGES - 1)].as_deref().unwrap_or(&ZERO_PAGE)
+    }
+
+    #[inline]
+    fn page_mut(&mut self, i: usize) -> &mut [u8; PAGE_SIZE] {
+        self.0[i >> PAGE_BITS & (PAGES - 1)].get_or_insert_with(|| Box::new([0; PAGE_SIZE]))
+    }
+
+    /// Copies the bytes from `i` into `buf`, which can go through many pages
+    fn read(&self, i: usize, buf: &mut [u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            let (addr, offset) = (i + done, (i + done) & PAGE_MASK);
+            let n = (PAGE_SIZE - offset).min(buf.len() - done);
+            buf[done..done + n].copy_from_slice(&self.page(addr)[offset..offset + n]);
+            done += n;
+        }
+    }
+
+    /// Copies `buf` into the bytes from `i`. Pages that aren't there yet and would only get zeros
+    /// are left alone.
+    fn write(&mut self, i: usize, buf: &[u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            let (addr, offset) = (i + done, (i + done) & PAGE_MASK);
+            let n = (PAGE_SIZE - offset).min(buf.len() - done);
+            let bytes = &buf[done..done + n];
+            if self.0[addr >> PAGE_BITS & (PAGES - 1)].is_some() || bytes.iter().any(|&b| b != 0) {
+                self.page_mut(addr)[offset..offset + n].copy_from_slice(bytes);
+            }
+            done += n;
+        }
+    }
+}
+
+/// What a [snapshot](../snapshot/index.html) keeps of the memory: where each segment is, the pages
+/// that were written to, and the bytes of the video and the keyboard. The devices mapped by hand,
+/// like a shared memory window, aren't in it.
+pub(super) struct Image {
+    end: usize,
+    segments: Vec<(Segment, Range<usize>)>,
+    pages: Vec<(usize, Box<[u8; PAGE_SIZE]>)>,
+    mmio: Vec<u8>,
+}
+
+impl Image {
+    /// Reads what [write_image](struct.Memory.html#method.write_image) wrote
+    pub(super) fn read<R: Read>(input: &mut R) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+        let end = input.read_u64::<LittleEndian>()? as usize;
+
+        let mut segments = Vec::new();
+        for _ in 0..input.read_u32::<LittleEndian>()? {
+            let segment = *SEGMENTS.get(input.read_u8()? as usize).ok_or_else(|| invalid("unknown segment"))?;
+            let start = input.read_u64::<LittleEndian>()? as usize;
+            segments.push((segment, start..input.read_u64::<LittleEndian>()? as usize));
+        }
+
+        let mut pages = Vec::new();
+        for _ in 0..input.read_u32::<LittleEndian>()? {
+            let page = input.read_u32::<LittleEndian>()? as usize;
+            if page >= PAGES {
+                return Err(invalid("page out of the memory"));
+            }
+            let mut bytes = Box::new([0; PAGE_SIZE]);
+            input.read_exact(&mut bytes[..])?;
+            pages.push((page, bytes));
+        }
+
+        let mut mmio = vec![0; input.read_u32::<LittleEndian>()? as usize];
+        input.read_exact(&mut mmio)?;
+        Ok(Self { end, segments, pages, mmio })
+    }
+}
+
+struct Mapping {
+    segment: Segment,
+    range: Range<usize>,
+    device: Box<dyn Device>,
+}
+
+pub struct Memory {
+    pub mmio: Arc<Mutex<Vec<u8>>>,
+    /// The text, data, heap and stack
+    pages: Pages,
+    /// The most pages there were before the last time they were all thrown away
+    peak_pages: usize,
+    /// Where the last segment ends
+    end: usize,
+    /// Which addresses each segment has, in the order they were added
+    segments: Vec<(Segment, Range<usize>)>,
+    /// Mapped over the pages, the last one mapped first
+    devices: Vec<Mapping>,
+    /// Where the first device starts, so most accesses don't have to look for one
+    devices_start: usize,
+    /// What the program can do with each of the `SEGMENTS`
+    permissions: [Permissions; SEGMENTS.len()],
+    /// Parts of the data the program can't write to
+    pub(super) read_only: Vec<Range<usize>>,
+    /// With [self_modifying](../struct.Simulator.html#method.self_modifying), the code is in these
+    /// addresses, and the stores to them are kept in `code_writes` until the code is decoded again
+    pub(super) code_range: Range<usize>,
+    pub(super) code_writes: Vec<usize>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        let mmio = Arc::new(Mutex::new(vec![0; MMIO_SIZE]));
+        let mut memory = Self {
+            mmio: mmio.clone(),
+            pages: Pages::new(),
+            peak_pages: 0,
+            end: 0,
+            segments: Vec::new(),
+            devices: Vec::new(),
+            devices_start: usize::MAX,
+            permissions: [Permissions::DATA; SEGMENTS.len()],
+            read_only: Vec::new(),
+            code_range: 0..0,
+            code_writes: Vec::new(),
+        };
+        memory.map(Segment::Mmio, MMIO_START..MMIO_START + MMIO_SIZE, Box::new(Mmio(mmio)));
+        memory.add_segments(DATA_SIZE, 0);
+        memory.set_permissions(Segment::Text, Permissions::CODE);
+        memory
+    }
+
+    /// Lets the program do `permissions` with the bytes of `segment`
+    pub(super) fn set_permissions(&mut self, segment: Segment, permissions: Permissions) {
+        self.permissions[segment.index()] = permissions;
+    }
+
+    /// Maps `device` at `range`, over whatever was there
+    pub fn map(&mut self, segment: Segment, range: Range<usize>, device: Box<dyn Device>) {
+        self.devices_start = self.devices_start.min(range.start);
+        self.devices.insert(0, Mapping { segment, range, device });
+    }
+
+    /// Puts the data of a program at `base`, with nothing before it. `data_end` is where its data
+    /// ends, and the heap and the stack take the rest of `data`.
+    pub(super) fn load(&mut self, base: usize, data: Vec<u8>, data_end: usize) {
+        self.peak_pages = self.peak_pages.max(self.pages.allocated());
+        self.pages = Pages::new();
+        self.end = base;
+        self.segments.clear();
+        self.push_program(data, data_end);
+    }
+
+    /// Puts the data of another program after everything else, like [load](#method.load)
+    pub(super) fn push_program(&mut self, data: Vec<u8>, data_end: usize) {
+        self.pages.write(self.end, &data);
+        self.add_segments(data.len(), data_end);
+    }
+
+    /// The data, heap and stack of a program that takes `len` bytes after the end
+    fn add_segments(&mut self, len: usize, data_end: usize) {
+        let (base, end) = (self.end, self.end + len);
+        self.segments.push((Segment::Data, base..data_end));
+        self.segments.push((Segment::Heap, data_end..data_end));
+        self.segments.push((Segment::Stack, data_end..end));
+        self.end = end;
+    }
+
+    /// Writes the segments, the pages that aren't all zeros and the MMIO, see [Image](struct.Image.html)
+    pub(super) fn write_image<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_u64::<LittleEndian>(self.end as u64)?;
+        out.write_u32::<LittleEndian>(self.segments.len() as u32)?;
+        for (segment, range) in &self.segments {
+            out.write_u8(segment.index() as u8)?;
+            out.write_u64::<LittleEndian>(range.start as u64)?;
+            out.write_u64::<LittleEndian>(range.end as u64)?;
+        }
+
+        let pages: Vec<(usize, &[u8; PAGE_SIZE])> = self.pages.0.iter()
+            .enumerate()
+            .filter_map(|(i, page)| page.as_deref().map(|page| (i, page)))
+            .filter(|(_, page)| page.iter().any(|&b| b != 0))
+            .collect();
+        out.write_u32::<LittleEndian>(pages.len() as u32)?;
+        for (i, page) in pages {
+            out.write_u32::<LittleEndian>(i as u32)?;
+            out.write_all(page)?;
+        }
+
+        let mmio = self.mmio.lock().unwrap();
+        out.write_u32::<LittleEndian>(mmio.len() as u32)?;
+        out.write_all(&mmio)
+    }
+
+    /// Puts the memory back like it was in `image`
+    pub(super) fn restore(&mut self, image: Image) {
+        self.peak_pages = self.peak_pages.max(self.pages.allocated());
+        self.pages = Pages::new();
+        for (i, bytes) in image.pages {
+            self.pages.0[i] = Some(bytes);
+        }
+        self.end = image.end;
+        self.segments = image.segments;
+
+        let mut mmio = self.mmio.lock().unwrap();
+        let n = mmio.len().min(image.mmio.len());
+        mmio[..n].copy_from_slice(&image.mmio[..n]);
+    }
+
+    /// Moves the stack of the last program to `range`, like the one of RARS that ends at
+    /// 0x7ffff000. What's left between the heap and the stack isn't in the memory until the heap
+    /// grows into it.
+    pub(super) fn move_stack(&mut self, range: Range<usize>) {
+        let n = self.segments.len();
+        self.segments[n - 1].1 = range;
+    }
+
+    /// The addresses of the stack of the program `id`, 0 being the first one loaded
+    pub fn stack(&self, id: usize) -> Range<usize> {
+        self.nth(Segment::Stack, id).map_or(0..0, |i| self.segments[i].1.clone())
+    }
+
+    /// The addresses of the heap of the program `id`, up to what it allocated so far
+    pub fn heap(&self, id: usize) -> Range<usize> {
+        self.nth(Segment::Heap, id).map_or(0..0, |i| self.segments[i].1.clone())
+    }
+
+    /// Makes the heap of the program `id` end at `end`. If it grows into the stack, the stack
+    /// starts after it now.
+    pub(super) fn set_heap_end(&mut self, id: usize, end: usize) {
+        let (heap, stack) = match (self.nth(Segment::Heap, id), self.nth(Segment::Stack, id)) {
+            (Some(heap), Some(stack)) => (heap, stack),
+            _ => return,
+        };
+        self.segments[heap].1.end = end;
+        let stack = &mut self.segments[stack].1;
+        stack.start = stack.start.max(end);
+    }
+
+    /// The index in `segments` of the `segment` of the program `id`
+    fn nth(&self, segment: Segment, id: usize) -> Option<usize> {
+        let mut indices = self.segments.iter().enumerate().filter(|(_, (s, _))| *s == segment);
+        indices.nth(id).map(|(i, _)| i)
+    }
+
+    /// The code is in the memory at `range` now
+    pub(super) fn set_text(&mut self, range: Range<usize>) {
+        self.segments.retain(|(segment, _)| *segment != Segment::Text);
+        self.segments.insert(0, (Segment::Text, range));
+    }
+
+    /// The most bytes of pages the program had at once, since pages are never freed while it runs
+    pub fn peak_size(&self) -> usize {
+        self.peak_pages.max(self.pages.allocated()) * PAGE_SIZE
+    }
+
+    /// Where the last segment ends, which is the top of the stack of the last program
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Whether the addresses in `range` aren't in any segment yet, besides the text, which they
+    /// can take
+    pub(super) fn is_free(&self, range: Range<usize>) -> bool {
+        let others = self.segments.iter().filter(|(segment, _)| *segment != Segment::Text);
+        others.map(|(_, r)| r).all(|r| r.end <= range.start || range.end <= r.start)
+    }
+
+    /// What owns the address `i`, if anything
+    pub fn segment(&self, i: usize) -> Option<Segment> {
+        match self.device(i) {
+            Some(mapping) => Some(mapping.segment),
+            None => self
+                .segments
+                .iter()
+                .find(|(_, range)| range.contains(&i))
+                .map(|&(segment, _)| segment),
+        }
+    }
+
+    /// The device mapped at `i`, if any
+    #[inline]
+    fn device(&self, i: usize) -> Option<&Mapping> {
+        if i < self.devices_start {
+            return None;
+        }
+        self.devices.iter().find(|mapping| mapping.range.contains(&i))
+    }
+
+    /// Whether the `n` bytes starting at `i` are all in the memory, either in a segment or in a
+    /// single device. Accessing anything else is a bug in the program, like a null pointer.
+    #[inline]
+    pub fn is_mapped(&self, i: usize, n: usize) -> bool {
+        let in_segment = |i| self.segments.iter().any(|(_, range)| range.contains(&i));
+        match self.device(i) {
+            Some(mapping) => i + n <= mapping.range.end,
+            None => in_segment(i) && in_segment(i + n - 1),
+        }
+    }
+
+    /// What the program can do with all of the `n` bytes starting at `i`, or `None` if they
+    /// aren't all in the memory, like in [is_mapped](#method.is_mapped)
+    #[inline]
+    pub fn permissions(&self, i: usize, n: usize) -> Option<Permissions> {
+        let segment = |i| self.segments.iter().find(|(_, range)| range.contains(&i)).map(|&(segment, _)| segment);
+        let permissions = match self.device(i) {
+            Some(mapping) if i + n <= mapping.range.end => self.permissions[mapping.segment.index()],
+            Some(_) => return None,
+            None => {
+                let (first, last) = (segment(i)?, segment(i + n - 1)?);
+                self.permissions[first.index()].and(self.permissions[last.index()])
+            }
+        };
+        if self.read_only.iter().any(|r| r.start < i + n && i < r.end) {
+            return Some(Permissions { write: false, ..permissions });
+        }
+        Some(permissions)
+    }
+
+    /// Whether any of the `n` bytes starting at `i` are read-only, like the `.rodata`
+    pub(super) fn is_read_only(&self, i: usize, n: usize) -> bool {
+        self.permissions(i, n).is_some_and(|permissions| !permissions.write)
+    }
+
+    /// Sets N bytes in the video memory, but ignores bytes equal to 0xC7.
+    fn set_with_transparency(&mut self, i: usize, mut x: u32, n: usize) -> bool {
+        if !(VIDEO_START..VIDEO_END).contains(&i) {
+            return false;
+        }
+
+        let mut mmio = self.mmio.lock().unwrap();
+        let i = i - MMIO_START;
+
+        for data in &mut mmio[i..i+n] {
+            let byte = x as u8;
+            if byte != 0xC7 {
+                *data = byte;
+            }
+
+            x >>= 8;
+        }
+
+        true
+    }
+
+    /// Calls `read` with the `len` bytes from `i`
+    #[inline]
+    pub fn get_with<T, F>(&self, i: usize, len: usize, read: F) -> T
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        let offset = i & PAGE_MASK;
+        if i < self.devices_start && offset + len <= PAGE_SIZE {
+            return read(&self.pages.page(i)[offset..offset + len]);
+        }
+        self.get_with_slow(i, len, read)
+    }
+
+    /// [get_with](#method.get_with) for devices and for accesses that go through many pages
+    #[cold]
+    fn get_with_slow<T, F>(&self, i: usize, len: usize, read: F) -> T
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        if let Some(mapping) = self.device(i) {
+            let (mut read, mut result) = (Some(read), None);
+            mapping.device.read(i, &mut |bytes| result = read.take().map(|read| read(&bytes[..len])));
+            return result.expect("a device didn't read anything");
+        }
+
+        let mut buf = vec![0; len];
+        self.pages.read(i, &mut buf);
+        read(&buf)
+    }
+
+    /// Calls `write` with `x` and the `len` bytes from `i`, to change them
+    #[inline]
+    pub fn set_with<T, F, R>(&mut self, i: usize, len: usize, x: T, write: F) -> R
+    where
+        F: FnOnce(&mut [u8], T) -> R,
+    {
+        if self.code_range.contains(&i) {
+            self.code_writes.push(i);
+        }
+
+        let offset = i & PAGE_MASK;
+        if i < self.devices_start && offset + len <= PAGE_SIZE {
+            return write(&mut self.pages.page_mut(i)[offset..offset + len], x);
+        }
+        self.set_with_slow(i, len, x, write)
+    }
+
+    /// [set_with](#method.set_with) for devices and for accesses that go through many pages
+    #[cold]
+    fn set_with_slow<T, F, R>(&mut self, i: usize, len: usize, x: T, write: F) -> R
+    where
+        F: FnOnce(&mut [u8], T) -> R,
+    {
+        let device = self.devices.iter_mut().find(|mapping| mapping.range.contains(&i));
+        if let Some(mapping) = device {
+            let (mut write, mut x, mut result) = (Some(write), Some(x), None);
+            mapping.device.write(i, &mut |bytes| {
+                if let (Some(write), Some(x)) = (write.take(), x.take()) {
+                    result = Some(write(&mut bytes[..len], x));
+                }
+            });
+            return result.expect("a device didn't write anything");
+        }
+
+        let mut buf = vec![0; len];
+        self.pages.read(i, &mut buf);
+        let result = write(&mut buf, x);
+        self.pages.write(i, &buf);
+        result
+    }
+
+    pub fn get_byte(&self, i: usize) -> u8 {
+        self.get_with(i, 1, |v| v[0])
+    }
+
+    pub fn set_byte(&mut self, i: usize, x: u8) {
+        if self.set_with_transparency(i, x as u32, 1) {
+            return;
+        }
+        self.set_with(i, 1, x, |v, x| v[0] = x)
+    }
+
+    pub fn get_half(&self, i: usize) -> u16 {
+        self.get_with(i, 2, LittleEndian::read_u16)
+    }
+
+    pub fn set_half(&mut self, i: usize, x: u16) {
+        if self.set_with_transparency(i, x as u32, 2) {
+            return;
+        }
+        self.set_with(i, 2, x, LittleEndian::write_u16)
+    }
+
+    pub fn get_word(&self, i: usize) -> u32 {
+        self.get_with(i, 4, LittleEndian::read_u32)
+    }
+
+    pub fn set_word(&mut self, i: usize, x: u32) {
+        if self.set_with_transparency(i, x, 4) {
+            return;
+        }
+        self.set_with(i, 4, x, LittleEndian::write_u32)
+    }
+
+    pub fn get_dword(&self, i: usize) -> u64 {
+        self.get_with(i, 8, LittleEndian::read_u64)
+    }
+
+    pub fn set_dword(&mut self, i: usize, x: u64) {
+        self.set_with(i, 8, x, LittleEndian::write_u64)
+    }
+
+    pub fn get_float(&self, i: usize) -> f32 {
+        self.get_with(i, 4, LittleEndian::read_f32)
+    }
+
+    pub fn set_float(&mut self, i: usize, x: f32) {
+        self.set_with(i, 4, x, LittleEndian::write_f32)
+    }
+
+    pub fn get_double(&self, i: usize) -> f64 {
+        self.get_with(i, 8, LittleEndian::read_f64)
+    }
+
+    pub fn set_double(&mut self, i: usize, x: f64) {
+        self.set_with(i, 8, x, LittleEndian::write_f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts the accesses, and reads as the number of writes so far
+    struct Counter(u32);
+
+    impl Device for Counter {
+        fn read(&self, _: usize, read: &mut dyn FnMut(&[u8])) {
+            read(&self.0.to_le_bytes())
+        }
+
+        fn write(&mut self, _: usize, write: &mut dyn FnMut(&mut [u8])) {
+            self.0 += 1;
+            write(&mut [0; 8])
+        }
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut memory = Memory::new();
+        memory.load(0x100, vec![0; 0x200], 0x140);
+        memory.set_text(0..0x40);
+        memory.push_program(vec![0; 0x100], 0x320);
+
+        let segment = |i| memory.segment(i);
+        assert_eq!(segment(0x3c), Some(Segment::Text));
+        assert_eq!(segment(0x80), None);
+        assert_eq!(segment(0x13c), Some(Segment::Data));
+        assert_eq!(segment(0x140), Some(Segment::Stack));
+        assert_eq!(segment(0x2ff), Some(Segment::Stack));
+        assert_eq!(segment(0x310), Some(Segment::Data));
+        assert_eq!(segment(0x320), Some(Segment::Stack));
+        assert_eq!(segment(KBMMIO_DATA), Some(Segment::Mmio));
+        assert_eq!(memory.end(), 0x400);
+
+        assert!(memory.is_mapped(0x3c, 4) && memory.is_mapped(0x13c, 8));
+        assert!(!memory.is_mapped(0x3e, 4));
+        assert!(!memory.is_mapped(0x3fe, 4));
+        assert!(!memory.is_mapped(MMIO_START + MMIO_SIZE - 2, 4));
+
+        // The stack of RARS, with the heap where it was
+        let mut memory = Memory::new();
+        memory.load(0x1001_0000, vec![0; 0x200], 0x1001_0040);
+        memory.move_stack(0x7fff_ee40..0x7fff_f000);
+        assert_eq!(memory.stack(0), 0x7fff_ee40..0x7fff_f000);
+        assert_eq!(memory.segment(0x1001_0100), None);
+        memory.set_heap_end(0, 0x1001_0200);
+        assert_eq!(memory.segment(0x1001_0100), Some(Segment::Heap));
+        assert_eq!(memory.segment(0x7fff_effc), Some(Segment::Stack));
+        assert!(!memory.is_mapped(0x1001_0200, 4));
+
+        // A heap that grows into the stack
+        let mut memory = Memory::new();
+        memory.load(0, vec![0; 0x200], 0x40);
+        memory.set_heap_end(0, 0x100);
+        assert_eq!(memory.heap(0), 0x40..0x100);
+        assert_eq!(memory.stack(0), 0x100..0x200);
+    }
+
+    #[test]
+    fn test_pages() {
+        let mut memory = Memory::new();
+        let allocated = |memory: &Memory| memory.pages.allocated();
+
+        // Like the data and the stack of RARS, which are far apart
+        memory.set_word(0x1001_0000, 0x1234_5678);
+        memory.set_dword(0x7fff_effc, u64::MAX);
+        assert_eq!(memory.get_word(0x1001_0000), 0x1234_5678);
+        assert_eq!(memory.get_word(0x7fff_f000), u32::MAX);
+        assert_eq!(memory.get_word(0x4000_0000), 0);
+        assert_eq!(allocated(&memory), 3);
+
+        // Across a page
+        memory.set_word(0x2ffe, 0xaabb_ccdd);
+        assert_eq!(memory.get_half(0x3000), 0xaabb);
+        assert_eq!(memory.get_word(0x2ffe), 0xaabb_ccdd);
+
+        assert_eq!(memory.peak_size(), 5 * PAGE_SIZE);
+
+        // The zeros of the data don't take any pages, and the peak is still the one from before
+        memory.load(0x1001_0000, vec![0; 0x10_0000], 0x1001_0010);
+        assert_eq!(allocated(&memory), 0);
+        assert_eq!(memory.peak_size(), 5 * PAGE_SIZE);
+        memory.push_program([vec![0; PAGE_SIZE], vec![1]].concat(), 0x1011_0010);
+        assert_eq!(allocated(&memory), 1);
+        assert_eq!(memory.get_byte(0x1011_1000), 1);
+        assert_eq!(memory.end(), 0x1011_1001);
+    }
+
+    #[test]
+    fn test_rars_layout() {
+        // The addresses of a RARS example, written as constants
+        let code = ".data\nx: .word 42\n.text\nli t0, 0x10010000\nlw a0, 0(t0)\nauipc a1, 0
+            li t0, 0xffff0000\nlw a2, 8(t0)\nsw a0, -4(sp)\nli a7, 93\necall";
+        let mut sim = crate::simulator::Simulator::new()
+            .rars_layout(true)
+            .load_str(code)
+            .unwrap();
+        assert_eq!(sim.run(), 42);
+        assert_eq!(sim.get_reg::<u32>(11), 0x0040_0008);
+        assert_eq!(sim.get_reg::<u32>(12), 1);
+        assert_eq!(sim.get_reg::<u32>(2), 0x7fff_effc);
+        assert_eq!(sim.memory.get_word(0x7fff_eff8), 42);
+
+        // The data covers all of RARS's, not only what's declared
+        let code = ".data\nx: .word 1\n.text\nli t0, 0x10010000\nli t1, 5\nsw t1, 64(t0)
+            li t0, 0x1003fffc\nsw t1, 0(t0)\nlw a0, 0(t0)\nli a7, 93\necall";
+        let mut sim = crate::simulator::Simulator::new()
+            .rars_layout(true)
+            .load_str(code)
+            .unwrap();
+        assert_eq!(sim.run(), 5);
+        assert_eq!(sim.memory.get_word(0x1001_0040), 5);
+        assert_eq!(sim.memory.segment(0x1003_fffc), Some(Segment::Data));
+    }
+
+    #[test]
+    fn test_permissions() {
+        // Runs `body` with a trap handler that exits with the cause, and the pc in a1
+        let run = |body: &str, trap: bool, readable_code: bool| {
+            let handler = if trap { "la t0, handler\ncsrw t0, mtvec\n" } else { "" };
+            let code = format!(
+                ".data\nx: .word 0x00000013\n.text\n{}{}\nli a7, 10\necall
+                handler: csrr a0, mcause\ncsrr a1, mepc\nli a7, 93\necall",
+                handler, body
+            );
+            let mut sim = crate::simulator::Simulator::new()
+                .rars_layout(true)
+                .readable_code(readable_code)
+                .load_str(&code)
+                .unwrap();
+            (sim.run(), sim.get_reg::<u32>(11) as usize, sim.resolve_address("x").unwrap())
+        };
+
+        // A store to the code, a load from it and running the data are access faults
+        let store = "la t1, handler\nsw zero, 0(t1)";
+        let load = "la t1, handler\nlw t1, 0(t1)";
+        let jump = "la t1, x\njr t1";
+        assert_eq!(run(store, true, false).0, 7);
+        assert_eq!(run(load, true, false).0, 5);
+        let (cause, pc, x) = run(jump, true, false);
+        assert_eq!((cause, pc), (1, x));
+
+        // Which stop the program without a trap handler
+        assert_eq!(run(store, false, false).0, 1);
+        assert_eq!(run(jump, false, false).0, 1);
+
+        // The code can be read with readable_code, but still not written to
+        assert_eq!(run(load, true, true).0, 0);
+        assert_eq!(run(store, true, true).0, 7);
+
+        let mut memory = Memory::new();
+        memory.load(0x1000, vec![0; 0x100], 0x1040);
+        memory.set_text(0..0x100);
+        memory.read_only.push(0x1000..0x1010);
+        assert_eq!(memory.permissions(0x80, 4), Some(Permissions::CODE));
+        assert_eq!(memory.permissions(0x1000, 4), Some(Permissions { write: false, ..Permissions::DATA }));
+        assert_eq!(memory.permissions(0x1040, 4), Some(Permissions::DATA));
+        assert_eq!(memory.permissions(0xfe, 4), None);
+        assert_eq!(memory.permissions(KBMMIO_DATA, 4), Some(Permissions::DATA));
+    }
+
+    #[test]
+    fn test_devices() {
+        let mut memory = Memory::new();
+        memory.set_word(0x1000, 7);
+        memory.map(Segment::Mmio, 0x1000..0x1004, Box::new(Counter(0)));
+
+        // The device hides the ram below it
+        assert_eq!(memory.get_word(0x1000), 0);
+        memory.set_word(0x1000, 7);
+        memory.set_byte(0x1003, 7);
+        assert_eq!(memory.get_word(0x1000), 2);
+        assert_eq!(memory.get_word(0x1004), 0);
+
+        // Reading the key clears the control word
+        memory.mmio.lock().unwrap()[KBMMIO_CONTROL - MMIO_START] = 1;
+        memory.get_byte(KBMMIO_DATA);
+        assert_eq!(memory.get_word(KBMMIO_CONTROL), 0);
+
+        // The keyboard of RARS is the same one
+        memory.map(Segment::Mmio, RARS_MMIO..RARS_MMIO + RARS_MMIO_SIZE, Box::new(RarsMmio(memory.mmio.clone())));
+        memory.set_word(KBMMIO_CONTROL, 1);
+        memory.set_word(KBMMIO_DATA, 'a' as u32);
+        assert_eq!(memory.get_word(RARS_MMIO), 1);
+        assert_eq!(memory.get_word(RARS_MMIO + 8), 1);
+        assert_eq!(memory.get_word(RARS_MMIO + 4), 'a' as u32);
+        assert_eq!(memory.get_word(RARS_MMIO), 0);
+    }
+}
@@ -0,0 +1,205 @@
+//!
+//! The instruction mix of a run, which `--instruction-mix` shows when the program exits: how many
+//! times each instruction ran, by kind and by mnemonic, and how many times the branches were taken.
+//! We count the runs of each instruction of the code while running, which only takes an increment,
+//! and only put them together by mnemonic at the end. A branch was taken when the next instruction
+//! isn't the one after it, so one to the instruction right after it counts as not taken.
+//!
+
+use std::fmt;
+
+use crate::parser::{FloatInstruction as F, Instruction, Rv64Instruction as R, VectorInstruction as V};
+
+/// The runs of each slot of the code, counted when the
+/// [instruction_mix](../struct.Simulator.html#method.instruction_mix) is on
+#[derive(Debug, Default)]
+pub struct Mix {
+    runs: Vec<u64>,
+    /// How many of the runs of each slot were followed by something else than the next instruction,
+    /// which for a branch means it was taken
+    jumped: Vec<u64>,
+    /// The slot that ran last
+    last: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Category {
+    Alu,
+    Branch,
+    Jump,
+    Memory,
+    Float,
+    Vector,
+    Ecall,
+    System,
+}
+
+impl Category {
+    fn of(instruction: &Instruction) -> Self {
+        use Instruction::*;
+
+        match instruction {
+            Beq(..) | Bne(..) | Blt(..) | Bge(..) | Bltu(..) | Bgeu(..) => Category::Branch,
+            Jal(..) | Jalr(..) | Ret => Category::Jump,
+            Lb(..) | Lh(..) | Lw(..) | Lbu(..) | Lhu(..) | Sb(..) | Sh(..) | Sw(..) => Category::Memory,
+            LrW(..) | ScW(..) | AmoSwapW(..) | AmoAddW(..) | AmoXorW(..) | AmoAndW(..) | AmoOrW(..) => Category::Memory,
+            AmoMinW(..) | AmoMaxW(..) | AmoMinuW(..) | AmoMaxuW(..) => Category::Memory,
+            Rv64(R::Ld(..) | R::Lwu(..) | R::Sd(..)) => Category::Memory,
+            Float(F::Lw(..) | F::Sw(..) | F::Ld(..) | F::Sd(..)) => Category::Memory,
+            Vector(V::Le32(..) | V::Se32(..)) => Category::Memory,
+            Float(_) => Category::Float,
+            Vector(_) => Category::Vector,
+            Ecall => Category::Ecall,
+            Ebreak | Fence(..) | FenceI | URet | MRet | Wfi | Illegal(_) => Category::System,
+            CsrRw(..) | CsrRs(..) | CsrRc(..) | CsrRwi(..) | CsrRsi(..) | CsrRci(..) => Category::System,
+            _ => Category::Alu,
+        }
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Category::Alu => "alu",
+            Category::Branch => "branch",
+            Category::Jump => "jump",
+            Category::Memory => "memory",
+            Category::Float => "float",
+            Category::Vector => "vector",
+            Category::Ecall => "ecall",
+            Category::System => "system",
+        };
+        f.pad(name)
+    }
+}
+
+impl Mix {
+    /// Counts a run of the instruction in `slot` of `code`
+    pub(super) fn run(&mut self, slot: usize, code: &[Instruction]) {
+        // The code can grow while running, when another program is loaded
+        if slot >= self.runs.len() {
+            self.runs.resize(code.len().max(slot + 1), 0);
+            self.jumped.resize(code.len().max(slot + 1), 0);
+        }
+        self.runs[slot] += 1;
+
+        if let Some(last) = self.last {
+            // A 4-byte instruction in compressed code is followed by its padding
+            let next = match code.get(last + 1) {
+                Some(next) if next.is_padding() => last + 2,
+                _ => last + 1,
+            };
+            if slot != next {
+                self.jumped[last] += 1;
+            }
+        }
+        self.last = Some(slot);
+    }
+
+    /// The report of the mix of `code`
+    pub fn report<'a>(&'a self, code: &'a [Instruction]) -> Report<'a> {
+        Report { mix: self, code }
+    }
+}
+
+pub struct Report<'a> {
+    mix: &'a Mix,
+    code: &'a [Instruction],
+}
+
+/// The runs by category and by mnemonic, the most common first, and of the branches
+struct Totals {
+    categories: Vec<(Category, u64)>,
+    mnemonics: Vec<(String, u64)>,
+    taken: u64,
+    not_taken: u64,
+}
+
+impl Report<'_> {
+    fn totals(&self) -> Totals {
+        let mut categories: Vec<(Category, u64)> = Vec::new();
+        let mut mnemonics: Vec<(String, u64)> = Vec::new();
+        let (mut taken, mut not_taken) = (0, 0);
+
+        for (slot, &runs) in self.mix.runs.iter().enumerate().filter(|&(_, &runs)| runs > 0) {
+            let instruction = &self.code[slot];
+            let category = Category::of(instruction);
+            match categories.iter_mut().find(|(c, _)| *c == category) {
+                Some((_, total)) => *total += runs,
+                None => categories.push((category, runs)),
+            }
+
+            let text = instruction.to_string();
+            let mnemonic = text.split_whitespace().next().unwrap_or("");
+            match mnemonics.iter_mut().find(|(m, _)| m == mnemonic) {
+                Some((_, total)) => *total += runs,
+                None => mnemonics.push((mnemonic.to_owned(), runs)),
+            }
+
+            if category == Category::Branch {
+                taken += self.mix.jumped[slot];
+                not_taken += runs - self.mix.jumped[slot];
+            }
+        }
+
+        categories.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        mnemonics.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Totals {
+            categories,
+            mnemonics,
+            taken,
+            not_taken,
+        }
+    }
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Totals { categories, mnemonics, taken, not_taken } = self.totals();
+        let total: u64 = self.mix.runs.iter().sum();
+        let percent = |runs: u64| runs as f64 / total.max(1) as f64 * 100.0;
+
+        writeln!(f, "Instruction mix of {} instructions", total)?;
+        for &(category, runs) in &categories {
+            writeln!(f, "  {:<10} {:>14} {:>6.2}%", category, runs, percent(runs))?;
+        }
+        if taken + not_taken > 0 {
+            writeln!(f, "Branches: {} taken, {} not taken", taken, not_taken)?;
+        }
+        writeln!(f, "By mnemonic:")?;
+        for (mnemonic, runs) in &mnemonics {
+            writeln!(f, "  {:<10} {:>14} {:>6.2}%", mnemonic, runs, percent(*runs))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+
+    #[test]
+    fn test_mix() {
+        // 10 passes of a loop that adds up a word, with a branch taken every other pass
+        let code = ".data\nx: .word 3\n.text\nli t0, 10\nli a0, 0
+            loop: lw t1, x\nadd a0, a0, t1\nandi t2, t0, 1\nbeqz t2, skip\naddi a0, a0, 1
+            skip: addi t0, t0, -1\nbnez t0, loop\nli a7, 93\necall";
+        let mut sim = Simulator::new()
+            .instruction_mix(true)
+            .load_str(code)
+            .unwrap();
+        assert_eq!(sim.run(), 35);
+
+        let report = sim.stats.mix.report(&sim.code);
+        let Totals { categories, mnemonics, taken, not_taken } = report.totals();
+        let total: u64 = categories.iter().map(|(_, runs)| runs).sum();
+        assert_eq!(total, sim.stats.instructions);
+        assert_eq!(categories[0].1, 10 * 4 + 5 + 3); // alu
+        assert_eq!(categories[1].1, 20); // branch
+        // `beqz` jumps 5 times and `bnez` 9
+        assert_eq!((taken, not_taken), (14, 6));
+        assert_eq!(mnemonics[0], ("addi".to_owned(), 15));
+        assert!(report.to_string().contains("Branches: 14 taken, 6 not taken"));
+    }
+}
@@ -1,681 +1,2386 @@
-//!
-//! Runs a given RISC-V program instruction by instruction.
-//!
-//! Implemented instructions can be found at [Instructions](./parser/enum.Instruction.html),
-//! and you can find how they're simulated at [Simulator::run](struct.Simulator.html#method.run)
-//!
-
-use std::sync::{Arc, Mutex};
-use std::time;
-
-const DATA_SIZE: usize = 0x0040_0000; // TODO: this, but I think it's about this much
-const MMIO_SIZE: usize = 0x0021_0000;
-const MMIO_START: usize = 0xff00_0000;
-const KBMMIO_CONTROL: usize = 0xff20_0000;
-const KBMMIO_DATA: usize = 0xff20_0004;
-
-use crate::renderer::{FRAME_0, FRAME_1, HEIGHT, WIDTH};
-const VIDEO_START: usize = MMIO_START + FRAME_0;
-const VIDEO_END: usize = MMIO_START + FRAME_1 + WIDTH * HEIGHT;
-
-use crate::parser::{self, Includable, MacroParseable, RISCVParser};
-
-mod into_register;
-use into_register::*;
-
-mod files;
-
-mod util;
-
-use byteorder::{ByteOrder, LittleEndian};
-
-pub struct Memory {
-    pub mmio: Arc<Mutex<Vec<u8>>>,
-    data: Vec<u8>,
-}
-
-impl Memory {
-    pub fn new() -> Self {
-        Self {
-            mmio: Arc::new(Mutex::new(vec![0; MMIO_SIZE])),
-            data: vec![0; DATA_SIZE],
-        }
-    }
-
-    /// Sets N bytes in the video memory, but ignores bytes equal to 0xC7.
-    fn set_with_transparency(&mut self, i: usize, mut x: u32, n: usize) -> bool {
-        if i < VIDEO_START || i >= VIDEO_END {
-            return false;
-        }
-
-        let mut mmio = self.mmio.lock().unwrap();
-        let i = i - MMIO_START;
-
-        for data in &mut mmio[i..i+n] {
-            let byte = x as u8;
-            if byte != 0xC7 {
-                *data = byte;
-            }
-
-            x >>= 8;
-        }
-
-        true
-    }
-
-    pub fn get_with<T, F>(&self, i: usize, read: F) -> T
-    where
-        F: FnOnce(&[u8]) -> T,
-    {
-        if i >= MMIO_START {
-            let mut mmio = self.mmio.lock().unwrap();
-            if i == KBMMIO_DATA {
-                mmio[KBMMIO_CONTROL - MMIO_START] = 0;
-            }
-            read(&mmio[i - MMIO_START..])
-        } else {
-            read(&self.data[i..])
-        }
-    }
-
-    pub fn set_with<T, F, R>(&mut self, i: usize, x: T, write: F) -> R
-    where
-        F: FnOnce(&mut [u8], T) -> R,
-    {
-        if i >= MMIO_START {
-            let mut mmio = self.mmio.lock().unwrap();
-            write(&mut mmio[i - MMIO_START..], x)
-        } else {
-            write(&mut self.data[i..], x)
-        }
-    }
-
-    pub fn get_byte(&self, i: usize) -> u8 {
-        self.get_with(i, |v| v[0])
-    }
-
-    pub fn set_byte(&mut self, i: usize, x: u8) {
-        if self.set_with_transparency(i, x as u32, 1) {
-            return;
-        }
-        self.set_with(i, x, |v, x| v[0] = x)
-    }
-
-    pub fn get_half(&self, i: usize) -> u16 {
-        self.get_with(i, LittleEndian::read_u16)
-    }
-
-    pub fn set_half(&mut self, i: usize, x: u16) {
-        if self.set_with_transparency(i, x as u32, 2) {
-            return;
-        }
-        self.set_with(i, x, LittleEndian::write_u16)
-    }
-
-    pub fn get_word(&self, i: usize) -> u32 {
-        self.get_with(i, LittleEndian::read_u32)
-    }
-
-    pub fn set_word(&mut self, i: usize, x: u32) {
-        if self.set_with_transparency(i, x, 4) {
-            return;
-        }
-        self.set_with(i, x, LittleEndian::write_u32)
-    }
-
-    pub fn get_float(&self, i: usize) -> f32 {
-        self.get_with(i, LittleEndian::read_f32)
-    }
-
-    pub fn set_float(&mut self, i: usize, x: f32) {
-        self.set_with(i, x, LittleEndian::write_f32)
-    }
-}
-
-/// Returned by the [ecall](struct.Simulator.html#method.ecall) procedure
-enum EcallSignal {
-    Nothing,
-    Exit,
-    Continue,
-}
-
-/// Simulates a RISC-V CPU. Generally initialized by calling [load_from_file](struct.Simulator.html#method.load_from_file)
-/// and ran by calling [run](struct.Simulator.html#method.run).
-pub struct Simulator {
-    registers: [u32; 32],
-    floats: [f32; 32],
-    status: Vec<u32>, // I'm not sure myself how many status register I'll use
-    pc: usize,
-    started_at: time::Instant,
-
-    open_files: files::FileHolder,
-
-    pub memory: Memory,
-    pub code: Vec<parser::Instruction>,
-}
-
-impl Simulator {
-    pub fn new() -> Self {
-        Self {
-            registers: [0; 32],
-            floats: [0.0; 32],
-            status: Vec::new(),
-            pc: 0,
-            started_at: time::Instant::now(), // Will be set again in run()
-            open_files: files::FileHolder::new(),
-            memory: Memory::new(),
-            code: Vec::new(),
-        }
-    }
-
-    fn get_reg<T: FromRegister>(&self, i: u8) -> T {
-        FromRegister::from(self.registers[i as usize])
-    }
-
-    fn set_reg<T: IntoRegister>(&mut self, i: u8, x: T) {
-        // This could be made branchless by setting reg[i] = i == 0 ? 0 : x, but I'm not sure it's worth it
-        if i != 0 {
-            self.registers[i as usize] = x.into();
-        }
-    }
-
-    fn get_status(&self, i: u8) -> u32 {
-        if i == parser::register_names::TIME_INDEX {
-            self.started_at.elapsed().as_millis() as u32
-        } else {
-            self.status[i as usize]
-        }
-    }
-
-    pub fn load_from_file(mut self, path: String) -> Result<Self, parser::Error> {
-        // TODO: some of this logic is duplicated from the Includer, try to dedup?
-        let pathbuf = std::path::PathBuf::from(&path);
-        let error = format!("Can't open file: <{:?}>", pathbuf.to_str());
-        let parser::Parsed { code, data } = parser::file_lines(&path)
-            .expect(&error)
-            .parse_includes(pathbuf)
-            .parse_macros()
-            .parse_riscv(DATA_SIZE)?;
-
-        self.code = code;
-        self.memory.data = data;
-
-        Ok(self)
-    }
-
-    fn init(&mut self) {
-        // Create necessary status registers
-        self.status
-            .resize(parser::register_names::status().len(), 0);
-
-        // Set stack pointer
-        self.set_reg(2, self.memory.data.len() as u32 - 4);
-
-        // Set global pointer
-        self.set_reg(3, 0x10008000);
-
-        self.started_at = time::Instant::now();
-        self.status[parser::register_names::MISA_INDEX as usize] = 0x40001128;
-    }
-
-    pub fn run(&mut self) {
-        use parser::FloatInstruction as F;
-        use parser::Instruction::*;
-
-        let to_1 = |b| if b { 1 } else { 0 };
-
-        macro_rules! branch {
-            ($cond:expr, $pc:expr, $label:expr) => {
-                if $cond {
-                    $pc = $label;
-                    continue;
-                }
-            };
-        }
-
-        self.init();
-
-        loop {
-            match self.code[self.pc / 4] {
-                // Type R
-                Add(rd, rs1, rs2) => {
-                    self.set_reg(rd, self.get_reg::<i32>(rs1) + self.get_reg::<i32>(rs2))
-                }
-                Sub(rd, rs1, rs2) => {
-                    self.set_reg(rd, self.get_reg::<i32>(rs1) - self.get_reg::<i32>(rs2))
-                }
-                Sll(rd, rs1, rs2) => self.set_reg(
-                    rd,
-                    self.get_reg::<u32>(rs1) << (self.get_reg::<i32>(rs2) & 0x1f),
-                ),
-                Slt(rd, rs1, rs2) => self.set_reg(
-                    rd,
-                    to_1(self.get_reg::<i32>(rs1) < self.get_reg::<i32>(rs2)),
-                ),
-                Sltu(rd, rs1, rs2) => self.set_reg(
-                    rd,
-                    to_1(self.get_reg::<u32>(rs1) < self.get_reg::<u32>(rs2)),
-                ),
-                Xor(rd, rs1, rs2) => {
-                    self.set_reg(rd, self.get_reg::<u32>(rs1) ^ self.get_reg::<u32>(rs2))
-                }
-                Srl(rd, rs1, rs2) => self.set_reg(
-                    rd,
-                    self.get_reg::<u32>(rs1) >> (self.get_reg::<i32>(rs2) & 0x1f),
-                ),
-                Sra(rd, rs1, rs2) => self.set_reg(
-                    rd,
-                    self.get_reg::<i32>(rs1) >> (self.get_reg::<i32>(rs2) & 0x1f),
-                ),
-                Or(rd, rs1, rs2) => {
-                    self.set_reg(rd, self.get_reg::<u32>(rs1) | self.get_reg::<u32>(rs2))
-                }
-                And(rd, rs1, rs2) => {
-                    self.set_reg(rd, self.get_reg::<u32>(rs1) & self.get_reg::<u32>(rs2))
-                }
-                Mul(rd, rs1, rs2) => {
-                    self.set_reg(rd, self.get_reg::<i32>(rs1) * self.get_reg::<i32>(rs2))
-                }
-                Div(rd, rs1, rs2) => {
-                    self.set_reg(rd, self.get_reg::<i32>(rs1) / self.get_reg::<i32>(rs2))
-                }
-                Divu(rd, rs1, rs2) => {
-                    self.set_reg(rd, self.get_reg::<u32>(rs1) / self.get_reg::<u32>(rs2))
-                }
-                Rem(rd, rs1, rs2) => {
-                    self.set_reg(rd, self.get_reg::<i32>(rs1) % self.get_reg::<i32>(rs2))
-                }
-                Remu(rd, rs1, rs2) => {
-                    self.set_reg(rd, self.get_reg::<u32>(rs1) % self.get_reg::<u32>(rs2))
-                }
-
-                // Type I
-                Ecall => {
-                    use EcallSignal::*;
-                    match self.ecall() {
-                        Exit => {
-                            return;
-                        }
-                        Continue => {
-                            continue;
-                        }
-                        Nothing => {}
-                    }
-                }
-                Addi(rd, rs1, imm) => self.set_reg(rd, self.get_reg::<i32>(rs1) + (imm as i32)),
-                Slli(rd, rs1, imm) => self.set_reg(rd, self.get_reg::<i32>(rs1) << (imm & 0x1f)),
-                Slti(rd, rs1, imm) => {
-                    self.set_reg(rd, to_1(self.get_reg::<i32>(rs1) < (imm as i32)))
-                }
-                Sltiu(rd, rs1, imm) => self.set_reg(rd, to_1(self.get_reg::<u32>(rs1) < imm)),
-                Xori(rd, rs1, imm) => self.set_reg(rd, self.get_reg::<u32>(rs1) ^ imm),
-                Srli(rd, rs1, imm) => self.set_reg(rd, self.get_reg::<u32>(rs1) >> (imm & 0x1f)),
-                Srai(rd, rs1, imm) => self.set_reg(rd, self.get_reg::<i32>(rs1) >> (imm & 0x1f)),
-                Ori(rd, rs1, imm) => self.set_reg(rd, self.get_reg::<u32>(rs1) | imm),
-                Andi(rd, rs1, imm) => self.set_reg(rd, self.get_reg::<u32>(rs1) & imm),
-
-                // Type I, loads from memory
-                Lb(rd, imm, rs1) => self.set_reg(
-                    rd,
-                    self.memory
-                        .get_byte((self.get_reg::<u32>(rs1).wrapping_add(imm)) as usize)
-                        as u32,
-                ),
-                Lh(rd, imm, rs1) => self.set_reg(
-                    rd,
-                    self.memory
-                        .get_half((self.get_reg::<u32>(rs1).wrapping_add(imm)) as usize)
-                        as u32,
-                ),
-                Lw(rd, imm, rs1) => self.set_reg(
-                    rd,
-                    self.memory
-                        .get_word((self.get_reg::<u32>(rs1).wrapping_add(imm)) as usize)
-                        as u32,
-                ),
-                Lbu(rd, imm, rs1) => self.set_reg(
-                    rd,
-                    self.memory
-                        .get_byte((self.get_reg::<u32>(rs1).wrapping_add(imm)) as usize)
-                        as u8 as u32,
-                ),
-                Lhu(rd, imm, rs1) => self.set_reg(
-                    rd,
-                    self.memory
-                        .get_half((self.get_reg::<u32>(rs1).wrapping_add(imm)) as usize)
-                        as u16 as u32,
-                ),
-                Float(F::Lw(rd, imm, rs1)) => {
-                    let rd = rd as usize;
-                    let x = self
-                        .memory
-                        .get_float(self.get_reg::<u32>(rs1).wrapping_add(imm) as usize);
-                    self.floats[rd] = x;
-                }
-
-                // Type S
-                Sb(rs2, imm, rs1) => self.memory.set_byte(
-                    (self.get_reg::<u32>(rs1).wrapping_add(imm)) as usize,
-                    self.get_reg::<u8>(rs2),
-                ),
-                Sh(rs2, imm, rs1) => self.memory.set_half(
-                    (self.get_reg::<u32>(rs1).wrapping_add(imm)) as usize,
-                    self.get_reg::<u16>(rs2),
-                ),
-                Sw(rs2, imm, rs1) => self.memory.set_word(
-                    (self.get_reg::<u32>(rs1).wrapping_add(imm)) as usize,
-                    self.get_reg::<u32>(rs2),
-                ),
-                Float(F::Sw(rs2, imm, rs1)) => {
-                    let x = self.floats[rs2 as usize];
-                    self.memory
-                        .set_float(self.get_reg::<u32>(rs1).wrapping_add(imm) as usize, x);
-                }
-
-                // Type SB + jumps
-                Beq(rs1, rs2, label) => branch!(
-                    self.get_reg::<i32>(rs1) == self.get_reg::<i32>(rs2),
-                    self.pc,
-                    label
-                ),
-                Bne(rs1, rs2, label) => branch!(
-                    self.get_reg::<i32>(rs1) != self.get_reg::<i32>(rs2),
-                    self.pc,
-                    label
-                ),
-                Blt(rs1, rs2, label) => branch!(
-                    self.get_reg::<i32>(rs1) < self.get_reg::<i32>(rs2),
-                    self.pc,
-                    label
-                ),
-                Bge(rs1, rs2, label) => branch!(
-                    self.get_reg::<i32>(rs1) >= self.get_reg::<i32>(rs2),
-                    self.pc,
-                    label
-                ),
-                Bltu(rs1, rs2, label) => branch!(
-                    self.get_reg::<u32>(rs1) < self.get_reg::<u32>(rs2),
-                    self.pc,
-                    label
-                ),
-                Bgeu(rs1, rs2, label) => branch!(
-                    self.get_reg::<u32>(rs1) >= self.get_reg::<u32>(rs2),
-                    self.pc,
-                    label
-                ),
-                Jalr(rd, rs1, imm) => {
-                    // This produces a weird result for `jalr s0 s0 0`. s0 is set to pc+4 before the jump occurs
-                    // so it works as a nop. Maybe this is correct, maybe it's not, but I'll copy the behavior seen in
-                    // RARS to be consistent.
-                    self.set_reg(rd, (self.pc + 4) as u32);
-                    self.pc = (self.get_reg::<i32>(rs1) + (imm as i32)) as usize & !1;
-                    continue;
-                }
-                Jal(rd, label) => {
-                    self.set_reg(rd, (self.pc + 4) as u32);
-                    self.pc = label;
-                    continue;
-                }
-
-                // CSR
-                CsrRw(rd, fcsr, rs1) => {
-                    self.set_reg(rd, self.get_status(fcsr));
-                    self.status[fcsr as usize] = self.get_reg::<u32>(rs1);
-                }
-                CsrRwi(rd, fcsr, imm) => {
-                    self.set_reg(rd, self.get_status(fcsr));
-                    self.status[fcsr as usize] = imm;
-                }
-                CsrRs(rd, fcsr, rs1) => {
-                    self.set_reg(rd, self.get_status(fcsr));
-                    self.status[fcsr as usize] |= self.get_reg::<u32>(rs1);
-                }
-                CsrRsi(rd, fcsr, imm) => {
-                    self.set_reg(rd, self.get_status(fcsr));
-                    self.status[fcsr as usize] |= imm;
-                }
-                CsrRc(rd, fcsr, rs1) => {
-                    self.set_reg(rd, self.get_status(fcsr));
-                    self.status[fcsr as usize] &= !self.get_reg::<u32>(rs1);
-                }
-                CsrRci(rd, fcsr, imm) => {
-                    self.set_reg(rd, self.get_status(fcsr));
-                    self.status[fcsr as usize] &= !imm;
-                }
-
-                // Floating point
-                Float(F::Add(rd, rs1, rs2)) => {
-                    let (rd, rs1, rs2) = (rd as usize, rs1 as usize, rs2 as usize);
-                    self.floats[rd] = self.floats[rs1] + self.floats[rs2];
-                }
-                Float(F::Sub(rd, rs1, rs2)) => {
-                    let (rd, rs1, rs2) = (rd as usize, rs1 as usize, rs2 as usize);
-                    self.floats[rd] = self.floats[rs1] - self.floats[rs2];
-                }
-                Float(F::Mul(rd, rs1, rs2)) => {
-                    let (rd, rs1, rs2) = (rd as usize, rs1 as usize, rs2 as usize);
-                    self.floats[rd] = self.floats[rs1] * self.floats[rs2];
-                }
-                Float(F::Div(rd, rs1, rs2)) => {
-                    let (rd, rs1, rs2) = (rd as usize, rs1 as usize, rs2 as usize);
-                    self.floats[rd] = self.floats[rs1] / self.floats[rs2];
-                }
-                Float(F::Equ(rd, rs1, rs2)) => {
-                    let (rs1, rs2) = (rs1 as usize, rs2 as usize);
-                    self.set_reg(rd, to_1(self.floats[rs1] == self.floats[rs2]));
-                }
-                Float(F::Le(rd, rs1, rs2)) => {
-                    let (rs1, rs2) = (rs1 as usize, rs2 as usize);
-                    self.set_reg(rd, to_1(self.floats[rs1] <= self.floats[rs2]));
-                }
-                Float(F::Lt(rd, rs1, rs2)) => {
-                    let (rs1, rs2) = (rs1 as usize, rs2 as usize);
-                    self.set_reg(rd, to_1(self.floats[rs1] < self.floats[rs2]));
-                }
-                Float(F::Max(rd, rs1, rs2)) => {
-                    let (rd, rs1, rs2) = (rd as usize, rs1 as usize, rs2 as usize);
-                    self.floats[rd] = self.floats[rs1].max(self.floats[rs2]);
-                }
-                Float(F::Min(rd, rs1, rs2)) => {
-                    let (rd, rs1, rs2) = (rd as usize, rs1 as usize, rs2 as usize);
-                    self.floats[rd] = self.floats[rs1].min(self.floats[rs2]);
-                }
-                Float(F::SgnjS(rd, rs1, rs2)) => {
-                    let (rd, rs1, rs2) = (rd as usize, rs1 as usize, rs2 as usize);
-                    self.floats[rd] = self.floats[rs1].copysign(self.floats[rs2]);
-                }
-                Float(F::SgnjNS(rd, rs1, rs2)) => {
-                    let (rd, rs1, rs2) = (rd as usize, rs1 as usize, rs2 as usize);
-                    self.floats[rd] = self.floats[rs1].copysign(-self.floats[rs2]);
-                }
-                Float(F::SgnjXS(rd, rs1, rs2)) => {
-                    let (rd, rs1, rs2) = (rd as usize, rs1 as usize, rs2 as usize);
-                    let (a, b) = (self.floats[rs1], self.floats[rs2]);
-
-                    // I'm pretty sure this is correct (for most architectures anyway)
-                    self.floats[rd] = f32::from_bits(a.to_bits() ^ (b.to_bits() & (1 << 31)));
-                }
-
-                // I didn't even know this existed before this project
-                Float(F::Class(rd, rs1)) => {
-                    let rs1 = rs1 as usize;
-                    self.set_reg(rd, util::class_mask(self.floats[rs1]));
-                }
-
-                Float(F::CvtSW(rd, rs1)) => {
-                    let rd = rd as usize;
-                    self.floats[rd] = self.get_reg::<i32>(rs1) as f32;
-                }
-                Float(F::CvtSWu(rd, rs1)) => {
-                    let rd = rd as usize;
-                    self.floats[rd] = self.get_reg::<u32>(rs1) as f32;
-                }
-                Float(F::CvtWS(rd, rs1)) => {
-                    let rs1 = rs1 as usize;
-                    self.set_reg(rd, self.floats[rs1] as i32);
-                }
-                Float(F::CvtWuS(rd, rs1)) => {
-                    let rs1 = rs1 as usize;
-                    self.set_reg(rd, self.floats[rs1] as u32);
-                }
-
-                Float(F::MvSX(rd, rs1)) => {
-                    let rd = rd as usize;
-                    self.floats[rd] = f32::from_bits(self.get_reg::<u32>(rs1));
-                }
-                Float(F::MvXS(rd, rs1)) => {
-                    let rs1 = rs1 as usize;
-                    self.set_reg(rd, self.floats[rs1].to_bits());
-                }
-
-                Float(F::Sqrt(rd, rs1)) => {
-                    let (rd, rs1) = (rd as usize, rs1 as usize);
-                    self.floats[rd] = self.floats[rs1].sqrt();
-                }
-
-                // Pseudoinstructions
-                Li(rd, imm) => self.set_reg(rd, imm),
-                Mv(rd, rs1) => self.registers[rd as usize] = self.registers[rs1 as usize],
-                Ret => {
-                    self.pc = self.registers[1] as usize;
-                    continue;
-                }
-                URet => {
-                    use crate::parser::register_names::UEPC_INDEX;
-                    self.pc = self.status[UEPC_INDEX as usize] as usize;
-                    continue;
-                }
-            }
-
-            self.pc += 4;
-        }
-    }
-
-    fn ecall(&mut self) -> EcallSignal {
-        use crate::parser::register_names::*;
-        use rand::{thread_rng, Rng};
-
-        let a7 = self.get_reg::<u32>(17);
-
-        if files::handle_ecall(
-            a7,
-            &mut self.open_files,
-            &mut self.registers,
-            &mut self.memory,
-        ) {
-            return EcallSignal::Nothing;
-        }
-
-        match a7 {
-            10 => return EcallSignal::Exit,
-            110 => loop {
-                std::thread::sleep(time::Duration::from_millis(500));
-            },
-            1 => {
-                // print int
-                print!("{}", self.get_reg::<i32>(10));
-            }
-            4 => {
-                // print string
-                let start = self.get_reg::<u32>(10) as usize; // a0
-                (start..)
-                    .map(|i| self.memory.get_byte(i) as char)
-                    .take_while(|&c| c != '\0')
-                    .for_each(|c| print!("{}", c));
-            }
-            5 => {
-                // read int
-                let mut buf = String::new();
-                std::io::stdin().read_line(&mut buf).unwrap();
-                self.set_reg(10, buf.trim().parse::<i32>().unwrap());
-            }
-            6 => {
-                // print float
-                print!("{}", self.floats[10]);
-            }
-            11 => {
-                // print char
-                print!("{}", self.get_reg::<u32>(10) as u8 as char);
-            }
-
-            30 => {
-                // get time
-                let epoch = time::SystemTime::UNIX_EPOCH;
-                let duration = time::SystemTime::now().duration_since(epoch).unwrap();
-                let ms = duration.as_millis() as u64;
-                self.set_reg(10, ms as u32);
-                self.set_reg(11, (ms >> 32) as u32);
-            }
-
-            31 | 33 => {
-                // midi stuff, but nops for now
-            }
-
-            32 => {
-                // sleep ms
-                let t = self.get_reg::<u32>(10);
-                std::thread::sleep(time::Duration::from_millis(t as u64));
-            }
-
-            34 => {
-                // print hex int
-                print!("{:#X}", self.get_reg::<u32>(10));
-            }
-
-            36 => {
-                // print unsigned int
-                print!("{}", self.get_reg::<u32>(10));
-            }
-
-            // RNG stuff
-            40 => {
-                // TODO: seed the RNG
-            }
-            41 => {
-                // rand int
-                self.set_reg(10, thread_rng().gen::<i32>());
-            }
-            42 => {
-                // rand int in [0, a1)
-                let upper = self.get_reg::<u32>(11);
-                self.set_reg(10, thread_rng().gen_range::<u32, _, _>(0, upper));
-            }
-            43 => {
-                // rand float in [0, 1)
-                self.floats[10] = thread_rng().gen_range(0f32, 1f32);
-            }
-
-            48 | 148 => {
-                // clear screen
-                let color = self.get_reg::<u8>(10); // a0
-                let frame_select = self.get_reg::<u32>(11); // a1
-
-                let mut mmio = self.memory.mmio.lock().unwrap();
-                let frame = if frame_select == 0 { FRAME_0 } else { FRAME_1 };
-                for x in &mut mmio[frame..frame + WIDTH * HEIGHT] {
-                    *x = color;
-                }
-            }
-
-            // These two should only be here temporarily for convenience
-            0xff00 => {
-                self.floats[10] = self.floats[10].sin();
-            }
-            0xff01 => {
-                self.floats[10] = self.floats[10].cos();
-            }
-
-            // Does the user want to handle this ecall?
-            _x if self.status[USTATUS_INDEX as usize] & 1 == 1 => {
-                self.status[UCAUSE_INDEX as usize] = 8; // ecall exception
-                self.status[UEPC_INDEX as usize] = self.pc as u32; // set uret location
-                self.pc = self.status[UTVEC_INDEX as usize] as usize; // jump to utvec
-                return EcallSignal::Continue;
-            }
-
-            x => unimplemented!("Ecall {} is not implemented", x),
-        }
-
-        EcallSignal::Nothing
-    }
-}
+//!
+//! Runs a given RISC-V program instruction by instruction.
+//!
+//! Implemented instructions can be found at [Instructions](./parser/enum.Instruction.html),
+//! and you can find how they're simulated at [Simulator::run](struct.Simulator.html#method.run)
+//!
+
+use fnv::FnvHashSet;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time;
+
+const DATA_SIZE: usize = 0x0040_0000; // TODO: this, but I think it's about this much
+const MMIO_SIZE: usize = 0x0021_0000;
+const MMIO_START: usize = 0xff00_0000;
+const KBMMIO_CONTROL: usize = 0xff20_0000;
+const KBMMIO_DATA: usize = 0xff20_0004;
+/// Where the code, the data, the first `sp` and the keyboard and display MMIO are in RARS,
+/// see [rars_layout](struct.Simulator.html#method.rars_layout)
+const RARS_TEXT_BASE: usize = 0x0040_0000;
+const RARS_DATA_BASE: usize = 0x1001_0000;
+const RARS_HEAP_BASE: usize = 0x1004_0000;
+const RARS_STACK_TOP: usize = 0x7fff_effc;
+const RARS_MMIO: usize = 0xffff_0000;
+/// VLEN, the bits in each vector register, unless [vlen](struct.Simulator.html#method.vlen) says otherwise
+const DEFAULT_VLEN: usize = 128;
+
+use crate::renderer::{FRAME_0, FRAME_1, HEIGHT, WIDTH};
+const VIDEO_START: usize = MMIO_START + FRAME_0;
+const VIDEO_END: usize = MMIO_START + FRAME_1 + WIDTH * HEIGHT;
+
+use crate::parser::{self, Includable, MacroParseable, RISCVParser};
+
+mod into_register;
+use into_register::*;
+
+mod memory;
+pub use memory::{Device, Memory, Segment};
+
+mod bench;
+
+mod console;
+
+mod dap;
+
+mod debugger;
+pub use debugger::Location;
+
+mod dispatch;
+
+mod files;
+
+mod fpu;
+
+mod gdb;
+
+mod heap;
+
+mod history;
+
+mod limits;
+
+mod mix;
+
+mod os;
+
+mod privilege;
+
+mod profile;
+
+mod regions;
+
+mod replay;
+
+mod self_modifying;
+
+mod stack;
+
+mod trace;
+pub use trace::TraceFilter;
+
+mod tui;
+
+mod shared_memory;
+
+mod snapshot;
+pub use shared_memory::SharedMemoryConfig;
+
+mod vector;
+
+pub mod difftest;
+
+mod util;
+
+/// Returned by the [ecall](struct.Simulator.html#method.ecall) procedure
+enum EcallSignal {
+    Nothing,
+    /// Exit with the given status code
+    Exit(i32),
+    Continue,
+}
+
+/// What to do when the program runs into an instruction we don't know. These only exist when the
+/// simulator is [permissive](struct.Simulator.html#method.permissive), otherwise the parser
+/// refuses the program right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnIllegal {
+    /// Raise an illegal instruction exception, so the program's handler at `utvec` can deal with it
+    Trap,
+    /// Warn about it and move on to the next instruction
+    Skip,
+}
+
+impl FromStr for OnIllegal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trap" => Ok(OnIllegal::Trap),
+            "skip" => Ok(OnIllegal::Skip),
+            _ => Err(format!("expected `trap` or `skip`, got `{}`", s)),
+        }
+    }
+}
+
+/// Things we count while running the program
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// How many times we skipped an unknown instruction
+    pub illegal_skipped: usize,
+    /// Instructions executed so far
+    pub instructions: u64,
+    /// Parts of the program it asked us to measure, see [regions](regions/index.html)
+    pub regions: regions::Regions,
+    /// The time spent in ecalls, see [bench](bench/index.html)
+    pub bench: bench::Bench,
+    /// How many times each instruction ran, see [mix](mix/index.html)
+    pub mix: mix::Mix,
+    /// How many instructions ran on each line and in each function, see [profile](profile/index.html)
+    pub profile: profile::Profile,
+}
+
+/// Simulates a RISC-V CPU. Generally initialized by calling [load_from_files](struct.Simulator.html#method.load_from_files)
+/// and ran by calling [run](struct.Simulator.html#method.run).
+pub struct Simulator {
+    /// 64 bits wide for RV64. In RV32 they always hold a sign extended 32-bit value
+    registers: [u64; 32],
+    /// NaN-boxed, see [FloatRegister](into_register/trait.FloatRegister.html)
+    floats: [u64; 32],
+    /// The vector registers, with `vl` and `vtype`, see [vlen](#method.vlen)
+    vectors: vector::Vectors,
+    status: Vec<u32>, // I'm not sure myself how many status register I'll use
+    pc: usize,
+    started_at: time::Instant,
+    /// The address reserved by the last `lr.w`, which `sc.w` checks and clears
+    reservation: Option<usize>,
+    /// Whether we're in user mode instead of machine mode, see [privilege](privilege/index.html)
+    user_mode: bool,
+
+    console: console::Console,
+    open_files: files::FileHolder,
+    processes: os::Processes,
+
+    /// `None` if we don't accept unknown instructions at all
+    on_illegal: Option<OnIllegal>,
+    /// Names defined before parsing the code, like `.eqv`s, see [defines](#method.defines)
+    defines: Vec<(String, String)>,
+    /// Fail to load programs with warnings, see [werror](#method.werror)
+    werror: bool,
+    /// Warn about unused labels, see [warn_unused](#method.warn_unused)
+    warn_unused: bool,
+    /// Only accept code RARS would accept, see [strict](#method.strict)
+    strict: bool,
+    /// Simulate RV64 instead of RV32, see [rv64](#method.rv64)
+    rv64: bool,
+    /// Only have the registers x0 to x15, see [rv32e](#method.rv32e)
+    rv32e: bool,
+    /// Accept compressed instructions, whose code is made of 2-byte slots, see [rvc](#method.rvc)
+    rvc: bool,
+    /// The address of the first instruction, see [text_base](#method.text_base)
+    text_base: usize,
+    /// Stop at jumps to misaligned addresses, see [check_jumps](#method.check_jumps)
+    check_jumps: bool,
+    /// Stop at misaligned loads and stores, see [strict_alignment](#method.strict_alignment)
+    strict_alignment: bool,
+    /// Put everything where RARS does, see [rars_layout](#method.rars_layout)
+    rars_layout: bool,
+    /// The first `sp`, see [stack_top](#method.stack_top)
+    stack_top: Option<usize>,
+    /// How many bytes the stack has, see [stack_size](#method.stack_size)
+    stack_size: Option<usize>,
+    /// The stack of the current program, see [stack](stack/index.html)
+    stack: std::ops::Range<usize>,
+    /// Keep the code in the memory, read-only, see [readable_code](#method.readable_code)
+    readable_code: bool,
+    /// Keep the code in the memory, see [self_modifying](#method.self_modifying)
+    self_modifying: bool,
+    /// The CSRs, to decode the code that was written to, see [self_modifying](#method.self_modifying)
+    csr_names: parser::register_names::RegMap,
+    /// The label to start at, see [entry](#method.entry)
+    entry: Option<String>,
+    /// Time the ecalls, see [bench](#method.bench)
+    bench: bool,
+    /// Count the runs of each instruction, see [instruction_mix](#method.instruction_mix)
+    instruction_mix: bool,
+    /// Count the instructions of each line and function, see [profile](#method.profile)
+    profile: bool,
+    /// Where to write each instruction that runs, see [trace](#method.trace)
+    trace: Option<trace::Trace>,
+    /// The breakpoints and the commands of `--debug`, see [debugger](debugger/index.html)
+    debugger: debugger::Debugger,
+    /// Debug in the terminal, see [tui](#method.tui)
+    tui: bool,
+    /// The terminal once it's taken over, see [tui](tui/index.html)
+    terminal: Option<tui::Tui>,
+    /// The connection to GDB, see [gdb](gdb/index.html)
+    gdb: Option<gdb::Gdb>,
+    /// The connection to an editor, see [dap](dap/index.html)
+    dap: Option<dap::Dap>,
+    /// What the last instructions changed, to go back over them, see [history](history/index.html)
+    history: history::History,
+    /// Where and when to save snapshots, see [snapshot](snapshot/index.html)
+    saving: snapshot::Saving,
+    /// The snapshot to start from, see [restore](#method.restore)
+    snapshot: Option<snapshot::Snapshot>,
+    /// The input being recorded or replayed, see [replay](replay/index.html)
+    replay: Option<replay::Replay>,
+    /// How long the program can run, see [limits](limits/index.html)
+    limits: limits::Limits,
+    /// Something has to be done before each instruction, see [before_instruction](#method.before_instruction)
+    hooks: bool,
+    /// Positions of the unknown instructions we already warned about
+    warned_illegal: FnvHashSet<usize>,
+    pub stats: Stats,
+
+    pub memory: Memory,
+    pub code: Vec<parser::Instruction>,
+    /// `code`, compiled for the interpreter, see [dispatch](dispatch/index.html)
+    ops: Vec<dispatch::Op>,
+    /// Where each instruction came from, see [Parsed](../parser/struct.Parsed.html)
+    pub code_pos: Vec<Option<Arc<parser::Pos>>>,
+    /// The labels of the program
+    pub symbols: Vec<parser::Symbol>,
+    /// What looked wrong in the code, but still assembled
+    pub warnings: Vec<parser::Error>,
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self {
+            registers: [0; 32],
+            floats: [0f32.into_float_reg(); 32],
+            vectors: vector::Vectors::new(DEFAULT_VLEN),
+            status: Vec::new(),
+            pc: 0,
+            started_at: time::Instant::now(), // Will be set again in run()
+            reservation: None,
+            user_mode: false,
+            console: console::Console::default(),
+            open_files: files::FileHolder::new(),
+            processes: os::Processes::new(),
+            on_illegal: None,
+            defines: Vec::new(),
+            werror: false,
+            warn_unused: false,
+            strict: false,
+            rv64: false,
+            rv32e: false,
+            rvc: false,
+            text_base: 0,
+            check_jumps: false,
+            strict_alignment: false,
+            rars_layout: false,
+            stack_top: None,
+            stack_size: None,
+            stack: 0..0,
+            readable_code: false,
+            self_modifying: false,
+            csr_names: parser::register_names::RegMap::default(),
+            entry: None,
+            bench: false,
+            instruction_mix: false,
+            profile: false,
+            trace: None,
+            debugger: debugger::Debugger::default(),
+            tui: false,
+            terminal: None,
+            gdb: None,
+            dap: None,
+            history: history::History::default(),
+            saving: snapshot::Saving::default(),
+            snapshot: None,
+            replay: None,
+            limits: limits::Limits::default(),
+            hooks: false,
+            warned_illegal: FnvHashSet::default(),
+            stats: Stats::default(),
+            memory: Memory::new(),
+            code: Vec::new(),
+            ops: Vec::new(),
+            code_pos: Vec::new(),
+            symbols: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    fn get_reg<T: FromRegister>(&self, i: u8) -> T {
+        FromRegister::from(self.registers[i as usize])
+    }
+
+    fn set_reg<T: IntoRegister>(&mut self, i: u8, x: T) {
+        // This could be made branchless by setting reg[i] = i == 0 ? 0 : x, but I'm not sure it's worth it
+        if i != 0 {
+            self.registers[i as usize] = x.into();
+        }
+    }
+
+    fn get_float<T: FloatRegister>(&self, i: u8) -> T {
+        FloatRegister::from_float_reg(self.floats[i as usize])
+    }
+
+    fn set_float<T: FloatRegister>(&mut self, i: u8, x: T) {
+        self.floats[i as usize] = x.into_float_reg();
+    }
+
+    fn get_status(&self, i: u8) -> u32 {
+        use parser::register_names::*;
+
+        // Every instruction takes a cycle, and the one reading the counter hasn't retired yet
+        let retired = self.stats.instructions.saturating_sub(1);
+        let time = self.started_at.elapsed().as_millis() as u64;
+
+        // `fflags` and `frm` are just parts of `fcsr`
+        let fcsr = self.status[FCSR_INDEX as usize];
+        match i {
+            TIME_INDEX => time as u32,
+            TIMEH_INDEX => (time >> 32) as u32,
+            INSTRET_INDEX | CYCLE_INDEX => retired as u32,
+            INSTRETH_INDEX | CYCLEH_INDEX => (retired >> 32) as u32,
+            FFLAGS_INDEX => fcsr & 0x1f,
+            FRM_INDEX => (fcsr >> 5) & 7,
+            VL_INDEX => self.vectors.vl,
+            VTYPE_INDEX => self.vectors.vtype,
+            VLENB_INDEX => self.vectors.vlenb(),
+            _ => self.status[i as usize],
+        }
+    }
+
+    /// Reads a CSR for the program. The clock and the counters are input, like the time ecall, so a
+    /// [replay](replay/index.html) gives the program the ones it got when it was recorded.
+    fn read_status(&mut self, i: u8) -> u32 {
+        use parser::register_names::*;
+
+        match i {
+            TIME_INDEX | TIMEH_INDEX | INSTRET_INDEX | INSTRETH_INDEX | CYCLE_INDEX | CYCLEH_INDEX => {
+                self.input("csr_time", |sim| sim.get_status(i))
+            }
+            _ => self.get_status(i),
+        }
+    }
+
+    /// What a CSR read writes to the register. `misa` keeps MXL in its top 2 bits, which are the
+    /// bits 63:62 of the register in RV64, and every other CSR is sign-extended like a `lw`.
+    fn status_to_reg(&self, i: u8, x: u32) -> u64 {
+        if self.rv64 && i == parser::register_names::MISA_INDEX {
+            (x as u64 & 0x3fff_ffff) | (x as u64 >> 30) << 62
+        } else {
+            x as i32 as u64
+        }
+    }
+
+    fn set_status(&mut self, i: u8, x: u32) {
+        use parser::register_names::*;
+
+        let fcsr = &mut self.status[FCSR_INDEX as usize];
+        match i {
+            FFLAGS_INDEX => *fcsr = (*fcsr & !0x1f) | (x & 0x1f),
+            FRM_INDEX => *fcsr = (*fcsr & !0xe0) | (x & 7) << 5,
+            FCSR_INDEX => *fcsr = x & 0xff,
+            // The extensions can't be turned off, so writes to `misa` are ignored
+            MISA_INDEX => {}
+            MSTATUS_INDEX => self.set_mstatus(x),
+            // Instructions are at least 2 bytes apart, so the lowest bit is always 0
+            MEPC_INDEX => self.status[i as usize] = x & !1,
+            _ => self.status[i as usize] = x,
+        }
+    }
+
+    /// The rounding mode of a float instruction, which can say to use the one in `frm`
+    fn rounding(&self, rm: u8) -> fpu::Rounding {
+        use parser::register_names::FRM_INDEX;
+
+        let rm = match rm {
+            parser::DYNAMIC_ROUNDING => self.get_status(FRM_INDEX),
+            _ => rm as u32,
+        };
+
+        // A reserved mode in `frm` should make the instruction illegal, we round to the nearest
+        fpu::Rounding::from_bits(rm).unwrap_or(fpu::Rounding::NearestEven)
+    }
+
+    /// Accumulates the exception flags of a float operation in `fcsr`, and returns its result
+    fn raise<T>(&mut self, result: fpu::Rounded<T>) -> T {
+        use parser::register_names::FCSR_INDEX;
+        self.status[FCSR_INDEX as usize] |= result.flags;
+        result.value
+    }
+
+    /// Accepts programs with instructions we don't know, and deals with them at runtime instead
+    pub fn permissive(mut self, on_illegal: OnIllegal) -> Self {
+        self.on_illegal = Some(on_illegal);
+        self
+    }
+
+    /// Defines each `(name, value)` as if the code started with `.eqv name value`, so it can be
+    /// tested with `.ifdef`
+    pub fn defines(mut self, defines: Vec<(String, String)>) -> Self {
+        self.defines = defines;
+        self
+    }
+
+    /// Treats the warnings of the code as errors, so it only loads if there are none
+    pub fn werror(mut self, werror: bool) -> Self {
+        self.werror = werror;
+        self
+    }
+
+    /// Also warns about the labels nothing refers to, besides `main`, `_start` and the global ones
+    pub fn warn_unused(mut self, warn_unused: bool) -> Self {
+        self.warn_unused = warn_unused;
+        self
+    }
+
+    /// Rejects the code RARS wouldn't accept, see
+    /// [Options::strict](../parser/struct.Options.html#structfield.strict)
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Simulates RV64 instead of RV32, see
+    /// [Options::rv64](../parser/struct.Options.html#structfield.rv64)
+    pub fn rv64(mut self, rv64: bool) -> Self {
+        self.rv64 = rv64;
+        self
+    }
+
+    /// Simulates RV32E, which only has the registers x0 to x15. The ecalls take their number in
+    /// t0 instead of a7, like in its ABI. See
+    /// [Options::rv32e](../parser/struct.Options.html#structfield.rv32e)
+    pub fn rv32e(mut self, rv32e: bool) -> Self {
+        self.rv32e = rv32e;
+        self
+    }
+
+    /// Accepts the compressed instructions of RV32C, which take 2 bytes, so the addresses of the code
+    /// are the ones a real processor would have. See
+    /// [Options::rvc](../parser/struct.Options.html#structfield.rvc)
+    pub fn rvc(mut self, rvc: bool) -> Self {
+        self.rvc = rvc;
+        self
+    }
+
+    /// Puts the first instruction at `address` instead of 0, so the labels of the code, the addresses
+    /// `auipc` and `jal` compute and the pc are the ones of a processor whose code starts there. The
+    /// code isn't in the memory, unless with [readable_code](#method.readable_code) or
+    /// [self_modifying](#method.self_modifying), so it doesn't take the place of the data.
+    pub fn text_base(mut self, address: usize) -> Self {
+        self.text_base = address;
+        self
+    }
+
+    /// Checks the targets of `jalr` and `ret`, which only have their bit 0 cleared, so jumping to an
+    /// address that isn't a multiple of 4, or 2 with [rvc](#method.rvc), raises an instruction
+    /// address misaligned exception like in RARS, instead of running whatever instruction is there
+    pub fn check_jumps(mut self, check_jumps: bool) -> Self {
+        self.check_jumps = check_jumps;
+        self
+    }
+
+    /// Stops at loads and stores to an address that isn't a multiple of their size, like a `lw`
+    /// from 0x1002, which RARS doesn't accept. They raise a load or store address misaligned
+    /// exception instead, when there's a trap handler.
+    pub fn strict_alignment(mut self, strict_alignment: bool) -> Self {
+        self.strict_alignment = strict_alignment;
+        self
+    }
+
+    /// Uses the memory layout of RARS, so the addresses from its examples work unchanged: the code
+    /// starts at 0x00400000, the data at 0x10010000, the heap at 0x10040000, `sp` at 0x7fffeffc and
+    /// the keyboard and display MMIO is at 0xffff0000. A [text_base](#method.text_base) or
+    /// [stack_top](#method.stack_top) given after this still moves the code or the stack.
+    pub fn rars_layout(mut self, rars_layout: bool) -> Self {
+        self.rars_layout = rars_layout;
+        if rars_layout {
+            self.text_base = RARS_TEXT_BASE;
+            self.stack_top = Some(RARS_STACK_TOP);
+            let device = memory::RarsMmio(self.memory.mmio.clone());
+            let range = RARS_MMIO..RARS_MMIO + memory::RARS_MMIO_SIZE;
+            self.memory.map(Segment::Mmio, range, Box::new(device));
+        }
+        self
+    }
+
+    /// Starts `sp` at `address`, with the stack right below it, instead of at the end of the memory
+    /// after the data. See [stack](stack/index.html)
+    pub fn stack_top(mut self, address: usize) -> Self {
+        self.stack_top = Some(address);
+        self
+    }
+
+    /// Gives the stack `bytes` bytes, instead of everything after the data. The heap gets what's
+    /// left between them, and a push past the start of the stack is a stack overflow.
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Puts the machine code of the program in the memory, so it can read its own code with `lw`,
+    /// but not change it. The data then starts after the space for the code, like with
+    /// [self_modifying](#method.self_modifying).
+    pub fn readable_code(mut self, readable_code: bool) -> Self {
+        self.readable_code = readable_code;
+        self
+    }
+
+    /// Puts the machine code of the program in the memory, so it can read and change its own code
+    /// and run instructions it wrote to the data. The data then starts after the space for the code,
+    /// 0x00400000 bytes after the [text_base](#method.text_base). See
+    /// [self_modifying](self_modifying/index.html)
+    pub fn self_modifying(mut self, self_modifying: bool) -> Self {
+        self.self_modifying = self_modifying;
+        self
+    }
+
+    /// Gives each vector register `bits` bits, VLEN in the spec. It has to be a power of 2 of at
+    /// least 32, and it's 128 by default.
+    pub fn vlen(mut self, bits: usize) -> Self {
+        self.vectors = vector::Vectors::new(bits);
+        self
+    }
+
+    /// Starts the program at the label `entry`, instead of at a global `main` or at the first
+    /// instruction, see [Options::entry](../parser/struct.Options.html#structfield.entry)
+    pub fn entry(mut self, entry: Option<String>) -> Self {
+        self.entry = entry;
+        self
+    }
+
+    /// Times every ecall, so the time spent running instructions can be told apart from the time
+    /// spent printing or sleeping, see [bench](bench/index.html)
+    pub fn bench(mut self, bench: bool) -> Self {
+        self.bench = bench;
+        self
+    }
+
+    /// Counts how many times each instruction runs and how many times each branch is taken, for
+    /// the [Mix](mix/struct.Mix.html) in the stats
+    pub fn instruction_mix(mut self, instruction_mix: bool) -> Self {
+        self.instruction_mix = instruction_mix;
+        self
+    }
+
+    /// Counts how many instructions run on each line of the source and in each function, for
+    /// [write_profile](#method.write_profile), see [profile](profile/index.html)
+    pub fn profile(mut self, profile: bool) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Writes each instruction that runs to `out`, with the registers it changed and the memory it
+    /// touched, or only the ones `filters` cover, see [trace](trace/index.html)
+    pub fn trace(mut self, out: Box<dyn std::io::Write + Send>, filters: Vec<TraceFilter>) -> Self {
+        self.trace = Some(trace::Trace::new(out, filters));
+        self
+    }
+
+    /// Pauses right before the instructions at `locations`, see [debugger](debugger/index.html)
+    pub fn breakpoints(mut self, locations: Vec<Location>) -> Self {
+        self.debugger.set_locations(locations);
+        self
+    }
+
+    /// Starts paused at the first instruction, and reads commands like `step`, `next` and `x/4 sp`
+    /// from stdin at each pause, see [debugger](debugger/index.html)
+    pub fn debug(mut self, debug: bool) -> Self {
+        if debug {
+            self.debugger.set_commands();
+        }
+        self
+    }
+
+    /// Takes over the terminal to show the code, the registers, the memory and the output while
+    /// debugging with keys, see [tui](tui/index.html)
+    pub fn tui(mut self, tui: bool) -> Self {
+        if tui {
+            self.debugger.set_commands();
+        }
+        self.tui = tui;
+        self
+    }
+
+    /// Waits for GDB to connect on `port` once the program is loaded, and lets it debug the
+    /// program, see [gdb](gdb/index.html)
+    pub fn gdb(mut self, port: u16) -> std::io::Result<Self> {
+        self.gdb = Some(gdb::Gdb::listen(port)?);
+        self.debugger.set_commands();
+        Ok(self)
+    }
+
+    /// Waits for an editor to connect on `port` once the program is loaded, and lets it debug the
+    /// program with the Debug Adapter Protocol, see [dap](dap/index.html)
+    pub fn dap(mut self, port: u16) -> std::io::Result<Self> {
+        self.dap = Some(dap::Dap::listen(port)?);
+        self.debugger.set_commands();
+        Ok(self)
+    }
+
+    /// Keeps what the last `length` instructions changed while debugging, so the debugger can go
+    /// back over them, or nothing with 0, see [history](history/index.html)
+    pub fn history(mut self, length: usize) -> Self {
+        self.history = history::History::new(length);
+        self
+    }
+
+    /// Where to save the snapshots of `save_at` and of the `save` command of the debugger, see
+    /// [snapshot](snapshot/index.html)
+    pub fn snapshot(mut self, path: std::path::PathBuf) -> Self {
+        self.saving.set_path(path);
+        self
+    }
+
+    /// Saves a snapshot the first time the program gets to `location`, see
+    /// [snapshot](snapshot/index.html)
+    pub fn save_at(mut self, location: Location) -> Self {
+        self.saving.set_at(location);
+        self
+    }
+
+    /// Writes the keys the program gets and what its ecalls read to `out`, with how many
+    /// instructions ran when it got them, to [replay](#method.replay) them later, see
+    /// [replay](replay/index.html)
+    pub fn record(mut self, out: Box<dyn std::io::Write + Send>) -> Self {
+        self.replay = Some(replay::Replay::record(out));
+        self
+    }
+
+    /// Gives the program the keys and the input of a `recording` instead of the ones from outside,
+    /// see [replay](replay/index.html)
+    pub fn replay(mut self, recording: &str) -> Result<Self, String> {
+        self.replay = Some(replay::Replay::play_back(recording)?);
+        Ok(self)
+    }
+
+    /// Where the window has to put the keys while recording or replaying, instead of the MMIO
+    pub fn key_queue(&self) -> Option<crate::renderer::KeyQueue> {
+        self.replay.as_ref().map(replay::Replay::keys)
+    }
+
+    /// Stops the program if it's about to run more than `instructions` instructions, see
+    /// [limits](limits/index.html)
+    pub fn max_instructions(mut self, instructions: u64) -> Self {
+        self.limits.set_instructions(instructions);
+        self
+    }
+
+    /// Stops the program once it has run for `time`, see [limits](limits/index.html)
+    pub fn timeout(mut self, time: std::time::Duration) -> Self {
+        self.limits.set_time(time);
+        self
+    }
+
+    /// Stops printing the output of the program after `bytes` bytes
+    pub fn console_limit(mut self, bytes: usize) -> Self {
+        self.console = console::Console::new(Some(bytes));
+        self
+    }
+
+    /// How many bytes of output we didn't print because of the [console_limit](#method.console_limit)
+    pub fn output_dropped(&self) -> usize {
+        self.console.dropped()
+    }
+
+    /// Maps a window of memory shared with other FPGRARS instances, see [SharedMemoryConfig]
+    pub fn shared_memory(mut self, config: &SharedMemoryConfig) -> std::io::Result<Self> {
+        let shared = shared_memory::SharedMemory::open(config)?;
+        let range = config.start..config.start + config.len;
+        self.memory.map(Segment::Shared, range, Box::new(shared));
+        Ok(self)
+    }
+
+    /// Parses several files and links them into a single program, starting at the first one.
+    /// Only the labels declared with `.globl` are shared between the files.
+    pub fn load_from_files(mut self, paths: Vec<String>) -> Result<Self, parser::Error> {
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            // TODO: some of this logic is duplicated from the Includer, try to dedup?
+            let pathbuf = std::path::PathBuf::from(&path);
+            let error = format!("Can't open file: <{:?}>", pathbuf.to_str());
+            let lines = parser::file_lines(pathbuf.clone()).expect(&error);
+            files.push(self.preprocess(lines.parse_includes(pathbuf)));
+        }
+
+        let parsed = parser::link(files, DATA_SIZE, &self.parse_options())?;
+        self.load(parsed);
+        Ok(self)
+    }
+
+    /// Parses already read lines of RISC-V code. `path` is used to resolve `.include`s.
+    pub fn load_from_lines<I>(mut self, lines: I, path: std::path::PathBuf) -> Result<Self, parser::Error>
+    where
+        I: Iterator<Item = String>,
+    {
+        let parsed = self
+            .preprocess(lines.parse_includes(path))
+            .parse_riscv(DATA_SIZE, &self.parse_options())?;
+        self.load(parsed);
+        Ok(self)
+    }
+
+    /// Parses `code` as if it were a file called `main.s`, for the tests
+    #[cfg(test)]
+    pub(crate) fn load_str(self, code: &str) -> Result<Self, parser::Error> {
+        self.load_from_lines(code.lines().map(str::to_owned), std::path::PathBuf::from("main.s"))
+    }
+
+    fn load(&mut self, parsed: parser::Parsed) {
+        let parser::Parsed { code, code_pos, data, read_only, symbols, warnings, entry, exit, data_end } = parsed;
+        self.code = code;
+        self.code_pos = code_pos;
+        self.symbols = symbols;
+        self.warnings = warnings;
+        // The data of RARS goes all the way up to its heap, whatever the program declares
+        let data_end = if self.rars_layout { data_end.max(RARS_HEAP_BASE) } else { data_end };
+        self.memory.load(self.parse_options().data_base, data, data_end);
+        self.place_stack();
+        self.memory.read_only = read_only;
+        if self.code_in_memory() {
+            self.csr_names = parser::register_names::status();
+            self.write_code(0);
+        } else {
+            self.map_code();
+        }
+
+        // Returning from the entry ends the program
+        self.pc = self.text_base;
+        if let Some(entry) = entry {
+            self.pc = entry;
+            self.set_reg(1, exit as u32);
+        }
+    }
+
+    /// Writes the program as it was assembled, see [write_listing](../parser/fn.write_listing.html)
+    pub fn write_listing<W: std::io::Write>(&self, out: &mut W) -> std::io::Result<()> {
+        parser::write_listing(out, &self.code, &self.code_pos, self.text_base, self.rvc)
+    }
+
+    /// Writes the labels of the program, see [write_symbols](../parser/fn.write_symbols.html)
+    pub fn write_symbols<W: std::io::Write>(&self, out: &mut W) -> std::io::Result<()> {
+        parser::write_symbols(out, &self.symbols)
+    }
+
+    fn preprocess<I: Iterator<Item = parser::Line>>(&self, lines: I) -> parser::MacroParser<I> {
+        let parser = lines.parse_macros().strict(self.strict);
+        self.defines
+            .iter()
+            .fold(parser, |parser, (name, value)| parser.define(name, value))
+    }
+
+    fn parse_options(&self) -> parser::Options {
+        parser::Options {
+            permissive: self.on_illegal.is_some(),
+            werror: self.werror,
+            warn_unused: self.warn_unused,
+            strict: self.strict,
+            rv64: self.rv64,
+            rv32e: self.rv32e,
+            rvc: self.rvc,
+            entry: self.entry.clone(),
+            text_base: self.text_base,
+            data_base: if self.rars_layout {
+                RARS_DATA_BASE
+            } else if self.code_in_memory() {
+                self.data_base()
+            } else {
+                0
+            },
+        }
+    }
+
+    fn init(&mut self) {
+        // Create necessary status registers
+        self.status
+            .resize(parser::register_names::status().len(), 0);
+
+        self.hooks = self.self_modifying || self.instruction_mix || self.profile || self.trace.is_some();
+        self.hooks |= self.debugger.is_on() || self.saving.is_on() || self.replay.is_some();
+        self.hooks |= self.limits.is_on();
+        self.init_trace();
+        self.init_breakpoints();
+        self.init_tui();
+        self.init_gdb();
+        self.init_dap();
+        self.history.start(self.debugger.reads_commands());
+        self.ops.clear();
+        self.compile_code(0..self.code.len());
+
+        // Set stack pointer
+        self.set_reg(2, self.memory.stack(0).end as u32 - 4);
+
+        // Set global pointer
+        self.set_reg(3, 0x10008000);
+
+        self.started_at = time::Instant::now();
+        self.user_mode = false;
+        // RV32IMAFDUV, or RV64 with MXL = 2
+        let mxl = if self.rv64 { 2 } else { 1 };
+        self.status[parser::register_names::MISA_INDEX as usize] = mxl << 30 | 0x00301129;
+        if self.rvc {
+            self.status[parser::register_names::MISA_INDEX as usize] |= 0x4; // C
+        }
+        self.init_snapshot();
+    }
+
+    /// Writes the last instruction to the `trace`, decodes the code again with `self_modifying`,
+    /// counts the instruction about to run with `instruction_mix` and `profile`, keeps what the
+    /// last one changed in the `history`, stops the program at its `limits`, and pauses at a
+    /// breakpoint or after a step of the debugger. The run loop only checks `hooks` before calling this, so a program that doesn't
+    /// use them doesn't pay for them.
+    fn before_instruction(&mut self) -> debugger::Resume {
+        if self.trace.is_some() {
+            self.trace_step();
+        }
+        if self.self_modifying {
+            self.refresh_code();
+        }
+        if self.instruction_mix {
+            self.stats.mix.run(self.slot(self.pc), &self.code);
+        }
+        if self.profile {
+            let slot = self.slot(self.pc);
+            self.stats.profile.run(slot, self.pc, &self.code[slot]);
+        }
+        if self.history.is_on() {
+            self.record_step();
+        }
+        if self.saving.is_on() {
+            self.check_save_at();
+        }
+        if self.replay.is_some() {
+            self.replay_step();
+        }
+        if self.limits.is_on() {
+            if let stop @ debugger::Resume::Exit(_) = self.check_limits() {
+                return stop;
+            }
+        }
+        self.check_breakpoint()
+    }
+
+    /// How many bytes each element of `code` takes, see [rvc](#method.rvc)
+    pub fn slot_size(&self) -> usize {
+        if self.rvc {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// The address of the instruction `code[i]`
+    pub fn code_address(&self, i: usize) -> usize {
+        self.text_base + i * self.slot_size()
+    }
+
+    /// The index in `code` of the instruction at `pc`
+    #[inline]
+    fn slot(&self, pc: usize) -> usize {
+        let offset = pc.wrapping_sub(self.text_base);
+        if self.rvc {
+            offset / 2
+        } else {
+            offset / 4
+        }
+    }
+
+    /// Where the instruction at `pc` came from, like `, main.s:12`, to add to an error message
+    fn source_at(&self, pc: usize) -> String {
+        let pos = self.code_pos.get(self.slot(pc)).and_then(Option::as_ref);
+        pos.map(|pos| format!(", {}:{}", pos.file, pos.line)).unwrap_or_default()
+    }
+
+    /// A line of the input of the program, which is typed in the [tui](tui/index.html) if it's on
+    fn read_line(&mut self) -> String {
+        self.input("line", |sim| {
+            if sim.terminal.is_some() {
+                return sim.tui_read_line();
+            }
+            let mut buf = String::new();
+            std::io::stdin().read_line(&mut buf).unwrap();
+            buf
+        })
+    }
+
+    /// Explains why the program stopped at a load or store of `size` bytes from `addr`, which
+    /// isn't in the memory, or isn't allowed in its segment
+    fn report_memory_fault(&self, what: &str, addr: usize, size: usize) {
+        let segment = self.memory.segment(addr).unwrap_or(Segment::Data);
+        let why = match self.memory.permissions(addr, size) {
+            Some(permissions) if !permissions.read => format!("in the {}, which can't be read", segment),
+            Some(_) => format!("in the {}, which is read-only", segment),
+            None if addr < 0x1000 => "which looks like a null pointer".to_owned(),
+            None => "which isn't in the memory".to_owned(),
+        };
+        eprintln!("Tried to {} {} bytes at {:#x}, {}", what, size, addr, why);
+        eprintln!("  at {:#x}: {}", self.pc, self.code[self.slot(self.pc)]);
+        if let Some(Some(pos)) = self.code_pos.get(self.slot(self.pc)) {
+            eprintln!("  from {}:{}: {}", pos.file, pos.line, pos.source.trim());
+        }
+    }
+
+    /// Whether there's an instruction at the pc, past the end of the code, which is only when the
+    /// [self_modifying](#method.self_modifying) code jumped to instructions it wrote
+    fn can_fetch(&mut self) -> bool {
+        if self.self_modifying {
+            self.refresh_code();
+        }
+        self.slot(self.pc) < self.ops.len()
+    }
+
+    /// Explains why the program stopped at a pc that isn't in the code
+    fn report_fetch_fault(&self) {
+        let why = match self.memory.segment(self.pc) {
+            Some(Segment::Text) | None if self.pc < 0x1000 => "which looks like a null pointer".to_owned(),
+            Some(Segment::Text) | None => "which isn't in the code".to_owned(),
+            Some(segment) => format!("in the {}, which can't be run", segment),
+        };
+        eprintln!("Tried to run the instruction at {:#x}, {}", self.pc, why);
+        self.report_calls();
+    }
+
+    /// How many bytes the instruction at `pc` takes. With [rvc](#method.rvc), the 4-byte ones are
+    /// followed by a [padding](../parser/enum.Instruction.html#method.padding).
+    #[inline]
+    fn size_at(&self, pc: usize) -> usize {
+        if self.rvc && !self.code.get(self.slot(pc) + 1).is_some_and(parser::Instruction::is_padding) {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// Runs the program until it exits and returns its exit code
+    pub fn run(&mut self) -> i32 {
+        let exit_code = self.execute();
+        if self.trace.is_some() {
+            self.finish_trace();
+        }
+        self.finish_tui();
+        self.finish_gdb(exit_code);
+        self.finish_dap(exit_code);
+        self.finish_replay();
+        exit_code
+    }
+
+    fn execute(&mut self) -> i32 {
+        use parser::FloatInstruction as F;
+        use parser::Rv64Instruction as R;
+        use parser::VectorInstruction as V;
+        use parser::Instruction::*;
+        use dispatch::Kind as K;
+
+        let to_1 = |b| if b { 1 } else { 0 };
+
+        macro_rules! branch {
+            ($cond:expr, $pc:expr, $label:expr) => {
+                if $cond {
+                    $pc = $label;
+                    continue;
+                }
+            };
+        }
+
+        // Moves on to the second instruction of a superinstruction, which counts as one more
+        macro_rules! next_instruction {
+            () => {
+                self.stats.instructions += 1;
+                self.pc += 4;
+                crate::crash::PC.store(self.pc, std::sync::atomic::Ordering::Relaxed);
+            };
+        }
+
+        // With `strict_alignment`, an access to an address that isn't a multiple of its size
+        // raises `$cause`, or stops the program if there's no trap handler
+        macro_rules! check_alignment {
+            ($addr:expr, $size:expr, $cause:expr, $what:expr) => {
+                if self.strict_alignment && !$addr.is_multiple_of($size) {
+                    if self.can_trap() {
+                        self.trap($cause);
+                        continue;
+                    }
+                    let (addr, pc) = ($addr, self.pc);
+                    eprintln!("Misaligned {} of {} bytes at {:#x}, from {:#x}{}", $what, $size, addr, pc, self.source_at(pc));
+                    return 1;
+                }
+            };
+        }
+
+        // An access to an address outside of the memory, like a null pointer, or one the
+        // `permissions` of its segment don't allow, like a store to the `.rodata`, raises the access
+        // fault `$cause`, or stops the program if there's no trap handler
+        macro_rules! check_mapped {
+            ($addr:expr, $size:expr, $cause:expr, $what:expr, |$permissions:ident| $allowed:expr) => {
+                match self.memory.permissions($addr, $size) {
+                    Some($permissions) if $allowed => {}
+                    _ => {
+                        if self.can_trap() {
+                            self.trap($cause);
+                            continue;
+                        }
+                        self.report_memory_fault($what, $addr, $size);
+                        return 1;
+                    }
+                }
+            };
+        }
+
+        // The address of a load of `$size` bytes
+        macro_rules! load_address {
+            ($rs1:expr, $imm:expr, $size:expr) => {{
+                let addr = self.get_reg::<u32>($rs1).wrapping_add($imm) as usize;
+                check_alignment!(addr, $size, 4, "load");
+                check_mapped!(addr, $size, 5, "load", |permissions| permissions.read);
+                addr
+            }};
+        }
+
+        // The address of a store through `$rs1`. A push below the start of the stack, through `sp`,
+        // means it overflowed into the heap, which stops the program even with a trap handler
+        macro_rules! store_address {
+            ($rs1:expr, $imm:expr) => {{
+                let addr = self.get_reg::<u32>($rs1).wrapping_add($imm) as usize;
+                if $rs1 == 2 && self.overflows_stack(addr) {
+                    self.report_stack_overflow(addr);
+                    return 1;
+                }
+                addr
+            }};
+        }
+
+        // Stores to read-only data, or to the code, raise a store access fault, or stop the
+        // program if there's no trap handler to deal with it
+        macro_rules! store {
+            ($set:ident, $addr:expr, $x:expr, $size:expr) => {{
+                let addr = $addr;
+                check_alignment!(addr, $size, 6, "store");
+                check_mapped!(addr, $size, 7, "store", |permissions| permissions.write);
+                self.memory.$set(addr, $x)
+            }};
+        }
+
+        // The target of a jump, with its bit 0 already cleared. With `check_jumps`, a misaligned one
+        // raises an instruction address misaligned exception, or stops the program if there's no
+        // trap handler
+        macro_rules! jump_target {
+            ($target:expr) => {{
+                let target = $target;
+                if self.check_jumps && !target.is_multiple_of(self.slot_size()) {
+                    if self.can_trap() {
+                        self.trap(0);
+                        continue;
+                    }
+                    eprintln!("Tried to jump to the misaligned address {:#x}, from {:#x}", target, self.pc);
+                    return 1;
+                }
+                target
+            }};
+        }
+
+        // The address of an atomic instruction, which has to be aligned. A misaligned one raises
+        // `$cause`, or stops the program if there's no trap handler.
+        macro_rules! atomic_address {
+            ($rs1:expr, $cause:expr) => {{
+                let addr = self.get_reg::<u32>($rs1) as usize;
+                if addr % 4 != 0 {
+                    if self.can_trap() {
+                        self.trap($cause);
+                        continue;
+                    }
+                    eprintln!("Misaligned atomic access at {:#x}, from {:#x}", addr, self.pc);
+                    return 1;
+                }
+                check_mapped!(addr, 4, $cause + 1, "access", |permissions| permissions.read && ($cause == 4 || permissions.write));
+                addr
+            }};
+        }
+
+        // Reads the word at (rs1) into rd and writes `$op` of it and rs2 back, reading everything
+        // before writing, since rd can be rs1 or rs2
+        macro_rules! amo {
+            ($rd:expr, $rs2:expr, $rs1:expr, |$old:ident, $x:ident| $op:expr) => {{
+                let addr = atomic_address!($rs1, 6);
+                let ($old, $x) = (self.memory.get_word(addr), self.get_reg::<u32>($rs2));
+                store!(set_word, addr, $op, 4);
+                self.set_reg($rd, $old);
+            }};
+        }
+
+        // A vector instruction returns `None` when `vtype` is invalid or one of its register groups
+        // is misaligned, which is an illegal instruction
+        macro_rules! vector {
+            ($op:expr) => {
+                if $op.is_none() {
+                    if self.can_trap() {
+                        self.trap(2);
+                        continue;
+                    }
+                    let instruction = &self.code[self.slot(self.pc)];
+                    eprintln!("Can't run `{}` at {:#x} with the current `vtype`", instruction, self.pc);
+                    return 1;
+                }
+            };
+        }
+
+        // An instruction that can't run in user mode, see [is_privileged](#method.is_privileged)
+        macro_rules! privileged {
+            ($csr:expr) => {
+                if self.is_privileged($csr) {
+                    if self.can_trap() {
+                        self.trap(2);
+                        continue;
+                    }
+                    let instruction = &self.code[self.slot(self.pc)];
+                    eprintln!("Tried to run `{}` in user mode at {:#x}", instruction, self.pc);
+                    return 1;
+                }
+            };
+        }
+
+        // Reads a CSR into rd, and writes `$new` to it if `$writes`. `csrrs` and `csrrc` don't write
+        // when rs1 is x0 or the immediate is 0, so `csrr` works on the read-only CSRs, but writing
+        // to one is an illegal instruction. So is touching a machine CSR in user mode.
+        macro_rules! csr {
+            ($rd:expr, $csr:expr, $writes:expr, |$old:ident| $new:expr) => {{
+                privileged!(Some($csr));
+                let $old = self.read_status($csr);
+                if $writes {
+                    if parser::register_names::is_read_only($csr) {
+                        if self.can_trap() {
+                            self.trap(2);
+                            continue;
+                        }
+                        let name = parser::register_names::STATUS_NAMES[$csr as usize];
+                        eprintln!("Tried to write to the read-only CSR `{}` at {:#x}", name, self.pc);
+                        return 1;
+                    }
+                    self.set_status($csr, $new);
+                }
+                self.set_reg($rd, self.status_to_reg($csr, $old));
+            }};
+        }
+
+        self.init();
+
+        loop {
+            self.stats.instructions += 1;
+            crate::crash::PC.store(self.pc, std::sync::atomic::Ordering::Relaxed);
+            // Only the text can be run, which raises an instruction access fault otherwise
+            if self.slot(self.pc) >= self.ops.len() && !self.can_fetch() {
+                if self.can_trap() {
+                    self.trap(1);
+                    continue;
+                }
+                self.report_fetch_fault();
+                return 1;
+            }
+            if self.hooks {
+                match self.before_instruction() {
+                    debugger::Resume::Continue => {}
+                    debugger::Resume::Quit => return 1,
+                    debugger::Resume::Exit(code) => return code,
+                }
+            }
+            let slot = self.slot(self.pc);
+            let op = self.ops[slot];
+            match op.kind {
+                // The common instructions, compiled, see [dispatch](dispatch/index.html)
+                K::Add => self.set_reg(op.rd, self.get_reg::<u32>(op.rs1).wrapping_add(self.get_reg(op.rs2))),
+                K::Sub => self.set_reg(op.rd, self.get_reg::<u32>(op.rs1).wrapping_sub(self.get_reg(op.rs2))),
+                K::Sll => self.set_reg(op.rd, self.get_reg::<u32>(op.rs1) << (self.get_reg::<i32>(op.rs2) & 0x1f)),
+                K::Slt => self.set_reg(op.rd, to_1(self.get_reg::<i64>(op.rs1) < self.get_reg::<i64>(op.rs2))),
+                K::Sltu => self.set_reg(op.rd, to_1(self.get_reg::<u64>(op.rs1) < self.get_reg::<u64>(op.rs2))),
+                K::Xor => self.set_reg(op.rd, self.get_reg::<u64>(op.rs1) ^ self.get_reg::<u64>(op.rs2)),
+                K::Srl => self.set_reg(op.rd, self.get_reg::<u32>(op.rs1) >> (self.get_reg::<i32>(op.rs2) & 0x1f)),
+                K::Sra => self.set_reg(op.rd, self.get_reg::<i32>(op.rs1) >> (self.get_reg::<i32>(op.rs2) & 0x1f)),
+                K::Or => self.set_reg(op.rd, self.get_reg::<u64>(op.rs1) | self.get_reg::<u64>(op.rs2)),
+                K::And => self.set_reg(op.rd, self.get_reg::<u64>(op.rs1) & self.get_reg::<u64>(op.rs2)),
+                K::Addi => self.set_reg(op.rd, self.get_reg::<u32>(op.rs1).wrapping_add(op.imm)),
+                K::Slli => self.set_reg(op.rd, self.get_reg::<i32>(op.rs1) << (op.imm & 0x1f)),
+                K::Slti => self.set_reg(op.rd, to_1(self.get_reg::<i64>(op.rs1) < (op.imm as i32 as i64))),
+                K::Sltiu => self.set_reg(op.rd, to_1(self.get_reg::<u64>(op.rs1) < op.imm as i32 as u64)),
+                K::Xori => self.set_reg(op.rd, self.get_reg::<u64>(op.rs1) ^ op.imm as i32 as u64),
+                K::Srli => self.set_reg(op.rd, self.get_reg::<u32>(op.rs1) >> (op.imm & 0x1f)),
+                K::Srai => self.set_reg(op.rd, self.get_reg::<i32>(op.rs1) >> (op.imm & 0x1f)),
+                K::Ori => self.set_reg(op.rd, self.get_reg::<u64>(op.rs1) | op.imm as i32 as u64),
+                K::Andi => self.set_reg(op.rd, self.get_reg::<u64>(op.rs1) & op.imm as i32 as u64),
+                K::Lb => {
+                    let addr = load_address!(op.rs1, op.imm, 1);
+                    self.set_reg(op.rd, self.memory.get_byte(addr) as i8 as i32)
+                }
+                K::Lh => {
+                    let addr = load_address!(op.rs1, op.imm, 2);
+                    self.set_reg(op.rd, self.memory.get_half(addr) as i16 as i32)
+                }
+                K::Lw => {
+                    let addr = load_address!(op.rs1, op.imm, 4);
+                    self.set_reg(op.rd, self.memory.get_word(addr))
+                }
+                K::Lbu => {
+                    let addr = load_address!(op.rs1, op.imm, 1);
+                    self.set_reg(op.rd, self.memory.get_byte(addr) as u32)
+                }
+                K::Lhu => {
+                    let addr = load_address!(op.rs1, op.imm, 2);
+                    self.set_reg(op.rd, self.memory.get_half(addr) as u32)
+                }
+                K::Sb => store!(set_byte, store_address!(op.rs1, op.imm), self.get_reg::<u8>(op.rs2), 1),
+                K::Sh => store!(set_half, store_address!(op.rs1, op.imm), self.get_reg::<u16>(op.rs2), 2),
+                K::Sw => store!(set_word, store_address!(op.rs1, op.imm), self.get_reg::<u32>(op.rs2), 4),
+                K::Beq => branch!(self.get_reg::<u64>(op.rs1) == self.get_reg::<u64>(op.rs2), self.pc, op.imm as usize),
+                K::Bne => branch!(self.get_reg::<u64>(op.rs1) != self.get_reg::<u64>(op.rs2), self.pc, op.imm as usize),
+                K::Blt => branch!(self.get_reg::<i64>(op.rs1) < self.get_reg::<i64>(op.rs2), self.pc, op.imm as usize),
+                K::Bge => branch!(self.get_reg::<i64>(op.rs1) >= self.get_reg::<i64>(op.rs2), self.pc, op.imm as usize),
+                K::Bltu => branch!(self.get_reg::<u64>(op.rs1) < self.get_reg::<u64>(op.rs2), self.pc, op.imm as usize),
+                K::Bgeu => branch!(self.get_reg::<u64>(op.rs1) >= self.get_reg::<u64>(op.rs2), self.pc, op.imm as usize),
+                K::Jal => {
+                    self.set_reg(op.rd, (self.pc + self.size_at(self.pc)) as u32);
+                    self.pc = op.imm as usize;
+                    continue;
+                }
+                K::Li => self.set_reg(op.rd, op.imm),
+                K::Mv => self.set_reg(op.rd, self.get_reg::<u64>(op.rs1)),
+                K::Auipc => self.set_reg(op.rd, (self.pc as u32).wrapping_add(op.imm)),
+
+                // Superinstructions, which run two of the ones above
+                K::LiAddi => {
+                    self.set_reg(op.rd, op.imm);
+                    next_instruction!();
+                }
+                K::LiLw => {
+                    self.set_reg(op.rs1, op.imm);
+                    next_instruction!();
+                    let addr = load_address!(op.rs1, op.imm2, 4);
+                    self.set_reg(op.rd, self.memory.get_word(addr))
+                }
+                K::AddiBne => {
+                    self.set_reg(op.rd, self.get_reg::<u32>(op.rd).wrapping_add(op.imm2));
+                    next_instruction!();
+                    branch!(self.get_reg::<u64>(op.rd) != self.get_reg::<u64>(op.rs2), self.pc, op.imm as usize)
+                }
+                K::AddiBlt => {
+                    self.set_reg(op.rd, self.get_reg::<u32>(op.rd).wrapping_add(op.imm2));
+                    next_instruction!();
+                    branch!(self.get_reg::<i64>(op.rd) < self.get_reg::<i64>(op.rs2), self.pc, op.imm as usize)
+                }
+                K::SltBeqz | K::SltBnez => {
+                    self.set_reg(op.rd, to_1(self.get_reg::<i64>(op.rs1) < self.get_reg::<i64>(op.rs2)));
+                    next_instruction!();
+                    branch!((self.get_reg::<u64>(op.rd) == 0) == (op.kind == K::SltBeqz), self.pc, op.imm as usize)
+                }
+                K::SltuBeqz | K::SltuBnez => {
+                    self.set_reg(op.rd, to_1(self.get_reg::<u64>(op.rs1) < self.get_reg::<u64>(op.rs2)));
+                    next_instruction!();
+                    branch!((self.get_reg::<u64>(op.rd) == 0) == (op.kind == K::SltuBeqz), self.pc, op.imm as usize)
+                }
+
+                K::Slow => match self.code[slot] {
+                    // Type R
+                    Add(rd, rs1, rs2) | Rv64(R::Addw(rd, rs1, rs2)) => {
+                        self.set_reg(rd, self.get_reg::<u32>(rs1).wrapping_add(self.get_reg(rs2)))
+                    }
+                    Sub(rd, rs1, rs2) | Rv64(R::Subw(rd, rs1, rs2)) => {
+                        self.set_reg(rd, self.get_reg::<u32>(rs1).wrapping_sub(self.get_reg(rs2)))
+                    }
+                    Sll(rd, rs1, rs2) | Rv64(R::Sllw(rd, rs1, rs2)) => self.set_reg(
+                        rd,
+                        self.get_reg::<u32>(rs1) << (self.get_reg::<i32>(rs2) & 0x1f),
+                    ),
+                    Slt(rd, rs1, rs2) => self.set_reg(
+                        rd,
+                        to_1(self.get_reg::<i64>(rs1) < self.get_reg::<i64>(rs2)),
+                    ),
+                    Sltu(rd, rs1, rs2) => self.set_reg(
+                        rd,
+                        to_1(self.get_reg::<u64>(rs1) < self.get_reg::<u64>(rs2)),
+                    ),
+                    Xor(rd, rs1, rs2) => {
+                        self.set_reg(rd, self.get_reg::<u64>(rs1) ^ self.get_reg::<u64>(rs2))
+                    }
+                    Srl(rd, rs1, rs2) | Rv64(R::Srlw(rd, rs1, rs2)) => self.set_reg(
+                        rd,
+                        self.get_reg::<u32>(rs1) >> (self.get_reg::<i32>(rs2) & 0x1f),
+                    ),
+                    Sra(rd, rs1, rs2) | Rv64(R::Sraw(rd, rs1, rs2)) => self.set_reg(
+                        rd,
+                        self.get_reg::<i32>(rs1) >> (self.get_reg::<i32>(rs2) & 0x1f),
+                    ),
+                    Or(rd, rs1, rs2) => {
+                        self.set_reg(rd, self.get_reg::<u64>(rs1) | self.get_reg::<u64>(rs2))
+                    }
+                    And(rd, rs1, rs2) => {
+                        self.set_reg(rd, self.get_reg::<u64>(rs1) & self.get_reg::<u64>(rs2))
+                    }
+                    Mul(rd, rs1, rs2) | Rv64(R::Mulw(rd, rs1, rs2)) => {
+                        self.set_reg(rd, self.get_reg::<u32>(rs1).wrapping_mul(self.get_reg(rs2)))
+                    }
+                    // The upper half of the 64-bit product, with the operands extended to 64 bits
+                    Mulh(rd, rs1, rs2) => {
+                        let (a, b) = (self.get_reg::<i32>(rs1) as i64, self.get_reg::<i32>(rs2) as i64);
+                        self.set_reg(rd, ((a * b) >> 32) as u32)
+                    }
+                    Mulhsu(rd, rs1, rs2) => {
+                        let (a, b) = (self.get_reg::<i32>(rs1) as i64, self.get_reg::<u32>(rs2) as i64);
+                        self.set_reg(rd, ((a * b) >> 32) as u32)
+                    }
+                    Mulhu(rd, rs1, rs2) => {
+                        let (a, b) = (self.get_reg::<u32>(rs1) as u64, self.get_reg::<u32>(rs2) as u64);
+                        self.set_reg(rd, ((a * b) >> 32) as u32)
+                    }
+                    // Division by zero doesn't trap in RISC-V, it has defined results instead
+                    Div(rd, rs1, rs2) | Rv64(R::Divw(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_reg::<i32>(rs1), self.get_reg::<i32>(rs2));
+                        self.set_reg(rd, if b == 0 { -1 } else { a.wrapping_div(b) })
+                    }
+                    Divu(rd, rs1, rs2) | Rv64(R::Divuw(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_reg::<u32>(rs1), self.get_reg::<u32>(rs2));
+                        self.set_reg(rd, a.checked_div(b).unwrap_or(u32::MAX))
+                    }
+                    Rem(rd, rs1, rs2) | Rv64(R::Remw(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_reg::<i32>(rs1), self.get_reg::<i32>(rs2));
+                        self.set_reg(rd, if b == 0 { a } else { a.wrapping_rem(b) })
+                    }
+                    Remu(rd, rs1, rs2) | Rv64(R::Remuw(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_reg::<u32>(rs1), self.get_reg::<u32>(rs2));
+                        self.set_reg(rd, a.checked_rem(b).unwrap_or(a))
+                    }
+                    Sh1add(rd, rs1, rs2) => {
+                        self.set_reg(rd, (self.get_reg::<u32>(rs1) << 1).wrapping_add(self.get_reg(rs2)))
+                    }
+                    Sh2add(rd, rs1, rs2) => {
+                        self.set_reg(rd, (self.get_reg::<u32>(rs1) << 2).wrapping_add(self.get_reg(rs2)))
+                    }
+                    Sh3add(rd, rs1, rs2) => {
+                        self.set_reg(rd, (self.get_reg::<u32>(rs1) << 3).wrapping_add(self.get_reg(rs2)))
+                    }
+                    Bset(rd, rs1, rs2) => {
+                        let bit = 1 << (self.get_reg::<u32>(rs2) & 0x1f);
+                        self.set_reg(rd, self.get_reg::<u32>(rs1) | bit)
+                    }
+                    Bclr(rd, rs1, rs2) => {
+                        let bit = 1 << (self.get_reg::<u32>(rs2) & 0x1f);
+                        self.set_reg(rd, self.get_reg::<u32>(rs1) & !bit)
+                    }
+                    Binv(rd, rs1, rs2) => {
+                        let bit = 1 << (self.get_reg::<u32>(rs2) & 0x1f);
+                        self.set_reg(rd, self.get_reg::<u32>(rs1) ^ bit)
+                    }
+                    Bext(rd, rs1, rs2) => {
+                        let index = self.get_reg::<u32>(rs2) & 0x1f;
+                        self.set_reg(rd, self.get_reg::<u32>(rs1) >> index & 1)
+                    }
+                    CzeroEqz(rd, rs1, rs2) => {
+                        let x = if self.get_reg::<u64>(rs2) == 0 { 0 } else { self.get_reg::<u64>(rs1) };
+                        self.set_reg(rd, x)
+                    }
+                    CzeroNez(rd, rs1, rs2) => {
+                        let x = if self.get_reg::<u64>(rs2) != 0 { 0 } else { self.get_reg::<u64>(rs1) };
+                        self.set_reg(rd, x)
+                    }
+
+                    // RV64, where these work on all 64 bits. The `w` ones are the RV32 instructions,
+                    // since those sign extend their 32-bit results anyway.
+                    Rv64(R::Add(rd, rs1, rs2)) => {
+                        self.set_reg(rd, self.get_reg::<u64>(rs1).wrapping_add(self.get_reg(rs2)))
+                    }
+                    Rv64(R::Sub(rd, rs1, rs2)) => {
+                        self.set_reg(rd, self.get_reg::<u64>(rs1).wrapping_sub(self.get_reg(rs2)))
+                    }
+                    Rv64(R::Sll(rd, rs1, rs2)) => self.set_reg(
+                        rd,
+                        self.get_reg::<u64>(rs1) << (self.get_reg::<u64>(rs2) & 0x3f),
+                    ),
+                    Rv64(R::Srl(rd, rs1, rs2)) => self.set_reg(
+                        rd,
+                        self.get_reg::<u64>(rs1) >> (self.get_reg::<u64>(rs2) & 0x3f),
+                    ),
+                    Rv64(R::Sra(rd, rs1, rs2)) => self.set_reg(
+                        rd,
+                        self.get_reg::<i64>(rs1) >> (self.get_reg::<u64>(rs2) & 0x3f),
+                    ),
+                    Rv64(R::Mul(rd, rs1, rs2)) => {
+                        self.set_reg(rd, self.get_reg::<u64>(rs1).wrapping_mul(self.get_reg(rs2)))
+                    }
+                    Rv64(R::Mulh(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_reg::<i64>(rs1) as i128, self.get_reg::<i64>(rs2) as i128);
+                        self.set_reg(rd, ((a * b) >> 64) as u64)
+                    }
+                    Rv64(R::Mulhsu(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_reg::<i64>(rs1) as i128, self.get_reg::<u64>(rs2) as i128);
+                        self.set_reg(rd, ((a * b) >> 64) as u64)
+                    }
+                    Rv64(R::Mulhu(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_reg::<u64>(rs1) as u128, self.get_reg::<u64>(rs2) as u128);
+                        self.set_reg(rd, ((a * b) >> 64) as u64)
+                    }
+                    Rv64(R::Div(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_reg::<i64>(rs1), self.get_reg::<i64>(rs2));
+                        self.set_reg(rd, if b == 0 { -1 } else { a.wrapping_div(b) })
+                    }
+                    Rv64(R::Divu(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_reg::<u64>(rs1), self.get_reg::<u64>(rs2));
+                        self.set_reg(rd, a.checked_div(b).unwrap_or(u64::MAX))
+                    }
+                    Rv64(R::Rem(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_reg::<i64>(rs1), self.get_reg::<i64>(rs2));
+                        self.set_reg(rd, if b == 0 { a } else { a.wrapping_rem(b) })
+                    }
+                    Rv64(R::Remu(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_reg::<u64>(rs1), self.get_reg::<u64>(rs2));
+                        self.set_reg(rd, a.checked_rem(b).unwrap_or(a))
+                    }
+                    Rv64(R::Sh1add(rd, rs1, rs2)) => {
+                        self.set_reg(rd, (self.get_reg::<u64>(rs1) << 1).wrapping_add(self.get_reg(rs2)))
+                    }
+                    Rv64(R::Sh2add(rd, rs1, rs2)) => {
+                        self.set_reg(rd, (self.get_reg::<u64>(rs1) << 2).wrapping_add(self.get_reg(rs2)))
+                    }
+                    Rv64(R::Sh3add(rd, rs1, rs2)) => {
+                        self.set_reg(rd, (self.get_reg::<u64>(rs1) << 3).wrapping_add(self.get_reg(rs2)))
+                    }
+                    Rv64(R::Bset(rd, rs1, rs2)) => {
+                        let bit = 1 << (self.get_reg::<u64>(rs2) & 0x3f);
+                        self.set_reg(rd, self.get_reg::<u64>(rs1) | bit)
+                    }
+                    Rv64(R::Bclr(rd, rs1, rs2)) => {
+                        let bit = 1 << (self.get_reg::<u64>(rs2) & 0x3f);
+                        self.set_reg(rd, self.get_reg::<u64>(rs1) & !bit)
+                    }
+                    Rv64(R::Binv(rd, rs1, rs2)) => {
+                        let bit = 1 << (self.get_reg::<u64>(rs2) & 0x3f);
+                        self.set_reg(rd, self.get_reg::<u64>(rs1) ^ bit)
+                    }
+                    Rv64(R::Bext(rd, rs1, rs2)) => {
+                        let index = self.get_reg::<u64>(rs2) & 0x3f;
+                        self.set_reg(rd, self.get_reg::<u64>(rs1) >> index & 1)
+                    }
+                    Rv64(R::Bseti(rd, rs1, imm)) => {
+                        self.set_reg(rd, self.get_reg::<u64>(rs1) | 1 << (imm & 0x3f))
+                    }
+                    Rv64(R::Bclri(rd, rs1, imm)) => {
+                        self.set_reg(rd, self.get_reg::<u64>(rs1) & !(1 << (imm & 0x3f)))
+                    }
+                    Rv64(R::Binvi(rd, rs1, imm)) => {
+                        self.set_reg(rd, self.get_reg::<u64>(rs1) ^ 1 << (imm & 0x3f))
+                    }
+                    Rv64(R::Bexti(rd, rs1, imm)) => {
+                        self.set_reg(rd, self.get_reg::<u64>(rs1) >> (imm & 0x3f) & 1)
+                    }
+                    Rv64(R::Addi(rd, rs1, imm)) => {
+                        self.set_reg(rd, self.get_reg::<u64>(rs1).wrapping_add(imm as i32 as u64))
+                    }
+                    Rv64(R::Slli(rd, rs1, imm)) => self.set_reg(rd, self.get_reg::<u64>(rs1) << (imm & 0x3f)),
+                    Rv64(R::Srli(rd, rs1, imm)) => self.set_reg(rd, self.get_reg::<u64>(rs1) >> (imm & 0x3f)),
+                    Rv64(R::Srai(rd, rs1, imm)) => self.set_reg(rd, self.get_reg::<i64>(rs1) >> (imm & 0x3f)),
+
+                    // Type I
+                    Ecall => {
+                        use EcallSignal::*;
+                        if self.ecall_traps() {
+                            self.trap(8); // ecall from user mode
+                            continue;
+                        }
+                        let signal = if self.bench { self.timed_ecall() } else { self.ecall() };
+                        match signal {
+                            Exit(code) => {
+                                return code;
+                            }
+                            Continue => {
+                                continue;
+                            }
+                            Nothing => {}
+                        }
+                    }
+                    Ebreak => {
+                        if self.can_trap() {
+                            self.trap(3); // breakpoint
+                            continue;
+                        }
+                        if !self.debugger.is_interactive() {
+                            eprintln!("Stopped at the ebreak at {:#x}{}", self.pc, self.source_at(self.pc));
+                            return 1;
+                        }
+                        let pc = self.pc;
+                        if self.pause("the ebreak") == debugger::Resume::Quit {
+                            return 1;
+                        }
+                        // Like after going back, or a `continue` to an address of GDB
+                        if self.pc != pc {
+                            self.resume_at_pc();
+                            continue;
+                        }
+                    }
+                    // There's a single hart and no cache, so there's nothing to wait for
+                    Fence(..) => {}
+                    FenceI => {
+                        if self.self_modifying {
+                            self.invalidate_code();
+                        }
+                    }
+
+                    // Atomics. There's a single hart, so the only thing that breaks a reservation is
+                    // another `sc.w`, a trap or switching programs.
+                    LrW(rd, rs1) => {
+                        let addr = atomic_address!(rs1, 4);
+                        self.reservation = Some(addr);
+                        self.set_reg(rd, self.memory.get_word(addr));
+                    }
+                    ScW(rd, rs2, rs1) => {
+                        let addr = atomic_address!(rs1, 6);
+                        if self.reservation.take() == Some(addr) {
+                            store!(set_word, addr, self.get_reg::<u32>(rs2), 4);
+                            self.set_reg(rd, 0);
+                        } else {
+                            self.set_reg(rd, 1);
+                        }
+                    }
+                    AmoSwapW(rd, rs2, rs1) => amo!(rd, rs2, rs1, |_old, x| x),
+                    AmoAddW(rd, rs2, rs1) => amo!(rd, rs2, rs1, |old, x| old.wrapping_add(x)),
+                    AmoXorW(rd, rs2, rs1) => amo!(rd, rs2, rs1, |old, x| old ^ x),
+                    AmoAndW(rd, rs2, rs1) => amo!(rd, rs2, rs1, |old, x| old & x),
+                    AmoOrW(rd, rs2, rs1) => amo!(rd, rs2, rs1, |old, x| old | x),
+                    AmoMinW(rd, rs2, rs1) => amo!(rd, rs2, rs1, |old, x| (old as i32).min(x as i32) as u32),
+                    AmoMaxW(rd, rs2, rs1) => amo!(rd, rs2, rs1, |old, x| (old as i32).max(x as i32) as u32),
+                    AmoMinuW(rd, rs2, rs1) => amo!(rd, rs2, rs1, |old, x| old.min(x)),
+                    AmoMaxuW(rd, rs2, rs1) => amo!(rd, rs2, rs1, |old, x| old.max(x)),
+                    Addi(rd, rs1, imm) | Rv64(R::Addiw(rd, rs1, imm)) => self.set_reg(rd, self.get_reg::<u32>(rs1).wrapping_add(imm)),
+                    Slli(rd, rs1, imm) | Rv64(R::Slliw(rd, rs1, imm)) => self.set_reg(rd, self.get_reg::<i32>(rs1) << (imm & 0x1f)),
+                    Slti(rd, rs1, imm) => {
+                        self.set_reg(rd, to_1(self.get_reg::<i64>(rs1) < (imm as i32 as i64)))
+                    }
+                    // The immediates are sign extended to the whole register
+                    Sltiu(rd, rs1, imm) => {
+                        self.set_reg(rd, to_1(self.get_reg::<u64>(rs1) < imm as i32 as u64))
+                    }
+                    Xori(rd, rs1, imm) => self.set_reg(rd, self.get_reg::<u64>(rs1) ^ imm as i32 as u64),
+                    Srli(rd, rs1, imm) | Rv64(R::Srliw(rd, rs1, imm)) => {
+                        self.set_reg(rd, self.get_reg::<u32>(rs1) >> (imm & 0x1f))
+                    }
+                    Srai(rd, rs1, imm) | Rv64(R::Sraiw(rd, rs1, imm)) => {
+                        self.set_reg(rd, self.get_reg::<i32>(rs1) >> (imm & 0x1f))
+                    }
+                    Bseti(rd, rs1, imm) => {
+                        self.set_reg(rd, self.get_reg::<u32>(rs1) | 1 << (imm & 0x1f))
+                    }
+                    Bclri(rd, rs1, imm) => {
+                        self.set_reg(rd, self.get_reg::<u32>(rs1) & !(1 << (imm & 0x1f)))
+                    }
+                    Binvi(rd, rs1, imm) => {
+                        self.set_reg(rd, self.get_reg::<u32>(rs1) ^ 1 << (imm & 0x1f))
+                    }
+                    Bexti(rd, rs1, imm) => {
+                        self.set_reg(rd, self.get_reg::<u32>(rs1) >> (imm & 0x1f) & 1)
+                    }
+                    Ori(rd, rs1, imm) => self.set_reg(rd, self.get_reg::<u64>(rs1) | imm as i32 as u64),
+                    Andi(rd, rs1, imm) => self.set_reg(rd, self.get_reg::<u64>(rs1) & imm as i32 as u64),
+
+                    // Type I, loads from memory
+                    Lb(rd, imm, rs1) => {
+                        let addr = load_address!(rs1, imm, 1);
+                        self.set_reg(rd, self.memory.get_byte(addr) as i8 as i32)
+                    }
+                    Lh(rd, imm, rs1) => {
+                        let addr = load_address!(rs1, imm, 2);
+                        self.set_reg(rd, self.memory.get_half(addr) as i16 as i32)
+                    }
+                    Lw(rd, imm, rs1) => {
+                        let addr = load_address!(rs1, imm, 4);
+                        self.set_reg(rd, self.memory.get_word(addr))
+                    }
+                    Lbu(rd, imm, rs1) => {
+                        let addr = load_address!(rs1, imm, 1);
+                        self.set_reg(rd, self.memory.get_byte(addr) as u32)
+                    }
+                    Lhu(rd, imm, rs1) => {
+                        let addr = load_address!(rs1, imm, 2);
+                        self.set_reg(rd, self.memory.get_half(addr) as u32)
+                    }
+                    Rv64(R::Lwu(rd, imm, rs1)) => {
+                        let addr = load_address!(rs1, imm, 4);
+                        self.set_reg(rd, self.memory.get_word(addr) as u64)
+                    }
+                    Rv64(R::Ld(rd, imm, rs1)) => {
+                        let addr = load_address!(rs1, imm, 8);
+                        self.set_reg(rd, self.memory.get_dword(addr))
+                    }
+                    Float(F::Lw(rd, imm, rs1)) => {
+                        let x = self.memory.get_float(load_address!(rs1, imm, 4));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::Ld(rd, imm, rs1)) => {
+                        let x = self.memory.get_double(load_address!(rs1, imm, 8));
+                        self.set_float(rd, x);
+                    }
+
+                    // Type S
+                    Sb(rs2, imm, rs1) => store!(
+                        set_byte,
+                        store_address!(rs1, imm),
+                        self.get_reg::<u8>(rs2),
+                        1
+                    ),
+                    Sh(rs2, imm, rs1) => store!(
+                        set_half,
+                        store_address!(rs1, imm),
+                        self.get_reg::<u16>(rs2),
+                        2
+                    ),
+                    Sw(rs2, imm, rs1) => store!(
+                        set_word,
+                        store_address!(rs1, imm),
+                        self.get_reg::<u32>(rs2),
+                        4
+                    ),
+                    Rv64(R::Sd(rs2, imm, rs1)) => store!(
+                        set_dword,
+                        store_address!(rs1, imm),
+                        self.get_reg::<u64>(rs2),
+                        8
+                    ),
+                    Float(F::Sw(rs2, imm, rs1)) => {
+                        // The lower 32 bits, whether they're NaN-boxed or not
+                        let x = f32::from_bits(self.floats[rs2 as usize] as u32);
+                        store!(set_float, store_address!(rs1, imm), x, 4);
+                    }
+                    Float(F::Sd(rs2, imm, rs1)) => {
+                        let x = self.get_float::<f64>(rs2);
+                        store!(set_double, store_address!(rs1, imm), x, 8);
+                    }
+
+                    // Type SB + jumps
+                    Beq(rs1, rs2, label) => branch!(
+                        self.get_reg::<u64>(rs1) == self.get_reg::<u64>(rs2),
+                        self.pc,
+                        label
+                    ),
+                    Bne(rs1, rs2, label) => branch!(
+                        self.get_reg::<u64>(rs1) != self.get_reg::<u64>(rs2),
+                        self.pc,
+                        label
+                    ),
+                    Blt(rs1, rs2, label) => branch!(
+                        self.get_reg::<i64>(rs1) < self.get_reg::<i64>(rs2),
+                        self.pc,
+                        label
+                    ),
+                    Bge(rs1, rs2, label) => branch!(
+                        self.get_reg::<i64>(rs1) >= self.get_reg::<i64>(rs2),
+                        self.pc,
+                        label
+                    ),
+                    Bltu(rs1, rs2, label) => branch!(
+                        self.get_reg::<u64>(rs1) < self.get_reg::<u64>(rs2),
+                        self.pc,
+                        label
+                    ),
+                    Bgeu(rs1, rs2, label) => branch!(
+                        self.get_reg::<u64>(rs1) >= self.get_reg::<u64>(rs2),
+                        self.pc,
+                        label
+                    ),
+                    Jalr(rd, rs1, imm) => {
+                        // The target has to be read before rd is written, otherwise `jalr s0 s0 0` would
+                        // jump to the next instruction
+                        let target = jump_target!(self.get_reg::<u32>(rs1).wrapping_add(imm) as usize & !1);
+                        self.set_reg(rd, (self.pc + self.size_at(self.pc)) as u32);
+                        self.pc = target;
+                        continue;
+                    }
+                    Jal(rd, label) => {
+                        self.set_reg(rd, (self.pc + self.size_at(self.pc)) as u32);
+                        self.pc = label;
+                        continue;
+                    }
+
+                    // CSR. rs1 is read before rd is written, since they can be the same register
+                    CsrRw(rd, csr, rs1) => {
+                        let x = self.get_reg::<u32>(rs1);
+                        csr!(rd, csr, true, |old| x);
+                    }
+                    CsrRwi(rd, csr, imm) => csr!(rd, csr, true, |old| imm),
+                    CsrRs(rd, csr, rs1) => {
+                        let x = self.get_reg::<u32>(rs1);
+                        csr!(rd, csr, rs1 != 0, |old| old | x);
+                    }
+                    CsrRsi(rd, csr, imm) => csr!(rd, csr, imm != 0, |old| old | imm),
+                    CsrRc(rd, csr, rs1) => {
+                        let x = self.get_reg::<u32>(rs1);
+                        csr!(rd, csr, rs1 != 0, |old| old & !x);
+                    }
+                    CsrRci(rd, csr, imm) => csr!(rd, csr, imm != 0, |old| old & !imm),
+
+                    // Floating point
+                    Float(F::Add(rd, rs1, rs2, rm)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let x = self.raise(fpu::add(a, b, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::Sub(rd, rs1, rs2, rm)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let x = self.raise(fpu::sub(a, b, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::Mul(rd, rs1, rs2, rm)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let x = self.raise(fpu::mul(a, b, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::Div(rd, rs1, rs2, rm)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let x = self.raise(fpu::div(a, b, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::Equ(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let x = self.raise(fpu::equal(a, b));
+                        self.set_reg(rd, to_1(x));
+                    }
+                    Float(F::Le(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let x = self.raise(fpu::less(a, b, true));
+                        self.set_reg(rd, to_1(x));
+                    }
+                    Float(F::Lt(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let x = self.raise(fpu::less(a, b, false));
+                        self.set_reg(rd, to_1(x));
+                    }
+                    Float(F::Max(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let x = self.raise(fpu::max(a, b));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::Min(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let x = self.raise(fpu::min(a, b));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::SgnjS(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        self.set_float(rd, a.copysign(b));
+                    }
+                    Float(F::SgnjNS(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        self.set_float(rd, a.copysign(-b));
+                    }
+                    Float(F::SgnjXS(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+
+                        // I'm pretty sure this is correct (for most architectures anyway)
+                        self.set_float(rd, f32::from_bits(a.to_bits() ^ (b.to_bits() & (1 << 31))));
+                    }
+
+                    // I didn't even know this existed before this project
+                    Float(F::Class(rd, rs1)) => {
+                        self.set_reg(rd, util::class_mask(self.get_float(rs1)));
+                    }
+
+                    // Every integer and every float is exactly a double, so the conversions go through one
+                    Float(F::CvtSW(rd, rs1, rm)) => {
+                        let x = self.get_reg::<i32>(rs1) as f64;
+                        let x = self.raise(fpu::to_single(x, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::CvtSWu(rd, rs1, rm)) => {
+                        let x = self.get_reg::<u32>(rs1) as f64;
+                        let x = self.raise(fpu::to_single(x, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::CvtWS(rd, rs1, rm)) => {
+                        let x = self.get_float::<f32>(rs1) as f64;
+                        let x = self.raise(fpu::to_int(x, true, self.rounding(rm)));
+                        self.set_reg(rd, x);
+                    }
+                    Float(F::CvtWuS(rd, rs1, rm)) => {
+                        let x = self.get_float::<f32>(rs1) as f64;
+                        let x = self.raise(fpu::to_int(x, false, self.rounding(rm)));
+                        self.set_reg(rd, x);
+                    }
+
+                    // These move the bits as they are, without looking at the NaN-boxing
+                    Float(F::MvSX(rd, rs1)) => {
+                        self.set_float(rd, f32::from_bits(self.get_reg::<u32>(rs1)));
+                    }
+                    Float(F::MvXS(rd, rs1)) => {
+                        self.set_reg(rd, self.floats[rs1 as usize] as u32);
+                    }
+
+                    Float(F::Sqrt(rd, rs1, rm)) => {
+                        let x = self.get_float::<f32>(rs1);
+                        let x = self.raise(fpu::sqrt(x, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+
+                    // The negated ones negate the product, which is the same as negating `a`
+                    Float(F::Madd(rd, rs1, rs2, rs3, rm)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let c = self.get_float::<f32>(rs3);
+                        let x = self.raise(fpu::mul_add(a, b, c, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::Msub(rd, rs1, rs2, rs3, rm)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let c = self.get_float::<f32>(rs3);
+                        let x = self.raise(fpu::mul_add(a, b, -c, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::NMsub(rd, rs1, rs2, rs3, rm)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let c = self.get_float::<f32>(rs3);
+                        let x = self.raise(fpu::mul_add(-a, b, c, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::NMadd(rd, rs1, rs2, rs3, rm)) => {
+                        let (a, b) = (self.get_float::<f32>(rs1), self.get_float::<f32>(rs2));
+                        let c = self.get_float::<f32>(rs3);
+                        let x = self.raise(fpu::mul_add(-a, b, -c, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+
+                    // Double precision
+                    Float(F::AddD(rd, rs1, rs2, rm)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let x = self.raise(fpu::add(a, b, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::SubD(rd, rs1, rs2, rm)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let x = self.raise(fpu::sub(a, b, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::MulD(rd, rs1, rs2, rm)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let x = self.raise(fpu::mul(a, b, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::DivD(rd, rs1, rs2, rm)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let x = self.raise(fpu::div(a, b, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::EquD(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let x = self.raise(fpu::equal(a, b));
+                        self.set_reg(rd, to_1(x));
+                    }
+                    Float(F::LeD(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let x = self.raise(fpu::less(a, b, true));
+                        self.set_reg(rd, to_1(x));
+                    }
+                    Float(F::LtD(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let x = self.raise(fpu::less(a, b, false));
+                        self.set_reg(rd, to_1(x));
+                    }
+                    Float(F::MaxD(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let x = self.raise(fpu::max(a, b));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::MinD(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let x = self.raise(fpu::min(a, b));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::SgnjD(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        self.set_float(rd, a.copysign(b));
+                    }
+                    Float(F::SgnjND(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        self.set_float(rd, a.copysign(-b));
+                    }
+                    Float(F::SgnjXD(rd, rs1, rs2)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        self.set_float(rd, f64::from_bits(a.to_bits() ^ (b.to_bits() & (1 << 63))));
+                    }
+                    Float(F::ClassD(rd, rs1)) => {
+                        self.set_reg(rd, util::class_mask_d(self.get_float(rs1)));
+                    }
+                    Float(F::CvtDW(rd, rs1)) => {
+                        self.set_float(rd, self.get_reg::<i32>(rs1) as f64);
+                    }
+                    Float(F::CvtDWu(rd, rs1)) => {
+                        self.set_float(rd, self.get_reg::<u32>(rs1) as f64);
+                    }
+                    Float(F::CvtWD(rd, rs1, rm)) => {
+                        let x = self.get_float::<f64>(rs1);
+                        let x = self.raise(fpu::to_int(x, true, self.rounding(rm)));
+                        self.set_reg(rd, x);
+                    }
+                    Float(F::CvtWuD(rd, rs1, rm)) => {
+                        let x = self.get_float::<f64>(rs1);
+                        let x = self.raise(fpu::to_int(x, false, self.rounding(rm)));
+                        self.set_reg(rd, x);
+                    }
+                    Float(F::CvtSD(rd, rs1, rm)) => {
+                        let x = self.get_float::<f64>(rs1);
+                        let x = self.raise(fpu::to_single(x, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::CvtDS(rd, rs1)) => {
+                        let x = self.raise(fpu::to_double(self.get_float::<f32>(rs1)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::SqrtD(rd, rs1, rm)) => {
+                        let x = self.get_float::<f64>(rs1);
+                        let x = self.raise(fpu::sqrt(x, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+
+                    Float(F::MaddD(rd, rs1, rs2, rs3, rm)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let c = self.get_float::<f64>(rs3);
+                        let x = self.raise(fpu::mul_add(a, b, c, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::MsubD(rd, rs1, rs2, rs3, rm)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let c = self.get_float::<f64>(rs3);
+                        let x = self.raise(fpu::mul_add(a, b, -c, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::NMsubD(rd, rs1, rs2, rs3, rm)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let c = self.get_float::<f64>(rs3);
+                        let x = self.raise(fpu::mul_add(-a, b, c, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+                    Float(F::NMaddD(rd, rs1, rs2, rs3, rm)) => {
+                        let (a, b) = (self.get_float::<f64>(rs1), self.get_float::<f64>(rs2));
+                        let c = self.get_float::<f64>(rs3);
+                        let x = self.raise(fpu::mul_add(-a, b, -c, self.rounding(rm)));
+                        self.set_float(rd, x);
+                    }
+
+                    // Pseudoinstructions
+                    Li(rd, imm) => self.set_reg(rd, imm),
+                    Auipc(rd, imm) => self.set_reg(rd, (self.pc as u32).wrapping_add(imm)),
+                    Mv(rd, rs1) => self.set_reg(rd, self.get_reg::<u64>(rs1)),
+                    Ret => {
+                        self.pc = jump_target!(self.registers[1] as usize & !1);
+                        continue;
+                    }
+                    URet => {
+                        use crate::parser::register_names::UEPC_INDEX;
+                        self.pc = self.status[UEPC_INDEX as usize] as usize;
+                        continue;
+                    }
+                    // Vectors
+                    Vector(V::Setvli(rd, rs1, vtype)) => {
+                        // Without rs1, it's as many elements as fit, or the same `vl` if there's no rd
+                        let avl = match (rs1, rd) {
+                            (0, 0) => self.vectors.vl,
+                            (0, _) => u32::MAX,
+                            _ => self.get_reg::<u64>(rs1).min(u32::MAX as u64) as u32,
+                        };
+                        let vl = self.vectors.set_vtype(avl, vtype);
+                        self.set_reg(rd, vl);
+                    }
+                    Vector(V::Le32(vd, rs1)) => {
+                        let addr = self.get_reg::<u32>(rs1) as usize;
+                        let memory = &self.memory;
+                        let elements = self.vectors.elements_mut(vd);
+                        vector!(elements.map(|v| v.iter_mut().enumerate().for_each(|(i, x)| *x = memory.get_word(addr + 4 * i))));
+                    }
+                    Vector(V::Se32(vs3, rs1)) => {
+                        let addr = self.get_reg::<u32>(rs1) as usize;
+                        let elements = self.vectors.elements(vs3).map(<[u32]>::to_vec);
+                        vector!(elements);
+                        let elements = elements.unwrap_or_default();
+                        if self.memory.is_read_only(addr, 4 * elements.len()) {
+                            if self.can_trap() {
+                                self.trap(7);
+                                continue;
+                            }
+                            let segment = self.memory.segment(addr).unwrap_or(Segment::Data);
+                            eprintln!("Tried to write to read-only {} at {:#x}, from {:#x}", segment, addr, self.pc);
+                            return 1;
+                        }
+                        for (i, &x) in elements.iter().enumerate() {
+                            self.memory.set_word(addr + 4 * i, x);
+                        }
+                    }
+                    Vector(V::Add(vd, vs2, vs1)) => vector!(self.vectors.binary(vd, vs2, vs1, u32::wrapping_add)),
+                    Vector(V::Mul(vd, vs2, vs1)) => vector!(self.vectors.binary(vd, vs2, vs1, u32::wrapping_mul)),
+                    Vector(V::RedSum(vd, vs2, vs1)) => vector!(self.vectors.reduce(vd, vs2, vs1, u32::wrapping_add)),
+                    Vector(V::RedMax(vd, vs2, vs1)) => {
+                        vector!(self.vectors.reduce(vd, vs2, vs1, |a, b| (a as i32).max(b as i32) as u32))
+                    }
+                    Vector(V::RedMaxu(vd, vs2, vs1)) => vector!(self.vectors.reduce(vd, vs2, vs1, u32::max)),
+                    Vector(V::RedMin(vd, vs2, vs1)) => {
+                        vector!(self.vectors.reduce(vd, vs2, vs1, |a, b| (a as i32).min(b as i32) as u32))
+                    }
+                    Vector(V::RedMinu(vd, vs2, vs1)) => vector!(self.vectors.reduce(vd, vs2, vs1, u32::min)),
+                    Vector(V::MvXS(rd, vs2)) => {
+                        let x = self.vectors.scalar(vs2);
+                        vector!(x);
+                        self.set_reg(rd, x.unwrap_or_default());
+                    }
+                    Vector(V::MvSX(vd, rs1)) => vector!(self.vectors.set_scalar(vd, self.get_reg(rs1))),
+
+                    MRet => {
+                        privileged!(None);
+                        self.mret();
+                        continue;
+                    }
+                    // There are no interrupts to wait for
+                    Wfi => {}
+
+                    Illegal(_) => {
+                        if self.on_illegal == Some(OnIllegal::Skip) {
+                            self.skip_illegal();
+                        } else if self.can_trap() {
+                            self.trap(2); // illegal instruction exception
+                            continue;
+                        } else {
+                            eprintln!(
+                                "Unknown instruction `{}` at {:#x}, and there's no trap handler to deal with it",
+                                self.illegal_line(),
+                                self.pc
+                            );
+                            return 1;
+                        }
+                    }
+                },
+            }
+
+            self.pc += self.size_at(self.pc);
+        }
+    }
+
+    fn illegal_line(&self) -> &str {
+        match &self.code[self.slot(self.pc)] {
+            parser::Instruction::Illegal(line) if line.is_empty() => "(the middle of a 4-byte instruction)",
+            parser::Instruction::Illegal(line) => line,
+            _ => unreachable!("illegal_line should only be called on an Illegal instruction"),
+        }
+    }
+
+    /// Reads the null terminated string at `start`. It's UTF-8, like the `.string`s of the program,
+    /// so accents and box drawing characters come out right.
+    fn read_string(&self, start: usize) -> String {
+        let bytes: Vec<u8> = (start..)
+            .map(|i| self.memory.get_byte(i))
+            .take_while(|&b| b != 0)
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn skip_illegal(&mut self) {
+        self.stats.illegal_skipped += 1;
+        if self.warned_illegal.insert(self.pc) {
+            eprintln!(
+                "Warning: skipping unknown instruction `{}` at {:#x}",
+                self.illegal_line(),
+                self.pc
+            );
+        }
+    }
+
+    fn ecall(&mut self) -> EcallSignal {
+        use rand::{thread_rng, Rng};
+
+        // RV32E doesn't have a7, so the number goes in t0
+        let a7 = self.get_reg::<u32>(if self.rv32e { 5 } else { 17 });
+
+        if files::handle_ecall(
+            a7,
+            &mut self.open_files,
+            &mut self.registers,
+            &mut self.memory,
+        ) {
+            return EcallSignal::Nothing;
+        }
+
+        if let Some(signal) = self.os_ecall(a7) {
+            return signal;
+        }
+
+        if let Some(signal) = self.region_ecall(a7) {
+            return signal;
+        }
+
+        if let Some(signal) = self.heap_ecall(a7) {
+            return signal;
+        }
+
+        match a7 {
+            10 => return self.exit_program(0),
+            93 => return self.exit_program(self.get_reg::<i32>(10)),
+            110 => loop {
+                std::thread::sleep(time::Duration::from_millis(500));
+            },
+            1 => {
+                // print int
+                self.console.print(self.get_reg::<i64>(10));
+            }
+            4 => {
+                // print string
+                let s = self.read_string(self.get_reg::<u32>(10) as usize);
+                self.console.print(s);
+            }
+            5 => {
+                // read int
+                let buf = self.read_line();
+                self.set_reg(10, util::parse_input::<i32>(&buf));
+            }
+            2 => {
+                // print float
+                self.console.print(util::float_to_string(self.get_float::<f32>(10)));
+            }
+            3 => {
+                // print double
+                self.console.print(util::float_to_string(self.get_float::<f64>(10)));
+            }
+            6 => {
+                // read float
+                let buf = self.read_line();
+                self.set_float(10, util::parse_input::<f32>(&buf));
+            }
+            7 => {
+                // read double
+                let buf = self.read_line();
+                self.set_float(10, util::parse_input::<f64>(&buf));
+            }
+            11 => {
+                // print char
+                let c = self.get_reg::<u32>(10);
+                self.console.print(char::from_u32(c).unwrap_or(c as u8 as char));
+            }
+
+            30 => {
+                // get time
+                let ms = self.input("time", |_| {
+                    let epoch = time::SystemTime::UNIX_EPOCH;
+                    let duration = time::SystemTime::now().duration_since(epoch).unwrap();
+                    duration.as_millis() as u64
+                });
+                self.set_reg(10, ms as u32);
+                self.set_reg(11, (ms >> 32) as u32);
+            }
+
+            31 | 33 => {
+                // midi stuff, but nops for now
+            }
+
+            32 => {
+                // sleep ms
+                let t = self.get_reg::<u32>(10);
+                std::thread::sleep(time::Duration::from_millis(t as u64));
+            }
+
+            34 => {
+                // print hex int
+                if self.rv64 {
+                    self.console.print(format_args!("{:#X}", self.get_reg::<u64>(10)));
+                } else {
+                    self.console.print(format_args!("{:#X}", self.get_reg::<u32>(10)));
+                }
+            }
+
+            36 => {
+                // print unsigned int
+                if self.rv64 {
+                    self.console.print(self.get_reg::<u64>(10));
+                } else {
+                    self.console.print(self.get_reg::<u32>(10));
+                }
+            }
+
+            // RNG stuff
+            40 => {
+                // TODO: seed the RNG
+            }
+            41 => {
+                // rand int
+                let x = self.input("rand", |_| thread_rng().gen::<i32>());
+                self.set_reg(10, x);
+            }
+            42 => {
+                // rand int in [0, a1)
+                let upper = self.get_reg::<u32>(11);
+                let x = self.input("rand", |_| thread_rng().gen_range::<u32, _, _>(0, upper));
+                self.set_reg(10, x);
+            }
+            43 => {
+                // rand float in [0, 1)
+                let x = self.input("rand", |_| thread_rng().gen_range(0f32, 1f32));
+                self.set_float(10, x);
+            }
+
+            48 | 148 => {
+                // clear screen
+                let color = self.get_reg::<u8>(10); // a0
+                let frame_select = self.get_reg::<u32>(11); // a1
+
+                let mut mmio = self.memory.mmio.lock().unwrap();
+                let frame = if frame_select == 0 { FRAME_0 } else { FRAME_1 };
+                for x in &mut mmio[frame..frame + WIDTH * HEIGHT] {
+                    *x = color;
+                }
+            }
+
+            // These two should only be here temporarily for convenience
+            0xff00 => {
+                self.set_float(10, self.get_float::<f32>(10).sin());
+            }
+            0xff01 => {
+                self.set_float(10, self.get_float::<f32>(10).cos());
+            }
+
+            // Does the user want to handle this ecall?
+            _x if self.can_trap() => {
+                self.trap(self.ecall_cause()); // ecall exception
+                return EcallSignal::Continue;
+            }
+
+            x => unimplemented!("Ecall {} is not implemented", x),
+        }
+
+        EcallSignal::Nothing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(code: &str) -> Simulator {
+        let mut sim = Simulator::new().load_str(code).unwrap();
+        sim.run();
+        sim
+    }
+
+    #[test]
+    fn test_mulh() {
+        let sim = run(
+            "li t0, -2\nli t1, 3\nli t2, 0x80000000
+            mulh a0, t0, t1\nmulhu a1, t0, t1\nmulhsu a2, t0, t1\nmulhsu a3, t1, t0\nmulh a4, t2, t2",
+        );
+        // -6, 0x2_ffff_fffa, -6 again with t1 unsigned, and 0x2_ffff_fffa with t0 unsigned
+        assert_eq!(sim.get_reg::<i32>(10), -1);
+        assert_eq!(sim.get_reg::<u32>(11), 2);
+        assert_eq!(sim.get_reg::<i32>(12), -1);
+        assert_eq!(sim.get_reg::<u32>(13), 2);
+        // (-2^31)^2 = 2^62
+        assert_eq!(sim.get_reg::<u32>(14), 0x4000_0000);
+    }
+
+    #[test]
+    fn test_doubles() {
+        let sim = run(
+            ".data\nx: .double 1.5\ny: .double 0\n.text
+            la t0, x\nfld ft0, 0(t0)\nfadd.d ft1, ft0, ft0\nfsd ft1, 8(t0)\nfld ft2, 8(t0)
+            fcvt.s.d ft3, ft2\nfcvt.w.d a0, ft2\nfadd.s ft4, ft1, ft1\nfeq.s a1, ft4, ft4",
+        );
+        assert_eq!(sim.get_float::<f64>(2), 3.0);
+        assert_eq!(sim.get_reg::<u32>(10), 3);
+        // A single is NaN-boxed, with the upper 32 bits set
+        assert_eq!(sim.floats[3], 0xffff_ffff_0000_0000 | 3f32.to_bits() as u64);
+        // and a double read as a single isn't boxed, so it's NaN
+        assert_eq!(sim.get_reg::<u32>(11), 0);
+    }
+
+    #[test]
+    fn test_fused() {
+        let sim = run(
+            "li t0, 2\nfcvt.s.w ft0, t0\nli t0, 3\nfcvt.s.w ft1, t0\nli t0, 1\nfcvt.s.w ft2, t0
+            fmadd.s fa0, ft0, ft1, ft2\nfmsub.s fa1, ft0, ft1, ft2\nfnmsub.s fa2, ft0, ft1, ft2
+            fnmadd.s fa3, ft0, ft1, ft2\nfcvt.d.s ft0, ft0\nfcvt.d.s ft1, ft1\nfcvt.d.s ft2, ft2
+            fnmadd.d fa4, ft0, ft1, ft2",
+        );
+        // 2*3 + 1, 2*3 - 1, -(2*3) + 1 and -(2*3) - 1
+        let results: Vec<f32> = (10..14).map(|i| sim.get_float(i)).collect();
+        assert_eq!(results, [7.0, 5.0, -5.0, -7.0]);
+        assert_eq!(sim.get_float::<f64>(14), -7.0);
+    }
+
+    #[test]
+    fn test_csrs() {
+        let code = "li t0, 5\ncsrw t0, 0x040\ncsrr a0, uscratch\ncsrrwi a1, uscratch, 9\ncsrr a2, 0x040
+            csrrs a3, cycle, zero\ncsrrci a4, time, 0\nli a5, 1\ncsrw t0, cycle\nli a5, 2";
+        let mut sim = Simulator::new().load_str(code).unwrap();
+        // The counters can be read, but writing to one stops the program
+        assert_eq!(sim.run(), 1);
+        assert_eq!(sim.get_reg::<u32>(15), 1);
+        // `uscratch` is the CSR 0x040
+        assert_eq!((sim.get_reg::<u32>(10), sim.get_reg::<u32>(11), sim.get_reg::<u32>(12)), (5, 5, 9));
+    }
+
+    #[test]
+    fn test_counters() {
+        let sim = run(
+            "rdinstret a3\nnop\nnop\nrdcycle a1\nrdinstreth a2\nrdtime s0\nli a0, 20\nli a7, 32\necall
+            rdtime s1\nrdinstret a0",
+        );
+        // The instructions before the one reading the counter
+        assert_eq!(sim.get_reg::<u32>(13), 0);
+        assert_eq!(sim.get_reg::<u32>(11), 3);
+        assert_eq!(sim.get_reg::<u32>(12), 0);
+        assert_eq!(sim.get_reg::<u32>(10), 10);
+        // The time is in milliseconds
+        assert!(sim.get_reg::<u32>(9) - sim.get_reg::<u32>(8) >= 20);
+    }
+
+    #[test]
+    fn test_zba() {
+        let sim = run("li t0, 5\nli t1, 0x1000\nsh1add a0, t0, t1\nsh2add a1, t0, t1\nsh3add a2, t0, t1");
+        // The address of the element 5 of arrays of halves, words and doubles at 0x1000
+        assert_eq!(sim.get_reg::<u32>(10), 0x100a);
+        assert_eq!(sim.get_reg::<u32>(11), 0x1014);
+        assert_eq!(sim.get_reg::<u32>(12), 0x1028);
+    }
+
+    #[test]
+    fn test_zbs() {
+        let sim = run(
+            "li t0, 0xf0\nli t1, 33\nbset a0, t0, t1\nbclri a1, t0, 4\nbinv a2, t0, t1\nbinvi a3, t0, 31
+            bext a4, t0, t1\nbexti a5, t0, 7",
+        );
+        // Only the lower 5 bits of the index count, so 33 is the bit 1
+        assert_eq!(sim.get_reg::<u32>(10), 0xf2);
+        assert_eq!(sim.get_reg::<u32>(11), 0xe0);
+        assert_eq!(sim.get_reg::<u32>(12), 0xf2);
+        assert_eq!(sim.get_reg::<u32>(13), 0x8000_00f0);
+        assert_eq!((sim.get_reg::<u32>(14), sim.get_reg::<u32>(15)), (0, 1));
+    }
+
+    #[test]
+    fn test_zicond() {
+        let sim = run(
+            "li t0, 7\nli t1, 0\nli t2, 1\nczero.eqz a0, t0, t1\nczero.eqz a1, t0, t2\nczero.nez a2, t0, t1
+            czero.nez a3, t0, t2",
+        );
+        // `czero.eqz` zeroes when the condition is 0, `czero.nez` when it isn't
+        let results: Vec<u32> = (10..14).map(|i| sim.get_reg(i)).collect();
+        assert_eq!(results, [0, 7, 7, 0]);
+    }
+
+    #[test]
+    fn test_far_call() {
+        // `far` is 2 MiB away, too far for a `jal`
+        let mut sim = Simulator::new()
+            .load_str("li a0, 1\ncall far\naddi a0, a0, 1\nli a7, 93\necall\n.text 0x200000\nfar: slli a0, a0, 3\nret")
+            .unwrap();
+        assert_eq!(sim.run(), 9);
+        assert_eq!(sim.code[1..3], [parser::Instruction::Auipc(1, 0x20_0000), parser::Instruction::Jalr(1, 1, (-4i32) as u32)]);
+        assert!(sim.code[1..3].iter().enumerate().all(|(i, x)| parser::encode(x, 4 + i * 4).is_some()));
+    }
+
+    #[test]
+    fn test_rv64() {
+        let code = "li t0, 0x123456789abcdef0\nli t1, 0xffffffff\nli t2, -0x80000001\ncsrr a0, misa
+            li a7, 10\necall";
+        let mut sim = Simulator::new()
+            .rv64(true)
+            .load_str(code)
+            .unwrap();
+        sim.run();
+        assert_eq!(sim.get_reg::<u64>(5), 0x1234_5678_9abc_def0);
+        assert_eq!(sim.get_reg::<u64>(6), 0xffff_ffff);
+        assert_eq!(sim.get_reg::<i64>(7), -0x8000_0001);
+        // MXL = 2, and the extensions are the same as in RV32
+        assert_eq!(sim.get_reg::<u64>(10), 0x8000_0000_0030_1129);
+    }
+}
@@ -0,0 +1,266 @@
+//!
+//! A tiny "operating system" mode. A program can load other programs and run them, taking turns
+//! like a scheduler would, which is the kind of thing an OS course asks for.
+//!
+//! Every program gets its own registers and its own region of memory, with its `.data` at the
+//! start and the stack at the end. The code of all programs lives in the same address space,
+//! one after the other, so they can't overwrite each other's instructions.
+//!
+//! When a program runs another, it stops until the other one yields or exits, and then continues
+//! right after its "run program" ecall, with `a0` telling what happened:
+//!
+//! ```
+//! loop:
+//!     mv a0, s0         # the id returned by the "load program" ecall
+//!     li a7, 201        # run program
+//!     ecall
+//!     beqz a0, loop     # 0 = yielded, 1 = exited (with the exit code in a1)
+//! ```
+//!
+
+use std::path::PathBuf;
+
+use super::{into_register::FloatRegister, EcallSignal, Simulator, MMIO_START};
+use crate::parser::{self, Includable, RISCVParser};
+
+/// Size of the memory region of a loaded program
+const PROGRAM_MEMORY_SIZE: usize = 0x0010_0000;
+
+/// a0 = address of the path of the program. Returns its id in a0, or -1 if it couldn't be loaded
+const LOAD_PROGRAM: u32 = 200;
+/// a0 = id of the program to run. Returns 0 in a0 if it yielded, or 1 if it exited, with the exit
+/// code in a1. Returns -1 if the program can't run, because it exited or is already running
+const RUN_PROGRAM: u32 = 201;
+/// Gives control back to the program that ran this one
+const YIELD: u32 = 202;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Loaded or yielded, can be run
+    Ready,
+    /// Running, or waiting for a program it ran
+    Running,
+    Exited,
+}
+
+/// The registers a program had when it stopped running
+struct Process {
+    registers: [u64; 32],
+    floats: [u64; 32],
+    pc: usize,
+
+    /// The program that ran this one, which will continue when this one yields or exits
+    parent: Option<usize>,
+    state: State,
+}
+
+/// All the programs we know about. The first one is the program FPGRARS was started with.
+pub struct Processes {
+    list: Vec<Process>,
+    current: usize,
+}
+
+impl Processes {
+    /// The id of the program that's running
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// How many programs were loaded, counting the first one
+    pub fn count(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn new() -> Self {
+        let main = Process {
+            registers: [0; 32],
+            floats: [0f32.into_float_reg(); 32],
+            pc: 0,
+            parent: None,
+            state: State::Running,
+        };
+
+        Self {
+            list: vec![main],
+            current: 0,
+        }
+    }
+}
+
+impl Simulator {
+    /// Tries to handle an ecall of the OS mode, returns `None` if it isn't one
+    pub(super) fn os_ecall(&mut self, a7: u32) -> Option<EcallSignal> {
+        match a7 {
+            LOAD_PROGRAM => {
+                let id = self.load_program();
+                self.set_reg(10, id);
+                Some(EcallSignal::Nothing)
+            }
+            RUN_PROGRAM => Some(self.run_program(self.get_reg::<u32>(10) as usize)),
+            YIELD => Some(self.yield_program()),
+            _ => None,
+        }
+    }
+
+    /// Exits the current program. If it was run by another program, that one continues,
+    /// otherwise the simulation ends
+    pub(super) fn exit_program(&mut self, code: i32) -> EcallSignal {
+        let current = self.processes.current;
+        let parent = match self.processes.list[current].parent {
+            Some(parent) => parent,
+            None => return EcallSignal::Exit(code),
+        };
+
+        self.processes.list[current].state = State::Exited;
+        self.switch_to(parent);
+        self.set_reg(10, 1);
+        self.set_reg(11, code);
+        EcallSignal::Continue
+    }
+
+    fn load_program(&mut self) -> i32 {
+        let path = self.read_string(self.get_reg::<u32>(10) as usize);
+
+        let data_base = self.memory.end();
+        if data_base + PROGRAM_MEMORY_SIZE > MMIO_START {
+            eprintln!("Couldn't load the program `{}`: out of memory", path);
+            return -1;
+        }
+
+        let options = parser::Options {
+            permissive: self.on_illegal.is_some(),
+            text_base: self.text_base + self.code.len() * self.slot_size(),
+            data_base,
+            werror: self.werror,
+            warn_unused: self.warn_unused,
+            strict: self.strict,
+            rv64: self.rv64,
+            rv32e: self.rv32e,
+            rvc: self.rvc,
+            entry: None,
+        };
+
+        let parsed = parser::file_lines(&path).and_then(|lines| {
+            self.preprocess(lines.parse_includes(PathBuf::from(&path)))
+                .parse_riscv(PROGRAM_MEMORY_SIZE, &options)
+        });
+
+        let parser::Parsed { code, code_pos, data, read_only, symbols, warnings, entry, exit, data_end } = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("Couldn't load the program `{}`:\n{}", path, e);
+                return -1;
+            }
+        };
+
+        for warning in &warnings {
+            eprintln!("{}\n", warning);
+        }
+
+        let mut registers = [0; 32];
+        registers[2] = (data_base + data.len() - 4) as u64; // stack pointer
+        if entry.is_some() {
+            registers[1] = exit as u64; // so returning from the entry exits
+        }
+
+        self.processes.list.push(Process {
+            registers,
+            floats: [0f32.into_float_reg(); 32],
+            pc: entry.unwrap_or(options.text_base),
+            parent: None,
+            state: State::Ready,
+        });
+
+        let code_start = self.code.len();
+        self.code.extend(code);
+        self.code_pos.extend(code_pos);
+        self.compile_code(code_start..self.code.len());
+        self.symbols.extend(symbols);
+        self.warnings.extend(warnings);
+        self.memory.push_program(data, data_end);
+        self.memory.read_only.extend(read_only);
+        if self.code_in_memory() {
+            self.write_code(code_start);
+        } else {
+            self.map_code();
+        }
+
+        self.processes.list.len() as i32 - 1
+    }
+
+    fn run_program(&mut self, id: usize) -> EcallSignal {
+        let can_run = self
+            .processes
+            .list
+            .get(id)
+            .is_some_and(|p| p.state == State::Ready);
+
+        if !can_run {
+            self.set_reg(10, -1);
+            return EcallSignal::Nothing;
+        }
+
+        let process = &mut self.processes.list[id];
+        process.parent = Some(self.processes.current);
+        process.state = State::Running;
+        self.switch_to(id);
+        EcallSignal::Continue
+    }
+
+    fn yield_program(&mut self) -> EcallSignal {
+        let current = self.processes.current;
+        let parent = match self.processes.list[current].parent.take() {
+            Some(parent) => parent,
+            None => return EcallSignal::Nothing, // nobody to yield to
+        };
+
+        self.processes.list[current].state = State::Ready;
+        self.switch_to(parent);
+        self.set_reg(10, 0);
+        EcallSignal::Continue
+    }
+
+    /// Saves the context of the current program, which will continue after the current ecall,
+    /// and restores the context of program `id`
+    fn switch_to(&mut self, id: usize) {
+        let current = &mut self.processes.list[self.processes.current];
+        current.registers = self.registers;
+        current.floats = self.floats;
+        current.pc = self.pc + 4;
+
+        let next = &self.processes.list[id];
+        self.registers = next.registers;
+        self.floats = next.floats;
+        self.pc = next.pc;
+        self.processes.current = id;
+        self.stack = self.memory.stack(id);
+        // Otherwise an `sc.w` could succeed on a reservation made by another program
+        self.reservation = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_programs() {
+        let guest = std::env::temp_dir().join(format!("fpgrars-guest-{}.s", std::process::id()));
+        std::fs::write(&guest, "li s0, 10\nli a7, 202\necall\naddi a0, s0, 1\nli a7, 93\necall").unwrap();
+
+        // Runs the guest twice: the first time it yields, the second time it exits with 11
+        let main = format!(
+            ".data\npath: .string \"{}\"\n.text\nla a0, path\nli a7, 200\necall\nmv s0, a0
+            li s1, 99\nli a7, 201\necall\nmv s2, a0\nmv a0, s0\nli a7, 201\necall
+            add a0, a0, a1\nadd a0, a0, s2\nadd a0, a0, s1\nli a7, 93\necall",
+            guest.display()
+        );
+
+        let mut sim = Simulator::new().load_str(&main).unwrap();
+        let exit_code = sim.run();
+        std::fs::remove_file(&guest).unwrap();
+
+        // 1 (exited) + 11 (its exit code) + 0 (yielded) + 99 (s1 survived the guest)
+        assert_eq!(exit_code, 111);
+    }
+}
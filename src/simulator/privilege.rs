@@ -0,0 +1,213 @@
+//!
+//! Traps and the two privilege levels we have, machine and user.
+//!
+//! Programs start in machine mode, so everything works like before unless they ask otherwise. A
+//! program that sets `mtvec` gets its exceptions there instead of at `utvec`, and can drop to user
+//! mode with `mret`:
+//!
+//! ```
+//!     la t0, handler
+//!     csrw t0, mtvec
+//!     la t0, user_code
+//!     csrw t0, mepc
+//!     li t0, 0x1800     # mstatus.MPP = user
+//!     csrc t0, mstatus
+//!     mret
+//! ```
+//!
+//! In user mode, `ecall` always traps to the handler, with `mcause` = 8, and the machine CSRs and
+//! `mret` are illegal instructions. In machine mode, `ecall` is still the RARS service, so the
+//! handler can print and read things for the user code.
+//!
+
+use super::Simulator;
+use crate::parser::register_names::*;
+
+/// Bits of `mstatus` that we have: MIE, MPIE and MPP
+const MIE: u32 = 1 << 3;
+const MPIE: u32 = 1 << 7;
+const MPP: u32 = 0b11 << 11;
+
+impl Simulator {
+    /// Whether the program set a trap handler at `mtvec`, or enabled the one at `utvec`
+    pub(super) fn can_trap(&self) -> bool {
+        self.status[MTVEC_INDEX as usize] != 0 || self.status[USTATUS_INDEX as usize] & 1 == 1
+    }
+
+    /// Jumps to the trap handler at `mtvec`, which can return to the current instruction with
+    /// `mret`. Without one, it's the handler at `utvec`, which returns with `uret`.
+    pub(super) fn trap(&mut self, cause: u32) {
+        self.reservation = None;
+
+        let mtvec = self.status[MTVEC_INDEX as usize];
+        if mtvec == 0 {
+            self.status[UCAUSE_INDEX as usize] = cause;
+            self.status[UEPC_INDEX as usize] = self.pc as u32; // set uret location
+            self.pc = self.status[UTVEC_INDEX as usize] as usize; // jump to utvec
+            return;
+        }
+
+        self.status[MCAUSE_INDEX as usize] = cause;
+        self.status[MEPC_INDEX as usize] = self.pc as u32;
+
+        // Remember where we came from, and disable interrupts while in the handler
+        let mstatus = self.status[MSTATUS_INDEX as usize];
+        let mpie = if mstatus & MIE != 0 { MPIE } else { 0 };
+        let mpp = if self.user_mode { 0 } else { MPP };
+        self.status[MSTATUS_INDEX as usize] = (mstatus & !(MIE | MPIE | MPP)) | mpie | mpp;
+
+        self.user_mode = false;
+        self.pc = (mtvec & !0b11) as usize; // only direct mode, there are no interrupts
+    }
+
+    /// Returns from the trap handler to `mepc`, in the privilege level saved in `mstatus`
+    pub(super) fn mret(&mut self) {
+        let mstatus = self.status[MSTATUS_INDEX as usize];
+        let mie = if mstatus & MPIE != 0 { MIE } else { 0 };
+        self.status[MSTATUS_INDEX as usize] = (mstatus & !(MIE | MPP)) | mie | MPIE;
+
+        self.user_mode = mstatus & MPP == 0;
+        self.pc = self.status[MEPC_INDEX as usize] as usize;
+    }
+
+    /// Whether an `ecall` should go to the trap handler before being a RARS service
+    pub(super) fn ecall_traps(&self) -> bool {
+        self.user_mode && self.status[MTVEC_INDEX as usize] != 0
+    }
+
+    /// The cause of an `ecall` exception, which tells the privilege level it came from
+    pub(super) fn ecall_cause(&self) -> u32 {
+        match (self.user_mode, self.status[MTVEC_INDEX as usize]) {
+            (false, mtvec) if mtvec != 0 => 11,
+            _ => 8,
+        }
+    }
+
+    /// Whether the instruction at pc can't run in the current privilege level, like an `mret` or
+    /// accessing `mstatus` in user mode
+    pub(super) fn is_privileged(&self, csr: Option<u8>) -> bool {
+        self.user_mode && csr.is_none_or(is_machine_only)
+    }
+
+    /// Writes to `mstatus`, keeping only the bits we have. MPP only holds machine or user mode.
+    pub(super) fn set_mstatus(&mut self, x: u32) {
+        let mpp = if x & MPP == MPP { MPP } else { 0 };
+        self.status[MSTATUS_INDEX as usize] = (x & (MIE | MPIE)) | mpp;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(code: &str) -> (Simulator, i32) {
+        let mut sim = Simulator::new().load_str(code).unwrap();
+        let exit_code = sim.run();
+        (sim, exit_code)
+    }
+
+    #[test]
+    fn test_user_ecall() {
+        // The user code asks for exit 7, the handler exits with 100 + the cause instead
+        let (sim, exit_code) = run(
+            "la t0, handler\ncsrw t0, mtvec\nla t0, user\ncsrw t0, mepc\nli t0, 0x1800
+            csrc t0, mstatus\nmret
+            user: li a0, 7\nli a7, 93\necall
+            handler: csrr a0, mcause\naddi a0, a0, 100\necall",
+        );
+        assert_eq!(exit_code, 108);
+        assert!(!sim.user_mode);
+        // Came from user mode, so MPP is 0
+        assert_eq!(sim.status[MSTATUS_INDEX as usize] & MPP, 0);
+    }
+
+    #[test]
+    fn test_mret_to_machine() {
+        // With MPP = machine, mret stays there and the ecall is a service
+        let (sim, exit_code) = run(
+            "la t0, next\ncsrw t0, mepc\nli t0, 0x1880\ncsrs t0, mstatus\nmret\nli a0, 1
+            next: csrr a0, mstatus\nli a7, 93\necall",
+        );
+        // MIE = MPIE = 1 after the mret
+        assert_eq!(exit_code, 0x88);
+        assert!(!sim.user_mode);
+    }
+
+    #[test]
+    fn test_user_privileged() {
+        // Reading mstatus in user mode is an illegal instruction, and so is mret
+        let handler = "handler: csrr a0, mcause\ncsrr a1, mepc\nli a7, 93\necall";
+        let user = "la t0, handler\ncsrw t0, mtvec\nla t0, user\ncsrw t0, mepc\nmret\nuser:";
+
+        let (sim, exit_code) = run(&format!("{}\ncsrr a0, mstatus\n{}", user, handler));
+        assert_eq!(exit_code, 2);
+        assert_eq!(sim.get_reg::<u32>(11), 0x1c);
+
+        let (_, exit_code) = run(&format!("{}\nmret\n{}", user, handler));
+        assert_eq!(exit_code, 2);
+
+        // The user CSRs are still fine
+        let (_, exit_code) = run(&format!("{}\ncsrr a0, cycle\nli a0, 5\nebreak\n{}", user, handler));
+        assert_eq!(exit_code, 3);
+    }
+
+    #[test]
+    fn test_misaligned_jump() {
+        // jalr clears the bit 0, but the bit 1 makes it misaligned
+        let code = "la t0, handler\ncsrw t0, mtvec\nla t0, next\njalr ra, t0, 3\nnext: li a0, 7\nli a7, 93\necall
+            handler: csrr a0, mcause\ncsrr a1, mepc\nli a7, 93\necall";
+        let load = |check_jumps| {
+            Simulator::new()
+                .check_jumps(check_jumps)
+                .load_str(code)
+                .unwrap()
+        };
+
+        // Without the check, it runs from the middle of the `li`
+        assert_eq!(load(false).run(), 7);
+
+        let mut sim = load(true);
+        assert_eq!(sim.run(), 0);
+        assert_eq!(sim.get_reg::<u32>(11), 20);
+        assert_eq!(sim.get_reg::<u32>(1), 0);
+    }
+
+    #[test]
+    fn test_misaligned_access() {
+        let handler = "li a7, 93\necall\nhandler: csrr a0, mcause\ncsrr a1, mepc\nli a7, 93\necall";
+        let run = |access: &str, strict_alignment| {
+            let code = format!("la t0, handler\ncsrw t0, mtvec\nli t0, 2\nli a0, 0\n{}\n{}", access, handler);
+            let mut sim = Simulator::new()
+                .strict_alignment(strict_alignment)
+                .load_str(&code)
+                .unwrap();
+            (sim.run(), sim.get_reg::<u32>(11))
+        };
+
+        assert_eq!(run("lw a0, 0(t0)", false), (0, 0));
+        assert_eq!(run("lw a0, 0(t0)", true), (4, 20));
+        assert_eq!(run("lh a0, 0(t0)", true), (0, 0));
+        assert_eq!(run("sw zero, 1(t0)", true), (6, 20));
+        assert_eq!(run("sb zero, 1(t0)", true), (0, 0));
+        assert_eq!(run("flw ft0, 1(t0)", true), (4, 20));
+    }
+
+    #[test]
+    fn test_memory_fault() {
+        let handler = "li a7, 93\necall\nhandler: csrr a0, mcause\ncsrr a1, mepc\nli a7, 93\necall";
+        let run = |access: &str, trap| {
+            let setup = if trap { "la t0, handler\ncsrw t0, mtvec" } else { "nop\nnop\nnop" };
+            let code = format!("{}\nli t0, -8\nli a0, 0\n{}\n{}", setup, access, handler);
+            let mut sim = Simulator::new().load_str(&code).unwrap();
+            (sim.run(), sim.get_reg::<u32>(11))
+        };
+
+        assert_eq!(run("lw a0, 0(t0)", true), (5, 20));
+        assert_eq!(run("sb zero, 0(t0)", true), (7, 20));
+        assert_eq!(run("amoadd.w a0, zero, (t0)", true), (7, 20));
+        assert_eq!(run("lw a0, 0(t0)", false), (1, 0));
+        // Only the bytes past the end of the stack are outside of the memory
+        assert_eq!(run("lw a0, 0(sp)", true), (0, 0));
+        assert_eq!(run("lw a0, 2(sp)", true), (5, 20));
+    }
+}
@@ -0,0 +1,265 @@
+//!
+//! A profiler, for `--profile`: counts how many instructions ran on each line of the source and in
+//! each function, and shows the hot spots when the program exits.
+//!
+//! Assembly doesn't say where a function begins or ends, so a function is whatever a call jumps
+//! to: a `jal` or `jalr` that writes `ra` enters the function at its target, named after the label
+//! there, and a `ret` goes back to the one that called it. A function that's left some other way,
+//! like with a tail call, keeps the instructions that run until it returns. Each line is shown
+//! with the label it's under, which for a loop is the name of the loop. The lines of a macro are
+//! counted apart for each place it's used, and shown under it as an inlined frame, with the line
+//! of the `.macro` they were written in. The [regions](../regions/index.html) the program marked
+//! come last, with their instructions and cycles.
+//!
+//! The calls make up a tree of the stacks they were made from, which `--profile-folded PATH` writes
+//! in the folded stacks format that [flamegraph.pl](https://github.com/brendangregg/FlameGraph)
+//! and [inferno](https://github.com/jonhoo/inferno) turn into a flame graph: a line per stack, with
+//! the functions from the outermost in, separated by `;`, and how many instructions ran in it.
+//!
+
+use std::io::{self, Write};
+
+use fnv::FnvHashMap;
+
+use super::Simulator;
+use crate::parser::Instruction;
+
+/// How many of the hottest functions and lines we show
+const SHOWN: usize = 20;
+
+/// A function as called from a stack of other ones
+#[derive(Debug)]
+struct Frame {
+    /// The frame that called it, or itself for the first one
+    parent: usize,
+    /// The address the call jumped to
+    entry: usize,
+    calls: u64,
+    /// Instructions that ran in it, not counting the functions it called
+    instructions: u64,
+}
+
+/// What an instruction does to the stack of calls
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Transfer {
+    #[default]
+    Nothing,
+    Call,
+    Return,
+}
+
+impl Transfer {
+    pub(super) fn of(instruction: &Instruction) -> Self {
+        match instruction {
+            Instruction::Jal(1, _) | Instruction::Jalr(1, _, _) => Transfer::Call,
+            Instruction::Ret | Instruction::Jalr(0, 1, 0) => Transfer::Return,
+            _ => Transfer::Nothing,
+        }
+    }
+}
+
+/// The runs of each instruction and of each stack of calls, counted when the
+/// [profile](../struct.Simulator.html#method.profile) is on
+#[derive(Debug, Default)]
+pub struct Profile {
+    runs: Vec<u64>,
+    frames: Vec<Frame>,
+    /// The frame of each function called from a frame
+    children: FnvHashMap<(usize, usize), usize>,
+    current: usize,
+    last: Transfer,
+}
+
+impl Profile {
+    /// Counts a run of `instruction`, in `slot` of the code and at `pc`
+    pub(super) fn run(&mut self, slot: usize, pc: usize, instruction: &Instruction) {
+        if self.frames.is_empty() {
+            self.frames.push(Frame { parent: 0, entry: pc, calls: 1, instructions: 0 });
+        }
+        match std::mem::take(&mut self.last) {
+            Transfer::Call => self.current = self.enter(pc),
+            Transfer::Return => self.current = self.frames[self.current].parent,
+            Transfer::Nothing => {}
+        }
+
+        // The code can grow while running, when another program is loaded
+        if slot >= self.runs.len() {
+            self.runs.resize(slot + 1, 0);
+        }
+        self.runs[slot] += 1;
+        self.frames[self.current].instructions += 1;
+
+        self.last = Transfer::of(instruction);
+    }
+
+    /// The frame of a call from the current one to `entry`
+    fn enter(&mut self, entry: usize) -> usize {
+        let frames = &mut self.frames;
+        let current = self.current;
+        let frame = *self.children.entry((current, entry)).or_insert_with(|| {
+            frames.push(Frame { parent: current, entry, calls: 0, instructions: 0 });
+            frames.len() - 1
+        });
+        frames[frame].calls += 1;
+        frame
+    }
+}
+
+impl Simulator {
+    /// Writes the functions and the lines where most instructions ran, see [profile](profile/index.html)
+    pub fn write_profile<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let profile = &self.stats.profile;
+        let total = profile.runs.iter().sum::<u64>().max(1);
+        let percent = |runs: u64| runs as f64 / total as f64 * 100.0;
+
+        // The frames of a function from different stacks are added up
+        let mut functions: Vec<(String, u64, u64)> = Vec::new();
+        for frame in &profile.frames {
+            let name = self.function_name(frame.entry);
+            match functions.iter_mut().find(|(f, _, _)| *f == name) {
+                Some((_, calls, runs)) => {
+                    *calls += frame.calls;
+                    *runs += frame.instructions;
+                }
+                None => functions.push((name, frame.calls, frame.instructions)),
+            }
+        }
+        functions.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+
+        writeln!(out, "Profile of {} instructions", total)?;
+        writeln!(out, "{:>8} {:>14} {:>10}  Function", "Self", "Instructions", "Calls")?;
+        for (name, calls, runs) in functions.iter().take(SHOWN) {
+            writeln!(out, "{:>7.2}% {:>14} {:>10}  {}", percent(*runs), runs, calls, name)?;
+        }
+        if functions.len() > SHOWN {
+            writeln!(out, "  ... and {} more", functions.len() - SHOWN)?;
+        }
+
+        // The instructions of a line, like the two of an `la`, are added up
+        let mut by_line: FnvHashMap<_, (usize, u64)> = FnvHashMap::default();
+        for (slot, &runs) in profile.runs.iter().enumerate().filter(|&(_, &runs)| runs > 0) {
+            let line = match self.code_pos.get(slot).and_then(Option::as_ref) {
+                Some(pos) => {
+                    let inlined = pos.macro_origin.as_ref().map(|origin| (origin.pos.file.as_str(), origin.pos.line));
+                    (pos.file.as_str(), pos.line, inlined)
+                }
+                None => ("", slot, None),
+            };
+            by_line.entry(line).or_insert((slot, 0)).1 += runs;
+        }
+        let mut lines: Vec<(usize, u64)> = by_line.into_values().collect();
+        lines.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        writeln!(out, "{:>8} {:>14}  Line", "", "Instructions")?;
+        for &(slot, runs) in lines.iter().take(SHOWN) {
+            let address = self.code_address(slot);
+            let label = self.function_at(address).map(|name| format!(" in {}", name)).unwrap_or_default();
+            let line = match &self.code_pos[slot] {
+                Some(pos) => format!("{}:{}{}  {}", pos.file, pos.line, label, pos.source.trim()),
+                None => format!("{:#x}{}  {}", address, label, self.code[slot]),
+            };
+            writeln!(out, "{:>7.2}% {:>14}  {}", percent(runs), runs, line)?;
+            if let Some(origin) = self.code_pos[slot].as_ref().and_then(|pos| pos.macro_origin.as_ref()) {
+                writeln!(out, "{:>24}  {}", "", origin)?;
+            }
+        }
+        if lines.len() > SHOWN {
+            writeln!(out, "  ... and {} more", lines.len() - SHOWN)?;
+        }
+
+        if !self.stats.regions.is_empty() {
+            self.stats.regions.write_profile(out, total)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the instructions that ran in each stack of calls, in the folded stacks format, see
+    /// [profile](profile/index.html)
+    pub fn write_folded_stacks<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let frames = &self.stats.profile.frames;
+        for (i, frame) in frames.iter().enumerate().filter(|(_, frame)| frame.instructions > 0) {
+            let mut stack = vec![self.function_name(frame.entry)];
+            let mut parent = i;
+            while parent != 0 {
+                parent = frames[parent].parent;
+                stack.push(self.function_name(frames[parent].entry));
+            }
+            stack.reverse();
+            writeln!(out, "{} {}", stack.join(";"), frame.instructions)?;
+        }
+        Ok(())
+    }
+
+    /// The label a function starts at, or its address if there's none
+    fn function_name(&self, entry: usize) -> String {
+        match self.function_at(entry) {
+            Some(name) => name.to_owned(),
+            None => format!("{:#x}", entry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        // `main` calls `square` 3 times, which loops in `times`
+        let code = "main: li s0, 3\nli s1, 0
+            again: mv a0, s0\ncall square\nadd s1, s1, a0\naddi s0, s0, -1\nbnez s0, again
+            mv a0, s1\nli a7, 93\necall
+            square: mv t0, a0\nli t1, 0
+            times: add t1, t1, a0\naddi t0, t0, -1\nbnez t0, times
+            mv a0, t1\nret";
+        let mut sim = Simulator::new()
+            .profile(true)
+            .load_str(code)
+            .unwrap();
+        assert_eq!(sim.run(), 14);
+
+        let frames = &sim.stats.profile.frames;
+        assert_eq!(frames.len(), 2);
+        // 2 + 3 * 5 + 3
+        assert_eq!((frames[0].calls, frames[0].instructions), (1, 20));
+        // 3 * 4 + (3 + 2 + 1) * 3
+        assert_eq!((frames[1].calls, frames[1].instructions), (3, 30));
+
+        let mut folded = Vec::new();
+        sim.write_folded_stacks(&mut folded).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "main 20\nmain;square 30\n");
+
+        let mut report = Vec::new();
+        sim.write_profile(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("  60.00%             30          3  square\n"), "{}", report);
+        // The hottest line
+        assert!(report.contains("  12.00%              6  main.s:13 in times  times: add t1, t1, a0\n"), "{}", report);
+    }
+
+    #[test]
+    fn test_profile_macro() {
+        // The lines of `dec` run 3 times each, and once more from the second place it's used
+        let code = ".macro dec(%r)\naddi %r, %r, -1\nnop\n.end_macro
+            main: li t0, 3\nloop: dec(t0)\nbnez t0, loop\ndec(t0)
+            li a7, 10\necall";
+        let mut sim = Simulator::new()
+            .profile(true)
+            .load_str(code)
+            .unwrap();
+        sim.run();
+
+        let mut report = Vec::new();
+        sim.write_profile(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let inlined = |line: usize, source: &str| {
+            format!("{:>24}  inlined from macro `dec` at main.s:{}: {}\n", "", line, source)
+        };
+        let first = format!("              3  main.s:6 in loop  loop: dec(t0)\n{}", inlined(2, "addi %r, %r, -1"));
+        assert!(report.contains(&first), "{}", report);
+        let second = format!("              3  main.s:6 in loop  loop: dec(t0)\n{}", inlined(3, "nop"));
+        assert!(report.contains(&second), "{}", report);
+        let last = format!("              1  main.s:8 in loop  dec(t0)\n{}", inlined(2, "addi %r, %r, -1"));
+        assert!(report.contains(&last), "{}", report);
+    }
+}
@@ -0,0 +1,209 @@
+//!
+//! Lets a program measure parts of itself. It marks where a region begins and ends with ecalls,
+//! giving it a name, and when the simulation ends we show how many instructions each region ran
+//! and how long it took:
+//!
+//! ```
+//! la a0, physics_str    # "physics"
+//! li a7, 210            # begin region
+//! ecall
+//! ...
+//! la a0, physics_str
+//! li a7, 211            # end region
+//! ecall
+//! ```
+//!
+//! Regions can be nested and run many times, every run is added to the total. FPGRARS runs one
+//! instruction per cycle, so the instruction count is also the cycle count. A fused pair of
+//! instructions, see [dispatch](../dispatch/index.html), counts as the two it's made of.
+//!
+//! With `--profile`, the regions are shown along with the rest of the
+//! [profile](../profile/index.html), with how much of the program each one took.
+//!
+
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use super::{EcallSignal, Simulator};
+
+/// a0 = address of the name of the region
+const BEGIN_REGION: u32 = 210;
+/// a0 = address of the name of the region
+const END_REGION: u32 = 211;
+
+/// Totals of a region over all its runs
+#[derive(Debug)]
+struct Region {
+    name: String,
+    runs: u64,
+    instructions: u64,
+    elapsed: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct Regions {
+    /// In the order they first began
+    totals: Vec<Region>,
+    /// Regions that began and didn't end yet: index in `totals`, and the instruction count and
+    /// time when it began
+    open: Vec<(usize, u64, Instant)>,
+}
+
+impl Regions {
+    pub fn is_empty(&self) -> bool {
+        self.totals.is_empty()
+    }
+
+    /// Writes the instructions and cycles of each region for the [profile](../profile/index.html),
+    /// with how much of the `total` instructions they are
+    pub(super) fn write_profile<W: Write>(&self, out: &mut W, total: u64) -> io::Result<()> {
+        writeln!(out, "{:>8} {:>14} {:>14} {:>10}  Region", "", "Instructions", "Cycles", "Runs")?;
+        for r in &self.totals {
+            let percent = r.instructions as f64 / total.max(1) as f64 * 100.0;
+            writeln!(out, "{:>7.2}% {:>14} {:>14} {:>10}  {}", percent, r.instructions, r.instructions, r.runs, r.name)?;
+        }
+        Ok(())
+    }
+
+    fn begin(&mut self, name: String, instructions: u64) {
+        let index = match self.totals.iter().position(|r| r.name == name) {
+            Some(index) => index,
+            None => {
+                self.totals.push(Region {
+                    name,
+                    runs: 0,
+                    instructions: 0,
+                    elapsed: Duration::default(),
+                });
+                self.totals.len() - 1
+            }
+        };
+
+        self.open.push((index, instructions, Instant::now()));
+    }
+
+    /// Ends the innermost region with this name. Returns false if there isn't one
+    fn end(&mut self, name: &str, instructions: u64) -> bool {
+        let totals = &self.totals;
+        let open = self.open.iter().rposition(|&(i, _, _)| totals[i].name == name);
+        let (index, began_at, began_time) = match open {
+            Some(open) => self.open.remove(open),
+            None => return false,
+        };
+
+        let region = &mut self.totals[index];
+        region.runs += 1;
+        // Doesn't count the "end region" ecall itself
+        region.instructions += instructions - began_at - 1;
+        region.elapsed += began_time.elapsed();
+        true
+    }
+}
+
+impl fmt::Display for Regions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.totals.iter().map(|r| r.name.len()).max().unwrap_or(0).max(6);
+
+        writeln!(f, "{:<width$} {:>8} {:>14} {:>10}", "Region", "Runs", "Instructions", "Time", width = width)?;
+        for r in &self.totals {
+            let ms = format!("{:.2}ms", r.elapsed.as_secs_f64() * 1000.0);
+            writeln!(f, "{:<width$} {:>8} {:>14} {:>10}", r.name, r.runs, r.instructions, ms, width = width)?;
+        }
+        Ok(())
+    }
+}
+
+impl Simulator {
+    /// Tries to handle a region marker ecall, returns `None` if it isn't one
+    pub(super) fn region_ecall(&mut self, a7: u32) -> Option<EcallSignal> {
+        if a7 != BEGIN_REGION && a7 != END_REGION {
+            return None;
+        }
+
+        let name = self.read_string(self.get_reg::<u32>(10) as usize);
+        let instructions = self.stats.instructions;
+
+        if a7 == BEGIN_REGION {
+            self.stats.regions.begin(name, instructions);
+        } else if !self.stats.regions.end(&name, instructions) {
+            eprintln!("Warning: region `{}` ended at {:#x}, but it never began", name, self.pc);
+        }
+
+        Some(EcallSignal::Nothing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regions() {
+        let program = r#"
+            .data
+            outer: .string "outer"
+            inner: .string "inner"
+            .text
+            la a0 outer
+            li a7 210
+            ecall
+            li t0 3
+            loop:
+                la a0 inner
+                li a7 210
+                ecall
+                addi t0 t0 -1
+                la a0 inner
+                li a7 211
+                ecall
+                bnez t0 loop
+            la a0 outer
+            li a7 211
+            ecall
+        "#;
+
+        let mut sim = Simulator::new().load_str(program).unwrap();
+        sim.run();
+
+        let totals = &sim.stats.regions.totals;
+        assert_eq!(totals.len(), 2);
+
+        // li, 3 * (4 + 1 + 4 + 1), and la + li before the ecall, where each `la` is two instructions
+        assert_eq!(totals[0].name, "outer");
+        assert_eq!((totals[0].runs, totals[0].instructions), (1, 34));
+
+        // 3 runs of addi + la + li
+        assert_eq!(totals[1].name, "inner");
+        assert_eq!((totals[1].runs, totals[1].instructions), (3, 12));
+    }
+
+    #[test]
+    fn test_fused_regions() {
+        // The `lui` and `addi` and the `addi` and `bne` of the loop are fused pairs
+        let program = ".data\nname: .string \"loop\"\n.text
+            la a0, name\nli a7, 210\necall
+            lui t0, 0x1\naddi t0, t0, -0x7f0\nli t1, 0
+            loop: addi t1, t1, 1\naddi t0, t0, -1\nbne t0, zero, loop
+            la a0, name\nli a7, 211\necall";
+
+        // 3 + 0x810 runs of 3, and the `la` and `li` before the ecall. Without fusing, like when
+        // profiling, it's the same.
+        let expected = 3 + 0x810 * 3 + 3;
+        let mut sim = Simulator::new().load_str(program).unwrap();
+        sim.run();
+        assert!(sim.ops.iter().any(|op| op.kind == super::super::dispatch::Kind::AddiBne));
+        assert_eq!(sim.stats.regions.totals[0].instructions, expected);
+
+        let mut sim = Simulator::new().profile(true).load_str(program).unwrap();
+        sim.run();
+        assert_eq!(sim.stats.regions.totals[0].instructions, expected);
+
+        let mut report = Vec::new();
+        sim.write_profile(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let line = format!("{:>14} {:>14} {:>10}  loop\n", expected, expected, 1);
+        assert!(report.contains("Instructions         Cycles       Runs  Region\n"), "{}", report);
+        assert!(report.contains(&line), "{}", report);
+    }
+}
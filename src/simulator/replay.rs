@@ -0,0 +1,270 @@
+//!
+//! Recording what a program gets from outside and playing it back, for `--record PATH` and
+//! `--replay PATH`, so a run of an interactive program, like a game, can be seen again just like
+//! it went, to find a bug or to grade it. We keep the keys pressed and released in the window, with
+//! how many instructions ran when the program got them, and what the ecalls read: the lines of
+//! input, the time and the random numbers, in the order the program asked for them. The counters
+//! read with `rdtime`, `rdcycle` or `rdinstret` are kept as a `csr_time` too.
+//!
+//! A recording is a text file with a line for each of them, which can also be written by hand:
+//!
+//! ```text
+//! 1024 press "30"
+//! 8192 release "30"
+//! 10240 line "42\n"
+//! 10500 time "1697040000000"
+//! 10600 rand "7"
+//! ```
+//!
+//! While recording, the keys get to the program every so many instructions instead of right when
+//! they're pressed, so they get there at the same instruction when replayed, even without the
+//! window. Once the recording runs out, or if the program asks for something else than what comes
+//! next in it, the input comes from the keyboard and stdin again.
+//!
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::str::FromStr;
+
+use super::Simulator;
+use crate::renderer::{apply_key, Key, KeyQueue};
+
+/// How many instructions run between two looks at the keys of the window
+const POLL_EVERY: u64 = 1 << 10;
+
+/// Something the program got, how many instructions ran when it did, and what it was
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    at: u64,
+    kind: String,
+    value: String,
+}
+
+impl Entry {
+    /// The key of a `press` or `release`
+    fn key(&self) -> Option<Key> {
+        let scancode = self.value.parse().ok()?;
+        match self.kind.as_str() {
+            "press" => Some(Key { scancode, pressed: true }),
+            "release" => Some(Key { scancode, pressed: false }),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the lines of a recording, like `1024 press "30"`
+fn parse(recording: &str) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    for (i, line) in recording.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = line.split_once(' ').and_then(|(at, rest)| {
+            let (kind, value) = rest.split_once(' ')?;
+            Some(Entry { at: at.parse().ok()?, kind: kind.to_owned(), value: serde_json::from_str(value).ok()? })
+        });
+        match entry {
+            Some(entry) => entries.push(entry),
+            None => return Err(format!("line {}: expected `INSTRUCTIONS KIND \"VALUE\"`, got `{}`", i + 1, line)),
+        }
+    }
+    Ok(entries)
+}
+
+enum Mode {
+    Record(Box<dyn Write + Send>),
+    /// The keys, and the other entries, that are still to come
+    Replay(VecDeque<Entry>, VecDeque<Entry>),
+    /// The input comes from outside, after a replay
+    Live,
+}
+
+pub struct Replay {
+    mode: Mode,
+    /// The keys of the window, which get to the program from here
+    keys: KeyQueue,
+    /// Instructions until the next look at the keys
+    countdown: u64,
+}
+
+impl Replay {
+    pub(super) fn record(out: Box<dyn Write + Send>) -> Self {
+        Self { mode: Mode::Record(out), keys: KeyQueue::default(), countdown: 1 }
+    }
+
+    /// Plays back `recording`, or says what's wrong with it
+    pub(super) fn play_back(recording: &str) -> Result<Self, String> {
+        let (keys, values) = parse(recording)?.into_iter().partition(|entry| entry.key().is_some());
+        Ok(Self { mode: Mode::Replay(keys, values), keys: KeyQueue::default(), countdown: 1 })
+    }
+
+    pub(super) fn keys(&self) -> KeyQueue {
+        self.keys.clone()
+    }
+
+    /// Writes down an entry, if we're recording
+    fn write(&mut self, at: u64, kind: &str, value: &str) {
+        let Mode::Record(out) = &mut self.mode else { return };
+        let value = serde_json::to_string(value).unwrap();
+        if let Err(e) = writeln!(out, "{} {} {}", at, kind, value) {
+            eprintln!("Couldn't write the recording: {}", e);
+            self.mode = Mode::Live;
+        }
+    }
+}
+
+impl Simulator {
+    /// Gives the program the keys whose time has come, the recorded ones when replaying and the
+    /// ones of the window otherwise
+    pub(super) fn replay_step(&mut self) {
+        let Some(replay) = &mut self.replay else { return };
+        let at = self.stats.instructions;
+        if let Mode::Replay(keys, values) = &mut replay.mode {
+            while let Some(entry) = keys.front().filter(|entry| entry.at <= at) {
+                apply_key(&mut self.memory.mmio.lock().unwrap(), entry.key().unwrap());
+                keys.pop_front();
+            }
+            if keys.is_empty() && values.is_empty() {
+                replay.mode = Mode::Live;
+            }
+        }
+
+        replay.countdown -= 1;
+        if replay.countdown > 0 {
+            return;
+        }
+        replay.countdown = POLL_EVERY;
+
+        let pressed = std::mem::take(&mut *replay.keys.lock().unwrap());
+        if matches!(replay.mode, Mode::Replay(..)) {
+            // The keys are the recorded ones until they run out
+            return;
+        }
+        for key in pressed {
+            apply_key(&mut self.memory.mmio.lock().unwrap(), key);
+            let kind = if key.pressed { "press" } else { "release" };
+            replay.write(at, kind, &key.scancode.to_string());
+        }
+    }
+
+    /// What the program gets for an input of `kind`: the next one of the recording when replaying,
+    /// or what `live` gets, which is recorded when recording
+    pub(super) fn input<T, F>(&mut self, kind: &str, live: F) -> T
+    where
+        T: FromStr + ToString,
+        F: FnOnce(&mut Self) -> T,
+    {
+        let Some(replay) = &mut self.replay else { return live(self) };
+        if let Mode::Replay(_, values) = &mut replay.mode {
+            match values.pop_front() {
+                Some(entry) if entry.kind == kind => match entry.value.parse() {
+                    Ok(value) => return value,
+                    Err(_) => eprintln!("Warning: the recording has `{}` for a {}, so the input is live from now on", entry.value, kind),
+                },
+                Some(entry) => eprintln!(
+                    "Warning: the program asked for a {} at {} instructions where the recording has a {} at {}, so the input is live from now on",
+                    kind, self.stats.instructions, entry.kind, entry.at
+                ),
+                None => {}
+            }
+            replay.mode = Mode::Live;
+        }
+
+        let value = live(self);
+        let at = self.stats.instructions;
+        if let Some(replay) = &mut self.replay {
+            replay.write(at, kind, &value.to_string());
+        }
+        value
+    }
+
+    /// Writes what's left of the recording
+    pub(super) fn finish_replay(&mut self) {
+        if let Some(Replay { mode: Mode::Record(out), .. }) = &mut self.replay {
+            if let Err(e) = out.flush() {
+                eprintln!("Couldn't write the recording: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    /// Somewhere to write the recording that the test can still read
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn load(code: &str, replay: Replay) -> Simulator {
+        let mut sim = Simulator::new().load_str(code).unwrap();
+        sim.replay = Some(replay);
+        sim
+    }
+
+    #[test]
+    fn test_replay() {
+        // Waits for a key, and exits with it plus a random number
+        let code = "li t0, 0xff200000
+            wait: lw t1, 0(t0)\nbeqz t1, wait
+            lw s0, 4(t0)\nli a1, 1000\nli a7, 42\necall\nadd a0, a0, s0\nli a7, 93\necall";
+        let out = Shared::default();
+        let mut sim = load(code, Replay::record(Box::new(out.clone())));
+        sim.replay.as_ref().unwrap().keys().lock().unwrap().push(Key { scancode: 30, pressed: true });
+        let code_recorded = sim.run();
+
+        let recording = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = recording.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", recording);
+        assert_eq!(lines[0], "1 press \"30\"");
+        assert!(lines[1].contains(" rand \""), "{}", recording);
+
+        // Gets the same key and number, without the window
+        for _ in 0..3 {
+            let mut sim = load(code, Replay::play_back(&recording).unwrap());
+            assert_eq!(sim.run(), code_recorded);
+        }
+
+        // A key that comes later, read and then the rest is live
+        let recording = "# by hand\n500 press \"30\"\n600 rand \"5\"";
+        let mut sim = load(code, Replay::play_back(recording).unwrap());
+        assert_eq!(sim.run(), 'a' as i32 + 5);
+        assert!(sim.stats.instructions > 500);
+
+        let code = "li a7, 5\necall\nli a7, 93\necall";
+        let mut sim = load(code, Replay::play_back("3 line \"42\\n\"").unwrap());
+        assert_eq!(sim.run(), 42);
+
+        // The clock, which is recorded and replayed like the time ecall
+        let code = "rdtime a0\nrdcycle a1\nsub a0, a0, a1\nli a7, 93\necall";
+        let out = Shared::default();
+        let mut sim = load(code, Replay::record(Box::new(out.clone())));
+        sim.run();
+        let recording = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(recording.lines().filter(|line| line.contains(" csr_time \"")).count(), 2, "{}", recording);
+        let mut sim = load(code, Replay::play_back("1 csr_time \"123456\"\n2 csr_time \"1\"").unwrap());
+        assert_eq!(sim.run(), 123455);
+
+        // A read float that doesn't get a number reads 0 instead of crashing
+        let code = "li a7, 6\necall\nfcvt.w.s a0, fa0\naddi a0, a0, 1\nli a7, 93\necall";
+        let mut sim = load(code, Replay::play_back("3 line \"4.0\\n\"").unwrap());
+        assert_eq!(sim.run(), 5);
+        let mut sim = load(code, Replay::play_back("3 line \"four\\n\"").unwrap());
+        assert_eq!(sim.run(), 1);
+
+        assert_eq!(Replay::play_back("1 press").err().unwrap(), "line 1: expected `INSTRUCTIONS KIND \"VALUE\"`, got `1 press`");
+    }
+}
@@ -0,0 +1,243 @@
+//!
+//! Self-modifying code, with [Simulator::self_modifying](../struct.Simulator.html#method.self_modifying).
+//!
+//! Normally the code isn't in the memory at all, and the data starts at address 0. In this mode the
+//! machine code of every instruction is in the memory from the
+//! [text_base](../struct.Simulator.html#method.text_base), 0 by default, and the data starts
+//! [DATA_BASE](constant.DATA_BASE.html) bytes after it instead. The instructions are still decoded
+//! once, when the program is loaded, and `code` works as a cache of them: a store to the code marks
+//! the words it wrote, which are decoded again before the next instruction runs. Jumping past the
+//! end of the code, like to a buffer in the data that the program filled with instructions, decodes
+//! the memory up to there.
+//!
+//! Some writes don't go through a store, like a `read` ecall that fills a buffer with a file, or
+//! another instance writing to a [shared memory](../struct.SharedMemoryConfig.html) window, so we
+//! can't tell which words they changed. `fence.i`, which a real processor needs before running code
+//! it just wrote anyway, decodes all of the code again.
+//!
+//! Pseudoinstructions that we run as a single instruction, like a `li` with a big constant, have no
+//! machine code, so their words read as 0, but they still run until the program overwrites them.
+//!
+//! With [Simulator::readable_code](../struct.Simulator.html#method.readable_code) the code is in
+//! the memory the same way, but it's read-only, so it's never decoded again. With
+//! [rvc](../struct.Simulator.html#method.rvc) the compressed instructions take 2 bytes there.
+//!
+
+use super::memory::Permissions;
+use super::{Segment, Simulator};
+use crate::parser;
+
+/// Where the data starts, after the space for the code, when the code starts at 0
+pub const DATA_BASE: usize = 0x0040_0000;
+
+impl Simulator {
+    /// Whether the code is in the memory, with the data after it
+    pub(super) fn code_in_memory(&self) -> bool {
+        self.readable_code || self.self_modifying
+    }
+
+    /// Where the data starts when the code is in the memory
+    pub(super) fn data_base(&self) -> usize {
+        self.text_base + DATA_BASE
+    }
+
+    /// Writes the machine code of the instructions from `start` to the end of `code` into the
+    /// memory, at their addresses. Unless the code can be modified, it's made read-only.
+    pub(super) fn write_code(&mut self, start: usize) {
+        let slot = self.slot_size();
+        for (i, instruction) in self.code.iter().enumerate().skip(start) {
+            let addr = self.text_base + i * slot;
+            if self.rvc && instruction.is_padding() {
+                continue;
+            } else if self.rvc && !self.code.get(i + 1).is_some_and(parser::Instruction::is_padding) {
+                self.memory.set_half(addr, parser::compress(instruction, addr).unwrap_or(0));
+            } else {
+                self.memory.set_word(addr, parser::encode(instruction, addr).unwrap_or(0));
+            }
+        }
+
+        let end = self.text_base + self.code.len() * slot;
+        self.memory.set_text(self.text_base..end);
+        if self.self_modifying {
+            self.memory.set_permissions(Segment::Text, Permissions::ALL);
+            self.memory.code_range = self.text_base..end;
+            self.memory.code_writes.clear();
+        } else {
+            self.memory.set_permissions(Segment::Text, Permissions::READABLE_CODE);
+        }
+    }
+
+    /// Makes the addresses of the code a segment that can only be run, when the code isn't in the
+    /// memory, so reading or writing to them is an access fault. Unless they're the addresses of
+    /// the data too, like when both start at 0.
+    pub(super) fn map_code(&mut self) {
+        let code = self.text_base..self.code_address(self.code.len());
+        if self.memory.is_free(code.clone()) {
+            self.memory.set_text(code);
+        }
+    }
+
+    /// Decodes again the words of the code that were written to, and the memory up to pc if it's
+    /// past the end of the code. A store takes at most 8 bytes, so it touches at most 2 words.
+    pub(super) fn refresh_code(&mut self) {
+        while let Some(addr) = self.memory.code_writes.pop() {
+            for slot in [self.slot(addr), self.slot(addr + 7)] {
+                if slot < self.code.len() {
+                    self.set_code(slot, self.decode_at(self.text_base + slot * 4));
+                }
+            }
+        }
+
+        let slot = self.slot(self.pc);
+        if self.pc >= self.text_base && slot >= self.code.len() && self.pc + 4 <= self.memory.end() {
+            let start = self.code.len();
+            for slot in start..=slot {
+                let instruction = self.decode_at(self.text_base + slot * 4);
+                self.code.push(instruction);
+                self.code_pos.push(None);
+            }
+            self.compile_code(start..self.code.len());
+            self.memory.code_range.end = self.text_base + self.code.len() * 4;
+            self.memory.set_text(self.memory.code_range.clone());
+        }
+    }
+
+    /// Decodes all of the code again, for a `fence.i`. Only the words that changed are decoded, so
+    /// the pseudoinstructions without machine code keep running.
+    pub(super) fn invalidate_code(&mut self) {
+        self.memory.code_writes.clear();
+        for slot in 0..self.code.len() {
+            let addr = self.text_base + slot * 4;
+            if self.memory.get_word(addr) != parser::encode(&self.code[slot], addr).unwrap_or(0) {
+                self.set_code(slot, self.decode_at(addr));
+            }
+        }
+    }
+
+    /// Replaces the instruction at `slot`, and what the interpreter runs for it
+    fn set_code(&mut self, slot: usize, instruction: parser::Instruction) {
+        self.code[slot] = instruction;
+        self.compile_code(slot..slot + 1);
+    }
+
+    fn decode_at(&self, addr: usize) -> parser::Instruction {
+        let word = self.memory.get_word(addr);
+        parser::decode(word, addr, self.rv64, &self.csr_names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(code: &str) -> i32 {
+        let mut sim = Simulator::new()
+            .self_modifying(true)
+            .load_str(code)
+            .unwrap();
+        sim.run()
+    }
+
+    #[test]
+    fn test_patch_code() {
+        // Overwrites the `li a0, 1` with the `addi a0, zero, 42` in the data
+        let code = ".data\nnew: .word 0x02a00513\n.text\nla t0, new\nlw t1, 0(t0)\nla t2, patch
+            sw t1, 0(t2)\npatch: li a0, 1\nli a7, 93\necall";
+        assert_eq!(run(code), 42);
+    }
+
+    #[test]
+    fn test_run_from_data() {
+        // `addi a0, a0, 5` and `ret`, written and then called in the data
+        let code = ".data\nbuffer: .space 8\n.text\nla t0, buffer\nli t1, 0x00550513\nsw t1, 0(t0)
+            li t1, 0x00008067\nsw t1, 4(t0)\nli a0, 10\njalr t0\njalr t0\nli a7, 93\necall";
+        assert_eq!(run(code), 20);
+    }
+
+    #[test]
+    fn test_code_is_data() {
+        // The code can read its own machine code, and the labels of the data moved to DATA_BASE
+        let code = ".data\nx: .word 7\n.text\nstart: lw a0, start\nla a1, x\nli a7, 93\necall";
+        let mut sim = Simulator::new()
+            .self_modifying(true)
+            .load_str(code)
+            .unwrap();
+        // `lw a0, start` is an `auipc a0, 0` and then a `lw a0, 0(a0)`
+        assert_eq!(sim.run(), 0x0000_0517);
+        assert_eq!(sim.get_reg::<u32>(11), DATA_BASE as u32);
+    }
+
+    #[test]
+    fn test_fence_i() {
+        // Changes the `li a0, 1` without a store, like a `read` ecall would
+        let code = "fence.i\nli a0, 1\nli a1, 0x12345678\nli a7, 93\necall";
+        let load = |code: &str| {
+            let mut sim = Simulator::new()
+                .self_modifying(true)
+                .load_str(code)
+                .unwrap();
+            sim.memory.set_word(4, 0x02a0_0513); // li a0, 42
+            sim.memory.code_writes.clear();
+            sim
+        };
+
+        let mut sim = load(code);
+        assert_eq!(sim.run(), 42);
+        assert_eq!(sim.get_reg::<u32>(11), 0x1234_5678);
+        assert_eq!(load(&code.replace("fence.i", "fence")).run(), 1);
+    }
+
+    #[test]
+    fn test_readable_code() {
+        // Sums the words of the code up to `end`, which can't be written to
+        let code = "la t0, end\nli a0, 0\nli t1, 0
+            loop: lw t2, 0(t1)\nadd a0, a0, t2\naddi t1, t1, 4\nbltu t1, t0, loop
+            end: li a7, 93\necall";
+        let load = |code: &str, rvc| {
+            Simulator::new()
+                .readable_code(true)
+                .rvc(rvc)
+                .load_str(code)
+                .unwrap()
+        };
+
+        let mut sim = load(code, false);
+        let sum = (0..8).fold(0u32, |sum, i| sum.wrapping_add(sim.memory.get_word(i * 4)));
+        // la t0, end is an auipc t0, 0 and an addi t0, t0, 32
+        assert_eq!((sim.memory.get_word(0), sim.memory.get_word(4)), (0x0000_0297, 0x0202_8293));
+        assert_eq!(sim.run() as u32, sum);
+
+        // The compressed ones take 2 bytes
+        let sim = load(&code.replace("li a0, 0", "c.li a0, 0"), true);
+        assert_eq!(sim.memory.get_half(8), 0x4501);
+        assert_eq!(sim.memory.get_word(10), 0x0000_0313); // li t1, 0
+
+        let mut sim = load(&code.replace("end: ", "sw zero, 0(zero)\nend: "), false);
+        assert_eq!(sim.run(), 1);
+    }
+
+    #[test]
+    fn test_text_base() {
+        // `auipc` and a jump table see the addresses of the code at 0x00400000
+        let code = ".data\ntable: .word one two\n.text\nauipc s0, 0\nla t0, table\nlw t0, 4(t0)\njr t0
+            one: li a0, 1\ntwo: sub a0, s0, a0\nla a1, two\nli a7, 93\necall";
+        let load = |readable_code| {
+            Simulator::new()
+                .text_base(0x0040_0000)
+                .readable_code(readable_code)
+                .load_str(code)
+                .unwrap()
+        };
+
+        let mut sim = load(false);
+        assert_eq!(sim.run(), 0x0040_0000);
+        assert_eq!(sim.get_reg::<u32>(11), 0x0040_0018);
+        assert_eq!(sim.memory.get_word(4), 0x0040_0018);
+
+        // With the code in the memory, it's at the text base and the data comes after it
+        let mut sim = load(true);
+        assert_eq!(sim.memory.get_word(0x0040_0000), 0x0000_0417); // auipc s0, 0
+        assert_eq!(sim.memory.get_word(0x0080_0000), 0x0040_0014);
+        assert_eq!(sim.run(), 0x0040_0000);
+    }
+}
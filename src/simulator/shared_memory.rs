@@ -0,0 +1,143 @@
+//!
+//! A window of memory shared between FPGRARS instances. Every instance started with the same
+//! `--shared-memory name:addr:len` sees the same bytes at `addr..addr+len`, so one program can
+//! write something there and another one, in another simulator, reads it right away. Good for
+//! producer/consumer exercises, or a game with two players on two windows.
+//!
+//! The window is backed by a file named after `name` in the temporary directory, mapped into
+//! memory. Nothing synchronizes the programs, that's up to them (like spinning on a flag word).
+//!
+
+use memmap::{MmapMut, MmapOptions};
+use std::fs::OpenOptions;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use super::{Device, MMIO_START};
+use crate::parser::combinators::integer_literal;
+
+/// Where to put the window and which one, as given in the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedMemoryConfig {
+    pub name: String,
+    /// Address of the first byte of the window in the simulated memory
+    pub start: usize,
+    pub len: usize,
+}
+
+impl SharedMemoryConfig {
+    fn path(&self) -> PathBuf {
+        std::env::temp_dir().join(format!("fpgrars-shm-{}", self.name))
+    }
+}
+
+impl FromStr for SharedMemoryConfig {
+    type Err = String;
+
+    /// Parses `name:addr:len`, like `game:0x10040000:4096`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (name, start, len) = match parts[..] {
+            [name, start, len] => (name, start, len),
+            _ => return Err(format!("expected `name:addr:len`, got `{}`", s)),
+        };
+
+        let valid_name = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if name.is_empty() || !name.chars().all(valid_name) {
+            return Err(format!("invalid name `{}`, use only letters, digits, `_` and `-`", name));
+        }
+
+        let number = |x: &str| {
+            integer_literal(x)
+                .map(|x| x as usize)
+                .map_err(|_| format!("expected a number, got `{}`", x))
+        };
+        let (start, len) = (number(start)?, number(len)?);
+
+        if len == 0 || start + len > MMIO_START {
+            return Err(format!(
+                "the window must have some bytes and end before the MMIO, at {:#x}",
+                MMIO_START
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            start,
+            len,
+        })
+    }
+}
+
+pub struct SharedMemory {
+    start: usize,
+    map: MmapMut,
+}
+
+impl SharedMemory {
+    /// Maps the window, creating it if no other instance did yet
+    pub fn open(config: &SharedMemoryConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(config.path())?;
+
+        if file.metadata()?.len() < config.len as u64 {
+            file.set_len(config.len as u64)?;
+        }
+
+        let map = unsafe { MmapOptions::new().len(config.len).map_mut(&file)? };
+        Ok(Self {
+            start: config.start,
+            map,
+        })
+    }
+
+    /// The bytes from address `i` until the end of the window
+    pub fn bytes(&self, i: usize) -> &[u8] {
+        &self.map[i - self.start..]
+    }
+
+    pub fn bytes_mut(&mut self, i: usize) -> &mut [u8] {
+        &mut self.map[i - self.start..]
+    }
+}
+
+impl Device for SharedMemory {
+    fn read(&self, addr: usize, read: &mut dyn FnMut(&[u8])) {
+        read(self.bytes(addr))
+    }
+
+    fn write(&mut self, addr: usize, write: &mut dyn FnMut(&mut [u8])) {
+        write(self.bytes_mut(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_memory() {
+        let config: SharedMemoryConfig = format!("test-{}:0x1000:16", std::process::id())
+            .parse()
+            .unwrap();
+        assert_eq!((config.start, config.len), (0x1000, 16));
+
+        // Two simulators would map the window like this
+        let mut a = SharedMemory::open(&config).unwrap();
+        let b = SharedMemory::open(&config).unwrap();
+        a.bytes_mut(0x1004)[..2].copy_from_slice(&[42, 43]);
+        assert_eq!(b.bytes(0x1004)[..2], [42, 43]);
+        std::fs::remove_file(config.path()).unwrap();
+
+        assert!("game:0x1000".parse::<SharedMemoryConfig>().is_err());
+        assert!("../game:0x1000:16".parse::<SharedMemoryConfig>().is_err());
+        assert!("game:0x1000:0".parse::<SharedMemoryConfig>().is_err());
+        assert!("game:0xff000000:16".parse::<SharedMemoryConfig>().is_err());
+        assert!("game:nowhere:16".parse::<SharedMemoryConfig>().is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//!
+//! Snapshots of a running program, so a long setup, like a game board that takes a while to be
+//! drawn, doesn't have to run again each time. A snapshot is saved with the `save` command of the
+//! [debugger](../debugger/index.html), or with `--save-at LOCATION`, the first time the program
+//! gets to an address or a label, and `--restore PATH` starts the program from it instead of from
+//! its first instruction.
+//!
+//! It has the pc, the registers, the CSRs, the vector registers, the memory the program can see,
+//! with the video and the keyboard, and the end of the heap. It can only be restored to the same
+//! program, run with the same options, since the code isn't in it. The files the program opened,
+//! what it printed, the time and the shared memory aren't in it either, and a snapshot can't be
+//! saved once the program loaded others.
+//!
+
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use fnv::FnvHasher;
+
+use super::debugger::Location;
+use super::memory::Image;
+use super::Simulator;
+
+/// What a snapshot starts with, followed by its version
+const MAGIC: &[u8; 16] = b"FPGRARS snapshot";
+const VERSION: u32 = 1;
+
+/// Where and when to save snapshots, as given in the command line
+#[derive(Debug, Default)]
+pub struct Saving {
+    /// Where the snapshots go, unless the `save` command says otherwise
+    path: Option<PathBuf>,
+    at: Option<Location>,
+    /// The address of `at` once the program is loaded, until the snapshot is saved there
+    address: Option<usize>,
+}
+
+impl Saving {
+    pub(super) fn set_path(&mut self, path: PathBuf) {
+        self.path = Some(path);
+    }
+
+    pub(super) fn set_at(&mut self, location: Location) {
+        self.at = Some(location);
+    }
+
+    pub(super) fn is_on(&self) -> bool {
+        self.at.is_some()
+    }
+}
+
+/// The state of a program, read from a file, until it's restored
+pub struct Snapshot {
+    /// The [fingerprint](../struct.Simulator.html#method.fingerprint) of the program it's of
+    fingerprint: u64,
+    pc: usize,
+    instructions: u64,
+    user_mode: bool,
+    reservation: Option<usize>,
+    registers: [u64; 32],
+    floats: [u64; 32],
+    status: Vec<u32>,
+    vl: u32,
+    vtype: u32,
+    vectors: Vec<u32>,
+    memory: Image,
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_owned())
+}
+
+fn read_u32s<R: Read>(input: &mut R) -> io::Result<Vec<u32>> {
+    let mut xs = vec![0; input.read_u32::<LittleEndian>()? as usize];
+    input.read_u32_into::<LittleEndian>(&mut xs)?;
+    Ok(xs)
+}
+
+fn write_u32s<W: Write>(out: &mut W, xs: &[u32]) -> io::Result<()> {
+    out.write_u32::<LittleEndian>(xs.len() as u32)?;
+    xs.iter().try_for_each(|&x| out.write_u32::<LittleEndian>(x))
+}
+
+impl Snapshot {
+    fn read<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        input.read_exact(&mut magic)?;
+        if magic != *MAGIC {
+            return Err(invalid("it isn't a snapshot of FPGRARS"));
+        }
+        if input.read_u32::<LittleEndian>()? != VERSION {
+            return Err(invalid("it's from another version of FPGRARS"));
+        }
+
+        let fingerprint = input.read_u64::<LittleEndian>()?;
+        let pc = input.read_u64::<LittleEndian>()? as usize;
+        let instructions = input.read_u64::<LittleEndian>()?;
+        let user_mode = input.read_u8()? != 0;
+        let reservation = match input.read_u64::<LittleEndian>()? {
+            u64::MAX => None,
+            address => Some(address as usize),
+        };
+        let (mut registers, mut floats) = ([0; 32], [0; 32]);
+        input.read_u64_into::<LittleEndian>(&mut registers)?;
+        input.read_u64_into::<LittleEndian>(&mut floats)?;
+        let status = read_u32s(input)?;
+        let (vl, vtype) = (input.read_u32::<LittleEndian>()?, input.read_u32::<LittleEndian>()?);
+        let vectors = read_u32s(input)?;
+        let memory = Image::read(input)?;
+
+        Ok(Self { fingerprint, pc, instructions, user_mode, reservation, registers, floats, status, vl, vtype, vectors, memory })
+    }
+}
+
+impl Simulator {
+    /// Saves the state of the program to `path`, to [restore](#method.restore) it later, see
+    /// [snapshot](snapshot/index.html)
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()> {
+        if self.processes.count() > 1 {
+            return Err(io::Error::other("the program loaded others, whose code isn't in the snapshot"));
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_u32::<LittleEndian>(VERSION)?;
+        out.write_u64::<LittleEndian>(self.fingerprint())?;
+
+        out.write_u64::<LittleEndian>(self.pc as u64)?;
+        // The instruction at the pc is counted before it runs
+        out.write_u64::<LittleEndian>(self.stats.instructions.saturating_sub(1))?;
+        out.write_u8(self.user_mode as u8)?;
+        out.write_u64::<LittleEndian>(self.reservation.map_or(u64::MAX, |address| address as u64))?;
+        for &x in self.registers.iter().chain(&self.floats) {
+            out.write_u64::<LittleEndian>(x)?;
+        }
+        write_u32s(&mut out, &self.status)?;
+        out.write_u32::<LittleEndian>(self.vectors.vl)?;
+        out.write_u32::<LittleEndian>(self.vectors.vtype)?;
+        write_u32s(&mut out, self.vectors.registers())?;
+        self.memory.write_image(&mut out)?;
+        out.flush()
+    }
+
+    /// Starts the program from the snapshot at `path`, once it runs, instead of from its first
+    /// instruction. It has to be a snapshot of the program that's loaded, see
+    /// [snapshot](snapshot/index.html)
+    pub fn restore(&mut self, path: &Path) -> io::Result<()> {
+        let snapshot = Snapshot::read(&mut BufReader::new(File::open(path)?))?;
+        if snapshot.fingerprint != self.fingerprint() {
+            return Err(invalid("it's of another program, or of this one run with other options"));
+        }
+        if snapshot.vectors.len() != self.vectors.registers().len() {
+            return Err(invalid("it has vector registers of another size"));
+        }
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+
+    /// A hash of the code and of the options that change how it runs, to know a snapshot is of
+    /// this program
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        (self.rv64, self.rvc, self.text_base, self.vectors.vlenb()).hash(&mut hasher);
+        for instruction in &self.code {
+            format!("{:?}", instruction).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Puts back the state of the snapshot to restore, and works out where to save one, once the
+    /// program is loaded
+    pub(super) fn init_snapshot(&mut self) {
+        if let Some(location) = &self.saving.at {
+            self.saving.address = self.address_of(location);
+            if let (None, Location::Label(name)) = (self.saving.address, location) {
+                eprintln!("Warning: there's no label `{}` in the code to save a snapshot at", name);
+            }
+        }
+
+        let Some(snapshot) = self.snapshot.take() else { return };
+        self.pc = snapshot.pc;
+        self.stats.instructions = snapshot.instructions;
+        self.user_mode = snapshot.user_mode;
+        self.reservation = snapshot.reservation;
+        self.registers = snapshot.registers;
+        self.floats = snapshot.floats;
+        let n = self.status.len().min(snapshot.status.len());
+        self.status[..n].copy_from_slice(&snapshot.status[..n]);
+        self.vectors.vl = snapshot.vl;
+        self.vectors.vtype = snapshot.vtype;
+        self.vectors.registers_mut().copy_from_slice(&snapshot.vectors);
+        self.memory.restore(snapshot.memory);
+        self.stack = self.memory.stack(0);
+    }
+
+    /// Saves the snapshot of `--save-at`, the first time the program gets there
+    pub(super) fn check_save_at(&mut self) {
+        if self.saving.address != Some(self.pc) {
+            return;
+        }
+        self.saving.address = None;
+        if let Some(path) = self.saving.path.clone() {
+            if let Err(e) = self.save_snapshot(&path) {
+                eprintln!("Couldn't save the snapshot `{}`: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Saves a snapshot for the `save` command of the debugger, to `path` or to the one of
+    /// `--snapshot`, and says how it went
+    pub(super) fn save_command(&self, path: &str) -> String {
+        let path = match (path, &self.saving.path) {
+            ("", Some(path)) => path.clone(),
+            ("", None) => return "Where to? Like `save board.snapshot`".to_owned(),
+            (path, _) => PathBuf::from(path),
+        };
+        match self.save_snapshot(&path) {
+            Ok(()) => format!("Saved a snapshot to `{}`", path.display()),
+            Err(e) => format!("Couldn't save the snapshot `{}`: {}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(code: &str, debug: bool) -> Simulator {
+        Simulator::new()
+            .debug(debug)
+            .load_str(code)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_snapshot() {
+        // Draws a pixel, takes some heap and counts to 10, and the snapshot is saved halfway
+        let code = ".data\ncount: .word 0\n.text
+            li t0, 0xff000010\nli t1, 0x38\nsb t1, 0(t0)\nli a0, 16\nli a7, 9\necall\nmv s1, a0\nla s0, count
+            loop: lw t1, 0(s0)\naddi t1, t1, 1\nsw t1, 0(s0)\nli t2, 5\nbne t1, t2, skip
+            half: sw t1, 0(s1)
+            skip: li t2, 10\nblt t1, t2, loop
+            lw a0, 0(s1)\nadd a0, a0, t1\nli a7, 93\necall";
+        let path = std::env::temp_dir().join(format!("fpgrars-snapshot-{}", std::process::id()));
+
+        let mut sim = load(code, false);
+        sim.saving.set_path(path.clone());
+        sim.saving.set_at("half".parse().unwrap());
+        assert_eq!(sim.run(), 15);
+        let instructions = sim.stats.instructions;
+
+        // Picks up at `half`, where the count is 5
+        let mut sim = load(code, true);
+        sim.debugger.set_keys("q\n");
+        sim.restore(&path).unwrap();
+        assert_eq!(sim.run(), 1);
+        assert_eq!(Some(sim.pc), sim.resolve_address("half"));
+        assert_eq!(sim.memory.get_word(sim.resolve_address("count").unwrap()), 5);
+        assert_eq!(sim.memory.get_byte(0xff000010), 0x38);
+
+        // And the heap is still there
+        let mut sim = load(code, false);
+        sim.restore(&path).unwrap();
+        assert_eq!(sim.run(), 15);
+        assert_eq!(sim.stats.instructions, instructions);
+
+        let mut other = load("li a7, 93\necall", false);
+        let e = other.restore(&path).unwrap_err();
+        assert!(e.to_string().contains("another program"), "{}", e);
+        std::fs::remove_file(path).unwrap();
+    }
+}
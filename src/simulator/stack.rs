@@ -0,0 +1,176 @@
+//!
+//! The stack of a program, at the end of its memory after the data and the heap by default, or
+//! anywhere with [Simulator::stack_top](../struct.Simulator.html#method.stack_top) and
+//! [stack_size](../struct.Simulator.html#method.stack_size). `sp` starts at its last word.
+//!
+//! A recursion that never ends keeps pushing until the stack runs into the heap, and then it
+//! quietly overwrites the data below it, which only breaks much later. So with a `stack_top` or a
+//! `stack_size`, which leave room between the heap and the stack, a store through `sp` to that room
+//! stops the program right there, with the calls that got it there. Below it are the data and the
+//! heap, where a program can keep a stack of its own, like with `la sp, top`. Without them the
+//! stack starts right where the heap ends, so there's no way to tell. We don't keep track of the calls while running: the trace is made of the words on the
+//! stack that are the address right after a call, which is what a function pushes as `ra`. A small
+//! number that happens to be one of those addresses shows up too, when the code starts at 0.
+//!
+
+use std::ops::Range;
+
+use super::Simulator;
+use crate::parser::{self, Instruction};
+
+/// How many different calls of the trace we show, the most recent ones
+const SHOWN_CALLS: usize = 16;
+
+impl Simulator {
+    /// Moves the stack of the program just loaded to the `stack_top`, with `stack_size` bytes
+    pub(super) fn place_stack(&mut self) {
+        let stack = self.memory.stack(0);
+        let end = self.stack_top.map_or(stack.end, |top| top + 4);
+        let start = end.saturating_sub(self.stack_size.unwrap_or(stack.len()));
+        self.memory.move_stack(start..end);
+        self.stack = start..end;
+    }
+
+    /// Whether a store through `sp` to `addr` went past the start of the stack, into the room
+    /// between it and the heap
+    pub(super) fn overflows_stack(&self, addr: usize) -> bool {
+        addr < self.stack.start
+            && (self.stack_top.is_some() || self.stack_size.is_some())
+            && addr >= self.memory.heap(self.processes.current()).end
+    }
+
+    /// Explains that a store through `sp` to `addr` went past the start of the stack
+    pub(super) fn report_stack_overflow(&self, addr: usize) {
+        eprintln!(
+            "Stack overflow: tried to write to {:#x}, below the start of the stack at {:#x}",
+            addr, self.stack.start
+        );
+        eprintln!("  at {:#x}{}: {}", self.pc, self.source_at(self.pc), self.code[self.slot(self.pc)]);
+        self.report_calls();
+    }
+
+    /// Prints the calls that got the program to where it is, found on the stack
+    pub(super) fn report_calls(&self) {
+        let calls = self.call_trace(self.stack_of_current());
+        if calls.is_empty() {
+            return;
+        }
+        // A recursion makes the same call over and over, which we show once
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for &pc in &calls {
+            match runs.last_mut() {
+                Some((last, times)) if *last == pc => *times += 1,
+                _ => runs.push((pc, 1)),
+            }
+        }
+
+        eprintln!("Called from, most recent first:");
+        for &(pc, times) in runs.iter().take(SHOWN_CALLS) {
+            let function = self.function_at(pc).map(|name| format!(" in {}", name)).unwrap_or_default();
+            let times = if times > 1 { format!(" ({} times)", times) } else { String::new() };
+            eprintln!("  {:#x}{}{}{}", pc, function, self.source_at(pc), times);
+        }
+        if runs.len() > SHOWN_CALLS {
+            eprintln!("  ... and {} more", runs.len() - SHOWN_CALLS);
+        }
+    }
+
+    /// The part of the stack the current program is using, from `sp` up
+    pub(super) fn stack_of_current(&self) -> Range<usize> {
+        let stack = self.stack.clone();
+        let sp = self.get_reg::<u32>(2) as usize;
+        sp.clamp(stack.start, stack.end)..stack.end
+    }
+
+    /// The addresses of the calls that returned addresses pushed in `stack`, from the top of the
+    /// stack down, which are the most recent first
+    pub(super) fn call_trace(&self, stack: Range<usize>) -> Vec<usize> {
+        stack
+            .step_by(4)
+            .filter_map(|addr| self.call_before(self.memory.get_word(addr) as usize))
+            .collect()
+    }
+
+    /// The address of the call that returns to `ra`, if the instruction right before `ra` is one
+    pub(super) fn call_before(&self, ra: usize) -> Option<usize> {
+        if ra <= self.text_base || self.slot(ra) > self.code.len() {
+            return None;
+        }
+        let mut slot = self.slot(ra) - 1;
+        if self.code[slot].is_padding() {
+            slot = slot.checked_sub(1)?;
+        }
+        match self.code[slot] {
+            Instruction::Jal(1, _) | Instruction::Jalr(1, _, _) => Some(self.code_address(slot)),
+            _ => None,
+        }
+    }
+
+    /// The name of the last label of the code at or before `pc`
+    pub(super) fn function_at(&self, pc: usize) -> Option<&str> {
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.segment == parser::Segment::Text && symbol.address <= pc)
+            .max_by_key(|symbol| symbol.address)
+            .map(|symbol| symbol.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(code: &str) -> Simulator {
+        Simulator::new()
+            .text_base(0x0040_0000)
+            .stack_size(0x100)
+            .load_str(code)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // Pushes 8 bytes per call, so it runs out of stack after 32 calls
+        let code = ".data\nx: .word 0\n.text\nli a0, 40\ncall sum\nli a7, 93\necall
+            sum: addi sp, sp, -8\nsw ra, 4(sp)\nsw a0, 0(sp)\nbeqz a0, done\naddi a0, a0, -1\ncall sum
+            lw t0, 0(sp)\nadd a0, a0, t0\ndone: lw ra, 4(sp)\naddi sp, sp, 8\nret";
+
+        let mut sim = load(code);
+        assert_eq!(sim.run(), 1);
+        let calls = sim.call_trace(sim.stack_of_current());
+        assert_eq!(calls.len(), 32);
+        assert_eq!(calls[0], sim.code_address(9)); // the `call sum` in `sum`
+        assert_eq!(calls[31], sim.code_address(1)); // the first `call sum`
+        assert_eq!(sim.function_at(calls[0]), Some("sum"));
+        assert_eq!(sim.memory.get_word(sim.stack.start - 4), 0); // the heap, untouched
+
+        assert_eq!(load(&code.replace("li a0, 40", "li a0, 10")).run(), 55);
+    }
+
+    #[test]
+    fn test_stack_in_data() {
+        // A stack of its own in the data isn't an overflow, with the stack anywhere
+        let code = ".data\nbuffer: .space 16\ntop:\n.text\nla sp, top\naddi sp, sp, -4\nli t0, 7\nsw t0, 0(sp)
+            lw a0, 0(sp)\nli a7, 93\necall";
+        assert_eq!(Simulator::new().load_str(code).unwrap().run(), 7);
+        assert_eq!(load(code).run(), 7);
+        assert_eq!(Simulator::new().rars_layout(true).load_str(code).unwrap().run(), 7);
+    }
+
+    #[test]
+    fn test_stack_top() {
+        let code = "mv a0, sp\nli a7, 93\necall";
+        let mut sim = Simulator::new()
+            .stack_top(0x0100_0000)
+            .load_str(code)
+            .unwrap();
+        assert_eq!(sim.run(), 0x0100_0000);
+        assert_eq!(sim.memory.stack(0).end, 0x0100_0004);
+
+        // The heap can grow into what's left between the data and the stack
+        let sim = load(code);
+        let end = sim.memory.stack(0).end;
+        assert_eq!(sim.memory.stack(0), end - 0x100..end);
+        assert_eq!(sim.memory.segment(end - 0x104), None);
+    }
+}
@@ -0,0 +1,320 @@
+//!
+//! The trace of a run, for `--trace PATH`: a line for each instruction that ran, with its address,
+//! the instruction, the registers it changed and the memory it read or wrote, like
+//!
+//! ```text
+//! 0x00000008  addi sp, sp, -16              sp = 0x7ffffff0
+//! 0x0000000c  sw ra, 12(sp)                 wrote 0x00000010 to 0x7ffffffc
+//! 0x00000010  lw a0, 0(a1)                  a0 = 0x00000005, read 4 bytes at 0x00002000
+//! ```
+//!
+//! An instruction that came from a macro ends with the line of the `.macro` it was written in, as a
+//! frame inlined where the macro was used, like
+//! ``(inlined from macro `inc` at main.s:2: addi %r, %r, 1)``.
+//!
+//! What an instruction changed is only known once it ran, so each line is written before the next
+//! instruction, and the last one when the program exits. A long program makes a huge trace, so it
+//! can be limited to some parts of the code with `--trace-only`, which takes a range of addresses,
+//! like `0x400000:0x400100`, or a label, for the code from it to the next label.
+//!
+
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::str::FromStr;
+
+use super::Simulator;
+use crate::parser::combinators::integer_literal;
+use crate::parser::register_names::{FLOAT_NAMES, REGISTER_NAMES};
+use crate::parser::{self, FloatInstruction as F, Instruction, Rv64Instruction as R};
+
+/// Which instructions to trace, as given in the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceFilter {
+    /// The instructions at these addresses
+    Range(Range<usize>),
+    /// The instructions from this label to the next one
+    Label(String),
+}
+
+impl FromStr for TraceFilter {
+    type Err = String;
+
+    /// Parses `start:end`, like `0x400000:0x400100`, or a label
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((start, end)) = s.split_once(':') {
+            let number = |x: &str| {
+                integer_literal(x)
+                    .map(|x| x as usize)
+                    .map_err(|_| format!("expected an address, got `{}`", x))
+            };
+            let (start, end) = (number(start)?, number(end)?);
+            if start >= end {
+                return Err(format!("the range `{}` is empty", s));
+            }
+            return Ok(TraceFilter::Range(start..end));
+        }
+
+        let valid_label = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$';
+        if s.is_empty() || !s.chars().all(valid_label) {
+            return Err(format!("expected `start:end` or a label, got `{}`", s));
+        }
+        Ok(TraceFilter::Label(s.to_owned()))
+    }
+}
+
+/// How an instruction touches the memory
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Access {
+    Read,
+    /// With the value written
+    Write(u64),
+    /// Atomics read and write
+    Both,
+}
+
+/// The instruction that's running, and what things were like before it
+struct Running {
+    pc: usize,
+    slot: usize,
+    registers: [u64; 32],
+    floats: [u64; 32],
+    /// The access and its address and size, worked out before the instruction changes the
+    /// registers they come from
+    access: Option<(Access, usize, usize)>,
+}
+
+pub struct Trace {
+    out: BufWriter<Box<dyn Write + Send>>,
+    filters: Vec<TraceFilter>,
+    /// The addresses the filters cover, or everything if there are none
+    ranges: Vec<Range<usize>>,
+    running: Option<Running>,
+}
+
+impl Trace {
+    pub(super) fn new(out: Box<dyn Write + Send>, filters: Vec<TraceFilter>) -> Self {
+        Self {
+            out: BufWriter::new(out),
+            filters,
+            ranges: Vec::new(),
+            running: None,
+        }
+    }
+
+    fn covers(&self, pc: usize) -> bool {
+        self.filters.is_empty() || self.ranges.iter().any(|range| range.contains(&pc))
+    }
+}
+
+impl Simulator {
+    /// Works out the addresses of the labels of `--trace-only`, once the program is loaded
+    pub(super) fn init_trace(&mut self) {
+        let code_end = self.code_address(self.code.len());
+        let Some(trace) = &mut self.trace else { return };
+        let mut labels: Vec<&parser::Symbol> =
+            self.symbols.iter().filter(|symbol| symbol.segment == parser::Segment::Text).collect();
+        labels.sort_by_key(|symbol| symbol.address);
+
+        trace.ranges.clear();
+        for filter in &trace.filters {
+            match filter {
+                TraceFilter::Range(range) => trace.ranges.push(range.clone()),
+                TraceFilter::Label(name) => match labels.iter().position(|symbol| symbol.name == *name) {
+                    Some(i) => {
+                        let start = labels[i].address;
+                        let next = labels[i..].iter().find(|symbol| symbol.address > start);
+                        trace.ranges.push(start..next.map_or(code_end, |symbol| symbol.address));
+                    }
+                    None => eprintln!("Warning: there's no label `{}` in the code to trace", name),
+                },
+            }
+        }
+    }
+
+    /// Writes the line of the instruction that just ran, and keeps what things are like before the
+    /// one at `pc`, if it's traced
+    pub(super) fn trace_step(&mut self) {
+        let Some(mut trace) = self.trace.take() else { return };
+        if let Some(running) = trace.running.take() {
+            if let Err(e) = self.write_trace_line(&mut trace.out, &running) {
+                eprintln!("Couldn't write the trace: {}", e);
+                return;
+            }
+        }
+
+        if trace.covers(self.pc) {
+            let slot = self.slot(self.pc);
+            trace.running = Some(Running {
+                pc: self.pc,
+                slot,
+                registers: self.registers,
+                floats: self.floats,
+                access: self.memory_access(&self.code[slot]),
+            });
+        }
+        self.trace = Some(trace);
+    }
+
+    /// Writes the line of the last instruction, after the program exited
+    pub(super) fn finish_trace(&mut self) {
+        self.trace_step();
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = trace.out.flush() {
+                eprintln!("Couldn't write the trace: {}", e);
+            }
+        }
+    }
+
+    fn write_trace_line<W: Write>(&self, out: &mut W, running: &Running) -> io::Result<()> {
+        let mut effects = Vec::new();
+        let registers = self.registers.iter().zip(running.registers).zip(REGISTER_NAMES);
+        for ((&x, _), name) in registers.filter(|((&x, before), _)| x != *before) {
+            effects.push(format!("{} = {}", name, self.hex(x)));
+        }
+        let floats = self.floats.iter().zip(running.floats).zip(FLOAT_NAMES);
+        for ((&x, _), name) in floats.filter(|((&x, before), _)| x != *before) {
+            // A single is NaN-boxed, anything else is a double
+            let value = if x >> 32 == 0xffff_ffff { f32::from_bits(x as u32) as f64 } else { f64::from_bits(x) };
+            effects.push(format!("{} = {}", name, value));
+        }
+        match running.access {
+            Some((Access::Read, addr, size)) => effects.push(format!("read {} bytes at {:#010x}", size, addr)),
+            Some((Access::Write(x), addr, size)) => {
+                let x = if size < 8 { x & ((1 << (size * 8)) - 1) } else { x };
+                effects.push(format!("wrote {:#0width$x} to {:#010x}", x, addr, width = size * 2 + 2));
+            }
+            Some((Access::Both, addr, size)) => effects.push(format!("read and wrote {} bytes at {:#010x}", size, addr)),
+            None => {}
+        }
+
+        let mut effects = effects.join(", ");
+        let pos = self.code_pos.get(running.slot).and_then(Option::as_ref);
+        if let Some(origin) = pos.and_then(|pos| pos.macro_origin.as_ref()) {
+            let gap = if effects.is_empty() { "" } else { "  " };
+            effects = format!("{}{}({})", effects, gap, origin);
+        }
+
+        let line = format!("{:#010x}  {:<28}  {}", running.pc, self.code[running.slot].to_string(), effects);
+        writeln!(out, "{}", line.trim_end())
+    }
+
+    /// A register as hex, 32 or 64 bits wide
+    fn hex(&self, x: u64) -> String {
+        if self.rv64 {
+            format!("{:#018x}", x)
+        } else {
+            format!("{:#010x}", x as u32)
+        }
+    }
+
+    /// The memory `instruction` is about to touch, with its address and how many bytes
+    pub(super) fn memory_access(&self, instruction: &Instruction) -> Option<(Access, usize, usize)> {
+        use Instruction::*;
+
+        let addr = |rs1: u8, imm: u32| self.get_reg::<u32>(rs1).wrapping_add(imm) as usize;
+        let store = |rs2: u8| Access::Write(self.registers[rs2 as usize]);
+        let store_float = |rs2: u8| Access::Write(self.floats[rs2 as usize]);
+        let access = match *instruction {
+            Lb(_, imm, rs1) | Lbu(_, imm, rs1) => (Access::Read, addr(rs1, imm), 1),
+            Lh(_, imm, rs1) | Lhu(_, imm, rs1) => (Access::Read, addr(rs1, imm), 2),
+            Lw(_, imm, rs1) | Rv64(R::Lwu(_, imm, rs1)) | Float(F::Lw(_, imm, rs1)) => (Access::Read, addr(rs1, imm), 4),
+            Rv64(R::Ld(_, imm, rs1)) | Float(F::Ld(_, imm, rs1)) => (Access::Read, addr(rs1, imm), 8),
+            Sb(rs2, imm, rs1) => (store(rs2), addr(rs1, imm), 1),
+            Sh(rs2, imm, rs1) => (store(rs2), addr(rs1, imm), 2),
+            Sw(rs2, imm, rs1) => (store(rs2), addr(rs1, imm), 4),
+            Rv64(R::Sd(rs2, imm, rs1)) => (store(rs2), addr(rs1, imm), 8),
+            Float(F::Sw(rs2, imm, rs1)) => (store_float(rs2), addr(rs1, imm), 4),
+            Float(F::Sd(rs2, imm, rs1)) => (store_float(rs2), addr(rs1, imm), 8),
+            LrW(_, rs1) => (Access::Read, addr(rs1, 0), 4),
+            ScW(_, rs2, rs1) => (store(rs2), addr(rs1, 0), 4),
+            AmoSwapW(_, _, rs1) | AmoAddW(_, _, rs1) | AmoXorW(_, _, rs1) | AmoAndW(_, _, rs1) | AmoOrW(_, _, rs1)
+            | AmoMinW(_, _, rs1) | AmoMaxW(_, _, rs1) | AmoMinuW(_, _, rs1) | AmoMaxuW(_, _, rs1) => {
+                (Access::Both, addr(rs1, 0), 4)
+            }
+            _ => return None,
+        };
+        Some(access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Somewhere to write the trace that the test can still read
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(code: &str, filters: Vec<TraceFilter>) -> String {
+        let out = Shared::default();
+        let mut sim = Simulator::new()
+            .trace(Box::new(out.clone()), filters)
+            .load_str(code)
+            .unwrap();
+        sim.run();
+        let bytes = out.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_trace() {
+        let code = ".data\npad: .word 0\nx: .word 5\n.text\nla a1, x\nlw a0, 0(a1)\nsb a0, 1(a1)\ncall f\nli a7, 93\necall
+            f: addi a0, a0, 1\nret";
+
+        let trace_all = trace(code, Vec::new());
+        let lines: Vec<&str> = trace_all.lines().collect();
+        assert_eq!(lines.len(), 9);
+        // `la` is an `auipc`, which doesn't change a1 since it's 0 already, and an `addi`
+        assert_eq!(lines[0], "0x00000000  auipc a1, 0x0");
+        assert_eq!(lines[1], format!("0x00000004  {:<28}  a1 = 0x00000004", "addi a1, a1, 4"));
+        assert_eq!(lines[2], format!("0x00000008  {:<28}  a0 = 0x00000005, read 4 bytes at 0x00000004", "lw a0, 0(a1)"));
+        assert_eq!(lines[3], format!("0x0000000c  {:<28}  wrote 0x05 to 0x00000005", "sb a0, 1(a1)"));
+        assert_eq!(lines[4], format!("0x00000010  {:<28}  ra = 0x00000014", "jal ra, 0x1c"));
+        // The ecall that exits is traced too
+        assert_eq!(lines[8], "0x00000018  ecall");
+
+        // Only `f`
+        let only_f = trace(code, vec!["f".parse().unwrap()]);
+        assert_eq!(only_f.lines().count(), 2);
+        assert!(only_f.starts_with("0x0000001c  addi a0, a0, 1"));
+        let range = trace(code, vec!["0x8:0x10".parse().unwrap()]);
+        assert_eq!(range.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_trace_macro() {
+        let code = ".macro inc(%r)\naddi %r, %r, 1\nnop\n.end_macro\n.text\ninc(a0)\nli a7, 93\necall";
+        let trace = trace(code, Vec::new());
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!(
+                "0x00000000  {:<28}  a0 = 0x00000001  (inlined from macro `inc` at main.s:2: addi %r, %r, 1)",
+                "addi a0, a0, 1"
+            )
+        );
+        assert_eq!(lines[1], format!("0x00000004  {:<28}  (inlined from macro `inc` at main.s:3: nop)", "nop"));
+        assert_eq!(lines[2], format!("0x00000008  {:<28}  a7 = 0x0000005d", "li a7, 93"));
+    }
+
+    #[test]
+    fn test_trace_filter() {
+        assert_eq!("0x10:0x20".parse(), Ok(TraceFilter::Range(0x10..0x20)));
+        assert_eq!("main".parse(), Ok(TraceFilter::Label("main".to_owned())));
+        assert!("0x20:0x10".parse::<TraceFilter>().is_err());
+        assert!("0x10:end".parse::<TraceFilter>().is_err());
+        assert!("two words".parse::<TraceFilter>().is_err());
+    }
+}
@@ -0,0 +1,499 @@
+//!
+//! A debugger in the terminal, for `--tui`, with panes for the code around the pc, the registers,
+//! the memory and the output of the program. It's the [debugger](../debugger/index.html) with keys
+//! instead of commands: the program starts paused, `s`, `n` and `f` step, next and finish, `c`
+//! continues, `b` adds a breakpoint, `m` shows the memory at an address, a register or a label,
+//! the arrows scroll it, `w` saves a [snapshot](../snapshot/index.html), and `q` quits. `S` steps back and `C` goes back to the last breakpoint, see
+//! [history](../history/index.html).
+//!
+//! While the program runs, the panes are drawn again every so many instructions, and `p` pauses it.
+//! The output of the program goes to its pane instead of the terminal, and is printed when it
+//! exits, and what the program reads is typed in the bottom line.
+//!
+
+use std::time::Duration;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::terminal;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use super::debugger::{Mode, Resume};
+use super::Simulator;
+use crate::parser::register_names::REGISTER_NAMES;
+
+/// How many instructions run between two draws while the program runs
+const DRAW_EVERY: u64 = 1 << 18;
+
+/// Bytes in each line of the memory pane
+const MEMORY_LINE: usize = 16;
+
+/// What's being typed in the bottom line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Prompt {
+    Memory,
+    Break,
+    Save,
+    /// A line of input for the program
+    Input,
+}
+
+/// Where the panes are drawn, and where the keys come from
+enum Screen {
+    Terminal(DefaultTerminal),
+    #[cfg(test)]
+    Test(ratatui::Terminal<ratatui::backend::TestBackend>, std::vec::IntoIter<KeyCode>),
+}
+
+/// What the panes show, besides the state of the simulator
+#[derive(Debug)]
+struct View {
+    /// The first address of the memory pane
+    memory: usize,
+    typing: Option<(Prompt, String)>,
+    /// Shown in the bottom line until the next key
+    message: String,
+    running: bool,
+}
+
+pub struct Tui {
+    screen: Screen,
+    view: View,
+    /// Instructions until the next draw while running
+    countdown: u64,
+}
+
+impl Tui {
+    fn new(screen: Screen, memory: usize) -> Self {
+        Self {
+            screen,
+            view: View {
+                memory,
+                typing: None,
+                message: String::new(),
+                running: false,
+            },
+            countdown: DRAW_EVERY,
+        }
+    }
+
+    fn draw(&mut self, sim: &Simulator) {
+        let view = &self.view;
+        // There's nowhere to say it if the terminal is gone
+        let _ = match &mut self.screen {
+            Screen::Terminal(terminal) => terminal.draw(|frame| view.render(sim, frame)).map(drop),
+            #[cfg(test)]
+            Screen::Test(terminal, _) => terminal.draw(|frame| view.render(sim, frame)).map(drop),
+        };
+    }
+
+    /// Waits for a key, or returns `None` if there are no more
+    fn read_key(&mut self) -> Option<KeyEvent> {
+        match &mut self.screen {
+            Screen::Terminal(_) => loop {
+                match event::read() {
+                    Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => return Some(key),
+                    Ok(_) => {}
+                    Err(_) => return None,
+                }
+            },
+            #[cfg(test)]
+            Screen::Test(_, keys) => keys.next().map(KeyEvent::from),
+        }
+    }
+
+    /// A key pressed while the program runs, if there's one
+    fn poll_key(&mut self) -> Option<KeyEvent> {
+        match &mut self.screen {
+            Screen::Terminal(_) => match event::poll(Duration::ZERO) {
+                Ok(true) => match event::read() {
+                    Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => Some(key),
+                    _ => None,
+                },
+                _ => None,
+            },
+            #[cfg(test)]
+            Screen::Test(..) => None,
+        }
+    }
+
+    /// Gives the terminal back
+    fn restore(&mut self) {
+        match &mut self.screen {
+            Screen::Terminal(terminal) => {
+                let _ = terminal.show_cursor();
+                ratatui::restore();
+            }
+            #[cfg(test)]
+            Screen::Test(..) => {}
+        }
+    }
+}
+
+impl View {
+    fn render(&self, sim: &Simulator, frame: &mut Frame) {
+        let register_width = if sim.rv64 { 52 } else { 36 };
+        let rows = [Constraint::Percentage(60), Constraint::Fill(1), Constraint::Length(1)];
+        let [top, bottom, status] = Layout::vertical(rows).areas(frame.area());
+        let [code, registers] = Layout::horizontal([Constraint::Fill(1), Constraint::Length(register_width)]).areas(top);
+        let [memory, output] = Layout::horizontal([Constraint::Length(79), Constraint::Fill(1)]).areas(bottom);
+
+        // Inside the borders
+        let height = |area: Rect| area.height.saturating_sub(2) as usize;
+        let pane = |lines, title| Paragraph::new(lines).block(Block::bordered().title(title));
+        frame.render_widget(pane(self.code(sim, height(code)), " Code "), code);
+        frame.render_widget(pane(self.registers(sim), " Registers "), registers);
+        frame.render_widget(pane(self.memory(sim, height(memory)), " Memory "), memory);
+        frame.render_widget(pane(self.output(sim, height(output)), " Output "), output);
+        frame.render_widget(Paragraph::new(self.status()), status);
+    }
+
+    /// The instructions around the pc, with `>` at the pc and `*` at the breakpoints
+    fn code(&self, sim: &Simulator, height: usize) -> Vec<Line<'static>> {
+        let at = sim.slot(sim.pc);
+        let slots = (0..sim.code.len()).filter(|&slot| !sim.code[slot].is_padding());
+        slots
+            .skip_while(|&slot| slot + height / 3 < at)
+            .take(height)
+            .map(|slot| {
+                let address = sim.code_address(slot);
+                let mark = match address {
+                    _ if slot == at => '>',
+                    _ if sim.debugger.breakpoints().contains(&address) => '*',
+                    _ => ' ',
+                };
+                let source = match &sim.code_pos[slot] {
+                    Some(pos) => format!("{}:{}  {}", pos.file, pos.line, pos.source.trim()),
+                    None => String::new(),
+                };
+                let instruction = sim.code[slot].to_string();
+                let line = Line::from(format!("{} {:#010x}  {:<24} {}", mark, address, instruction, source));
+                if slot == at {
+                    line.style(Style::new().add_modifier(Modifier::REVERSED))
+                } else {
+                    line
+                }
+            })
+            .collect()
+    }
+
+    /// The pc and the integer registers, 2 to a line
+    fn registers(&self, sim: &Simulator) -> Vec<Line<'static>> {
+        let width = if sim.rv64 { 18 } else { 10 };
+        let value = |i: usize| if sim.rv64 { sim.registers[i] } else { sim.registers[i] as u32 as u64 };
+        let mut lines = vec![Line::from(format!("{:>4} {:#0width$x}", "pc", sim.pc, width = width))];
+        for i in (0..REGISTER_NAMES.len()).step_by(2) {
+            lines.push(Line::from(format!(
+                "{:>4} {:#0width$x}  {:>4} {:#0width$x}",
+                REGISTER_NAMES[i],
+                value(i),
+                REGISTER_NAMES[i + 1],
+                value(i + 1),
+                width = width
+            )));
+        }
+        lines
+    }
+
+    /// The bytes of memory from `self.memory`, with `--` where there's nothing
+    fn memory(&self, sim: &Simulator, height: usize) -> Vec<Line<'static>> {
+        (0..height)
+            .map(|line| {
+                let start = self.memory + line * MEMORY_LINE;
+                let mut hex = String::new();
+                let mut text = String::new();
+                for address in start..start + MEMORY_LINE {
+                    if sim.memory.is_mapped(address, 1) {
+                        let byte = sim.memory.get_byte(address);
+                        hex += &format!(" {:02x}", byte);
+                        text.push(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' });
+                    } else {
+                        hex += " --";
+                        text.push(' ');
+                    }
+                }
+                Line::from(format!("{:#010x}{}  {}", start, hex, text))
+            })
+            .collect()
+    }
+
+    /// The last lines the program printed
+    fn output(&self, sim: &Simulator, height: usize) -> Vec<Line<'static>> {
+        let lines: Vec<&str> = sim.console.captured_lines().collect();
+        let shown = &lines[lines.len().saturating_sub(height)..];
+        shown.iter().map(|&line| Line::from(line.to_owned())).collect()
+    }
+
+    fn status(&self) -> Line<'static> {
+        if let Some((prompt, text)) = &self.typing {
+            let what = match prompt {
+                Prompt::Memory => "Show the memory at",
+                Prompt::Break => "Break at",
+                Prompt::Save => "Save a snapshot to",
+                Prompt::Input => "Input",
+            };
+            return Line::from(format!("{}: {}_", what, text));
+        }
+        if !self.message.is_empty() {
+            return Line::from(self.message.clone());
+        }
+        if self.running {
+            Line::from("Running... p pause  q quit")
+        } else {
+            Line::from("s step  n next  f finish  c continue  S C back  b break  m memory  w save  ↑↓ PgUp PgDn scroll  q quit")
+        }
+    }
+}
+
+/// The text typed, once Enter is pressed
+fn type_key(text: &mut String, key: KeyEvent) -> Option<String> {
+    match key.code {
+        KeyCode::Enter => return Some(std::mem::take(text)),
+        KeyCode::Backspace => {
+            text.pop();
+        }
+        KeyCode::Char(c) => text.push(c),
+        _ => {}
+    }
+    None
+}
+
+fn is_quit(key: KeyEvent) -> bool {
+    key.code == KeyCode::Char('q') || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL))
+}
+
+impl Simulator {
+    /// Shows where we are and waits for keys until one of them runs the program again
+    pub(super) fn tui_pause(&mut self, what: &str) -> Resume {
+        let Some(mut tui) = self.terminal.take() else { return Resume::Continue };
+        tui.view.running = false;
+        if !what.is_empty() {
+            tui.view.message = format!("Paused at {} at {:#x}", what, self.pc);
+        }
+
+        let resume = loop {
+            tui.draw(self);
+            let Some(key) = tui.read_key() else { break Resume::Quit };
+            tui.view.message.clear();
+            if let Some((prompt, text)) = &mut tui.view.typing {
+                if key.code == KeyCode::Esc {
+                    tui.view.typing = None;
+                } else if let Some(text) = type_key(text, key) {
+                    let prompt = *prompt;
+                    tui.view.typing = None;
+                    self.tui_submit(&mut tui.view, prompt, &text);
+                }
+                continue;
+            }
+
+            let mode = match key.code {
+                _ if is_quit(key) => break Resume::Quit,
+                KeyCode::Char('s') => Mode::Step,
+                KeyCode::Char('n') => Mode::Over(0),
+                KeyCode::Char('f') => Mode::Over(1),
+                KeyCode::Char('c') => Mode::Run,
+                KeyCode::Char('S') => {
+                    tui.view.message = self.go_back(1);
+                    continue;
+                }
+                KeyCode::Char('C') => {
+                    tui.view.message = if self.reverse_continue() {
+                        format!("Back at the breakpoint at {:#x}", self.pc)
+                    } else {
+                        "Went back as far as the history goes".to_owned()
+                    };
+                    continue;
+                }
+                KeyCode::Char('b') => {
+                    tui.view.typing = Some((Prompt::Break, String::new()));
+                    continue;
+                }
+                KeyCode::Char('m') => {
+                    tui.view.typing = Some((Prompt::Memory, String::new()));
+                    continue;
+                }
+                KeyCode::Char('w') => {
+                    tui.view.typing = Some((Prompt::Save, String::new()));
+                    continue;
+                }
+                KeyCode::Up => {
+                    tui.view.memory = tui.view.memory.saturating_sub(MEMORY_LINE);
+                    continue;
+                }
+                KeyCode::Down => {
+                    tui.view.memory += MEMORY_LINE;
+                    continue;
+                }
+                KeyCode::PageUp => {
+                    tui.view.memory = tui.view.memory.saturating_sub(16 * MEMORY_LINE);
+                    continue;
+                }
+                KeyCode::PageDown => {
+                    tui.view.memory += 16 * MEMORY_LINE;
+                    continue;
+                }
+                _ => continue,
+            };
+            self.debugger.set_mode(mode);
+            tui.view.running = mode != Mode::Step;
+            if tui.view.running {
+                tui.draw(self);
+            }
+            break Resume::Continue;
+        };
+        self.terminal = Some(tui);
+        resume
+    }
+
+    /// Does what the text typed for `prompt` asks
+    fn tui_submit(&mut self, view: &mut View, prompt: Prompt, text: &str) {
+        let text = text.trim();
+        match prompt {
+            Prompt::Memory => match self.resolve_address(text) {
+                Some(address) => view.memory = address,
+                None => view.message = format!("Expected an address, a register or a label, got `{}`", text),
+            },
+            Prompt::Break => match text.parse().ok().and_then(|location| self.address_of(&location)) {
+                Some(address) => {
+                    self.debugger.add_breakpoint(address);
+                    view.message = format!("Breakpoint at {:#x}{}", address, self.source_at(address));
+                }
+                None => view.message = format!("There's no label `{}` in the code", text),
+            },
+            Prompt::Save => view.message = self.save_command(text),
+            Prompt::Input => {}
+        }
+    }
+
+    /// Draws the panes every so many instructions while the program runs, and pauses it on `p`
+    pub(super) fn tui_tick(&mut self) -> Resume {
+        let Some(tui) = &mut self.terminal else { return Resume::Continue };
+        tui.countdown -= 1;
+        if tui.countdown > 0 {
+            return Resume::Continue;
+        }
+        tui.countdown = DRAW_EVERY;
+
+        let mut tui = self.terminal.take().unwrap();
+        tui.draw(self);
+        let key = tui.poll_key();
+        self.terminal = Some(tui);
+        match key {
+            Some(key) if is_quit(key) => Resume::Quit,
+            Some(key) if key.code == KeyCode::Char('p') => self.tui_pause(""),
+            _ => Resume::Continue,
+        }
+    }
+
+    /// A line of input for the program, typed in the bottom line and shown with its output
+    pub(super) fn tui_read_line(&mut self) -> String {
+        let Some(mut tui) = self.terminal.take() else { return String::new() };
+        tui.view.typing = Some((Prompt::Input, String::new()));
+        let line = loop {
+            tui.draw(self);
+            let Some(key) = tui.read_key() else { break String::new() };
+            if let Some((_, text)) = &mut tui.view.typing {
+                if let Some(text) = type_key(text, key) {
+                    break text;
+                }
+            }
+        };
+        tui.view.typing = None;
+        self.terminal = Some(tui);
+        let line = line + "\n";
+        self.console.print(&line);
+        line
+    }
+
+    /// Takes over the terminal, once the program is loaded, and keeps the output for its pane.
+    /// Without a terminal, the debugger reads commands instead.
+    pub(super) fn init_tui(&mut self) {
+        if !self.tui || self.terminal.is_some() {
+            return;
+        }
+        match ratatui::try_init() {
+            Ok(terminal) => {
+                let data_base = self.parse_options().data_base;
+                self.terminal = Some(Tui::new(Screen::Terminal(terminal), data_base));
+                self.console.capture();
+            }
+            Err(e) => {
+                let _ = terminal::disable_raw_mode();
+                eprintln!("Warning: couldn't take over the terminal ({}), so the debugger reads commands instead", e);
+            }
+        }
+    }
+
+    /// Gives the terminal back, and prints the output of the program that was in its pane
+    pub(super) fn finish_tui(&mut self) {
+        if let Some(tui) = &mut self.terminal {
+            tui.restore();
+            print!("{}", self.console.captured());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+
+    fn load(code: &str, keys: Vec<KeyCode>) -> Simulator {
+        let terminal = ratatui::Terminal::new(TestBackend::new(120, 40)).unwrap();
+        let mut sim = Simulator::new().load_str(code).unwrap();
+        sim.terminal = Some(Tui::new(Screen::Test(terminal, keys.into_iter()), sim.parse_options().data_base));
+        sim.console.capture();
+        sim.debugger.set_commands();
+        sim
+    }
+
+    /// The text of the screen as last drawn
+    fn screen(sim: &Simulator) -> String {
+        match &sim.terminal.as_ref().unwrap().screen {
+            Screen::Test(terminal, _) => {
+                let buffer = terminal.backend().buffer();
+                let width = buffer.area.width as usize;
+                let symbols: Vec<&str> = buffer.content.iter().map(|cell| cell.symbol()).collect();
+                symbols.chunks(width).map(|line| line.concat() + "\n").collect()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_tui() {
+        use KeyCode::{Char, Enter};
+
+        let code = ".data\nmsg: .string \"hi\"\n.text\nmain: li a0, 5\ncall double\nli a7, 1\necall
+            la a0, msg\nli a7, 4\necall\nli a7, 5\necall\naddi a0, a0, 1\nli a7, 93\necall
+            double: add a0, a0, a0\nret";
+        let mut keys = vec![Char('s'), Char('n'), Char('m'), Char('s'), Char('p'), Enter];
+        keys.extend([Char('b'), Char('x'), Enter, Char('b'), Char('0'), Char('x'), Char('2'), Char('8'), Enter]);
+        keys.extend([Char('c'), Char('4'), Char('1'), Enter, Char('q')]);
+        let mut sim = load(code, keys);
+        assert_eq!(sim.run(), 1);
+        assert_eq!(sim.get_reg::<u32>(10), 41); // read, and stopped at the breakpoint
+        assert_eq!(sim.pc, 0x28);
+
+        let screen = screen(&sim);
+        assert!(screen.contains("> 0x00000028  addi a0, a0, 1"), "{}", screen);
+        assert!(screen.contains("a0 0x00000029"), "{}", screen);
+        assert!(screen.contains("│10hi41 "), "{}", screen);
+        assert!(screen.contains("Paused at the breakpoint at 0x28"), "{}", screen);
+        assert_eq!(sim.console.captured(), "10hi41\n");
+
+        // `m` at `sp` showed the stack, which is empty
+        let sp = format!("{:#010x} 00 00 00 00 -- --", sim.get_reg::<u32>(2));
+        assert!(screen.contains(&sp), "{}", screen);
+
+        // Into `double` and back out
+        let mut sim = load(code, vec![Char('s'), Char('s'), Char('S'), Char('q')]);
+        assert_eq!(sim.run(), 1);
+        assert_eq!(sim.pc, 0x4);
+        let screen = self::screen(&sim);
+        assert!(screen.contains("Went back 1 instruction "), "{}", screen);
+    }
+}
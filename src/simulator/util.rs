@@ -1,39 +1,104 @@
-/// Returns a bitmask of a floating point classification, according to the
-/// [RISC-V spec](https://riscv.org//wp-content/uploads/2019/06/riscv-spec.pdf).
-/// The definition can be found at the "Single Precision Floating-Point Classify Instruction",
-/// but I'll copy the table here anyway:
-///
-/// | _rd_ bit | Meaning |
-/// |---------:|---------|
-/// 0| _rs1_ is −∞.
-/// 1| _rs1_ is a negative normal number.
-/// 2| _rs1_ is a negative subnormal number.
-/// 3| _rs1_ is −0.
-/// 4| _rs1_ is +0.
-/// 5| _rs1_ is a positive subnormal number.
-/// 6| _rs1_ is a positive normal number.
-/// 7| _rs1_ is +∞.
-/// 8| _rs1_ is a signaling NaN.
-/// 9| _rs1_ is a quiet NaN.
-///
-/// The last 2 bits may or may not be wrong in some (hopefully older) architectures
-/// because of encoding shenanigans I don't know how to deal with.
-/// See [https://en.wikipedia.org/wiki/NaN#Encoding](https://en.wikipedia.org/wiki/NaN#Encoding)
-pub fn class_mask(f: f32) -> u32 {
-    use std::num::FpCategory::*;
-    let neg = f.is_sign_negative();
-    let bit = match f.classify() {
-        Infinite if neg => 0,
-        Normal if neg => 1,
-        Subnormal if neg => 2,
-        Zero if neg => 3,
-        Zero => 4,
-        Subnormal => 5,
-        Normal => 6,
-        Infinite => 7,
-        Nan if neg => 9,
-        Nan => 8,
-    };
-
-    1_u32 << bit
-}
+/// Returns a bitmask of a floating point classification, according to the
+/// [RISC-V spec](https://riscv.org//wp-content/uploads/2019/06/riscv-spec.pdf).
+/// The definition can be found at the "Single Precision Floating-Point Classify Instruction",
+/// but I'll copy the table here anyway:
+///
+/// | _rd_ bit | Meaning |
+/// |---------:|---------|
+/// 0| _rs1_ is −∞.
+/// 1| _rs1_ is a negative normal number.
+/// 2| _rs1_ is a negative subnormal number.
+/// 3| _rs1_ is −0.
+/// 4| _rs1_ is +0.
+/// 5| _rs1_ is a positive subnormal number.
+/// 6| _rs1_ is a positive normal number.
+/// 7| _rs1_ is +∞.
+/// 8| _rs1_ is a signaling NaN.
+/// 9| _rs1_ is a quiet NaN.
+///
+/// The last 2 bits may or may not be wrong in some (hopefully older) architectures
+/// because of encoding shenanigans I don't know how to deal with.
+/// See [https://en.wikipedia.org/wiki/NaN#Encoding](https://en.wikipedia.org/wiki/NaN#Encoding)
+pub fn class_mask(f: f32) -> u32 {
+    class_bit(f.classify(), f.is_sign_negative())
+}
+
+/// [class_mask](fn.class_mask.html) for doubles
+pub fn class_mask_d(f: f64) -> u32 {
+    class_bit(f.classify(), f.is_sign_negative())
+}
+
+fn class_bit(category: std::num::FpCategory, neg: bool) -> u32 {
+    use std::num::FpCategory::*;
+    let bit = match category {
+        Infinite if neg => 0,
+        Normal if neg => 1,
+        Subnormal if neg => 2,
+        Zero if neg => 3,
+        Zero => 4,
+        Subnormal => 5,
+        Normal => 6,
+        Infinite => 7,
+        Nan if neg => 9,
+        Nan => 8,
+    };
+
+    1_u32 << bit
+}
+
+/// Writes a float or a double like RARS does, which is how Java does it: always with a decimal
+/// point, as in `3.0`, and in scientific notation like `1.0E10` outside of [10^-3, 10^7)
+pub fn float_to_string<T>(f: T) -> String
+where
+    T: Copy + Into<f64> + std::fmt::Display + std::fmt::LowerExp,
+{
+    let x: f64 = f.into();
+    if x.is_nan() {
+        return "NaN".to_owned();
+    }
+    if x.is_infinite() {
+        return if x > 0.0 { "Infinity" } else { "-Infinity" }.to_owned();
+    }
+
+    let with_point = |s: &str| if s.contains('.') { s.to_owned() } else { format!("{}.0", s) };
+    if x == 0.0 || (1e-3..1e7).contains(&x.abs()) {
+        return with_point(&f.to_string());
+    }
+
+    let s = format!("{:e}", f);
+    let (mantissa, exponent) = s.split_once('e').unwrap_or((&s, "0"));
+    format!("{}E{}", with_point(mantissa), exponent)
+}
+
+/// The number typed for a read int, float or double ecall, or 0 if what was typed isn't one, so a
+/// typo doesn't crash the simulator
+pub fn parse_input<T: std::str::FromStr + Default>(line: &str) -> T {
+    line.trim().parse().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_float_to_string() {
+        assert_eq!(float_to_string(3.0), "3.0");
+        assert_eq!(float_to_string(-0.0), "-0.0");
+        assert_eq!(float_to_string(0.1), "0.1");
+        assert_eq!(float_to_string(1e10), "1.0E10");
+        assert_eq!(float_to_string(-1.5e-5), "-1.5E-5");
+        assert_eq!(float_to_string(f32::NEG_INFINITY), "-Infinity");
+        assert_eq!(float_to_string(0.1f64), "0.1");
+        assert_eq!(float_to_string(1e-10f64), "1.0E-10");
+    }
+
+    #[test]
+    fn test_parse_input() {
+        assert_eq!(parse_input::<f32>("2.5\n"), 2.5);
+        assert_eq!(parse_input::<f64>("  -1e3\r\n"), -1000.0);
+        assert_eq!(parse_input::<f32>("two\n"), 0.0);
+        assert_eq!(parse_input::<f64>(""), 0.0);
+        assert_eq!(parse_input::<i32>("-7\n"), -7);
+        assert_eq!(parse_input::<i32>("7.5\n"), 0);
+    }
+}
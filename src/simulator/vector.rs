@@ -0,0 +1,190 @@
+//!
+//! The registers of the vector extension, for the [VectorInstructions](../../parser/enum.VectorInstruction.html).
+//!
+//! Only 32-bit elements are supported, so `vsetvli` has to ask for `e32`, with LMUL from `m1` to
+//! `m8`. Anything else sets `vill`, like a processor without those widths would, and then every
+//! vector instruction but `vsetvli` is illegal. Each register has VLEN bits, 128 by default:
+//!
+//! ```
+//! loop:
+//!     vsetvli t0, a2, e32, m1, ta, ma   # t0 = how many we'll do now, at most VLEN / 32
+//!     vle32.v v1, (a0)
+//!     vadd.vv v2, v2, v1
+//!     slli t1, t0, 2
+//!     add a0, a0, t1
+//!     sub a2, a2, t0
+//!     bnez a2, loop
+//! ```
+//!
+
+use std::ops::Range;
+
+/// The `vill` bit of `vtype`, set when `vsetvli` asked for something we don't have
+const VILL: u32 = 1 << 31;
+
+pub struct Vectors {
+    /// VLEN, how many bits each register has
+    vlen: usize,
+    /// Every register, one after the other, so a group of LMUL registers is just a longer slice
+    elements: Vec<u32>,
+    pub vl: u32,
+    pub vtype: u32,
+}
+
+impl Vectors {
+    /// The registers of a processor whose VLEN is `vlen`, a power of 2 of at least 32
+    pub fn new(vlen: usize) -> Self {
+        Self {
+            vlen,
+            elements: vec![0; vlen / 32 * 32],
+            vl: 0,
+            vtype: VILL,
+        }
+    }
+
+    /// The size of a register in bytes, the `vlenb` CSR
+    pub fn vlenb(&self) -> u32 {
+        (self.vlen / 8) as u32
+    }
+
+    /// Every register, one after the other, for a [snapshot](../snapshot/index.html)
+    pub(super) fn registers(&self) -> &[u32] {
+        &self.elements
+    }
+
+    pub(super) fn registers_mut(&mut self) -> &mut [u32] {
+        &mut self.elements
+    }
+
+    /// How many elements a register has
+    fn per_register(&self) -> usize {
+        self.vlen / 32
+    }
+
+    /// How many registers each operand has, or `None` if `vtype` is invalid
+    fn lmul(&self) -> Option<usize> {
+        match self.vtype & VILL {
+            0 => Some(1 << (self.vtype & 7)),
+            _ => None,
+        }
+    }
+
+    /// Sets `vtype` like a `vsetvli` that asked for `avl` elements, and returns the new `vl`
+    pub fn set_vtype(&mut self, avl: u32, vtype: u32) -> u32 {
+        // e32, an LMUL of at least 1 and none of the reserved bits
+        let (sew, lmul) = (vtype >> 3 & 7, vtype & 7);
+        if sew != 2 || lmul > 3 || vtype >> 8 != 0 {
+            self.vtype = VILL;
+            self.vl = 0;
+            return 0;
+        }
+
+        self.vtype = vtype;
+        let vlmax = (self.per_register() << lmul) as u32;
+        self.vl = avl.min(vlmax);
+        self.vl
+    }
+
+    /// The first `vl` elements of the group that starts at `v`, or `None` if `vtype` is invalid or
+    /// `v` isn't a multiple of LMUL
+    fn group(&self, v: u8) -> Option<Range<usize>> {
+        let lmul = self.lmul()?;
+        if !(v as usize).is_multiple_of(lmul) {
+            return None;
+        }
+        let start = v as usize * self.per_register();
+        Some(start..start + self.vl as usize)
+    }
+
+    pub fn elements(&self, v: u8) -> Option<&[u32]> {
+        let group = self.group(v)?;
+        Some(&self.elements[group])
+    }
+
+    pub fn elements_mut(&mut self, v: u8) -> Option<&mut [u32]> {
+        let group = self.group(v)?;
+        Some(&mut self.elements[group])
+    }
+
+    /// The element 0 of `v`, which doesn't depend on `vl`
+    pub fn scalar(&self, v: u8) -> Option<u32> {
+        self.lmul()?;
+        Some(self.elements[v as usize * self.per_register()])
+    }
+
+    /// Writes `x` to the element 0 of `v`, if `vl` isn't 0
+    pub fn set_scalar(&mut self, v: u8, x: u32) -> Option<()> {
+        self.lmul()?;
+        if self.vl > 0 {
+            let i = v as usize * self.per_register();
+            self.elements[i] = x;
+        }
+        Some(())
+    }
+
+    /// `vd[i] = op(vs2[i], vs1[i])` for the first `vl` elements
+    pub fn binary(&mut self, vd: u8, vs2: u8, vs1: u8, op: impl Fn(u32, u32) -> u32) -> Option<()> {
+        let (a, b) = (self.group(vs2)?, self.group(vs1)?);
+        let results: Vec<_> = a.zip(b).map(|(a, b)| op(self.elements[a], self.elements[b])).collect();
+        self.elements_mut(vd)?.copy_from_slice(&results);
+        Some(())
+    }
+
+    /// Folds the first `vl` elements of `vs2` with `op`, starting from the element 0 of `vs1`, into
+    /// the element 0 of `vd`. With `vl` = 0 nothing is written.
+    pub fn reduce(&mut self, vd: u8, vs2: u8, vs1: u8, op: impl Fn(u32, u32) -> u32) -> Option<()> {
+        let start = self.scalar(vs1)?;
+        let result = self.elements(vs2)?.iter().fold(start, |acc, &x| op(acc, x));
+        self.set_scalar(vd, result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+
+    #[test]
+    fn test_vectors() {
+        let mut v = Vectors::new(128);
+        assert_eq!(v.elements(1), None);
+
+        // e32, m2: 8 elements in v2 and v3
+        assert_eq!(v.set_vtype(100, 0x11), 8);
+        assert_eq!(v.elements(1), None);
+        v.elements_mut(2).unwrap().copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        v.binary(4, 2, 2, u32::wrapping_mul).unwrap();
+        assert_eq!(v.elements(4).unwrap(), [1, 4, 9, 16, 25, 36, 49, 64]);
+
+        assert_eq!(v.set_vtype(3, 0x10), 3);
+        v.set_scalar(1, 10).unwrap();
+        v.reduce(0, 4, 1, u32::wrapping_add).unwrap();
+        assert_eq!(v.scalar(0), Some(24));
+
+        // e8 isn't supported
+        assert_eq!(v.set_vtype(3, 0x00), 0);
+        assert_eq!(v.scalar(0), None);
+    }
+
+    #[test]
+    fn test_dot_product() {
+        // 10 elements take 3 rounds with VLEN = 128, and 1 with 512
+        let code = ".data\na: .word 1 2 3 4 5 6 7 8 9 10\nb: .word 10 9 8 7 6 5 4 3 2 -1
+            .text\nla a0, a\nla a1, b\nli a2, 10
+            vsetvli zero, a2, e32, m1\nvmv.s.x v3, zero
+            loop: vsetvli t0, a2, e32, m1, ta, ma\nvle32.v v1, (a0)\nvle32.v v2, (a1)
+            vmul.vv v1, v1, v2\nvredsum.vs v3, v1, v3\nvse32.v v1, (a0)
+            slli t1, t0, 2\nadd a0, a0, t1\nadd a1, a1, t1\nsub a2, a2, t0\nbnez a2, loop
+            vmv.x.s a0, v3\nli a7, 93\necall";
+
+        for vlen in [128, 512] {
+            let mut sim = Simulator::new()
+                .vlen(vlen)
+                .load_str(code)
+                .unwrap();
+            assert_eq!(sim.run(), 10 + 18 + 24 + 28 + 30 + 30 + 28 + 24 + 18 - 10);
+            // The products were stored back
+            assert_eq!(sim.memory.get_word(36), (-10i32) as u32);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Writes `code` to a file of its own and runs FPGRARS on it with `args`, returning the exit code
+fn run(name: &str, code: &str, args: &[&str]) -> Option<i32> {
+    let dir = std::env::temp_dir().join(format!("fpgrars-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path: PathBuf = dir.join(name);
+    std::fs::write(&path, code).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_fpgrars"))
+        .args(["--no-video", "--quiet"])
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap()
+        .status;
+    std::fs::remove_file(&path).unwrap();
+    status.code()
+}
+
+#[test]
+fn test_exit_code() {
+    assert_eq!(run("exit.s", "li a0, 3\nli a7, 93\necall\n", &[]), Some(3));
+}
+
+#[test]
+fn test_parse_error_exit_code() {
+    assert_eq!(run("error.s", "li a0, 3\nnotaninstruction t0\n", &[]), Some(1));
+
+    // A warning only stops the program with --werror
+    let code = ".data\nx: .byte 1\ny: .word 2\n.text\nli a0, 0\nli a7, 93\necall\n";
+    assert_eq!(run("warning.s", code, &[]), Some(0));
+    assert_eq!(run("werror.s", code, &["--werror"]), Some(1));
+}